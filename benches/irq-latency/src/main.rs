@@ -0,0 +1,188 @@
+//! Interrupt latency benchmark for HT32F523xx
+//!
+//! Measures, using BFTM0 as a free-running PCLK cycle counter:
+//! - GPIO edge to task wake-up latency (PA0 output looped back to PA1 input)
+//! - USB interrupt handler duration on the idle path (IRQ pended by software)
+//! - embassy-time wake-up jitter for a 1 ms timer
+//!
+//! Results are printed as a defmt table. Every measurement is bounded by an
+//! `embassy-time` timeout, so a wake-up that never arrives is reported as a
+//! timeout instead of hanging the benchmark. Nothing else wakes the executor
+//! while it waits, so the GPIO figure is the EXTI interrupt through to the
+//! task being polled.
+//!
+//! Wiring: connect PA0 to PA1 with a jumper wire.
+
+#![no_std]
+#![no_main]
+
+use core::cell::Cell;
+
+use cortex_m::peripheral::NVIC;
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_ht32f523xx::exti::Edge;
+use embassy_ht32f523xx::gpio::{Level, Pull, Speed};
+use embassy_ht32f523xx::pac::{self, Interrupt};
+use embassy_ht32f523xx::usb::{Config as UsbConfig, Driver};
+use embassy_time::{Duration, Timer, with_timeout};
+use embedded_hal::digital::OutputPin;
+use {defmt_rtt as _, panic_probe as _};
+
+/// Samples taken per metric
+const SAMPLES: u32 = 64;
+/// Timer period used for the jitter measurement
+const TIMER_PERIOD_US: u32 = 1_000;
+/// Give up on a single sample after this long
+const TIMEOUT_US: u32 = 20_000;
+
+/// BFTMCR counter enable bit
+const BFTM_CR_CEN: u32 = 1 << 2;
+
+/// Min/avg/max accumulator in BFTM cycles
+struct Stats {
+    name: &'static str,
+    count: u32,
+    min: u32,
+    max: u32,
+    sum: u64,
+    timeouts: u32,
+}
+
+impl Stats {
+    const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            count: 0,
+            min: u32::MAX,
+            max: 0,
+            sum: 0,
+            timeouts: 0,
+        }
+    }
+
+    fn add(&mut self, cycles: u32) {
+        self.count += 1;
+        self.min = self.min.min(cycles);
+        self.max = self.max.max(cycles);
+        self.sum += cycles as u64;
+    }
+
+    fn timeout(&mut self) {
+        self.timeouts += 1;
+    }
+
+    fn report(&self, cycles_per_us: u32) {
+        if self.count == 0 {
+            info!("| {=str:<24} | {=u32:>4} | {=str:>8} | {=str:>8} | {=str:>8} | {=u32:>8} |",
+                self.name, 0, "-", "-", "-", self.timeouts);
+            return;
+        }
+
+        let avg = (self.sum / self.count as u64) as u32;
+        info!("| {=str:<24} | {=u32:>4} | {=u32:>8} | {=u32:>8} | {=u32:>8} | {=u32:>8} |",
+            self.name, self.count, self.min, avg, self.max, self.timeouts);
+        info!("| {=str:<24} |      | {=u32:>6}ns | {=u32:>6}ns | {=u32:>6}ns |          |",
+            "", cycles_to_ns(self.min, cycles_per_us), cycles_to_ns(avg, cycles_per_us),
+            cycles_to_ns(self.max, cycles_per_us));
+    }
+}
+
+fn cycles_to_ns(cycles: u32, cycles_per_us: u32) -> u32 {
+    (cycles as u64 * 1_000 / cycles_per_us as u64) as u32
+}
+
+/// Start BFTM0 as a free-running 32-bit counter clocked from PCLK
+fn bftm_start() {
+    let ckcu = unsafe { &*pac::Ckcu::ptr() };
+    ckcu.apbccr1().modify(|_, w| w.bftm0en().set_bit());
+
+    let bftm = unsafe { &*pac::Bftm0::ptr() };
+    bftm.bftm_cr().write(|w| unsafe { w.bits(0) });
+    bftm.bftm_cmpr().write(|w| unsafe { w.bits(u32::MAX) });
+    bftm.bftm_cntr().write(|w| unsafe { w.bits(0) });
+    bftm.bftm_cr().write(|w| unsafe { w.bits(BFTM_CR_CEN) });
+}
+
+#[inline(always)]
+fn now() -> u32 {
+    let bftm = unsafe { &*pac::Bftm0::ptr() };
+    bftm.bftm_cntr().read().bits()
+}
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let mut p = embassy_ht32f523xx::init(embassy_ht32f523xx::Config::default());
+
+    bftm_start();
+    let cycles_per_us = embassy_ht32f523xx::rcc::get_clocks().apb_clk().to_mhz();
+    let timeout = Duration::from_micros(TIMEOUT_US as u64);
+
+    info!("irq-latency: PCLK {} MHz, {} samples per metric", cycles_per_us, SAMPLES);
+
+    // Cost of two back-to-back counter reads, subtracted from the ISR measurement
+    let t0 = now();
+    let overhead = now().wrapping_sub(t0);
+
+    // GPIO edge to task wake-up
+    let mut out = p.gpioa.pa0().into_push_pull_output(Level::Low, Speed::High);
+    let input = p.gpioa.pa1().into_input_with_pull(Pull::Down);
+    let mut gpio = Stats::new("gpio edge -> task");
+
+    for _ in 0..SAMPLES {
+        let _ = out.set_low();
+        Timer::after_micros(50).await;
+
+        let edge_at = Cell::new(0u32);
+        let wait = async {
+            let woke = with_timeout(timeout, input.wait_for_interrupt(Edge::Rising)).await;
+            (woke, now())
+        };
+        let trigger = async {
+            // Let the waiter register before the edge happens
+            Timer::after_micros(10).await;
+            edge_at.set(now());
+            let _ = out.set_high();
+        };
+
+        match join(wait, trigger).await.0 {
+            (Ok(()), woke_at) => gpio.add(woke_at.wrapping_sub(edge_at.get())),
+            (Err(_), _) => gpio.timeout(),
+        }
+    }
+
+    // USB interrupt handler, idle path
+    let _usb = Driver::new(p.usb, UsbConfig::default());
+    let mut usb_isr = Stats::new("usb isr (idle)");
+
+    for _ in 0..SAMPLES {
+        let t0 = now();
+        NVIC::pend(Interrupt::USB);
+        cortex_m::asm::isb();
+        usb_isr.add(now().wrapping_sub(t0).saturating_sub(overhead));
+    }
+
+    // embassy-time wake-up jitter
+    let expected = TIMER_PERIOD_US * cycles_per_us;
+    let mut jitter = Stats::new("time driver jitter");
+
+    for _ in 0..SAMPLES {
+        let t0 = now();
+        match with_timeout(timeout, Timer::after_micros(TIMER_PERIOD_US as u64)).await {
+            Ok(()) => jitter.add(now().wrapping_sub(t0).abs_diff(expected)),
+            Err(_) => jitter.timeout(),
+        }
+    }
+
+    info!("| {=str:<24} | {=str:>4} | {=str:>8} | {=str:>8} | {=str:>8} | {=str:>8} |",
+        "metric (cycles)", "n", "min", "avg", "max", "timeouts");
+    gpio.report(cycles_per_us);
+    usb_isr.report(cycles_per_us);
+    jitter.report(cycles_per_us);
+    info!("irq-latency: done");
+
+    loop {
+        Timer::after_secs(1).await;
+    }
+}
@@ -4,6 +4,7 @@ fn main() {
     println!("cargo:rustc-check-cfg=cfg(flash_size_128k)");
     println!("cargo:rustc-check-cfg=cfg(ram_size_8k)");
     println!("cargo:rustc-check-cfg=cfg(ram_size_16k)");
+    println!("cargo:rustc-check-cfg=cfg(hot_path_log)");
     // Determine which memory layout to use and provide chip information
     let (memory_file, chip_info) = if cfg!(feature = "ht32f52342") {
         ("memory_ht32f52342.x", "HT32F52342: 64KB Flash, 8KB RAM")
@@ -37,4 +38,9 @@ fn main() {
         println!("cargo:rustc-cfg=flash_size_128k");
         println!("cargo:rustc-cfg=ram_size_16k");
     }
-}
\ No newline at end of file
+
+    // Logging on per-packet and per-edge paths, see src/fmt.rs
+    if cfg!(feature = "defmt") && !cfg!(feature = "strip-hot-path-logs") {
+        println!("cargo:rustc-cfg=hot_path_log");
+    }
+}
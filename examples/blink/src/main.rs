@@ -4,7 +4,7 @@
 use cortex_m_rt::entry;
 use embedded_hal::digital::OutputPin;
 use ht32_bsp::Leds;
-use embassy_ht32f523xx::{self, Config};
+use embassy_ht32f523xx::{self, cortex_delay, Config};
 use panic_halt as _;
 
 #[entry]
@@ -28,9 +28,7 @@ fn main() -> ! {
         }
         led1_on = !led1_on;
 
-        // Simple delay - no Embassy Timer
-        for _ in 0..1_000_000 {
-            cortex_m::asm::nop();
-        }
+        // Busy-wait, no Embassy Timer
+        cortex_delay::delay_ms(500);
     }
 }
\ No newline at end of file
@@ -0,0 +1,101 @@
+//! defmt logging over USB CDC-ACM
+//!
+//! The HAL only emits `defmt` log frames; this example links `defmt-bbq`
+//! instead of `defmt-rtt`, so the frames land in a buffer that a task drains
+//! to a CDC-ACM port. No debug probe is needed to read the logs:
+//!
+//! ```bash
+//! stty -F /dev/ttyACM0 raw
+//! defmt-print -e target/thumbv6m-none-eabi/release/defmt-usb < /dev/ttyACM0
+//! ```
+//!
+//! Frames logged before the host opens the port are kept until the buffer
+//! fills, then dropped.
+
+#![no_std]
+#![no_main]
+
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_futures::join::join3;
+use embassy_ht32f523xx::usb::{Config as UsbConfig, Driver};
+use embassy_time::Timer;
+use embassy_usb::class::cdc_acm::{CdcAcmClass, Sender, State};
+use embassy_usb::Builder;
+use panic_probe as _;
+use static_cell::StaticCell;
+
+/// USB full-speed bulk packet size
+const PACKET_SIZE: usize = 64;
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    // Must run before the first log call so no frame is lost
+    let consumer = defmt_bbq::init().unwrap();
+
+    let p = embassy_ht32f523xx::init(embassy_ht32f523xx::Config::default());
+    info!("defmt-usb: logging over CDC-ACM");
+
+    let driver = Driver::new(p.usb, UsbConfig::default());
+
+    let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("HT32 defmt log");
+    config.serial_number = Some("12345678");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+
+    static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+    static STATE: StaticCell<State> = StaticCell::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        CONFIG_DESCRIPTOR.init([0; 256]),
+        BOS_DESCRIPTOR.init([0; 256]),
+        &mut [],
+        CONTROL_BUF.init([0; 64]),
+    );
+
+    let class = CdcAcmClass::new(&mut builder, STATE.init(State::new()), PACKET_SIZE as u16);
+    let (mut sender, _receiver) = class.split();
+    let mut usb = builder.build();
+
+    join3(usb.run(), drain(&mut sender, consumer), heartbeat()).await;
+}
+
+/// Forward buffered defmt frames to the host while the port is open
+async fn drain<'d>(sender: &mut Sender<'d, Driver<'d>>, mut consumer: defmt_bbq::DefmtConsumer) {
+    loop {
+        sender.wait_connection().await;
+
+        loop {
+            let Ok(grant) = consumer.read() else {
+                // Nothing buffered
+                Timer::after_millis(10).await;
+                continue;
+            };
+
+            let len = grant.len().min(PACKET_SIZE);
+            let sent = sender.write_packet(&grant[..len]).await;
+            grant.release(len);
+
+            if sent.is_err() {
+                // Port closed; keep buffering until it is reopened
+                break;
+            }
+        }
+    }
+}
+
+/// Something to log
+async fn heartbeat() {
+    let mut count = 0u32;
+    loop {
+        info!("heartbeat {}", count);
+        count = count.wrapping_add(1);
+        Timer::after_secs(1).await;
+    }
+}
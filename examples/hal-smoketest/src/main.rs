@@ -0,0 +1,230 @@
+//! HAL smoke test for board bring-up
+//!
+//! Runs every implemented driver briefly and prints a pass/fail matrix over
+//! defmt, so a new board needs only this one binary to check the basics.
+//!
+//! Wiring:
+//! - PA0 to PA1: GPIO loopback and EXTI edge
+//! - PA2 to PA3: USART0 TX to RX loopback
+//! - USB cable to a host, optional: without one the USB row is skipped
+//!
+//! The flash check erases the last flash page.
+
+#![no_std]
+#![no_main]
+
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_futures::select::select;
+use embassy_ht32f523xx::exti::{self, Edge, ExtiChannel};
+use embassy_ht32f523xx::flash::Flash;
+use embassy_ht32f523xx::gpio::{Level, Pull, Speed};
+use embassy_ht32f523xx::timer::{Channel, Pwm, Timer as GpTimer};
+use embassy_ht32f523xx::uart::{Config as UartConfig, Uart};
+use embassy_ht32f523xx::usb::{self, Config as UsbConfig, Driver, PowerSource};
+use embassy_ht32f523xx::{pac, time::Hertz, Peripherals};
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use {defmt_rtt as _, panic_probe as _};
+
+/// Result of one check
+#[derive(Format)]
+enum Outcome {
+    Pass,
+    Fail(&'static str),
+    Skip(&'static str),
+}
+
+impl From<Result<(), &'static str>> for Outcome {
+    fn from(result: Result<(), &'static str>) -> Self {
+        match result {
+            Ok(()) => Outcome::Pass,
+            Err(reason) => Outcome::Fail(reason),
+        }
+    }
+}
+
+fn check(condition: bool, reason: &'static str) -> Result<(), &'static str> {
+    if condition { Ok(()) } else { Err(reason) }
+}
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let mut p = embassy_ht32f523xx::init(embassy_ht32f523xx::Config::default());
+    info!("HAL smoke test");
+
+    let results = [
+        ("gpio", gpio(&mut p).await),
+        ("exti", exti(&mut p).await),
+        ("uart", uart(&mut p).await),
+        ("pwm", pwm(&mut p).await),
+        ("adc", adc(&mut p)),
+        ("flash", flash(&mut p.flash).await),
+        ("usb", usb(p).await),
+    ];
+
+    let mut failed = 0;
+    info!("---------------------------");
+    for (name, outcome) in &results {
+        if matches!(outcome, Outcome::Fail(_)) {
+            failed += 1;
+        }
+        info!("{=str} {}", *name, outcome);
+    }
+    info!("---------------------------");
+    if failed == 0 {
+        info!("SMOKETEST PASS");
+    } else {
+        error!("SMOKETEST FAIL: {} check(s) failed", failed);
+    }
+
+    loop {
+        Timer::after_secs(1).await;
+    }
+}
+
+/// PA0 drives PA1 through the jumper
+async fn gpio(p: &mut Peripherals) -> Outcome {
+    let mut out = p.gpioa.pa0().into_push_pull_output(Level::Low, Speed::High);
+    let mut input = p.gpioa.pa1().into_input_with_pull(Pull::Down);
+
+    let result = async {
+        for level in [true, false, true, false] {
+            if level { out.set_high().unwrap() } else { out.set_low().unwrap() }
+            Timer::after_micros(10).await;
+            check(input.is_high().unwrap() == level, "PA1 does not follow PA0")?;
+        }
+        Ok(())
+    }
+    .await;
+    result.into()
+}
+
+/// A PA0 rising edge wakes an EXTI wait on PA1
+async fn exti(p: &mut Peripherals) -> Outcome {
+    let mut out = p.gpioa.pa0().into_push_pull_output(Level::Low, Speed::High);
+    let _input = p.gpioa.pa1().into_input_with_pull(Pull::Down);
+    let channel = ExtiChannel::new(1).unwrap();
+    exti::configure_exti_source(channel.line(), 'A');
+    channel.enable_interrupt(Edge::Rising);
+
+    let edge = async {
+        Timer::after_millis(1).await;
+        out.set_high().unwrap();
+    };
+    let (woken, ()) = embassy_futures::join::join(with_timeout(Duration::from_millis(100), channel.wait()), edge).await;
+    channel.disable_interrupt();
+    check(woken.is_ok(), "no EXTI wake-up on PA1").into()
+}
+
+/// USART0 echoes through the PA2-PA3 jumper
+async fn uart(p: &mut Peripherals) -> Outcome {
+    let tx = p.gpioa.pa2().into_alternate_function::<6>();
+    let rx = p.gpioa.pa3().into_alternate_function::<6>();
+    let mut uart = Uart::new(&mut p.usart0, tx, rx, UartConfig::default());
+
+    let result = async {
+        let sent = b"smoke";
+        uart.write(sent).await.map_err(|_| "write failed")?;
+        let mut echoed = [0u8; 5];
+        let mut received = 0;
+        while received < echoed.len() {
+            received += uart
+                .read_timeout(&mut echoed[received..], Duration::from_millis(100))
+                .await
+                .map_err(|_| "no echo on PA3")?;
+        }
+        check(&echoed == sent, "echo differs")
+    }
+    .await;
+    result.into()
+}
+
+/// GPTM1 counts and takes a PWM duty cycle
+async fn pwm(p: &mut Peripherals) -> Outcome {
+    let result = async {
+        let mut timer = GpTimer::new(&mut p.timer1);
+        timer.set_frequency(Hertz::khz(1));
+        timer.start();
+        let before = timer.get_counter();
+        Timer::after_micros(200).await;
+        check(timer.get_counter() != before, "GPTM1 counter does not run")?;
+        drop(timer);
+
+        let mut pwm = Pwm::new(&mut p.timer1);
+        pwm.set_duty_cycle(Channel::Ch0, 1, 2);
+        pwm.enable_channel(Channel::Ch0);
+        let gptm1 = unsafe { &*pac::Gptm1::ptr() };
+        check(gptm1.gptm_ch0ccr().read().bits() != 0, "CH0 duty not applied")
+    }
+    .await;
+    result.into()
+}
+
+/// One conversion per channel 0..=3 completes in range
+fn adc(p: &mut Peripherals) -> Outcome {
+    for channel in 0..4 {
+        let value = p.adc.read(channel);
+        if value > embassy_ht32f523xx::adc::MAX_VALUE {
+            return Outcome::Fail("conversion out of range");
+        }
+        debug!("adc: channel {} = {}", channel, value);
+    }
+    Outcome::Pass
+}
+
+/// Erase, program and read back the last page
+async fn flash(flash: &mut Flash) -> Outcome {
+    let page = (flash.capacity() - Flash::ERASE_SIZE) as u32;
+    let end = page + Flash::ERASE_SIZE as u32;
+
+    let result = async {
+        let pattern: [u8; 16] = core::array::from_fn(|i| (i as u8).wrapping_mul(37) ^ 0x5A);
+        let mut readback = [0u8; 16];
+        flash.erase_async(page, end).await.map_err(|_| "erase failed")?;
+        flash.write_async(page, &pattern).await.map_err(|_| "program failed")?;
+        flash.read(page, &mut readback).map_err(|_| "read failed")?;
+        check(readback == pattern, "read back differs")?;
+        flash.erase_async(page, end).await.map_err(|_| "final erase failed")
+    }
+    .await;
+    result.into()
+}
+
+/// The D+ pull-up comes on and, with a host attached, the bus is reset
+async fn usb(p: Peripherals) -> Outcome {
+    let driver = Driver::new(p.usb, UsbConfig::default());
+    let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+    config.product = Some("HT32 smoke test");
+
+    let mut config_descriptor = [0; 64];
+    let mut bos_descriptor = [0; 32];
+    let mut control_buf = [0; 64];
+    let builder = embassy_usb::Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut [],
+        &mut control_buf,
+    );
+    let mut device = builder.build();
+
+    let check_bus = async {
+        Timer::after_millis(500).await;
+        let usb = unsafe { &*pac::Usb::ptr() };
+        if !usb.csr().read().dppuen().bit_is_set() {
+            return Outcome::Fail("D+ pull-up not enabled");
+        }
+        match usb::detect_power_source(500).await {
+            PowerSource::Unconfigured | PowerSource::Configured { .. } | PowerSource::Suspended => Outcome::Pass,
+            PowerSource::Charger | PowerSource::Unknown => Outcome::Skip("no host reset the bus"),
+            PowerSource::Detached => Outcome::Skip("no VBUS"),
+        }
+    };
+    match select(device.run(), check_bus).await {
+        embassy_futures::select::Either::First(_) => Outcome::Fail("USB device stopped"),
+        embassy_futures::select::Either::Second(outcome) => outcome,
+    }
+}
@@ -0,0 +1,65 @@
+//! I2C bus scanner
+//!
+//! Scans the bus on PB0 (SCL) and PB1 (SDA) every two seconds and prints the
+//! devices that answer as an `i2cdetect`-style table. The bus is bit-banged
+//! by `soft_i2c` at 100 kHz; fit pull-ups unless the devices' breakout
+//! boards already have them.
+
+#![no_std]
+#![no_main]
+
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_ht32f523xx::i2c::{self, AddressMap};
+use embassy_ht32f523xx::soft_i2c::{Config, SoftI2c};
+use embassy_time::Timer;
+use {defmt_rtt as _, panic_probe as _};
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_ht32f523xx::init(embassy_ht32f523xx::Config::default());
+    info!("I2C scanner");
+
+    let mut bus = SoftI2c::new(p.bftm0, p.gpiob.pb0().degrade(), p.gpiob.pb1().degrade(), Config::default());
+
+    let mut last = None;
+    loop {
+        match i2c::scan(&mut bus).await {
+            // Only print the table when something changed, e.g. a breakout was plugged in
+            Ok(found) if last != Some(found) => {
+                info!("{} device(s): {}", found.len(), found);
+                print_table(found);
+                last = Some(found);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("bus error: {}, recovering", e);
+                bus.recover();
+                last = None;
+            }
+        }
+        Timer::after_secs(2).await;
+    }
+}
+
+/// One line per 16 addresses, like `i2cdetect -y`
+fn print_table(found: AddressMap) {
+    info!("     0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f");
+    for row in 0..8u8 {
+        let mut cells = [*b"-- "; 16];
+        for (col, cell) in cells.iter_mut().enumerate() {
+            let address = row * 16 + col as u8;
+            if !(i2c::FIRST_ADDRESS..=i2c::LAST_ADDRESS).contains(&address) {
+                *cell = *b"   ";
+            } else if found.contains(address) {
+                *cell = [hex(address >> 4), hex(address & 0xF), b' '];
+            }
+        }
+        let line = cells.as_flattened();
+        info!("{=u8:02x}: {=str}", row * 16, core::str::from_utf8(line).unwrap_or("?"));
+    }
+}
+
+fn hex(nibble: u8) -> u8 {
+    b"0123456789abcdef"[nibble as usize]
+}
@@ -0,0 +1,320 @@
+//! BME280 on a shared I2C bus
+//!
+//! Reads temperature, pressure and humidity from a BME280 (address 0x76, SDO
+//! low) on PB0 (SCL) / PB1 (SDA) at 400 kHz, while a second task scans the
+//! same bus through its own `shared_bus` device. Wire an SHT31 alongside it
+//! to run both examples on the same board.
+//!
+//! Doubles as a hardware check of `soft_i2c`:
+//!
+//! - register reads are `write_read`s, so the chip ID and calibration checks
+//!   fail unless repeated starts work;
+//! - the 26-byte calibration burst is read twice and must match, catching
+//!   bit slips at fast-mode timing;
+//! - the scanner's transactions interleave with the sensor's, so a missing
+//!   device or failed transfer points at bus sharing.
+//!
+//! Every 10 readings it logs the readings and the pass/fail counters; any
+//! failure is a `warn!`.
+
+#![no_std]
+#![no_main]
+
+use defmt::*;
+use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
+use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_ht32f523xx::i2c;
+use embassy_ht32f523xx::soft_i2c::{Config, SoftI2c};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::Timer;
+use embedded_hal_async::i2c::I2c;
+use {defmt_rtt as _, panic_probe as _};
+
+const ADDRESS: u8 = 0x76;
+const CHIP_ID: u8 = 0x60;
+
+const REG_CALIB_00: u8 = 0x88;
+const REG_CHIP_ID: u8 = 0xD0;
+const REG_RESET: u8 = 0xE0;
+const REG_CALIB_26: u8 = 0xE1;
+const REG_CTRL_HUM: u8 = 0xF2;
+const REG_STATUS: u8 = 0xF3;
+const REG_CTRL_MEAS: u8 = 0xF4;
+const REG_PRESS_MSB: u8 = 0xF7;
+
+const RESET_WORD: u8 = 0xB6;
+const STATUS_MEASURING: u8 = 1 << 3;
+const STATUS_IM_UPDATE: u8 = 1 << 0;
+/// Humidity oversampling x1
+const CTRL_HUM: u8 = 0b001;
+/// Temperature and pressure oversampling x1, forced mode
+const CTRL_MEAS_FORCED: u8 = (0b001 << 5) | (0b001 << 2) | 0b01;
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_ht32f523xx::init(embassy_ht32f523xx::Config::default());
+    info!("BME280 on a shared bus");
+
+    let bus = SoftI2c::new(p.bftm0, p.gpiob.pb0().degrade(), p.gpiob.pb1().degrade(), Config::fast());
+    let bus = Mutex::<NoopRawMutex, _>::new(bus);
+
+    join(sensor(I2cDevice::new(&bus)), scanner(I2cDevice::new(&bus))).await;
+}
+
+async fn sensor<I: I2c>(mut i2c: I)
+where
+    I::Error: Format,
+{
+    let mut stats = Stats::default();
+    loop {
+        let calibration = match setup(&mut i2c, &mut stats).await {
+            Some(calibration) => calibration,
+            None => {
+                Timer::after_secs(1).await;
+                continue;
+            }
+        };
+
+        loop {
+            match measure(&mut i2c, &calibration).await {
+                Ok(reading) if reading.plausible() => {
+                    stats.pass += 1;
+                    if stats.pass % 10 == 0 {
+                        info!(
+                            "{} C, {} hPa, {} %RH; {} passed, {} failed",
+                            reading.temperature as f32 / 100.0,
+                            reading.pressure as f32 / 25600.0,
+                            reading.humidity as f32 / 1024.0,
+                            stats.pass,
+                            stats.fail
+                        );
+                    }
+                }
+                Ok(reading) => {
+                    stats.fail += 1;
+                    warn!("implausible reading {}", reading);
+                }
+                Err(e) => {
+                    stats.fail += 1;
+                    warn!("measurement failed: {}", e);
+                    break;
+                }
+            }
+            Timer::after_millis(100).await;
+        }
+    }
+}
+
+/// Check the chip, reset it and read its calibration, counting the outcome
+async fn setup<I: I2c>(i2c: &mut I, stats: &mut Stats) -> Option<Calibration>
+where
+    I::Error: Format,
+{
+    match try_setup(i2c).await {
+        Ok(Some(calibration)) => {
+            stats.pass += 1;
+            info!("BME280 found, calibration read");
+            Some(calibration)
+        }
+        Ok(None) => {
+            stats.fail += 1;
+            None
+        }
+        Err(e) => {
+            stats.fail += 1;
+            warn!("setup failed: {}", e);
+            None
+        }
+    }
+}
+
+/// `Ok(None)` when the device answers but is not a working BME280
+async fn try_setup<I: I2c>(i2c: &mut I) -> Result<Option<Calibration>, I::Error> {
+    let id = read_register(i2c, REG_CHIP_ID).await?;
+    if id != CHIP_ID {
+        warn!("chip ID {=u8:#04x}, expected {=u8:#04x}", id, CHIP_ID);
+        return Ok(None);
+    }
+
+    i2c.write(ADDRESS, &[REG_RESET, RESET_WORD]).await?;
+    Timer::after_millis(3).await;
+    while read_register(i2c, REG_STATUS).await? & STATUS_IM_UPDATE != 0 {
+        Timer::after_millis(1).await;
+    }
+
+    // Read twice; a bit slipped at fast-mode timing shows up as a difference
+    let first = read_calibration(i2c).await?;
+    let second = read_calibration(i2c).await?;
+    if first != second {
+        warn!("calibration reads differ: {} / {}", first, second);
+        return Ok(None);
+    }
+    Ok(Some(Calibration::parse(&first)))
+}
+
+/// Both calibration blocks, 0x88..=0xA1 then 0xE1..=0xE7
+async fn read_calibration<I: I2c>(i2c: &mut I) -> Result<[u8; 33], I::Error> {
+    let mut raw = [0u8; 33];
+    let (low, high) = raw.split_at_mut(26);
+    i2c.write_read(ADDRESS, &[REG_CALIB_00], low).await?;
+    i2c.write_read(ADDRESS, &[REG_CALIB_26], high).await?;
+    Ok(raw)
+}
+
+async fn read_register<I: I2c>(i2c: &mut I, register: u8) -> Result<u8, I::Error> {
+    let mut value = [0u8];
+    i2c.write_read(ADDRESS, &[register], &mut value).await?;
+    Ok(value[0])
+}
+
+/// One forced-mode conversion
+async fn measure<I: I2c>(i2c: &mut I, calibration: &Calibration) -> Result<Reading, I::Error> {
+    // ctrl_hum only takes effect with the ctrl_meas write after it
+    i2c.write(ADDRESS, &[REG_CTRL_HUM, CTRL_HUM]).await?;
+    i2c.write(ADDRESS, &[REG_CTRL_MEAS, CTRL_MEAS_FORCED]).await?;
+    // About 8 ms at x1 oversampling
+    Timer::after_millis(8).await;
+    while read_register(i2c, REG_STATUS).await? & STATUS_MEASURING != 0 {
+        Timer::after_millis(1).await;
+    }
+
+    let mut raw = [0u8; 8];
+    i2c.write_read(ADDRESS, &[REG_PRESS_MSB], &mut raw).await?;
+    let adc_p = ((raw[0] as i32) << 12) | ((raw[1] as i32) << 4) | ((raw[2] as i32) >> 4);
+    let adc_t = ((raw[3] as i32) << 12) | ((raw[4] as i32) << 4) | ((raw[5] as i32) >> 4);
+    let adc_h = ((raw[6] as i32) << 8) | raw[7] as i32;
+    Ok(calibration.compensate(adc_t, adc_p, adc_h))
+}
+
+/// Scan the bus every 250 ms and report devices coming and going
+async fn scanner<I: I2c>(mut i2c: I)
+where
+    I::Error: Format,
+{
+    let mut last = None;
+    loop {
+        match i2c::scan(&mut i2c).await {
+            Ok(found) if last != Some(found) => {
+                info!("bus: {}", found);
+                if !found.contains(ADDRESS) {
+                    warn!("BME280 not answering at {=u8:#04x}", ADDRESS);
+                }
+                last = Some(found);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("scan failed: {}", e);
+                last = None;
+            }
+        }
+        Timer::after_millis(250).await;
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    pass: u32,
+    fail: u32,
+}
+
+/// Compensated values in the datasheet's fixed-point units
+#[derive(Format)]
+struct Reading {
+    /// 0.01 °C
+    temperature: i32,
+    /// Pa in Q24.8
+    pressure: u32,
+    /// %RH in Q22.10
+    humidity: u32,
+}
+
+impl Reading {
+    /// Within the sensor's operating range
+    fn plausible(&self) -> bool {
+        (-4000..=8500).contains(&self.temperature)
+            && (300 * 100 * 256..=1100 * 100 * 256).contains(&self.pressure)
+            && self.humidity <= 100 * 1024
+    }
+}
+
+/// Trimming parameters, named as in the datasheet
+struct Calibration {
+    t1: u16,
+    t2: i16,
+    t3: i16,
+    p1: u16,
+    p: [i16; 8],
+    h1: u8,
+    h2: i16,
+    h3: u8,
+    h4: i16,
+    h5: i16,
+    h6: i8,
+}
+
+impl Calibration {
+    fn parse(raw: &[u8; 33]) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([raw[i], raw[i + 1]]);
+        let i16_at = |i: usize| i16::from_le_bytes([raw[i], raw[i + 1]]);
+        // 0xE4..=0xE6 pack two signed 12-bit values around a shared nibble
+        let h = &raw[26..];
+        Self {
+            t1: u16_at(0),
+            t2: i16_at(2),
+            t3: i16_at(4),
+            p1: u16_at(6),
+            p: core::array::from_fn(|n| i16_at(8 + 2 * n)),
+            h1: raw[25],
+            h2: i16::from_le_bytes([h[0], h[1]]),
+            h3: h[2],
+            h4: ((h[3] as i8 as i16) << 4) | (h[4] & 0x0F) as i16,
+            h5: ((h[5] as i8 as i16) << 4) | (h[4] >> 4) as i16,
+            h6: h[6] as i8,
+        }
+    }
+
+    /// The datasheet's integer compensation formulas
+    fn compensate(&self, adc_t: i32, adc_p: i32, adc_h: i32) -> Reading {
+        let t1 = self.t1 as i32;
+        let var1 = (((adc_t >> 3) - (t1 << 1)) * self.t2 as i32) >> 11;
+        let var2 = (((((adc_t >> 4) - t1) * ((adc_t >> 4) - t1)) >> 12) * self.t3 as i32) >> 14;
+        let t_fine = var1 + var2;
+
+        Reading {
+            temperature: (t_fine * 5 + 128) >> 8,
+            pressure: self.pressure(t_fine, adc_p),
+            humidity: self.humidity(t_fine, adc_h),
+        }
+    }
+
+    fn pressure(&self, t_fine: i32, adc_p: i32) -> u32 {
+        let [p2, p3, p4, p5, p6, p7, p8, p9] = self.p.map(i64::from);
+        let mut var1 = t_fine as i64 - 128_000;
+        let mut var2 = var1 * var1 * p6;
+        var2 += (var1 * p5) << 17;
+        var2 += p4 << 35;
+        var1 = ((var1 * var1 * p3) >> 8) + ((var1 * p2) << 12);
+        var1 = (((1i64 << 47) + var1) * self.p1 as i64) >> 33;
+        if var1 == 0 {
+            return 0;
+        }
+        let mut p = 1_048_576 - adc_p as i64;
+        p = (((p << 31) - var2) * 3125) / var1;
+        let var1 = (p9 * (p >> 13) * (p >> 13)) >> 25;
+        let var2 = (p8 * p) >> 19;
+        (((p + var1 + var2) >> 8) + (p7 << 4)) as u32
+    }
+
+    fn humidity(&self, t_fine: i32, adc_h: i32) -> u32 {
+        let x = t_fine - 76_800;
+        let x = (((adc_h << 14) - ((self.h4 as i32) << 20) - (self.h5 as i32 * x) + 16_384) >> 15)
+            * (((((((x * self.h6 as i32) >> 10) * (((x * self.h3 as i32) >> 11) + 32_768)) >> 10) + 2_097_152)
+                * self.h2 as i32
+                + 8192)
+                >> 14);
+        let x = x - (((((x >> 15) * (x >> 15)) >> 7) * self.h1 as i32) >> 4);
+        (x.clamp(0, 419_430_400) >> 12) as u32
+    }
+}
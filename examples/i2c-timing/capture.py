@@ -0,0 +1,111 @@
+#!/usr/bin/env python3
+"""Check I2C SCL timing from a logic analyzer capture.
+
+Captures SCL with sigrok-cli, or reads a saved capture, and measures each
+clock cycle in the bursts of the i2c-timing example. Cycles are grouped by
+the nearest standard speed. For each speed the script reports the frequency
+and the shortest tLOW and tHIGH, and checks them against the I2C
+specification (UM10204, table 10). It exits with status 1 on a violation.
+
+    python3 capture.py --driver fx2lafw --samplerate 24m
+    python3 capture.py --input capture.sr --samplerate 24m --scl D0
+
+The analyzer switches at its own logic threshold, not at the 30 % / 70 %
+points of the specification. Slow edges therefore shift tLOW and tHIGH by
+up to the rise time. Sample at 24 MHz or more for 1 MHz buses.
+"""
+
+import argparse
+import subprocess
+import sys
+
+# Nominal frequency, minimum tLOW and tHIGH in ns
+MODES = {
+    "standard": (100_000, 4_700, 4_000),
+    "fast": (400_000, 1_300, 600),
+    "fast-plus": (1_000_000, 500, 260),
+}
+# Longest cycle taken as part of a transfer; longer ones are gaps between them
+MAX_CYCLE_S = 50e-6
+
+
+def parse_rate(text):
+    """Sample rate with an optional k/m suffix, as sigrok-cli takes it."""
+    scale = {"k": 1e3, "m": 1e6}.get(text[-1].lower(), 1)
+    return float(text.rstrip("kKmM")) * scale
+
+
+def capture(args):
+    """Run sigrok-cli and return the SCL level of every sample."""
+    cmd = ["sigrok-cli", "-C", args.scl, "-O", "csv:header=false"]
+    if args.input:
+        cmd += ["-i", args.input]
+    else:
+        cmd += ["-d", args.driver, "--config", f"samplerate={args.samplerate}", "--time", str(args.time)]
+    out = subprocess.run(cmd, check=True, capture_output=True, text=True).stdout
+    return [line.rsplit(",", 1)[-1].strip() == "1" for line in out.splitlines() if line.strip()]
+
+
+def cycles(levels, rate):
+    """(period, high, low) in seconds for every full SCL cycle, rising edge to rising edge."""
+    rises, falls = [], []
+    for i in range(1, len(levels)):
+        if levels[i] != levels[i - 1]:
+            (rises if levels[i] else falls).append(i)
+    fi = 0
+    for start, end in zip(rises, rises[1:]):
+        while fi < len(falls) and falls[fi] < start:
+            fi += 1
+        if fi == len(falls) or falls[fi] > end:
+            continue
+        period = (end - start) / rate
+        if period <= MAX_CYCLE_S:
+            yield period, (falls[fi] - start) / rate, (end - falls[fi]) / rate
+
+
+def nearest_mode(frequency):
+    return min(MODES, key=lambda m: abs(MODES[m][0] - frequency) / MODES[m][0])
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__, formatter_class=argparse.RawDescriptionHelpFormatter)
+    source = parser.add_mutually_exclusive_group(required=True)
+    source.add_argument("--driver", help="sigrok driver to capture with, e.g. fx2lafw")
+    source.add_argument("--input", help="saved capture to read instead")
+    parser.add_argument("--samplerate", default="24m", help="sample rate, e.g. 24m (default)")
+    parser.add_argument("--time", type=int, default=3000, help="capture length in ms (default 3000)")
+    parser.add_argument("--scl", default="D0", help="SCL channel (default D0)")
+    parser.add_argument("--tolerance", type=float, default=2.0, help="allowed overspeed in %% (default 2)")
+    args = parser.parse_args()
+
+    rate = parse_rate(args.samplerate)
+    groups = {}
+    for period, high, low in cycles(capture(args), rate):
+        groups.setdefault(nearest_mode(1 / period), []).append((period, high, low))
+    if not groups:
+        sys.exit("no SCL cycles found; check the channel and that the example is running")
+
+    failed = False
+    for mode, seen in groups.items():
+        nominal, min_low, min_high = MODES[mode]
+        fastest = 1 / min(p for p, _, _ in seen)
+        median = 1 / sorted(p for p, _, _ in seen)[len(seen) // 2]
+        high_ns = min(h for _, h, _ in seen) * 1e9
+        low_ns = min(l for _, _, l in seen) * 1e9
+        problems = []
+        if fastest > nominal * (1 + args.tolerance / 100):
+            problems.append(f"up to {fastest:.0f} Hz")
+        if low_ns < min_low:
+            problems.append(f"tLOW below {min_low} ns")
+        if high_ns < min_high:
+            problems.append(f"tHIGH below {min_high} ns")
+        failed |= bool(problems)
+        print(
+            f"{mode:>9}: {len(seen)} cycles, {median:.0f} Hz median, tLOW >= {low_ns:.0f} ns, "
+            f"tHIGH >= {high_ns:.0f} ns  {'FAIL: ' + ', '.join(problems) if problems else 'ok'}"
+        )
+    sys.exit(1 if failed else 0)
+
+
+if __name__ == "__main__":
+    main()
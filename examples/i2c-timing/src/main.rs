@@ -0,0 +1,74 @@
+//! I2C timing check
+//!
+//! Logs the SCL periods `i2c::Timing` works out for 100 kHz, 400 kHz and
+//! 1 MHz at the actual PCLK. Then it drives PB0 (SCL) / PB1 (SDA) in bursts
+//! at 100 kHz and 400 kHz, so a logic analyzer can measure the bus against
+//! the I2C specification with `capture.py` next to this file:
+//!
+//! ```text
+//! python3 examples/i2c-timing/capture.py --driver fx2lafw --samplerate 24m
+//! ```
+//!
+//! Each burst writes two bytes to 0x55 for a second. No device needs to be
+//! present, because a missing acknowledge still clocks out the whole
+//! address byte. Fit pull-ups, e.g. 2.2 kΩ, so the rise time is realistic.
+//! The bursts come from `soft_i2c`, which stops at 400 kHz, so checking
+//! 1 MHz on the bus waits for a driver for the I2C peripheral.
+
+#![no_std]
+#![no_main]
+
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_ht32f523xx::i2c::{Timing, TimingConfig};
+use embassy_ht32f523xx::rcc;
+use embassy_ht32f523xx::soft_i2c::{self, SoftI2c};
+use embassy_ht32f523xx::time::Hertz;
+use embassy_time::{Instant, Timer};
+use embedded_hal_async::i2c::I2c;
+use {defmt_rtt as _, panic_probe as _};
+
+/// Address the bursts are sent to
+const TARGET: u8 = 0x55;
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_ht32f523xx::init(embassy_ht32f523xx::Config::default());
+
+    let pclk = rcc::get_clocks().apb_clk().to_hz();
+    info!("I2C timing at PCLK {} Hz, worst-case edges", pclk);
+    for config in [TimingConfig::standard(), TimingConfig::fast(), TimingConfig::fast_plus()] {
+        match Timing::calculate(pclk, config) {
+            Ok(t) => info!(
+                "{} Hz: SHPG {} SLPG {}, {} Hz on the bus, tHIGH {} ns, tLOW {} ns",
+                config.frequency.to_hz(),
+                t.shpg,
+                t.slpg,
+                t.frequency_hz,
+                t.high_ns,
+                t.low_ns
+            ),
+            Err(e) => warn!("{} Hz: {}", config.frequency.to_hz(), e),
+        }
+    }
+
+    let mut bus = SoftI2c::new(p.bftm0, p.gpiob.pb0().degrade(), p.gpiob.pb1().degrade(), Default::default());
+    loop {
+        for khz in [100, 400] {
+            let config = soft_i2c::Config {
+                frequency: Hertz::khz(khz),
+                ..Default::default()
+            };
+            bus.set_config(&config);
+            info!("bursts at {} kHz", khz);
+
+            let start = Instant::now();
+            while start.elapsed().as_secs() < 1 {
+                // A missing acknowledge is expected without a device
+                let _ = bus.write(TARGET, &[0xA5, 0x5A]).await;
+                Timer::after_micros(200).await;
+            }
+            Timer::after_millis(500).await;
+        }
+    }
+}
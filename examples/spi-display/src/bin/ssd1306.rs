@@ -0,0 +1,140 @@
+//! SSD1306 frame rate over SPI, polled and PDMA
+//!
+//! Pushes full 128x64 frames (1 KiB) to an SSD1306 in 4-wire SPI mode and
+//! logs frames per second, alternating every 3 s between `Spi::write`, which
+//! polls every byte, and `Spi::write_dma`. With SCK at 8 MHz a frame is
+//! 1 ms on the wire, so the DMA figure should land close to the line rate
+//! and well above the polled one.
+//!
+//! Wiring (SPI0, AF5): PB3 SCK -> D0, PB4 MOSI -> D1, PB6 -> DC, PB7 -> CS,
+//! PB8 -> RES. PB5 is claimed as MISO but not connected.
+
+#![no_std]
+#![no_main]
+
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_ht32f523xx::gpio::{Level, Speed};
+use embassy_ht32f523xx::spi::{self, Spi, Spi0};
+use embassy_ht32f523xx::time::Hertz;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::digital::OutputPin;
+use {defmt_rtt as _, panic_probe as _};
+
+const WIDTH: usize = 128;
+const PAGES: usize = 8;
+const FRAME_LEN: usize = WIDTH * PAGES;
+/// The SSD1306's fastest serial clock
+const SCK: Hertz = Hertz::mhz(8);
+const PHASE: Duration = Duration::from_secs(3);
+
+const INIT: &[u8] = &[
+    0xAE, // display off
+    0xD5, 0x80, // clock divide
+    0xA8, 0x3F, // multiplex, 64 rows
+    0xD3, 0x00, // no display offset
+    0x40, // start line 0
+    0x8D, 0x14, // charge pump on
+    0x20, 0x00, // horizontal addressing, so a frame is one write
+    0xA1, // segment remap
+    0xC8, // COM scan descending
+    0xDA, 0x12, // COM pins
+    0x81, 0xCF, // contrast
+    0xD9, 0xF1, // precharge
+    0xDB, 0x40, // VCOMH
+    0xA4, // show RAM
+    0xA6, // not inverted
+    0xAF, // display on
+];
+/// Whole screen as the write window
+const WINDOW: &[u8] = &[0x21, 0x00, (WIDTH - 1) as u8, 0x22, 0x00, (PAGES - 1) as u8];
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let mut p = embassy_ht32f523xx::init(embassy_ht32f523xx::Config::default());
+    info!("SSD1306 frame rate");
+
+    let config = spi::Config {
+        frequency: SCK,
+        ..Default::default()
+    };
+    let spi = Spi::new(
+        p.spi0,
+        p.gpiob.pb3().into_alternate_function::<5>(),
+        p.gpiob.pb4().into_alternate_function::<5>(),
+        p.gpiob.pb5().into_alternate_function::<5>(),
+        config,
+    )
+    .with_dma();
+    let mut display = Ssd1306 {
+        spi,
+        dc: p.gpiob.pb6().into_push_pull_output(Level::Low, Speed::High),
+        cs: p.gpiob.pb7().into_push_pull_output(Level::High, Speed::High),
+    };
+    let mut reset = p.gpiob.pb8().into_push_pull_output(Level::Low, Speed::Low);
+    Timer::after_millis(1).await;
+    reset.set_high().unwrap();
+    Timer::after_millis(1).await;
+    unwrap!(display.command(INIT).await);
+
+    let mut frame = [0u8; FRAME_LEN];
+    let mut n = 0u32;
+    for dma in [false, true].into_iter().cycle() {
+        let start = Instant::now();
+        let mut frames = 0u32;
+        while start.elapsed() < PHASE {
+            draw(&mut frame, n);
+            n = n.wrapping_add(1);
+            unwrap!(display.frame(&frame, dma).await);
+            frames += 1;
+        }
+
+        let us = start.elapsed().as_micros() as u32;
+        let bytes = frames * FRAME_LEN as u32;
+        info!(
+            "{}: {} frames/s, {} kB/s, {}% of SCK",
+            if dma { "dma" } else { "polled" },
+            frames as f32 * 1e6 / us as f32,
+            bytes / (us / 1000).max(1),
+            (bytes as u64 * 8 * 100 / (SCK.to_hz() as u64 * us as u64 / 1_000_000).max(1)) as u32
+        );
+    }
+}
+
+/// Scrolling diagonal stripes, so dropped or torn frames are visible
+fn draw(frame: &mut [u8; FRAME_LEN], n: u32) {
+    for (i, byte) in frame.iter_mut().enumerate() {
+        let x = (i % WIDTH) as u32;
+        let page = (i / WIDTH) as u32;
+        *byte = (0..8).fold(0, |acc, bit| {
+            let y = page * 8 + bit;
+            let on = (x + y).wrapping_add(n) / 8 % 2 == 0;
+            acc | ((on as u8) << bit)
+        });
+    }
+}
+
+struct Ssd1306<'d, DC, CS> {
+    spi: Spi<'d, Spi0>,
+    dc: DC,
+    cs: CS,
+}
+
+impl<DC: OutputPin, CS: OutputPin> Ssd1306<'_, DC, CS> {
+    async fn command(&mut self, bytes: &[u8]) -> Result<(), spi::Error> {
+        self.dc.set_low().ok();
+        self.cs.set_low().ok();
+        let result = self.spi.write(bytes).await;
+        self.cs.set_high().ok();
+        result
+    }
+
+    async fn frame(&mut self, frame: &[u8], dma: bool) -> Result<(), spi::Error> {
+        self.command(WINDOW).await?;
+        self.dc.set_high().ok();
+        self.cs.set_low().ok();
+        let result = if dma { self.spi.write_dma(frame).await } else { self.spi.write(frame).await };
+        self.cs.set_high().ok();
+        result
+    }
+}
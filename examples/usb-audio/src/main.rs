@@ -0,0 +1,194 @@
+//! USB audio (UAC1) speaker
+//!
+//! Enumerates as a 48 kHz mono speaker and plays the stream on PA4
+//! (GPTM1 CH0) through `pwm_audio`; filter the pin with an RC low-pass into
+//! a small amplifier such as a PAM8302. Endpoints are at most 64 bytes, so
+//! samples are 8 bit: 48 per frame, 49 when the host catches up.
+//!
+//! The PWM runs off the board's clock, not the host's, so the two drift
+//! apart. The explicit feedback endpoint reports the rate PWM actually
+//! consumes samples at, measured against the host's frame counter, plus a
+//! correction that steers the ring buffer back to half full. Once a second
+//! the example logs packets received, frames without a packet and ring
+//! underruns and overruns; on a healthy link the first is 1000 and the
+//! others stay at 0 after the first second.
+//!
+//! Both isochronous endpoints are double buffered: the next packet lands
+//! in the endpoint's second buffer while the task still works on the last
+//! one. The once-a-second report is logged between two reads on purpose,
+//! so a frame without a packet right after each report means the ping-pong
+//! is not working.
+//!
+//! Play something with `aplay -D plughw:CARD=Speaker` or pick "HT32 Speaker"
+//! as the output device.
+
+#![no_std]
+#![no_main]
+
+use core::cell::RefCell;
+
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_futures::join::join3;
+use embassy_ht32f523xx::pwm_audio::{PwmAudio, RING_LEN};
+use embassy_ht32f523xx::time::Hertz;
+use embassy_ht32f523xx::timer::Channel;
+use embassy_ht32f523xx::usb::{self, assert_endpoint_budget, Config as UsbConfig, Driver, EndpointBudget};
+use embassy_usb::class::uac1;
+use embassy_usb::class::uac1::speaker::{Speaker, State, Volume};
+use embassy_usb::Builder;
+use static_cell::StaticCell;
+use {defmt_rtt as _, panic_probe as _};
+
+const SAMPLE_RATE_HZ: u32 = 48_000;
+const SAMPLES_PER_FRAME: u32 = SAMPLE_RATE_HZ / 1000;
+/// Room for the host sending an extra sample now and then
+const MAX_PACKET_SIZE: u16 = SAMPLES_PER_FRAME as u16 + 8;
+
+/// Ring fill the feedback steers towards, in samples
+const TARGET_FILL: i32 = RING_LEN as i32 / 2;
+/// 10.14 fixed point, the full-speed feedback format
+const FEEDBACK_ONE: i32 = 1 << 14;
+
+const _: () = assert_endpoint_budget(&[EndpointBudget::uac1_speaker(MAX_PACKET_SIZE)]);
+
+static CHANNELS: [uac1::Channel; 1] = [uac1::Channel::CenterFront];
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_ht32f523xx::init(embassy_ht32f523xx::Config::default());
+    info!("USB audio speaker");
+
+    let pin = p.gpioa.pa4().into_alternate_function::<4>();
+    let audio = RefCell::new(PwmAudio::new(p.timer1, pin, Channel::Ch0, Hertz::hz(SAMPLE_RATE_HZ)));
+
+    let driver = Driver::new(p.usb, UsbConfig::default());
+    let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("HT32 Speaker");
+    config.serial_number = Some("12345678");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+
+    static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+    static STATE: StaticCell<State> = StaticCell::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        CONFIG_DESCRIPTOR.init([0; 256]),
+        BOS_DESCRIPTOR.init([0; 256]),
+        &mut [],
+        CONTROL_BUF.init([0; 64]),
+    );
+    let (mut stream, mut feedback, control) = Speaker::new(
+        &mut builder,
+        STATE.init(State::new()),
+        MAX_PACKET_SIZE,
+        uac1::SampleWidth::Width1Byte,
+        &[SAMPLE_RATE_HZ],
+        &CHANNELS,
+        uac1::FeedbackRefresh::Period8Frames,
+    );
+    let mut usb = builder.build();
+
+    let playback = async {
+        let mut packet = [0u8; MAX_PACKET_SIZE as usize];
+        let mut stats = Stats::default();
+        let mut last_frame = None;
+        loop {
+            stream.wait_connection().await;
+            info!("streaming");
+            loop {
+                let len = match stream.read_packet(&mut packet).await {
+                    Ok(len) => len,
+                    Err(_) => break,
+                };
+                let muted = matches!(control.volume(uac1::Channel::CenterFront), Some(Volume::Muted));
+                for sample in &mut packet[..len] {
+                    // UAC1 8-bit PCM is signed; the PWM wants offset binary
+                    *sample = if muted { 0x80 } else { *sample ^ 0x80 };
+                }
+
+                let mut audio = audio.borrow_mut();
+                let written = audio.write(&packet[..len]);
+                stats.overruns += (len - written) as u32;
+
+                // Frames elapsed against packets received shows OUT packets that never arrived
+                let frame = usb::frame_number();
+                if let Some(last) = last_frame {
+                    stats.frames += (frame.wrapping_sub(last) & 0x7FF) as u32;
+                }
+                last_frame = Some(frame);
+
+                // A packet arriving while this reports waits in the stream
+                // endpoint's other buffer
+                stats.packets += 1;
+                if stats.packets == 1000 {
+                    stats.report(audio.underruns(), audio.buffered());
+                    stats = Stats::default();
+                }
+            }
+            info!("stream stopped");
+            last_frame = None;
+            audio.borrow_mut().clear();
+        }
+    };
+
+    let rate_feedback = async {
+        loop {
+            feedback.wait_connection().await;
+            let mut frame = usb::frame_number();
+            let mut played = audio.borrow().samples_played();
+            let mut rate = SAMPLES_PER_FRAME as i32 * FEEDBACK_ONE;
+            loop {
+                // Samples per frame as measured, smoothed over about 16 refresh periods
+                let (now_frame, now_played, fill) = {
+                    let audio = audio.borrow();
+                    (usb::frame_number(), audio.samples_played(), audio.buffered() as i32)
+                };
+                let frames = (now_frame.wrapping_sub(frame) & 0x7FF) as i32;
+                if frames > 0 {
+                    let measured = (now_played.wrapping_sub(played) as i32) * FEEDBACK_ONE / frames;
+                    rate += (measured - rate) / 16;
+                    frame = now_frame;
+                    played = now_played;
+                }
+
+                // Ask for up to 1/8 sample per frame more while the ring is below half, less above
+                let correction = (TARGET_FILL - fill) * (FEEDBACK_ONE / 8) / TARGET_FILL;
+                let value = (rate + correction) as u32;
+                if feedback.write_packet(&value.to_le_bytes()[..3]).await.is_err() {
+                    break;
+                }
+            }
+        }
+    };
+
+    join3(usb.run(), playback, rate_feedback).await;
+}
+
+/// Streaming counters for one second of packets
+#[derive(Default)]
+struct Stats {
+    packets: u32,
+    /// Frames since the packet before this window
+    frames: u32,
+    overruns: u32,
+}
+
+impl Stats {
+    fn report(&self, underruns: u32, fill: usize) {
+        let missed = self.frames.saturating_sub(self.packets);
+        if missed == 0 && self.overruns == 0 {
+            info!("{} packets, ring {}/{}, {} underruns total", self.packets, fill, RING_LEN, underruns);
+        } else {
+            warn!(
+                "{} packets, {} frames missed, {} samples overrun, ring {}/{}, {} underruns total",
+                self.packets, missed, self.overruns, fill, RING_LEN, underruns
+            );
+        }
+    }
+}
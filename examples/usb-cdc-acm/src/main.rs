@@ -0,0 +1,124 @@
+//! USB CDC-ACM echo example
+//!
+//! Enumerates as a virtual serial port and echoes every packet back to the host.
+//! Line coding (`SET_LINE_CODING`) and control-line state (`SET_CONTROL_LINE_STATE`)
+//! changes are logged as they arrive on EP0.
+//!
+//! It also implements the Arduino-style "1200 bps touch": opening the port at
+//! 1200 baud and then dropping DTR resets the board into the factory ISP bootloader.
+
+#![no_std]
+#![no_main]
+
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_futures::select::{select, Either};
+use embassy_ht32f523xx::power;
+use embassy_ht32f523xx::usb::{assert_endpoint_budget, Config as UsbConfig, Driver, EndpointBudget};
+use embassy_time::Timer;
+use embassy_usb::class::cdc_acm::{CdcAcmClass, ControlChanged, Receiver, Sender, State};
+use embassy_usb::driver::EndpointError;
+use embassy_usb::Builder;
+use static_cell::StaticCell;
+use {defmt_rtt as _, panic_probe as _};
+
+/// Baud rate that requests a reset into the ISP bootloader when DTR drops
+const TOUCH_BAUD_RATE: u32 = 1200;
+
+// One CDC-ACM function with 64-byte packets
+const _: () = assert_endpoint_budget(&[EndpointBudget::cdc_acm(64)]);
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    info!("Starting USB CDC-ACM example");
+
+    let p = embassy_ht32f523xx::init(embassy_ht32f523xx::Config::default());
+
+    // Create the USB driver
+    let driver = Driver::new(p.usb, UsbConfig::default());
+
+    // Create embassy-usb Config
+    let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("HT32 CDC-ACM");
+    config.serial_number = Some("12345678");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+
+    // Required buffers for USB
+    static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+    static STATE: StaticCell<State> = StaticCell::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        CONFIG_DESCRIPTOR.init([0; 256]),
+        BOS_DESCRIPTOR.init([0; 256]),
+        &mut [], // no msos descriptors
+        CONTROL_BUF.init([0; 64]),
+    );
+
+    let class = CdcAcmClass::new(&mut builder, STATE.init(State::new()), 64);
+    let (mut sender, mut receiver, control) = class.split_with_control();
+
+    // Build the USB device
+    let mut usb = builder.build();
+
+    info!("Starting USB device and serial tasks");
+
+    join(usb.run(), serial(&mut sender, &mut receiver, &control)).await;
+}
+
+/// Echo every packet back to the host and react to control-line changes
+async fn serial<'d>(
+    sender: &mut Sender<'d, Driver<'d>>,
+    receiver: &mut Receiver<'d, Driver<'d>>,
+    control: &ControlChanged<'d>,
+) {
+    let mut buf = [0u8; 64];
+
+    receiver.wait_connection().await;
+    info!("Host opened the port");
+
+    loop {
+        match select(receiver.read_packet(&mut buf), control.control_changed()).await {
+            Either::First(Ok(n)) => {
+                if sender.write_packet(&buf[..n]).await.is_err() {
+                    warn!("Echo failed");
+                }
+            }
+            Either::First(Err(EndpointError::BufferOverflow)) => {
+                warn!("Packet too large, dropped");
+            }
+            Either::First(Err(EndpointError::Disabled)) => {
+                info!("Host closed the port");
+                receiver.wait_connection().await;
+                info!("Host opened the port");
+            }
+            Either::Second(()) => on_control_changed(receiver).await,
+        }
+    }
+}
+
+/// Log line coding / control-line changes and implement the 1200 bps touch
+async fn on_control_changed<'d>(receiver: &Receiver<'d, Driver<'d>>) {
+    let coding = receiver.line_coding();
+    let dtr = receiver.dtr();
+    info!(
+        "Line state: {} baud, DTR={}, RTS={}",
+        coding.data_rate(),
+        dtr,
+        receiver.rts()
+    );
+
+    if coding.data_rate() == TOUCH_BAUD_RATE && !dtr {
+        info!("1200 bps touch detected, resetting into ISP");
+
+        // Give the host time to see the status stage of SET_CONTROL_LINE_STATE
+        Timer::after_millis(50).await;
+        power::reset_into_isp();
+    }
+}
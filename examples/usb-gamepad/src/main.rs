@@ -0,0 +1,166 @@
+//! USB HID gamepad with analog sticks on the ADC
+//!
+//! Two sticks on PA0..PA3 (ADC channels 0..3) and eight buttons on PB0..PB7
+//! (to ground, internal pull-ups) are sent as a four-axis, eight-button
+//! gamepad. The interrupt endpoint asks for a 10 ms polling interval rather
+//! than the usual 1 ms; once a second the example logs how many reports the
+//! host actually collected and warns if that is not close to 100, which
+//! shows whether the interval made it through the endpoint setup.
+//!
+//! Check it on the host with `jstest-gtk`, `evtest` or a browser gamepad tester.
+
+#![no_std]
+#![no_main]
+
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_ht32f523xx::adc::{Adc, MAX_VALUE};
+use embassy_ht32f523xx::gpio::{AnyPin, Pull};
+use embassy_ht32f523xx::usb::{assert_endpoint_budget, Config as UsbConfig, Driver, EndpointBudget};
+use embassy_time::{Duration, Instant};
+use embassy_usb::class::hid::{Config as HidConfig, HidWriter, State};
+use embassy_usb::Builder;
+use embedded_hal::digital::InputPin;
+use static_cell::StaticCell;
+use {defmt_rtt as _, panic_probe as _};
+
+/// Requested polling interval
+const POLL_MS: u8 = 10;
+/// Report rate accepted as matching [`POLL_MS`], per second
+const RATE_MIN: u32 = 1000 / POLL_MS as u32 * 9 / 10;
+const RATE_MAX: u32 = 1000 / POLL_MS as u32 * 11 / 10;
+
+/// Buttons, then X, Y, Rx, Ry as 16-bit little-endian
+const REPORT_SIZE: usize = 9;
+
+const _: () = assert_endpoint_budget(&[EndpointBudget::hid(REPORT_SIZE as u16, None)]);
+
+/// Eight buttons and four 12-bit axes
+const GAMEPAD_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, //       Usage Page (Generic Desktop)
+    0x09, 0x05, //       Usage (Gamepad)
+    0xA1, 0x01, //       Collection (Application)
+    0x05, 0x09, //         Usage Page (Button)
+    0x19, 0x01, //         Usage Minimum (1)
+    0x29, 0x08, //         Usage Maximum (8)
+    0x15, 0x00, //         Logical Minimum (0)
+    0x25, 0x01, //         Logical Maximum (1)
+    0x75, 0x01, //         Report Size (1)
+    0x95, 0x08, //         Report Count (8)
+    0x81, 0x02, //         Input (Data, Var, Abs)
+    0x05, 0x01, //         Usage Page (Generic Desktop)
+    0x09, 0x30, //         Usage (X)
+    0x09, 0x31, //         Usage (Y)
+    0x09, 0x33, //         Usage (Rx)
+    0x09, 0x34, //         Usage (Ry)
+    0x15, 0x00, //         Logical Minimum (0)
+    0x26, 0xFF, 0x0F, //   Logical Maximum (4095)
+    0x75, 0x10, //         Report Size (16)
+    0x95, 0x04, //         Report Count (4)
+    0x81, 0x02, //         Input (Data, Var, Abs)
+    0xC0, //             End Collection
+];
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let mut p = embassy_ht32f523xx::init(embassy_ht32f523xx::Config::default());
+    info!("USB gamepad");
+
+    // Stick inputs in analog mode, buttons as pulled-up inputs
+    let _sticks = (
+        p.gpioa.pa0().into_alternate_function::<1>(),
+        p.gpioa.pa1().into_alternate_function::<1>(),
+        p.gpioa.pa2().into_alternate_function::<1>(),
+        p.gpioa.pa3().into_alternate_function::<1>(),
+    );
+    let mut buttons: [AnyPin; 8] = core::array::from_fn(|pin| {
+        let mut button = AnyPin::new('B', pin as u8);
+        button.set_as_input(Pull::Up);
+        button
+    });
+    // Stick potentiometers are ~10 kΩ; give the sample capacitor time to settle
+    p.adc.set_sample_time(32);
+
+    let driver = Driver::new(p.usb, UsbConfig::default());
+    let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("HT32 Gamepad");
+    config.serial_number = Some("12345678");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+
+    static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+    static STATE: StaticCell<State> = StaticCell::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        CONFIG_DESCRIPTOR.init([0; 256]),
+        BOS_DESCRIPTOR.init([0; 256]),
+        &mut [],
+        CONTROL_BUF.init([0; 64]),
+    );
+    let mut gamepad = HidWriter::<_, REPORT_SIZE>::new(
+        &mut builder,
+        STATE.init(State::new()),
+        HidConfig {
+            report_descriptor: GAMEPAD_DESCRIPTOR,
+            request_handler: None,
+            poll_ms: POLL_MS,
+            max_packet_size: REPORT_SIZE as u16,
+        },
+    );
+    let mut usb = builder.build();
+
+    let reports = async {
+        gamepad.ready().await;
+        info!("configured, {} ms polling", POLL_MS);
+
+        let mut window_start = Instant::now();
+        let mut sent = 0u32;
+        loop {
+            // Sample right before the write so the host gets fresh values;
+            // the write completes at the host's next poll
+            let report = sample(&mut p.adc, &mut buttons);
+            if gamepad.write(&report).await.is_err() {
+                warn!("report write failed, waiting for the host");
+                gamepad.ready().await;
+                window_start = Instant::now();
+                sent = 0;
+                continue;
+            }
+            sent += 1;
+
+            if window_start.elapsed() >= Duration::from_secs(1) {
+                if (RATE_MIN..=RATE_MAX).contains(&sent) {
+                    info!("{} reports/s", sent);
+                } else {
+                    warn!("{} reports/s, expected about {}", sent, 1000 / POLL_MS as u32);
+                }
+                window_start = Instant::now();
+                sent = 0;
+            }
+        }
+    };
+
+    join(usb.run(), reports).await;
+}
+
+/// Read the sticks and buttons into a report
+fn sample(adc: &mut Adc, buttons: &mut [AnyPin; 8]) -> [u8; REPORT_SIZE] {
+    let mut report = [0u8; REPORT_SIZE];
+    for (bit, button) in buttons.iter_mut().enumerate() {
+        if button.is_low().unwrap_or(false) {
+            report[0] |= 1 << bit;
+        }
+    }
+    for channel in 0..4u8 {
+        let value = adc.read(channel).min(MAX_VALUE);
+        let at = 1 + 2 * channel as usize;
+        report[at..at + 2].copy_from_slice(&value.to_le_bytes());
+    }
+    report
+}
@@ -0,0 +1,109 @@
+#!/usr/bin/env python3
+"""Live plot for the usb-scope example.
+
+Starts a capture on the device, decodes its frames and plots the newest
+samples. Reports throughput and lost frames once a second.
+
+    pip install pyserial matplotlib
+    python3 plot.py /dev/ttyACM0 --channel 0 --rate 40000
+"""
+
+import argparse
+import struct
+import sys
+import time
+from collections import deque
+
+import matplotlib.animation as animation
+import matplotlib.pyplot as plt
+import serial
+
+FRAME_MAGIC = 0x5C0E
+FLAG_OVERRUN = 1 << 0
+HEADER = struct.Struct("<HHHH")
+ADC_MAX = 4095
+
+
+class FrameReader:
+    """Splits the byte stream into frames, resynchronising on the magic."""
+
+    def __init__(self, port):
+        self.port = port
+        self.buf = bytearray()
+        self.last_sequence = None
+        self.frames = 0
+        self.lost = 0
+        self.overruns = 0
+        self.bytes = 0
+
+    def poll(self):
+        self.buf += self.port.read(self.port.in_waiting or 1)
+        frames = []
+        while len(self.buf) >= HEADER.size:
+            magic, sequence, count, flags = HEADER.unpack_from(self.buf)
+            if magic != FRAME_MAGIC:
+                del self.buf[0]
+                continue
+            size = HEADER.size + 2 * count
+            if len(self.buf) < size:
+                break
+            samples = struct.unpack_from(f"<{count}H", self.buf, HEADER.size)
+            del self.buf[:size]
+
+            if self.last_sequence is not None:
+                self.lost += (sequence - self.last_sequence - 1) & 0xFFFF
+            self.last_sequence = sequence
+            self.frames += 1
+            self.bytes += size
+            if flags & FLAG_OVERRUN:
+                self.overruns += 1
+            frames.append(samples)
+        return frames
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("port")
+    parser.add_argument("--channel", type=int, default=0)
+    parser.add_argument("--rate", type=int, default=40_000)
+    parser.add_argument("--window", type=int, default=2048, help="samples shown")
+    args = parser.parse_args()
+
+    port = serial.Serial(args.port, timeout=0.05)
+    port.write(b"S" + bytes([0, args.channel, 0]) + struct.pack("<I", args.rate))
+    reader = FrameReader(port)
+
+    window = deque([0] * args.window, maxlen=args.window)
+    fig, ax = plt.subplots()
+    (line,) = ax.plot(range(args.window), list(window))
+    ax.set_ylim(0, ADC_MAX)
+    ax.set_xlabel(f"sample ({args.rate} Hz)")
+    ax.set_ylabel("ADC counts")
+    stats = {"time": time.monotonic(), "bytes": 0}
+
+    def update(_):
+        for samples in reader.poll():
+            window.extend(samples)
+        line.set_ydata(list(window))
+
+        now = time.monotonic()
+        if now - stats["time"] >= 1.0:
+            rate = (reader.bytes - stats["bytes"]) / (now - stats["time"])
+            print(
+                f"{rate / 1000:.1f} kB/s, {reader.frames} frames, "
+                f"{reader.lost} lost on the host, {reader.overruns} overruns on the device",
+                file=sys.stderr,
+            )
+            stats.update(time=now, bytes=reader.bytes)
+        return (line,)
+
+    _anim = animation.FuncAnimation(fig, update, interval=30, blit=True, cache_frame_data=False)
+    try:
+        plt.show()
+    finally:
+        port.write(b"X" + bytes(7))
+        port.close()
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,67 @@
+//! USB oscilloscope example
+//!
+//! Enumerates as a CDC-ACM port and serves `scope::Scope`: the host picks an
+//! ADC channel and sample rate and receives a continuous stream of sample
+//! frames. Plot them with `plot.py` next to this file:
+//!
+//! ```text
+//! python3 examples/usb-scope/plot.py /dev/ttyACM0 --channel 0 --rate 40000
+//! ```
+//!
+//! GPTM1 paces the conversions; the analog pin must be switched to AF1.
+
+#![no_std]
+#![no_main]
+
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_ht32f523xx::scope::Scope;
+use embassy_ht32f523xx::usb::{Config as UsbConfig, Driver};
+use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
+use embassy_usb::Builder;
+use static_cell::StaticCell;
+use {defmt_rtt as _, panic_probe as _};
+
+/// Samples per frame; 256 samples at 40 kHz is one frame every 6.4 ms
+const BLOCK: usize = 256;
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    info!("Starting USB scope example");
+
+    let mut p = embassy_ht32f523xx::init(embassy_ht32f523xx::Config::default());
+
+    let driver = Driver::new(p.usb, UsbConfig::default());
+
+    let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("HT32 scope");
+    config.serial_number = Some("12345678");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+
+    static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+    static STATE: StaticCell<State> = StaticCell::new();
+    static SAMPLES: StaticCell<[[u16; BLOCK]; 2]> = StaticCell::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        CONFIG_DESCRIPTOR.init([0; 256]),
+        BOS_DESCRIPTOR.init([0; 256]),
+        &mut [],
+        CONTROL_BUF.init([0; 64]),
+    );
+
+    let class = CdcAcmClass::new(&mut builder, STATE.init(State::new()), 64);
+    let (sender, receiver) = class.split();
+    let mut usb = builder.build();
+
+    let mut scope: Scope<'_, BLOCK> = Scope::new(sender, receiver);
+    let samples = SAMPLES.init([[0; BLOCK]; 2]);
+
+    join(usb.run(), scope.run(&mut p.adc, &mut p.timer1, samples)).await;
+}
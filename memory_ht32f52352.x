@@ -4,3 +4,21 @@ MEMORY
   FLASH : ORIGIN = 0x00000000, LENGTH = 128K
   RAM   : ORIGIN = 0x20000000, LENGTH = 16K
 }
+
+/* Firmware identification block (`fw_info`), at a fixed offset after the vectors */
+SECTIONS {
+  .fw_info : ALIGN(4) {
+    KEEP(*(.fw_info));
+  } > FLASH
+} INSERT AFTER .vector_table;
+
+/* RAM-resident code (`ramfunc!`): runs in RAM, loaded after .data, copied by `init()` */
+SECTIONS {
+  .ramfunc : ALIGN(4) {
+    __sramfunc = .;
+    *(.ramfunc .ramfunc.*);
+    . = ALIGN(4);
+    __eramfunc = .;
+  } > RAM AT > FLASH
+  __siramfunc = LOADADDR(.ramfunc);
+} INSERT AFTER .data;
@@ -0,0 +1,274 @@
+//! 12-bit analog-to-digital converter
+//!
+//! One-shot, software-triggered conversions of a single channel. A
+//! conversion takes a couple of microseconds, so [`Adc::read`] polls for the
+//! result. The pin of an external channel must be switched to its analog
+//! function (AF1) first.
+//!
+//! [`AdcStream`] samples one channel continuously at a fixed rate for audio
+//! work: a GPTM update event triggers each conversion and PDMA moves the
+//! results into a ping-pong buffer, so the CPU only sees one wakeup per
+//! half buffer. 40 kHz is comfortably within reach.
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::adc::AdcStream;
+//!
+//! static mut BUF: [[u16; 256]; 2] = [[0; 256]; 2];
+//! let mut stream = AdcStream::start(&mut p.adc, &mut p.timer1, 3, Hertz(32_000), unsafe { &mut *addr_of_mut!(BUF) });
+//! loop {
+//!     let block = stream.next_block().await?;
+//!     tuner.feed(block);
+//! }
+//! ```
+//!
+//! The PAC's ADC view is incomplete, so registers are accessed by address.
+
+use core::future::poll_fn;
+use core::marker::PhantomData;
+use core::task::Poll;
+
+use crate::dma;
+use crate::regs::{Mmio, RegisterAccess};
+use crate::time::Hertz;
+use crate::timer;
+
+const ADC_BASE: usize = 0x4001_0000;
+const ADC_CR: usize = 0x000;
+const ADC_LST0: usize = 0x004;
+const ADC_STR: usize = 0x020;
+const ADC_DR0: usize = 0x030;
+const ADC_TCR: usize = 0x070;
+const ADC_TSR: usize = 0x074;
+const ADC_PDMAR: usize = 0x0A0;
+
+// ADCCR bits; ADMODE = 0 (one-shot) and SEQL = 0 (one channel)
+const CR_ADCEN: u32 = 1 << 7;
+const CR_ADRST: u32 = 1 << 6;
+// ADCTCR: software or GPTM trigger source
+const TCR_ADSW: u32 = 1 << 0;
+const TCR_GPTM: u32 = 1 << 2;
+// ADCTSR: software start; GPTM select in [18:16], GPTM event in [26:24] (0 = MTO)
+const TSR_ADSC: u32 = 1 << 0;
+const TSR_GPTMS_SHIFT: u32 = 16;
+// ADCPDMAR: DMA request after each single conversion
+const PDMAR_ADSPDMA: u32 = 1 << 0;
+// ADCDRn: data valid
+const DR_ADVLD: u32 = 1 << 31;
+
+// CKCU APBCFGR ADCDIV field
+const APBCFGR_ADCDIV_SHIFT: u32 = 16;
+const APBCFGR_ADCDIV_MASK: u32 = 0b111 << APBCFGR_ADCDIV_SHIFT;
+
+/// Highest conversion result
+pub const MAX_VALUE: u16 = 4095;
+
+// GPTM MDCFR master mode: MTO follows the update event
+const MDCFR_MMSEL_SHIFT: u32 = 16;
+const MDCFR_MMSEL_MASK: u32 = 0b111 << MDCFR_MMSEL_SHIFT;
+const MMSEL_UPDATE: u32 = 0b010;
+
+/// PDMA channel wired to the ADC's request line; shared with SPI0 RX
+const DMA_CHANNEL: usize = 0;
+
+/// Highest ADC clock the converter is specified for
+const MAX_ADC_CLOCK_HZ: u32 = 16_000_000;
+
+/// Analog-to-digital converter
+pub struct Adc {
+    enabled: bool,
+}
+
+impl Adc {
+    pub(crate) fn new() -> Self {
+        Self { enabled: false }
+    }
+
+    fn enable(&mut self) {
+        let ckcu = unsafe { &*crate::pac::Ckcu::ptr() };
+
+        // Divide PCLK down to the ADC's limit (ADCDIV: 0 = /1 .. 6 = /64)
+        let pclk = crate::rcc::get_clocks().apb_clk().to_hz();
+        let mut div = 0u32;
+        while (pclk >> div) > MAX_ADC_CLOCK_HZ && div < 6 {
+            div += 1;
+        }
+        ckcu.apbcfgr().modify(|r, w| unsafe { w.bits((r.bits() & !APBCFGR_ADCDIV_MASK) | div << APBCFGR_ADCDIV_SHIFT) });
+        ckcu.apbccr1().modify(|_, w| w.adcen().set_bit());
+
+        Mmio.write(ADC_BASE + ADC_CR, CR_ADRST);
+        Mmio.write(ADC_BASE + ADC_CR, CR_ADCEN);
+        Mmio.write(ADC_BASE + ADC_TCR, TCR_ADSW);
+        self.enabled = true;
+    }
+
+    /// Extra sampling time in ADC clocks on top of the minimum 1.5
+    ///
+    /// Sources with more than a few kΩ output impedance, such as voltage
+    /// dividers, need a longer sampling time to settle.
+    pub fn set_sample_time(&mut self, cycles: u8) {
+        if !self.enabled {
+            self.enable();
+        }
+        Mmio.write(ADC_BASE + ADC_STR, cycles as u32);
+    }
+
+    /// Convert `channel` (0..=11 external, higher for the internal references)
+    pub fn read(&mut self, channel: u8) -> u16 {
+        if !self.enabled {
+            self.enable();
+        }
+
+        Mmio.write(ADC_BASE + ADC_LST0, (channel & 0x1F) as u32);
+        // Reading the data register clears its valid flag
+        let _ = Mmio.read(ADC_BASE + ADC_DR0);
+        Mmio.write(ADC_BASE + ADC_TSR, TSR_ADSC);
+
+        loop {
+            let dr = Mmio.read(ADC_BASE + ADC_DR0);
+            if dr & DR_ADVLD != 0 {
+                return (dr & 0xFFF) as u16;
+            }
+        }
+    }
+
+    /// Convert `channel` and scale to millivolts against a `vref_mv` reference (VDDA)
+    pub fn read_millivolts(&mut self, channel: u8, vref_mv: u32) -> u32 {
+        self.read(channel) as u32 * vref_mv / MAX_VALUE as u32
+    }
+}
+
+/// Streaming error
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// A half buffer was overwritten before [`AdcStream::next_block`] returned it
+    Overrun,
+    /// The PDMA transfer failed
+    Transfer,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Error::Overrun => "ADC stream overrun",
+            Error::Transfer => "ADC stream DMA error",
+        })
+    }
+}
+
+impl core::error::Error for Error {}
+
+/// Timer-paced, DMA-fed continuous conversion of one channel
+///
+/// The buffer is split into two halves of `N` samples; while the PDMA fills
+/// one, [`next_block`](Self::next_block) hands out the other. The stream
+/// uses PDMA channel 0, so SPI0 cannot use DMA while it runs.
+pub struct AdcStream<'a, T: timer::Instance, const N: usize> {
+    _adc: &'a mut Adc,
+    _timer: PhantomData<&'a mut T>,
+    buf: *mut [[u16; N]; 2],
+    _buf: PhantomData<&'a mut [[u16; N]; 2]>,
+    /// Half expected next
+    next: usize,
+}
+
+impl<'a, T: timer::Instance, const N: usize> AdcStream<'a, T, N> {
+    const CHECK_N: () = assert!(N > 0 && 2 * N <= 0xFFFF, "2 * N samples must fit one PDMA transfer");
+
+    /// Start sampling `channel` at `sample_rate` into `buf`
+    pub fn start(adc: &'a mut Adc, _timer: &'a mut T, channel: u8, sample_rate: Hertz, buf: &'a mut [[u16; N]; 2]) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::CHECK_N;
+
+        dma::init();
+        dma::claim(DMA_CHANNEL, "ADC");
+
+        if !adc.enabled {
+            adc.enable();
+        }
+        T::enable_clock();
+        let regs = T::regs();
+        regs.gptm_ctr().modify(|_, w| w.tme().clear_bit());
+
+        // Trigger on GPTM0 or GPTM1 MTO, one conversion and one DMA request each
+        let gptm = if core::ptr::eq(regs, unsafe { &*crate::pac::Gptm1::ptr() }) { 1 } else { 0 };
+        Mmio.write(ADC_BASE + ADC_LST0, (channel & 0x1F) as u32);
+        let _ = Mmio.read(ADC_BASE + ADC_DR0);
+        Mmio.write(ADC_BASE + ADC_TSR, gptm << TSR_GPTMS_SHIFT);
+        Mmio.write(ADC_BASE + ADC_TCR, TCR_GPTM);
+        Mmio.write(ADC_BASE + ADC_PDMAR, PDMAR_ADSPDMA);
+
+        let mut ch = dma::Channel::new(DMA_CHANNEL);
+        let buf: *mut [[u16; N]; 2] = buf;
+        ch.start_circular(
+            (ADC_BASE + ADC_DR0) as *const u8,
+            buf as *mut u8,
+            2 * N,
+            dma::Width::HalfWord,
+        );
+
+        // Update events at the sample rate, prescaled into 16 bits
+        let clock = crate::rcc::get_clocks().apb_clk().to_hz();
+        let ticks = (clock / sample_rate.to_hz().max(1)).max(1);
+        let prescaler = (ticks - 1) / 0x1_0000;
+        let period = ticks / (prescaler + 1) - 1;
+        regs.gptm_pscr().write(|w| unsafe { w.bits(prescaler) });
+        regs.gptm_crr().write(|w| unsafe { w.bits(period) });
+        regs.gptm_mdcfr().modify(|r, w| unsafe {
+            w.bits((r.bits() & !MDCFR_MMSEL_MASK) | MMSEL_UPDATE << MDCFR_MMSEL_SHIFT)
+        });
+        regs.gptm_cntr().reset();
+        regs.gptm_ctr().modify(|_, w| w.tme().set_bit());
+
+        debug!("adc: streaming channel {} at {} Hz", channel, clock / ((prescaler + 1) * (period + 1)));
+        Self {
+            _adc: adc,
+            _timer: PhantomData,
+            buf,
+            _buf: PhantomData,
+            next: 0,
+        }
+    }
+
+    /// Wait for the next half buffer to fill and return it
+    ///
+    /// The block stays valid until the PDMA wraps around to it again, i.e.
+    /// for `N` sample periods. Taking longer than that between calls reports
+    /// [`Error::Overrun`] once and resynchronises to the newest block.
+    pub async fn next_block(&mut self) -> Result<&[u16; N], Error> {
+        let ch = dma::Channel::new(DMA_CHANNEL);
+        let mut seen = 0;
+        let events = poll_fn(|cx| {
+            ch.waker().register(cx.waker());
+            seen |= ch.take_events();
+            if seen != 0 { Poll::Ready(seen) } else { Poll::Pending }
+        })
+        .await;
+
+        if events & dma::EVENT_ERROR != 0 {
+            return Err(Error::Transfer);
+        }
+        let expected = if self.next == 0 { dma::EVENT_HALF } else { dma::EVENT_FULL };
+        let half = self.next;
+        self.next ^= 1;
+        if events != expected {
+            // Both halves completed, or the other one: a block was missed
+            self.next = if events & dma::EVENT_FULL != 0 { 0 } else { 1 };
+            return Err(Error::Overrun);
+        }
+
+        // The PDMA is writing the other half now
+        Ok(unsafe { &(*self.buf)[half] })
+    }
+}
+
+impl<T: timer::Instance, const N: usize> Drop for AdcStream<'_, T, N> {
+    fn drop(&mut self) {
+        T::regs().gptm_ctr().modify(|_, w| w.tme().clear_bit());
+        dma::Channel::new(DMA_CHANNEL).stop();
+        dma::release(DMA_CHANNEL, "ADC");
+        Mmio.write(ADC_BASE + ADC_PDMAR, 0);
+        Mmio.write(ADC_BASE + ADC_TCR, TCR_ADSW);
+        Mmio.write(ADC_BASE + ADC_TSR, 0);
+    }
+}
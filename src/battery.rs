@@ -0,0 +1,213 @@
+//! Battery state of charge from the cell voltage
+//!
+//! Without a gauge IC the charge is estimated from the voltage alone: the
+//! ADC reads the cell through a resistor divider, a moving average smooths
+//! out load steps, and a discharge curve ([`LIPO_CURVE`] by default) maps
+//! the voltage to a percentage. This is good to about ±10 % on a resting
+//! single-cell LiPo, which is what a low-battery indicator needs.
+//!
+//! [`Battery`] samples; [`BatteryStatus`] is the shared result other tasks
+//! read and wait on, usually from a `static`.
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::battery::{Battery, BatteryStatus, Config};
+//!
+//! static STATUS: BatteryStatus = BatteryStatus::new();
+//!
+//! let mut battery = Battery::<8>::new(Config { channel: 3, ..Default::default() });
+//! let monitor = battery.monitor(&mut p.adc, &STATUS, Duration::from_secs(1));
+//! let alert = async {
+//!     loop {
+//!         let percent = STATUS.wait_low().await;
+//!         // blink the caps lock LED ...
+//!     }
+//! };
+//! join(monitor, alert).await;
+//! ```
+
+use core::cell::Cell;
+
+use critical_section::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+
+use crate::adc::Adc;
+
+/// Percent above the low threshold that clears the low state, e.g. while charging
+const LOW_HYSTERESIS: u8 = 5;
+
+/// Single-cell LiPo at light load: (millivolts, percent), highest voltage first
+pub const LIPO_CURVE: &[(u16, u8)] = &[
+    (4200, 100),
+    (4150, 95),
+    (4110, 90),
+    (4080, 85),
+    (4020, 80),
+    (3980, 70),
+    (3950, 60),
+    (3910, 50),
+    (3870, 40),
+    (3850, 30),
+    (3840, 20),
+    (3820, 15),
+    (3800, 10),
+    (3770, 5),
+    (3730, 2),
+    (3600, 0),
+];
+
+/// Battery measurement configuration
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// ADC channel the divider is connected to
+    pub channel: u8,
+    /// Divider resistor from the battery to the ADC pin, in ohms
+    pub r_top: u32,
+    /// Divider resistor from the ADC pin to ground, in ohms
+    pub r_bottom: u32,
+    /// ADC reference (VDDA) in millivolts
+    pub vref_mv: u32,
+    /// Discharge curve: (millivolts, percent), highest voltage first
+    pub curve: &'static [(u16, u8)],
+    /// At or below this percentage the battery counts as low
+    pub low_percent: u8,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            channel: 0,
+            r_top: 100_000,
+            r_bottom: 100_000,
+            vref_mv: 3300,
+            curve: LIPO_CURVE,
+            low_percent: 10,
+        }
+    }
+}
+
+/// Map a cell voltage to a percentage by linear interpolation on `curve`
+pub fn percent_from_curve(mv: u32, curve: &[(u16, u8)]) -> u8 {
+    let Some(&(top_mv, top_pct)) = curve.first() else { return 0 };
+    if mv >= top_mv as u32 {
+        return top_pct;
+    }
+
+    for pair in curve.windows(2) {
+        let (hi_mv, hi_pct) = (pair[0].0 as u32, pair[0].1 as u32);
+        let (lo_mv, lo_pct) = (pair[1].0 as u32, pair[1].1 as u32);
+        if mv >= lo_mv {
+            if hi_mv == lo_mv {
+                return hi_pct as u8;
+            }
+            return (lo_pct + (mv - lo_mv) * (hi_pct - lo_pct) / (hi_mv - lo_mv)) as u8;
+        }
+    }
+    curve.last().map_or(0, |&(_, pct)| pct)
+}
+
+/// Latest estimate, shared between the sampling task and its readers
+pub struct BatteryStatus {
+    reading: Mutex<Cell<Option<(u32, u8)>>>,
+    low: Mutex<Cell<bool>>,
+    low_signal: Signal<CriticalSectionRawMutex, u8>,
+}
+
+impl BatteryStatus {
+    /// No reading yet; usable in a `static`
+    pub const fn new() -> Self {
+        Self {
+            reading: Mutex::new(Cell::new(None)),
+            low: Mutex::new(Cell::new(false)),
+            low_signal: Signal::new(),
+        }
+    }
+
+    /// Filtered battery voltage in millivolts
+    pub fn millivolts(&self) -> Option<u32> {
+        critical_section::with(|cs| self.reading.borrow(cs).get()).map(|(mv, _)| mv)
+    }
+
+    /// Estimated state of charge
+    pub fn percent(&self) -> Option<u8> {
+        critical_section::with(|cs| self.reading.borrow(cs).get()).map(|(_, pct)| pct)
+    }
+
+    /// Whether the battery is currently low
+    pub fn is_low(&self) -> bool {
+        critical_section::with(|cs| self.low.borrow(cs).get())
+    }
+
+    /// Wait until the battery becomes low; returns the percentage at that point
+    ///
+    /// Fires once per drop below the threshold, not on every sample while low.
+    pub async fn wait_low(&self) -> u8 {
+        self.low_signal.wait().await
+    }
+
+    fn publish(&self, mv: u32, percent: u8, low_percent: u8) {
+        critical_section::with(|cs| {
+            self.reading.borrow(cs).set(Some((mv, percent)));
+
+            let low = self.low.borrow(cs);
+            if !low.get() && percent <= low_percent {
+                low.set(true);
+                self.low_signal.signal(percent);
+            } else if low.get() && percent >= low_percent.saturating_add(LOW_HYSTERESIS) {
+                low.set(false);
+            }
+        });
+    }
+}
+
+impl Default for BatteryStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Battery sampler averaging the last `N` readings
+pub struct Battery<const N: usize> {
+    config: Config,
+    window: [u32; N],
+    next: usize,
+    filled: usize,
+}
+
+impl<const N: usize> Battery<N> {
+    /// Create a sampler; nothing is read until the first [`sample`](Self::sample)
+    pub fn new(config: Config) -> Self {
+        assert!(N > 0, "the averaging window needs at least one sample");
+        Self {
+            config,
+            window: [0; N],
+            next: 0,
+            filled: 0,
+        }
+    }
+
+    /// Take one reading, update `status` and return the filtered voltage in millivolts
+    pub fn sample(&mut self, adc: &mut Adc, status: &BatteryStatus) -> u32 {
+        let pin_mv = adc.read_millivolts(self.config.channel, self.config.vref_mv);
+        let mv = (pin_mv as u64 * (self.config.r_top + self.config.r_bottom) as u64 / self.config.r_bottom.max(1) as u64) as u32;
+
+        self.window[self.next] = mv;
+        self.next = (self.next + 1) % N;
+        self.filled = (self.filled + 1).min(N);
+        let average = self.window[..self.filled].iter().sum::<u32>() / self.filled as u32;
+
+        let percent = percent_from_curve(average, self.config.curve);
+        status.publish(average, percent, self.config.low_percent);
+        average
+    }
+
+    /// Sample every `period` forever
+    #[cfg(feature = "time")]
+    pub async fn monitor(&mut self, adc: &mut Adc, status: &BatteryStatus, period: embassy_time::Duration) -> ! {
+        let mut ticker = embassy_time::Ticker::every(period);
+        loop {
+            self.sample(adc, status);
+            ticker.next().await;
+        }
+    }
+}
@@ -0,0 +1,275 @@
+//! Charlieplexed LED matrix
+//!
+//! `N` GPIOs drive `N * (N - 1)` LEDs, one between every ordered pair of
+//! pins: with pin `a` high, pin `c` low and every other pin floating, only
+//! the LED from `a` to `c` lights. [`Charlieplex`] scans the anodes from a
+//! GPTM update interrupt, lighting up to `N - 1` LEDs at a time, so six pins
+//! give a 30-LED status panel on a board with no spare driver chip:
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::charlieplex::Charlieplex;
+//! use embassy_ht32f523xx::time::Hertz;
+//!
+//! let pins = [pa0.degrade(), pa1.degrade(), pa2.degrade(), pa3.degrade()];
+//! let mut leds = Charlieplex::new(p.timer1, pins, Hertz::hz(200));
+//! leds.set(Charlieplex::<Timer1, 4>::led(0, 1), 255);
+//! leds.set(Charlieplex::<Timer1, 4>::led(2, 0), 16);
+//! ```
+//!
+//! Brightness is 8-bit bit angle modulation (BAM): each anode's slot is
+//! split into eight planes lasting 1, 2, 4 ... 128 time units, and an LED is
+//! lit in the planes of its brightness bits. That is eight interrupts per
+//! anode however many LEDs are lit, against 256 for PWM steps. Brightness is
+//! linear in on-time, so apply a gamma curve for perceptually even steps.
+//!
+//! Each LED is lit at most `1 / N` of the time, so run them near their
+//! rated current. Fit one resistor per pin: an LED then sees two in series,
+//! and the anode pin sources up to `N - 1` LEDs at once. Pins are switched
+//! to input (floating) between planes, so no LED ghosts from the previous
+//! one; the handler owns the direction registers of the ports it uses, so
+//! do not reconfigure other pins of those ports while it runs.
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+
+use crate::gpio::{self, AnyPin, Pull};
+use crate::peripheral::{Peri, Peripheral};
+use crate::time::Hertz;
+use crate::timer::{self, Instance};
+
+/// Maximum number of pins driven by one [`Charlieplex`]
+pub const MAX_PINS: usize = 8;
+
+/// LEDs on [`MAX_PINS`] pins
+const MAX_LEDS: usize = MAX_PINS * (MAX_PINS - 1);
+
+/// Brightness bits, one plane each
+const PLANES: u32 = 8;
+
+/// Time units per anode slot, the sum of all plane lengths
+const UNITS_PER_SLOT: u32 = (1 << PLANES) - 1;
+
+/// Longest unit in timer ticks, so the longest plane fits the 16-bit counter
+const MAX_UNIT_TICKS: u32 = u16::MAX as u32 >> (PLANES - 1);
+
+/// Number of GPIO ports (A..=D)
+const PORTS: usize = 4;
+
+/// Per-port pin masks
+type PortMasks = [u16; PORTS];
+
+fn port_index(port: char) -> usize {
+    (port as u8 - b'A') as usize
+}
+
+fn port_name(index: usize) -> char {
+    (b'A' + index as u8) as char
+}
+
+struct State {
+    /// Port index and pin number of each matrix pin
+    pins: [(u8, u8); MAX_PINS],
+    len: usize,
+    /// Matrix pins on each port
+    all: PortMasks,
+    brightness: [u8; MAX_LEDS],
+    /// Anode and plane shown by the next interrupt
+    anode: usize,
+    plane: u32,
+    /// Timer ticks per time unit
+    unit_ticks: u32,
+}
+
+static STATE: Mutex<RefCell<Option<State>>> = Mutex::new(RefCell::new(None));
+
+const fn led_index(len: usize, anode: usize, cathode: usize) -> usize {
+    anode * (len - 1) + if cathode > anode { cathode - 1 } else { cathode }
+}
+
+impl State {
+    /// Float every matrix pin, then light the current anode's LEDs that have
+    /// the current plane's bit set
+    fn show(&self) {
+        for (port, &mask) in self.all.iter().enumerate() {
+            if mask != 0 {
+                gpio::set_port_direction(port_name(port), 0, mask);
+            }
+        }
+
+        let mut cathodes = [0; PORTS];
+        for cathode in (0..self.len).filter(|&c| c != self.anode) {
+            if self.brightness[led_index(self.len, self.anode, cathode)] & (1 << self.plane) != 0 {
+                let (port, pin) = self.pins[cathode];
+                cathodes[port as usize] |= 1 << pin;
+            }
+        }
+        if cathodes == [0; PORTS] {
+            return;
+        }
+
+        let (port, pin) = self.pins[self.anode];
+        let mut anode = [0; PORTS];
+        anode[port as usize] = 1 << pin;
+        for port in 0..PORTS {
+            if anode[port] | cathodes[port] != 0 {
+                gpio::write_port(port_name(port), anode[port], cathodes[port]);
+                gpio::set_port_direction(port_name(port), anode[port] | cathodes[port], 0);
+            }
+        }
+    }
+}
+
+/// Update interrupt: one brightness plane per timer period
+fn on_interrupt<T: Instance>() {
+    let regs = T::regs();
+    // INTSR flags are cleared by writing 0
+    regs.gptm_intsr().write(|w| unsafe { w.bits(!timer::INT_UEV) });
+
+    critical_section::with(|cs| {
+        let mut state = STATE.borrow_ref_mut(cs);
+        let Some(state) = state.as_mut() else { return };
+
+        state.show();
+        regs.gptm_crr().write(|w| unsafe { w.bits((state.unit_ticks << state.plane) - 1) });
+
+        state.plane += 1;
+        if state.plane == PLANES {
+            state.plane = 0;
+            state.anode = (state.anode + 1) % state.len;
+        }
+    });
+}
+
+/// Up to [`MAX_PINS`] GPIOs driving `N * (N - 1)` LEDs with 8-bit brightness
+///
+/// Only one `Charlieplex` can run at a time.
+pub struct Charlieplex<'d, T: Instance, const N: usize> {
+    _timer: Peri<'d, T>,
+    pins: [(char, u8); N],
+}
+
+impl<'d, T: Instance, const N: usize> Charlieplex<'d, T, N> {
+    /// Number of LEDs
+    pub const LEDS: usize = N * (N - 1);
+
+    const CHECK: () = assert!(N >= 2 && N <= MAX_PINS, "Charlieplex drives 2 to MAX_PINS pins");
+
+    /// Index of the LED from `anode` to `cathode`, both indices into the pins
+    ///
+    /// LEDs are numbered anode by anode: `led(0, 1)` is 0, `led(0, 2)` is 1,
+    /// and so on. Panics if the two are the same pin.
+    pub const fn led(anode: usize, cathode: usize) -> usize {
+        assert!(anode != cathode && anode < N && cathode < N, "no LED between these pins");
+        led_index(N, anode, cathode)
+    }
+
+    /// Start scanning `pins` so every LED refreshes at `refresh`, all off
+    ///
+    /// 100 Hz and up avoids visible flicker. The interrupt rate is
+    /// `8 * N * refresh`, and the shortest plane lasts `1 / 255` of an anode
+    /// slot, so very high rates leave the handler no time for the short
+    /// planes. The timer's interrupt handler must be installed (`rt` feature).
+    pub fn new(timer: impl Peripheral<P = T> + 'd, pins: [AnyPin; N], refresh: Hertz) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::CHECK;
+
+        let pins = pins.map(|mut pin| {
+            pin.set_as_input(Pull::None);
+            (pin.port(), pin.pin())
+        });
+        let mut state = State {
+            pins: [(0, 0); MAX_PINS],
+            len: N,
+            all: [0; PORTS],
+            brightness: [0; MAX_LEDS],
+            anode: 0,
+            plane: 0,
+            unit_ticks: 0,
+        };
+        for (slot, &(port, pin)) in state.pins.iter_mut().zip(&pins) {
+            *slot = (port_index(port) as u8, pin);
+            state.all[port_index(port)] |= 1 << pin;
+        }
+
+        T::enable_clock();
+        let mut peri = timer.into_ref();
+        let mut timer = timer::Timer::new(peri.reborrow());
+
+        // Prescale until a unit fits, so the longest plane fits the counter
+        let pclk = crate::rcc::get_clocks().apb_clk().to_hz();
+        let ticks = (pclk / (refresh.to_hz() * N as u32 * UNITS_PER_SLOT)).max(1);
+        let prescaler = ticks.div_ceil(MAX_UNIT_TICKS).min(u16::MAX as u32 + 1);
+        timer.set_prescaler((prescaler - 1) as u16);
+        state.unit_ticks = (ticks / prescaler).clamp(1, MAX_UNIT_TICKS);
+        debug!("charlieplex: {} LEDs, {} ticks per unit, prescaler {}", Self::LEDS, state.unit_ticks, prescaler);
+
+        let regs = T::regs();
+        critical_section::with(|cs| {
+            regs.gptm_crr().write(|w| unsafe { w.bits(state.unit_ticks - 1) });
+            *STATE.borrow_ref_mut(cs) = Some(state);
+        });
+        timer::set_handler::<T>(Some(on_interrupt::<T>));
+        regs.gptm_cntr().reset();
+        regs.gptm_intsr().write(|w| unsafe { w.bits(0) });
+        regs.gptm_dictr().write(|w| unsafe { w.bits(timer::INT_UEV) });
+        regs.gptm_ctr().modify(|_, w| w.tme().set_bit());
+
+        Self { _timer: peri, pins }
+    }
+
+    /// Set the brightness of LED `led`, 0 for off; takes effect at its anode's next slot
+    pub fn set(&mut self, led: usize, brightness: u8) {
+        assert!(led < Self::LEDS, "LED index out of range");
+        critical_section::with(|cs| {
+            if let Some(state) = STATE.borrow_ref_mut(cs).as_mut() {
+                state.brightness[led] = brightness;
+            }
+        });
+    }
+
+    /// Brightness of LED `led`
+    pub fn brightness(&self, led: usize) -> u8 {
+        assert!(led < Self::LEDS, "LED index out of range");
+        critical_section::with(|cs| STATE.borrow_ref(cs).as_ref().map_or(0, |s| s.brightness[led]))
+    }
+
+    /// Set every LED at once, `brightness[i]` for LED `i`; extra entries are ignored
+    pub fn set_all(&mut self, brightness: &[u8]) {
+        let len = brightness.len().min(Self::LEDS);
+        critical_section::with(|cs| {
+            if let Some(state) = STATE.borrow_ref_mut(cs).as_mut() {
+                state.brightness[..len].copy_from_slice(&brightness[..len]);
+            }
+        });
+    }
+
+    /// Turn every LED off
+    pub fn clear(&mut self) {
+        critical_section::with(|cs| {
+            if let Some(state) = STATE.borrow_ref_mut(cs).as_mut() {
+                state.brightness = [0; MAX_LEDS];
+            }
+        });
+    }
+}
+
+impl<T: Instance, const N: usize> Drop for Charlieplex<'_, T, N> {
+    fn drop(&mut self) {
+        let regs = T::regs();
+        regs.gptm_dictr().write(|w| unsafe { w.bits(0) });
+        regs.gptm_ctr().modify(|_, w| w.tme().clear_bit());
+        timer::set_handler::<T>(None);
+        critical_section::with(|cs| STATE.borrow_ref_mut(cs).take());
+
+        let mut all = [0; PORTS];
+        for &(port, pin) in &self.pins {
+            all[port_index(port)] |= 1 << pin;
+        }
+        for (port, &mask) in all.iter().enumerate() {
+            if mask != 0 {
+                gpio::set_port_direction(port_name(port), 0, mask);
+            }
+        }
+    }
+}
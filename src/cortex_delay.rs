@@ -0,0 +1,96 @@
+//! Calibrated busy-wait delays
+//!
+//! `cortex_m::asm::delay(n)` waits at least `n` core cycles, but on the M0+
+//! its loop takes 1.5 cycles per count from zero-wait memory and more with
+//! flash wait states, so counting in sysclk cycles overshoots by a varying
+//! amount. `init()` measures the loop against SysTick once the clocks are
+//! set, and [`delay_us`]/[`delay_ms`] spin for the measured number of counts.
+//!
+//! They need no driver or executor, so they work in interrupt handlers,
+//! fault paths and before the executor starts:
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::cortex_delay;
+//!
+//! cortex_delay::delay_us(125);
+//! ```
+//!
+//! Interrupts taken during the wait extend it. After a clock change the
+//! calibration falls back to the zero-wait loop speed, which can only make
+//! delays longer.
+
+use core::cell::Cell;
+
+use critical_section::Mutex;
+
+use crate::regs::{Mmio, RegisterAccess};
+
+const SYST_CSR: usize = 0xE000_E010;
+const SYST_RVR: usize = 0xE000_E014;
+const SYST_CVR: usize = 0xE000_E018;
+const SYST_CSR_ENABLE_CORE: u32 = 0b101;
+const SYST_MAX: u32 = 0x00FF_FFFF;
+
+/// Core cycles per 256 delay counts from zero-wait memory, the fastest the loop runs
+const ZERO_WAIT_RATIO: u32 = 384;
+/// Delay counts timed by [`calibrate`]
+const CALIBRATION_COUNTS: u32 = 4096;
+
+/// (sysclk in Hz, core cycles per 256 delay counts) measured by [`calibrate`]
+static CALIBRATION: Mutex<Cell<Option<(u32, u32)>>> = Mutex::new(Cell::new(None));
+
+/// Time the delay loop against SysTick at the current sysclk
+///
+/// Borrows SysTick and restores it, so it must not race a SysTick user;
+/// `init()` calls it before anything else can run.
+pub(crate) fn calibrate() {
+    let sys_hz = crate::rcc::get_clocks().sys_clk().to_hz();
+
+    let saved_csr = Mmio.read(SYST_CSR);
+    let saved_rvr = Mmio.read(SYST_RVR);
+    Mmio.write(SYST_RVR, SYST_MAX);
+    Mmio.write(SYST_CVR, 0);
+    Mmio.write(SYST_CSR, SYST_CSR_ENABLE_CORE);
+
+    let cycles = critical_section::with(|_| {
+        let start = Mmio.read(SYST_CVR);
+        cortex_m::asm::delay(CALIBRATION_COUNTS);
+        // SysTick counts down
+        start.wrapping_sub(Mmio.read(SYST_CVR)) & SYST_MAX
+    });
+
+    Mmio.write(SYST_CSR, saved_csr & !SYST_CSR_ENABLE_CORE);
+    Mmio.write(SYST_RVR, saved_rvr);
+    Mmio.write(SYST_CVR, 0);
+    Mmio.write(SYST_CSR, saved_csr);
+
+    // Rounding down errs towards longer delays
+    let ratio = (cycles * 256 / CALIBRATION_COUNTS).max(256);
+    critical_section::with(|cs| CALIBRATION.borrow(cs).set(Some((sys_hz, ratio))));
+    debug!("cortex_delay: {} cycles per 256 counts at {} Hz", ratio, sys_hz);
+}
+
+/// `cortex_m::asm::delay` counts that take one µs at the current sysclk
+pub fn cycles_per_us() -> u32 {
+    let sys_hz = crate::rcc::get_clocks().sys_clk().to_hz();
+    let ratio = match critical_section::with(|cs| CALIBRATION.borrow(cs).get()) {
+        Some((hz, ratio)) if hz == sys_hz => ratio,
+        // Wait states may differ at another clock; no loop runs faster than zero-wait
+        Some((_, ratio)) => ratio.min(ZERO_WAIT_RATIO),
+        None => ZERO_WAIT_RATIO,
+    };
+    (sys_hz as u64 * 256).div_ceil(ratio as u64 * 1_000_000) as u32
+}
+
+/// Spin for at least `us` microseconds
+pub fn delay_us(us: u32) {
+    cortex_m::asm::delay(us.saturating_mul(cycles_per_us()));
+}
+
+/// Spin for at least `ms` milliseconds
+pub fn delay_ms(ms: u32) {
+    let per_ms = 1000 * cycles_per_us();
+    for _ in 0..ms {
+        cortex_m::asm::delay(per_ms);
+    }
+}
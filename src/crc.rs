@@ -0,0 +1,92 @@
+//! Hardware CRC unit
+//!
+//! [`Crc`] computes the standard CRC-32 (IEEE 802.3, as in zlib and
+//! Ethernet) in hardware: words are fed through the data register, a
+//! trailing partial word byte by byte. The unit is configured for the
+//! reflected polynomial with an all-ones seed and a complemented result, so
+//! the checksum matches `crc32fast` and friends on the host.
+//! [`crc16_modbus`](Crc::crc16_modbus) switches it to the Modbus RTU CRC-16.
+//!
+//! The PAC has no CRC view, so registers are accessed by address.
+
+use crate::regs::{Mmio, RegisterAccess};
+
+const CRC_BASE: usize = 0x4008_A000;
+const CRC_CR: usize = 0x000;
+const CRC_SDR: usize = 0x004;
+const CRC_CSR: usize = 0x008;
+const CRC_DR: usize = 0x00C;
+
+// CRCCR: polynomial select in [1:0], then data and checksum bit/byte reversal and complement
+const CR_POLY_CRC16: u32 = 0b01;
+const CR_POLY_CRC32: u32 = 0b10;
+const CR_DATBREV: u32 = 1 << 2;
+const CR_SUMBREV: u32 = 1 << 5;
+const CR_SUMCMPL: u32 = 1 << 7;
+
+const CRC32_SEED: u32 = 0xFFFF_FFFF;
+const CRC16_SEED: u32 = 0xFFFF;
+
+/// CRC-32 of `b"123456789"`, the usual check value
+pub const CHECK_VALUE: u32 = 0xCBF4_3926;
+/// CRC-16/MODBUS of `b"123456789"`
+pub const MODBUS_CHECK_VALUE: u16 = 0x4B37;
+
+/// Hardware CRC unit
+pub struct Crc {
+    _private: (),
+}
+
+impl Crc {
+    pub(crate) fn new() -> Self {
+        Self { _private: () }
+    }
+
+    /// CRC-32 of `bytes`
+    pub fn crc32(&mut self, bytes: &[u8]) -> u32 {
+        self.reset();
+        self.feed(bytes);
+        self.finish()
+    }
+
+    /// CRC-16/MODBUS of `bytes` (reflected 0x8005, seed 0xFFFF)
+    ///
+    /// Modbus RTU sends it low byte first. Leaves the unit in CRC-16 mode;
+    /// [`reset`](Self::reset) goes back to CRC-32.
+    pub fn crc16_modbus(&mut self, bytes: &[u8]) -> u16 {
+        self.start(CR_POLY_CRC16 | CR_DATBREV | CR_SUMBREV, CRC16_SEED);
+        self.feed(bytes);
+        self.finish() as u16
+    }
+
+    /// Start a new checksum; continue with [`feed`](Self::feed)
+    pub fn reset(&mut self) {
+        self.start(CR_POLY_CRC32 | CR_DATBREV | CR_SUMBREV | CR_SUMCMPL, CRC32_SEED);
+    }
+
+    fn start(&mut self, cr: u32, seed: u32) {
+        let ckcu = unsafe { &*crate::pac::Ckcu::ptr() };
+        ckcu.ahbccr().modify(|_, w| w.crcen().set_bit());
+
+        Mmio.write(CRC_BASE + CRC_CR, cr);
+        // Writing the seed restarts the calculation
+        Mmio.write(CRC_BASE + CRC_SDR, seed);
+    }
+
+    /// Add `bytes` to the running checksum
+    pub fn feed(&mut self, bytes: &[u8]) {
+        let mut words = bytes.chunks_exact(4);
+        for word in &mut words {
+            Mmio.write(CRC_BASE + CRC_DR, u32::from_le_bytes([word[0], word[1], word[2], word[3]]));
+        }
+        // The data register also takes byte writes, reversed within the byte
+        for &byte in words.remainder() {
+            unsafe { core::ptr::write_volatile((CRC_BASE + CRC_DR) as *mut u8, byte) };
+        }
+    }
+
+    /// Checksum of everything fed since [`reset`](Self::reset)
+    pub fn finish(&mut self) -> u32 {
+        Mmio.read(CRC_BASE + CRC_CSR)
+    }
+}
@@ -0,0 +1,56 @@
+//! Busy-wait delays
+//!
+//! `Delay` spins the CPU for a number of core clock cycles, so it works before
+//! the time driver is running and inside panic or fault handlers. It is only as
+//! accurate as the configured system clock and is extended by interrupts.
+
+use embedded_hal::delay::DelayNs;
+
+/// Cycle-counting delay provider
+#[derive(Debug, Copy, Clone)]
+pub struct Delay {
+    sys_clk_hz: u32,
+}
+
+impl Delay {
+    /// Create a delay using the current system clock
+    pub fn new() -> Self {
+        Self {
+            sys_clk_hz: crate::rcc::get_clocks().sys_clk().to_hz(),
+        }
+    }
+
+    /// Create a delay for a known system clock, e.g. before `init()` has run
+    pub const fn with_sys_clk(sys_clk_hz: u32) -> Self {
+        Self { sys_clk_hz }
+    }
+
+    /// Spin for `cycles` core clock cycles
+    pub fn delay_cycles(&mut self, cycles: u32) {
+        cortex_m::asm::delay(cycles);
+    }
+}
+
+impl Default for Delay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DelayNs for Delay {
+    fn delay_ns(&mut self, ns: u32) {
+        let cycles = (ns as u64 * self.sys_clk_hz as u64).div_ceil(1_000_000_000);
+        self.delay_cycles(cycles.min(u32::MAX as u64) as u32);
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        let cycles = us as u64 * (self.sys_clk_hz as u64 / 1_000_000).max(1);
+        self.delay_cycles(cycles.min(u32::MAX as u64) as u32);
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        for _ in 0..ms {
+            self.delay_us(1_000);
+        }
+    }
+}
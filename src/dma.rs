@@ -0,0 +1,449 @@
+//! Peripheral DMA (PDMA) support for HT32F523xx
+//!
+//! The PDMA controller has 6 channels. Each transfer moves `block_count` blocks
+//! of `block_len` data units, where a data unit is 8, 16 or 32 bits wide.
+//!
+//! Software-triggered memory-to-memory copies move USB packets in and out of
+//! EP_SRAM. Peripheral-paced transfers run one unit per request from a
+//! peripheral whose DMA enable is set; the request lines are wired to fixed
+//! channels (SPI0 RX/TX on 0/1, SPI1 RX/TX on 2/3).
+//!
+//! Circular transfers with auto-reload raise half- and full-transfer
+//! interrupts, which the interrupt handler latches per channel for
+//! [`Channel::take_events`] and wakes the channel's waker; ADC streaming
+//! uses them for ping-pong buffers. One-shot paced transfers can report
+//! their end the same way after [`Channel::listen`].
+//!
+//! Several request lines share a channel (the ADC and SPI0 RX both use 0),
+//! so every driver claims its channels in a registry when it is created: an
+//! ADC stream, a logic capture and an SPI built `with_dma` release them when
+//! dropped, while the USB driver keeps its EP_SRAM copier channel for good
+//! once the copier passes its probe. A second user panics with both names
+//! instead of silently taking over the channel. Applications that program
+//! the PDMA themselves should [`reserve`] their channels at boot the same way:
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::dma::{self, Priority};
+//!
+//! dma::reserve(4, "audio out")?;
+//! dma::set_priority(4, Priority::VeryHigh);
+//! ```
+//!
+//! When several channels have a request pending, the one with the highest
+//! [`Priority`] is served first, and the lower channel number among equals.
+//! All channels start at [`Priority::Low`].
+//!
+//! The PAC does not model the per-channel register array in a way that can be
+//! indexed, so channels are addressed through their documented offsets.
+
+use core::cell::Cell;
+use core::sync::atomic::{Ordering, compiler_fence};
+
+use critical_section::Mutex;
+use embassy_sync::waitqueue::AtomicWaker;
+
+use crate::pac;
+use crate::regs::{Mmio, RegisterAccess};
+
+/// Number of PDMA channels
+pub const CHANNEL_COUNT: usize = 6;
+
+// PDMA register layout
+const PDMA_BASE: usize = 0x4009_0000;
+const CH_STRIDE: usize = 0x18;
+const CH_CR: usize = 0x00;
+const CH_SADR: usize = 0x04;
+const CH_DADR: usize = 0x08;
+const CH_TSR: usize = 0x10;
+const PDMA_ISR: usize = 0x120;
+const PDMA_ISCR: usize = 0x128;
+const PDMA_IER: usize = 0x130;
+
+// PDMACHnCR bits
+const CR_CHEN: u32 = 1 << 0;
+const CR_DWIDTH_SHIFT: u32 = 1;
+const CR_DSTAINC: u32 = 1 << 3;
+const CR_SRCAINC: u32 = 1 << 5;
+const CR_CHPRI_SHIFT: u32 = 8;
+const CR_AUTORL: u32 = 1 << 11;
+const CR_SWTRIG: u32 = 1 << 23;
+
+// Per-channel interrupt flags, 5 bits per channel in PDMAISR/PDMAISCR
+const FLAG_GE: u32 = 1 << 0; // Global event
+const FLAG_BE: u32 = 1 << 1; // Block end
+const FLAG_HT: u32 = 1 << 2; // Half transfer
+const FLAG_TC: u32 = 1 << 3; // Transfer complete
+const FLAG_TE: u32 = 1 << 4; // Transfer error
+const FLAG_ALL: u32 = FLAG_GE | FLAG_BE | FLAG_HT | FLAG_TC | FLAG_TE;
+
+/// Flag polls before [`Channel::blocking_copy`] gives up
+///
+/// A full 255-word block takes a few thousand cycles; this allows about a
+/// hundred times that, so only a PDMA that never signals runs into it.
+const COPY_POLL_LIMIT: u32 = 50_000;
+
+/// Events latched by the interrupt handler, see [`Channel::take_events`]
+pub(crate) const EVENT_HALF: u32 = FLAG_HT;
+pub(crate) const EVENT_FULL: u32 = FLAG_TC;
+pub(crate) const EVENT_ERROR: u32 = FLAG_TE;
+
+/// Per-channel events seen by the interrupt handler and not yet taken
+static EVENTS: Mutex<Cell<[u32; CHANNEL_COUNT]>> = Mutex::new(Cell::new([0; CHANNEL_COUNT]));
+static WAKERS: [AtomicWaker; CHANNEL_COUNT] = [const { AtomicWaker::new() }; CHANNEL_COUNT];
+/// Current user of each channel, see [`reserve`]
+static OWNERS: Mutex<Cell<[Option<&'static str>; CHANNEL_COUNT]>> = Mutex::new(Cell::new([None; CHANNEL_COUNT]));
+static PRIORITIES: Mutex<Cell<[Priority; CHANNEL_COUNT]>> = Mutex::new(Cell::new([Priority::Low; CHANNEL_COUNT]));
+
+/// Transfer data unit width
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Width {
+    Byte,
+    HalfWord,
+    Word,
+}
+
+impl Width {
+    fn bits(self) -> u32 {
+        match self {
+            Width::Byte => 0,
+            Width::HalfWord => 1,
+            Width::Word => 2,
+        }
+    }
+}
+
+/// Channel priority in PDMA arbitration
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Priority {
+    Low,
+    Medium,
+    High,
+    VeryHigh,
+}
+
+impl Priority {
+    /// CHPRI field value
+    fn bits(self) -> u32 {
+        self as u32
+    }
+}
+
+/// PDMA error
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The bus rejected an access to the source or destination address
+    Transfer,
+    /// A blocking copy did not complete in time
+    Timeout,
+    /// The channel is reserved by another user, named here
+    Reserved(&'static str),
+    /// No such channel
+    Channel,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Transfer => f.write_str("PDMA transfer error"),
+            Error::Timeout => f.write_str("PDMA transfer timed out"),
+            Error::Reserved(owner) => write!(f, "PDMA channel reserved by {}", owner),
+            Error::Channel => f.write_str("no such PDMA channel"),
+        }
+    }
+}
+
+impl core::error::Error for Error {}
+
+/// Enable the PDMA controller clock
+pub fn init() {
+    let ckcu = unsafe { &*pac::Ckcu::ptr() };
+    ckcu.ahbccr().modify(|_, w| w.pdmaen().set_bit());
+}
+
+/// Reserve `channel` for `owner`, which is named in the error or panic of any other user
+///
+/// Reserving a channel again under the same name succeeds.
+pub fn reserve(channel: usize, owner: &'static str) -> Result<(), Error> {
+    if channel >= CHANNEL_COUNT {
+        return Err(Error::Channel);
+    }
+    critical_section::with(|cs| {
+        let owners = OWNERS.borrow(cs);
+        let mut all = owners.get();
+        match all[channel] {
+            Some(current) if current != owner => Err(Error::Reserved(current)),
+            _ => {
+                all[channel] = Some(owner);
+                owners.set(all);
+                Ok(())
+            }
+        }
+    })
+}
+
+/// Give up a reservation made with [`reserve`]; does nothing if `owner` does not hold `channel`
+pub fn release(channel: usize, owner: &'static str) {
+    critical_section::with(|cs| {
+        let owners = OWNERS.borrow(cs);
+        let mut all = owners.get();
+        if all.get(channel).copied().flatten() == Some(owner) {
+            all[channel] = None;
+            owners.set(all);
+        }
+    });
+}
+
+/// Who holds `channel`, if anyone
+pub fn owner(channel: usize) -> Option<&'static str> {
+    critical_section::with(|cs| OWNERS.borrow(cs).get().get(channel).copied().flatten())
+}
+
+/// [`reserve`] for the HAL's own drivers, panicking on a conflict
+pub(crate) fn claim(channel: usize, owner: &'static str) {
+    if let Err(e) = reserve(channel, owner) {
+        match e {
+            Error::Reserved(current) => {
+                panic!("PDMA channel {} wanted by {} is reserved by {}", channel, owner, current)
+            }
+            _ => panic!("{} wants PDMA channel {}: {}", owner, channel, e),
+        }
+    }
+}
+
+/// Set the arbitration priority of `channel`, from the next transfer it starts
+pub fn set_priority(channel: usize, priority: Priority) {
+    assert!(channel < CHANNEL_COUNT, "no such PDMA channel");
+    critical_section::with(|cs| {
+        let priorities = PRIORITIES.borrow(cs);
+        let mut all = priorities.get();
+        all[channel] = priority;
+        priorities.set(all);
+    });
+}
+
+/// Arbitration priority of `channel`, `None` if there is no such channel
+pub fn priority(channel: usize) -> Option<Priority> {
+    critical_section::with(|cs| PRIORITIES.borrow(cs).get().get(channel).copied())
+}
+
+/// Raw access to a single PDMA channel
+pub(crate) struct Channel {
+    index: usize,
+}
+
+impl Channel {
+    /// The caller is responsible for not sharing `index` with another user,
+    /// normally by holding it with [`claim`]
+    pub(crate) const fn new(index: usize) -> Self {
+        Self { index }
+    }
+
+    fn reg(&self, offset: usize) -> usize {
+        PDMA_BASE + self.index * CH_STRIDE + offset
+    }
+
+    fn flags(&self) -> u32 {
+        (Mmio.read(PDMA_BASE + PDMA_ISR) >> (self.index * 5)) & FLAG_ALL
+    }
+
+    fn clear_flags(&self) {
+        Mmio.write(PDMA_BASE + PDMA_ISCR, FLAG_ALL << (self.index * 5));
+    }
+
+    /// Channel enable and priority bits for a new transfer
+    fn enable_bits(&self) -> u32 {
+        let priority = critical_section::with(|cs| PRIORITIES.borrow(cs).get()[self.index]);
+        CR_CHEN | priority.bits() << CR_CHPRI_SHIFT
+    }
+
+    /// Start a peripheral-paced transfer of `count` units, one per request
+    ///
+    /// The address that is not incremented is the peripheral data register.
+    /// Poll [`Channel::poll`] for completion and call [`Channel::stop`] after.
+    pub(crate) fn start_paced(
+        &mut self,
+        src: *const u8,
+        dst: *mut u8,
+        count: usize,
+        width: Width,
+        src_inc: bool,
+        dst_inc: bool,
+    ) {
+        debug_assert!(count <= 0xFFFF);
+
+        self.clear_flags();
+        Mmio.write(self.reg(CH_CR), 0);
+        Mmio.write(self.reg(CH_SADR), src as u32);
+        Mmio.write(self.reg(CH_DADR), dst as u32);
+        // `count` blocks of one unit, so each request moves one unit
+        Mmio.write(self.reg(CH_TSR), ((count as u32 & 0xFFFF) << 16) | 1);
+
+        let mut cr = self.enable_bits() | (width.bits() << CR_DWIDTH_SHIFT);
+        if src_inc {
+            cr |= CR_SRCAINC;
+        }
+        if dst_inc {
+            cr |= CR_DSTAINC;
+        }
+        Mmio.write(self.reg(CH_CR), cr);
+    }
+
+    /// `Some` once the transfer started with [`Channel::start_paced`] has ended
+    pub(crate) fn poll(&self) -> Option<Result<(), Error>> {
+        let flags = self.flags();
+        if flags & FLAG_TE != 0 {
+            Some(Err(Error::Transfer))
+        } else if flags & FLAG_TC != 0 {
+            Some(Ok(()))
+        } else {
+            None
+        }
+    }
+
+    /// Disable the channel and its interrupts and clear its flags
+    pub(crate) fn stop(&mut self) {
+        Mmio.write(self.reg(CH_CR), 0);
+        Mmio.modify(PDMA_BASE + PDMA_IER, |v| v & !(FLAG_ALL << (self.index * 5)));
+        self.clear_flags();
+    }
+
+    /// Start a peripheral-paced circular transfer into `dst`, reloading after `count` units
+    ///
+    /// The half- and full-transfer interrupts are enabled; take them with
+    /// [`Channel::take_events`] after [`Channel::waker`] fires. Call
+    /// [`Channel::stop`] to end the transfer.
+    pub(crate) fn start_circular(&mut self, src: *const u8, dst: *mut u8, count: usize, width: Width) {
+        debug_assert!(count >= 2 && count <= 0xFFFF);
+
+        self.stop();
+        Mmio.write(self.reg(CH_SADR), src as u32);
+        Mmio.write(self.reg(CH_DADR), dst as u32);
+        Mmio.write(self.reg(CH_TSR), ((count as u32 & 0xFFFF) << 16) | 1);
+        self.enable_interrupts(FLAG_HT | FLAG_TC | FLAG_TE);
+        let cr = self.enable_bits() | CR_AUTORL | (width.bits() << CR_DWIDTH_SHIFT) | CR_DSTAINC;
+        Mmio.write(self.reg(CH_CR), cr);
+    }
+
+    /// Report the end of a [`Channel::start_paced`] transfer through [`Channel::take_events`]
+    pub(crate) fn listen(&mut self) {
+        self.enable_interrupts(FLAG_TC | FLAG_TE);
+    }
+
+    fn enable_interrupts(&mut self, flags: u32) {
+        critical_section::with(|cs| {
+            let events = EVENTS.borrow(cs);
+            let mut all = events.get();
+            all[self.index] = 0;
+            events.set(all);
+        });
+        Mmio.modify(PDMA_BASE + PDMA_IER, |v| v | flags << (self.index * 5));
+
+        let irq = if self.index < 2 { pac::Interrupt::PDMA_CH0_1 } else { pac::Interrupt::PDMA_CH2_5 };
+        unsafe { cortex_m::peripheral::NVIC::unmask(irq) };
+    }
+
+    /// Events latched since the last call (`EVENT_*` bits)
+    pub(crate) fn take_events(&self) -> u32 {
+        critical_section::with(|cs| {
+            let events = EVENTS.borrow(cs);
+            let mut all = events.get();
+            let taken = all[self.index];
+            all[self.index] = 0;
+            events.set(all);
+            taken
+        })
+    }
+
+    /// Woken by the interrupt handler when this channel latches an event
+    pub(crate) fn waker(&self) -> &'static AtomicWaker {
+        &WAKERS[self.index]
+    }
+
+    /// Copy `count` units from `src` to `dst` with a software trigger and wait for completion
+    ///
+    /// Both addresses must be aligned to `width`, and `count` must fit in one block (255 units).
+    /// Returns [`Error::Timeout`] with the channel stopped if neither completion nor an error
+    /// is flagged within [`COPY_POLL_LIMIT`] polls.
+    pub(crate) fn blocking_copy(&mut self, src: *const u8, dst: *mut u8, count: usize, width: Width) -> Result<(), Error> {
+        debug_assert!(count <= 0xFF);
+        if count == 0 {
+            return Ok(());
+        }
+
+        self.clear_flags();
+        Mmio.write(self.reg(CH_CR), 0);
+        Mmio.write(self.reg(CH_SADR), src as u32);
+        Mmio.write(self.reg(CH_DADR), dst as u32);
+        // One block of `count` units (BLKCNT = 1, BLKLEN = count)
+        Mmio.write(self.reg(CH_TSR), (1 << 16) | (count as u32 & 0xFF));
+
+        // Stores to `src` land before the PDMA reads it
+        compiler_fence(Ordering::Release);
+        let cr = self.enable_bits() | (width.bits() << CR_DWIDTH_SHIFT) | CR_SRCAINC | CR_DSTAINC;
+        Mmio.write(self.reg(CH_CR), cr);
+        Mmio.write(self.reg(CH_CR), cr | CR_SWTRIG);
+
+        let mut result = Err(Error::Timeout);
+        for _ in 0..COPY_POLL_LIMIT {
+            let flags = self.flags();
+            if flags & FLAG_TE != 0 {
+                result = Err(Error::Transfer);
+                break;
+            }
+            if flags & FLAG_TC != 0 {
+                result = Ok(());
+                break;
+            }
+        }
+
+        Mmio.write(self.reg(CH_CR), 0);
+        self.clear_flags();
+        // The caller reads what the PDMA wrote
+        compiler_fence(Ordering::Acquire);
+        result
+    }
+}
+
+/// PDMA interrupt handler body for `channels`
+///
+/// Latches and clears the enabled flags of each channel and wakes it.
+fn on_interrupt(channels: core::ops::Range<usize>) {
+    let enabled = Mmio.read(PDMA_BASE + PDMA_IER);
+    let pending = Mmio.read(PDMA_BASE + PDMA_ISR) & enabled;
+
+    for index in channels {
+        let flags = (pending >> (index * 5)) & FLAG_ALL;
+        if flags == 0 {
+            continue;
+        }
+        Mmio.write(PDMA_BASE + PDMA_ISCR, flags << (index * 5));
+        critical_section::with(|cs| {
+            let events = EVENTS.borrow(cs);
+            let mut all = events.get();
+            all[index] |= flags;
+            events.set(all);
+        });
+        WAKERS[index].wake();
+    }
+}
+
+#[cfg(feature = "rt")]
+use crate::pac::interrupt;
+
+#[cfg(feature = "rt")]
+#[interrupt]
+fn PDMA_CH0_1() {
+    #[cfg(feature = "task-trace")]
+    let _isr = crate::task_trace::Isr::enter();
+    on_interrupt(0..2);
+}
+
+#[cfg(feature = "rt")]
+#[interrupt]
+fn PDMA_CH2_5() {
+    #[cfg(feature = "task-trace")]
+    let _isr = crate::task_trace::Isr::enter();
+    on_interrupt(2..CHANNEL_COUNT);
+}
@@ -0,0 +1,141 @@
+//! DMX512 transmitter
+//!
+//! A DMX512 packet is a break, a mark-after-break (MAB), a start code and up
+//! to 512 channel slots, sent at 250 kbaud with 8 data bits, no parity and
+//! 2 stop bits. The USART generates the break itself (USRCR.BCB); its length
+//! comes from an `embassy-time` timer, and the short MAB is counted in CPU
+//! cycles. For a transmitter both only have minimums (92 µs and 12 µs, with
+//! 1 s maximums), so the executor or an interrupt stretching them a little is
+//! harmless, but neither ever comes out short.
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::dmx::{Dmx, DmxConfig};
+//!
+//! let tx = p.gpioa.pa4().into_alternate_function::<6>();
+//! let rx = p.gpioa.pa5().into_alternate_function::<6>();
+//! let mut dmx = Dmx::new(p.usart1, tx, rx, DmxConfig::default());
+//! let mut universe = [0u8; 512];
+//! loop {
+//!     universe[0] = 255; // dimmer on channel 1
+//!     dmx.send_universe(&universe).await?;
+//! }
+//! ```
+//!
+//! The RS-485 transceiver's driver enable can simply be tied high for a
+//! transmit-only node. A full universe takes about 23 ms, so back-to-back
+//! calls refresh at roughly 44 Hz.
+
+use embassy_time::Timer;
+
+use crate::peripheral::Peripheral;
+use crate::time::Hertz;
+use crate::uart::{self, DataBits, Error, Instance, Parity, StopBits, Uart, UartRx, UartTx};
+
+/// DMX512 line rate
+pub const BAUD_RATE: u32 = 250_000;
+/// Channel slots in a universe
+pub const UNIVERSE_SIZE: usize = 512;
+/// Shortest break a transmitter may send, in µs
+pub const BREAK_MIN_US: u32 = 92;
+/// Shortest mark-after-break a transmitter may send, in µs
+pub const MAB_MIN_US: u32 = 12;
+
+/// Packet timing and start code
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DmxConfig {
+    /// Break length in µs, at least [`BREAK_MIN_US`]
+    pub break_us: u32,
+    /// Mark-after-break length in µs, at least [`MAB_MIN_US`]
+    pub mab_us: u32,
+    /// Start code, 0 for dimmer data
+    pub start_code: u8,
+}
+
+impl Default for DmxConfig {
+    /// 176 µs break and 12 µs MAB, the usual console timing
+    fn default() -> Self {
+        Self {
+            break_us: 176,
+            mab_us: 12,
+            start_code: 0,
+        }
+    }
+}
+
+/// DMX512 output on a USART
+pub struct Dmx<'d, T: Instance> {
+    uart: Uart<'d, T>,
+    config: DmxConfig,
+}
+
+impl<'d, T: Instance> Dmx<'d, T> {
+    /// Set up the USART for DMX512 (250 kbaud, 8N2)
+    ///
+    /// Panics if the break or MAB is below the DMX512 minimum.
+    pub fn new<TX: UartTx<T>, RX: UartRx<T>>(
+        uart: impl Peripheral<P = T> + 'd,
+        tx_pin: impl Peripheral<P = TX> + 'd,
+        rx_pin: impl Peripheral<P = RX> + 'd,
+        config: DmxConfig,
+    ) -> Self {
+        assert!(config.break_us >= BREAK_MIN_US, "DMX break shorter than 92 µs");
+        assert!(config.mab_us >= MAB_MIN_US, "DMX mark-after-break shorter than 12 µs");
+
+        let uart = Uart::new(
+            uart,
+            tx_pin,
+            rx_pin,
+            uart::Config {
+                baudrate: Hertz::hz(BAUD_RATE),
+                data_bits: DataBits::Eight,
+                stop_bits: StopBits::Two,
+                parity: Parity::None,
+                ..uart::Config::default()
+            },
+        );
+        // Receivers accept 245..255 kbaud
+        if !uart.baud_rate().within(20_000) {
+            warn!("dmx: {} baud is off by {} ppm", uart.baud_rate().actual, uart.baud_rate().error_ppm());
+        }
+
+        Self { uart, config }
+    }
+
+    /// Send a full universe
+    pub async fn send_universe(&mut self, channels: &[u8; UNIVERSE_SIZE]) -> Result<(), Error> {
+        self.send(channels).await
+    }
+
+    /// Send a packet with the first `channels.len()` slots, at most 512
+    ///
+    /// Shorter packets refresh faster; receivers keep the channels not sent.
+    /// Returns once the last slot is in the transmitter.
+    pub async fn send(&mut self, channels: &[u8]) -> Result<(), Error> {
+        assert!(channels.len() <= UNIVERSE_SIZE, "a DMX universe has 512 channels");
+
+        // The previous packet's last stop bits must be out before the break
+        self.uart.wait_tx_complete().await;
+
+        self.uart.set_break(true);
+        Timer::after_micros(self.config.break_us as u64).await;
+        self.uart.set_break(false);
+
+        crate::cortex_delay::delay_us(self.config.mab_us);
+
+        self.uart.write(&[self.config.start_code]).await?;
+        self.uart.write(channels).await
+    }
+
+    /// Change the break, MAB or start code for the next packet
+    pub fn set_config(&mut self, config: DmxConfig) {
+        assert!(config.break_us >= BREAK_MIN_US, "DMX break shorter than 92 µs");
+        assert!(config.mab_us >= MAB_MIN_US, "DMX mark-after-break shorter than 12 µs");
+        self.config = config;
+    }
+
+    /// Release the USART
+    pub fn into_uart(self) -> Uart<'d, T> {
+        self.uart
+    }
+}
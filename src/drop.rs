@@ -0,0 +1,31 @@
+//! Cleanup on future cancellation
+//!
+//! An async driver operation can be dropped at any `.await` (a `select` that
+//! picked another branch, a timeout). Operations that leave hardware armed
+//! while waiting hold a [`DropGuard`] that undoes that on drop, and defuse it
+//! once they complete normally.
+
+/// Runs a closure when dropped, unless defused
+#[must_use = "the guard runs its closure as soon as it is dropped"]
+pub(crate) struct DropGuard<F: FnOnce()> {
+    f: Option<F>,
+}
+
+impl<F: FnOnce()> DropGuard<F> {
+    pub(crate) fn new(f: F) -> Self {
+        Self { f: Some(f) }
+    }
+
+    /// The operation completed; do not run the cleanup
+    pub(crate) fn defuse(mut self) {
+        self.f = None;
+    }
+}
+
+impl<F: FnOnce()> Drop for DropGuard<F> {
+    fn drop(&mut self) {
+        if let Some(f) = self.f.take() {
+            f();
+        }
+    }
+}
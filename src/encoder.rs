@@ -0,0 +1,227 @@
+//! Rotary encoder (quadrature) input
+//!
+//! [`Encoder`] watches both encoder pins on their EXTI lines and runs a
+//! Gray-code state machine in the interrupt. Contact bounce only toggles one
+//! pin back and forth, which the state machine sees as a step and its undo,
+//! so no debounce delay is needed; transitions that skip a state are
+//! ignored. Steps are collected into detents ([`Config::steps_per_detent`])
+//! and each detent queues a delta on an `embassy-sync` channel: `+1`
+//! clockwise (A leads B), `-1` counter-clockwise, scaled up by
+//! [`Acceleration`] when the knob turns fast.
+//!
+//! The A and B pins need distinct EXTI lines (pin numbers) that no other
+//! driver uses. Up to [`MAX_ENCODERS`] encoders can be open.
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::encoder::{Config, Encoder};
+//!
+//! let mut knob = Encoder::new(p.gpioa.pa2().degrade(), p.gpioa.pa3().degrade(), Config::default());
+//! loop {
+//!     let delta = knob.receive().await;
+//!     volume = volume.saturating_add_signed(delta);
+//! }
+//! ```
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant};
+
+use crate::exti::{self, Edge, ExtiChannel};
+use crate::gpio::{AnyPin, Pull};
+
+/// Encoders that can be open at once
+pub const MAX_ENCODERS: usize = 2;
+
+/// Deltas buffered per encoder between receives
+pub const QUEUE_LEN: usize = 16;
+
+/// Queue of detent deltas for one encoder
+pub type EncoderChannel = Channel<CriticalSectionRawMutex, i8, QUEUE_LEN>;
+
+/// Step for each (previous AB, current AB) pair; 0 for no change or a skipped state
+const TRANSITIONS: [i8; 16] = [
+    0, -1, 1, 0, //
+    1, 0, 0, -1, //
+    -1, 0, 0, 1, //
+    0, 1, -1, 0, //
+];
+
+/// Speed-up for fast turns
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Acceleration {
+    /// Detents closer together than this count as fast
+    pub interval: Duration,
+    /// Delta reported for a fast detent
+    pub multiplier: i8,
+}
+
+impl Default for Acceleration {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(30),
+            multiplier: 4,
+        }
+    }
+}
+
+/// Encoder configuration
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Quadrature steps per click; 4 for most keyboard knobs, 2 or 1 for some
+    pub steps_per_detent: u8,
+    /// Swap the direction reported
+    pub reverse: bool,
+    /// Pull on both pins; encoders usually switch to ground
+    pub pull: Pull,
+    /// Scale deltas of fast turns, or `None` for always ±1
+    pub acceleration: Option<Acceleration>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            steps_per_detent: 4,
+            reverse: false,
+            pull: Pull::Up,
+            acceleration: None,
+        }
+    }
+}
+
+struct Slot {
+    a: AnyPin,
+    b: AnyPin,
+    config: Config,
+    state: u8,
+    steps: i8,
+    last_detent: Instant,
+}
+
+impl Slot {
+    fn read(&self) -> u8 {
+        (self.a.level() as u8) << 1 | self.b.level() as u8
+    }
+
+    /// Advance the state machine; returns a delta on a completed detent
+    fn update(&mut self) -> Option<i8> {
+        let state = self.read();
+        let step = TRANSITIONS[(self.state << 2 | state) as usize];
+        self.state = state;
+        if step == 0 {
+            return None;
+        }
+
+        self.steps += step;
+        let detent = self.config.steps_per_detent.max(1) as i8;
+        if self.steps.abs() < detent {
+            return None;
+        }
+
+        let mut delta = self.steps.signum();
+        self.steps = 0;
+        if self.config.reverse {
+            delta = -delta;
+        }
+
+        let now = Instant::now();
+        if let Some(acceleration) = self.config.acceleration {
+            if now.duration_since(self.last_detent) < acceleration.interval {
+                delta *= acceleration.multiplier;
+            }
+        }
+        self.last_detent = now;
+        Some(delta)
+    }
+}
+
+static SLOTS: Mutex<RefCell<[Option<Slot>; MAX_ENCODERS]>> = Mutex::new(RefCell::new([const { None }; MAX_ENCODERS]));
+static CHANNELS: [EncoderChannel; MAX_ENCODERS] = [const { Channel::new() }; MAX_ENCODERS];
+
+/// Edge on any encoder pin; every open encoder is sampled
+fn on_edge() {
+    critical_section::with(|cs| {
+        let mut slots = SLOTS.borrow_ref_mut(cs);
+        for (slot, channel) in slots.iter_mut().zip(&CHANNELS) {
+            let Some(slot) = slot.as_mut() else { continue };
+            if let Some(delta) = slot.update() {
+                if channel.try_send(delta).is_err() {
+                    hot_warn!("encoder: queue full, detent dropped");
+                }
+            }
+        }
+    });
+}
+
+/// Quadrature rotary encoder
+pub struct Encoder {
+    index: usize,
+    line_a: ExtiChannel,
+    line_b: ExtiChannel,
+}
+
+impl Encoder {
+    /// Start decoding; panics if the pins share an EXTI line or all slots are taken
+    pub fn new(mut a: AnyPin, mut b: AnyPin, config: Config) -> Self {
+        assert!(a.pin() != b.pin(), "encoder pins need distinct EXTI lines");
+
+        a.set_as_input(config.pull);
+        b.set_as_input(config.pull);
+
+        let line_a = ExtiChannel::new(a.pin()).unwrap();
+        let line_b = ExtiChannel::new(b.pin()).unwrap();
+        exti::configure_exti_source(a.pin(), a.port());
+        exti::configure_exti_source(b.pin(), b.port());
+
+        let index = critical_section::with(|cs| {
+            let mut slots = SLOTS.borrow_ref_mut(cs);
+            let index = slots.iter().position(Option::is_none).expect("too many encoders");
+            let mut slot = Slot {
+                a,
+                b,
+                config,
+                state: 0,
+                steps: 0,
+                last_detent: Instant::from_ticks(0),
+            };
+            slot.state = slot.read();
+            slots[index] = Some(slot);
+            index
+        });
+        CHANNELS[index].clear();
+
+        exti::set_line_handler(line_a.line(), Some(on_edge));
+        exti::set_line_handler(line_b.line(), Some(on_edge));
+        line_a.enable_interrupt(Edge::RisingFalling);
+        line_b.enable_interrupt(Edge::RisingFalling);
+
+        Self { index, line_a, line_b }
+    }
+
+    /// Wait for the next detent
+    pub async fn receive(&mut self) -> i8 {
+        CHANNELS[self.index].receive().await
+    }
+
+    /// Take a queued detent, if any
+    pub fn try_receive(&mut self) -> Option<i8> {
+        CHANNELS[self.index].try_receive().ok()
+    }
+
+    /// The delta queue, e.g. to hand to another task
+    pub fn channel(&self) -> &'static EncoderChannel {
+        &CHANNELS[self.index]
+    }
+}
+
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        self.line_a.disable_interrupt();
+        self.line_b.disable_interrupt();
+        exti::set_line_handler(self.line_a.line(), None);
+        exti::set_line_handler(self.line_b.line(), None);
+        critical_section::with(|cs| SLOTS.borrow_ref_mut(cs)[self.index] = None);
+    }
+}
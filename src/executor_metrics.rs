@@ -0,0 +1,248 @@
+//! Executor statistics from the `embassy-executor` trace hooks
+//!
+//! With the `executor-metrics` feature the HAL builds `embassy-executor` with
+//! its `trace` feature and implements the hooks it calls, counting for every
+//! task how often it ran, how long it waited between being woken (often from
+//! an interrupt) and being polled, and how many tasks were queued at once.
+//! When the USB task starves the matrix scan, or the other way round, the
+//! task with the long wait and the one with the many polls show up side by
+//! side:
+//!
+//! ```rust,ignore
+//! loop {
+//!     Timer::after_secs(10).await;
+//!     executor_metrics::report();
+//!     executor_metrics::reset();
+//! }
+//! ```
+//!
+//! Tasks are identified by the address of their task storage, as printed by
+//! [`report`]; match it against the `__embassy_main_task`-style statics in the
+//! map file or `nm` output. Timing uses `embassy-time`, so latencies have
+//! its tick resolution (1 µs with `time-driver`).
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+
+/// Tasks tracked individually; later ones are only counted in the totals
+pub const MAX_TASKS: usize = 16;
+
+/// One task's counters
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TaskStats {
+    /// Address of the task's storage
+    pub task_id: u32,
+    /// Times the task was polled
+    pub polls: u32,
+    /// Longest time from wake-up to poll, in `embassy-time` ticks
+    pub max_latency: u32,
+    /// Longest single poll, in `embassy-time` ticks
+    pub max_poll: u32,
+}
+
+/// Totals over all executors and tasks
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ExecutorStats {
+    /// Task polls
+    pub polls: u32,
+    /// Times an executor went idle (found its run queue empty)
+    pub idle: u32,
+    /// Longest wake-up to poll latency of any task, in `embassy-time` ticks
+    pub max_latency: u32,
+    /// Most tasks woken but not yet polled at the same time
+    pub max_queue_depth: u32,
+    /// Per-task counters, in spawn order
+    pub tasks: [Option<TaskStats>; MAX_TASKS],
+}
+
+impl ExecutorStats {
+    const fn new() -> Self {
+        Self {
+            polls: 0,
+            idle: 0,
+            max_latency: 0,
+            max_queue_depth: 0,
+            tasks: [None; MAX_TASKS],
+        }
+    }
+}
+
+/// Per-task bookkeeping besides the reported counters
+#[derive(Copy, Clone)]
+struct Pending {
+    /// Tick the task was woken at, while it waits in the run queue
+    ready_at: Option<u64>,
+    /// Tick its current poll started at
+    poll_start: u64,
+}
+
+struct State {
+    stats: ExecutorStats,
+    pending: [Pending; MAX_TASKS],
+    queue_depth: u32,
+}
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    stats: ExecutorStats::new(),
+    pending: [Pending { ready_at: None, poll_start: 0 }; MAX_TASKS],
+    queue_depth: 0,
+}));
+
+/// Snapshot of the counters
+pub fn stats() -> ExecutorStats {
+    critical_section::with(|cs| STATE.borrow_ref(cs).stats)
+}
+
+/// Zero the counters, keeping the task list
+pub fn reset() {
+    critical_section::with(|cs| {
+        let mut state = STATE.borrow_ref_mut(cs);
+        let stats = &mut state.stats;
+        stats.polls = 0;
+        stats.idle = 0;
+        stats.max_latency = 0;
+        stats.max_queue_depth = 0;
+        for task in stats.tasks.iter_mut().flatten() {
+            *task = TaskStats { task_id: task.task_id, ..TaskStats::default() };
+        }
+    });
+}
+
+/// Log the counters, one line per task
+pub fn report() {
+    let stats = stats();
+    info!(
+        "executor: {} polls, {} idle, max latency {} ticks, max queue depth {}",
+        stats.polls, stats.idle, stats.max_latency, stats.max_queue_depth
+    );
+    for task in stats.tasks.iter().flatten() {
+        info!(
+            "executor: task {:#x}: {} polls, max latency {} ticks, max poll {} ticks",
+            task.task_id, task.polls, task.max_latency, task.max_poll
+        );
+    }
+}
+
+fn now() -> u64 {
+    embassy_time::Instant::now().as_ticks()
+}
+
+/// Slot of `task_id`, claiming a free one for a new task
+fn slot(state: &mut State, task_id: u32) -> Option<usize> {
+    if let Some(index) = find(state, task_id) {
+        return Some(index);
+    }
+    let tasks = &mut state.stats.tasks;
+    let index = tasks.iter().position(Option::is_none)?;
+    tasks[index] = Some(TaskStats { task_id, ..TaskStats::default() });
+    Some(index)
+}
+
+/// Slot of a task already being tracked
+fn find(state: &State, task_id: u32) -> Option<usize> {
+    state.stats.tasks.iter().position(|t| t.is_some_and(|t| t.task_id == task_id))
+}
+
+fn saturate(ticks: u64) -> u32 {
+    ticks.min(u32::MAX as u64) as u32
+}
+
+// Hooks called by embassy-executor's `trace` feature, also feeding `task_trace`
+
+#[unsafe(no_mangle)]
+fn _embassy_trace_task_new(_executor_id: u32, task_id: u32) {
+    #[cfg(feature = "task-trace")]
+    crate::task_trace::task_new(task_id);
+    critical_section::with(|cs| {
+        slot(&mut STATE.borrow_ref_mut(cs), task_id);
+    });
+}
+
+#[unsafe(no_mangle)]
+fn _embassy_trace_task_end(_executor_id: u32, task_id: u32) {
+    #[cfg(feature = "task-trace")]
+    crate::task_trace::task_end(task_id);
+    critical_section::with(|cs| {
+        let mut state = STATE.borrow_ref_mut(cs);
+        if let Some(index) = find(&state, task_id) {
+            state.stats.tasks[index] = None;
+            state.pending[index].ready_at = None;
+        }
+    });
+}
+
+#[unsafe(no_mangle)]
+fn _embassy_trace_task_ready_begin(_executor_id: u32, task_id: u32) {
+    #[cfg(feature = "task-trace")]
+    crate::task_trace::task_ready(task_id);
+    let at = now();
+    critical_section::with(|cs| {
+        let mut state = STATE.borrow_ref_mut(cs);
+        state.queue_depth += 1;
+        state.stats.max_queue_depth = state.stats.max_queue_depth.max(state.queue_depth);
+        if let Some(index) = slot(&mut state, task_id) {
+            state.pending[index].ready_at = Some(at);
+        }
+    });
+}
+
+#[unsafe(no_mangle)]
+fn _embassy_trace_task_exec_begin(_executor_id: u32, task_id: u32) {
+    #[cfg(feature = "task-trace")]
+    crate::task_trace::task_exec_begin(task_id);
+    let at = now();
+    critical_section::with(|cs| {
+        let mut state = STATE.borrow_ref_mut(cs);
+        state.queue_depth = state.queue_depth.saturating_sub(1);
+        state.stats.polls = state.stats.polls.wrapping_add(1);
+        let Some(index) = slot(&mut state, task_id) else {
+            return;
+        };
+
+        let pending = &mut state.pending[index];
+        pending.poll_start = at;
+        let latency = pending.ready_at.take().map_or(0, |ready| saturate(at - ready));
+        let stats = &mut state.stats;
+        stats.max_latency = stats.max_latency.max(latency);
+        if let Some(task) = stats.tasks[index].as_mut() {
+            task.polls = task.polls.wrapping_add(1);
+            task.max_latency = task.max_latency.max(latency);
+        }
+    });
+}
+
+#[unsafe(no_mangle)]
+fn _embassy_trace_task_exec_end(_executor_id: u32, task_id: u32) {
+    #[cfg(feature = "task-trace")]
+    crate::task_trace::task_exec_end(task_id);
+    let at = now();
+    critical_section::with(|cs| {
+        let mut state = STATE.borrow_ref_mut(cs);
+        let Some(index) = find(&state, task_id) else {
+            return;
+        };
+        let poll = saturate(at - state.pending[index].poll_start);
+        if let Some(task) = state.stats.tasks[index].as_mut() {
+            task.max_poll = task.max_poll.max(poll);
+        }
+    });
+}
+
+#[unsafe(no_mangle)]
+fn _embassy_trace_poll_start(_executor_id: u32) {
+    #[cfg(feature = "task-trace")]
+    crate::task_trace::poll_start();
+}
+
+#[unsafe(no_mangle)]
+fn _embassy_trace_executor_idle(_executor_id: u32) {
+    #[cfg(feature = "task-trace")]
+    crate::task_trace::executor_idle();
+    critical_section::with(|cs| {
+        let mut state = STATE.borrow_ref_mut(cs);
+        state.stats.idle = state.stats.idle.wrapping_add(1);
+    });
+}
@@ -0,0 +1,217 @@
+//! I2C GPIO expanders (PCA9555, MCP23017)
+//!
+//! [`Expander`] keeps a shadow copy of the expander's 16 pins, and
+//! [`ExpanderPin`] works on that copy through the same `embedded-hal` digital
+//! traits and [`GpioError`] as [`AnyPin`](crate::gpio::AnyPin), so generic
+//! matrix code can mix on-chip and expander pins. [`Expander::sync`] moves
+//! the shadow over I2C in one burst: pending direction and output changes go
+//! out, then the inputs are read back.
+//!
+//! A matrix scan with columns on the expander then looks like:
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::expander::{Chip, Expander};
+//!
+//! let expander = Expander::new(i2c, Chip::Mcp23017, 0x20);
+//! let mut cols = [expander.pin(0), expander.pin(1), expander.pin(2)];
+//! for col in &mut cols {
+//!     col.set_as_input(true);
+//! }
+//!
+//! for row in &mut rows {
+//!     row.set_low()?;
+//!     expander.sync().await?;
+//!     for col in &mut cols {
+//!         let pressed = col.is_low()?;
+//!     }
+//!     row.set_high()?;
+//! }
+//! ```
+//!
+//! The driver is generic over `embedded-hal-async` I2C, so it also runs on a
+//! bit-banged bus.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+use embedded_hal_async::i2c::I2c;
+
+use crate::gpio::{GpioError, Level};
+
+/// Supported expander chips
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Chip {
+    /// NXP/TI PCA9555 (and TCA9555); inputs have fixed 100 kΩ pull-ups
+    Pca9555,
+    /// Microchip MCP23017 in its default `IOCON.BANK = 0` layout
+    Mcp23017,
+}
+
+impl Chip {
+    /// Register pairs: (input, output latch, direction)
+    fn registers(self) -> (u8, u8, u8) {
+        match self {
+            Chip::Pca9555 => (0x00, 0x02, 0x06),
+            Chip::Mcp23017 => (0x12, 0x14, 0x00),
+        }
+    }
+
+    /// Pull-up enable register pair, if the chip has one
+    fn pull_up_register(self) -> Option<u8> {
+        match self {
+            Chip::Pca9555 => None,
+            Chip::Mcp23017 => Some(0x0C),
+        }
+    }
+}
+
+/// 16-pin I2C expander
+pub struct Expander<I2C> {
+    i2c: Mutex<NoopRawMutex, I2C>,
+    chip: Chip,
+    address: u8,
+    /// Direction bits, 1 = input (the power-on state of both chips)
+    inputs: Cell<u16>,
+    pull_ups: Cell<u16>,
+    outputs: Cell<u16>,
+    levels: Cell<u16>,
+    config_dirty: Cell<bool>,
+    outputs_dirty: Cell<bool>,
+}
+
+impl<I2C: I2c> Expander<I2C> {
+    /// `address` is the 7-bit address set by the chip's A0..A2 pins
+    pub fn new(i2c: I2C, chip: Chip, address: u8) -> Self {
+        Self {
+            i2c: Mutex::new(i2c),
+            chip,
+            address,
+            inputs: Cell::new(0xFFFF),
+            pull_ups: Cell::new(0),
+            outputs: Cell::new(0xFFFF),
+            levels: Cell::new(0),
+            // Push the shadow on the first sync in case the chip was not reset
+            config_dirty: Cell::new(true),
+            outputs_dirty: Cell::new(true),
+        }
+    }
+
+    /// Virtual pin `n` (0..16; port 0 / A is 0..8, port 1 / B is 8..16)
+    pub fn pin(&self, n: u8) -> ExpanderPin<'_, I2C> {
+        assert!(n < 16, "expanders have 16 pins");
+        ExpanderPin { expander: self, mask: 1 << n }
+    }
+
+    /// Write pending direction and output changes, then read all inputs
+    pub async fn sync(&self) -> Result<(), I2C::Error> {
+        let mut i2c = self.i2c.lock().await;
+        let (input_reg, output_reg, direction_reg) = self.chip.registers();
+
+        if self.outputs_dirty.replace(false) {
+            // Latch outputs before switching pins to output so they do not glitch
+            let [lo, hi] = self.outputs.get().to_le_bytes();
+            if let Err(e) = i2c.write(self.address, &[output_reg, lo, hi]).await {
+                self.outputs_dirty.set(true);
+                return Err(e);
+            }
+        }
+
+        if self.config_dirty.replace(false) {
+            let result = async {
+                let [lo, hi] = self.inputs.get().to_le_bytes();
+                i2c.write(self.address, &[direction_reg, lo, hi]).await?;
+                if let Some(pull_up_reg) = self.chip.pull_up_register() {
+                    let [lo, hi] = self.pull_ups.get().to_le_bytes();
+                    i2c.write(self.address, &[pull_up_reg, lo, hi]).await?;
+                }
+                Ok(())
+            }
+            .await;
+            if result.is_err() {
+                self.config_dirty.set(true);
+            }
+            result?;
+        }
+
+        let mut levels = [0u8; 2];
+        i2c.write_read(self.address, &[input_reg], &mut levels).await?;
+        self.levels.set(u16::from_le_bytes(levels));
+        Ok(())
+    }
+
+    /// Release the I2C bus
+    pub fn release(self) -> I2C {
+        self.i2c.into_inner()
+    }
+}
+
+/// One expander pin, acting on the shadow state until [`Expander::sync`]
+pub struct ExpanderPin<'a, I2C> {
+    expander: &'a Expander<I2C>,
+    mask: u16,
+}
+
+impl<'a, I2C> ExpanderPin<'a, I2C> {
+    fn update(cell: &Cell<u16>, mask: u16, set: bool) {
+        let value = cell.get();
+        cell.set(if set { value | mask } else { value & !mask });
+    }
+
+    /// Configure as an output driving `level`
+    pub fn set_as_output(&mut self, level: Level) {
+        self.write(level == Level::High);
+        Self::update(&self.expander.inputs, self.mask, false);
+        self.expander.config_dirty.set(true);
+    }
+
+    /// Configure as an input; `pull_up` is ignored on the PCA9555, whose pull-ups are fixed
+    pub fn set_as_input(&mut self, pull_up: bool) {
+        Self::update(&self.expander.inputs, self.mask, true);
+        Self::update(&self.expander.pull_ups, self.mask, pull_up);
+        self.expander.config_dirty.set(true);
+    }
+
+    fn write(&mut self, high: bool) {
+        Self::update(&self.expander.outputs, self.mask, high);
+        self.expander.outputs_dirty.set(true);
+    }
+}
+
+impl<'a, I2C> embedded_hal::digital::ErrorType for ExpanderPin<'a, I2C> {
+    type Error = GpioError;
+}
+
+impl<'a, I2C> embedded_hal::digital::OutputPin for ExpanderPin<'a, I2C> {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.write(false);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.write(true);
+        Ok(())
+    }
+}
+
+impl<'a, I2C> embedded_hal::digital::StatefulOutputPin for ExpanderPin<'a, I2C> {
+    fn is_set_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.expander.outputs.get() & self.mask != 0)
+    }
+
+    fn is_set_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.expander.outputs.get() & self.mask == 0)
+    }
+}
+
+impl<'a, I2C> embedded_hal::digital::InputPin for ExpanderPin<'a, I2C> {
+    /// Level read by the last [`Expander::sync`]
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.expander.levels.get() & self.mask != 0)
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.expander.levels.get() & self.mask == 0)
+    }
+}
@@ -4,10 +4,30 @@
 //! similar to embassy-stm32 EXTI implementation.
 //!
 //! Note: This is a simplified implementation that focuses on basic functionality.
+//! Drivers that need every edge (PS/2 clock, decoders) install a per-line
+//! handler that runs in the interrupt; other lines are masked when they fire
+//! and [`ExtiChannel::wait`] picks up the flag.
+
+use core::cell::Cell;
+
+use critical_section::Mutex;
 
 use crate::pac::{Exti, Afio};
-use crate::interrupt::{self};
+use crate::interrupt as irq;
 use crate::pac::Interrupt;
+use crate::regs::{Mmio, RegisterAccess};
+
+// EXTI register layout; EXTICFGRn is indexed by line, which the PAC does not model
+const EXTI_BASE: usize = 0x4002_4000;
+const EXTI_CFGR0: usize = 0x000;
+const EXTI_CR: usize = 0x040;
+
+// EXTICFGRn SRCTYPE field
+const CFGR_SRCTYPE_SHIFT: u32 = 28;
+const CFGR_SRCTYPE_MASK: u32 = 0b111 << CFGR_SRCTYPE_SHIFT;
+
+/// Handlers installed with [`set_line_handler`]
+static HANDLERS: Mutex<[Cell<Option<fn()>>; 16]> = Mutex::new([const { Cell::new(None) }; 16]);
 
 /// EXTI trigger edge configuration
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -38,33 +58,36 @@ impl ExtiChannel {
         }
     }
 
+    /// EXTI line number
+    pub fn line(&self) -> ExtiLine {
+        self.line
+    }
+
     /// Enable the EXTI line with the specified trigger edge
     pub fn enable_interrupt(&self, edge: Edge) {
-        // For now, this is a simplified implementation
-        // The actual HT32F523xx EXTI register layout needs to be determined
-        // from the reference manual or PAC documentation
-
-        let _edge_config = self.get_edge_config(edge);
+        let ckcu = unsafe { &*crate::pac::Ckcu::ptr() };
+        ckcu.apbccr0().modify(|_, w| w.extien().set_bit());
 
-        // TODO: Implement proper EXTI configuration once PAC register layout is known
-        // For now, we'll rely on the NVIC interrupt being enabled
+        let cfgr = EXTI_BASE + EXTI_CFGR0 + 4 * self.line as usize;
+        Mmio.modify(cfgr, |v| (v & !CFGR_SRCTYPE_MASK) | (self.get_edge_config(edge) << CFGR_SRCTYPE_SHIFT));
 
         // Clear any pending interrupt
         self.clear_pending();
+        critical_section::with(|_| Mmio.modify(EXTI_BASE + EXTI_CR, |v| v | 1 << self.line));
     }
 
-    /// Get edge configuration value for HT32 EXTI
-    fn get_edge_config(&self, edge: Edge) -> u8 {
+    /// Get the EXTICFGRn SRCTYPE value for an edge
+    fn get_edge_config(&self, edge: Edge) -> u32 {
         match edge {
-            Edge::Rising => 1,    // Rising edge
-            Edge::Falling => 2,   // Falling edge
-            Edge::RisingFalling => 3, // Both edges
+            Edge::Falling => 0b010,
+            Edge::Rising => 0b011,
+            Edge::RisingFalling => 0b100,
         }
     }
 
     /// Disable the EXTI line
     pub fn disable_interrupt(&self) {
-        // TODO: Implement proper EXTI disable once PAC register layout is known
+        critical_section::with(|_| Mmio.modify(EXTI_BASE + EXTI_CR, |v| v & !(1 << self.line)));
         // Clear any pending interrupt
         self.clear_pending();
     }
@@ -86,13 +109,21 @@ impl ExtiChannel {
     /// Wait for interrupt
     pub async fn wait(&self) {
         let interrupt = self.get_interrupt();
-        let waker = interrupt::get_waker(interrupt);
+        let waker = irq::get_waker(interrupt);
 
         // Enable interrupt
         self.enable_interrupt(Edge::RisingFalling); // Default to both edges
 
-        // Wait for interrupt
-        waker.wait().await;
+        // The handler masks the line and leaves its flag set
+        core::future::poll_fn(|cx| {
+            waker.register(cx.waker());
+            if self.is_pending() {
+                core::task::Poll::Ready(())
+            } else {
+                core::task::Poll::Pending
+            }
+        })
+        .await;
 
         // Clear the interrupt flag
         self.clear_pending();
@@ -157,6 +188,64 @@ pub fn configure_exti_source(line: ExtiLine, port: char) {
     }
 }
 
+/// Run `handler` in interrupt context on every edge of `line`, or stop with `None`
+///
+/// The flag is cleared before the handler runs and the line stays enabled.
+pub(crate) fn set_line_handler(line: ExtiLine, handler: Option<fn()>) {
+    critical_section::with(|cs| HANDLERS.borrow(cs)[line as usize].set(handler));
+}
+
+/// EXTI interrupt handler body for the lines in `lines`
+pub(crate) fn on_interrupt(lines: core::ops::RangeInclusive<ExtiLine>, interrupt: Interrupt) {
+    let exti = unsafe { &*Exti::ptr() };
+    let flags = exti.edgeflgr().read().bits();
+
+    for line in lines {
+        if flags & (1 << line) == 0 {
+            continue;
+        }
+
+        match critical_section::with(|cs| HANDLERS.borrow(cs)[line as usize].get()) {
+            Some(handler) => {
+                exti.edgeflgr().write(|w| unsafe { w.bits(1 << line) });
+                handler();
+            }
+            None => {
+                // Leave the flag for the waiting task
+                critical_section::with(|_| Mmio.modify(EXTI_BASE + EXTI_CR, |v| v & !(1 << line)));
+                irq::get_waker(interrupt).wake();
+            }
+        }
+    }
+}
+
+#[cfg(feature = "rt")]
+use crate::pac::interrupt;
+
+#[cfg(feature = "rt")]
+#[interrupt]
+fn EXTI0_1() {
+    #[cfg(feature = "task-trace")]
+    let _isr = crate::task_trace::Isr::enter();
+    on_interrupt(0..=1, Interrupt::EXTI0_1);
+}
+
+#[cfg(feature = "rt")]
+#[interrupt]
+fn EXTI2_3() {
+    #[cfg(feature = "task-trace")]
+    let _isr = crate::task_trace::Isr::enter();
+    on_interrupt(2..=3, Interrupt::EXTI2_3);
+}
+
+#[cfg(feature = "rt")]
+#[interrupt]
+fn EXTI4_15() {
+    #[cfg(feature = "task-trace")]
+    let _isr = crate::task_trace::Isr::enter();
+    on_interrupt(4..=15, Interrupt::EXTI4_15);
+}
+
 /// Initialize EXTI system
 pub fn init() {
     // Enable EXTI and AFIO clocks (already done in RCC init)
@@ -0,0 +1,80 @@
+//! HardFault and NMI handlers that log the stacked registers
+//!
+//! With the `fault-handler` feature the HAL defines `HardFault` and
+//! `NonMaskableInt`. Either one runs [`safe_state::enter`], logs the
+//! registers the core stacked on entry and the exception that was active
+//! when it hit, then halts:
+//!
+//! ```text
+//! ERROR HardFault at PC 0x00001a2e in thread mode
+//! ERROR   r0 0x20003ff0  r1 0x00000000  r2 0x00000001  r3 0xffffffff
+//! ERROR  r12 0x00000000  lr 0x00001a1b  pc 0x00001a2e xpsr 0x61000000
+//! ERROR   sp 0x20003fb8
+//! ```
+//!
+//! The M0+ has no fault status registers, so this is all there is to go
+//! on: look the PC up with `addr2line -e <elf>` and the LR for the caller.
+//! A clear Thumb bit in xPSR means a jump through a bad function pointer.
+//! The log goes out over RTT; UART and CDC sinks of [`log_sink`] need tasks
+//! that will not run again. With the watchdog running, the halt ends in a
+//! watchdog reset.
+//!
+//! [`log_sink`]: crate::log_sink
+
+use cortex_m_rt::ExceptionFrame;
+
+use crate::{safe_state, vectors};
+
+/// xPSR bit set when the core padded the stack to 8 bytes before stacking
+const XPSR_STACK_ALIGN: u32 = 1 << 9;
+/// xPSR Thumb state bit; clear means a branch to an even address
+const XPSR_THUMB: u32 = 1 << 24;
+/// xPSR exception number field
+const XPSR_EXCEPTION: u32 = 0x1FF;
+/// Bytes in the stacked frame
+const FRAME_SIZE: u32 = 32;
+
+crate::vectors::exception_trampoline!("HardFault", hard_fault);
+crate::vectors::exception_trampoline!("NonMaskableInt", nmi);
+
+extern "C" fn hard_fault(frame: &ExceptionFrame) -> ! {
+    report("HardFault", frame)
+}
+
+extern "C" fn nmi(frame: &ExceptionFrame) -> ! {
+    report("NMI", frame)
+}
+
+fn report(kind: &str, frame: &ExceptionFrame) -> ! {
+    safe_state::enter();
+
+    let xpsr = frame.xpsr();
+    let exception = (xpsr & XPSR_EXCEPTION) as u16;
+    let context = if exception == 0 { "thread mode" } else { vectors::name(exception) };
+    error!("{} at PC {:#010x} in {}", kind, frame.pc(), context);
+    error!(
+        "  r0 {:#010x}  r1 {:#010x}  r2 {:#010x}  r3 {:#010x}",
+        frame.r0(),
+        frame.r1(),
+        frame.r2(),
+        frame.r3()
+    );
+    error!(
+        " r12 {:#010x}  lr {:#010x}  pc {:#010x} xpsr {:#010x}",
+        frame.r12(),
+        frame.lr(),
+        frame.pc(),
+        xpsr
+    );
+    // The stack pointer before the exception, above the frame and any padding
+    let padding = if xpsr & XPSR_STACK_ALIGN != 0 { 4 } else { 0 };
+    let sp = frame as *const ExceptionFrame as u32 + FRAME_SIZE + padding;
+    error!("  sp {:#010x}", sp);
+    if xpsr & XPSR_THUMB == 0 {
+        error!("Thumb bit clear: jump to an even address, e.g. through a bad function pointer");
+    }
+
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
@@ -1,18 +1,64 @@
 //! Flash memory driver for HT32F523xx
 //!
 //! This module provides flash memory operations using the HT32F523xx Flash Memory Controller (FMC).
+//!
+//! It also controls the FMC's fetch accelerator, the pre-fetch buffer and
+//! branch cache that hide the flash wait state at 48 MHz. Timing-critical
+//! bit-banging (WS2812, software UARTs) can run with both off, so every
+//! instruction fetch costs the same:
+//!
+//! ```rust,ignore
+//! let cycles = flash::benchmark(|| render_frame(&mut leds));
+//! info!("cache saves {}%", cycles.saved_percent());
+//!
+//! flash::with_cache_config(flash::CacheConfig::DETERMINISTIC, || ws2812_send(&frame));
+//! ```
 
 use core::ptr;
+#[cfg(feature = "time")]
 use embassy_time::{Duration, Timer};
 use embedded_storage::nor_flash::{ErrorType, NorFlash, ReadNorFlash, NorFlashError, NorFlashErrorKind};
 
+use crate::lvd::Lvd;
 use crate::pac;
+use crate::regs::{Mmio, RegisterAccess};
+
+// FMC cache and pre-fetch control register
+const FMC_CFCR: usize = 0x4008_0200;
+const CFCR_WAIT_MASK: u32 = 0b111;
+const CFCR_PFBE: u32 = 1 << 4; // Pre-fetch buffer enable
+const CFCR_CE: u32 = 1 << 12; // Branch cache enable
+
+const SYST_CSR: usize = 0xE000_E010;
+const SYST_RVR: usize = 0xE000_E014;
+const SYST_CVR: usize = 0xE000_E018;
+const SYST_CSR_ENABLE_CORE: u32 = 0b101;
+const SYST_MAX: u32 = 0x00FF_FFFF;
+
+/// Let other tasks run for about a millisecond while the FMC is busy
+async fn pause_1ms() {
+    crate::wdt::pet_if_scoped();
+    #[cfg(feature = "time")]
+    Timer::after(Duration::from_millis(1)).await;
+    // Without embassy-time, spin for 1ms and then yield
+    #[cfg(not(feature = "time"))]
+    {
+        crate::cortex_delay::delay_ms(1);
+        embassy_futures::yield_now().await;
+    }
+}
 
 /// Flash memory controller
+///
+/// The peripheral is its own driver, so it is lent by `&mut` rather than
+/// wrapped; it still implements [`Peripheral`](crate::peripheral::Peripheral)
+/// for drivers that hold it as a [`Peri`](crate::peripheral::Peri).
 pub struct Flash {
     _private: (),
 }
 
+impl_peripheral!(Flash);
+
 impl Flash {
     /// Create a new flash controller instance
     pub fn new() -> Self {
@@ -31,7 +77,7 @@ impl Flash {
         // Wait for operation to complete (bit 0 of OISR is busy flag)
         let mut timeout = 1000; // 1000ms timeout
         while fmc.oisr().read().bits() & 0x01 != 0 && timeout > 0 {
-            Timer::after(Duration::from_millis(1)).await;
+            pause_1ms().await;
             timeout -= 1;
         }
 
@@ -114,15 +160,42 @@ impl Flash {
     }
 }
 
+/// Flash error
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum FlashError {
+    /// The FMC stayed busy for more than a second
     Timeout,
+    /// Programming failed (FMC reported a write error)
     WriteError,
+    /// Page erase failed (FMC reported an erase error)
     EraseError,
+    /// Address beyond the end of flash
     AddressOutOfRange,
+    /// Address or length not a multiple of the write/erase size
     UnalignedAddress,
+    /// Blocking erase/program needs the `ramfunc` feature; otherwise use `erase_async`/`write_async`
+    Unsupported,
+    /// VDD is or was below the LVD threshold; nothing further was erased or programmed
+    LowVoltage,
 }
 
+impl core::fmt::Display for FlashError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            FlashError::Timeout => "flash operation timed out",
+            FlashError::WriteError => "flash program failed",
+            FlashError::EraseError => "flash erase failed",
+            FlashError::AddressOutOfRange => "address out of range",
+            FlashError::UnalignedAddress => "unaligned address or length",
+            FlashError::Unsupported => "blocking flash operation not supported",
+            FlashError::LowVoltage => "supply voltage too low",
+        })
+    }
+}
+
+impl core::error::Error for FlashError {}
+
 impl NorFlashError for FlashError {
     fn kind(&self) -> NorFlashErrorKind {
         match self {
@@ -131,6 +204,8 @@ impl NorFlashError for FlashError {
             FlashError::EraseError => NorFlashErrorKind::Other,
             FlashError::AddressOutOfRange => NorFlashErrorKind::OutOfBounds,
             FlashError::UnalignedAddress => NorFlashErrorKind::NotAligned,
+            FlashError::Unsupported => NorFlashErrorKind::Other,
+            FlashError::LowVoltage => NorFlashErrorKind::Other,
         }
     }
 }
@@ -180,10 +255,8 @@ impl NorFlash for Flash {
             return Err(FlashError::AddressOutOfRange);
         }
 
-        // Note: This would need to be called from an async context
-        // For now, we'll return an error as sync erase is not supported
-        // Use erase_async() instead
-        Err(FlashError::WriteError)
+        // Sync erase needs the RAM routines (`ramfunc` feature), otherwise use erase_async()
+        blocking_erase(from, to)
     }
 
     fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
@@ -199,9 +272,8 @@ impl NorFlash for Flash {
             return Err(FlashError::UnalignedAddress);
         }
 
-        // Note: This would need to be called from an async context
-        // For now, we'll return an error as sync write is not supported
-        Err(FlashError::WriteError)
+        // Sync write needs the RAM routines (`ramfunc` feature), otherwise use write_async()
+        blocking_write(offset, bytes)
     }
 }
 
@@ -209,6 +281,30 @@ impl NorFlash for Flash {
 impl Flash {
     /// Erase a range of flash memory (async)
     pub async fn erase_async(&mut self, from: u32, to: u32) -> Result<(), FlashError> {
+        self.erase_all(from, to, None).await
+    }
+
+    /// Write data to flash memory (async)
+    pub async fn write_async(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        self.write_range(offset, bytes, None).await
+    }
+
+    /// Erase `from..to` one page per [`ErasePages::next`] call
+    ///
+    /// Each page takes the FMC tens of milliseconds, during which the task
+    /// waits asynchronously; between pages it yields, so a long DFU wipe
+    /// leaves room for USB and the watchdog. Drive it page by page to report
+    /// progress, or hand [`run`](ErasePages::run) a callback:
+    ///
+    /// ```rust,ignore
+    /// let mut erase = flash.erase_range(APP_START, APP_END)?;
+    /// while let Some(progress) = erase.next().await? {
+    ///     dfu.set_status_percent(progress.percent());
+    /// }
+    /// ```
+    ///
+    /// Dropping the iterator stops the erase after the last finished page.
+    pub fn erase_range(&mut self, from: u32, to: u32) -> Result<ErasePages<'_>, FlashError> {
         if from % Self::ERASE_SIZE as u32 != 0 || to % Self::ERASE_SIZE as u32 != 0 {
             return Err(FlashError::UnalignedAddress);
         }
@@ -217,18 +313,35 @@ impl Flash {
             return Err(FlashError::AddressOutOfRange);
         }
 
-        // Erase all pages in the range
-        let mut address = from;
-        while address < to {
-            self.erase_page(address).await?;
-            address += Self::ERASE_SIZE as u32;
-        }
+        Ok(ErasePages { flash: self, lvd: None, from, next: from, to: to.max(from) })
+    }
 
-        Ok(())
+    /// Erase like [`erase_async`](Self::erase_async), unless the supply is failing
+    ///
+    /// Refuses to start while `lvd` reports VDD below its threshold and
+    /// checks again before every page, so an unplug stops the erase at a page
+    /// boundary with [`FlashError::LowVoltage`] instead of half-erasing a page.
+    pub async fn erase_checked(&mut self, lvd: &Lvd, from: u32, to: u32) -> Result<(), FlashError> {
+        self.erase_all(from, to, Some(lvd)).await
     }
 
-    /// Write data to flash memory (async)
-    pub async fn write_async(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+    /// Write like [`write_async`](Self::write_async), unless the supply is failing
+    ///
+    /// Checks `lvd` before every word. On [`FlashError::LowVoltage`] the
+    /// words before the failing one are programmed and the rest still read
+    /// as erased, which a record format with a trailing commit marker can
+    /// detect on the next boot.
+    pub async fn write_checked(&mut self, lvd: &Lvd, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        self.write_range(offset, bytes, Some(lvd)).await
+    }
+
+    async fn erase_all(&mut self, from: u32, to: u32, lvd: Option<&Lvd>) -> Result<(), FlashError> {
+        let mut pages = self.erase_range(from, to)?;
+        pages.lvd = lvd;
+        pages.run(|_| {}).await
+    }
+
+    async fn write_range(&mut self, offset: u32, bytes: &[u8], lvd: Option<&Lvd>) -> Result<(), FlashError> {
         if offset % Self::WRITE_SIZE as u32 != 0 {
             return Err(FlashError::UnalignedAddress);
         }
@@ -253,6 +366,7 @@ impl Flash {
                 ((*data_ptr.add(3)) as u32) << 24
             };
 
+            check_supply(lvd)?;
             self.write_word(address, word).await?;
 
             address += Self::WRITE_SIZE as u32;
@@ -261,4 +375,295 @@ impl Flash {
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Page-by-page erase, from [`Flash::erase_range`]
+pub struct ErasePages<'a> {
+    flash: &'a mut Flash,
+    lvd: Option<&'a Lvd>,
+    from: u32,
+    next: u32,
+    to: u32,
+}
+
+/// How far an [`ErasePages`] has got
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EraseProgress {
+    /// Page just erased
+    pub page: u32,
+    /// Bytes erased so far
+    pub erased: u32,
+    /// Bytes in the whole range
+    pub total: u32,
+}
+
+impl EraseProgress {
+    /// Share of the range erased, 0..=100
+    pub fn percent(&self) -> u8 {
+        if self.total == 0 {
+            100
+        } else {
+            (self.erased as u64 * 100 / self.total as u64) as u8
+        }
+    }
+
+    /// Whether this was the last page
+    pub fn is_done(&self) -> bool {
+        self.erased == self.total
+    }
+}
+
+impl<'a> ErasePages<'a> {
+    /// Stop at a page boundary with [`FlashError::LowVoltage`] once `lvd` trips
+    ///
+    /// As with [`Flash::erase_checked`], the supply is checked before every page.
+    pub fn with_lvd(mut self, lvd: &'a Lvd) -> Self {
+        self.lvd = Some(lvd);
+        self
+    }
+
+    /// Erase the next page; `Ok(None)` once the range is done
+    pub async fn next(&mut self) -> Result<Option<EraseProgress>, FlashError> {
+        if self.next >= self.to {
+            return Ok(None);
+        }
+        if self.next != self.from {
+            // Let USB and the other tasks in between pages
+            crate::wdt::pet_if_scoped();
+            embassy_futures::yield_now().await;
+        }
+
+        let page = self.next;
+        check_supply(self.lvd)?;
+        self.flash.erase_page(page).await?;
+        self.next += Flash::ERASE_SIZE as u32;
+
+        Ok(Some(EraseProgress { page, erased: self.next - self.from, total: self.to - self.from }))
+    }
+
+    /// Erase the rest of the range, calling `progress` after every page
+    pub async fn run(mut self, mut progress: impl FnMut(EraseProgress)) -> Result<(), FlashError> {
+        while let Some(step) = self.next().await? {
+            progress(step);
+        }
+        Ok(())
+    }
+
+    /// Bytes left to erase
+    pub fn remaining(&self) -> u32 {
+        self.to - self.next
+    }
+}
+
+// Raw FMC registers for the RAM routines, which must not call the PAC accessors in flash
+#[cfg(feature = "ramfunc")]
+mod ram {
+    pub const TADR: *mut u32 = 0x4008_0000 as *mut u32;
+    pub const WRDR: *mut u32 = 0x4008_0004 as *mut u32;
+    pub const OCMR: *mut u32 = 0x4008_000C as *mut u32;
+    pub const OPCR: *mut u32 = 0x4008_0010 as *mut u32;
+    pub const OISR: *mut u32 = 0x4008_0018 as *mut u32;
+    /// OPCR.OPM field position
+    pub const OPM_SHIFT: u32 = 1;
+    pub const OPM_ERASE: u32 = 0x2;
+    pub const OPM_WRITE: u32 = 0x4;
+
+    crate::ramfunc! {
+        /// One FMC operation, busy-waiting from RAM; returns the final OISR
+        ///
+        /// Same sequence as the async driver: unlock, address, data, start, wait, lock.
+        pub fn flash_op(address: u32, data: u32, opm: u32) -> u32 {
+            unsafe {
+                OCMR.write_volatile(0xA9B8_C7D6);
+                OCMR.write_volatile(0xD6C7_B8A9);
+                TADR.write_volatile(address);
+                WRDR.write_volatile(data);
+                OPCR.write_volatile(opm << OPM_SHIFT);
+                while OISR.read_volatile() & 0x01 != 0 {}
+                let status = OISR.read_volatile();
+                OCMR.write_volatile(0);
+                status
+            }
+        }
+    }
+}
+
+/// Map a final OISR value to the result, as `wait_ready` does
+#[cfg(feature = "ramfunc")]
+fn op_status(status: u32) -> Result<(), FlashError> {
+    if status & 0x02 != 0 {
+        Err(FlashError::WriteError)
+    } else if status & 0x04 != 0 {
+        Err(FlashError::EraseError)
+    } else {
+        Ok(())
+    }
+}
+
+/// Erase whole pages with the busy-wait running from RAM
+///
+/// Interrupt handlers in flash stall until each page is done.
+#[cfg(feature = "ramfunc")]
+fn blocking_erase(from: u32, to: u32) -> Result<(), FlashError> {
+    let mut address = from;
+    while address < to {
+        crate::wdt::pet_if_scoped();
+        op_status(ram::flash_op(address, 0, ram::OPM_ERASE))?;
+        address += Flash::ERASE_SIZE as u32;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "ramfunc"))]
+fn blocking_erase(_from: u32, _to: u32) -> Result<(), FlashError> {
+    Err(FlashError::Unsupported)
+}
+
+/// Program whole words with the busy-wait running from RAM
+#[cfg(feature = "ramfunc")]
+fn blocking_write(offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+    for (i, chunk) in bytes.chunks_exact(Flash::WRITE_SIZE).enumerate() {
+        let word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        op_status(ram::flash_op(offset + (i * Flash::WRITE_SIZE) as u32, word, ram::OPM_WRITE))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "ramfunc"))]
+fn blocking_write(_offset: u32, _bytes: &[u8]) -> Result<(), FlashError> {
+    Err(FlashError::Unsupported)
+}
+
+/// Fail with [`FlashError::LowVoltage`] if the LVD has seen VDD drop
+fn check_supply(lvd: Option<&Lvd>) -> Result<(), FlashError> {
+    match lvd {
+        Some(lvd) if lvd.tripped() => {
+            warn!("flash: supply below LVD threshold, not programming");
+            Err(FlashError::LowVoltage)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// FMC fetch accelerator settings
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CacheConfig {
+    /// Fetch the next flash word while the current one executes
+    pub prefetch: bool,
+    /// Keep recent branch targets, so loops run without wait states
+    pub branch_cache: bool,
+}
+
+impl CacheConfig {
+    /// Both on, the reset state
+    pub const FAST: Self = Self { prefetch: true, branch_cache: true };
+    /// Both off: every fetch pays the wait state, so timing only depends on the code
+    pub const DETERMINISTIC: Self = Self { prefetch: false, branch_cache: false };
+}
+
+/// Current accelerator settings
+pub fn cache_config() -> CacheConfig {
+    let cfcr = Mmio.read(FMC_CFCR);
+    CacheConfig {
+        prefetch: cfcr & CFCR_PFBE != 0,
+        branch_cache: cfcr & CFCR_CE != 0,
+    }
+}
+
+/// Change the accelerator settings
+pub fn set_cache_config(config: CacheConfig) {
+    Mmio.modify(FMC_CFCR, |v| {
+        let v = v & !(CFCR_PFBE | CFCR_CE);
+        v | if config.prefetch { CFCR_PFBE } else { 0 } | if config.branch_cache { CFCR_CE } else { 0 }
+    });
+    // Fetches already in flight were made with the old setting
+    cortex_m::asm::isb();
+}
+
+/// Run `f` with `config`, then put the previous settings back
+pub fn with_cache_config<R>(config: CacheConfig, f: impl FnOnce() -> R) -> R {
+    let saved = cache_config();
+    set_cache_config(config);
+    let result = f();
+    set_cache_config(saved);
+    result
+}
+
+/// Flash wait states the FMC inserts per uncached fetch
+pub fn wait_states() -> u8 {
+    // WAIT = 1 means zero wait states
+    (Mmio.read(FMC_CFCR) & CFCR_WAIT_MASK).saturating_sub(1) as u8
+}
+
+/// Core cycles of one routine with the accelerator on and off, from [`benchmark`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CacheBenchmark {
+    /// Cycles with pre-fetch and branch cache on
+    pub cached: u32,
+    /// Cycles with both off
+    pub uncached: u32,
+}
+
+impl CacheBenchmark {
+    /// Share of the uncached run the accelerator saves, 0..=100
+    pub fn saved_percent(&self) -> u8 {
+        if self.uncached == 0 {
+            return 0;
+        }
+        (self.uncached.saturating_sub(self.cached) as u64 * 100 / self.uncached as u64) as u8
+    }
+
+    /// Estimated share of fetches served without a wait state, 0..=100
+    ///
+    /// The FMC has no hit counters, so this models the uncached run as one
+    /// fetch per instruction cycle, each stalled by [`wait_states`]: the
+    /// stall cycles are `uncached * W / (1 + W)`, and the share of them the
+    /// accelerator removed is the hit rate. Code that spends cycles on RAM
+    /// or peripheral accesses fetches less often than that, so the estimate
+    /// errs low.
+    pub fn hit_rate_estimate(&self) -> u8 {
+        let wait = wait_states() as u64;
+        let stalls = self.uncached as u64 * wait / (1 + wait);
+        if stalls == 0 {
+            return 100;
+        }
+        let saved = self.uncached.saturating_sub(self.cached) as u64;
+        (saved * 100 / stalls).min(100) as u8
+    }
+}
+
+/// Time `f` in core cycles with the accelerator on, then off
+///
+/// Runs `f` once per setting with interrupts disabled, using SysTick; `f`
+/// must take less than 2^24 cycles (about 350 ms at 48 MHz). The previous
+/// accelerator settings and SysTick configuration are restored afterwards.
+pub fn benchmark(mut f: impl FnMut()) -> CacheBenchmark {
+    let saved_csr = Mmio.read(SYST_CSR);
+    let saved_rvr = Mmio.read(SYST_RVR);
+    Mmio.write(SYST_RVR, SYST_MAX);
+    Mmio.write(SYST_CVR, 0);
+    Mmio.write(SYST_CSR, SYST_CSR_ENABLE_CORE);
+
+    let mut time = |config| {
+        with_cache_config(config, || {
+            critical_section::with(|_| {
+                let start = Mmio.read(SYST_CVR);
+                f();
+                // SysTick counts down and wraps at 24 bits
+                start.wrapping_sub(Mmio.read(SYST_CVR)) & SYST_MAX
+            })
+        })
+    };
+    let cached = time(CacheConfig::FAST);
+    let uncached = time(CacheConfig::DETERMINISTIC);
+
+    Mmio.write(SYST_CSR, saved_csr & !SYST_CSR_ENABLE_CORE);
+    Mmio.write(SYST_RVR, saved_rvr);
+    Mmio.write(SYST_CVR, 0);
+    Mmio.write(SYST_CSR, saved_csr);
+
+    CacheBenchmark { cached, uncached }
+}
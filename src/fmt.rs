@@ -1,7 +1,49 @@
 //! Formatting utilities for debugging
+//!
+//! The `trace!` .. `error!` macros forward to `defmt` with the `defmt` feature
+//! and compile to nothing otherwise, without evaluating their arguments. Only
+//! the macros are used, so the choice of transport stays with the application.
+//!
+//! `hot_trace!` .. `hot_error!` are for code that runs per USB packet, key
+//! press or pin edge. They log like the plain macros unless the
+//! `strip-hot-path-logs` feature removes them, so a release build can keep
+//! `defmt` for everything else. `build.rs` sets `cfg(hot_path_log)` when they
+//! are live; code that only exists to feed one of them goes under it.
+
+#![allow(unused_macros)]
 
 use core::fmt::Write;
 
+macro_rules! log_macro {
+    ($d:tt $name:ident, $hot:ident) => {
+        macro_rules! $name {
+            ($d s:literal $d (, $d x:expr)* $d (,)?) => {{
+                #[cfg(feature = "defmt")]
+                ::defmt::$name!($d s $d (, $d x)*);
+                // Reference the arguments in a closure that is never called,
+                // so they count as used without being evaluated
+                #[cfg(not(feature = "defmt"))]
+                let _ = || ($d ( & $d x ),*);
+            }};
+        }
+
+        macro_rules! $hot {
+            ($d s:literal $d (, $d x:expr)* $d (,)?) => {{
+                #[cfg(hot_path_log)]
+                ::defmt::$name!($d s $d (, $d x)*);
+                #[cfg(not(hot_path_log))]
+                let _ = || ($d ( & $d x ),*);
+            }};
+        }
+    };
+}
+
+log_macro!($ trace, hot_trace);
+log_macro!($ debug, hot_debug);
+log_macro!($ info, hot_info);
+log_macro!($ warn, hot_warn);
+log_macro!($ error, hot_error);
+
 /// A writer that ignores everything written to it
 pub struct Sink;
 
@@ -12,14 +54,14 @@ impl Write for Sink {
 }
 
 /// Initialize logging/formatting infrastructure
-pub fn init() {
-    // This function can be extended to setup RTT, defmt, or other logging
-}
+///
+/// Nothing to do: the defmt transport is set up by the application.
+pub fn init() {}
 
 /// Print to defmt if available
 #[cfg(feature = "defmt")]
-pub fn println(args: defmt::Arguments) {
-    defmt::println!("{}", args);
+pub fn println(args: core::fmt::Arguments) {
+    defmt::println!("{}", defmt::Display2Format(&args));
 }
 
 /// Print to defmt if available (no-op otherwise)
@@ -29,4 +71,4 @@ pub fn println(_args: core::fmt::Arguments) {
 }
 
 // Note: Panic handler is intentionally not provided by the HAL
-// Applications should choose their own panic handler (panic-probe, panic-halt, etc.)
\ No newline at end of file
+// Applications should choose their own panic handler (panic-probe, panic-halt, etc.)
@@ -0,0 +1,278 @@
+//! Firmware identification block
+//!
+//! [`firmware_info!`](crate::firmware_info) places a [`FirmwareInfo`] in the
+//! `.fw_info` section, which the memory layouts put right after the vector
+//! table. It carries the crate version from the application's `Cargo.toml`;
+//! `cargo xtask stamp <elf>` then fills in the git hash, build time, image
+//! length and CRC-32 of the linked image:
+//!
+//! ```rust,ignore
+//! embassy_ht32f523xx::firmware_info!();
+//!
+//! let info = fw_info::get().unwrap();
+//! info!("firmware {}", info.version());
+//! if info.verify(&mut p.flash, &mut p.crc).is_err() {
+//!     // corrupted or unstamped image
+//! }
+//!
+//! // Identify the build on the bus, e.g. "0.3.1+1a2b3c4d5e6f"
+//! static SERIAL: StaticCell<[u8; 40]> = StaticCell::new();
+//! usb_config.device_release = info.bcd_device();
+//! usb_config.serial_number = Some(info.describe(SERIAL.init([0; 40])));
+//! ```
+//!
+//! The CRC covers the image from flash address 0 to `image_len` with the
+//! `crc` field itself skipped, so stamping does not change what it covers.
+//! [`FirmwareInfo`]'s `Display` prints all of it for a CDC or UART console.
+
+use core::fmt;
+use core::mem::offset_of;
+
+use embedded_storage::nor_flash::ReadNorFlash;
+
+use crate::crc::Crc;
+use crate::flash::Flash;
+
+/// `"HTFW"`, marks a [`FirmwareInfo`] in flash
+pub const MAGIC: u32 = u32::from_le_bytes(*b"HTFW");
+/// Value of the stamped fields before `cargo xtask stamp` runs
+pub const UNSTAMPED: u32 = 0xFFFF_FFFF;
+
+/// Firmware metadata, 48 bytes in flash
+///
+/// Field layout is shared with `cargo xtask stamp`; do not reorder.
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct FirmwareInfo {
+    /// [`MAGIC`]
+    pub magic: u32,
+    /// Version string, NUL-padded
+    pub version: [u8; 16],
+    /// Abbreviated git commit hash, NUL-padded; stamped
+    pub git_hash: [u8; 16],
+    /// Build time in seconds since the Unix epoch; stamped
+    pub build_time: u32,
+    /// Image length in bytes from flash address 0; stamped
+    pub image_len: u32,
+    /// CRC-32 (IEEE) of the image without this field; stamped
+    pub crc: u32,
+}
+
+const _: () = assert!(core::mem::size_of::<FirmwareInfo>() == 48);
+const _: () = assert!(offset_of!(FirmwareInfo, crc) == 44);
+
+/// Why [`FirmwareInfo::verify`] failed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// `cargo xtask stamp` has not run on this image
+    Unstamped,
+    /// `image_len` does not fit in flash or does not contain the block
+    BadLength,
+    /// The image does not match its CRC
+    CrcMismatch,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Error::Unstamped => "firmware image not stamped",
+            Error::BadLength => "firmware image length out of range",
+            Error::CrcMismatch => "firmware image CRC mismatch",
+        })
+    }
+}
+
+impl core::error::Error for Error {}
+
+impl FirmwareInfo {
+    /// Unstamped block for `version`, truncated to 16 bytes; used by [`firmware_info!`](crate::firmware_info)
+    pub const fn new(version: &str) -> Self {
+        Self {
+            magic: MAGIC,
+            version: pad(version),
+            git_hash: [0; 16],
+            build_time: 0,
+            image_len: UNSTAMPED,
+            crc: UNSTAMPED,
+        }
+    }
+
+    /// Version string
+    pub fn version(&self) -> &str {
+        trim(&self.version)
+    }
+
+    /// Git commit hash, if stamped
+    pub fn git_hash(&self) -> Option<&str> {
+        Some(trim(&self.git_hash)).filter(|hash| !hash.is_empty())
+    }
+
+    /// Build time in Unix seconds, if stamped
+    pub fn build_time(&self) -> Option<u32> {
+        Some(self.build_time).filter(|&t| t != 0 && t != UNSTAMPED)
+    }
+
+    /// Whether `cargo xtask stamp` has filled in the length and CRC
+    pub fn is_stamped(&self) -> bool {
+        self.image_len != UNSTAMPED
+    }
+
+    /// `major.minor` of the version as USB `bcdDevice`, e.g. 0x0301 for 3.1.x
+    pub fn bcd_device(&self) -> u16 {
+        let mut parts = self.version().split('.').map(|part| part.parse::<u16>().unwrap_or(0));
+        let major = parts.next().unwrap_or(0).min(99);
+        let minor = parts.next().unwrap_or(0).min(99);
+        ((major / 10) << 12) | ((major % 10) << 8) | ((minor / 10) << 4) | (minor % 10)
+    }
+
+    /// Check the image in flash against the stamped length and CRC
+    pub fn verify(&self, flash: &mut Flash, crc: &mut Crc) -> Result<(), Error> {
+        if !self.is_stamped() {
+            return Err(Error::Unstamped);
+        }
+        let crc_at = core::ptr::addr_of!(self.crc) as u32;
+        if self.image_len as usize > flash.capacity() || crc_at + 4 > self.image_len {
+            return Err(Error::BadLength);
+        }
+
+        crc.reset();
+        feed_flash(flash, crc, 0, crc_at)?;
+        feed_flash(flash, crc, crc_at + 4, self.image_len)?;
+        let actual = crc.finish();
+        if actual != self.crc {
+            warn!("fw_info: image CRC {:#x}, expected {:#x}", actual, self.crc);
+            return Err(Error::CrcMismatch);
+        }
+        Ok(())
+    }
+
+    /// Write `version[+git_hash]` into `buf`, leaving out what does not fit
+    ///
+    /// Short enough for a USB string descriptor such as the serial number.
+    pub fn describe<'b>(&self, buf: &'b mut [u8]) -> &'b str {
+        let mut out = SliceWriter { buf, len: 0 };
+        let _ = fmt::Write::write_str(&mut out, self.version());
+        if let Some(hash) = self.git_hash() {
+            let _ = fmt::Write::write_str(&mut out, "+");
+            let _ = fmt::Write::write_str(&mut out, hash);
+        }
+        let SliceWriter { buf, len } = out;
+        core::str::from_utf8(&buf[..len]).unwrap_or("")
+    }
+}
+
+/// `0.3.1+1a2b3c4d5e6f built 1760000000 len 23456 crc 0x89abcdef`
+impl fmt::Display for FirmwareInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.version())?;
+        if let Some(hash) = self.git_hash() {
+            write!(f, "+{}", hash)?;
+        }
+        if let Some(time) = self.build_time() {
+            write!(f, " built {}", time)?;
+        }
+        if self.is_stamped() {
+            write!(f, " len {} crc {:#010x}", self.image_len, self.crc)?;
+        } else {
+            f.write_str(" unstamped")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for FirmwareInfo {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "{} {} built {} len {} crc {:#x}",
+            self.version(),
+            self.git_hash().unwrap_or("-"),
+            self.build_time().unwrap_or(0),
+            self.image_len,
+            self.crc
+        )
+    }
+}
+
+unsafe extern "Rust" {
+    // Defined by `firmware_info!` in the application
+    static _FW_INFO: FirmwareInfo;
+}
+
+/// The application's [`FirmwareInfo`], or `None` if the block is missing or erased
+///
+/// Fails to link unless the application invokes [`firmware_info!`](crate::firmware_info).
+pub fn get() -> Option<&'static FirmwareInfo> {
+    // Read through the symbol so the stamped values are not folded at compile time
+    let info = unsafe { &*core::ptr::addr_of!(_FW_INFO) };
+    let magic = unsafe { core::ptr::read_volatile(&info.magic) };
+    (magic == MAGIC).then_some(info)
+}
+
+/// Place the application's [`FirmwareInfo`] in the `.fw_info` section
+///
+/// The version defaults to the package version of the invoking crate.
+#[macro_export]
+macro_rules! firmware_info {
+    () => {
+        $crate::firmware_info!(env!("CARGO_PKG_VERSION"));
+    };
+    ($version:expr) => {
+        #[used]
+        #[unsafe(no_mangle)]
+        #[unsafe(link_section = ".fw_info")]
+        static _FW_INFO: $crate::fw_info::FirmwareInfo = $crate::fw_info::FirmwareInfo::new($version);
+    };
+}
+
+fn feed_flash(flash: &mut Flash, crc: &mut Crc, start: u32, end: u32) -> Result<(), Error> {
+    let mut chunk = [0u8; 64];
+    let mut offset = start;
+    while offset < end {
+        let n = (end - offset).min(chunk.len() as u32) as usize;
+        flash.read(offset, &mut chunk[..n]).map_err(|_| Error::BadLength)?;
+        crc.feed(&chunk[..n]);
+        offset += n as u32;
+    }
+    Ok(())
+}
+
+const fn pad(s: &str) -> [u8; 16] {
+    let bytes = s.as_bytes();
+    let mut out = [0u8; 16];
+    let mut i = 0;
+    while i < bytes.len() && i < out.len() {
+        out[i] = bytes[i];
+        i += 1;
+    }
+    out
+}
+
+fn trim(bytes: &[u8]) -> &str {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    // Cut at the last whole character if truncation split one
+    match core::str::from_utf8(&bytes[..len]) {
+        Ok(s) => s,
+        Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or(""),
+    }
+}
+
+/// `fmt::Write` into a byte slice that stops at the end
+struct SliceWriter<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl fmt::Write for SliceWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = self.buf.len() - self.len;
+        if s.len() > room {
+            return Err(fmt::Error);
+        }
+        self.buf[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+        self.len += s.len();
+        Ok(())
+    }
+}
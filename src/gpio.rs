@@ -66,6 +66,12 @@ macro_rules! gpio_op {
         $gpio.pdr().modify(|r, w| w.bits(r.bits() | (1 << $pin)));
         $gpio.pur().modify(|r, w| w.bits(r.bits() & !(1 << $pin)));
     }};
+    ($gpio:expr, $pin:expr, enable_input) => {
+        $gpio.iner().modify(|r, w| w.bits(r.bits() | (1 << $pin)))
+    };
+    ($gpio:expr, $pin:expr, disable_input) => {
+        $gpio.iner().modify(|r, w| w.bits(r.bits() & !(1 << $pin)))
+    };
     ($gpio:expr, $pin:expr, disable_pull) => {{
         $gpio.pur().modify(|r, w| w.bits(r.bits() & !(1 << $pin)));
         $gpio.pdr().modify(|r, w| w.bits(r.bits() & !(1 << $pin)));
@@ -75,14 +81,33 @@ use embedded_hal::digital::{ErrorType, InputPin, OutputPin, StatefulOutputPin};
 use embedded_hal_async::digital::Wait;
 use crate::pac::{Gpioa, Gpiob, Gpioc, Gpiod, Afio};
 use crate::exti::{ExtiChannel, Edge};
+use crate::peripheral::{Peri, Peripheral, PeripheralType};
 
 /// GPIO error type
+///
+/// Pin reads and writes cannot fail, so this has no values; `unwrap()` on a
+/// GPIO result never panics.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub struct GpioError;
+pub enum GpioError {}
 
 impl embedded_hal::digital::Error for GpioError {
     fn kind(&self) -> embedded_hal::digital::ErrorKind {
-        embedded_hal::digital::ErrorKind::Other
+        match *self {}
+    }
+}
+
+impl core::fmt::Display for GpioError {
+    fn fmt(&self, _f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match *self {}
+    }
+}
+
+impl core::error::Error for GpioError {}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for GpioError {
+    fn format(&self, _f: defmt::Formatter) {
+        match *self {}
     }
 }
 
@@ -149,11 +174,31 @@ pub mod mode {
     pub type AF7 = AlternateFunction<7>;
 }
 
+/// AFIO function number of the ADC/comparator analog inputs
+const AF_ANALOG: u8 = 2;
+
 /// GPIO pin
 pub struct Pin<const PORT: char, const PIN: u8, MODE> {
     _mode: PhantomData<MODE>,
 }
 
+unsafe impl<const PORT: char, const PIN: u8, MODE> PeripheralType for Pin<PORT, PIN, MODE> {
+    unsafe fn steal() -> Self {
+        Pin { _mode: PhantomData }
+    }
+}
+
+impl<const PORT: char, const PIN: u8, MODE> Peripheral for Pin<PORT, PIN, MODE> {
+    type P = Self;
+
+    fn into_ref<'d>(self) -> Peri<'d, Self>
+    where
+        Self: 'd,
+    {
+        unsafe { Peri::new_unchecked(self) }
+    }
+}
+
 // Type aliases for specific pins - GPIOA
 pub type PA0 = Pin<'A', 0, mode::Input>;
 pub type PA1 = Pin<'A', 1, mode::Input>;
@@ -253,6 +298,219 @@ impl AnyPin {
     pub fn pin(&self) -> u8 {
         self.pin
     }
+
+    /// Configure as a push-pull output driving `level`
+    pub fn set_as_output(&mut self, level: Level) {
+        if level == Level::High {
+            gpio_impl!(self.port, self.pin, set_high);
+        } else {
+            gpio_impl!(self.port, self.pin, set_low);
+        }
+        gpio_impl!(self.port, self.pin, set_output);
+    }
+
+    /// Configure as an input with `pull`
+    pub fn set_as_input(&mut self, pull: Pull) {
+        gpio_impl!(self.port, self.pin, enable_input);
+        match pull {
+            Pull::None => gpio_impl!(self.port, self.pin, disable_pull),
+            Pull::Up => gpio_impl!(self.port, self.pin, enable_pullup),
+            Pull::Down => gpio_impl!(self.port, self.pin, enable_pulldown),
+        }
+        gpio_impl!(self.port, self.pin, set_input);
+    }
+
+    /// Configure as an emulated open-drain line with pull-up, released
+    ///
+    /// The output latch is held low and the pin switches between output
+    /// (pulled low) and input (released), see [`pull_low`](Self::pull_low).
+    pub fn set_as_open_drain(&mut self) {
+        gpio_impl!(self.port, self.pin, enable_input);
+        gpio_impl!(self.port, self.pin, enable_pullup);
+        gpio_impl!(self.port, self.pin, set_low);
+        gpio_impl!(self.port, self.pin, set_input);
+    }
+
+    /// Drive an open-drain line low
+    pub(crate) fn pull_low(&self) {
+        gpio_impl!(self.port, self.pin, set_output);
+    }
+
+    /// Let an open-drain line float to its pull-up
+    pub(crate) fn release(&self) {
+        gpio_impl!(self.port, self.pin, set_input);
+    }
+
+    /// Line level, including while another device pulls it
+    pub(crate) fn level(&self) -> bool {
+        gpio_impl!(self.port, self.pin, read_input)
+    }
+}
+
+/// Drive several pins of one port in a single write
+///
+/// `set` and `reset` are pin masks; a pin in both ends up set.
+pub(crate) fn write_port(port: char, set: u16, reset: u16) {
+    // PxSRR: SET[15:0], RST[31:16]
+    let bits = set as u32 | ((reset & !set) as u32) << 16;
+    unsafe {
+        match port {
+            'A' => (*Gpioa::ptr()).srr().write(|w| w.bits(bits)),
+            'B' => (*Gpiob::ptr()).srr().write(|w| w.bits(bits)),
+            'C' => (*Gpioc::ptr()).srr().write(|w| w.bits(bits)),
+            'D' => (*Gpiod::ptr()).srr().write(|w| w.bits(bits)),
+            _ => panic!("Invalid GPIO port"),
+        }
+    };
+}
+
+/// Input levels of a whole port
+pub(crate) fn read_port(port: char) -> u16 {
+    unsafe {
+        match port {
+            'A' => (*Gpioa::ptr()).dinr().read().bits() as u16,
+            'B' => (*Gpiob::ptr()).dinr().read().bits() as u16,
+            'C' => (*Gpioc::ptr()).dinr().read().bits() as u16,
+            'D' => (*Gpiod::ptr()).dinr().read().bits() as u16,
+            _ => panic!("Invalid GPIO port"),
+        }
+    }
+}
+
+/// Switch several pins of one port between output and input
+///
+/// `output` and `input` are pin masks; a pin in both ends up an output. The
+/// direction register is read-modify-written, so the caller must be the only
+/// one changing directions on the port at the time.
+pub(crate) fn set_port_direction(port: char, output: u16, input: u16) {
+    let update = |dir: u32| (dir & !(input as u32)) | output as u32;
+    unsafe {
+        match port {
+            'A' => (*Gpioa::ptr()).dircr().modify(|r, w| w.bits(update(r.bits()))),
+            'B' => (*Gpiob::ptr()).dircr().modify(|r, w| w.bits(update(r.bits()))),
+            'C' => (*Gpioc::ptr()).dircr().modify(|r, w| w.bits(update(r.bits()))),
+            'D' => (*Gpiod::ptr()).dircr().modify(|r, w| w.bits(update(r.bits()))),
+            _ => panic!("Invalid GPIO port"),
+        }
+    };
+}
+
+/// Number of GPIO ports, A to D
+const PORT_COUNT: usize = 4;
+
+/// Up to 32 pins on any ports, read and written a port at a time
+///
+/// [`AnyPin`] works out its port on every access. A `PinGroup` works out the
+/// per-port masks once, so [`read`](Self::read) costs one input register read
+/// per port in use and [`write`](Self::write) one set/reset write per port,
+/// however many pins there are. Bit `i` of the values is `pins[i]`:
+///
+/// ```rust,ignore
+/// let mut cols = PinGroup::new([pa0.degrade(), pa1.degrade(), pb4.degrade(), pc2.degrade()]);
+/// cols.set_as_inputs(Pull::Up);
+/// for row in &mut rows {
+///     row.set_low()?;
+///     let pressed = !cols.read() & 0b1111;
+///     row.set_high()?;
+/// }
+/// ```
+pub struct PinGroup<const N: usize> {
+    pins: [AnyPin; N],
+    /// Port index (0 = A) and pin number of each group bit
+    bits: [(u8, u8); N],
+    /// Group pins on each port
+    masks: [u16; PORT_COUNT],
+}
+
+impl<const N: usize> PinGroup<N> {
+    const CHECK: () = assert!(N <= 32, "a PinGroup holds at most 32 pins");
+
+    /// Group `pins`, keeping their current configuration
+    pub fn new(pins: [AnyPin; N]) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::CHECK;
+
+        let bits = core::array::from_fn(|i| ((pins[i].port() as u8 - b'A'), pins[i].pin()));
+        let mut masks = [0; PORT_COUNT];
+        for &(port, pin) in &bits {
+            masks[port as usize] |= 1 << pin;
+        }
+        Self { pins, bits, masks }
+    }
+
+    /// Configure every pin as a push-pull output driving `level`
+    pub fn set_as_outputs(&mut self, level: Level) {
+        for pin in &mut self.pins {
+            pin.set_as_output(level);
+        }
+    }
+
+    /// Configure every pin as an input with `pull`
+    pub fn set_as_inputs(&mut self, pull: Pull) {
+        for pin in &mut self.pins {
+            pin.set_as_input(pull);
+        }
+    }
+
+    /// Input levels, bit `i` for `pins[i]`
+    pub fn read(&self) -> u32 {
+        let mut levels = [0u16; PORT_COUNT];
+        for (port, &mask) in self.masks.iter().enumerate() {
+            if mask != 0 {
+                levels[port] = read_port(port_name(port));
+            }
+        }
+        self.bits.iter().enumerate().fold(0, |value, (i, &(port, pin))| {
+            value | ((((levels[port as usize] >> pin) & 1) as u32) << i)
+        })
+    }
+
+    /// Drive every output, bit `i` of `value` for `pins[i]`
+    pub fn write(&mut self, value: u32) {
+        let mut set = [0u16; PORT_COUNT];
+        for (i, &(port, pin)) in self.bits.iter().enumerate() {
+            if value & (1 << i) != 0 {
+                set[port as usize] |= 1 << pin;
+            }
+        }
+        for (port, &mask) in self.masks.iter().enumerate() {
+            if mask != 0 {
+                write_port(port_name(port), set[port], mask);
+            }
+        }
+    }
+
+    /// Drive `pins[index]` high
+    pub fn set_high(&mut self, index: usize) {
+        let (port, pin) = self.bits[index];
+        write_port(port_name(port as usize), 1 << pin, 0);
+    }
+
+    /// Drive `pins[index]` low
+    pub fn set_low(&mut self, index: usize) {
+        let (port, pin) = self.bits[index];
+        write_port(port_name(port as usize), 0, 1 << pin);
+    }
+
+    /// Input level of `pins[index]`
+    pub fn is_high(&self, index: usize) -> bool {
+        let (port, pin) = self.bits[index];
+        read_port(port_name(port as usize)) & (1 << pin) != 0
+    }
+
+    /// The grouped pins
+    pub fn pins(&self) -> &[AnyPin; N] {
+        &self.pins
+    }
+
+    /// Split the group up again
+    pub fn into_pins(self) -> [AnyPin; N] {
+        self.pins
+    }
+}
+
+fn port_name(index: usize) -> char {
+    (b'A' + index as u8) as char
 }
 
 // Implement embedded-hal traits for AnyPin
@@ -292,12 +550,21 @@ impl embedded_hal::digital::InputPin for AnyPin {
     }
 }
 
+/// Pause between pin polls in the async `Wait` implementation
+async fn poll_delay() {
+    #[cfg(feature = "time")]
+    embassy_time::Timer::after(embassy_time::Duration::from_micros(10)).await;
+    // Without embassy-time just let other tasks run before polling again
+    #[cfg(not(feature = "time"))]
+    embassy_futures::yield_now().await;
+}
+
 // Implement embedded-hal-async traits for AnyPin
 impl embedded_hal_async::digital::Wait for AnyPin {
     async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
         // Simple polling implementation - in a real implementation this would use interrupts
         while self.is_low()? {
-            embassy_time::Timer::after(embassy_time::Duration::from_micros(10)).await;
+            poll_delay().await;
         }
         Ok(())
     }
@@ -305,7 +572,7 @@ impl embedded_hal_async::digital::Wait for AnyPin {
     async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
         // Simple polling implementation - in a real implementation this would use interrupts
         while self.is_high()? {
-            embassy_time::Timer::after(embassy_time::Duration::from_micros(10)).await;
+            poll_delay().await;
         }
         Ok(())
     }
@@ -326,7 +593,7 @@ impl embedded_hal_async::digital::Wait for AnyPin {
             if self.is_high()? != initial_state {
                 return Ok(());
             }
-            embassy_time::Timer::after(embassy_time::Duration::from_micros(10)).await;
+            poll_delay().await;
         }
     }
 }
@@ -378,8 +645,9 @@ impl<const PORT: char, const PIN: u8, MODE> Pin<PORT, PIN, MODE> {
 
     /// Convert pin to input mode with pull configuration
     pub fn into_input_with_pull(self, pull: Pull) -> Pin<PORT, PIN, mode::Input> {
-        // Configure pin as input
+        // Configure pin as input with the digital input buffer enabled
         gpio_impl!(PORT, PIN, set_input);
+        gpio_impl!(PORT, PIN, enable_input);
 
         // Configure pull-up/pull-down
         configure_pull::<PORT, PIN>(pull);
@@ -387,6 +655,16 @@ impl<const PORT: char, const PIN: u8, MODE> Pin<PORT, PIN, MODE> {
         Pin { _mode: PhantomData }
     }
 
+    /// Convert pin to analog mode
+    ///
+    /// Disables the Schmitt-trigger input and pulls and routes the pin to the
+    /// ADC/comparator function. This is also the lowest-leakage pin state.
+    pub fn into_analog(self) -> Pin<PORT, PIN, mode::Analog> {
+        set_analog(PORT, PIN);
+
+        Pin { _mode: PhantomData }
+    }
+
     /// Convert pin to alternate function mode
     pub fn into_alternate_function<const AF: u8>(self) -> Pin<PORT, PIN, mode::AlternateFunction<AF>> {
         // For HT32, alternate function is configured through AFIO only
@@ -493,84 +771,52 @@ fn configure_pull<const PORT: char, const PIN: u8>(pull: Pull) {
 
 
 unsafe fn configure_alternate_function<const PORT: char, const PIN: u8, const AF: u8>() {
+    set_alternate_function(PORT, PIN, AF);
+}
+
+/// Route `pin` of `port` to alternate function `af` in AFIO
+fn set_alternate_function(port: char, pin: u8, af: u8) {
     // Configure AFIO for alternate function
     let afio = unsafe { &*Afio::ptr() };
 
     // HT32 uses different AFIO registers for each GPIO port
     // Each port has two registers: low (pins 0-7) and high (pins 8-15)
-    match PORT {
-        'A' => {
-            if PIN < 8 {
-                afio.gpacfglr().modify(|r, w| {
-                    let mut val = r.bits();
-                    val &= !(0b1111 << (PIN * 4));  // Clear AF bits (4 bits per pin)
-                    val |= (AF as u32) << (PIN * 4); // Set AF value
-                    unsafe { w.bits(val) }
-                });
-            } else {
-                afio.gpacfghr().modify(|r, w| {
-                    let mut val = r.bits();
-                    val &= !(0b1111 << ((PIN - 8) * 4));  // Clear AF bits
-                    val |= (AF as u32) << ((PIN - 8) * 4); // Set AF value
-                    unsafe { w.bits(val) }
-                });
-            }
-        }
-        'B' => {
-            if PIN < 8 {
-                afio.gpbcfglr().modify(|r, w| {
-                    let mut val = r.bits();
-                    val &= !(0b1111 << (PIN * 4));
-                    val |= (AF as u32) << (PIN * 4);
-                    unsafe { w.bits(val) }
-                });
-            } else {
-                afio.gpbcfghr().modify(|r, w| {
-                    let mut val = r.bits();
-                    val &= !(0b1111 << ((PIN - 8) * 4));
-                    val |= (AF as u32) << ((PIN - 8) * 4);
-                    unsafe { w.bits(val) }
-                });
-            }
-        }
-        'C' => {
-            if PIN < 8 {
-                afio.gpccfglr().modify(|r, w| {
-                    let mut val = r.bits();
-                    val &= !(0b1111 << (PIN * 4));
-                    val |= (AF as u32) << (PIN * 4);
-                    unsafe { w.bits(val) }
-                });
-            } else {
-                afio.gpccfghr().modify(|r, w| {
-                    let mut val = r.bits();
-                    val &= !(0b1111 << ((PIN - 8) * 4));
-                    val |= (AF as u32) << ((PIN - 8) * 4);
-                    unsafe { w.bits(val) }
-                });
-            }
-        }
-        'D' => {
-            if PIN < 8 {
-                afio.gpdcfglr().modify(|r, w| {
-                    let mut val = r.bits();
-                    val &= !(0b1111 << (PIN * 4));
-                    val |= (AF as u32) << (PIN * 4);
-                    unsafe { w.bits(val) }
-                });
-            } else {
-                afio.gpdcfghr().modify(|r, w| {
-                    let mut val = r.bits();
-                    val &= !(0b1111 << ((PIN - 8) * 4));
-                    val |= (AF as u32) << ((PIN - 8) * 4);
-                    unsafe { w.bits(val) }
-                });
-            }
-        }
+    let shift = (pin % 8) * 4;
+    let update = |bits: u32| (bits & !(0b1111 << shift)) | ((af as u32) << shift);
+
+    match (port, pin < 8) {
+        ('A', true) => afio.gpacfglr().modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+        ('A', false) => afio.gpacfghr().modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+        ('B', true) => afio.gpbcfglr().modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+        ('B', false) => afio.gpbcfghr().modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+        ('C', true) => afio.gpccfglr().modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+        ('C', false) => afio.gpccfghr().modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+        ('D', true) => afio.gpdcfglr().modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+        ('D', false) => afio.gpdcfghr().modify(|r, w| unsafe { w.bits(update(r.bits())) }),
         _ => panic!("Invalid GPIO port for AF configuration"),
     }
 }
 
+/// Put a pin into its lowest-leakage state: input direction, no pulls,
+/// digital input buffer off and routed to the analog function
+fn set_analog(port: char, pin: u8) {
+    gpio_impl!(port, pin, set_input);
+    gpio_impl!(port, pin, disable_pull);
+    gpio_impl!(port, pin, disable_input);
+    set_alternate_function(port, pin, AF_ANALOG);
+}
+
+/// Park unused pins in the lowest-leakage state
+///
+/// Floating digital inputs draw shoot-through current in the Schmitt trigger;
+/// parked pins have the input buffer disabled and no pulls. Call this for every
+/// unconnected pin on battery builds before entering sleep.
+pub fn park_unused(pins: &[AnyPin]) {
+    for pin in pins {
+        set_analog(pin.port(), pin.pin());
+    }
+}
+
 /// GPIO port abstractions
 pub struct PortA {
     _private: (),
@@ -588,6 +834,8 @@ pub struct PortD {
     _private: (),
 }
 
+impl_peripheral!(PortA, PortB, PortC, PortD);
+
 impl PortA {
     pub(crate) fn new() -> Self {
         Self { _private: () }
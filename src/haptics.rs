@@ -0,0 +1,209 @@
+//! Haptic feedback on ERM and LRA vibration motors
+//!
+//! [`Haptics`] plays amplitude envelopes on a PWM channel: a short full-power
+//! kick for a click, a held level for a buzz, ramps for a swell. Envelopes are
+//! lists of [`Step`]s, stepped every [`UPDATE_MS`] from `embassy-time`.
+//! Keyboard code rarely wants to wait for a 150 ms buzz, so patterns are
+//! usually posted to a [`HapticQueue`] and played by a task:
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::haptics::{self, Actuator, HapticQueue, Haptics};
+//!
+//! static HAPTICS: HapticQueue = HapticQueue::new();
+//!
+//! #[embassy_executor::task]
+//! async fn haptics_task(pwm: Pwm<'static, Gptm1>) {
+//!     Haptics::new(pwm, Channel::Ch2, Actuator::Erm).run(&HAPTICS).await
+//! }
+//!
+//! // In the key scan loop; never blocks
+//! HAPTICS.trigger(haptics::CLICK);
+//! ```
+//!
+//! An ERM needs only a logic-level MOSFET and a flyback diode, with the duty
+//! cycle as drive strength. An LRA must be driven at its resonance, so it
+//! goes through a driver such as the DRV2603 in PWM-input mode, which tracks
+//! the resonance itself and reads the amplitude from the duty cycle around
+//! 50 % ([`Actuator::Lra`]). Either way run the PWM above 20 kHz so the
+//! carrier is not audible.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel as PatternChannel;
+use embassy_time::{Duration, Ticker};
+
+use crate::timer::{self, Channel, Pwm};
+
+/// Envelope resolution in ms
+pub const UPDATE_MS: u16 = 2;
+
+/// Patterns a [`HapticQueue`] buffers
+pub const QUEUE_LEN: usize = 4;
+
+/// Duty cycle resolution: 0..=255 for an ERM, 255 ± 255 for an LRA
+const DUTY_STEPS: u16 = 510;
+
+/// One segment of an envelope; amplitudes run from 0 (off) to 255
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Step {
+    /// Jump to an amplitude
+    Level(u8),
+    /// Keep the current amplitude for a number of ms
+    Hold(u16),
+    /// Move linearly to an amplitude over a number of ms
+    Ramp(u8, u16),
+}
+
+/// An envelope; the motor returns to rest after the last step
+pub type Pattern = &'static [Step];
+
+/// Short, hard tap for key presses; full power overcomes the motor's spin-up
+pub const CLICK: Pattern = &[Step::Level(255), Step::Hold(12)];
+/// Lighter tap, e.g. for key releases or encoder detents
+pub const TICK: Pattern = &[Step::Level(160), Step::Hold(6)];
+/// Two clicks, e.g. for a layer change
+pub const DOUBLE_CLICK: Pattern = &[
+    Step::Level(255),
+    Step::Hold(12),
+    Step::Level(0),
+    Step::Hold(60),
+    Step::Level(255),
+    Step::Hold(12),
+];
+/// Sustained vibration, e.g. for caps lock or an error
+pub const BUZZ: Pattern = &[Step::Level(200), Step::Hold(150)];
+/// Swell and fade, e.g. for a notification
+pub const PULSE: Pattern = &[Step::Ramp(255, 120), Step::Ramp(0, 120)];
+
+/// How the duty cycle maps to drive strength
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Actuator {
+    /// Eccentric rotating mass on a low-side switch: 0 % duty is rest
+    Erm,
+    /// Linear resonant actuator behind a PWM-input driver: 50 % duty is rest
+    Lra,
+}
+
+/// Patterns waiting for a [`Haptics`] task
+pub struct HapticQueue {
+    patterns: PatternChannel<CriticalSectionRawMutex, Pattern, QUEUE_LEN>,
+}
+
+impl HapticQueue {
+    /// Create an empty queue, usually as a `static`
+    pub const fn new() -> Self {
+        Self {
+            patterns: PatternChannel::new(),
+        }
+    }
+
+    /// Queue a pattern without waiting; returns `false` and drops it when the queue is full
+    pub fn trigger(&self, pattern: Pattern) -> bool {
+        self.patterns.try_send(pattern).is_ok()
+    }
+
+    /// Queue a pattern, waiting for room
+    pub async fn send(&self, pattern: Pattern) {
+        self.patterns.send(pattern).await
+    }
+
+    /// Drop the patterns not yet started
+    pub fn clear(&self) {
+        self.patterns.clear();
+    }
+}
+
+impl Default for HapticQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Envelope player on one PWM channel
+pub struct Haptics<'d, T: timer::Instance> {
+    pwm: Pwm<'d, T>,
+    channel: Channel,
+    actuator: Actuator,
+    intensity: u8,
+    level: u8,
+}
+
+impl<'d, T: timer::Instance> Haptics<'d, T> {
+    /// Take over `channel` and put the motor at rest
+    ///
+    /// `pwm` must already run at the carrier frequency, see the module docs.
+    pub fn new(mut pwm: Pwm<'d, T>, channel: Channel, actuator: Actuator) -> Self {
+        pwm.enable_channel(channel);
+        let mut haptics = Self {
+            pwm,
+            channel,
+            actuator,
+            intensity: 255,
+            level: 0,
+        };
+        haptics.set_level(0);
+        haptics
+    }
+
+    /// Scale every pattern, 255 for full strength; a user setting
+    pub fn set_intensity(&mut self, intensity: u8) {
+        self.intensity = intensity;
+    }
+
+    /// Play one pattern to the end, then rest
+    ///
+    /// Cancelling leaves the motor at the current amplitude; call
+    /// [`stop`](Self::stop) afterwards.
+    pub async fn play(&mut self, pattern: Pattern) {
+        let mut ticker = Ticker::every(Duration::from_millis(UPDATE_MS as u64));
+        for &step in pattern {
+            match step {
+                Step::Level(level) => self.set_level(level),
+                Step::Hold(ms) => {
+                    for _ in 0..ms.div_ceil(UPDATE_MS) {
+                        ticker.next().await;
+                    }
+                }
+                Step::Ramp(to, ms) => {
+                    let from = self.level as i32;
+                    let updates = ms.div_ceil(UPDATE_MS).max(1) as i32;
+                    for i in 1..=updates {
+                        ticker.next().await;
+                        self.set_level((from + (to as i32 - from) * i / updates) as u8);
+                    }
+                }
+            }
+        }
+        self.stop();
+    }
+
+    /// Play patterns from `queue` forever
+    pub async fn run(&mut self, queue: &HapticQueue) -> ! {
+        loop {
+            let pattern = queue.patterns.receive().await;
+            self.play(pattern).await;
+        }
+    }
+
+    /// Put the motor at rest
+    pub fn stop(&mut self) {
+        self.set_level(0);
+    }
+
+    /// Release the PWM, with the motor at rest
+    pub fn into_pwm(mut self) -> Pwm<'d, T> {
+        self.stop();
+        self.pwm
+    }
+
+    fn set_level(&mut self, level: u8) {
+        self.level = level;
+        let amplitude = level as u16 * self.intensity as u16 / 255;
+        let duty = match self.actuator {
+            Actuator::Erm => 2 * amplitude,
+            Actuator::Lra => DUTY_STEPS / 2 + amplitude,
+        };
+        self.pwm.set_duty_cycle(self.channel, duty, DUTY_STEPS);
+    }
+}
@@ -0,0 +1,146 @@
+//! Global allocator for crates that need `alloc`
+//!
+//! With the `alloc` feature the HAL registers a TLSF (two-level segregated
+//! fit) allocator as the `#[global_allocator]`. TLSF allocates and frees in
+//! constant time and fragments little, which matters more than speed when
+//! the whole heap is a few KB. The heap memory is a static array declared by
+//! [`init_heap!`](crate::init_heap!), so it shows up in the map file and in
+//! `cargo size` like any other buffer:
+//!
+//! ```rust,ignore
+//! extern crate alloc;
+//!
+//! let p = embassy_ht32f523xx::init(Config::default());
+//! embassy_ht32f523xx::init_heap!(4 * 1024);
+//! let report: alloc::vec::Vec<u8> = alloc::vec![0; 64];
+//! ```
+//!
+//! The size is checked twice: at compile time against [`MAX_HEAP_SIZE`],
+//! and when the heap is set up against where the statics actually end, so
+//! that `.data`, `.bss` and the heap together still leave [`STACK_RESERVE`]
+//! bytes for the stack. Allocation failure calls the default
+//! `alloc_error_handler`, i.e. panics; check [`stats`] while sizing.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::Cell;
+
+use critical_section::Mutex;
+use embedded_alloc::TlsfHeap;
+
+/// RAM kept free below the top of RAM for the main stack
+pub const STACK_RESERVE: usize = crate::RAM_SIZE / 4;
+/// Largest heap [`init_heap!`](crate::init_heap!) accepts, before counting other statics
+pub const MAX_HEAP_SIZE: usize = crate::RAM_SIZE - STACK_RESERVE;
+
+/// Heap usage, in bytes
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HeapStats {
+    /// Heap size given to [`init_heap!`](crate::init_heap!)
+    pub size: usize,
+    /// Bytes currently allocated, as requested by the callers
+    pub used: usize,
+    /// Most bytes allocated at once since the heap was set up
+    pub peak: usize,
+}
+
+/// [`TlsfHeap`] with usage counters
+pub struct Heap {
+    inner: TlsfHeap,
+    stats: Mutex<Cell<HeapStats>>,
+    ready: Mutex<Cell<bool>>,
+}
+
+#[global_allocator]
+static HEAP: Heap = Heap {
+    inner: TlsfHeap::empty(),
+    stats: Mutex::new(Cell::new(HeapStats { size: 0, used: 0, peak: 0 })),
+    ready: Mutex::new(Cell::new(false)),
+};
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { self.inner.alloc(layout) };
+        if !ptr.is_null() {
+            critical_section::with(|cs| {
+                let cell = self.stats.borrow(cs);
+                let mut stats = cell.get();
+                stats.used += layout.size();
+                stats.peak = stats.peak.max(stats.used);
+                cell.set(stats);
+            });
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.inner.dealloc(ptr, layout) };
+        critical_section::with(|cs| {
+            let cell = self.stats.borrow(cs);
+            let mut stats = cell.get();
+            stats.used -= layout.size();
+            cell.set(stats);
+        });
+    }
+}
+
+unsafe extern "C" {
+    // From cortex-m-rt's link.x: end of .data, .bss and .uninit
+    static __sheap: u32;
+}
+
+/// Compile-time size check used by [`init_heap!`](crate::init_heap!)
+#[doc(hidden)]
+pub const fn check_size(bytes: usize) {
+    assert!(bytes > 0, "heap size must not be zero");
+    assert!(bytes <= MAX_HEAP_SIZE, "heap does not fit in RAM next to the stack reserve");
+}
+
+/// Hand `len` bytes at `start` to the allocator
+///
+/// Panics if the heap is already set up or if the statics, the heap among
+/// them, leave less than [`STACK_RESERVE`] bytes at the top of RAM.
+///
+/// # Safety
+///
+/// The region must be valid, unused memory that lives forever.
+#[doc(hidden)]
+pub unsafe fn init(start: usize, len: usize) {
+    let ram_end = crate::chip::MEMORY.ram_origin as usize + crate::RAM_SIZE;
+    // The heap need not be the last static, so check where they all end
+    let statics_end = &raw const __sheap as usize;
+    assert!(
+        statics_end + STACK_RESERVE <= ram_end,
+        "heap and statics leave less than STACK_RESERVE bytes for the stack"
+    );
+
+    critical_section::with(|cs| {
+        let ready = HEAP.ready.borrow(cs);
+        assert!(!ready.get(), "heap already initialized");
+        ready.set(true);
+        unsafe { HEAP.inner.init(start, len) };
+        HEAP.stats.borrow(cs).set(HeapStats { size: len, used: 0, peak: 0 });
+    });
+    debug!("heap: {} bytes at {:#x}, {} bytes left above the statics", len, start, ram_end - statics_end);
+}
+
+/// Current heap usage
+pub fn stats() -> HeapStats {
+    critical_section::with(|cs| HEAP.stats.borrow(cs).get())
+}
+
+/// Set up the global heap with a static buffer of `$bytes` bytes
+///
+/// Call once, early in `main`; a second call panics. The size must be a
+/// constant expression.
+#[macro_export]
+macro_rules! init_heap {
+    ($bytes:expr) => {{
+        const HEAP_BYTES: usize = $bytes;
+        const _: () = $crate::heap::check_size(HEAP_BYTES);
+        static mut HEAP_MEM: [::core::mem::MaybeUninit<u8>; HEAP_BYTES] =
+            [::core::mem::MaybeUninit::uninit(); HEAP_BYTES];
+        // SAFETY: HEAP_MEM is only reachable from here, and `init` refuses a second call
+        unsafe { $crate::heap::init(&raw mut HEAP_MEM as usize, HEAP_BYTES) }
+    }};
+}
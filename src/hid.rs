@@ -0,0 +1,545 @@
+//! HID keyboard and mouse report descriptors and report builders
+//!
+//! Ready-made report descriptors for a keyboard interface, either the boot
+//! protocol layout (6 keys, [`BOOT_KEYBOARD_DESCRIPTOR`]) or n-key rollover
+//! ([`NKRO_KEYBOARD_DESCRIPTOR`]), plus a system/consumer control interface
+//! ([`EXTRA_KEYS_DESCRIPTOR`]). [`KeyBitmap`] tracks the pressed keys and
+//! produces either report, so the scan code only deals in HID usages:
+//!
+//! ```rust,ignore
+//! let keyboard = HidWriter::<_, NKRO_REPORT_SIZE>::new(&mut builder, STATE.init(State::new()), hid::Config {
+//!     report_descriptor: embassy_ht32f523xx::hid::NKRO_KEYBOARD_DESCRIPTOR,
+//!     request_handler: None,
+//!     poll_ms: 1,
+//!     max_packet_size: 32,
+//! });
+//!
+//! let mut keys = KeyBitmap::new();
+//! keys.press(usage::KEY_A);
+//! keys.press(usage::LEFT_SHIFT);
+//! keyboard.write(&keys.nkro_report()).await?;
+//!
+//! extra_keys.write(&consumer_report(usage::VOLUME_UP)).await?;
+//! extra_keys.write(&consumer_report(0)).await?; // released
+//! ```
+//!
+//! [`Mouse`] does the same for a pointing device ([`MOUSE_DESCRIPTOR`]). It
+//! takes motion in 1/[`SUBPIXEL`] counts and carries the fractions over to the
+//! next report, so a trackpoint with a scaled or accelerated transfer curve
+//! moves smoothly instead of losing every sub-count step:
+//!
+//! ```rust,ignore
+//! let mut mouse = Mouse::new();
+//! loop {
+//!     let (dx, dy) = trackpoint.read().await;
+//!     mouse.move_by(dx * speed, dy * speed); // speed in 1/256 counts
+//!     if let Some(report) = mouse.report() {
+//!         pointer.write(&report.to_bytes()).await?;
+//!     }
+//! }
+//! ```
+//!
+//! Nothing here touches the hardware; the descriptors only describe the
+//! report layouts the builders produce.
+
+/// Boot keyboard report: modifiers, reserved byte, six key usages
+pub const BOOT_REPORT_SIZE: usize = 8;
+/// NKRO report: modifiers, then one bit per usage 0x00..=0xDF
+pub const NKRO_REPORT_SIZE: usize = 1 + BITMAP_BYTES;
+/// System or consumer control report: report ID and one 16-bit usage
+pub const EXTRA_KEYS_REPORT_SIZE: usize = 3;
+/// Mouse report: buttons, 16-bit X and Y, wheel, horizontal pan
+pub const MOUSE_REPORT_SIZE: usize = 7;
+
+/// Report ID of [`system_report`]
+pub const SYSTEM_REPORT_ID: u8 = 1;
+/// Report ID of [`consumer_report`]
+pub const CONSUMER_REPORT_ID: u8 = 2;
+
+/// Keys the NKRO bitmap covers, usages 0x00..=0xDF
+const BITMAP_KEYS: usize = 0xE0;
+const BITMAP_BYTES: usize = BITMAP_KEYS / 8;
+
+const _: () = assert!(NKRO_REPORT_SIZE <= 64, "NKRO report must fit a full-speed interrupt packet");
+
+/// Boot protocol keyboard, 8-byte input report and 1-byte LED output report
+pub const BOOT_KEYBOARD_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, //       Usage Page (Generic Desktop)
+    0x09, 0x06, //       Usage (Keyboard)
+    0xA1, 0x01, //       Collection (Application)
+    0x05, 0x07, //         Usage Page (Keyboard/Keypad)
+    0x19, 0xE0, //         Usage Minimum (Left Control)
+    0x29, 0xE7, //         Usage Maximum (Right GUI)
+    0x15, 0x00, //         Logical Minimum (0)
+    0x25, 0x01, //         Logical Maximum (1)
+    0x75, 0x01, //         Report Size (1)
+    0x95, 0x08, //         Report Count (8)
+    0x81, 0x02, //         Input (Data, Var, Abs): modifiers
+    0x75, 0x08, //         Report Size (8)
+    0x95, 0x01, //         Report Count (1)
+    0x81, 0x01, //         Input (Const): reserved
+    0x05, 0x08, //         Usage Page (LEDs)
+    0x19, 0x01, //         Usage Minimum (Num Lock)
+    0x29, 0x05, //         Usage Maximum (Kana)
+    0x75, 0x01, //         Report Size (1)
+    0x95, 0x05, //         Report Count (5)
+    0x91, 0x02, //         Output (Data, Var, Abs): LEDs
+    0x75, 0x03, //         Report Size (3)
+    0x95, 0x01, //         Report Count (1)
+    0x91, 0x01, //         Output (Const): padding
+    0x05, 0x07, //         Usage Page (Keyboard/Keypad)
+    0x19, 0x00, //         Usage Minimum (0)
+    0x2A, 0xFF, 0x00, //   Usage Maximum (255)
+    0x15, 0x00, //         Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08, //         Report Size (8)
+    0x95, 0x06, //         Report Count (6)
+    0x81, 0x00, //         Input (Data, Array, Abs): keys
+    0xC0, //             End Collection
+];
+
+/// N-key rollover keyboard, [`NKRO_REPORT_SIZE`]-byte input report and 1-byte LED output report
+///
+/// Not usable in the BIOS; offer [`BOOT_KEYBOARD_DESCRIPTOR`] on a second
+/// interface or a firmware switch where that matters.
+pub const NKRO_KEYBOARD_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, //       Usage Page (Generic Desktop)
+    0x09, 0x06, //       Usage (Keyboard)
+    0xA1, 0x01, //       Collection (Application)
+    0x05, 0x07, //         Usage Page (Keyboard/Keypad)
+    0x19, 0xE0, //         Usage Minimum (Left Control)
+    0x29, 0xE7, //         Usage Maximum (Right GUI)
+    0x15, 0x00, //         Logical Minimum (0)
+    0x25, 0x01, //         Logical Maximum (1)
+    0x75, 0x01, //         Report Size (1)
+    0x95, 0x08, //         Report Count (8)
+    0x81, 0x02, //         Input (Data, Var, Abs): modifiers
+    0x19, 0x00, //         Usage Minimum (0)
+    0x29, (BITMAP_KEYS - 1) as u8, // Usage Maximum
+    0x95, BITMAP_KEYS as u8, // Report Count
+    0x81, 0x02, //         Input (Data, Var, Abs): key bitmap
+    0x05, 0x08, //         Usage Page (LEDs)
+    0x19, 0x01, //         Usage Minimum (Num Lock)
+    0x29, 0x05, //         Usage Maximum (Kana)
+    0x95, 0x05, //         Report Count (5)
+    0x91, 0x02, //         Output (Data, Var, Abs): LEDs
+    0x75, 0x03, //         Report Size (3)
+    0x95, 0x01, //         Report Count (1)
+    0x91, 0x01, //         Output (Const): padding
+    0xC0, //             End Collection
+];
+
+/// System control (report ID 1) and consumer control (report ID 2), one usage each
+pub const EXTRA_KEYS_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, //       Usage Page (Generic Desktop)
+    0x09, 0x80, //       Usage (System Control)
+    0xA1, 0x01, //       Collection (Application)
+    0x85, SYSTEM_REPORT_ID, // Report ID
+    0x19, 0x81, //         Usage Minimum (System Power Down)
+    0x29, 0xB7, //         Usage Maximum (System Display LCD Autoscale)
+    0x15, 0x01, //         Logical Minimum (1)
+    0x26, 0xB7, 0x00, //   Logical Maximum (0xB7)
+    0x75, 0x10, //         Report Size (16)
+    0x95, 0x01, //         Report Count (1)
+    0x81, 0x00, //         Input (Data, Array, Abs)
+    0xC0, //             End Collection
+    0x05, 0x0C, //       Usage Page (Consumer)
+    0x09, 0x01, //       Usage (Consumer Control)
+    0xA1, 0x01, //       Collection (Application)
+    0x85, CONSUMER_REPORT_ID, // Report ID
+    0x19, 0x01, //         Usage Minimum (1)
+    0x2A, 0xA0, 0x02, //   Usage Maximum (0x2A0)
+    0x15, 0x01, //         Logical Minimum (1)
+    0x26, 0xA0, 0x02, //   Logical Maximum (0x2A0)
+    0x75, 0x10, //         Report Size (16)
+    0x95, 0x01, //         Report Count (1)
+    0x81, 0x00, //         Input (Data, Array, Abs)
+    0xC0, //             End Collection
+];
+
+/// Five-button mouse with 16-bit relative X/Y, wheel and horizontal pan
+pub const MOUSE_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, //       Usage Page (Generic Desktop)
+    0x09, 0x02, //       Usage (Mouse)
+    0xA1, 0x01, //       Collection (Application)
+    0x09, 0x01, //         Usage (Pointer)
+    0xA1, 0x00, //         Collection (Physical)
+    0x05, 0x09, //           Usage Page (Button)
+    0x19, 0x01, //           Usage Minimum (1)
+    0x29, 0x05, //           Usage Maximum (5)
+    0x15, 0x00, //           Logical Minimum (0)
+    0x25, 0x01, //           Logical Maximum (1)
+    0x75, 0x01, //           Report Size (1)
+    0x95, 0x05, //           Report Count (5)
+    0x81, 0x02, //           Input (Data, Var, Abs): buttons
+    0x75, 0x03, //           Report Size (3)
+    0x95, 0x01, //           Report Count (1)
+    0x81, 0x01, //           Input (Const): padding
+    0x05, 0x01, //           Usage Page (Generic Desktop)
+    0x09, 0x30, //           Usage (X)
+    0x09, 0x31, //           Usage (Y)
+    0x16, 0x01, 0x80, //     Logical Minimum (-32767)
+    0x26, 0xFF, 0x7F, //     Logical Maximum (32767)
+    0x75, 0x10, //           Report Size (16)
+    0x95, 0x02, //           Report Count (2)
+    0x81, 0x06, //           Input (Data, Var, Rel)
+    0x09, 0x38, //           Usage (Wheel)
+    0x15, 0x81, //           Logical Minimum (-127)
+    0x25, 0x7F, //           Logical Maximum (127)
+    0x75, 0x08, //           Report Size (8)
+    0x95, 0x01, //           Report Count (1)
+    0x81, 0x06, //           Input (Data, Var, Rel)
+    0x05, 0x0C, //           Usage Page (Consumer)
+    0x0A, 0x38, 0x02, //     Usage (AC Pan)
+    0x95, 0x01, //           Report Count (1)
+    0x81, 0x06, //           Input (Data, Var, Rel)
+    0xC0, //               End Collection
+    0xC0, //             End Collection
+];
+
+/// Endpoints a keyboard interface with LED output reports allocates, for [`crate::usb::assert_endpoint_budget`]
+#[cfg(feature = "usb")]
+pub const KEYBOARD_ENDPOINT_BUDGET: crate::usb::EndpointBudget =
+    crate::usb::EndpointBudget::hid(NKRO_REPORT_SIZE as u16, Some(1));
+
+/// Endpoints an [`EXTRA_KEYS_DESCRIPTOR`] interface allocates
+#[cfg(feature = "usb")]
+pub const EXTRA_KEYS_ENDPOINT_BUDGET: crate::usb::EndpointBudget =
+    crate::usb::EndpointBudget::hid(EXTRA_KEYS_REPORT_SIZE as u16, None);
+
+/// Endpoints a [`MOUSE_DESCRIPTOR`] interface allocates
+#[cfg(feature = "usb")]
+pub const MOUSE_ENDPOINT_BUDGET: crate::usb::EndpointBudget =
+    crate::usb::EndpointBudget::hid(MOUSE_REPORT_SIZE as u16, None);
+
+/// HID usages used in the examples; the full tables are in the HID Usage Tables spec
+pub mod usage {
+    // Keyboard/Keypad page
+    pub const KEY_A: u8 = 0x04;
+    pub const KEY_Z: u8 = 0x1D;
+    pub const KEY_1: u8 = 0x1E;
+    pub const KEY_0: u8 = 0x27;
+    pub const ENTER: u8 = 0x28;
+    pub const ESCAPE: u8 = 0x29;
+    pub const BACKSPACE: u8 = 0x2A;
+    pub const TAB: u8 = 0x2B;
+    pub const SPACE: u8 = 0x2C;
+    pub const CAPS_LOCK: u8 = 0x39;
+    pub const F1: u8 = 0x3A;
+    pub const LEFT_CTRL: u8 = 0xE0;
+    pub const LEFT_SHIFT: u8 = 0xE1;
+    pub const LEFT_ALT: u8 = 0xE2;
+    pub const LEFT_GUI: u8 = 0xE3;
+    pub const RIGHT_CTRL: u8 = 0xE4;
+    pub const RIGHT_SHIFT: u8 = 0xE5;
+    pub const RIGHT_ALT: u8 = 0xE6;
+    pub const RIGHT_GUI: u8 = 0xE7;
+
+    // Generic Desktop page, system control
+    pub const SYSTEM_POWER_DOWN: u16 = 0x81;
+    pub const SYSTEM_SLEEP: u16 = 0x82;
+    pub const SYSTEM_WAKE_UP: u16 = 0x83;
+
+    // Consumer page
+    pub const PLAY_PAUSE: u16 = 0xCD;
+    pub const NEXT_TRACK: u16 = 0xB5;
+    pub const PREVIOUS_TRACK: u16 = 0xB6;
+    pub const MUTE: u16 = 0xE2;
+    pub const VOLUME_UP: u16 = 0xE9;
+    pub const VOLUME_DOWN: u16 = 0xEA;
+    pub const BRIGHTNESS_UP: u16 = 0x6F;
+    pub const BRIGHTNESS_DOWN: u16 = 0x70;
+}
+
+/// First modifier usage; 0xE0..=0xE7 map to the modifier byte
+const MODIFIER_FIRST: u8 = usage::LEFT_CTRL;
+/// Boot report key slot value when more than six keys are held
+const ERROR_ROLL_OVER: u8 = 0x01;
+
+/// Keyboard LEDs from an output report
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Leds(u8);
+
+impl Leds {
+    pub const NUM_LOCK: Leds = Leds(1 << 0);
+    pub const CAPS_LOCK: Leds = Leds(1 << 1);
+    pub const SCROLL_LOCK: Leds = Leds(1 << 2);
+    pub const COMPOSE: Leds = Leds(1 << 3);
+    pub const KANA: Leds = Leds(1 << 4);
+
+    /// Decode the first byte of a LED output report
+    pub const fn from_report(report: u8) -> Self {
+        Leds(report & 0x1F)
+    }
+
+    /// Whether all LEDs in `other` are lit
+    pub const fn contains(self, other: Leds) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Raw LED bits
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+}
+
+/// Pressed keys, by HID usage, with modifiers kept apart
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeyBitmap {
+    modifiers: u8,
+    keys: [u8; BITMAP_BYTES],
+}
+
+impl Default for KeyBitmap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyBitmap {
+    /// No keys pressed
+    pub const fn new() -> Self {
+        Self { modifiers: 0, keys: [0; BITMAP_BYTES] }
+    }
+
+    /// Mark `usage` pressed; usages the NKRO bitmap cannot hold are ignored
+    pub fn press(&mut self, usage: u8) {
+        self.set(usage, true);
+    }
+
+    /// Mark `usage` released
+    pub fn release(&mut self, usage: u8) {
+        self.set(usage, false);
+    }
+
+    /// Mark `usage` pressed or released
+    pub fn set(&mut self, usage: u8, pressed: bool) {
+        let (byte, mask) = match usage {
+            MODIFIER_FIRST.. => (&mut self.modifiers, 1 << (usage - MODIFIER_FIRST)),
+            0..=3 => return, // reserved and error codes
+            _ => (&mut self.keys[usage as usize / 8], 1 << (usage % 8)),
+        };
+        if pressed {
+            *byte |= mask;
+        } else {
+            *byte &= !mask;
+        }
+    }
+
+    /// Whether `usage` is pressed
+    pub fn is_pressed(&self, usage: u8) -> bool {
+        match usage {
+            MODIFIER_FIRST.. => self.modifiers & (1 << (usage - MODIFIER_FIRST)) != 0,
+            _ => self.keys[usage as usize / 8] & (1 << (usage % 8)) != 0,
+        }
+    }
+
+    /// Release everything
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Modifier byte, bit 0 = Left Control .. bit 7 = Right GUI
+    pub fn modifiers(&self) -> u8 {
+        self.modifiers
+    }
+
+    /// Pressed non-modifier usages, lowest first
+    pub fn pressed(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..BITMAP_KEYS as u8).filter(|&usage| self.keys[usage as usize / 8] & (1 << (usage % 8)) != 0)
+    }
+
+    /// Report for [`NKRO_KEYBOARD_DESCRIPTOR`]
+    pub fn nkro_report(&self) -> [u8; NKRO_REPORT_SIZE] {
+        let mut report = [0; NKRO_REPORT_SIZE];
+        report[0] = self.modifiers;
+        report[1..].copy_from_slice(&self.keys);
+        report
+    }
+
+    /// Report for [`BOOT_KEYBOARD_DESCRIPTOR`]
+    ///
+    /// With more than six keys held every slot reports ErrorRollOver, as the
+    /// boot protocol requires, until enough keys are released.
+    pub fn boot_report(&self) -> [u8; BOOT_REPORT_SIZE] {
+        let mut report = [0; BOOT_REPORT_SIZE];
+        report[0] = self.modifiers;
+        let mut slots = 0;
+        for usage in self.pressed() {
+            if slots == 6 {
+                report[2..].fill(ERROR_ROLL_OVER);
+                break;
+            }
+            report[2 + slots] = usage;
+            slots += 1;
+        }
+        report
+    }
+}
+
+/// System control report for [`EXTRA_KEYS_DESCRIPTOR`]; 0 releases
+pub fn system_report(usage: u16) -> [u8; EXTRA_KEYS_REPORT_SIZE] {
+    let [lo, hi] = usage.to_le_bytes();
+    [SYSTEM_REPORT_ID, lo, hi]
+}
+
+/// Consumer control report for [`EXTRA_KEYS_DESCRIPTOR`]; 0 releases
+pub fn consumer_report(usage: u16) -> [u8; EXTRA_KEYS_REPORT_SIZE] {
+    let [lo, hi] = usage.to_le_bytes();
+    [CONSUMER_REPORT_ID, lo, hi]
+}
+
+/// Motion and scroll units per report count in [`Mouse::move_by`] and [`Mouse::scroll_by`]
+pub const SUBPIXEL: i32 = 256;
+
+/// Largest X/Y step in one report
+const MOTION_MAX: i32 = 32767;
+/// Largest wheel/pan step in one report
+const SCROLL_MAX: i32 = 127;
+
+/// Mouse buttons
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MouseButtons(u8);
+
+impl MouseButtons {
+    pub const NONE: MouseButtons = MouseButtons(0);
+    pub const LEFT: MouseButtons = MouseButtons(1 << 0);
+    pub const RIGHT: MouseButtons = MouseButtons(1 << 1);
+    pub const MIDDLE: MouseButtons = MouseButtons(1 << 2);
+    pub const BACK: MouseButtons = MouseButtons(1 << 3);
+    pub const FORWARD: MouseButtons = MouseButtons(1 << 4);
+
+    /// Whether all buttons in `other` are held
+    pub const fn contains(self, other: MouseButtons) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Whether no button is held
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Raw button bits, as in the report
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+}
+
+impl core::ops::BitOr for MouseButtons {
+    type Output = MouseButtons;
+
+    fn bitor(self, rhs: MouseButtons) -> MouseButtons {
+        MouseButtons(self.0 | rhs.0)
+    }
+}
+
+/// One report for [`MOUSE_DESCRIPTOR`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MouseReport {
+    pub buttons: MouseButtons,
+    pub x: i16,
+    pub y: i16,
+    pub wheel: i8,
+    pub pan: i8,
+}
+
+impl MouseReport {
+    /// Report bytes as sent on the interrupt endpoint
+    pub fn to_bytes(&self) -> [u8; MOUSE_REPORT_SIZE] {
+        let [x0, x1] = self.x.to_le_bytes();
+        let [y0, y1] = self.y.to_le_bytes();
+        [self.buttons.bits(), x0, x1, y0, y1, self.wheel as u8, self.pan as u8]
+    }
+}
+
+/// Mouse state with fractional motion carried between reports
+#[derive(Debug, Clone, Default)]
+pub struct Mouse {
+    buttons: MouseButtons,
+    reported_buttons: MouseButtons,
+    x: i32,
+    y: i32,
+    wheel: i32,
+    pan: i32,
+}
+
+impl Mouse {
+    /// No buttons held, no motion pending
+    pub const fn new() -> Self {
+        Self {
+            buttons: MouseButtons::NONE,
+            reported_buttons: MouseButtons::NONE,
+            x: 0,
+            y: 0,
+            wheel: 0,
+            pan: 0,
+        }
+    }
+
+    /// Hold or release `buttons`
+    pub fn set_buttons(&mut self, buttons: MouseButtons, pressed: bool) {
+        self.buttons = if pressed {
+            self.buttons | buttons
+        } else {
+            MouseButtons(self.buttons.0 & !buttons.0)
+        };
+    }
+
+    /// Buttons currently held
+    pub fn buttons(&self) -> MouseButtons {
+        self.buttons
+    }
+
+    /// Add motion in 1/[`SUBPIXEL`] counts; positive `dy` moves down
+    pub fn move_by(&mut self, dx: i32, dy: i32) {
+        self.x = self.x.saturating_add(dx);
+        self.y = self.y.saturating_add(dy);
+    }
+
+    /// Add scrolling in 1/[`SUBPIXEL`] detents; positive `wheel` scrolls up, positive `pan` right
+    pub fn scroll_by(&mut self, wheel: i32, pan: i32) {
+        self.wheel = self.wheel.saturating_add(wheel);
+        self.pan = self.pan.saturating_add(pan);
+    }
+
+    /// Drop pending motion, e.g. when a trackpoint recalibrates
+    pub fn discard_motion(&mut self) {
+        self.x = 0;
+        self.y = 0;
+        self.wheel = 0;
+        self.pan = 0;
+    }
+
+    /// Next report, or `None` if the buttons are unchanged and no whole count is pending
+    ///
+    /// Fractions, and motion beyond what one report can carry, stay for the next one.
+    pub fn report(&mut self) -> Option<MouseReport> {
+        let x = take_whole(&mut self.x, MOTION_MAX);
+        let y = take_whole(&mut self.y, MOTION_MAX);
+        let wheel = take_whole(&mut self.wheel, SCROLL_MAX);
+        let pan = take_whole(&mut self.pan, SCROLL_MAX);
+        if self.buttons == self.reported_buttons && x == 0 && y == 0 && wheel == 0 && pan == 0 {
+            return None;
+        }
+
+        self.reported_buttons = self.buttons;
+        Some(MouseReport {
+            buttons: self.buttons,
+            x: x as i16,
+            y: y as i16,
+            wheel: wheel as i8,
+            pan: pan as i8,
+        })
+    }
+}
+
+/// Remove the whole counts, at most `max` either way, from `accumulator`
+fn take_whole(accumulator: &mut i32, max: i32) -> i32 {
+    let whole = (*accumulator / SUBPIXEL).clamp(-max, max);
+    *accumulator -= whole * SUBPIXEL;
+    whole
+}
@@ -0,0 +1,224 @@
+//! Firmware update over raw HID
+//!
+//! [`HidUpdate`] adds a vendor HID interface with 32-byte reports like
+//! [`raw_hid`](crate::raw_hid) and serves erase / write / verify / reboot
+//! commands against a fixed flash region. HID needs no driver on any host
+//! OS, so end users can update with a small script instead of a DFU utility.
+//!
+//! The interface uses its own usage page, [`USAGE_PAGE`] / [`USAGE`], not
+//! VIA's 0xFF60 / 0x61: VIA and Vial send their get/set keyboard value
+//! commands (0x02 / 0x03) to every interface on that page, which here would
+//! be ERASE and WRITE. The command set is this crate's own; QMK and Vial
+//! flashing tools do not speak it.
+//!
+//! The region must not contain the code that is running. Typical layouts are
+//! a small updater image at the start of flash that rewrites the application
+//! behind it, or an application that fills a staging region for a bootloader
+//! to copy.
+//!
+//! # Protocol
+//!
+//! Every request is one 32-byte output report and gets one 32-byte input
+//! report back. Multi-byte fields are little-endian.
+//!
+//! | Offset | Request          | Response            |
+//! |--------|------------------|---------------------|
+//! | 0      | command          | command (echoed)    |
+//! | 1      | data length      | [`Status`]          |
+//! | 2..6   | flash address    | command-specific    |
+//! | 8..32  | data (24 bytes)  |                     |
+//!
+//! | Command | Name   | Request                                   | Response payload (offset 2..)               |
+//! |---------|--------|-------------------------------------------|---------------------------------------------|
+//! | 0x01    | INFO   | -                                         | region start u32, length u32, page size u16, protocol version u8 |
+//! | 0x02    | ERASE  | address of one page                       | -                                           |
+//! | 0x03    | WRITE  | address and length multiple of 4, data    | -                                           |
+//! | 0x04    | VERIFY | address, data = length u32, CRC-32 u32    | CRC-32 of the flash contents                |
+//! | 0x05    | REBOOT | -                                         | - (the device resets after replying)        |
+//!
+//! CRC-32 is the IEEE/zlib variant (reflected, polynomial 0xEDB88320).
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::hid_update::{HidUpdate, State};
+//!
+//! static STATE: StaticCell<State> = StaticCell::new();
+//! let mut update = HidUpdate::new(&mut builder, STATE.init(State::new()), 0x4000..0x1_0000);
+//! let mut usb = builder.build();
+//! join(usb.run(), update.run(&mut p.flash)).await;
+//! ```
+
+use core::ops::Range;
+
+use embassy_usb::class::hid::HidReaderWriter;
+use embassy_usb::Builder;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+use crate::flash::Flash;
+use crate::raw_hid::{self, REPORT_SIZE};
+use crate::usb::Driver;
+
+pub use embassy_usb::class::hid::State;
+
+/// Bumped when the command set changes incompatibly
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Vendor usage page of the update interface; host tools select it by this
+pub const USAGE_PAGE: u16 = 0xFF61;
+/// Top-level usage of the update interface
+pub const USAGE: u8 = 0x01;
+
+const REPORT_DESCRIPTOR: &[u8] = &raw_hid::vendor_descriptor(USAGE_PAGE, USAGE);
+
+const HEADER_LEN: usize = 8;
+const MAX_DATA: usize = REPORT_SIZE - HEADER_LEN;
+
+const CMD_INFO: u8 = 0x01;
+const CMD_ERASE: u8 = 0x02;
+const CMD_WRITE: u8 = 0x03;
+const CMD_VERIFY: u8 = 0x04;
+const CMD_REBOOT: u8 = 0x05;
+
+/// Result code in byte 1 of every response
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Status {
+    /// Command succeeded
+    Ok = 0,
+    /// Unknown command or malformed length
+    BadCommand = 1,
+    /// The range is not inside the update region
+    OutOfRange = 2,
+    /// Address or length is not page / word aligned
+    Unaligned = 3,
+    /// The flash controller reported an error
+    Flash = 4,
+    /// VERIFY computed a different CRC
+    Mismatch = 5,
+}
+
+/// Firmware update interface
+pub struct HidUpdate<'d> {
+    hid: HidReaderWriter<'d, Driver<'d>, REPORT_SIZE, REPORT_SIZE>,
+    region: Range<u32>,
+}
+
+impl<'d> HidUpdate<'d> {
+    /// Add the update interface to `builder`
+    ///
+    /// `region` holds flash offsets and must be page aligned.
+    pub fn new(builder: &mut Builder<'d, Driver<'d>>, state: &'d mut State<'d>, region: Range<u32>) -> Self {
+        let page = <Flash as NorFlash>::ERASE_SIZE as u32;
+        assert!(region.start % page == 0 && region.end % page == 0, "update region must be page aligned");
+
+        Self {
+            hid: HidReaderWriter::new(builder, state, raw_hid::hid_config(REPORT_DESCRIPTOR)),
+            region,
+        }
+    }
+
+    /// Serve update commands until a REBOOT resets the device
+    pub async fn run(&mut self, flash: &mut Flash) -> ! {
+        loop {
+            self.hid.ready().await;
+
+            let mut request = [0u8; REPORT_SIZE];
+            match self.hid.read(&mut request).await {
+                Ok(REPORT_SIZE) => {}
+                _ => continue,
+            }
+
+            let mut response = [0u8; REPORT_SIZE];
+            response[0] = request[0];
+            let status = match self.handle(flash, &request, &mut response[2..]).await {
+                Ok(()) => Status::Ok,
+                Err(status) => status,
+            };
+            response[1] = status as u8;
+
+            if status != Status::Ok {
+                warn!("hid_update: command {:#x} failed: {}", request[0], status as u8);
+            }
+
+            // A lost response only stalls the host tool; it will retry
+            let sent = self.hid.write(&response).await.is_ok();
+
+            if sent && request[0] == CMD_REBOOT && status == Status::Ok {
+                crate::power::software_reset();
+            }
+        }
+    }
+
+    async fn handle(&mut self, flash: &mut Flash, request: &[u8; REPORT_SIZE], payload: &mut [u8]) -> Result<(), Status> {
+        let len = request[1] as usize;
+        let address = u32::from_le_bytes([request[2], request[3], request[4], request[5]]);
+        let data = &request[HEADER_LEN..];
+        let page = <Flash as NorFlash>::ERASE_SIZE as u32;
+
+        match request[0] {
+            CMD_INFO => {
+                payload[0..4].copy_from_slice(&self.region.start.to_le_bytes());
+                payload[4..8].copy_from_slice(&(self.region.end - self.region.start).to_le_bytes());
+                payload[8..10].copy_from_slice(&(page as u16).to_le_bytes());
+                payload[10] = PROTOCOL_VERSION;
+                Ok(())
+            }
+            CMD_ERASE => {
+                if address % page != 0 {
+                    return Err(Status::Unaligned);
+                }
+                self.check_range(address, page)?;
+                flash.erase_async(address, address + page).await.map_err(|_| Status::Flash)
+            }
+            CMD_WRITE => {
+                if len > MAX_DATA {
+                    return Err(Status::BadCommand);
+                }
+                if address % 4 != 0 || len % 4 != 0 {
+                    return Err(Status::Unaligned);
+                }
+                self.check_range(address, len as u32)?;
+                flash.write_async(address, &data[..len]).await.map_err(|_| Status::Flash)
+            }
+            CMD_VERIFY => {
+                let length = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+                let expected = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+                self.check_range(address, length)?;
+
+                let mut crc = !0u32;
+                let mut buf = [0u8; 64];
+                let mut offset = address;
+                while offset < address + length {
+                    let n = (address + length - offset).min(buf.len() as u32) as usize;
+                    flash.read(offset, &mut buf[..n]).map_err(|_| Status::Flash)?;
+                    crc = crc32_update(crc, &buf[..n]);
+                    offset += n as u32;
+                }
+                let crc = !crc;
+
+                payload[0..4].copy_from_slice(&crc.to_le_bytes());
+                if crc == expected { Ok(()) } else { Err(Status::Mismatch) }
+            }
+            CMD_REBOOT => Ok(()),
+            _ => Err(Status::BadCommand),
+        }
+    }
+
+    fn check_range(&self, address: u32, len: u32) -> Result<(), Status> {
+        match address.checked_add(len) {
+            Some(end) if address >= self.region.start && end <= self.region.end => Ok(()),
+            _ => Err(Status::OutOfRange),
+        }
+    }
+}
+
+/// Bitwise CRC-32 (IEEE); slow but table-free, and VERIFY runs once per update
+fn crc32_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    crc
+}
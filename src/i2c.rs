@@ -0,0 +1,368 @@
+//! I2C bus helpers
+//!
+//! Bring-up utilities that work on any `embedded-hal-async` I2C bus, such as
+//! [`SoftI2c`](crate::soft_i2c::SoftI2c): [`probe`] checks whether a device
+//! answers at an address and [`scan`] walks the whole 7-bit range, like
+//! `i2cdetect` on Linux:
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::i2c;
+//!
+//! let found = i2c::scan(&mut bus).await?;
+//! info!("I2C devices: {}", found);
+//! if found.contains(0x76) { /* BME280 */ }
+//! ```
+//!
+//! Probing follows `i2cdetect`'s defaults: an empty write, except in the
+//! EEPROM ranges (0x30..=0x37 and 0x50..=0x5F) where an empty write could
+//! start a write cycle and a one-byte read is used instead.
+//!
+//! [`Timing::calculate`] works out the SCL high and low counts (SHPGR and
+//! SLPGR) of the I2C peripheral for standard, fast and fast-mode plus from
+//! the actual PCLK and the bus rise and fall times, instead of fixed
+//! dividers that only hold at one clock and bus load:
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::i2c::{Timing, TimingConfig};
+//!
+//! // Rise time measured on the board, 30 % to 70 %
+//! let config = TimingConfig { rise_ns: 80, ..TimingConfig::fast_plus() };
+//! let timing = Timing::calculate(rcc::get_clocks().apb_clk().to_hz(), config)?;
+//! info!("SCL {} Hz, low {} ns, high {} ns", timing.frequency_hz, timing.low_ns, timing.high_ns);
+//! ```
+//!
+//! The `i2c-timing` example checks the results on a real bus with a logic
+//! analyzer.
+
+use embedded_hal::i2c::{Error as _, ErrorKind, NoAcknowledgeSource};
+use embedded_hal_async::i2c::I2c;
+
+use crate::time::Hertz;
+
+/// First address [`scan`] probes; 0x00..=0x07 are reserved
+pub const FIRST_ADDRESS: u8 = 0x08;
+/// Last address [`scan`] probes; 0x78..=0x7F are reserved
+pub const LAST_ADDRESS: u8 = 0x77;
+
+/// Set of 7-bit addresses, one bit per address
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct AddressMap(u128);
+
+impl AddressMap {
+    /// No addresses
+    pub const fn new() -> Self {
+        Self(0)
+    }
+
+    /// Set from a bitmap, bit `n` for address `n`
+    pub const fn from_bits(bits: u128) -> Self {
+        Self(bits)
+    }
+
+    /// The bitmap, bit `n` for address `n`
+    pub const fn bits(&self) -> u128 {
+        self.0
+    }
+
+    /// Add `address`; panics above 0x7F
+    pub fn insert(&mut self, address: u8) {
+        assert!(address < 0x80, "I2C addresses are 7 bits");
+        self.0 |= 1 << address;
+    }
+
+    /// Whether `address` is in the set
+    pub const fn contains(&self, address: u8) -> bool {
+        address < 0x80 && self.0 & (1 << address) != 0
+    }
+
+    /// Number of addresses in the set
+    pub const fn len(&self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    /// Whether the set is empty
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Addresses in ascending order
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..0x80).filter(|&address| self.contains(address))
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for AddressMap {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "[");
+        for (i, address) in self.iter().enumerate() {
+            if i > 0 {
+                defmt::write!(f, ", ");
+            }
+            defmt::write!(f, "{=u8:#04x}", address);
+        }
+        defmt::write!(f, "]");
+    }
+}
+
+/// Whether `i2cdetect` would read rather than write to probe `address`
+fn probe_by_read(address: u8) -> bool {
+    matches!(address, 0x30..=0x37 | 0x50..=0x5F)
+}
+
+/// Whether a device acknowledges `address`
+///
+/// A missing acknowledge is `Ok(false)`; other errors, e.g. a stuck bus or
+/// lost arbitration, are passed on.
+pub async fn probe<I: I2c>(i2c: &mut I, address: u8) -> Result<bool, I::Error> {
+    let result = if probe_by_read(address) {
+        i2c.read(address, &mut [0]).await
+    } else {
+        i2c.write(address, &[]).await
+    };
+    match result {
+        Ok(()) => Ok(true),
+        Err(e) => match e.kind() {
+            ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address | NoAcknowledgeSource::Unknown) => Ok(false),
+            _ => Err(e),
+        },
+    }
+}
+
+/// Probe every non-reserved address, 0x08..=0x77
+///
+/// Stops at the first error that is not a missing acknowledge, as the bus
+/// is unlikely to work for the addresses after it.
+pub async fn scan<I: I2c>(i2c: &mut I) -> Result<AddressMap, I::Error> {
+    let mut found = AddressMap::new();
+    for address in FIRST_ADDRESS..=LAST_ADDRESS {
+        if probe(i2c, address).await? {
+            found.insert(address);
+        }
+    }
+    debug!("i2c: {} devices", found.len());
+    Ok(found)
+}
+
+/// PCLK cycles the I2C peripheral adds to SHPG and SLPG for each SCL half
+/// period, with the sequential filter off
+const PERIOD_OVERHEAD: u32 = 6;
+/// Largest SHPG/SLPG value
+const PERIOD_MAX: u32 = 0xFFFF;
+
+/// I2C speed mode, with the SCL limits of the I2C specification (UM10204, table 10)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Mode {
+    /// Up to 100 kHz
+    Standard,
+    /// Up to 400 kHz
+    Fast,
+    /// Up to 1 MHz
+    FastPlus,
+}
+
+impl Mode {
+    /// Slowest mode that allows `hz`, or `None` for 0 and above 1 MHz
+    pub const fn for_frequency(hz: u32) -> Option<Self> {
+        match hz {
+            0 => None,
+            1..=100_000 => Some(Mode::Standard),
+            100_001..=400_000 => Some(Mode::Fast),
+            400_001..=1_000_000 => Some(Mode::FastPlus),
+            _ => None,
+        }
+    }
+
+    /// Minimum SCL low time, tLOW, in ns
+    pub const fn min_low_ns(self) -> u32 {
+        match self {
+            Mode::Standard => 4_700,
+            Mode::Fast => 1_300,
+            Mode::FastPlus => 500,
+        }
+    }
+
+    /// Minimum SCL high time, tHIGH, in ns
+    pub const fn min_high_ns(self) -> u32 {
+        match self {
+            Mode::Standard => 4_000,
+            Mode::Fast => 600,
+            Mode::FastPlus => 260,
+        }
+    }
+
+    /// Maximum rise time, tr, in ns
+    pub const fn max_rise_ns(self) -> u32 {
+        match self {
+            Mode::Standard => 1_000,
+            Mode::Fast => 300,
+            Mode::FastPlus => 120,
+        }
+    }
+
+    /// Maximum fall time, tf, in ns
+    pub const fn max_fall_ns(self) -> u32 {
+        match self {
+            Mode::Standard | Mode::Fast => 300,
+            Mode::FastPlus => 120,
+        }
+    }
+}
+
+/// Bus parameters for [`Timing::calculate`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TimingConfig {
+    /// Requested SCL frequency, at most 1 MHz
+    pub frequency: Hertz,
+    /// SCL rise time, 30 % to 70 % of VDD, in ns
+    pub rise_ns: u32,
+    /// SCL fall time, 70 % to 30 % of VDD, in ns
+    pub fall_ns: u32,
+}
+
+impl TimingConfig {
+    /// Standard mode, 100 kHz, with the slowest edges the mode allows
+    pub const fn standard() -> Self {
+        Self::worst_case(Hertz::khz(100), Mode::Standard)
+    }
+
+    /// Fast mode, 400 kHz, with the slowest edges the mode allows
+    pub const fn fast() -> Self {
+        Self::worst_case(Hertz::khz(400), Mode::Fast)
+    }
+
+    /// Fast-mode plus, 1 MHz, with the slowest edges the mode allows
+    pub const fn fast_plus() -> Self {
+        Self::worst_case(Hertz::mhz(1), Mode::FastPlus)
+    }
+
+    const fn worst_case(frequency: Hertz, mode: Mode) -> Self {
+        Self {
+            frequency,
+            rise_ns: mode.max_rise_ns(),
+            fall_ns: mode.max_fall_ns(),
+        }
+    }
+}
+
+/// Why [`Timing::calculate`] failed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TimingError {
+    /// Zero, above 1 MHz, or too slow for the 16-bit period counters at this PCLK
+    Frequency,
+    /// Rise or fall time beyond what the mode allows; lower the pull-ups or the bus capacitance
+    Edges,
+}
+
+impl core::fmt::Display for TimingError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            TimingError::Frequency => "SCL frequency out of range",
+            TimingError::Edges => "rise or fall time too slow for the mode",
+        })
+    }
+}
+
+impl core::error::Error for TimingError {}
+
+/// SCL high and low period settings for the I2C peripheral
+///
+/// The peripheral counts the high period from when it sees SCL high, so
+/// the rise time comes on top of the programmed periods, while the fall
+/// time is part of the low period. Both halves are sized for the mode's
+/// minimum tHIGH and tLOW, and any time left in the period is shared in
+/// proportion to those minimums. The period is rounded up, so the clock
+/// never runs faster than requested.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Timing {
+    /// Speed mode the frequency falls in
+    pub mode: Mode,
+    /// SHPGR value
+    pub shpg: u16,
+    /// SLPGR value
+    pub slpg: u16,
+    /// Expected SCL frequency on the bus, in Hz
+    pub frequency_hz: u32,
+    /// Expected tHIGH, in ns
+    pub high_ns: u32,
+    /// Expected tLOW, in ns
+    pub low_ns: u32,
+}
+
+impl Timing {
+    /// Periods for `config` with the peripheral clocked at `pclk` Hz
+    pub const fn calculate(pclk: u32, config: TimingConfig) -> Result<Self, TimingError> {
+        let hz = config.frequency.to_hz();
+        let Some(mode) = Mode::for_frequency(hz) else {
+            return Err(TimingError::Frequency);
+        };
+        if config.rise_ns > mode.max_rise_ns() || config.fall_ns > mode.max_fall_ns() {
+            return Err(TimingError::Edges);
+        }
+
+        let min_low = clocks(pclk, mode.min_low_ns() + config.fall_ns);
+        let min_high = clocks(pclk, mode.min_high_ns());
+        let budget = pclk.div_ceil(hz).saturating_sub(clocks(pclk, config.rise_ns));
+
+        let mut low = (budget as u64 * min_low as u64 / (min_low + min_high) as u64) as u32;
+        if low < min_low {
+            low = min_low;
+        }
+        let mut high = budget.saturating_sub(low);
+        if high < min_high {
+            high = min_high;
+        }
+        // SHPG = SLPG = 0 is the shortest the peripheral can do
+        if low < PERIOD_OVERHEAD {
+            low = PERIOD_OVERHEAD;
+        }
+        if high < PERIOD_OVERHEAD {
+            high = PERIOD_OVERHEAD;
+        }
+        if low - PERIOD_OVERHEAD > PERIOD_MAX || high - PERIOD_OVERHEAD > PERIOD_MAX {
+            return Err(TimingError::Frequency);
+        }
+
+        let high_ns = nanos(pclk, high);
+        let period_ns = nanos(pclk, low + high) + config.rise_ns;
+        Ok(Self {
+            mode,
+            shpg: (high - PERIOD_OVERHEAD) as u16,
+            slpg: (low - PERIOD_OVERHEAD) as u16,
+            frequency_hz: 1_000_000_000 / period_ns,
+            high_ns,
+            low_ns: nanos(pclk, low) - config.fall_ns,
+        })
+    }
+}
+
+/// PCLK cycles covering at least `ns`
+const fn clocks(pclk: u32, ns: u32) -> u32 {
+    (ns as u64 * pclk as u64).div_ceil(1_000_000_000) as u32
+}
+
+/// Length of `clocks` PCLK cycles in ns, rounded down
+const fn nanos(pclk: u32, clocks: u32) -> u32 {
+    (clocks as u64 * 1_000_000_000 / pclk as u64) as u32
+}
+
+// With the worst-case bus at the 48 MHz PCLK each mode stays within its
+// limits and loses no more than the rounding of the half periods, about 2 %
+const _: () = {
+    let configs = [TimingConfig::standard(), TimingConfig::fast(), TimingConfig::fast_plus()];
+    let mut i = 0;
+    while i < configs.len() {
+        match Timing::calculate(48_000_000, configs[i]) {
+            Ok(timing) => {
+                let requested = configs[i].frequency.to_hz();
+                assert!(timing.frequency_hz <= requested && timing.frequency_hz >= requested / 50 * 49);
+                assert!(timing.low_ns >= timing.mode.min_low_ns() && timing.high_ns >= timing.mode.min_high_ns());
+            }
+            Err(_) => panic!("no I2C timing for a standard speed"),
+        }
+        i += 1;
+    }
+};
@@ -9,15 +9,12 @@ use core::task::Poll;
 
 pub use crate::pac::Interrupt;
 
-// Import cortex_m_rt for interrupt handlers
-#[cfg(feature = "rt")]
-// TODO: Will be used when implementing actual interrupt handlers
-// use cortex_m_rt::interrupt;
-
 /// Critical section implementation for defmt
 ///
 /// This provides the necessary symbols for defmt logging to work
-/// with the HT32F523xx microcontroller.
+/// with the HT32F523xx microcontroller. Host builds (`cargo test-host`)
+/// link `critical-section`'s `std` implementation instead.
+#[cfg(all(target_arch = "arm", not(feature = "mock-registers")))]
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn _critical_section_1_0_acquire() -> u32 {
     // Disable all interrupts using PRIMASK
@@ -33,6 +30,7 @@ pub unsafe extern "C" fn _critical_section_1_0_acquire() -> u32 {
     primask
 }
 
+#[cfg(all(target_arch = "arm", not(feature = "mock-registers")))]
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn _critical_section_1_0_release(token: u32) {
     // Restore interrupt state from token
@@ -45,16 +43,6 @@ pub unsafe extern "C" fn _critical_section_1_0_release(token: u32) {
     // If interrupts were disabled (token & 0x1 == 1), keep them disabled
 }
 
-/// Default interrupt handler placeholder
-#[unsafe(no_mangle)]
-pub extern "C" fn DefaultHandler() -> ! {
-    loop {
-        unsafe {
-            core::arch::asm!("wfi");
-        }
-    }
-}
-
 /// Trait for interrupt handlers
 pub trait InterruptHandler<T> {
     /// Handle the interrupt
@@ -106,6 +94,10 @@ impl InterruptWaker {
         self.waker.wake();
     }
 
+    pub fn register(&self, waker: &core::task::Waker) {
+        self.waker.register(waker);
+    }
+
     pub fn wait(&self) -> impl core::future::Future<Output = ()> + '_ {
         // Use embassy's waitqueue API correctly
         core::future::poll_fn(move |cx| {
@@ -0,0 +1,609 @@
+//! Infrared remote control (NEC and RC5)
+//!
+//! [`IrReceiver`] timestamps both edges of a demodulating receiver (TSOP38xx
+//! style, active low) with GPTM channel 0 input capture, decodes NEC and RC5
+//! frames in the interrupt handler and queues [`IrEvent`]s. The timer runs at
+//! 1 MHz; 12 ms without an edge ends a frame.
+//!
+//! [`IrTransmitter`] drives an IR LED with a PWM carrier (38 kHz for NEC,
+//! 36 kHz for RC5, 1/3 duty) on one GPTM channel and gates it per mark and
+//! space by counting carrier periods in the update interrupt.
+//!
+//! Each driver takes over the whole timer and its interrupt, so a receiver
+//! and a transmitter need one GPTM each. GPTM0 is only available without
+//! the `time-driver` feature.
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::ir::{IrEvent, IrReceiver};
+//!
+//! let mut ir = IrReceiver::new(p.timer1, p.gpioa.pa4().into_alternate_function::<4>());
+//! loop {
+//!     match ir.receive().await {
+//!         IrEvent::Nec { address, command } => { /* ... */ }
+//!         _ => {}
+//!     }
+//! }
+//! ```
+
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::task::Poll;
+
+use critical_section::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel as EventChannel;
+
+use crate::peripheral::{Peri, Peripheral};
+use crate::timer::{self, Channel, Instance, TimerInputPin, TimerOutputPin};
+
+/// Decoded frames queued between receives
+pub const QUEUE_LEN: usize = 8;
+
+/// Receiver tick rate
+const RX_TICK_HZ: u32 = 1_000_000;
+/// Silence that ends a frame, in microseconds
+const IDLE_US: u32 = 12_000;
+
+/// RC5 half-bit time in microseconds
+const RC5_HALF_BIT_US: u32 = 889;
+
+// CHxOCFR output modes
+const OM_FORCE_INACTIVE: u32 = 0b100;
+const OM_PWM1: u32 = 0b110;
+const OCFR_OM_MASK: u32 = 0b111;
+
+// DICTR / INTSR: CH1 compare is the idle timeout
+const INT_CH1CC: u32 = 1 << 1;
+
+// CHPOLR: CH0P selects the falling edge for capture
+const CHPOLR_CH0P: u32 = 1 << 0;
+
+/// Decoded remote-control frame
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum IrEvent {
+    /// NEC frame; `address` is 8-bit for standard and 16-bit for extended NEC
+    Nec { address: u16, command: u8 },
+    /// NEC repeat code while a button is held
+    NecRepeat,
+    /// RC5 frame; `toggle` flips on every new button press
+    Rc5 { address: u8, command: u8, toggle: bool },
+}
+
+fn near(us: u32, nominal: u32, tolerance: u32) -> bool {
+    us.abs_diff(nominal) <= tolerance
+}
+
+#[derive(Copy, Clone)]
+enum NecState {
+    Idle,
+    LeaderSpace,
+    Repeat,
+    BitMark,
+    BitSpace,
+}
+
+struct NecDecoder {
+    state: NecState,
+    bits: u32,
+    count: u8,
+}
+
+impl NecDecoder {
+    const fn new() -> Self {
+        Self {
+            state: NecState::Idle,
+            bits: 0,
+            count: 0,
+        }
+    }
+
+    /// Feed one mark or space that just ended
+    fn feed(&mut self, mark: bool, us: u32) -> Option<IrEvent> {
+        let mut event = None;
+        self.state = match (self.state, mark) {
+            (NecState::LeaderSpace, false) if near(us, 4500, 800) => {
+                self.bits = 0;
+                self.count = 0;
+                NecState::BitMark
+            }
+            (NecState::LeaderSpace, false) if near(us, 2250, 500) => NecState::Repeat,
+            (NecState::Repeat, true) if near(us, 560, 300) => {
+                event = Some(IrEvent::NecRepeat);
+                NecState::Idle
+            }
+            (NecState::BitMark, true) if near(us, 560, 300) => {
+                if self.count == 32 {
+                    event = Self::frame(self.bits);
+                    NecState::Idle
+                } else {
+                    NecState::BitSpace
+                }
+            }
+            (NecState::BitSpace, false) if near(us, 560, 300) => {
+                self.count += 1;
+                NecState::BitMark
+            }
+            (NecState::BitSpace, false) if near(us, 1690, 400) => {
+                self.bits |= 1 << self.count;
+                self.count += 1;
+                NecState::BitMark
+            }
+            // Anything unexpected: resynchronise on the next leader
+            (_, true) if near(us, 9000, 1500) => NecState::LeaderSpace,
+            _ => NecState::Idle,
+        };
+        event
+    }
+
+    fn frame(bits: u32) -> Option<IrEvent> {
+        let [addr_lo, addr_hi, command, command_inv] = bits.to_le_bytes();
+        if command != !command_inv {
+            return None;
+        }
+        let address = if addr_hi == !addr_lo {
+            addr_lo as u16
+        } else {
+            u16::from_le_bytes([addr_lo, addr_hi])
+        };
+        Some(IrEvent::Nec { address, command })
+    }
+
+    fn reset(&mut self) {
+        self.state = NecState::Idle;
+    }
+}
+
+/// RC5 decoder working on half-bit levels (true = carrier)
+struct Rc5Decoder {
+    halves: u32,
+    count: u8,
+    active: bool,
+}
+
+impl Rc5Decoder {
+    const HALVES: u8 = 28;
+
+    const fn new() -> Self {
+        Self {
+            halves: 0,
+            count: 0,
+            active: false,
+        }
+    }
+
+    fn push(&mut self, mark: bool) {
+        if self.count < Self::HALVES {
+            self.halves |= (mark as u32) << self.count;
+            self.count += 1;
+        }
+    }
+
+    fn feed(&mut self, mark: bool, us: u32) -> Option<IrEvent> {
+        if !self.active {
+            // A frame starts in the middle of S1, after its idle first half
+            if !mark {
+                return None;
+            }
+            self.active = true;
+            self.halves = 0;
+            self.count = 0;
+            self.push(false);
+        }
+
+        let n = if near(us, RC5_HALF_BIT_US, 300) {
+            1
+        } else if near(us, 2 * RC5_HALF_BIT_US, 400) {
+            2
+        } else {
+            self.active = false;
+            return None;
+        };
+        for _ in 0..n {
+            self.push(mark);
+        }
+
+        if self.count == Self::HALVES { self.finish() } else { None }
+    }
+
+    /// The line went idle; a frame ending in a 0 bit is missing its last space
+    fn flush(&mut self) -> Option<IrEvent> {
+        if self.active && self.count == Self::HALVES - 1 {
+            self.push(false);
+            return self.finish();
+        }
+        self.active = false;
+        None
+    }
+
+    fn finish(&mut self) -> Option<IrEvent> {
+        self.active = false;
+        let mut bits = 0u16;
+        for i in 0..14 {
+            let pair = (self.halves >> (2 * i)) & 0b11;
+            bits = (bits << 1)
+                | match pair {
+                    0b10 => 1, // space then carrier
+                    0b01 => 0, // carrier then space
+                    _ => return None,
+                };
+        }
+        // S1 S2 T A4..A0 C5..C0; an inverted S2 is command bit 6 (RC5X)
+        let field = (bits >> 12) & 1;
+        Some(IrEvent::Rc5 {
+            address: ((bits >> 6) & 0x1F) as u8,
+            command: (bits & 0x3F) as u8 | ((field ^ 1) << 6) as u8,
+            toggle: bits & (1 << 11) != 0,
+        })
+    }
+}
+
+struct RxState {
+    last: u16,
+    idle: bool,
+    nec: NecDecoder,
+    rc5: Rc5Decoder,
+}
+
+static RX: Mutex<RefCell<RxState>> = Mutex::new(RefCell::new(RxState {
+    last: 0,
+    idle: true,
+    nec: NecDecoder::new(),
+    rc5: Rc5Decoder::new(),
+}));
+static EVENTS: EventChannel<CriticalSectionRawMutex, IrEvent, QUEUE_LEN> = EventChannel::new();
+
+fn emit(event: Option<IrEvent>) {
+    if let Some(event) = event {
+        if EVENTS.try_send(event).is_err() {
+            warn!("ir: event queue full");
+        }
+    }
+}
+
+/// Receiver interrupt: CH0 captures edges, CH1 compare detects idle
+fn rx_interrupt<T: Instance>() {
+    let regs = T::regs();
+    let flags = regs.gptm_intsr().read().bits() & regs.gptm_dictr().read().bits();
+
+    if flags & timer::INT_CH0CC != 0 {
+        regs.gptm_intsr().write(|w| unsafe { w.bits(!timer::INT_CH0CC) });
+        let capture = regs.gptm_ch0ccr().read().bits() as u16;
+
+        // Capture the opposite edge next
+        let polarity = regs.gptm_chpolr().read().bits();
+        regs.gptm_chpolr().write(|w| unsafe { w.bits(polarity ^ CHPOLR_CH0P) });
+        // The receiver output is active low: a falling edge ends a space
+        let mark_ended = polarity & CHPOLR_CH0P == 0;
+
+        critical_section::with(|cs| {
+            let mut rx = RX.borrow_ref_mut(cs);
+            let us = capture.wrapping_sub(rx.last) as u32;
+            rx.last = capture;
+
+            if rx.idle {
+                rx.idle = false;
+            } else {
+                let event = rx.nec.feed(mark_ended, us);
+                emit(event);
+                let event = rx.rc5.feed(mark_ended, us);
+                emit(event);
+            }
+        });
+
+        regs.gptm_ch1ccr().write(|w| unsafe { w.bits(capture.wrapping_add(IDLE_US as u16) as u32) });
+        regs.gptm_intsr().write(|w| unsafe { w.bits(!INT_CH1CC) });
+        regs.gptm_dictr().modify(|r, w| unsafe { w.bits(r.bits() | INT_CH1CC) });
+    }
+
+    if flags & INT_CH1CC != 0 {
+        regs.gptm_intsr().write(|w| unsafe { w.bits(!INT_CH1CC) });
+        regs.gptm_dictr().modify(|r, w| unsafe { w.bits(r.bits() & !INT_CH1CC) });
+        // Idle line is high; wait for the falling edge of the next mark
+        regs.gptm_chpolr().modify(|r, w| unsafe { w.bits(r.bits() | CHPOLR_CH0P) });
+
+        critical_section::with(|cs| {
+            let mut rx = RX.borrow_ref_mut(cs);
+            rx.idle = true;
+            rx.nec.reset();
+            let event = rx.rc5.flush();
+            emit(event);
+        });
+    }
+}
+
+/// IR receiver on GPTM channel 0
+pub struct IrReceiver<'d, T: Instance> {
+    _timer: Peri<'d, T>,
+}
+
+impl<'d, T: Instance> IrReceiver<'d, T> {
+    /// Start decoding; `pin` is the timer's channel 0 input (AF4)
+    pub fn new<P: TimerInputPin<T>>(timer: impl Peripheral<P = T> + 'd, _pin: impl Peripheral<P = P> + 'd) -> Self {
+        T::enable_clock();
+        let regs = T::regs();
+        let mut peri = timer.into_ref();
+        let mut timer = timer::Timer::new(peri.reborrow());
+
+        let pclk = crate::rcc::get_clocks().apb_clk().to_hz();
+        timer.set_prescaler((pclk / RX_TICK_HZ - 1) as u16);
+        timer.set_period(u16::MAX);
+
+        // CH0 captures TI0, starting with the falling edge of the first mark
+        regs.gptm_ch0icfr().write(|w| unsafe { w.bits(timer::ICFR_CCS_DIRECT) });
+        regs.gptm_chpolr().modify(|r, w| unsafe { w.bits(r.bits() | CHPOLR_CH0P) });
+        regs.gptm_chctr().modify(|_, w| w.ch0e().set_bit());
+
+        critical_section::with(|cs| {
+            let mut rx = RX.borrow_ref_mut(cs);
+            rx.idle = true;
+            rx.nec.reset();
+            rx.rc5 = Rc5Decoder::new();
+        });
+        EVENTS.clear();
+
+        timer::set_handler::<T>(Some(rx_interrupt::<T>));
+        regs.gptm_intsr().write(|w| unsafe { w.bits(0) });
+        regs.gptm_dictr().write(|w| unsafe { w.bits(timer::INT_CH0CC) });
+        timer.start();
+
+        Self { _timer: peri }
+    }
+
+    /// Wait for the next decoded frame
+    pub async fn receive(&mut self) -> IrEvent {
+        EVENTS.receive().await
+    }
+
+    /// Take a decoded frame if one is queued
+    pub fn try_receive(&mut self) -> Option<IrEvent> {
+        EVENTS.try_receive().ok()
+    }
+}
+
+impl<T: Instance> Drop for IrReceiver<'_, T> {
+    fn drop(&mut self) {
+        let regs = T::regs();
+        regs.gptm_dictr().write(|w| unsafe { w.bits(0) });
+        regs.gptm_ctr().modify(|_, w| w.tme().clear_bit());
+        timer::set_handler::<T>(None);
+    }
+}
+
+/// Most marks and spaces in one transmitted frame
+const MAX_PULSES: usize = 72;
+
+/// Marks and spaces counted in carrier periods
+struct Pulses {
+    items: [(bool, u16); MAX_PULSES],
+    len: usize,
+    carrier_hz: u32,
+}
+
+impl Pulses {
+    fn new(carrier_hz: u32) -> Self {
+        Self {
+            items: [(false, 0); MAX_PULSES],
+            len: 0,
+            carrier_hz,
+        }
+    }
+
+    fn push(&mut self, mark: bool, us: u32) {
+        let cycles = ((us * self.carrier_hz + 500_000) / 1_000_000) as u16;
+        // Merge with the previous pulse of the same level; a leading space is dropped
+        if self.len > 0 && self.items[self.len - 1].0 == mark {
+            self.items[self.len - 1].1 += cycles;
+        } else if self.len > 0 || mark {
+            self.items[self.len] = (mark, cycles);
+            self.len += 1;
+        }
+    }
+
+    fn nec(address: u16, command: u8) -> Self {
+        let mut p = Self::new(38_000);
+        let address = if address <= 0xFF { (address as u8 as u16) | ((!(address as u8) as u16) << 8) } else { address };
+        let bits = address as u32 | (command as u32) << 16 | (!command as u32) << 24;
+
+        p.push(true, 9000);
+        p.push(false, 4500);
+        for i in 0..32 {
+            p.push(true, 560);
+            p.push(false, if bits & (1 << i) != 0 { 1690 } else { 560 });
+        }
+        p.push(true, 560);
+        p
+    }
+
+    fn nec_repeat() -> Self {
+        let mut p = Self::new(38_000);
+        p.push(true, 9000);
+        p.push(false, 2250);
+        p.push(true, 560);
+        p
+    }
+
+    fn rc5(address: u8, command: u8, toggle: bool) -> Self {
+        let mut p = Self::new(36_000);
+        let field = (command >> 6) & 1 == 0;
+        let bits = (1u16 << 13)
+            | (field as u16) << 12
+            | (toggle as u16) << 11
+            | ((address as u16 & 0x1F) << 6)
+            | (command as u16 & 0x3F);
+
+        for i in (0..14).rev() {
+            let one = bits & (1 << i) != 0;
+            p.push(!one, RC5_HALF_BIT_US);
+            p.push(one, RC5_HALF_BIT_US);
+        }
+        // The trailing space is just idle
+        if p.len > 0 && !p.items[p.len - 1].0 {
+            p.len -= 1;
+        }
+        p
+    }
+}
+
+struct TxState {
+    pulses: Pulses,
+    index: usize,
+    remaining: u16,
+    channel: Channel,
+    done: bool,
+}
+
+static TX: Mutex<RefCell<Option<TxState>>> = Mutex::new(RefCell::new(None));
+
+fn set_output_mode<T: Instance>(channel: Channel, mode: u32) {
+    let regs = T::regs();
+    let set = |r: u32| (r & !OCFR_OM_MASK) | mode;
+    match channel {
+        Channel::Ch0 => regs.gptm_ch0ocfr().modify(|r, w| unsafe { w.bits(set(r.bits())) }),
+        Channel::Ch1 => regs.gptm_ch1ocfr().modify(|r, w| unsafe { w.bits(set(r.bits())) }),
+        Channel::Ch2 => regs.gptm_ch2ocfr().modify(|r, w| unsafe { w.bits(set(r.bits())) }),
+        Channel::Ch3 => regs.gptm_ch3ocfr().modify(|r, w| unsafe { w.bits(set(r.bits())) }),
+    }
+}
+
+/// Transmitter interrupt: one update event per carrier period
+fn tx_interrupt<T: Instance>() {
+    let regs = T::regs();
+    regs.gptm_intsr().write(|w| unsafe { w.bits(!timer::INT_UEV) });
+
+    critical_section::with(|cs| {
+        let mut tx = TX.borrow_ref_mut(cs);
+        let Some(tx) = tx.as_mut() else { return };
+
+        tx.remaining = tx.remaining.saturating_sub(1);
+        if tx.remaining > 0 {
+            return;
+        }
+
+        tx.index += 1;
+        if tx.index < tx.pulses.len {
+            let (mark, cycles) = tx.pulses.items[tx.index];
+            tx.remaining = cycles;
+            set_output_mode::<T>(tx.channel, if mark { OM_PWM1 } else { OM_FORCE_INACTIVE });
+        } else {
+            set_output_mode::<T>(tx.channel, OM_FORCE_INACTIVE);
+            regs.gptm_dictr().write(|w| unsafe { w.bits(0) });
+            regs.gptm_ctr().modify(|_, w| w.tme().clear_bit());
+            tx.done = true;
+            T::waker().wake();
+        }
+    });
+}
+
+/// IR transmitter: carrier PWM on one GPTM channel
+pub struct IrTransmitter<'d, T: Instance> {
+    channel: Channel,
+    _timer: Peri<'d, T>,
+}
+
+impl<'d, T: Instance> IrTransmitter<'d, T> {
+    /// `pin` is the output of `channel` (AF4); the LED is on while the output is high
+    pub fn new<P: TimerOutputPin<T>>(
+        timer: impl Peripheral<P = T> + 'd,
+        _pin: impl Peripheral<P = P> + 'd,
+        channel: Channel,
+    ) -> Self {
+        T::enable_clock();
+        let regs = T::regs();
+        let mut peri = timer.into_ref();
+        let mut timer = timer::Timer::new(peri.reborrow());
+        timer.set_prescaler(0);
+
+        set_output_mode::<T>(channel, OM_FORCE_INACTIVE);
+        match channel {
+            Channel::Ch0 => regs.gptm_chctr().modify(|_, w| w.ch0e().set_bit()),
+            Channel::Ch1 => regs.gptm_chctr().modify(|_, w| w.ch1e().set_bit()),
+            Channel::Ch2 => regs.gptm_chctr().modify(|_, w| w.ch2e().set_bit()),
+            Channel::Ch3 => regs.gptm_chctr().modify(|_, w| w.ch3e().set_bit()),
+        }
+
+        timer::set_handler::<T>(Some(tx_interrupt::<T>));
+
+        Self {
+            channel,
+            _timer: peri,
+        }
+    }
+
+    /// Send an NEC frame; addresses above 0xFF are sent as extended NEC
+    pub async fn send_nec(&mut self, address: u16, command: u8) {
+        self.send(Pulses::nec(address, command)).await
+    }
+
+    /// Send the NEC repeat code; hosts expect one every 108 ms while held
+    pub async fn send_nec_repeat(&mut self) {
+        self.send(Pulses::nec_repeat()).await
+    }
+
+    /// Send an RC5 frame; commands 64..128 use the inverted field bit (RC5X)
+    pub async fn send_rc5(&mut self, address: u8, command: u8, toggle: bool) {
+        self.send(Pulses::rc5(address, command, toggle)).await
+    }
+
+    async fn send(&mut self, pulses: Pulses) {
+        let regs = T::regs();
+        let pclk = crate::rcc::get_clocks().apb_clk().to_hz();
+        let period = pclk / pulses.carrier_hz;
+
+        regs.gptm_ctr().modify(|_, w| w.tme().clear_bit());
+        regs.gptm_crr().write(|w| unsafe { w.bits(period - 1) });
+        let duty = period / 3;
+        match self.channel {
+            Channel::Ch0 => regs.gptm_ch0ccr().write(|w| unsafe { w.bits(duty) }),
+            Channel::Ch1 => regs.gptm_ch1ccr().write(|w| unsafe { w.bits(duty) }),
+            Channel::Ch2 => regs.gptm_ch2ccr().write(|w| unsafe { w.bits(duty) }),
+            Channel::Ch3 => regs.gptm_ch3ccr().write(|w| unsafe { w.bits(duty) }),
+        }
+        regs.gptm_cntr().reset();
+
+        let channel = self.channel;
+        let first = pulses.items[0];
+        critical_section::with(|cs| {
+            TX.borrow_ref_mut(cs).replace(TxState {
+                remaining: first.1,
+                pulses,
+                index: 0,
+                channel,
+                done: false,
+            });
+        });
+
+        // Stop the carrier if the send is cancelled
+        let guard = crate::drop::DropGuard::new(move || {
+            let regs = T::regs();
+            regs.gptm_dictr().write(|w| unsafe { w.bits(0) });
+            regs.gptm_ctr().modify(|_, w| w.tme().clear_bit());
+            set_output_mode::<T>(channel, OM_FORCE_INACTIVE);
+        });
+
+        set_output_mode::<T>(channel, OM_PWM1);
+        regs.gptm_intsr().write(|w| unsafe { w.bits(0) });
+        regs.gptm_dictr().write(|w| unsafe { w.bits(timer::INT_UEV) });
+        regs.gptm_ctr().modify(|_, w| w.tme().set_bit());
+
+        poll_fn(|cx| {
+            T::waker().register(cx.waker());
+            let done = critical_section::with(|cs| TX.borrow_ref(cs).as_ref().map_or(true, |tx| tx.done));
+            if done { Poll::Ready(()) } else { Poll::Pending }
+        })
+        .await;
+
+        guard.defuse();
+    }
+}
+
+impl<T: Instance> Drop for IrTransmitter<'_, T> {
+    fn drop(&mut self) {
+        let regs = T::regs();
+        regs.gptm_dictr().write(|w| unsafe { w.bits(0) });
+        regs.gptm_ctr().modify(|_, w| w.tme().clear_bit());
+        set_output_mode::<T>(self.channel, OM_FORCE_INACTIVE);
+        timer::set_handler::<T>(None);
+        critical_section::with(|cs| TX.borrow_ref_mut(cs).take());
+    }
+}
@@ -0,0 +1,378 @@
+//! Append-only settings journal
+//!
+//! [`Journal`] stores small key/value records in a flash range split into
+//! two areas. Every [`put`](Journal::put) appends a record; the newest
+//! record for a key wins. A record is
+//!
+//! ```text
+//! key: u16, len: u16, payload[len] padded to a word, crc32(key, len, payload)
+//! ```
+//!
+//! with the CRC computed by the [`Crc`] unit and programmed last. A power
+//! loss mid-write leaves a record whose CRC does not match, which replay
+//! skips, so the previous value of the key stays in effect.
+//!
+//! When the active area fills up, the newest record of every key is copied
+//! to the other area, that area's header is written, and the old area is
+//! erased. The header carries a sequence number, so after a power loss
+//! during compaction the mount picks whichever area is complete.
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::journal::{Config, Journal};
+//!
+//! const KEY_BRIGHTNESS: u16 = 1;
+//!
+//! let mut journal = Journal::new(p.flash, p.crc, Config { start: 0x1_F000, pages_per_area: 2 }).await?;
+//! let mut value = [0u8; 1];
+//! if journal.get(KEY_BRIGHTNESS, &mut value)?.is_none() {
+//!     value[0] = 128;
+//! }
+//! journal.put(KEY_BRIGHTNESS, &value).await?;
+//! ```
+
+use embedded_storage::nor_flash::{NorFlash as _, ReadNorFlash as _};
+
+use crate::crc::Crc;
+use crate::flash::{Flash, FlashError};
+
+/// Longest payload of a single record
+pub const MAX_PAYLOAD: usize = 256;
+
+const PAGE_SIZE: u32 = Flash::ERASE_SIZE as u32;
+/// Area header: sequence, !sequence, magic (written last)
+const HEADER_SIZE: u32 = 12;
+const HEADER_MAGIC: u32 = 0x4A52_4E4C;
+/// Record header word plus CRC word
+const RECORD_OVERHEAD: u32 = 8;
+const ERASED: u32 = 0xFFFF_FFFF;
+
+/// Journal error
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// Flash erase or program failed
+    Flash(FlashError),
+    /// Payload longer than [`MAX_PAYLOAD`]
+    TooLarge,
+    /// The newest records alone fill an area
+    Full,
+    /// The configured range is unaligned or outside flash
+    InvalidConfig,
+}
+
+impl From<FlashError> for Error {
+    fn from(e: FlashError) -> Self {
+        Error::Flash(e)
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Flash(e) => write!(f, "journal flash error: {}", e),
+            Error::TooLarge => f.write_str("journal record too large"),
+            Error::Full => f.write_str("journal full"),
+            Error::InvalidConfig => f.write_str("invalid journal range"),
+        }
+    }
+}
+
+impl core::error::Error for Error {}
+
+/// Flash range of the journal
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Config {
+    /// Page-aligned start address
+    pub start: u32,
+    /// Pages in each of the two areas
+    pub pages_per_area: u32,
+}
+
+/// A record found in flash
+#[derive(Copy, Clone)]
+struct Record {
+    /// Address of the record header
+    address: u32,
+    key: u16,
+    len: u16,
+    valid: bool,
+}
+
+impl Record {
+    fn size(&self) -> u32 {
+        RECORD_OVERHEAD + padded(self.len as u32)
+    }
+
+    fn payload(&self) -> u32 {
+        self.address + 4
+    }
+}
+
+fn padded(len: u32) -> u32 {
+    (len + 3) & !3
+}
+
+/// Crash-consistent key/value journal in on-chip flash
+pub struct Journal {
+    flash: Flash,
+    crc: Crc,
+    config: Config,
+    /// Index of the active area (0 or 1)
+    active: u32,
+    sequence: u32,
+    /// Next free address in the active area
+    end: u32,
+}
+
+impl Journal {
+    /// Mount the journal and replay it to find the end of the log
+    ///
+    /// Blank or foreign flash in the range is formatted.
+    pub async fn new(flash: Flash, crc: Crc, config: Config) -> Result<Self, Error> {
+        let size = 2 * config.pages_per_area * PAGE_SIZE;
+        if config.pages_per_area == 0
+            || config.start % PAGE_SIZE != 0
+            || !matches!(config.start.checked_add(size), Some(end) if end <= flash.capacity() as u32)
+        {
+            return Err(Error::InvalidConfig);
+        }
+
+        let mut this = Self {
+            flash,
+            crc,
+            config,
+            active: 0,
+            sequence: 0,
+            end: 0,
+        };
+
+        match (this.read_header(0), this.read_header(1)) {
+            (Some(a), Some(b)) if b > a => (this.active, this.sequence) = (1, b),
+            (Some(a), _) => (this.active, this.sequence) = (0, a),
+            (None, Some(b)) => (this.active, this.sequence) = (1, b),
+            (None, None) => {
+                info!("journal: no valid area, formatting");
+                this.erase_area(0).await?;
+                this.write_header(0, 1).await?;
+                (this.active, this.sequence) = (0, 1);
+            }
+        }
+
+        this.end = this.log_end();
+        debug!(
+            "journal: area {} sequence {}, {} bytes free",
+            this.active,
+            this.sequence,
+            this.area_end(this.active) - this.end
+        );
+        Ok(this)
+    }
+
+    /// Newest value of `key`, copied into `buf`; returns its length
+    ///
+    /// A value longer than `buf` is truncated to it.
+    pub fn get(&mut self, key: u16, buf: &mut [u8]) -> Result<Option<usize>, Error> {
+        let mut newest = None;
+        self.replay_raw(|record| {
+            if record.valid && record.key == key {
+                newest = Some(record);
+            }
+        });
+
+        match newest {
+            Some(record) => {
+                let n = (record.len as usize).min(buf.len());
+                self.flash.read(record.payload(), &mut buf[..n])?;
+                Ok(Some(record.len as usize))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Visit the newest value of every key, in the order they were written
+    pub fn replay(&mut self, mut f: impl FnMut(u16, &[u8])) -> Result<(), Error> {
+        let mut buf = [0u8; MAX_PAYLOAD];
+        let mut address = self.area_base(self.active) + HEADER_SIZE;
+        while let Some(record) = self.record_at(address) {
+            if record.valid && self.is_newest(&record) {
+                let payload = &mut buf[..record.len as usize];
+                self.flash.read(record.payload(), payload)?;
+                f(record.key, payload);
+            }
+            address += record.size();
+        }
+        Ok(())
+    }
+
+    /// Append a new value for `key`, compacting first if the area is full
+    pub async fn put(&mut self, key: u16, value: &[u8]) -> Result<(), Error> {
+        if value.len() > MAX_PAYLOAD {
+            return Err(Error::TooLarge);
+        }
+
+        let size = RECORD_OVERHEAD + padded(value.len() as u32);
+        if self.end + size > self.area_end(self.active) {
+            self.compact().await?;
+            if self.end + size > self.area_end(self.active) {
+                return Err(Error::Full);
+            }
+        }
+
+        let mut record = [0xFFu8; RECORD_OVERHEAD as usize + MAX_PAYLOAD];
+        let header = key as u32 | (value.len() as u32) << 16;
+        record[0..4].copy_from_slice(&header.to_le_bytes());
+        record[4..4 + value.len()].copy_from_slice(value);
+
+        self.crc.reset();
+        self.crc.feed(&record[0..4]);
+        self.crc.feed(value);
+        let crc = self.crc.finish();
+        let crc_at = size as usize - 4;
+        record[crc_at..crc_at + 4].copy_from_slice(&crc.to_le_bytes());
+
+        // Words are programmed in order, so the CRC lands last
+        self.flash.write_async(self.end, &record[..size as usize]).await?;
+        self.end += size;
+        Ok(())
+    }
+
+    /// Release the flash controller and CRC unit
+    pub fn free(self) -> (Flash, Crc) {
+        (self.flash, self.crc)
+    }
+
+    fn area_base(&self, area: u32) -> u32 {
+        self.config.start + area * self.config.pages_per_area * PAGE_SIZE
+    }
+
+    fn area_end(&self, area: u32) -> u32 {
+        self.area_base(area) + self.config.pages_per_area * PAGE_SIZE
+    }
+
+    fn read_word(&mut self, address: u32) -> u32 {
+        let mut word = [0u8; 4];
+        // In range by construction
+        let _ = self.flash.read(address, &mut word);
+        u32::from_le_bytes(word)
+    }
+
+    fn read_header(&mut self, area: u32) -> Option<u32> {
+        let address = self.area_base(area);
+        let sequence = self.read_word(address);
+        let check = self.read_word(address + 4);
+        let magic = self.read_word(address + 8);
+        (magic == HEADER_MAGIC && check == !sequence && sequence != ERASED).then_some(sequence)
+    }
+
+    async fn write_header(&mut self, area: u32, sequence: u32) -> Result<(), Error> {
+        let mut header = [0u8; HEADER_SIZE as usize];
+        header[0..4].copy_from_slice(&sequence.to_le_bytes());
+        header[4..8].copy_from_slice(&(!sequence).to_le_bytes());
+        header[8..12].copy_from_slice(&HEADER_MAGIC.to_le_bytes());
+        Ok(self.flash.write_async(self.area_base(area), &header).await?)
+    }
+
+    async fn erase_area(&mut self, area: u32) -> Result<(), Error> {
+        Ok(self.flash.erase_async(self.area_base(area), self.area_end(area)).await?)
+    }
+
+    /// The record at `address`, or `None` at the end of the log
+    fn record_at(&mut self, address: u32) -> Option<Record> {
+        let area_end = self.area_end(self.active);
+        if address + RECORD_OVERHEAD > area_end {
+            return None;
+        }
+        let header = self.read_word(address);
+        if header == ERASED {
+            return None;
+        }
+
+        let (key, len) = (header as u16, (header >> 16) as u16);
+        let mut record = Record { address, key, len, valid: false };
+        if len as usize > MAX_PAYLOAD || address + record.size() > area_end {
+            return None;
+        }
+
+        let mut payload = [0u8; MAX_PAYLOAD];
+        let payload = &mut payload[..len as usize];
+        let _ = self.flash.read(record.payload(), payload);
+        self.crc.reset();
+        self.crc.feed(&header.to_le_bytes());
+        self.crc.feed(payload);
+        let stored = self.read_word(address + record.size() - 4);
+        record.valid = self.crc.finish() == stored;
+        if !record.valid {
+            debug!("journal: skipping torn record at {:#x}", address);
+        }
+        Some(record)
+    }
+
+    /// Walk every record in the active area, valid or not
+    fn replay_raw(&mut self, mut f: impl FnMut(Record)) {
+        let mut address = self.area_base(self.active) + HEADER_SIZE;
+        while let Some(record) = self.record_at(address) {
+            f(record);
+            address += record.size();
+        }
+    }
+
+    /// Where the next record goes
+    ///
+    /// A header with an impossible length is a torn header write. Nothing
+    /// can be appended behind it, so the area counts as full and the next
+    /// [`put`](Journal::put) compacts.
+    fn log_end(&mut self) -> u32 {
+        let mut address = self.area_base(self.active) + HEADER_SIZE;
+        self.replay_raw(|record| address = record.address + record.size());
+
+        let area_end = self.area_end(self.active);
+        if address + 4 <= area_end && self.read_word(address) != ERASED {
+            warn!("journal: corrupt record at {:#x}", address);
+            return area_end;
+        }
+        address
+    }
+
+    /// No later valid record has the same key
+    fn is_newest(&mut self, record: &Record) -> bool {
+        let mut address = record.address + record.size();
+        while let Some(later) = self.record_at(address) {
+            if later.valid && later.key == record.key {
+                return false;
+            }
+            address += later.size();
+        }
+        true
+    }
+
+    /// Copy the newest record of each key to the other area and switch to it
+    async fn compact(&mut self) -> Result<(), Error> {
+        let old = self.active;
+        let new = 1 - old;
+        let sequence = self.sequence + 1;
+        debug!("journal: compacting into area {}", new);
+
+        // The spare area may hold a compaction cut short
+        self.erase_area(new).await?;
+
+        let mut buf = [0u8; RECORD_OVERHEAD as usize + MAX_PAYLOAD];
+        let mut to = self.area_base(new) + HEADER_SIZE;
+        let mut address = self.area_base(old) + HEADER_SIZE;
+        while let Some(record) = self.record_at(address) {
+            if record.valid && self.is_newest(&record) {
+                let raw = &mut buf[..record.size() as usize];
+                self.flash.read(record.address, raw)?;
+                self.flash.write_async(to, raw).await?;
+                to += record.size();
+            }
+            address += record.size();
+        }
+
+        // The new area takes over once its header is complete
+        self.write_header(new, sequence).await?;
+        (self.active, self.sequence, self.end) = (new, sequence, to);
+
+        self.erase_area(old).await
+    }
+}
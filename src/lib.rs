@@ -15,6 +15,59 @@
 //! - `ht32f52352` - Enable support for HT32F52352 (default)
 //! - `rt` - Enable runtime support (cortex-m-rt)
 //! - `usb` - Enable USB device support
+//! - `usb-crystalless` - Trim the HSI from USB SOFs so USB runs without a crystal
+//! - `blocking` - Busy-waiting driver methods (`blocking_*`) and `delay::Delay`
+//! - `executor` - Re-export `embassy-executor` (default)
+//! - `time` - Re-export `embassy-time` and use it for polling delays
+//! - `time-driver` - Provide the embassy-time driver on GPTM0 (default, implies `time`)
+//! - `time-rtc-discipline` - Hold the time driver to the LSE crystal through the RTC
+//! - `executor-metrics` - Per-task poll and wake-up latency counters from the executor trace hooks
+//! - `task-trace` - Stream task polls and interrupt entry/exit over RTT, decoded by `cargo xtask trace`
+//! - `alloc` - Global TLSF heap set up with `init_heap!`, sized against the RAM budget
+//! - `modbus` - Modbus RTU master and slave framing over a USART
+//! - `ramfunc` - Copy `ramfunc!` functions to RAM at init; enables blocking flash erase/write
+//! - `defmt` - Log driver events through the `defmt` macros
+//! - `log-sink` - Provide the defmt global logger, routed to RTT, UART and/or USB CDC
+//! - `strip-hot-path-logs` - Drop logging from USB, HID and pin-edge paths, even with `defmt`
+//! - `fault-handler` - HardFault/NMI handlers that log the stacked registers over defmt, then halt
+//! - `stack-guard` - Paint the stack at init for a high watermark and overflow canaries
+//!
+//! ## Logging
+//!
+//! The HAL only uses the `defmt` logging macros and never links a transport.
+//! The application picks one, e.g. `use defmt_rtt as _;` for a debug probe or
+//! `defmt-bbq` to stream logs over USB (see `examples/defmt-usb`). Or, with
+//! `log-sink`, the HAL's own logger in [`log_sink`], which can switch between
+//! RTT, a UART and a CDC-ACM port at run time.
+//!
+//! ## Using the HAL without embassy-executor
+//!
+//! The async drivers only rely on `core::task::Waker`, so they can be awaited
+//! from RTIC 2 software tasks or any other executor. Build with
+//! `default-features = false` and pick the chip plus the peripherals you need;
+//! leave out `executor` and, if you use your own monotonic, `time-driver`.
+//!
+//! Interrupt sharing rules:
+//! - With `rt` + `usb`, the HAL defines the `USB` handler that wakes the USB
+//!   driver. Do not bind `USB` to an RTIC task or use it as a dispatcher.
+//! - With `time-driver`, GPTM0 belongs to the time driver. Do not use it for
+//!   `timer::Timer`/`Pwm` or an RTIC monotonic. Without `rt`, call
+//!   [`time_driver::on_interrupt`] from your `GPTM0` handler. Without
+//!   `executor`, enable one of the `generic-queue-*` features of
+//!   `embassy-time-queue-utils` for its timer queue.
+//! - With `rt`, the HAL also defines the `GPTM0`, `GPTM1`, `EXTI0_1`,
+//!   `EXTI2_3`, `EXTI4_15`, `PDMA_CH0_1`, `PDMA_CH2_5` and `LVD_BOD`
+//!   handlers. Do not define them again.
+//! - `BFTM0` and `BFTM1` are left to the application. `soft_pwm::SoftPwm`
+//!   and `ps2::Ps2Device` register their handler in the [`vectors`] slot of
+//!   the BFTM they take while they exist; a `#[interrupt] fn BFTM0`/`BFTM1`
+//!   in the application would starve them.
+//! - `init()` unmasks the GPTM, USART, USB and EXTI interrupts in the NVIC;
+//!   pick RTIC dispatchers among the other vectors.
+//! - With `rt`, the HAL defines `DefaultHandler`, which logs and masks
+//!   interrupts that have no handler; see [`vectors`].
+//! - With `fault-handler`, the HAL defines `HardFault` and `NonMaskableInt`;
+//!   do not define them with `#[exception]` as well.
 //!
 //! ## Usage
 //!
@@ -65,26 +118,110 @@ pub const LARGE_BUFFER_SIZE: usize = 4096; // Can use more with 16KB RAM
 // Chip-specific configuration
 pub mod chip;
 
+// Logging macros; must come first so the other modules can use them
+#[macro_use]
+pub mod fmt;
+#[cfg(feature = "log-sink")]
+pub mod log_sink;
+// Peripheral ownership; its macro is used by the driver modules below
+#[macro_use]
+pub mod peripheral;
+
 // Core modules
 pub mod interrupt;
+pub mod vectors;
+#[cfg(feature = "fault-handler")]
+pub mod fault;
 pub mod time;
+#[cfg(feature = "time-driver")]
 pub mod time_driver;
+#[cfg(feature = "executor-metrics")]
+pub mod executor_metrics;
+#[cfg(feature = "task-trace")]
+pub mod task_trace;
+#[cfg(feature = "alloc")]
+pub mod heap;
+#[cfg(feature = "stack-guard")]
+pub mod stack_guard;
+pub mod ram_budget;
 
 // Utility modules
-pub mod fmt;
+pub mod regs;
+pub mod cortex_delay;
+#[cfg(feature = "blocking")]
+pub mod delay;
+pub(crate) mod drop;
+pub mod safe_state;
+pub mod selftest;
+pub mod soft_pwm;
+pub mod charlieplex;
+pub mod stepper;
+pub mod motor;
+pub mod pulse_counter;
+pub mod ticker;
+pub mod ps2;
+pub mod hid;
+pub mod soft_i2c;
+pub mod i2c;
+pub mod ir;
+pub mod pwm_audio;
+pub mod rgb_led;
+#[cfg(feature = "time")]
+pub mod encoder;
+#[cfg(feature = "time")]
+pub mod haptics;
+#[cfg(feature = "time")]
+pub mod freq_counter;
+#[cfg(feature = "time")]
+pub mod dmx;
+#[cfg(feature = "modbus")]
+pub mod modbus;
+#[cfg(feature = "time")]
+pub mod rc;
+pub mod battery;
+pub mod ntc;
+#[cfg(feature = "pid")]
+pub mod pid;
+#[cfg(feature = "time")]
+pub mod split_link;
 
 // Hardware abstraction layer modules
+pub mod adc;
+pub mod crc;
+pub mod dma;
 pub mod exti;
+pub mod expander;
 pub mod gpio;
+pub mod logic;
+pub mod lvd;
+pub mod power;
 pub mod rcc;
+pub mod spi;
 pub mod timer;
 pub mod uart;
+pub mod wdt;
 #[cfg(feature = "usb")]
 pub mod usb;
+#[cfg(feature = "raw-hid")]
+pub mod raw_hid;
+#[cfg(feature = "hid-update")]
+pub mod hid_update;
+#[cfg(feature = "scope")]
+pub mod scope;
 pub mod flash;
+#[cfg(feature = "ramfunc")]
+pub mod ramfunc;
+pub mod fw_info;
+pub mod journal;
+#[cfg(feature = "spiflash")]
+pub mod spiflash;
+#[cfg(feature = "storage")]
+pub mod storage;
 
 // Re-exports for convenience
+#[cfg(feature = "executor")]
 pub use embassy_executor;
+#[cfg(feature = "time")]
 pub use embassy_time;
 pub use embassy_sync;
 
@@ -110,19 +247,40 @@ pub struct Peripherals {
     pub gpiod: gpio::PortD,
     pub usart0: uart::Usart0,
     pub usart1: uart::Usart1,
+    pub spi0: spi::Spi0,
+    pub spi1: spi::Spi1,
     pub timer0: timer::Timer0,
     pub timer1: timer::Timer1,
+    pub bftm0: timer::Bftm0,
+    pub bftm1: timer::Bftm1,
+    pub mctm0: motor::Mctm0,
+    pub cmp0: motor::Cmp0,
     #[cfg(feature = "usb")]
     pub usb: usb::Usb,
     pub flash: flash::Flash,
+    pub adc: adc::Adc,
+    pub crc: crc::Crc,
+    pub wdt: wdt::Watchdog,
 }
 
 /// Initialize the chip and return peripheral instances
 pub fn init(config: Config) -> Peripherals {
+    // RAM functions before anything can call one
+    #[cfg(feature = "ramfunc")]
+    unsafe {
+        ramfunc::init()
+    };
+
     // Initialize clocks first
     let _clocks = rcc::init(config.rcc);
+    // Time the busy-wait loop at the final clock, before anything uses SysTick
+    cortex_delay::calibrate();
+    // Paint the free stack before anything has used much of it
+    #[cfg(feature = "stack-guard")]
+    stack_guard::init();
 
     // Initialize embassy-time driver using GPTM0
+    #[cfg(feature = "time-driver")]
     time_driver::init();
 
     // Initialize interrupt system
@@ -141,9 +299,17 @@ pub fn init(config: Config) -> Peripherals {
     let usart0 = uart::Usart0::new();
     let usart1 = uart::Usart1::new();
 
+    // Initialize SPI peripherals
+    let spi0 = spi::Spi0::new();
+    let spi1 = spi::Spi1::new();
+
     // Initialize Timer peripherals
     let timer0 = timer::Timer0::new();
     let timer1 = timer::Timer1::new();
+    let bftm0 = timer::Bftm0::new();
+    let bftm1 = timer::Bftm1::new();
+    let mctm0 = motor::Mctm0::new();
+    let cmp0 = motor::Cmp0::new();
 
     // Initialize USB peripheral if feature is enabled
     #[cfg(feature = "usb")]
@@ -152,6 +318,11 @@ pub fn init(config: Config) -> Peripherals {
     // Initialize Flash controller
     let flash = flash::Flash::new();
 
+    // ADC stays unclocked until the first conversion
+    let adc = adc::Adc::new();
+    let crc = crc::Crc::new();
+    let wdt = wdt::Watchdog::new();
+
     Peripherals {
         gpioa,
         gpiob,
@@ -159,11 +330,20 @@ pub fn init(config: Config) -> Peripherals {
         gpiod,
         usart0,
         usart1,
+        spi0,
+        spi1,
         timer0,
         timer1,
+        bftm0,
+        bftm1,
+        mctm0,
+        cmp0,
         #[cfg(feature = "usb")]
         usb,
         flash,
+        adc,
+        crc,
+        wdt,
     }
 }
 
@@ -0,0 +1,451 @@
+//! defmt transport with runtime-selectable sinks
+//!
+//! With the `log-sink` feature the HAL provides the defmt global logger
+//! itself, so the application must not link `defmt-rtt` or `defmt-bbq`.
+//! Every frame goes to each enabled [`Sinks`] member:
+//!
+//! - `RTT`: a SEGGER RTT up channel named `defmt`, read by probe-rs.
+//! - `UART`: a buffer drained by [`drain_uart`].
+//! - `CDC`: a buffer drained by [`drain_cdc`] (`usb` feature).
+//!
+//! The sinks can be switched at any time, e.g. to fall back to the serial
+//! ports on a sealed unit where no probe is attached:
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::log_sink::{self, Sinks};
+//!
+//! log_sink::set_sinks(Sinks::RTT | Sinks::UART | Sinks::CDC);
+//! spawner.spawn(uart_log(uart)).unwrap(); // loops on log_sink::drain_uart
+//! Timer::after_secs(2).await;
+//! if !log_sink::rtt_attached() {
+//!     log_sink::set_sinks(Sinks::UART | Sinks::CDC);
+//! }
+//! ```
+//!
+//! The UART and CDC streams carry the same binary frames as RTT; decode them
+//! with `defmt-print -e <elf> < /dev/ttyACM0`. Each buffer holds
+//! [`BUFFER_SIZE`] bytes; a frame that does not fit whole is dropped whole,
+//! so decoders never see a truncated one, and counted in [`dropped`]. With
+//! `task-trace` the RTT control block also carries the `trace` up channel of
+//! [`task_trace`](crate::task_trace).
+
+use core::cell::{Cell, RefCell};
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, Ordering};
+use core::task::Poll;
+
+use critical_section::{CriticalSection, Mutex, RestoreState};
+use embassy_sync::waitqueue::AtomicWaker;
+
+use crate::uart::{self, Uart};
+
+/// Bytes buffered per UART/CDC sink, and the size of the RTT buffer
+pub const BUFFER_SIZE: usize = 512;
+
+/// Set of log destinations
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Sinks(u8);
+
+impl Sinks {
+    /// Discard everything
+    pub const NONE: Sinks = Sinks(0);
+    /// SEGGER RTT through the debug probe
+    pub const RTT: Sinks = Sinks(1 << 0);
+    /// [`drain_uart`]
+    pub const UART: Sinks = Sinks(1 << 1);
+    /// [`drain_cdc`]
+    pub const CDC: Sinks = Sinks(1 << 2);
+
+    /// Whether every sink in `other` is in `self`
+    pub const fn contains(self, other: Sinks) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for Sinks {
+    type Output = Sinks;
+
+    fn bitor(self, rhs: Sinks) -> Sinks {
+        Sinks(self.0 | rhs.0)
+    }
+}
+
+static SINKS: AtomicU8 = AtomicU8::new(Sinks::RTT.0);
+static DROPPED: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Route frames to `sinks` from the next frame on
+pub fn set_sinks(sinks: Sinks) {
+    SINKS.store(sinks.0, Ordering::Relaxed);
+}
+
+/// Sinks in use
+pub fn sinks() -> Sinks {
+    Sinks(SINKS.load(Ordering::Relaxed))
+}
+
+/// Frames dropped because a sink's buffer was full, counted once per sink
+pub fn dropped() -> u32 {
+    critical_section::with(|cs| DROPPED.borrow(cs).get())
+}
+
+fn count_dropped(cs: CriticalSection) {
+    let dropped = DROPPED.borrow(cs);
+    dropped.set(dropped.get().wrapping_add(1));
+}
+
+/// Byte queue between the logger and a drain task
+struct Ring {
+    buf: [u8; BUFFER_SIZE],
+    start: usize,
+    len: usize,
+    /// `len` before the frame being written
+    frame_start: usize,
+    /// The frame being written did not fit
+    overflow: bool,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Self {
+            buf: [0; BUFFER_SIZE],
+            start: 0,
+            len: 0,
+            frame_start: 0,
+            overflow: false,
+        }
+    }
+
+    fn begin_frame(&mut self) {
+        self.frame_start = self.len;
+        self.overflow = false;
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        if self.overflow || BUFFER_SIZE - self.len < bytes.len() {
+            self.overflow = true;
+            return;
+        }
+        for &byte in bytes {
+            self.buf[(self.start + self.len) % BUFFER_SIZE] = byte;
+            self.len += 1;
+        }
+    }
+
+    /// Keep the frame, or take all of it back if it did not fit; `false` if dropped
+    fn end_frame(&mut self) -> bool {
+        if self.overflow {
+            self.len = self.frame_start;
+        }
+        !self.overflow
+    }
+
+    fn pop(&mut self, out: &mut [u8]) -> usize {
+        let n = self.len.min(out.len());
+        for slot in &mut out[..n] {
+            *slot = self.buf[self.start];
+            self.start = (self.start + 1) % BUFFER_SIZE;
+        }
+        self.len -= n;
+        n
+    }
+}
+
+/// A buffered sink: the queue and the task waiting on it
+struct Queue {
+    ring: Mutex<RefCell<Ring>>,
+    waker: AtomicWaker,
+}
+
+impl Queue {
+    const fn new() -> Self {
+        Self {
+            ring: Mutex::new(RefCell::new(Ring::new())),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    fn begin_frame(&self) {
+        critical_section::with(|cs| self.ring.borrow_ref_mut(cs).begin_frame());
+    }
+
+    fn push(&self, bytes: &[u8]) {
+        critical_section::with(|cs| self.ring.borrow_ref_mut(cs).push(bytes));
+    }
+
+    fn end_frame(&self) {
+        let fit = critical_section::with(|cs| {
+            let fit = self.ring.borrow_ref_mut(cs).end_frame();
+            if !fit {
+                count_dropped(cs);
+            }
+            fit
+        });
+        if fit {
+            self.waker.wake();
+        }
+    }
+
+    /// Wait for buffered bytes and move up to `out.len()` of them into `out`
+    async fn pop(&self, out: &mut [u8]) -> usize {
+        poll_fn(|cx| {
+            self.waker.register(cx.waker());
+            match critical_section::with(|cs| self.ring.borrow_ref_mut(cs).pop(out)) {
+                0 => Poll::Pending,
+                n => Poll::Ready(n),
+            }
+        })
+        .await
+    }
+}
+
+static UART_QUEUE: Queue = Queue::new();
+#[cfg(feature = "usb")]
+static CDC_QUEUE: Queue = Queue::new();
+
+/// Send buffered frames out of `uart` forever
+///
+/// Run it in its own task; with [`Sinks::UART`] off it just waits.
+pub async fn drain_uart<T: uart::Instance>(uart: &mut Uart<'_, T>) -> ! {
+    let mut chunk = [0u8; 32];
+    loop {
+        let n = UART_QUEUE.pop(&mut chunk).await;
+        // Nowhere to report a failed write
+        let _ = uart.write(&chunk[..n]).await;
+    }
+}
+
+/// Send buffered frames to a CDC-ACM port forever
+///
+/// Frames are kept while the port is closed until the buffer fills.
+#[cfg(feature = "usb")]
+pub async fn drain_cdc<'d>(sender: &mut embassy_usb::class::cdc_acm::Sender<'d, crate::usb::Driver<'d>>) -> ! {
+    let mut packet = [0u8; 64];
+    loop {
+        sender.wait_connection().await;
+        let max = (sender.max_packet_size() as usize).min(packet.len());
+        loop {
+            let n = CDC_QUEUE.pop(&mut packet[..max]).await;
+            if sender.write_packet(&packet[..n]).await.is_err() {
+                // Port closed; these bytes are lost, the rest waits for the next open
+                break;
+            }
+        }
+    }
+}
+
+/// SEGGER RTT up channel descriptor
+#[repr(C)]
+struct RttChannel {
+    name: *const u8,
+    buffer: *mut u8,
+    size: u32,
+    write: AtomicU32,
+    read: AtomicU32,
+    flags: u32,
+}
+
+/// SEGGER RTT control block with the `defmt` up channel, the `trace` one
+/// with `task-trace`, and no down channel
+#[repr(C)]
+struct RttControlBlock {
+    id: [u8; 16],
+    max_up: u32,
+    max_down: u32,
+    up: RttChannel,
+    #[cfg(feature = "task-trace")]
+    trace: RttChannel,
+}
+
+// Only the logger and the tracer write, under critical sections; the probe reads
+unsafe impl Sync for RttControlBlock {}
+
+static mut RTT_BUFFER: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+#[cfg(feature = "task-trace")]
+static mut TRACE_BUFFER: [u8; crate::task_trace::BUFFER_SIZE] = [0; crate::task_trace::BUFFER_SIZE];
+
+#[unsafe(no_mangle)]
+static _SEGGER_RTT: RttControlBlock = RttControlBlock {
+    id: *b"SEGGER RTT\0\0\0\0\0\0",
+    max_up: if cfg!(feature = "task-trace") { 2 } else { 1 },
+    max_down: 0,
+    up: RttChannel {
+        name: b"defmt\0".as_ptr(),
+        buffer: unsafe { core::ptr::addr_of_mut!(RTT_BUFFER) as *mut u8 },
+        size: BUFFER_SIZE as u32,
+        write: AtomicU32::new(0),
+        read: AtomicU32::new(0),
+        // Skip the frame when the buffer is full rather than block
+        flags: 0,
+    },
+    #[cfg(feature = "task-trace")]
+    trace: RttChannel {
+        name: b"trace\0".as_ptr(),
+        buffer: unsafe { core::ptr::addr_of_mut!(TRACE_BUFFER) as *mut u8 },
+        size: crate::task_trace::BUFFER_SIZE as u32,
+        write: AtomicU32::new(0),
+        read: AtomicU32::new(0),
+        flags: 0,
+    },
+};
+
+static RTT_SEEN: AtomicBool = AtomicBool::new(false);
+
+/// Whether a probe has read from the RTT channel since reset
+pub fn rtt_attached() -> bool {
+    if _SEGGER_RTT.up.read.load(Ordering::Relaxed) != 0 {
+        RTT_SEEN.store(true, Ordering::Relaxed);
+    }
+    RTT_SEEN.load(Ordering::Relaxed)
+}
+
+/// Bytes that fit in `up`'s buffer from `write` before it reaches the probe's read position
+fn rtt_free(up: &RttChannel, write: usize) -> usize {
+    let size = up.size as usize;
+    let read = up.read.load(Ordering::Relaxed) as usize;
+    if read > write { read - write - 1 } else { size + read - write - 1 }
+}
+
+/// Copy `bytes` into `up`'s buffer at `write`, returning the position after them
+///
+/// The caller checks that they fit; the probe does not see them until
+/// `up.write` is moved past them.
+fn rtt_copy(up: &RttChannel, mut write: usize, bytes: &[u8]) -> usize {
+    let size = up.size as usize;
+    for &byte in bytes {
+        unsafe { core::ptr::write_volatile(up.buffer.add(write), byte) };
+        write += 1;
+        if write == size {
+            write = 0;
+        }
+    }
+    write
+}
+
+/// Append `bytes` to the `trace` channel whole, or not at all if they do not fit
+///
+/// Called under a critical section.
+#[cfg(feature = "task-trace")]
+pub(crate) fn trace_write(bytes: &[u8]) -> bool {
+    let up = &_SEGGER_RTT.trace;
+    let write = up.write.load(Ordering::Relaxed) as usize;
+    if rtt_free(up, write) < bytes.len() {
+        return false;
+    }
+    let write = rtt_copy(up, write, bytes);
+    up.write.store(write as u32, Ordering::Release);
+    true
+}
+
+/// The frame being logged
+struct Frame {
+    /// Sinks it goes to, fixed at its start
+    sinks: Sinks,
+    /// RTT write position after the bytes so far, published at the end
+    rtt_write: usize,
+    /// The frame did not fit in the RTT buffer
+    rtt_overflow: bool,
+}
+
+static mut FRAME: Frame = Frame {
+    sinks: Sinks::NONE,
+    rtt_write: 0,
+    rtt_overflow: false,
+};
+
+/// Start a frame; called with the logger's critical section held
+fn begin_frame() {
+    let frame = unsafe { &mut *core::ptr::addr_of_mut!(FRAME) };
+    frame.sinks = sinks();
+    if frame.sinks.contains(Sinks::RTT) {
+        let up = &_SEGGER_RTT.up;
+        if up.read.load(Ordering::Relaxed) != 0 {
+            RTT_SEEN.store(true, Ordering::Relaxed);
+        }
+        frame.rtt_write = up.write.load(Ordering::Relaxed) as usize;
+        frame.rtt_overflow = false;
+    }
+    if frame.sinks.contains(Sinks::UART) {
+        UART_QUEUE.begin_frame();
+    }
+    #[cfg(feature = "usb")]
+    if frame.sinks.contains(Sinks::CDC) {
+        CDC_QUEUE.begin_frame();
+    }
+}
+
+fn emit(bytes: &[u8]) {
+    let frame = unsafe { &mut *core::ptr::addr_of_mut!(FRAME) };
+    if frame.sinks.contains(Sinks::RTT) && !frame.rtt_overflow {
+        let up = &_SEGGER_RTT.up;
+        if rtt_free(up, frame.rtt_write) < bytes.len() {
+            frame.rtt_overflow = true;
+        } else {
+            frame.rtt_write = rtt_copy(up, frame.rtt_write, bytes);
+        }
+    }
+    if frame.sinks.contains(Sinks::UART) {
+        UART_QUEUE.push(bytes);
+    }
+    #[cfg(feature = "usb")]
+    if frame.sinks.contains(Sinks::CDC) {
+        CDC_QUEUE.push(bytes);
+    }
+}
+
+/// Hand the frame to its sinks whole, or drop it from those it did not fit
+fn end_frame() {
+    let frame = unsafe { &mut *core::ptr::addr_of_mut!(FRAME) };
+    if frame.sinks.contains(Sinks::RTT) {
+        if frame.rtt_overflow {
+            critical_section::with(count_dropped);
+        } else {
+            _SEGGER_RTT.up.write.store(frame.rtt_write as u32, Ordering::Release);
+        }
+    }
+    if frame.sinks.contains(Sinks::UART) {
+        UART_QUEUE.end_frame();
+    }
+    #[cfg(feature = "usb")]
+    if frame.sinks.contains(Sinks::CDC) {
+        CDC_QUEUE.end_frame();
+    }
+}
+
+#[defmt::global_logger]
+struct Logger;
+
+static TAKEN: AtomicBool = AtomicBool::new(false);
+static mut RESTORE: RestoreState = RestoreState::invalid();
+static mut ENCODER: defmt::Encoder = defmt::Encoder::new();
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {
+        let restore = unsafe { critical_section::acquire() };
+        if TAKEN.load(Ordering::Relaxed) {
+            panic!("defmt logger taken reentrantly");
+        }
+        TAKEN.store(true, Ordering::Relaxed);
+        unsafe {
+            RESTORE = restore;
+            begin_frame();
+            (*core::ptr::addr_of_mut!(ENCODER)).start_frame(emit);
+        }
+    }
+
+    unsafe fn flush() {
+        // RTT is read by the probe and the queues by their tasks; nothing to push
+    }
+
+    unsafe fn release() {
+        unsafe {
+            (*core::ptr::addr_of_mut!(ENCODER)).end_frame(emit);
+            end_frame();
+            TAKEN.store(false, Ordering::Relaxed);
+            critical_section::release(RESTORE);
+        }
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        unsafe { (*core::ptr::addr_of_mut!(ENCODER)).write(bytes, emit) };
+    }
+}
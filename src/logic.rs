@@ -0,0 +1,174 @@
+//! Logic analyzer capture
+//!
+//! [`LogicCapture`] snapshots a whole GPIO port's input register into RAM
+//! at a fixed rate: the GPTM update event raises a PDMA request and the
+//! PDMA copies `DINR` into the next half-word of the buffer, without the
+//! CPU. Rates up to a few MHz work as long as nothing else keeps the AHB
+//! busy; each sample holds all 16 pins of the port.
+//!
+//! A capture is armed, triggered, then read:
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::logic::{LogicCapture, Trigger};
+//! use embassy_ht32f523xx::timer::TriggerInput;
+//!
+//! let mut samples = [0u16; 4096];
+//! let mut capture = LogicCapture::new(&mut p.timer1, 'B');
+//! capture.arm(Hertz(2_000_000), Trigger::Input(TriggerInput::Ch0), &mut samples);
+//! let trace = capture.read().await?; // starts on the first CH0 edge
+//! ```
+//!
+//! With [`Trigger::Input`] the timer's slave controller starts the counter
+//! on the edge itself, so the first sample lands one period after the edge
+//! with no interrupt latency. [`Trigger::Manual`] waits for
+//! [`trigger`](LogicCapture::trigger).
+//!
+//! The update requests of GPTM0 and GPTM1 are wired to PDMA channels 3 and
+//! 4; channel 3 is shared with SPI1 TX, so a capture on GPTM0 and an SPI1
+//! built [`with_dma`](crate::spi::Spi::with_dma) cannot exist together.
+
+use core::future::poll_fn;
+use core::marker::PhantomData;
+use core::task::Poll;
+
+use crate::dma;
+use crate::peripheral::{Peri, Peripheral};
+use crate::time::Hertz;
+use crate::timer::{self, SlaveMode, Timer, TriggerInput};
+
+// GPTM DICTR: DMA request on update event
+const DICTR_UEVDE: u32 = 1 << 24;
+
+/// When sampling starts
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Trigger {
+    /// On [`LogicCapture::trigger`]
+    Manual,
+    /// On an edge of the timer's trigger input, in hardware
+    Input(TriggerInput),
+}
+
+/// PDMA channel carrying the update requests of timer `T`
+fn dma_channel<T: timer::Instance>() -> usize {
+    if core::ptr::eq(T::regs(), unsafe { &*crate::pac::Gptm1::ptr() }) { 4 } else { 3 }
+}
+
+/// Timer-paced snapshots of a GPIO port
+pub struct LogicCapture<'d, T: timer::Instance> {
+    timer: Peri<'d, T>,
+    port: char,
+    buf: *mut u16,
+    len: usize,
+    _buf: PhantomData<&'d mut [u16]>,
+}
+
+impl<'d, T: timer::Instance> LogicCapture<'d, T> {
+    /// Capture port `port` ('A'..='D') with timer `T`; its pins must already be inputs
+    ///
+    /// Claims the timer's PDMA channel until the capture is dropped.
+    pub fn new(timer: impl Peripheral<P = T> + 'd, port: char) -> Self {
+        assert!(matches!(port, 'A'..='D'), "invalid GPIO port");
+        dma::init();
+        dma::claim(dma_channel::<T>(), "logic capture");
+        Self {
+            timer: timer.into_ref(),
+            port,
+            buf: core::ptr::null_mut(),
+            len: 0,
+            _buf: PhantomData,
+        }
+    }
+
+    fn dinr(&self) -> *const u8 {
+        unsafe {
+            match self.port {
+                'A' => (*crate::pac::Gpioa::ptr()).dinr().as_ptr() as *const u8,
+                'B' => (*crate::pac::Gpiob::ptr()).dinr().as_ptr() as *const u8,
+                'C' => (*crate::pac::Gpioc::ptr()).dinr().as_ptr() as *const u8,
+                _ => (*crate::pac::Gpiod::ptr()).dinr().as_ptr() as *const u8,
+            }
+        }
+    }
+
+    /// Prepare a capture of `buf.len()` samples (at most 65535) at `rate`
+    ///
+    /// Replaces any capture still in progress.
+    pub fn arm(&mut self, rate: Hertz, trigger: Trigger, buf: &'d mut [u16]) {
+        self.stop();
+        let len = buf.len().min(0xFFFF);
+        self.buf = buf.as_mut_ptr();
+        self.len = len;
+
+        T::enable_clock();
+        let dinr = self.dinr();
+        let mut timer = Timer::new(self.timer.reborrow());
+        let regs = T::regs();
+
+        let clock = crate::rcc::get_clocks().apb_clk().to_hz();
+        let ticks = (clock / rate.to_hz().max(1)).max(1);
+        let prescaler = (ticks - 1) / 0x1_0000;
+        let period = ticks / (prescaler + 1) - 1;
+        regs.gptm_pscr().write(|w| unsafe { w.bits(prescaler) });
+        regs.gptm_crr().write(|w| unsafe { w.bits(period) });
+        regs.gptm_cntr().reset();
+
+        let mut ch = dma::Channel::new(dma_channel::<T>());
+        ch.start_paced(dinr, self.buf as *mut u8, len, dma::Width::HalfWord, false, true);
+        ch.listen();
+        regs.gptm_dictr().modify(|r, w| unsafe { w.bits(r.bits() | DICTR_UEVDE) });
+
+        match trigger {
+            Trigger::Manual => timer.set_slave_mode(SlaveMode::Disabled, TriggerInput::Ch0),
+            Trigger::Input(input) => {
+                timer.set_slave_mode(SlaveMode::Triggered, input);
+            }
+        }
+        debug!(
+            "logic: armed {} samples of port {} at {} Hz",
+            len,
+            self.port,
+            clock / ((prescaler + 1) * (period + 1))
+        );
+    }
+
+    /// Start an armed [`Trigger::Manual`] capture now
+    pub fn trigger(&mut self) {
+        T::regs().gptm_ctr().modify(|_, w| w.tme().set_bit());
+    }
+
+    /// Wait for the armed capture to fill its buffer and return the samples
+    ///
+    /// Bit `n` of each sample is pin `n` of the port.
+    pub async fn read(&mut self) -> Result<&[u16], dma::Error> {
+        let ch = dma::Channel::new(dma_channel::<T>());
+        let events = poll_fn(|cx| {
+            ch.waker().register(cx.waker());
+            match ch.take_events() {
+                0 => Poll::Pending,
+                events => Poll::Ready(events),
+            }
+        })
+        .await;
+        self.stop();
+
+        if events & dma::EVENT_ERROR != 0 {
+            return Err(dma::Error::Transfer);
+        }
+        Ok(unsafe { core::slice::from_raw_parts(self.buf, self.len) })
+    }
+
+    /// Abort the capture
+    pub fn stop(&mut self) {
+        let regs = T::regs();
+        regs.gptm_ctr().modify(|_, w| w.tme().clear_bit());
+        regs.gptm_dictr().modify(|r, w| unsafe { w.bits(r.bits() & !DICTR_UEVDE) });
+        dma::Channel::new(dma_channel::<T>()).stop();
+    }
+}
+
+impl<T: timer::Instance> Drop for LogicCapture<'_, T> {
+    fn drop(&mut self) {
+        self.stop();
+        dma::release(dma_channel::<T>(), "logic capture");
+    }
+}
@@ -0,0 +1,177 @@
+//! Low voltage detector (LVD)
+//!
+//! The LVD compares VDD against one of eight thresholds and raises the
+//! `LVD_BOD` interrupt when VDD falls below it. [`Lvd`] reports the level
+//! and latches every drop, which the flash driver's
+//! [`erase_checked`](crate::flash::Flash::erase_checked) /
+//! [`write_checked`](crate::flash::Flash::write_checked) use to refuse or stop
+//! programming while the supply is collapsing, e.g. on unplug.
+//!
+//! Pick a threshold well above the flash's minimum operating voltage so the
+//! operation in progress still completes: a page erase takes about 20 ms,
+//! which bulk capacitance on VDD has to bridge.
+//!
+//! The PAC has no PWRCU view of the LVD bits, so registers are accessed by address.
+
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
+
+use embassy_sync::waitqueue::AtomicWaker;
+
+use crate::regs::{Mmio, RegisterAccess};
+
+// PWRCU shares its 4 KiB block with the RTC, which takes the first 256 bytes
+const PWRCU_BASE: usize = 0x4006_A100;
+const PWRCU_BAKTEST: usize = 0x008;
+const PWRCU_LVDCSR: usize = 0x010;
+
+/// BAKTEST reads this once the backup domain accepts accesses
+const BAKTEST_READY: u32 = 0x27;
+/// Longest wait for BAKTEST; the domain is ready within microseconds of its clock
+const BAKTEST_TIMEOUT_US: u32 = 1000;
+
+// LVDCSR bits
+const LVDCSR_LVDEN: u32 = 1 << 16;
+const LVDCSR_LVDS_SHIFT: u32 = 17;
+const LVDCSR_LVDS_MASK: u32 = 0b11 << LVDCSR_LVDS_SHIFT;
+const LVDCSR_LVDF: u32 = 1 << 19;
+const LVDCSR_LVDIWEN: u32 = 1 << 20;
+const LVDCSR_LVDS2: u32 = 1 << 22;
+
+/// Set by the interrupt on a drop below the threshold, cleared by [`Lvd::clear`]
+static TRIPPED: AtomicBool = AtomicBool::new(false);
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// LVD threshold, `LVDS` in the datasheet's LVD table (0 = lowest voltage, 7 = highest)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Threshold(pub u8);
+
+impl Threshold {
+    /// Highest threshold; leaves the most margin on a 3.3 V supply
+    pub const HIGHEST: Threshold = Threshold(7);
+}
+
+/// LVD error
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The backup domain did not become accessible
+    BackupDomain,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::BackupDomain => f.write_str("backup domain not accessible"),
+        }
+    }
+}
+
+impl core::error::Error for Error {}
+
+/// Low voltage detector
+pub struct Lvd {
+    _private: (),
+}
+
+impl Lvd {
+    /// Enable the detector at `threshold` with its interrupt
+    ///
+    /// Panics if the backup domain cannot be accessed; see [`try_new`](Self::try_new).
+    pub fn new(threshold: Threshold) -> Self {
+        match Self::try_new(threshold) {
+            Ok(lvd) => lvd,
+            Err(e) => panic!("LVD: {}", e),
+        }
+    }
+
+    /// Enable the detector at `threshold` with its interrupt, or fail if the
+    /// backup domain holding the PWRCU does not come up
+    pub fn try_new(threshold: Threshold) -> Result<Self, Error> {
+        enable_backup_domain()?;
+
+        let level = (threshold.0 & 0b111) as u32;
+        Mmio.modify(PWRCU_BASE + PWRCU_LVDCSR, |v| {
+            let v = v & !(LVDCSR_LVDS_MASK | LVDCSR_LVDS2);
+            let v = v | (level & 0b11) << LVDCSR_LVDS_SHIFT;
+            let v = if level & 0b100 != 0 { v | LVDCSR_LVDS2 } else { v };
+            v | LVDCSR_LVDEN | LVDCSR_LVDIWEN
+        });
+
+        TRIPPED.store(false, Ordering::Relaxed);
+        unsafe { cortex_m::peripheral::NVIC::unmask(crate::pac::Interrupt::LVD_BOD) };
+
+        Ok(Self { _private: () })
+    }
+
+    /// VDD is below the threshold right now
+    pub fn is_low(&self) -> bool {
+        Mmio.read(PWRCU_BASE + PWRCU_LVDCSR) & LVDCSR_LVDF != 0
+    }
+
+    /// VDD dropped below the threshold at some point since the last [`clear`](Self::clear)
+    pub fn tripped(&self) -> bool {
+        TRIPPED.load(Ordering::Acquire) || self.is_low()
+    }
+
+    /// Forget earlier drops, e.g. after the supply has been stable again for a while
+    pub fn clear(&mut self) {
+        TRIPPED.store(false, Ordering::Release);
+        cortex_m::peripheral::NVIC::unpend(crate::pac::Interrupt::LVD_BOD);
+        unsafe { cortex_m::peripheral::NVIC::unmask(crate::pac::Interrupt::LVD_BOD) };
+    }
+
+    /// Wait until VDD drops below the threshold
+    pub async fn wait_low(&self) {
+        poll_fn(|cx| {
+            WAKER.register(cx.waker());
+            if self.tripped() { Poll::Ready(()) } else { Poll::Pending }
+        })
+        .await
+    }
+}
+
+impl Drop for Lvd {
+    fn drop(&mut self) {
+        cortex_m::peripheral::NVIC::mask(crate::pac::Interrupt::LVD_BOD);
+        Mmio.modify(PWRCU_BASE + PWRCU_LVDCSR, |v| v & !(LVDCSR_LVDEN | LVDCSR_LVDIWEN));
+    }
+}
+
+/// Open the backup domain (PWRCU and RTC) for register access
+pub(crate) fn enable_backup_domain() -> Result<(), Error> {
+    let ckcu = unsafe { &*crate::pac::Ckcu::ptr() };
+    // PWRCU and RTC registers sit behind the backup-domain clock gate
+    ckcu.apbccr1().modify(|_, w| w.bkpren().set_bit());
+    for _ in 0..BAKTEST_TIMEOUT_US {
+        if Mmio.read(PWRCU_BASE + PWRCU_BAKTEST) & 0xFF == BAKTEST_READY {
+            return Ok(());
+        }
+        crate::cortex_delay::delay_us(1);
+    }
+    warn!("lvd: backup domain not ready");
+    Err(Error::BackupDomain)
+}
+
+/// LVD interrupt handler body
+///
+/// The LVD flag follows VDD and cannot be cleared, so the interrupt is
+/// masked after the first drop until [`Lvd::clear`].
+pub(crate) fn on_interrupt() {
+    TRIPPED.store(true, Ordering::Release);
+    cortex_m::peripheral::NVIC::mask(crate::pac::Interrupt::LVD_BOD);
+    WAKER.wake();
+}
+
+#[cfg(feature = "rt")]
+use crate::pac::interrupt;
+
+#[cfg(feature = "rt")]
+#[interrupt]
+fn LVD_BOD() {
+    #[cfg(feature = "task-trace")]
+    let _isr = crate::task_trace::Isr::enter();
+    on_interrupt();
+}
@@ -0,0 +1,458 @@
+//! Modbus RTU master and slave over a USART
+//!
+//! RTU frames are delimited by silence: a frame ends when the line has been
+//! idle for 3.5 characters (fixed at 1.75 ms above 19200 baud), which
+//! [`Uart::read_until_idle`] detects. Each frame ends with a CRC-16 computed
+//! by the [`Crc`] unit. Both sides handle the register functions, which is
+//! what sensor dongles and small PLC peripherals use:
+//!
+//! | Code | Function                 |
+//! |------|--------------------------|
+//! | 0x03 | Read holding registers   |
+//! | 0x04 | Read input registers     |
+//! | 0x06 | Write single register    |
+//! | 0x10 | Write multiple registers |
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::modbus::{Exception, RegisterMap, Slave};
+//!
+//! struct Sensor { temperature: u16, setpoint: u16 }
+//!
+//! impl RegisterMap for Sensor {
+//!     fn read_holding(&mut self, address: u16) -> Result<u16, Exception> {
+//!         match address {
+//!             0 => Ok(self.temperature),
+//!             1 => Ok(self.setpoint),
+//!             _ => Err(Exception::IllegalDataAddress),
+//!         }
+//!     }
+//!     fn write_holding(&mut self, address: u16, value: u16) -> Result<(), Exception> {
+//!         match address {
+//!             1 => Ok(self.setpoint = value),
+//!             _ => Err(Exception::IllegalDataAddress),
+//!         }
+//!     }
+//! }
+//!
+//! let uart = Uart::new(p.usart0, tx, rx, uart::Config { baudrate: Hertz::hz(19_200), ..Default::default() });
+//! let mut slave = Slave::new(uart, p.crc, 17).with_driver_enable(AnyPin::new('B', 2));
+//! slave.run(&mut sensor).await;
+//! ```
+//!
+//! For RS-485, give the transceiver's DE pin (with /RE tied to it) to
+//! `with_driver_enable`; it is driven high from the first byte until the
+//! last stop bit is out, so the driver does not hear its own frames.
+
+use embassy_time::{Duration, Timer};
+
+use crate::crc::Crc;
+use crate::gpio::{AnyPin, Level};
+use crate::uart::{self, Instance, Uart};
+
+/// Longest RTU frame, address to CRC
+pub const MAX_FRAME: usize = 256;
+/// Unit address every slave obeys, without replying
+pub const BROADCAST: u8 = 0;
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const WRITE_SINGLE_REGISTER: u8 = 0x06;
+const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+/// Set in the function code of an exception response
+const EXCEPTION_FLAG: u8 = 0x80;
+
+/// Registers per read request
+const MAX_READ: usize = 125;
+/// Registers per write-multiple request
+const MAX_WRITE: usize = 123;
+
+/// Exception code returned by a slave
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Exception {
+    /// Function code not supported
+    IllegalFunction,
+    /// Register address not in the map
+    IllegalDataAddress,
+    /// Value or quantity out of range
+    IllegalDataValue,
+    /// The slave failed while handling the request
+    ServerDeviceFailure,
+    /// Any other code
+    Other(u8),
+}
+
+impl Exception {
+    /// Code sent on the wire
+    pub fn code(self) -> u8 {
+        match self {
+            Exception::IllegalFunction => 0x01,
+            Exception::IllegalDataAddress => 0x02,
+            Exception::IllegalDataValue => 0x03,
+            Exception::ServerDeviceFailure => 0x04,
+            Exception::Other(code) => code,
+        }
+    }
+
+    fn from_code(code: u8) -> Self {
+        match code {
+            0x01 => Exception::IllegalFunction,
+            0x02 => Exception::IllegalDataAddress,
+            0x03 => Exception::IllegalDataValue,
+            0x04 => Exception::ServerDeviceFailure,
+            code => Exception::Other(code),
+        }
+    }
+}
+
+/// Modbus error
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The USART reported an error or the frame was longer than [`MAX_FRAME`]
+    Uart(uart::Error),
+    /// No response within the master's timeout
+    Timeout,
+    /// The frame's CRC did not match
+    Crc,
+    /// Too short, from the wrong unit or not the response to the request
+    Frame,
+    /// The slave answered with an exception
+    Exception(Exception),
+}
+
+impl From<uart::Error> for Error {
+    fn from(e: uart::Error) -> Self {
+        Error::Uart(e)
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Error::Uart(_) => "UART error",
+            Error::Timeout => "no response",
+            Error::Crc => "CRC mismatch",
+            Error::Frame => "unexpected frame",
+            Error::Exception(_) => "exception response",
+        })
+    }
+}
+
+impl core::error::Error for Error {}
+
+/// Silence that ends a frame at `baud`
+pub fn frame_gap(baud: u32) -> Duration {
+    if baud > 19_200 {
+        Duration::from_micros(1_750)
+    } else {
+        // 3.5 characters of 11 bits
+        Duration::from_micros(38_500_000 / baud.max(1) as u64)
+    }
+}
+
+fn get_u16(buf: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([buf[at], buf[at + 1]])
+}
+
+fn put_u16(buf: &mut [u8], at: usize, value: u16) {
+    buf[at..at + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+/// USART, CRC unit and frame buffer shared by master and slave
+struct Link<'d, T: Instance> {
+    uart: Uart<'d, T>,
+    crc: Crc,
+    de: Option<AnyPin>,
+    gap: Duration,
+    buf: [u8; MAX_FRAME],
+}
+
+impl<'d, T: Instance> Link<'d, T> {
+    fn new(uart: Uart<'d, T>, crc: Crc) -> Self {
+        let gap = frame_gap(uart.baud_rate().actual);
+        Self { uart, crc, de: None, gap, buf: [0; MAX_FRAME] }
+    }
+
+    fn set_driver_enable(&mut self, mut de: AnyPin) {
+        de.set_as_output(Level::Low);
+        self.de = Some(de);
+    }
+
+    /// Append the CRC to the first `len` bytes of the buffer and send them
+    async fn send(&mut self, len: usize) -> Result<(), Error> {
+        let crc = self.crc.crc16_modbus(&self.buf[..len]);
+        self.buf[len..len + 2].copy_from_slice(&crc.to_le_bytes());
+
+        if let Some(de) = self.de.as_mut() {
+            de.set_as_output(Level::High);
+        }
+        let result = self.uart.write(&self.buf[..len + 2]).await;
+        self.uart.wait_tx_complete().await;
+        if let Some(de) = self.de.as_mut() {
+            de.set_as_output(Level::Low);
+        }
+        Ok(result?)
+    }
+
+    /// Receive a frame and check its CRC; returns its length without the CRC
+    async fn receive(&mut self) -> Result<usize, Error> {
+        let len = self.uart.read_until_idle(&mut self.buf, self.gap).await?;
+        if len < 4 {
+            return Err(Error::Frame);
+        }
+        let len = len - 2;
+        let crc = self.crc.crc16_modbus(&self.buf[..len]);
+        if crc.to_le_bytes() != self.buf[len..len + 2] {
+            return Err(Error::Crc);
+        }
+        Ok(len)
+    }
+}
+
+/// Modbus RTU master (client)
+pub struct Master<'d, T: Instance> {
+    link: Link<'d, T>,
+    timeout: Duration,
+}
+
+impl<'d, T: Instance> Master<'d, T> {
+    /// Master on a configured USART, with a 100 ms response timeout
+    pub fn new(uart: Uart<'d, T>, crc: Crc) -> Self {
+        Self { link: Link::new(uart, crc), timeout: Duration::from_millis(100) }
+    }
+
+    /// Drive `de` high while transmitting, for an RS-485 transceiver
+    pub fn with_driver_enable(mut self, de: AnyPin) -> Self {
+        self.link.set_driver_enable(de);
+        self
+    }
+
+    /// How long to wait for the first byte of a response
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Read `out.len()` holding registers from `start` (1 to 125)
+    pub async fn read_holding_registers(&mut self, unit: u8, start: u16, out: &mut [u16]) -> Result<(), Error> {
+        self.read_registers(unit, READ_HOLDING_REGISTERS, start, out).await
+    }
+
+    /// Read `out.len()` input registers from `start` (1 to 125)
+    pub async fn read_input_registers(&mut self, unit: u8, start: u16, out: &mut [u16]) -> Result<(), Error> {
+        self.read_registers(unit, READ_INPUT_REGISTERS, start, out).await
+    }
+
+    /// Write one holding register
+    pub async fn write_single_register(&mut self, unit: u8, address: u16, value: u16) -> Result<(), Error> {
+        let buf = &mut self.link.buf;
+        buf[0] = unit;
+        buf[1] = WRITE_SINGLE_REGISTER;
+        put_u16(buf, 2, address);
+        put_u16(buf, 4, value);
+        let request = [buf[2], buf[3], buf[4], buf[5]];
+
+        let len = self.transact(unit, 6).await?;
+        // The slave echoes the request
+        if unit != BROADCAST && (len != 6 || self.link.buf[2..6] != request) {
+            return Err(Error::Frame);
+        }
+        Ok(())
+    }
+
+    /// Write consecutive holding registers from `start` (1 to 123)
+    pub async fn write_multiple_registers(&mut self, unit: u8, start: u16, values: &[u16]) -> Result<(), Error> {
+        assert!((1..=MAX_WRITE).contains(&values.len()), "Modbus writes 1 to 123 registers at a time");
+
+        let buf = &mut self.link.buf;
+        buf[0] = unit;
+        buf[1] = WRITE_MULTIPLE_REGISTERS;
+        put_u16(buf, 2, start);
+        put_u16(buf, 4, values.len() as u16);
+        buf[6] = (values.len() * 2) as u8;
+        for (i, &value) in values.iter().enumerate() {
+            put_u16(buf, 7 + i * 2, value);
+        }
+
+        let len = self.transact(unit, 7 + values.len() * 2).await?;
+        let buf = &self.link.buf;
+        if unit != BROADCAST && (len != 6 || get_u16(buf, 2) != start || get_u16(buf, 4) as usize != values.len()) {
+            return Err(Error::Frame);
+        }
+        Ok(())
+    }
+
+    async fn read_registers(&mut self, unit: u8, function: u8, start: u16, out: &mut [u16]) -> Result<(), Error> {
+        assert!((1..=MAX_READ).contains(&out.len()), "Modbus reads 1 to 125 registers at a time");
+        assert!(unit != BROADCAST, "reads cannot be broadcast");
+
+        let buf = &mut self.link.buf;
+        buf[0] = unit;
+        buf[1] = function;
+        put_u16(buf, 2, start);
+        put_u16(buf, 4, out.len() as u16);
+
+        let len = self.transact(unit, 6).await?;
+        let buf = &self.link.buf;
+        if buf[2] as usize != out.len() * 2 || len != 3 + out.len() * 2 {
+            return Err(Error::Frame);
+        }
+        for (i, value) in out.iter_mut().enumerate() {
+            *value = get_u16(buf, 3 + i * 2);
+        }
+        Ok(())
+    }
+
+    /// Send the request in the buffer and receive the response into it
+    ///
+    /// Returns the response length without the CRC, or 0 for a broadcast.
+    async fn transact(&mut self, unit: u8, len: usize) -> Result<usize, Error> {
+        let function = self.link.buf[1];
+
+        // The line must be quiet for a frame gap before a new request
+        Timer::after(self.link.gap).await;
+        self.link.send(len).await?;
+        if unit == BROADCAST {
+            return Ok(0);
+        }
+
+        let len = embassy_time::with_timeout(self.timeout, self.link.receive())
+            .await
+            .map_err(|_| Error::Timeout)??;
+        let buf = &self.link.buf;
+        if buf[0] != unit {
+            return Err(Error::Frame);
+        }
+        if buf[1] == function | EXCEPTION_FLAG {
+            return Err(Error::Exception(Exception::from_code(buf[2])));
+        }
+        if buf[1] != function || len < 3 {
+            return Err(Error::Frame);
+        }
+        Ok(len)
+    }
+}
+
+/// Registers a [`Slave`] serves
+///
+/// Addresses are the 0-based protocol addresses (holding register 40001 is
+/// address 0). Functions left at their default answer
+/// [`Exception::IllegalDataAddress`].
+pub trait RegisterMap {
+    /// Value of a holding register
+    fn read_holding(&mut self, address: u16) -> Result<u16, Exception>;
+
+    /// Store a holding register
+    fn write_holding(&mut self, address: u16, value: u16) -> Result<(), Exception> {
+        let _ = (address, value);
+        Err(Exception::IllegalDataAddress)
+    }
+
+    /// Value of an input register
+    fn read_input(&mut self, address: u16) -> Result<u16, Exception> {
+        let _ = address;
+        Err(Exception::IllegalDataAddress)
+    }
+}
+
+/// Modbus RTU slave (server)
+pub struct Slave<'d, T: Instance> {
+    link: Link<'d, T>,
+    unit: u8,
+}
+
+impl<'d, T: Instance> Slave<'d, T> {
+    /// Slave answering to `unit` (1 to 247) on a configured USART
+    pub fn new(uart: Uart<'d, T>, crc: Crc, unit: u8) -> Self {
+        assert!((1..=247).contains(&unit), "Modbus unit addresses are 1 to 247");
+        Self { link: Link::new(uart, crc), unit }
+    }
+
+    /// Drive `de` high while transmitting, for an RS-485 transceiver
+    pub fn with_driver_enable(mut self, de: AnyPin) -> Self {
+        self.link.set_driver_enable(de);
+        self
+    }
+
+    /// Serve requests forever, dropping bad frames
+    pub async fn run(&mut self, map: &mut impl RegisterMap) -> ! {
+        loop {
+            if let Err(e) = self.serve(map).await {
+                debug!("modbus: dropped frame: {}", e);
+            }
+        }
+    }
+
+    /// Wait for one request and answer it
+    ///
+    /// Frames for other units are ignored, broadcasts are carried out
+    /// without a reply. A write-multiple request stops at the first register
+    /// the map refuses; the ones before it stay written.
+    pub async fn serve(&mut self, map: &mut impl RegisterMap) -> Result<(), Error> {
+        let len = self.link.receive().await?;
+        let unit = self.link.buf[0];
+        if unit != self.unit && unit != BROADCAST {
+            return Ok(());
+        }
+
+        let reply = match Self::handle(&mut self.link.buf, len, map) {
+            Ok(reply) => reply,
+            Err(exception) => {
+                let buf = &mut self.link.buf;
+                buf[1] |= EXCEPTION_FLAG;
+                buf[2] = exception.code();
+                3
+            }
+        };
+        if unit == BROADCAST {
+            return Ok(());
+        }
+        self.link.send(reply).await
+    }
+
+    /// Carry out the request in `buf` and build the response in place
+    fn handle(buf: &mut [u8; MAX_FRAME], len: usize, map: &mut impl RegisterMap) -> Result<usize, Exception> {
+        let function = buf[1];
+        if len < 6 {
+            return Err(Exception::IllegalDataValue);
+        }
+        let address = get_u16(buf, 2);
+        let count = get_u16(buf, 4) as usize;
+
+        match function {
+            READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+                if len != 6 || !(1..=MAX_READ).contains(&count) {
+                    return Err(Exception::IllegalDataValue);
+                }
+                for i in 0..count {
+                    let register = address.wrapping_add(i as u16);
+                    let value = if function == READ_HOLDING_REGISTERS {
+                        map.read_holding(register)?
+                    } else {
+                        map.read_input(register)?
+                    };
+                    put_u16(buf, 3 + i * 2, value);
+                }
+                buf[2] = (count * 2) as u8;
+                Ok(3 + count * 2)
+            }
+            WRITE_SINGLE_REGISTER => {
+                if len != 6 {
+                    return Err(Exception::IllegalDataValue);
+                }
+                map.write_holding(address, get_u16(buf, 4))?;
+                Ok(6)
+            }
+            WRITE_MULTIPLE_REGISTERS => {
+                if !(1..=MAX_WRITE).contains(&count) || buf[6] as usize != count * 2 || len != 7 + count * 2 {
+                    return Err(Exception::IllegalDataValue);
+                }
+                for i in 0..count {
+                    map.write_holding(address.wrapping_add(i as u16), get_u16(buf, 7 + i * 2))?;
+                }
+                Ok(6)
+            }
+            _ => Err(Exception::IllegalFunction),
+        }
+    }
+}
@@ -0,0 +1,306 @@
+//! H-bridge DC motor driver on the MCTM
+//!
+//! The MCTM (motor control timer) drives both legs of an H-bridge with
+//! complementary outputs: CH0/CH0N switch the high and low side of leg A,
+//! CH1/CH1N those of leg B, with hardware dead time between each pair so
+//! the two FETs of a leg never conduct together. Speed is sign-magnitude:
+//! one leg switches at the duty cycle while the other holds its low side
+//! on, which also brakes synchronously during the off time.
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::motor::{CurrentLimit, MotorConfig, MotorDriver};
+//!
+//! // CH0, CH0N, CH1, CH1N in AF4 first; the shunt on the CMP0 positive input (analog)
+//! let mut motor = MotorDriver::new(p.mctm0, MotorConfig::default())
+//!     .with_current_limit(p.cmp0, CurrentLimit { shunt_milliohm: 50, limit_ma: 3_000, vdda_mv: 3_300 });
+//! motor.set_speed(0.6);
+//! if motor.is_tripped() {
+//!     motor.set_speed(0.0);
+//!     motor.resume();
+//! }
+//! ```
+//!
+//! Overcurrent is handled without software: the break input (the BRK pin,
+//! or comparator 0 comparing the shunt voltage with its internal reference)
+//! clears the main output enable within a few clock cycles and every output
+//! goes to its inactive level, all FETs off. The outputs stay off until
+//! [`MotorDriver::resume`], so a fault cannot restart the motor by itself.
+//!
+//! The PAC has no MCTM or comparator view, so registers are accessed by
+//! address.
+
+use crate::peripheral::{Peri, Peripheral};
+use crate::regs::{Mmio, RegisterAccess};
+use crate::time::Hertz;
+
+const MCTM_BASE: usize = 0x4002_C000;
+const MCTM_CTR: usize = 0x010;
+const MCTM_CH0OCFR: usize = 0x040;
+const MCTM_CH1OCFR: usize = 0x044;
+const MCTM_CHCTR: usize = 0x050;
+const MCTM_CHPOLR: usize = 0x054;
+const MCTM_CHBRKCFR: usize = 0x06C;
+const MCTM_CHBRKCTR: usize = 0x070;
+const MCTM_EVGR: usize = 0x078;
+const MCTM_PSCR: usize = 0x084;
+const MCTM_CRR: usize = 0x088;
+const MCTM_CH0CCR: usize = 0x090;
+const MCTM_CH1CCR: usize = 0x094;
+
+// CTR: timer enable, CRR preload
+const CTR_TME: u32 = 1 << 0;
+const CTR_CRBE: u32 = 1 << 1;
+// CHxOCFR: PWM mode 1 with CCR preload
+const OM_PWM1: u32 = 0b110;
+const OCFR_PRE: u32 = 1 << 4;
+// CHCTR: CH0, CH0N, CH1, CH1N enables
+const CHCTR_LEGS: u32 = 0b1111;
+// CHBRKCTR: break enable and polarity, filter, main/idle output control, dead time
+const BRK_BKE: u32 = 1 << 0;
+const BRK_BKP: u32 = 1 << 1;
+const BRK_BKF_SHIFT: u32 = 8;
+const BRK_CHMOE: u32 = 1 << 16;
+const BRK_CHOSSI: u32 = 1 << 20;
+const BRK_DTG_SHIFT: u32 = 24;
+// EVGR: update event, loads the preloaded registers
+const EVGR_UEVG: u32 = 1 << 0;
+/// Break filter setting: ignore glitches shorter than a few hundred ns of switching noise
+const BREAK_FILTER: u32 = 0x3;
+
+const CMP_BASE: usize = 0x4005_8000;
+const CMP_CR: usize = 0x000;
+const CMP_VALR: usize = 0x004;
+// CMPCR: enable, negative input from the internal reference, reference enable, write key
+const CMP_EN: u32 = 1 << 0;
+const CMP_INSEL_CVR: u32 = 1 << 8;
+const CMP_CVREN: u32 = 1 << 9;
+const CMP_WRITE_KEY: u32 = 0x9C3A << 16;
+/// Internal reference steps over VDDA
+const CVR_STEPS: u32 = 64;
+
+/// Motor control timer 0
+pub struct Mctm0 {
+    _private: (),
+}
+
+impl Mctm0 {
+    pub(crate) fn new() -> Self {
+        Self { _private: () }
+    }
+}
+
+/// Analog comparator 0
+pub struct Cmp0 {
+    _private: (),
+}
+
+impl Cmp0 {
+    pub(crate) fn new() -> Self {
+        Self { _private: () }
+    }
+}
+
+impl_peripheral!(Mctm0, Cmp0);
+
+/// DTG field for a dead time of at least `ns` with the timer clocked at `clock_hz`
+///
+/// Uses the four DTG ranges (1, 2, 8 and 16 clocks per step) and rounds up;
+/// longer dead times than the field can hold saturate at 1008 clocks.
+pub const fn dead_time_bits(clock_hz: u32, ns: u32) -> u8 {
+    let ticks = (ns as u64 * clock_hz as u64).div_ceil(1_000_000_000);
+    if ticks <= 127 {
+        ticks as u8
+    } else if ticks <= 254 {
+        0x80 | (ticks.div_ceil(2) - 64) as u8
+    } else if ticks <= 504 {
+        0xC0 | (ticks.div_ceil(8) - 32) as u8
+    } else if ticks <= 1008 {
+        0xE0 | (ticks.div_ceil(16) - 32) as u8
+    } else {
+        0xFF
+    }
+}
+
+const _: () = assert!(dead_time_bits(48_000_000, 500) == 24);
+const _: () = assert!(dead_time_bits(48_000_000, 4_000) == 0xA0);
+const _: () = assert!(dead_time_bits(48_000_000, 10_000) == 0xDC);
+const _: () = assert!(dead_time_bits(48_000_000, 100_000) == 0xFF);
+
+/// PWM frequency and dead time
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MotorConfig {
+    /// PWM frequency; 20 kHz is above hearing. At least 733 Hz at 48 MHz PCLK.
+    pub frequency: Hertz,
+    /// Dead time between one FET of a leg turning off and the other on
+    pub dead_time_ns: u32,
+}
+
+impl Default for MotorConfig {
+    fn default() -> Self {
+        Self {
+            frequency: Hertz::khz(20),
+            dead_time_ns: 500,
+        }
+    }
+}
+
+/// Overcurrent threshold for comparator 0
+///
+/// The comparator trips when the shunt voltage exceeds
+/// `limit_ma * shunt_milliohm`, rounded down to the internal reference's
+/// VDDA / 64 steps.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CurrentLimit {
+    /// Low-side shunt resistance
+    pub shunt_milliohm: u32,
+    /// Current that trips the break
+    pub limit_ma: u32,
+    /// Analog supply, the reference for the threshold
+    pub vdda_mv: u32,
+}
+
+impl CurrentLimit {
+    /// Internal reference setting, 1..=63
+    fn cvr(&self) -> u32 {
+        let threshold_mv = self.limit_ma * self.shunt_milliohm / 1000;
+        (threshold_mv * CVR_STEPS / self.vdda_mv.max(1)).clamp(1, CVR_STEPS - 1)
+    }
+}
+
+/// H-bridge on MCTM0 channels 0 and 1
+pub struct MotorDriver<'d> {
+    _mctm: Peri<'d, Mctm0>,
+    cmp: Option<Peri<'d, Cmp0>>,
+    period: u32,
+    speed: f32,
+    coasting: bool,
+}
+
+impl<'d> MotorDriver<'d> {
+    /// Start the bridge with both low sides on (braked), without a current limit
+    ///
+    /// Configure CH0, CH0N, CH1 and CH1N for AF4 first.
+    pub fn new(mctm: impl Peripheral<P = Mctm0> + 'd, config: MotorConfig) -> Self {
+        let ckcu = unsafe { &*crate::pac::Ckcu::ptr() };
+        ckcu.apbccr1().modify(|_, w| w.mctm0en().set_bit());
+
+        let pclk = crate::rcc::get_clocks().apb_clk().to_hz();
+        let period = (pclk / config.frequency.to_hz()).clamp(2, u16::MAX as u32 + 1);
+        let dtg = dead_time_bits(pclk, config.dead_time_ns) as u32;
+
+        let reg = |offset| MCTM_BASE + offset;
+        Mmio.write(reg(MCTM_CTR), 0);
+        Mmio.write(reg(MCTM_PSCR), 0);
+        Mmio.write(reg(MCTM_CRR), period - 1);
+        Mmio.write(reg(MCTM_CH0CCR), 0);
+        Mmio.write(reg(MCTM_CH1CCR), 0);
+        Mmio.write(reg(MCTM_CH0OCFR), OM_PWM1 | OCFR_PRE);
+        Mmio.write(reg(MCTM_CH1OCFR), OM_PWM1 | OCFR_PRE);
+        // Active high on every output, and all of them inactive (FETs off) when idle
+        Mmio.write(reg(MCTM_CHPOLR), 0);
+        Mmio.write(reg(MCTM_CHBRKCFR), 0);
+        Mmio.write(reg(MCTM_CHCTR), CHCTR_LEGS);
+        Mmio.write(reg(MCTM_CHBRKCTR), dtg << BRK_DTG_SHIFT | BRK_CHOSSI | BRK_CHMOE);
+        Mmio.write(reg(MCTM_EVGR), EVGR_UEVG);
+        Mmio.write(reg(MCTM_CTR), CTR_CRBE | CTR_TME);
+
+        debug!("motor: {} Hz PWM, period {} clocks, DTG {:#x}", pclk / period, period, dtg);
+
+        Self {
+            _mctm: mctm.into_ref(),
+            cmp: None,
+            period,
+            speed: 0.0,
+            coasting: false,
+        }
+    }
+
+    /// Trip on the BRK pin (AF4), high or low active
+    pub fn with_break_pin(self, active_high: bool) -> Self {
+        Self::enable_break(active_high);
+        self
+    }
+
+    /// Trip when comparator 0 sees the shunt voltage above `limit`
+    ///
+    /// Put the shunt (or its amplifier) on the CMP0 positive input pin in
+    /// analog mode first.
+    pub fn with_current_limit(mut self, cmp: impl Peripheral<P = Cmp0> + 'd, limit: CurrentLimit) -> Self {
+        let ckcu = unsafe { &*crate::pac::Ckcu::ptr() };
+        ckcu.apbccr1().modify(|_, w| w.cmpen().set_bit());
+
+        let cvr = limit.cvr();
+        Mmio.write(CMP_BASE + CMP_VALR, cvr);
+        Mmio.write(CMP_BASE + CMP_CR, CMP_WRITE_KEY | CMP_CVREN | CMP_INSEL_CVR | CMP_EN);
+        debug!("motor: current limit {} mA, reference {}/64 VDDA", limit.limit_ma, cvr);
+
+        // The comparator output is high while the shunt voltage is above the reference
+        Self::enable_break(true);
+        self.cmp = Some(cmp.into_ref());
+        self
+    }
+
+    fn enable_break(active_high: bool) {
+        let polarity = if active_high { BRK_BKP } else { 0 };
+        Mmio.modify(MCTM_BASE + MCTM_CHBRKCTR, |r| r | BREAK_FILTER << BRK_BKF_SHIFT | polarity | BRK_BKE);
+    }
+
+    /// Set speed and direction, -1.0 (full reverse) to 1.0 (full forward)
+    ///
+    /// 0.0 brakes. Takes effect at the next PWM period. While tripped or
+    /// coasting the value is stored for [`resume`](Self::resume).
+    pub fn set_speed(&mut self, speed: f32) {
+        let speed = speed.clamp(-1.0, 1.0);
+        self.speed = speed;
+        let duty = (speed.abs() * self.period as f32) as u32;
+        let (a, b) = if speed >= 0.0 { (duty, 0) } else { (0, duty) };
+        Mmio.write(MCTM_BASE + MCTM_CH0CCR, a);
+        Mmio.write(MCTM_BASE + MCTM_CH1CCR, b);
+    }
+
+    /// Last speed set
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Short the motor through both low sides
+    pub fn brake(&mut self) {
+        self.set_speed(0.0);
+    }
+
+    /// Turn every FET off and let the motor spin down freely
+    pub fn coast(&mut self) {
+        self.coasting = true;
+        Mmio.modify(MCTM_BASE + MCTM_CHBRKCTR, |r| r & !BRK_CHMOE);
+    }
+
+    /// Whether the break input has switched the outputs off
+    pub fn is_tripped(&self) -> bool {
+        !self.coasting && Mmio.read(MCTM_BASE + MCTM_CHBRKCTR) & BRK_CHMOE == 0
+    }
+
+    /// Turn the outputs back on after a trip or [`coast`](Self::coast)
+    ///
+    /// Returns `false` if the break input is still active; the hardware
+    /// keeps the outputs off then. Lower the speed before resuming.
+    pub fn resume(&mut self) -> bool {
+        self.coasting = false;
+        Mmio.modify(MCTM_BASE + MCTM_CHBRKCTR, |r| r | BRK_CHMOE);
+        let on = Mmio.read(MCTM_BASE + MCTM_CHBRKCTR) & BRK_CHMOE != 0;
+        if !on {
+            warn!("motor: break input still active");
+        }
+        on
+    }
+}
+
+impl Drop for MotorDriver<'_> {
+    fn drop(&mut self) {
+        Mmio.modify(MCTM_BASE + MCTM_CHBRKCTR, |r| r & !BRK_CHMOE);
+        Mmio.write(MCTM_BASE + MCTM_CTR, 0);
+        if self.cmp.is_some() {
+            Mmio.write(CMP_BASE + CMP_CR, CMP_WRITE_KEY);
+        }
+    }
+}
@@ -0,0 +1,281 @@
+//! NTC thermistor temperature measurement
+//!
+//! The thermistor forms a divider with a fixed resistor across VDDA, so the
+//! reading is ratiometric and the reference voltage drops out. [`Ntc`]
+//! oversamples the ADC, converts the ratio to a resistance and the
+//! resistance to a temperature with either the Beta equation or the
+//! Steinhart-Hart equation ([`Model`]). Temperatures are `f32` degrees
+//! Celsius; the M0+ has no FPU, so a conversion costs a few thousand cycles.
+//!
+//! [`TemperatureAlarm`] is the shared side: a limit with hysteresis that
+//! tasks can wait on, usually from a `static`.
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::ntc::{Config, Ntc, TemperatureAlarm, AlarmEvent};
+//!
+//! static ALARM: TemperatureAlarm = TemperatureAlarm::new();
+//!
+//! let mut ntc = Ntc::new(Config { channel: 4, ..Default::default() });
+//! ALARM.set_limit(Some(250.0), 5.0);
+//! let monitor = ntc.monitor(&mut p.adc, &ALARM, Duration::from_millis(100));
+//! let cutoff = async {
+//!     loop {
+//!         if let AlarmEvent::Over(_) = ALARM.wait().await {
+//!             heater.set_low().ok();
+//!         }
+//!     }
+//! };
+//! ```
+
+use core::cell::Cell;
+
+use critical_section::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+
+use crate::adc::{self, Adc};
+
+const KELVIN: f32 = 273.15;
+
+/// Measurement error
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The reading sits at a rail: thermistor disconnected
+    Open,
+    /// The reading sits at the other rail: thermistor shorted
+    Short,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Error::Open => "thermistor open",
+            Error::Short => "thermistor shorted",
+        })
+    }
+}
+
+impl core::error::Error for Error {}
+
+/// Resistance-to-temperature model
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Model {
+    /// `1/T = 1/T0 + ln(R/R0)/B`, from the datasheet's R25 and B value
+    Beta {
+        /// Resistance at `t0_celsius`, in ohms
+        r0: f32,
+        /// Reference temperature, usually 25 °C
+        t0_celsius: f32,
+        /// B constant in kelvin
+        beta: f32,
+    },
+    /// `1/T = A + B ln(R) + C ln(R)^3`, fitted to three calibration points
+    SteinhartHart { a: f32, b: f32, c: f32 },
+}
+
+impl Model {
+    /// Temperature in °C for a thermistor resistance in ohms
+    pub fn celsius(&self, ohms: f32) -> f32 {
+        let inverse_kelvin = match *self {
+            Model::Beta { r0, t0_celsius, beta } => 1.0 / (t0_celsius + KELVIN) + ln(ohms / r0) / beta,
+            Model::SteinhartHart { a, b, c } => {
+                let l = ln(ohms);
+                a + b * l + c * l * l * l
+            }
+        };
+        1.0 / inverse_kelvin - KELVIN
+    }
+}
+
+/// Which side of the divider the thermistor is on
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Placement {
+    /// Fixed resistor to VDDA, thermistor to ground
+    LowSide,
+    /// Thermistor to VDDA, fixed resistor to ground
+    HighSide,
+}
+
+/// Thermistor configuration; the default is a 100 kΩ B3950 against 4.7 kΩ, as on 3D printers and hotplates
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// ADC channel at the divider midpoint
+    pub channel: u8,
+    /// Fixed divider resistor in ohms
+    pub r_fixed: f32,
+    /// Thermistor position in the divider
+    pub placement: Placement,
+    /// Thermistor curve
+    pub model: Model,
+    /// Conversions averaged per reading
+    pub oversample: u16,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            channel: 0,
+            r_fixed: 4700.0,
+            placement: Placement::LowSide,
+            model: Model::Beta {
+                r0: 100_000.0,
+                t0_celsius: 25.0,
+                beta: 3950.0,
+            },
+            oversample: 16,
+        }
+    }
+}
+
+/// Natural logarithm for positive `x`, to about 1e-6 relative
+fn ln(x: f32) -> f32 {
+    // x = m * 2^e with m in [1, 2)
+    let bits = x.to_bits();
+    let e = ((bits >> 23) & 0xFF) as i32 - 127;
+    let m = f32::from_bits((bits & 0x007F_FFFF) | 0x3F80_0000);
+
+    // ln(m) = 2 atanh(t), t = (m - 1) / (m + 1) <= 1/3
+    let t = (m - 1.0) / (m + 1.0);
+    let t2 = t * t;
+    let series = t * (1.0 + t2 * (1.0 / 3.0 + t2 * (1.0 / 5.0 + t2 * (1.0 / 7.0 + t2 / 9.0))));
+    e as f32 * core::f32::consts::LN_2 + 2.0 * series
+}
+
+/// Thermistor channel
+pub struct Ntc {
+    config: Config,
+}
+
+impl Ntc {
+    /// Create a channel; the ADC is passed to each read so it can be shared
+    pub fn new(config: Config) -> Self {
+        Self { config }
+    }
+
+    /// Thermistor resistance in ohms
+    pub fn read_ohms(&mut self, adc: &mut Adc) -> Result<f32, Error> {
+        let samples = self.config.oversample.max(1) as u32;
+        let sum: u32 = (0..samples).map(|_| adc.read(self.config.channel) as u32).sum();
+        let full = adc::MAX_VALUE as u32 * samples;
+
+        // Within half an LSB of a rail there is no usable resistance
+        let (at_vdda, at_ground) = (sum + samples / 2 >= full, sum <= samples / 2);
+        let (high, low) = (sum as f32, (full - sum.min(full)) as f32);
+        match self.config.placement {
+            Placement::LowSide if at_vdda => Err(Error::Open),
+            Placement::LowSide if at_ground => Err(Error::Short),
+            Placement::LowSide => Ok(self.config.r_fixed * high / low),
+            Placement::HighSide if at_ground => Err(Error::Open),
+            Placement::HighSide if at_vdda => Err(Error::Short),
+            Placement::HighSide => Ok(self.config.r_fixed * low / high),
+        }
+    }
+
+    /// Temperature in °C
+    pub fn read_celsius(&mut self, adc: &mut Adc) -> Result<f32, Error> {
+        let ohms = self.read_ohms(adc)?;
+        Ok(self.config.model.celsius(ohms))
+    }
+
+    /// Read and feed `alarm` every `period` forever
+    ///
+    /// A faulty thermistor reads as +infinity, so an over-temperature limit
+    /// also trips on an open or shorted sensor.
+    #[cfg(feature = "time")]
+    pub async fn monitor(&mut self, adc: &mut Adc, alarm: &TemperatureAlarm, period: embassy_time::Duration) -> ! {
+        let mut ticker = embassy_time::Ticker::every(period);
+        loop {
+            let celsius = match self.read_celsius(adc) {
+                Ok(celsius) => celsius,
+                Err(e) => {
+                    warn!("ntc: {}", e);
+                    f32::INFINITY
+                }
+            };
+            alarm.update(celsius);
+            ticker.next().await;
+        }
+    }
+}
+
+/// Alarm state change
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AlarmEvent {
+    /// The temperature reached the limit
+    Over(f32),
+    /// The temperature fell back below the limit minus the hysteresis
+    Cleared(f32),
+}
+
+/// Over-temperature limit shared between the measuring task and its watchers
+pub struct TemperatureAlarm {
+    /// (limit, hysteresis) in °C
+    limit: Mutex<Cell<Option<(f32, f32)>>>,
+    over: Mutex<Cell<bool>>,
+    last: Mutex<Cell<Option<f32>>>,
+    signal: Signal<CriticalSectionRawMutex, AlarmEvent>,
+}
+
+impl TemperatureAlarm {
+    /// No limit set; usable in a `static`
+    pub const fn new() -> Self {
+        Self {
+            limit: Mutex::new(Cell::new(None)),
+            over: Mutex::new(Cell::new(false)),
+            last: Mutex::new(Cell::new(None)),
+            signal: Signal::new(),
+        }
+    }
+
+    /// Set or remove the limit; it clears again `hysteresis` °C below
+    pub fn set_limit(&self, limit: Option<f32>, hysteresis: f32) {
+        critical_section::with(|cs| self.limit.borrow(cs).set(limit.map(|l| (l, hysteresis))));
+    }
+
+    /// Last temperature fed in
+    pub fn celsius(&self) -> Option<f32> {
+        critical_section::with(|cs| self.last.borrow(cs).get())
+    }
+
+    /// Whether the limit is currently exceeded
+    pub fn is_over(&self) -> bool {
+        critical_section::with(|cs| self.over.borrow(cs).get())
+    }
+
+    /// Wait for the next alarm state change
+    pub async fn wait(&self) -> AlarmEvent {
+        self.signal.wait().await
+    }
+
+    /// Feed a reading; [`Ntc::monitor`] does this
+    pub fn update(&self, celsius: f32) {
+        critical_section::with(|cs| {
+            self.last.borrow(cs).set(Some(celsius));
+
+            let over = self.over.borrow(cs);
+            match self.limit.borrow(cs).get() {
+                Some((limit, _)) if !over.get() && celsius >= limit => {
+                    over.set(true);
+                    self.signal.signal(AlarmEvent::Over(celsius));
+                }
+                Some((limit, hysteresis)) if over.get() && celsius < limit - hysteresis => {
+                    over.set(false);
+                    self.signal.signal(AlarmEvent::Cleared(celsius));
+                }
+                None if over.get() => {
+                    over.set(false);
+                    self.signal.signal(AlarmEvent::Cleared(celsius));
+                }
+                _ => {}
+            }
+        });
+    }
+}
+
+impl Default for TemperatureAlarm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
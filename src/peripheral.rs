@@ -0,0 +1,139 @@
+//! Owned or borrowed peripherals
+//!
+//! Driver constructors take `impl Peripheral<P = T> + 'd` rather than `T`,
+//! so a peripheral can be handed over for good or lent for a while:
+//!
+//! ```rust,ignore
+//! // Owned: the driver keeps USART0 forever
+//! let uart = Uart::new(p.usart0, tx, rx, config);
+//!
+//! // Borrowed: USART0 and its pins come back once `uart` is dropped
+//! {
+//!     let mut uart = Uart::new(&mut p.usart0, &mut tx, &mut rx, config);
+//!     bootloader_handshake(&mut uart);
+//! }
+//! let tx = tx.into_push_pull_output(Level::High, Speed::Low);
+//! ```
+//!
+//! Inside a driver the peripheral is held as a [`Peri<'d, T>`], which ties the
+//! driver to the lifetime of the borrow; [`Peri::reborrow`] lends it on to a
+//! helper for a shorter time. This is the `PeripheralRef` pattern of the other
+//! embassy HALs.
+//!
+//! The peripheral types are zero-sized singletons, so none of this costs
+//! anything at run time.
+
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+
+/// Zero-sized peripheral singleton
+///
+/// # Safety
+///
+/// Implementors must be zero-sized handles whose only meaning is "owns this
+/// piece of hardware"; [`steal`](Self::steal) hands out another copy.
+pub unsafe trait PeripheralType: Sized {
+    /// Conjure another handle to the same hardware
+    ///
+    /// # Safety
+    ///
+    /// The caller must make sure the copies are not used at the same time.
+    unsafe fn steal() -> Self;
+}
+
+/// A peripheral held by a driver for lifetime `'d`
+pub struct Peri<'d, T> {
+    inner: T,
+    _lifetime: PhantomData<&'d mut T>,
+}
+
+impl<'d, T: PeripheralType> Peri<'d, T> {
+    /// Wrap `inner` without tying it to a borrow
+    ///
+    /// # Safety
+    ///
+    /// The caller must make sure nothing else uses the peripheral during `'d`.
+    pub unsafe fn new_unchecked(inner: T) -> Self {
+        Self {
+            inner,
+            _lifetime: PhantomData,
+        }
+    }
+
+    /// Lend the peripheral for a shorter lifetime
+    pub fn reborrow(&mut self) -> Peri<'_, T> {
+        unsafe { Peri::new_unchecked(T::steal()) }
+    }
+}
+
+impl<T> Deref for Peri<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T> DerefMut for Peri<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+/// Something a driver can take a peripheral from: the peripheral itself,
+/// a `&mut` to it, or a [`Peri`]
+pub trait Peripheral: Sized {
+    /// The peripheral type
+    type P: PeripheralType;
+
+    /// Turn `self` into a [`Peri`] that lives as long as `self`
+    fn into_ref<'d>(self) -> Peri<'d, Self::P>
+    where
+        Self: 'd;
+}
+
+impl<'b, T: PeripheralType> Peripheral for &'b mut T {
+    type P = T;
+
+    fn into_ref<'d>(self) -> Peri<'d, T>
+    where
+        Self: 'd,
+    {
+        unsafe { Peri::new_unchecked(T::steal()) }
+    }
+}
+
+impl<'b, T: PeripheralType> Peripheral for Peri<'b, T> {
+    type P = T;
+
+    fn into_ref<'d>(self) -> Peri<'d, T>
+    where
+        Self: 'd,
+    {
+        unsafe { Peri::new_unchecked(self.inner) }
+    }
+}
+
+/// Implement [`PeripheralType`] and [`Peripheral`] for singletons with a private `()` field
+macro_rules! impl_peripheral {
+    ($($name:ty),* $(,)?) => {
+        $(
+            unsafe impl $crate::peripheral::PeripheralType for $name {
+                unsafe fn steal() -> Self {
+                    Self { _private: () }
+                }
+            }
+
+            impl $crate::peripheral::Peripheral for $name {
+                type P = $name;
+
+                fn into_ref<'d>(self) -> $crate::peripheral::Peri<'d, $name>
+                where
+                    Self: 'd,
+                {
+                    unsafe { $crate::peripheral::Peri::new_unchecked(self) }
+                }
+            }
+        )*
+    };
+}
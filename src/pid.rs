@@ -0,0 +1,222 @@
+//! PID control loop
+//!
+//! [`Pid`] is the controller on its own: fixed sample period, derivative on
+//! the measurement through a first-order low-pass (so setpoint steps do not
+//! kick the output), and anti-windup by clamping the integral so that it
+//! alone never drives the output past its limits.
+//!
+//! [`ThermalLoop`] closes the loop for a heater: it reads an [`Ntc`], runs
+//! the controller and writes the result as a PWM duty cycle, on an
+//! `embassy-time` ticker. The loop records how late each iteration started
+//! ([`LoopStats`]); on the M0+ at 48 MHz one iteration with 16x
+//! oversampling takes well under a millisecond, so periods of 10 ms and up
+//! leave the executor plenty of headroom.
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::pid::{Gains, Pid, Setpoint, ThermalLoop};
+//!
+//! static SETPOINT: Setpoint = Setpoint::new(0.0);
+//!
+//! let period = Duration::from_millis(100);
+//! let pid = Pid::new(Gains { kp: 0.05, ki: 0.002, kd: 0.2 }, period, 0.0, 1.0);
+//! let mut hotplate = ThermalLoop::new(pid, ntc, pwm, Channel::Ch0);
+//! SETPOINT.set(150.0);
+//! hotplate.run(&mut p.adc, &SETPOINT, period).await;
+//! ```
+
+use core::cell::Cell;
+
+use critical_section::Mutex;
+use embassy_time::{Duration, Instant, Ticker};
+
+use crate::adc::Adc;
+use crate::ntc::Ntc;
+use crate::timer::{self, Channel, Pwm};
+
+/// Resolution of the duty cycle written by [`ThermalLoop`]
+const DUTY_STEPS: u16 = 1000;
+
+/// Controller gains, per second for `ki` and in seconds for `kd`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Gains {
+    /// Proportional gain
+    pub kp: f32,
+    /// Integral gain
+    pub ki: f32,
+    /// Derivative gain
+    pub kd: f32,
+}
+
+/// PID controller with a fixed sample period
+#[derive(Debug, Clone)]
+pub struct Pid {
+    gains: Gains,
+    dt: f32,
+    out_min: f32,
+    out_max: f32,
+    /// Derivative low-pass coefficient: 0 = no filtering, towards 1 = heavier
+    derivative_filter: f32,
+    integral: f32,
+    derivative: f32,
+    last_measurement: Option<f32>,
+}
+
+impl Pid {
+    /// Controller sampled every `period` with output limited to `out_min..=out_max`
+    pub fn new(gains: Gains, period: Duration, out_min: f32, out_max: f32) -> Self {
+        Self {
+            gains,
+            dt: period.as_micros() as f32 / 1_000_000.0,
+            out_min,
+            out_max,
+            derivative_filter: 0.8,
+            integral: 0.0,
+            derivative: 0.0,
+            last_measurement: None,
+        }
+    }
+
+    /// Change the gains; the integral is kept, so the output does not jump
+    pub fn set_gains(&mut self, gains: Gains) {
+        self.gains = gains;
+    }
+
+    /// Set the derivative low-pass coefficient (0.0..1.0)
+    pub fn set_derivative_filter(&mut self, alpha: f32) {
+        self.derivative_filter = alpha.clamp(0.0, 0.99);
+    }
+
+    /// Forget the integral and derivative history
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.derivative = 0.0;
+        self.last_measurement = None;
+    }
+
+    /// Run one period: returns the output for `measurement` against `setpoint`
+    pub fn update(&mut self, setpoint: f32, measurement: f32) -> f32 {
+        let error = setpoint - measurement;
+
+        // Derivative of the measurement, not the error, then low-passed
+        let raw = match self.last_measurement {
+            Some(last) => -(measurement - last) / self.dt,
+            None => 0.0,
+        };
+        self.last_measurement = Some(measurement);
+        let alpha = self.derivative_filter;
+        self.derivative = alpha * self.derivative + (1.0 - alpha) * raw;
+
+        // Anti-windup: the integral term alone stays within the output range
+        self.integral += self.gains.ki * error * self.dt;
+        self.integral = self.integral.clamp(self.out_min, self.out_max);
+
+        let output = self.gains.kp * error + self.integral + self.gains.kd * self.derivative;
+        output.clamp(self.out_min, self.out_max)
+    }
+}
+
+/// Setpoint shared with the control loop, usually from a `static`
+pub struct Setpoint(Mutex<Cell<f32>>);
+
+impl Setpoint {
+    /// Usable in a `static`
+    pub const fn new(value: f32) -> Self {
+        Self(Mutex::new(Cell::new(value)))
+    }
+
+    /// Current setpoint
+    pub fn get(&self) -> f32 {
+        critical_section::with(|cs| self.0.borrow(cs).get())
+    }
+
+    /// Change the setpoint; the loop picks it up on its next step
+    pub fn set(&self, value: f32) {
+        critical_section::with(|cs| self.0.borrow(cs).set(value))
+    }
+}
+
+/// Loop timing record
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LoopStats {
+    /// Iterations run
+    pub iterations: u32,
+    /// Latest start after the scheduled tick, in microseconds
+    pub max_lateness_us: u32,
+    /// Iterations that started a full period or more late
+    pub overruns: u32,
+}
+
+/// Thermistor-in, PWM-out heater control
+pub struct ThermalLoop<'d, T: timer::Instance> {
+    pid: Pid,
+    ntc: Ntc,
+    pwm: Pwm<'d, T>,
+    channel: Channel,
+    stats: LoopStats,
+}
+
+impl<'d, T: timer::Instance> ThermalLoop<'d, T> {
+    /// `pwm` must already run at the heater's PWM frequency
+    pub fn new(pid: Pid, ntc: Ntc, mut pwm: Pwm<'d, T>, channel: Channel) -> Self {
+        pwm.set_duty_cycle(channel, 0, DUTY_STEPS);
+        pwm.enable_channel(channel);
+        Self {
+            pid,
+            ntc,
+            pwm,
+            channel,
+            stats: LoopStats::default(),
+        }
+    }
+
+    /// Timing so far
+    pub fn stats(&self) -> LoopStats {
+        self.stats
+    }
+
+    /// Run one control step; the heater is switched off on a sensor fault
+    pub fn step(&mut self, adc: &mut Adc, setpoint: f32) -> f32 {
+        let output = match self.ntc.read_celsius(adc) {
+            Ok(celsius) => self.pid.update(setpoint, celsius),
+            Err(e) => {
+                warn!("pid: sensor fault ({}), heater off", e);
+                self.pid.reset();
+                0.0
+            }
+        };
+
+        let duty = (output.clamp(0.0, 1.0) * DUTY_STEPS as f32) as u16;
+        self.pwm.set_duty_cycle(self.channel, duty, DUTY_STEPS);
+        output
+    }
+
+    /// Step every `period` forever, tracking [`setpoint`](Setpoint)
+    ///
+    /// The PID output is taken as a duty fraction, so construct the
+    /// controller with limits within 0.0..=1.0.
+    pub async fn run(&mut self, adc: &mut Adc, setpoint: &Setpoint, period: Duration) -> ! {
+        let mut ticker = Ticker::every(period);
+        let mut scheduled = Instant::now();
+        loop {
+            let lateness = Instant::now().saturating_duration_since(scheduled);
+            self.stats.iterations = self.stats.iterations.wrapping_add(1);
+            self.stats.max_lateness_us = self.stats.max_lateness_us.max(lateness.as_micros() as u32);
+            if lateness >= period {
+                self.stats.overruns += 1;
+                warn!("pid: iteration {} us late", lateness.as_micros());
+            }
+
+            self.step(adc, setpoint.get());
+
+            ticker.next().await;
+            scheduled += period;
+        }
+    }
+}
+
+impl<T: timer::Instance> Drop for ThermalLoop<'_, T> {
+    fn drop(&mut self) {
+        self.pwm.set_duty_cycle(self.channel, 0, DUTY_STEPS);
+    }
+}
@@ -0,0 +1,274 @@
+//! Software reset, entry into the factory ISP bootloader and USB suspend
+//!
+//! The HT32F523xx boot ROM holds Holtek's ISP bootloader (USB HID and UART,
+//! driven by the HT32 Flash Programmer). Which memory the core boots from is
+//! decided by the FMC vector mapping register `VMCR`, which the BOOT pins load
+//! only on a power-on reset. [`reset_into_isp`] points it at the boot loader
+//! before a system reset, so the ISP comes up without touching the BOOT pins:
+//!
+//! ```rust,ignore
+//! if coding.data_rate() == 1200 && !receiver.dtr() {
+//!     Timer::after_millis(50).await; // let the host finish the control transfer
+//!     power::reset_into_isp();
+//! }
+//! ```
+//!
+//! The ISP runs until the programmer tells it to start the application or the
+//! board is power-cycled, which reloads the mapping from the BOOT pins.
+//!
+//! ## USB suspend
+//! A suspended USB device may draw 2.5 mA from VBUS, which a board running at
+//! 48 MHz with a lit backlight misses by an order of magnitude. With the `usb`
+//! feature, [`run_usb_suspend`] watches the bus and, while it is suspended,
+//! runs the registered [`SuspendHook`]s, puts the transceiver in low-power
+//! mode, gates idle peripheral clocks and runs the core from the 8 MHz HSI
+//! with the PLL and HSE stopped. Resume, bus reset or a remote wake-up undo
+//! every step in reverse:
+//!
+//! ```rust,ignore
+//! fn backlight_off() { /* drive the LED enable low */ }
+//! fn backlight_on() { /* and back */ }
+//!
+//! power::register_suspend_hook(power::pwm_suspend_hook::<timer::Timer1>()).unwrap();
+//! power::register_suspend_hook(SuspendHook { suspend: backlight_off, resume: backlight_on }).unwrap();
+//! spawner.spawn(usb_suspend()).unwrap();
+//!
+//! #[embassy_executor::task]
+//! async fn usb_suspend() {
+//!     power::run_usb_suspend(SuspendConfig::default()).await
+//! }
+//! ```
+//!
+//! Measure the VBUS current with the host asleep to find what else needs a
+//! hook: LEDs, pull-ups into loads and external chips are outside the HAL's
+//! reach. The executor already sleeps with `WFI` between wake-ups.
+
+#[cfg(feature = "usb")]
+use core::cell::RefCell;
+#[cfg(feature = "usb")]
+use core::sync::atomic::{AtomicU32, Ordering};
+
+#[cfg(feature = "usb")]
+use critical_section::Mutex;
+
+use crate::regs::{Mmio, RegisterAccess};
+#[cfg(feature = "usb")]
+use crate::{pac, rcc, timer, usb};
+
+// FMC vector mapping control register, see "Vector Mapping Control Register
+// - VMCR" in the FMC chapter of the HT32F52342/52352 user manual. VMCTL
+// picks what address 0 maps to after a reset: 00 the boot loader, 01 SRAM,
+// 10 (or 11) main flash.
+const FMC_VMCR: usize = 0x4008_0100;
+const VMCR_VMCTL_MASK: u32 = 0b11;
+const VMCTL_BOOT_LOADER: u32 = 0b00;
+const VMCTL_MAIN_FLASH: u32 = 0b10;
+
+/// Reset the chip and boot the application from main flash again
+pub fn software_reset() -> ! {
+    info!("power: software reset");
+    set_boot_mapping(VMCTL_MAIN_FLASH);
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// Reset the chip into the factory ISP bootloader
+///
+/// The USB device detaches with the reset; the ISP enumerates on its own.
+pub fn reset_into_isp() -> ! {
+    info!("power: reset into ISP");
+    set_boot_mapping(VMCTL_BOOT_LOADER);
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+fn set_boot_mapping(vmctl: u32) {
+    Mmio.modify(FMC_VMCR, |v| (v & !VMCR_VMCTL_MASK) | vmctl);
+}
+
+/// Maximum number of registered suspend hooks
+#[cfg(feature = "usb")]
+pub const SUSPEND_HOOKS: usize = 8;
+
+/// Application step run when the USB bus suspends and undone when it resumes
+///
+/// Both functions run in thread mode from [`run_usb_suspend`] and must not block.
+#[cfg(feature = "usb")]
+#[derive(Debug, Copy, Clone)]
+pub struct SuspendHook {
+    pub suspend: fn(),
+    pub resume: fn(),
+}
+
+/// What [`run_usb_suspend`] does besides running the hooks
+#[cfg(feature = "usb")]
+#[derive(Debug, Clone)]
+pub struct SuspendConfig {
+    /// Peripheral clocks to stop; leave out anything that must run while suspended
+    ///
+    /// GPTM0 drives `embassy-time` and is never stopped.
+    pub gate_clocks: &'static [rcc::Peripheral],
+    /// Run from the HSI with the PLL and HSE stopped
+    ///
+    /// Resuming then waits for the PLL to lock before the USB block runs again,
+    /// well inside the 10 ms the host allows.
+    pub hsi_only: bool,
+}
+
+#[cfg(feature = "usb")]
+impl Default for SuspendConfig {
+    fn default() -> Self {
+        Self {
+            gate_clocks: &[
+                rcc::Peripheral::USART0,
+                rcc::Peripheral::USART1,
+                rcc::Peripheral::TIM1,
+                rcc::Peripheral::PDMA,
+            ],
+            hsi_only: true,
+        }
+    }
+}
+
+/// The hook registry already holds [`SUSPEND_HOOKS`] hooks
+#[cfg(feature = "usb")]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HooksFull;
+
+#[cfg(feature = "usb")]
+impl core::fmt::Display for HooksFull {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("suspend hook registry full")
+    }
+}
+
+#[cfg(feature = "usb")]
+impl core::error::Error for HooksFull {}
+
+/// What [`leave_usb_suspend`] has to restore
+#[cfg(feature = "usb")]
+struct Suspended {
+    hooks: [Option<SuspendHook>; SUSPEND_HOOKS],
+    clocks: Option<rcc::SavedClocks>,
+    ahbccr: u32,
+    apbccr0: u32,
+    apbccr1: u32,
+}
+
+#[cfg(feature = "usb")]
+static HOOKS: Mutex<RefCell<[Option<SuspendHook>; SUSPEND_HOOKS]>> = Mutex::new(RefCell::new([None; SUSPEND_HOOKS]));
+#[cfg(feature = "usb")]
+static SUSPENDED: Mutex<RefCell<Option<Suspended>>> = Mutex::new(RefCell::new(None));
+/// Channel enables saved by [`pwm_suspend_hook`], per GPTM
+#[cfg(feature = "usb")]
+static PWM_CHCTR: [AtomicU32; 2] = [AtomicU32::new(0), AtomicU32::new(0)];
+
+/// Register a hook for [`run_usb_suspend`]
+///
+/// Suspend steps run in registration order, resume steps in reverse.
+#[cfg(feature = "usb")]
+pub fn register_suspend_hook(hook: SuspendHook) -> Result<(), HooksFull> {
+    critical_section::with(|cs| {
+        let mut hooks = HOOKS.borrow_ref_mut(cs);
+        let slot = hooks.iter_mut().find(|slot| slot.is_none()).ok_or(HooksFull)?;
+        *slot = Some(hook);
+        Ok(())
+    })
+}
+
+/// Stop timer `T`'s PWM outputs and counter while suspended, e.g. for a backlight
+#[cfg(feature = "usb")]
+pub fn pwm_suspend_hook<T: timer::Instance>() -> SuspendHook {
+    fn index<T: timer::Instance>() -> usize {
+        (!core::ptr::eq(T::regs(), pac::Gptm0::ptr())) as usize
+    }
+    fn suspend<T: timer::Instance>() {
+        let regs = T::regs();
+        PWM_CHCTR[index::<T>()].store(regs.gptm_chctr().read().bits(), Ordering::Relaxed);
+        regs.gptm_chctr().write(|w| unsafe { w.bits(0) });
+        regs.gptm_ctr().modify(|_, w| w.tme().clear_bit());
+    }
+    fn resume<T: timer::Instance>() {
+        let regs = T::regs();
+        regs.gptm_ctr().modify(|_, w| w.tme().set_bit());
+        regs.gptm_chctr().write(|w| unsafe { w.bits(PWM_CHCTR[index::<T>()].load(Ordering::Relaxed)) });
+    }
+    SuspendHook { suspend: suspend::<T>, resume: resume::<T> }
+}
+
+/// Drop to the suspend power budget whenever the host suspends the USB bus
+///
+/// Run it in its own task next to `UsbDevice::run()`.
+#[cfg(feature = "usb")]
+pub async fn run_usb_suspend(config: SuspendConfig) -> ! {
+    loop {
+        wait_for_suspend(true).await;
+        enter_usb_suspend(&config);
+        wait_for_suspend(false).await;
+        leave_usb_suspend();
+    }
+}
+
+#[cfg(feature = "usb")]
+async fn wait_for_suspend(suspended: bool) {
+    core::future::poll_fn(|cx| {
+        usb::SUSPEND_WAKER.register(cx.waker());
+        if usb::is_suspended() == suspended {
+            core::task::Poll::Ready(())
+        } else {
+            core::task::Poll::Pending
+        }
+    })
+    .await
+}
+
+#[cfg(feature = "usb")]
+fn enter_usb_suspend(config: &SuspendConfig) {
+    info!("power: USB suspend");
+    let hooks = critical_section::with(|cs| *HOOKS.borrow_ref(cs));
+    for hook in hooks.iter().flatten() {
+        (hook.suspend)();
+    }
+
+    usb::set_low_power(true);
+
+    let ckcu = unsafe { &*pac::Ckcu::ptr() };
+    let ahbccr = ckcu.ahbccr().read().bits();
+    let apbccr0 = ckcu.apbccr0().read().bits();
+    let apbccr1 = ckcu.apbccr1().read().bits();
+    let rcc = rcc::Rcc::new();
+    for &peripheral in config.gate_clocks {
+        if !matches!(peripheral, rcc::Peripheral::TIM0) {
+            rcc.disable_peripheral(peripheral);
+        }
+    }
+
+    let clocks = config.hsi_only.then(rcc::enter_hsi_only).flatten();
+    critical_section::with(|cs| {
+        *SUSPENDED.borrow_ref_mut(cs) = Some(Suspended { hooks, clocks, ahbccr, apbccr0, apbccr1 });
+    });
+}
+
+/// Undo what the suspend path did; does nothing unless it ran
+///
+/// Called on resume and before a remote wake-up.
+#[cfg(feature = "usb")]
+pub(crate) fn leave_usb_suspend() {
+    let Some(suspended) = critical_section::with(|cs| SUSPENDED.borrow_ref_mut(cs).take()) else {
+        return;
+    };
+
+    if let Some(clocks) = suspended.clocks {
+        rcc::restore_clocks(clocks);
+    }
+    let ckcu = unsafe { &*pac::Ckcu::ptr() };
+    ckcu.ahbccr().write(|w| unsafe { w.bits(suspended.ahbccr) });
+    ckcu.apbccr0().write(|w| unsafe { w.bits(suspended.apbccr0) });
+    ckcu.apbccr1().write(|w| unsafe { w.bits(suspended.apbccr1) });
+
+    usb::set_low_power(false);
+
+    for hook in suspended.hooks.iter().rev().flatten() {
+        (hook.resume)();
+    }
+    info!("power: USB resume");
+}
@@ -0,0 +1,458 @@
+//! PS/2 keyboard and mouse protocol
+//!
+//! [`Ps2Host`] talks to a PS/2 keyboard or mouse (converting old keyboards
+//! to USB): the device drives the clock and every falling edge is handled
+//! from the clock pin's EXTI line. [`Ps2Device`] is the other side (acting as
+//! a PS/2 keyboard or mouse): it generates the ~12.5 kHz clock from BFTM1
+//! interrupts, and while idle checks every millisecond for a host
+//! request-to-send. The BFTM1 handler is registered in its
+//! [`vectors`](crate::vectors) slot while a `Ps2Device` exists, so the
+//! device role needs the `rt` feature.
+//!
+//! Both lines are open-drain with pull-ups. PS/2 runs at 5 V: use 5 V
+//! tolerant pins with external pull-ups to 5 V, or level shifters.
+//!
+//! Only one PS/2 port can be active at a time. Received bytes are queued
+//! ([`QUEUE_LEN`] deep) until read.
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::ps2::Ps2Host;
+//!
+//! let mut kbd = Ps2Host::new(p.gpioa.pa0().degrade(), p.gpioa.pa1().degrade());
+//! kbd.write(0xFF).await?; // reset
+//! loop {
+//!     let scan_code = kbd.read().await?;
+//! }
+//! ```
+
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::task::Poll;
+
+use critical_section::Mutex;
+use embassy_sync::waitqueue::AtomicWaker;
+
+use crate::exti::{self, Edge, ExtiChannel};
+use crate::gpio::AnyPin;
+use crate::timer::{Bftm1, BftmInstance};
+use crate::vectors;
+
+/// Received bytes buffered between reads
+pub const QUEUE_LEN: usize = 16;
+
+/// Quarter of a clock period while a frame is on the wire (12.5 kHz clock)
+const FAST_TICK_HZ: u32 = 50_000;
+/// Idle poll rate for host requests; hosts wait up to 15 ms for the clock
+const SLOW_TICK_HZ: u32 = 1_000;
+
+// BFTMCR bits
+const CR_MIEN: u32 = 1 << 0;
+const CR_CEN: u32 = 1 << 2;
+
+/// PS/2 error
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// A received frame had bad parity
+    Parity,
+    /// A received frame had a bad start or stop bit
+    Framing,
+    /// The other side did not acknowledge a sent byte
+    NoAck,
+    /// Bytes were lost because the receive queue was full
+    Overrun,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Error::Parity => "parity error",
+            Error::Framing => "framing error",
+            Error::NoAck => "not acknowledged",
+            Error::Overrun => "receive queue overrun",
+        })
+    }
+}
+
+impl core::error::Error for Error {}
+
+/// Start bit, data LSB first, odd parity, stop bit
+fn encode(byte: u8) -> u16 {
+    let parity = (byte.count_ones() % 2 == 0) as u16;
+    ((byte as u16) << 1) | (parity << 9) | (1 << 10)
+}
+
+fn decode(frame: u16) -> Result<u8, Error> {
+    let byte = (frame >> 1) as u8;
+    let parity = (frame >> 9) & 1;
+    if frame & 1 != 0 || frame & (1 << 10) == 0 {
+        Err(Error::Framing)
+    } else if (byte.count_ones() + parity as u32) % 2 != 1 {
+        Err(Error::Parity)
+    } else {
+        Ok(byte)
+    }
+}
+
+struct Queue {
+    buf: [u8; QUEUE_LEN],
+    head: usize,
+    len: usize,
+    error: Option<Error>,
+}
+
+impl Queue {
+    const fn new() -> Self {
+        Self {
+            buf: [0; QUEUE_LEN],
+            head: 0,
+            len: 0,
+            error: None,
+        }
+    }
+
+    fn push(&mut self, result: Result<u8, Error>) {
+        match result {
+            Ok(_) if self.len == QUEUE_LEN => self.error = Some(Error::Overrun),
+            Ok(byte) => {
+                self.buf[(self.head + self.len) % QUEUE_LEN] = byte;
+                self.len += 1;
+            }
+            Err(e) => self.error = Some(e),
+        }
+    }
+
+    /// Errors are reported once, ahead of the bytes after them
+    fn pop(&mut self) -> Option<Result<u8, Error>> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % QUEUE_LEN;
+        self.len -= 1;
+        Some(Ok(byte))
+    }
+}
+
+enum Mode {
+    /// Host: receiving on device clock edges; `count` bits so far
+    HostReceive { frame: u16, count: u8 },
+    /// Host: sending `frame`; `count` falling edges seen
+    HostSend { frame: u16, count: u8 },
+    /// Device: nothing on the wire
+    DeviceIdle,
+    /// Device: clocking out `frame`
+    DeviceSend { frame: u16, bit: u8, phase: u8 },
+    /// Device: clocking in a host frame, then the ACK bit
+    DeviceReceive { frame: u16, bit: u8, phase: u8 },
+}
+
+struct Port {
+    clk: AnyPin,
+    data: AnyPin,
+    mode: Mode,
+    rx: Queue,
+    /// Device role: frame waiting for the line to be free
+    tx_pending: Option<u16>,
+    tx_result: Option<Result<(), Error>>,
+}
+
+static PORT: Mutex<RefCell<Option<Port>>> = Mutex::new(RefCell::new(None));
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+fn with_port<R>(f: impl FnOnce(&mut Port) -> R) -> R {
+    critical_section::with(|cs| f(PORT.borrow_ref_mut(cs).as_mut().expect("PS/2 port not open")))
+}
+
+fn open(clk: AnyPin, data: AnyPin, mode: Mode) {
+    critical_section::with(|cs| {
+        let mut port = PORT.borrow_ref_mut(cs);
+        assert!(port.is_none(), "only one PS/2 port can be open");
+        *port = Some(Port {
+            clk,
+            data,
+            mode,
+            rx: Queue::new(),
+            tx_pending: None,
+            tx_result: None,
+        });
+    });
+}
+
+fn close() -> Option<Port> {
+    critical_section::with(|cs| PORT.borrow_ref_mut(cs).take())
+}
+
+async fn read() -> Result<u8, Error> {
+    poll_fn(|cx| {
+        WAKER.register(cx.waker());
+        match with_port(|port| port.rx.pop()) {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
+        }
+    })
+    .await
+}
+
+async fn wait_tx_result() -> Result<(), Error> {
+    poll_fn(|cx| {
+        WAKER.register(cx.waker());
+        match with_port(|port| port.tx_result.take()) {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
+        }
+    })
+    .await
+}
+
+/// Host role: falling edge on the device clock
+fn on_clock_edge() {
+    critical_section::with(|cs| {
+        let mut port = PORT.borrow_ref_mut(cs);
+        let Some(port) = port.as_mut() else { return };
+
+        match &mut port.mode {
+            Mode::HostReceive { frame, count } => {
+                *frame |= (port.data.level() as u16) << *count;
+                *count += 1;
+                if *count == 11 {
+                    port.rx.push(decode(*frame));
+                    *frame = 0;
+                    *count = 0;
+                    WAKER.wake();
+                }
+            }
+            Mode::HostSend { frame, count } => {
+                *count += 1;
+                match *count {
+                    // Data, parity and stop bits change while the clock is low
+                    1..=10 => {
+                        if *frame & (1 << *count) != 0 {
+                            port.data.release();
+                        } else {
+                            port.data.pull_low();
+                        }
+                    }
+                    // The device pulls data low for one clock to acknowledge
+                    _ => {
+                        let ack = !port.data.level();
+                        port.tx_result = Some(if ack { Ok(()) } else { Err(Error::NoAck) });
+                        port.mode = Mode::HostReceive { frame: 0, count: 0 };
+                        WAKER.wake();
+                    }
+                }
+            }
+            _ => {}
+        }
+    });
+}
+
+/// Set the BFTM1 tick rate
+fn set_tick(hz: u32) {
+    let pclk = crate::rcc::get_clocks().apb_clk().to_hz();
+    Bftm1::regs().bftm_cmpr().write(|w| unsafe { w.bits(pclk / hz - 1) });
+}
+
+/// Device role: BFTM1 tick
+pub(crate) fn on_interrupt() {
+    Bftm1::regs().bftm_sr().write(|w| unsafe { w.bits(0) });
+
+    critical_section::with(|cs| {
+        let mut port = PORT.borrow_ref_mut(cs);
+        let Some(port) = port.as_mut() else { return };
+        let Port { clk, data, mode, rx, tx_pending, tx_result } = port;
+
+        match mode {
+            Mode::DeviceIdle => {
+                if !clk.level() {
+                    // Host is inhibiting communication
+                } else if !data.level() {
+                    // Request-to-send: host released the clock with data low
+                    *mode = Mode::DeviceReceive { frame: 0, bit: 1, phase: 0 };
+                    set_tick(FAST_TICK_HZ);
+                } else if let Some(frame) = *tx_pending {
+                    *mode = Mode::DeviceSend { frame, bit: 0, phase: 0 };
+                    set_tick(FAST_TICK_HZ);
+                }
+            }
+            Mode::DeviceSend { frame, bit, phase } => {
+                match *phase {
+                    0 => {
+                        // The host may pull the clock low to abort; retry once it lets go
+                        if !clk.level() {
+                            data.release();
+                            *mode = Mode::DeviceIdle;
+                            set_tick(SLOW_TICK_HZ);
+                            return;
+                        }
+                        if *frame & (1 << *bit) != 0 {
+                            data.release();
+                        } else {
+                            data.pull_low();
+                        }
+                    }
+                    1 => clk.pull_low(),
+                    2 => {}
+                    _ => {
+                        clk.release();
+                        *bit += 1;
+                        if *bit == 11 {
+                            *tx_pending = None;
+                            *tx_result = Some(Ok(()));
+                            *mode = Mode::DeviceIdle;
+                            set_tick(SLOW_TICK_HZ);
+                            WAKER.wake();
+                            return;
+                        }
+                    }
+                }
+                *phase = (*phase + 1) % 4;
+            }
+            Mode::DeviceReceive { frame, bit, phase } => {
+                match *phase {
+                    0 => {
+                        // Bit 11 is the ACK: hold data low across its clock
+                        if *bit == 11 {
+                            data.pull_low();
+                        }
+                        clk.pull_low();
+                    }
+                    1 => {}
+                    2 => clk.release(),
+                    _ => {
+                        if *bit < 11 {
+                            *frame |= (data.level() as u16) << *bit;
+                        }
+                        *bit += 1;
+
+                        // No ACK for a frame without a stop bit
+                        let done = *bit == 12 || (*bit == 11 && *frame & (1 << 10) == 0);
+                        if done {
+                            data.release();
+                            rx.push(decode(*frame));
+                            *mode = Mode::DeviceIdle;
+                            set_tick(SLOW_TICK_HZ);
+                            WAKER.wake();
+                            return;
+                        }
+                    }
+                }
+                *phase = (*phase + 1) % 4;
+            }
+            _ => {}
+        }
+    });
+}
+
+/// PS/2 host: reads a keyboard or mouse
+pub struct Ps2Host {
+    line: ExtiChannel,
+}
+
+impl Ps2Host {
+    /// Open the port; the clock pin's EXTI line must be free
+    pub fn new(mut clk: AnyPin, mut data: AnyPin) -> Self {
+        clk.set_as_open_drain();
+        data.set_as_open_drain();
+
+        let line = ExtiChannel::new(clk.pin()).unwrap();
+        exti::configure_exti_source(clk.pin(), clk.port());
+        open(clk, data, Mode::HostReceive { frame: 0, count: 0 });
+        exti::set_line_handler(line.line(), Some(on_clock_edge));
+        line.enable_interrupt(Edge::Falling);
+
+        Self { line }
+    }
+
+    /// Next byte from the device
+    pub async fn read(&mut self) -> Result<u8, Error> {
+        read().await
+    }
+
+    /// Send a command byte and wait for the device's acknowledge bit
+    ///
+    /// Replies to the command (0xFA and any data) arrive through [`read`](Self::read).
+    pub async fn write(&mut self, byte: u8) -> Result<(), Error> {
+        // Inhibit for at least 100 us, then request-to-send
+        self.line.disable_interrupt();
+        with_port(|port| {
+            port.tx_result = None;
+            port.mode = Mode::HostSend { frame: encode(byte), count: 0 };
+            port.clk.pull_low();
+        });
+        crate::cortex_delay::delay_us(125);
+        with_port(|port| {
+            port.data.pull_low();
+            port.clk.release();
+        });
+        self.line.enable_interrupt(Edge::Falling);
+
+        wait_tx_result().await
+    }
+}
+
+impl Drop for Ps2Host {
+    fn drop(&mut self) {
+        self.line.disable_interrupt();
+        exti::set_line_handler(self.line.line(), None);
+        if let Some(port) = close() {
+            port.data.release();
+            port.clk.release();
+        }
+    }
+}
+
+/// PS/2 device: acts as a keyboard or mouse towards a host
+pub struct Ps2Device {
+    _bftm: Bftm1,
+}
+
+impl Ps2Device {
+    /// Open the port; BFTM1 and its interrupt are taken over
+    pub fn new(bftm: Bftm1, mut clk: AnyPin, mut data: AnyPin) -> Self {
+        clk.set_as_open_drain();
+        data.set_as_open_drain();
+        open(clk, data, Mode::DeviceIdle);
+
+        Bftm1::enable_clock();
+        let regs = Bftm1::regs();
+        regs.bftm_cr().write(|w| unsafe { w.bits(0) });
+        regs.bftm_cntr().write(|w| unsafe { w.bits(0) });
+        regs.bftm_sr().write(|w| unsafe { w.bits(0) });
+        set_tick(SLOW_TICK_HZ);
+        regs.bftm_cr().write(|w| unsafe { w.bits(CR_MIEN | CR_CEN) });
+        vectors::set_handler::<vectors::Bftm1>(Some(on_interrupt));
+
+        Self { _bftm: bftm }
+    }
+
+    /// Next command byte from the host
+    pub async fn read(&mut self) -> Result<u8, Error> {
+        read().await
+    }
+
+    /// Send a byte once the host allows it
+    ///
+    /// A frame the host aborts by inhibiting is sent again afterwards.
+    pub async fn write(&mut self, byte: u8) -> Result<(), Error> {
+        with_port(|port| {
+            port.tx_result = None;
+            port.tx_pending = Some(encode(byte));
+        });
+        wait_tx_result().await
+    }
+}
+
+impl Drop for Ps2Device {
+    fn drop(&mut self) {
+        vectors::set_handler::<vectors::Bftm1>(None);
+        Bftm1::regs().bftm_cr().write(|w| unsafe { w.bits(0) });
+        if let Some(port) = close() {
+            port.data.release();
+            port.clk.release();
+        }
+    }
+}
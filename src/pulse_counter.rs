@@ -0,0 +1,130 @@
+//! Pulse counter on a GPTM ETR input
+//!
+//! Counts edges on the timer's ETR pin (external clock mode 2) and extends
+//! the 16-bit counter with the overflow interrupt, for tachometers, flow
+//! sensors and energy-meter pulse outputs.
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::pulse_counter::PulseCounter;
+//! use embassy_ht32f523xx::timer::EtrConfig;
+//!
+//! let etr = p.gpioa.pa10().into_alternate_function::<4>();
+//! let mut counter = PulseCounter::new(p.timer1, etr, EtrConfig::default());
+//! counter.wait_count(1_000).await;
+//! ```
+
+use core::future::poll_fn;
+use core::sync::atomic::Ordering;
+use core::task::Poll;
+
+use crate::peripheral::Peripheral;
+use crate::timer::{self, ClockSource, EtrConfig, Instance, SlaveMode, Timer, TimerInputPin, TriggerInput};
+
+/// Edge counter with a 48-bit range, reported as `u64`
+///
+/// The hardware counter is 16 bits wide and the interrupt handler counts its
+/// overflows in 32 bits, so the count wraps after 2^48 pulses.
+pub struct PulseCounter<'d, T: Instance> {
+    timer: Timer<'d, T>,
+}
+
+impl<'d, T: Instance> PulseCounter<'d, T> {
+    /// Start counting edges on `etr`, configured for AF4
+    ///
+    /// The timer's interrupt handler must be installed (`rt` feature); GPTM0
+    /// is only available without `time-driver`.
+    pub fn new<P: TimerInputPin<T>>(
+        timer: impl Peripheral<P = T> + 'd,
+        etr: impl Peripheral<P = P> + 'd,
+        config: EtrConfig,
+    ) -> Self {
+        T::enable_clock();
+
+        let mut timer = Timer::new(timer).with_input_pin(etr);
+        timer.set_prescaler(0);
+        timer.set_period(u16::MAX);
+        timer.set_clock_source(ClockSource::Etr(config));
+
+        let regs = T::regs();
+        critical_section::with(|_| {
+            timer.reset_counter();
+            T::overflows().store(0, Ordering::Relaxed);
+            regs.gptm_intsr().write(|w| unsafe { w.bits(0) });
+        });
+        regs.gptm_dictr().modify(|r, w| unsafe { w.bits(r.bits() | timer::INT_UEV) });
+
+        timer.start();
+
+        Self { timer }
+    }
+
+    /// Count only while `gate` is high, e.g. a measurement window from another input
+    pub fn set_gate(&mut self, gate: Option<TriggerInput>) {
+        match gate {
+            Some(input) => self.timer.set_slave_mode(SlaveMode::Gated, input),
+            None => self.timer.set_slave_mode(SlaveMode::Disabled, TriggerInput::Ch0),
+        }
+    }
+
+    /// Pulses counted so far
+    pub fn count(&self) -> u64 {
+        let regs = T::regs();
+        critical_section::with(|_| {
+            let mut high = T::overflows().load(Ordering::Acquire);
+            let low = regs.gptm_cntr().read().bits() & 0xFFFF;
+            // An overflow the handler has not seen yet belongs to a low reading
+            if regs.gptm_intsr().read().bits() & timer::INT_UEV != 0 && low < 0x8000 {
+                high = high.wrapping_add(1);
+            }
+            ((high as u64) << 16) | low as u64
+        })
+    }
+
+    /// Restart the count from zero
+    pub fn reset(&mut self) {
+        let regs = T::regs();
+        critical_section::with(|_| {
+            self.timer.reset_counter();
+            T::overflows().store(0, Ordering::Relaxed);
+            regs.gptm_intsr().write(|w| unsafe { w.bits(!timer::INT_UEV) });
+        });
+    }
+
+    /// Wait until the count reaches `target`
+    pub async fn wait_count(&mut self, target: u64) {
+        let regs = T::regs();
+
+        poll_fn(|cx| {
+            T::waker().register(cx.waker());
+
+            if self.count() >= target {
+                regs.gptm_dictr().modify(|r, w| unsafe { w.bits(r.bits() & !timer::INT_CH3CC) });
+                return Poll::Ready(());
+            }
+
+            // Within the current 16-bit epoch a CH3 compare wakes us at the
+            // exact pulse; otherwise the next overflow does
+            if (target >> 16) as u32 == T::overflows().load(Ordering::Acquire) {
+                regs.gptm_ch3ccr().write(|w| unsafe { w.bits((target & 0xFFFF) as u32) });
+                regs.gptm_intsr().write(|w| unsafe { w.bits(!timer::INT_CH3CC) });
+                regs.gptm_dictr().modify(|r, w| unsafe { w.bits(r.bits() | timer::INT_CH3CC) });
+
+                // The count may have passed the compare value while arming
+                if self.count() >= target {
+                    cx.waker().wake_by_ref();
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Stop counting and release the timer
+    pub fn stop(mut self) -> Timer<'d, T> {
+        let regs = T::regs();
+        self.timer.stop();
+        regs.gptm_dictr().modify(|r, w| unsafe { w.bits(r.bits() & !(timer::INT_UEV | timer::INT_CH3CC)) });
+        self.timer.set_clock_source(ClockSource::Internal);
+        self.timer
+    }
+}
@@ -0,0 +1,210 @@
+//! PWM audio output
+//!
+//! [`PwmAudio`] turns a GPTM channel into an 8-bit DAC. The timer period is
+//! one sample, and its update interrupt loads the next sample from a ring
+//! buffer into the compare register, so playback is paced by the timer
+//! alone and tasks only have to keep the ring topped up:
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::pwm_audio::PwmAudio;
+//!
+//! let pin = p.gpioa.pa4().into_alternate_function::<4>();
+//! let mut audio = PwmAudio::new(p.timer1, pin, Channel::Ch0, Hertz::khz(48));
+//! audio.write(&samples);
+//! ```
+//!
+//! Samples are unsigned, 128 being silence. At 48 kHz the carrier is the
+//! sample rate itself, above hearing but not above what a class-D amplifier
+//! or a speaker coil passes, so put an RC low-pass (e.g. 2.2 kΩ and 3.3 nF,
+//! about 22 kHz) between the pin and the amplifier. With PCLK at 48 MHz a
+//! period is 1000 counts, plenty for 8 bits.
+//!
+//! [`PwmAudio::samples_played`] counts timer periods, which makes it the
+//! sample clock a USB audio feedback endpoint reports. Only one `PwmAudio`
+//! can run at a time.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, AtomicUsize, Ordering};
+
+use crate::peripheral::{Peri, Peripheral};
+use crate::time::Hertz;
+use crate::timer::{self, Channel, Instance, TimerOutputPin};
+
+/// Ring buffer size in samples; 10.7 ms at 48 kHz
+pub const RING_LEN: usize = 512;
+
+/// Mid-scale output
+pub const SILENCE: u8 = 128;
+
+// CHnOCFR output modes
+const OM_FORCE_INACTIVE: u32 = 0b100;
+const OM_PWM1: u32 = 0b110;
+const OCFR_OM_MASK: u32 = 0b111;
+
+static RING: [AtomicU8; RING_LEN] = [const { AtomicU8::new(SILENCE) }; RING_LEN];
+/// Next slot `write` fills; only the writer stores it
+static HEAD: AtomicUsize = AtomicUsize::new(0);
+/// Next slot the interrupt plays; only the interrupt stores it
+static TAIL: AtomicUsize = AtomicUsize::new(0);
+static PLAYED: AtomicU32 = AtomicU32::new(0);
+static UNDERRUNS: AtomicU32 = AtomicU32::new(0);
+static STARVED: AtomicBool = AtomicBool::new(true);
+/// Timer period in counts
+static PERIOD: AtomicU32 = AtomicU32::new(0);
+/// Output channel, as 0..=3
+static CHANNEL: AtomicU8 = AtomicU8::new(0);
+
+fn channel_index(channel: Channel) -> u8 {
+    match channel {
+        Channel::Ch0 => 0,
+        Channel::Ch1 => 1,
+        Channel::Ch2 => 2,
+        Channel::Ch3 => 3,
+    }
+}
+
+fn set_compare<T: Instance>(channel: u8, value: u32) {
+    let regs = T::regs();
+    match channel {
+        0 => regs.gptm_ch0ccr().write(|w| unsafe { w.bits(value) }),
+        1 => regs.gptm_ch1ccr().write(|w| unsafe { w.bits(value) }),
+        2 => regs.gptm_ch2ccr().write(|w| unsafe { w.bits(value) }),
+        _ => regs.gptm_ch3ccr().write(|w| unsafe { w.bits(value) }),
+    }
+}
+
+fn set_output_mode<T: Instance>(channel: Channel, mode: u32) {
+    let regs = T::regs();
+    let set = |r: u32| (r & !OCFR_OM_MASK) | mode;
+    match channel {
+        Channel::Ch0 => regs.gptm_ch0ocfr().modify(|r, w| unsafe { w.bits(set(r.bits())) }),
+        Channel::Ch1 => regs.gptm_ch1ocfr().modify(|r, w| unsafe { w.bits(set(r.bits())) }),
+        Channel::Ch2 => regs.gptm_ch2ocfr().modify(|r, w| unsafe { w.bits(set(r.bits())) }),
+        Channel::Ch3 => regs.gptm_ch3ocfr().modify(|r, w| unsafe { w.bits(set(r.bits())) }),
+    }
+}
+
+/// Update interrupt: one sample per timer period
+fn on_interrupt<T: Instance>() {
+    let regs = T::regs();
+    // INTSR flags are cleared by writing 0
+    regs.gptm_intsr().write(|w| unsafe { w.bits(!timer::INT_UEV) });
+
+    let tail = TAIL.load(Ordering::Relaxed);
+    let sample = if tail != HEAD.load(Ordering::Acquire) {
+        let sample = RING[tail].load(Ordering::Relaxed);
+        TAIL.store((tail + 1) % RING_LEN, Ordering::Release);
+        STARVED.store(false, Ordering::Relaxed);
+        sample
+    } else {
+        if !STARVED.load(Ordering::Relaxed) {
+            STARVED.store(true, Ordering::Relaxed);
+            UNDERRUNS.store(UNDERRUNS.load(Ordering::Relaxed).wrapping_add(1), Ordering::Relaxed);
+        }
+        SILENCE
+    };
+    // The M0+ has no atomic read-modify-write; only this handler writes these
+    PLAYED.store(PLAYED.load(Ordering::Relaxed).wrapping_add(1), Ordering::Release);
+
+    let duty = (sample as u32 * PERIOD.load(Ordering::Relaxed)) >> 8;
+    set_compare::<T>(CHANNEL.load(Ordering::Relaxed), duty);
+}
+
+/// 8-bit PWM DAC on one GPTM channel
+pub struct PwmAudio<'d, T: Instance> {
+    _timer: Peri<'d, T>,
+    channel: Channel,
+}
+
+impl<'d, T: Instance> PwmAudio<'d, T> {
+    /// Start outputting silence at `sample_rate`; `pin` is the output of `channel` (AF4)
+    ///
+    /// The timer's interrupt handler must be installed (`rt` feature).
+    pub fn new<P: TimerOutputPin<T>>(
+        timer: impl Peripheral<P = T> + 'd,
+        _pin: impl Peripheral<P = P> + 'd,
+        channel: Channel,
+        sample_rate: Hertz,
+    ) -> Self {
+        T::enable_clock();
+        let regs = T::regs();
+        let mut peri = timer.into_ref();
+        let mut timer = timer::Timer::new(peri.reborrow());
+        timer.set_prescaler(0);
+
+        let pclk = crate::rcc::get_clocks().apb_clk().to_hz();
+        let period = pclk / sample_rate.to_hz();
+        assert!((256..=u16::MAX as u32).contains(&period), "sample rate out of range for 8-bit PWM");
+        regs.gptm_crr().write(|w| unsafe { w.bits(period - 1) });
+
+        HEAD.store(0, Ordering::Relaxed);
+        TAIL.store(0, Ordering::Relaxed);
+        PLAYED.store(0, Ordering::Relaxed);
+        UNDERRUNS.store(0, Ordering::Relaxed);
+        STARVED.store(true, Ordering::Relaxed);
+        PERIOD.store(period, Ordering::Relaxed);
+        CHANNEL.store(channel_index(channel), Ordering::Relaxed);
+        set_compare::<T>(channel_index(channel), (SILENCE as u32 * period) >> 8);
+
+        set_output_mode::<T>(channel, OM_PWM1);
+        match channel {
+            Channel::Ch0 => regs.gptm_chctr().modify(|_, w| w.ch0e().set_bit()),
+            Channel::Ch1 => regs.gptm_chctr().modify(|_, w| w.ch1e().set_bit()),
+            Channel::Ch2 => regs.gptm_chctr().modify(|_, w| w.ch2e().set_bit()),
+            Channel::Ch3 => regs.gptm_chctr().modify(|_, w| w.ch3e().set_bit()),
+        }
+
+        timer::set_handler::<T>(Some(on_interrupt::<T>));
+        regs.gptm_cntr().reset();
+        regs.gptm_intsr().write(|w| unsafe { w.bits(0) });
+        regs.gptm_dictr().write(|w| unsafe { w.bits(timer::INT_UEV) });
+        regs.gptm_ctr().modify(|_, w| w.tme().set_bit());
+        debug!("pwm_audio: {} Hz, {} counts per sample", sample_rate.to_hz(), period);
+
+        Self { _timer: peri, channel }
+    }
+
+    /// Queue as many of `samples` as fit and return how many that was
+    pub fn write(&mut self, samples: &[u8]) -> usize {
+        let mut head = HEAD.load(Ordering::Relaxed);
+        let free = RING_LEN - 1 - self.buffered();
+        for &sample in &samples[..samples.len().min(free)] {
+            RING[head].store(sample, Ordering::Relaxed);
+            head = (head + 1) % RING_LEN;
+        }
+        HEAD.store(head, Ordering::Release);
+        samples.len().min(free)
+    }
+
+    /// Samples queued and not yet played
+    pub fn buffered(&self) -> usize {
+        let head = HEAD.load(Ordering::Relaxed);
+        let tail = TAIL.load(Ordering::Acquire);
+        (head + RING_LEN - tail) % RING_LEN
+    }
+
+    /// Drop the queued samples; the output falls to silence
+    pub fn clear(&mut self) {
+        // Not between the interrupt reading the tail and moving it
+        critical_section::with(|_| HEAD.store(TAIL.load(Ordering::Acquire), Ordering::Release));
+    }
+
+    /// Timer periods since [`new`](Self::new), silent ones included (wrapping)
+    pub fn samples_played(&self) -> u32 {
+        PLAYED.load(Ordering::Acquire)
+    }
+
+    /// Times the ring ran dry while playing
+    pub fn underruns(&self) -> u32 {
+        UNDERRUNS.load(Ordering::Relaxed)
+    }
+}
+
+impl<T: Instance> Drop for PwmAudio<'_, T> {
+    fn drop(&mut self) {
+        let regs = T::regs();
+        regs.gptm_dictr().write(|w| unsafe { w.bits(0) });
+        regs.gptm_ctr().modify(|_, w| w.tme().clear_bit());
+        set_output_mode::<T>(self.channel, OM_FORCE_INACTIVE);
+        timer::set_handler::<T>(None);
+    }
+}
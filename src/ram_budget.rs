@@ -0,0 +1,45 @@
+//! Compile-time RAM budget
+//!
+//! The HT32F52342 has half the RAM of the HT32F52352, and nothing stops a
+//! firmware that fits the larger part from building for the smaller one:
+//! the linker only sees `.data` and `.bss`, and the stack grows down into
+//! them at run time. [`assert_ram_budget!`](crate::assert_ram_budget!) adds
+//! up what the application knows it needs and fails the build when that
+//! exceeds [`RAM_SIZE`](crate::RAM_SIZE) for the selected chip:
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::assert_ram_budget;
+//!
+//! const USB_BUFFERS: usize = 256 + 256 + 64; // config and BOS descriptors, control buffer
+//! const KEYMAP: usize = 4 * 5 * 14 * 2; // layers * rows * cols * KeyAction
+//! const HEAP: usize = 2 * 1024;
+//!
+//! assert_ram_budget!(USB_BUFFERS + KEYMAP + HEAP, 2 * 1024);
+//! ```
+//!
+//! The HAL's own statics are not counted, and neither is anything the
+//! application leaves out of the sum, so keep some slack and compare with
+//! `cargo size` from time to time. Build CI for both chip features so the
+//! check runs against 8 KB as well as 16 KB. The `stack-guard` feature
+//! shows at run time whether the stack figure was right.
+
+/// Compile-time check used by [`assert_ram_budget!`](crate::assert_ram_budget!)
+#[doc(hidden)]
+pub const fn check(static_bytes: usize, stack_bytes: usize) {
+    assert!(stack_bytes > 0, "stack budget must not be zero");
+    assert!(
+        static_bytes <= crate::RAM_SIZE && stack_bytes <= crate::RAM_SIZE - static_bytes,
+        "statics and stack exceed the RAM of the selected chip"
+    );
+}
+
+/// Fail the build if `$static_bytes` of statics and `$stack_bytes` of stack
+/// do not fit in [`RAM_SIZE`](crate::RAM_SIZE)
+///
+/// Both must be constant expressions. Usable at item level or in a function.
+#[macro_export]
+macro_rules! assert_ram_budget {
+    ($static_bytes:expr, $stack_bytes:expr $(,)?) => {
+        const _: () = $crate::ram_budget::check($static_bytes, $stack_bytes);
+    };
+}
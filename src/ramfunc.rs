@@ -0,0 +1,71 @@
+//! Functions that run from RAM
+//!
+//! The FMC stalls every flash fetch while it programs or erases, so code
+//! that must keep running meanwhile, or that rewrites the flash it would be
+//! running from (a bootloader updating itself), has to execute from RAM.
+//! [`ramfunc!`](crate::ramfunc!) puts functions in the `.ramfunc` section:
+//!
+//! ```rust,ignore
+//! embassy_ht32f523xx::ramfunc! {
+//!     /// Copy the staged image over the application, then reset
+//!     fn install(staged: u32, len: u32) -> ! {
+//!         // Only RAM code and inlined register accesses from here on
+//!     }
+//! }
+//! ```
+//!
+//! The section comes from `memory_ht32f52352.x` / `memory_ht32f52342.x`: it
+//! is linked to run in RAM and loaded into flash after `.data`, and
+//! [`init`](crate::init) copies it to RAM before anything else, so RAM
+//! functions can be called once `init` has returned.
+//!
+//! Everything such a function calls must be in RAM too or inlined; a call
+//! into flash stalls until the flash operation finishes, and jumps into
+//! flash that is being erased fault. Panics, `defmt` and formatting all
+//! live in flash. Code in RAM also runs without flash wait states, which
+//! makes it useful for tight timing loops.
+
+/// Place functions in RAM
+///
+/// Each function gets `#[link_section = ".ramfunc"]` and `#[inline(never)]`,
+/// so it keeps its own copy in RAM instead of being inlined into flash code.
+/// The `ramfunc` feature must be enabled for [`init`](crate::init) to copy
+/// the section.
+#[macro_export]
+macro_rules! ramfunc {
+    ($($item:item)*) => {
+        $(
+            #[unsafe(link_section = ".ramfunc")]
+            #[inline(never)]
+            $item
+        )*
+    };
+}
+
+unsafe extern "C" {
+    // From the `.ramfunc` section in memory.x
+    static mut __sramfunc: u32;
+    static mut __eramfunc: u32;
+    static __siramfunc: u32;
+}
+
+/// Copy the `.ramfunc` section from its load address in flash to RAM
+///
+/// # Safety
+///
+/// Must run once, before any RAM function is called.
+pub(crate) unsafe fn init() {
+    unsafe {
+        let mut dst = &raw mut __sramfunc;
+        let end = &raw mut __eramfunc;
+        let mut src = &raw const __siramfunc;
+        while dst < end {
+            dst.write_volatile(src.read_volatile());
+            dst = dst.add(1);
+            src = src.add(1);
+        }
+    }
+    // Make sure the copy is done before any instruction is fetched from it
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
@@ -0,0 +1,178 @@
+//! Raw HID transport (VIA / Vial compatible)
+//!
+//! [`RawHid`] adds a vendor HID interface with the usage page and report
+//! format VIA and Vial look for (0xFF60 / 0x61, 32-byte reports) and moves
+//! reports between its endpoints and a pair of `embassy-sync` channels. The
+//! VIA command set itself stays in the application; it only sees
+//! [`Report`]s through [`RawHidChannels`].
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::raw_hid::{RawHid, RawHidChannels, State};
+//!
+//! static CHANNELS: RawHidChannels<4> = RawHidChannels::new();
+//! static STATE: StaticCell<State> = StaticCell::new();
+//!
+//! let mut raw_hid = RawHid::new(&mut builder, STATE.init(State::new()));
+//! let mut usb = builder.build();
+//!
+//! let via = async {
+//!     loop {
+//!         let mut report = CHANNELS.receive().await;
+//!         handle_via_command(&mut report);
+//!         CHANNELS.send(report).await;
+//!     }
+//! };
+//! join3(usb.run(), raw_hid.run(&CHANNELS), via).await;
+//! ```
+
+use embassy_futures::join::join;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_usb::class::hid::{self, HidReader, HidReaderWriter, HidWriter};
+use embassy_usb::Builder;
+
+use crate::usb::Driver;
+
+pub use embassy_usb::class::hid::State;
+
+/// Report size in both directions
+pub const REPORT_SIZE: usize = 32;
+
+/// One raw HID report
+pub type Report = [u8; REPORT_SIZE];
+
+/// Endpoints [`RawHid`] allocates, for [`crate::usb::assert_endpoint_budget`]
+pub const ENDPOINT_BUDGET: crate::usb::EndpointBudget =
+    crate::usb::EndpointBudget::hid(REPORT_SIZE as u16, Some(REPORT_SIZE as u16));
+
+/// Report descriptor: vendor page 0xFF60, usage 0x61, 32-byte in/out reports
+const REPORT_DESCRIPTOR: &[u8] = &vendor_descriptor(0xFF60, 0x61);
+
+/// Report descriptor of a vendor interface with 32-byte in/out reports
+pub(crate) const fn vendor_descriptor(usage_page: u16, usage: u8) -> [u8; 34] {
+    let [page_lo, page_hi] = usage_page.to_le_bytes();
+    [
+        0x06, page_lo, page_hi, // Usage Page (Vendor)
+        0x09, usage, //      Usage
+        0xA1, 0x01, //       Collection (Application)
+        0x09, 0x62, //         Usage (0x62)
+        0x15, 0x00, //         Logical Minimum (0)
+        0x26, 0xFF, 0x00, //   Logical Maximum (255)
+        0x95, REPORT_SIZE as u8, // Report Count
+        0x75, 0x08, //         Report Size (8)
+        0x81, 0x02, //         Input (Data, Var, Abs)
+        0x09, 0x63, //         Usage (0x63)
+        0x15, 0x00, //         Logical Minimum (0)
+        0x26, 0xFF, 0x00, //   Logical Maximum (255)
+        0x95, REPORT_SIZE as u8, // Report Count
+        0x75, 0x08, //         Report Size (8)
+        0x91, 0x02, //         Output (Data, Var, Abs)
+        0xC0, //             End Collection
+    ]
+}
+
+/// HID class configuration for a vendor interface described by `report_descriptor`
+pub(crate) fn hid_config(report_descriptor: &'static [u8]) -> hid::Config<'static> {
+    hid::Config {
+        report_descriptor,
+        request_handler: None,
+        poll_ms: 1,
+        max_packet_size: REPORT_SIZE as u16,
+    }
+}
+
+/// Report queues between [`RawHid::run`] and the application, `N` deep each way
+pub struct RawHidChannels<const N: usize> {
+    from_host: Channel<CriticalSectionRawMutex, Report, N>,
+    to_host: Channel<CriticalSectionRawMutex, Report, N>,
+}
+
+impl<const N: usize> RawHidChannels<N> {
+    /// Create empty queues; usable in a `static`
+    pub const fn new() -> Self {
+        Self {
+            from_host: Channel::new(),
+            to_host: Channel::new(),
+        }
+    }
+
+    /// Wait for the next report from the host
+    pub async fn receive(&self) -> Report {
+        self.from_host.receive().await
+    }
+
+    /// Take a report from the host if one is queued
+    pub fn try_receive(&self) -> Option<Report> {
+        self.from_host.try_receive().ok()
+    }
+
+    /// Queue a report for the host, waiting for space
+    pub async fn send(&self, report: Report) {
+        self.to_host.send(report).await
+    }
+
+    /// Queue a report for the host, handing it back if the queue is full
+    pub fn try_send(&self, report: Report) -> Result<(), Report> {
+        self.to_host.try_send(report).map_err(|e| match e {
+            embassy_sync::channel::TrySendError::Full(report) => report,
+        })
+    }
+}
+
+impl<const N: usize> Default for RawHidChannels<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Raw HID interface
+pub struct RawHid<'d> {
+    reader: HidReader<'d, Driver<'d>, REPORT_SIZE>,
+    writer: HidWriter<'d, Driver<'d>, REPORT_SIZE>,
+}
+
+impl<'d> RawHid<'d> {
+    /// Add the interface and its interrupt IN/OUT endpoints to `builder`
+    pub fn new(builder: &mut Builder<'d, Driver<'d>>, state: &'d mut State<'d>) -> Self {
+        let (reader, writer) = HidReaderWriter::<_, REPORT_SIZE, REPORT_SIZE>::new(builder, state, hid_config(REPORT_DESCRIPTOR)).split();
+        Self { reader, writer }
+    }
+
+    /// Move reports between the endpoints and `channels`
+    ///
+    /// Reports from the host are dropped while the application's queue is
+    /// full, as a host would see on a missed interrupt transfer. Reports for
+    /// the host wait until the device is configured.
+    pub async fn run<const N: usize>(&mut self, channels: &RawHidChannels<N>) -> ! {
+        let Self { reader, writer } = self;
+
+        let host_to_device = async {
+            loop {
+                reader.ready().await;
+                let mut report = [0u8; REPORT_SIZE];
+                match reader.read(&mut report).await {
+                    Ok(REPORT_SIZE) => {
+                        if channels.from_host.try_send(report).is_err() {
+                            hot_warn!("raw_hid: receive queue full, report dropped");
+                        }
+                    }
+                    Ok(n) => hot_debug!("raw_hid: short report ({} bytes)", n),
+                    Err(_) => {}
+                }
+            }
+        };
+
+        let device_to_host = async {
+            loop {
+                let report = channels.to_host.receive().await;
+                writer.ready().await;
+                if writer.write(&report).await.is_err() {
+                    hot_debug!("raw_hid: report lost, bus reset or disabled");
+                }
+            }
+        };
+
+        join(host_to_device, device_to_host).await;
+        unreachable!()
+    }
+}
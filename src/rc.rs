@@ -0,0 +1,283 @@
+//! RC receiver protocols: SBUS and IBUS
+//!
+//! Both arrive as one UART frame every few milliseconds, separated by idle
+//! time, so [`RcReceiver`] reads whole frames with
+//! [`Uart::read_until_idle`] and decodes them into a channel array:
+//!
+//! | Protocol | Line                    | Frame    | Channels            |
+//! |----------|-------------------------|----------|---------------------|
+//! | SBUS     | 100 kbaud 8E2, inverted | 25 bytes | 16 × 11 bit + flags |
+//! | IBUS     | 115.2 kbaud 8N1         | 32 bytes | 14 × 16 bit, µs     |
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::rc::{Protocol, RcReceiver};
+//!
+//! let uart = Uart::new(p.usart1, tx, rx, Protocol::Sbus.uart_config());
+//! let mut rx = RcReceiver::new(uart, Protocol::Sbus);
+//! loop {
+//!     match embassy_time::with_timeout(Duration::from_millis(100), rx.receive()).await {
+//!         Ok(Ok(frame)) if !frame.failsafe => mixer.update(frame.channels_us()),
+//!         _ => mixer.failsafe(),
+//!     }
+//! }
+//! ```
+//!
+//! The USART cannot invert its input, and standard SBUS idles low. Put an
+//! NPN inverter in front of RX, or use a receiver's "uninverted SBUS" pad.
+//! Without one nearly every byte is a framing error; after a run of them
+//! [`RcReceiver::receive`] flags this with [`Error::Inverted`] instead of
+//! failing silently. IBUS has no failsafe flag; a receiver in failsafe
+//! either stops sending or sends the failsafe positions, so time out on
+//! missing frames as above.
+
+use embassy_time::Duration;
+
+use crate::time::Hertz;
+use crate::uart::{self, DataBits, Instance, Parity, StopBits, Uart};
+
+/// Most channels a frame carries
+pub const MAX_CHANNELS: usize = 16;
+
+const SBUS_FRAME: usize = 25;
+const SBUS_HEADER: u8 = 0x0F;
+const SBUS_FLAG_CH17: u8 = 1 << 0;
+const SBUS_FLAG_CH18: u8 = 1 << 1;
+const SBUS_FLAG_FRAME_LOST: u8 = 1 << 2;
+const SBUS_FLAG_FAILSAFE: u8 = 1 << 3;
+
+const IBUS_FRAME: usize = 32;
+const IBUS_HEADER: [u8; 2] = [0x20, 0x40];
+const IBUS_CHANNELS: usize = 14;
+
+/// Framing errors in a row that mean the line is inverted
+const INVERTED_AFTER: u8 = 8;
+
+/// Receiver protocol
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Protocol {
+    /// Futaba SBUS, also FrSky and most multi-protocol receivers
+    Sbus,
+    /// FlySky IBUS servo output
+    Ibus,
+}
+
+impl Protocol {
+    /// USART settings for the protocol
+    pub fn uart_config(self) -> uart::Config {
+        match self {
+            Protocol::Sbus => uart::Config {
+                baudrate: Hertz::hz(100_000),
+                data_bits: DataBits::Eight,
+                stop_bits: StopBits::Two,
+                parity: Parity::Even,
+                ..uart::Config::default()
+            },
+            Protocol::Ibus => uart::Config {
+                baudrate: Hertz::hz(115_200),
+                ..uart::Config::default()
+            },
+        }
+    }
+
+    /// Channels in a frame
+    pub fn channels(self) -> usize {
+        match self {
+            Protocol::Sbus => MAX_CHANNELS,
+            Protocol::Ibus => IBUS_CHANNELS,
+        }
+    }
+
+    fn frame_len(self) -> usize {
+        match self {
+            Protocol::Sbus => SBUS_FRAME,
+            Protocol::Ibus => IBUS_FRAME,
+        }
+    }
+
+    /// Idle time that separates frames: well over a byte, well under the frame interval
+    fn gap(self) -> Duration {
+        Duration::from_micros(500)
+    }
+}
+
+/// RC decoding error
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The USART reported an error
+    Uart(uart::Error),
+    /// Frame of the wrong length, e.g. from a late start
+    Length,
+    /// Missing start byte(s)
+    Header,
+    /// IBUS checksum mismatch
+    Checksum,
+    /// Only framing errors: SBUS without an inverter, or the wrong protocol
+    Inverted,
+}
+
+impl From<uart::Error> for Error {
+    fn from(e: uart::Error) -> Self {
+        Error::Uart(e)
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Error::Uart(_) => "UART error",
+            Error::Length => "wrong frame length",
+            Error::Header => "bad frame header",
+            Error::Checksum => "checksum mismatch",
+            Error::Inverted => "line looks inverted",
+        })
+    }
+}
+
+impl core::error::Error for Error {}
+
+/// Decoded frame
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Frame {
+    /// Raw channel values: 11-bit SBUS counts (172..1811 for ±100 %) or IBUS µs
+    pub channels: [u16; MAX_CHANNELS],
+    /// Protocol the frame came from
+    pub protocol: Protocol,
+    /// SBUS digital channels 17 and 18
+    pub digital: [bool; 2],
+    /// SBUS: the receiver missed the last radio frame
+    pub frame_lost: bool,
+    /// SBUS: the receiver lost the link and sends its failsafe values
+    pub failsafe: bool,
+}
+
+impl Frame {
+    /// Channels as servo pulse widths in µs (1000..2000 for ±100 %)
+    pub fn channels_us(&self) -> [u16; MAX_CHANNELS] {
+        let mut us = self.channels;
+        if self.protocol == Protocol::Sbus {
+            // The common SBUS mapping: 172 -> 988 µs, 1811 -> 2012 µs
+            for value in &mut us {
+                *value = (*value as u32 * 5 / 8 + 880) as u16;
+            }
+        }
+        us
+    }
+}
+
+/// Decode a 25-byte SBUS frame
+pub fn decode_sbus(frame: &[u8]) -> Result<Frame, Error> {
+    if frame.len() != SBUS_FRAME {
+        return Err(Error::Length);
+    }
+    if frame[0] != SBUS_HEADER {
+        return Err(Error::Header);
+    }
+
+    // 16 channels of 11 bits, packed LSB first into bytes 1..=22
+    let mut channels = [0u16; MAX_CHANNELS];
+    let mut bits = 0u32;
+    let mut pending = 0;
+    let mut bytes = frame[1..23].iter();
+    for channel in &mut channels {
+        while pending < 11 {
+            bits |= (*bytes.next().unwrap_or(&0) as u32) << pending;
+            pending += 8;
+        }
+        *channel = (bits & 0x7FF) as u16;
+        bits >>= 11;
+        pending -= 11;
+    }
+
+    // The end byte differs between SBUS and SBUS2 (telemetry slots), so it is not checked
+    let flags = frame[23];
+    Ok(Frame {
+        channels,
+        protocol: Protocol::Sbus,
+        digital: [flags & SBUS_FLAG_CH17 != 0, flags & SBUS_FLAG_CH18 != 0],
+        frame_lost: flags & SBUS_FLAG_FRAME_LOST != 0,
+        failsafe: flags & SBUS_FLAG_FAILSAFE != 0,
+    })
+}
+
+/// Decode a 32-byte IBUS servo frame
+pub fn decode_ibus(frame: &[u8]) -> Result<Frame, Error> {
+    if frame.len() != IBUS_FRAME {
+        return Err(Error::Length);
+    }
+    if frame[..2] != IBUS_HEADER {
+        return Err(Error::Header);
+    }
+    let sum = frame[..30].iter().fold(0xFFFFu16, |sum, &b| sum.wrapping_sub(b as u16));
+    if sum != u16::from_le_bytes([frame[30], frame[31]]) {
+        return Err(Error::Checksum);
+    }
+
+    let mut channels = [0u16; MAX_CHANNELS];
+    for (i, channel) in channels.iter_mut().take(IBUS_CHANNELS).enumerate() {
+        *channel = u16::from_le_bytes([frame[2 + i * 2], frame[3 + i * 2]]);
+    }
+    Ok(Frame {
+        channels,
+        protocol: Protocol::Ibus,
+        digital: [false; 2],
+        frame_lost: false,
+        failsafe: false,
+    })
+}
+
+/// Frame reader on a USART set up with [`Protocol::uart_config`]
+pub struct RcReceiver<'d, T: Instance> {
+    uart: Uart<'d, T>,
+    protocol: Protocol,
+    framing_errors: u8,
+    buf: [u8; IBUS_FRAME],
+}
+
+impl<'d, T: Instance> RcReceiver<'d, T> {
+    /// Decode `protocol` frames
+    pub fn new(uart: Uart<'d, T>, protocol: Protocol) -> Self {
+        Self {
+            uart,
+            protocol,
+            framing_errors: 0,
+            buf: [0; IBUS_FRAME],
+        }
+    }
+
+    /// Wait for the next frame and decode it
+    ///
+    /// Returns [`Error::Inverted`] once per run of framing errors, the
+    /// other errors of that run as [`Error::Uart`].
+    pub async fn receive(&mut self) -> Result<Frame, Error> {
+        let result = self.uart.read_until_idle(&mut self.buf, self.protocol.gap()).await;
+        let len = match result {
+            Err(uart::Error::Framing) => {
+                self.framing_errors = self.framing_errors.saturating_add(1);
+                if self.framing_errors == INVERTED_AFTER {
+                    warn!("rc: only framing errors, is the {} line inverted?", self.protocol);
+                    return Err(Error::Inverted);
+                }
+                return Err(Error::Uart(uart::Error::Framing));
+            }
+            result => result?,
+        };
+        self.framing_errors = 0;
+
+        let frame = &self.buf[..len];
+        if len != self.protocol.frame_len() {
+            return Err(Error::Length);
+        }
+        match self.protocol {
+            Protocol::Sbus => decode_sbus(frame),
+            Protocol::Ibus => decode_ibus(frame),
+        }
+    }
+
+    /// Release the USART
+    pub fn into_uart(self) -> Uart<'d, T> {
+        self.uart
+    }
+}
@@ -1,22 +1,80 @@
 //! Reset and Clock Control (RCC/CKCU) driver
 //!
 //! HT32 uses CKCU (Clock Control Unit) instead of RCC, but we maintain RCC naming for consistency
+//!
+//! `Config` describes the whole clock tree (oscillator, PLL, AHB divider, USB
+//! prescaler and HSE clock monitor). `init` applies it once and records the
+//! resulting frequencies, which every driver reads back through `get_clocks`.
+//! `status` reports oscillator readiness and clock monitor failures at runtime.
+//! `reset_reason` reports what caused the last reset, from the RSTCU.
+//! `calibrate_hsi` / `enable_hsi_auto_trim` trim the HSI against the LSE
+//! crystal or USB start-of-frame packets, for boards without an HSE crystal.
+
+use core::cell::Cell;
+
+use critical_section::Mutex;
 
 use crate::pac::Ckcu;
+use crate::regs::{Mmio, RegisterAccess};
 use crate::time::Hertz;
 
+/// Internal high speed oscillator frequency
+const HSI_FREQ: u32 = 8_000_000;
+
+// GCCR bits without a dedicated accessor in use here
+const GCCR_CKMEN: u32 = 1 << 16; // HSE clock monitor enable
+
+// GCIR bits
+const GCIR_CKSF: u32 = 1 << 0;   // Clock stuck (HSE failure) flag, write 1 to clear
+const GCIR_CKSIE: u32 = 1 << 16; // Clock stuck interrupt enable
+
+// RSTCU global reset status register; flags are write 1 to clear
+const RSTCU_GRSR: usize = 0x4008_8100;
+const GRSR_SYSRSTF: u32 = 1 << 0;
+const GRSR_EXTRSTF: u32 = 1 << 1;
+const GRSR_WDTRSTF: u32 = 1 << 2;
+const GRSR_PORSTF: u32 = 1 << 3;
+const GRSR_ALL: u32 = GRSR_SYSRSTF | GRSR_EXTRSTF | GRSR_WDTRSTF | GRSR_PORSTF;
+
+// CKCU HSI control register: trim enable and the auto-trimming controller (ATC)
+const CKCU_HSICR: usize = 0x4008_8040;
+const HSICR_TRIMEN: u32 = 1 << 0;
+const HSICR_ATCEN: u32 = 1 << 1;
+const HSICR_REFCLKSEL_USB: u32 = 1 << 5; // ATC reference: 0 = LSE, 1 = USB SOF
+const HSICR_HSIFT_SHIFT: u32 = 16; // Fine trim, written by the ATC
+const HSICR_HSIFT_MASK: u32 = 0xFF << HSICR_HSIFT_SHIFT;
+
+// LSE: enabled from the RTC control register, ready flag in GCSR
+const RTC_CR: usize = 0x4006_A008;
+const RTCCR_LSEEN: u32 = 1 << 3;
+const GCSR_LSERDY: u32 = 1 << 4;
+
+/// Consecutive 1 ms samples with an unchanged trim before the ATC counts as settled
+const TRIM_SETTLED_MS: u32 = 16;
+
+/// Longest wait for an oscillator or the PLL to report ready on the suspend path
+#[cfg(feature = "usb")]
+const OSC_READY_TIMEOUT_US: u32 = 20_000;
+
 /// Clock configuration
 pub struct Config {
     /// System clock frequency
     pub sys_clk: Option<Hertz>,
-    /// AHB clock frequency
+    /// AHB clock frequency; rounded down to the fastest `sys_clk / 2^n` (n = 0..=5)
+    /// not above it, or `sys_clk / 32` for anything slower
     pub ahb_clk: Option<Hertz>,
-    /// APB clock frequency
+    /// APB clock frequency; PCLK follows the AHB clock on this family, so this
+    /// is only checked against `ahb_clk` and otherwise ignored
     pub apb_clk: Option<Hertz>,
     /// Use external crystal oscillator
     pub use_hse: bool,
     /// HSE frequency (if used)
     pub hse_freq: Option<Hertz>,
+    /// USB clock divider applied to the PLL output; USB needs exactly 48 MHz
+    pub usb_prescaler: UsbPrescaler,
+    /// Enable the HSE clock monitor; on HSE failure the CKCU falls back to HSI
+    /// and `status().clock_failure` is set
+    pub clock_monitor: bool,
 }
 
 impl Default for Config {
@@ -27,8 +85,32 @@ impl Default for Config {
             apb_clk: None,  // Same as sys_clk by default
             use_hse: false, // Use HSI by default
             hse_freq: None,
+            usb_prescaler: UsbPrescaler::Div1,
+            clock_monitor: false,
+        }
+    }
+}
+
+/// USB clock prescaler (GCFGR.USBPRE)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UsbPrescaler {
+    Div1,
+    Div2,
+    Div3,
+}
+
+impl UsbPrescaler {
+    fn divisor(self) -> u32 {
+        match self {
+            UsbPrescaler::Div1 => 1,
+            UsbPrescaler::Div2 => 2,
+            UsbPrescaler::Div3 => 3,
         }
     }
+
+    fn bits(self) -> u8 {
+        self.divisor() as u8 - 1
+    }
 }
 
 /// Frozen clock frequencies
@@ -38,6 +120,8 @@ pub struct Clocks {
     pub ahb_clk: Hertz,
     pub apb_clk: Hertz,
     pub hse_clk: Option<Hertz>,
+    pub pll_clk: Option<Hertz>,
+    pub usb_clk: Option<Hertz>,
 }
 
 impl Clocks {
@@ -55,27 +139,64 @@ impl Clocks {
     pub fn apb_clk(&self) -> Hertz {
         self.apb_clk
     }
+
+    /// Get the PLL output frequency, if the PLL is running
+    pub fn pll_clk(&self) -> Option<Hertz> {
+        self.pll_clk
+    }
+
+    /// Get the USB clock frequency, if the PLL is running
+    pub fn usb_clk(&self) -> Option<Hertz> {
+        self.usb_clk
+    }
 }
 
-static mut CLOCKS: Option<Clocks> = None;
+/// Clocks after reset, before `init` has run
+const RESET_CLOCKS: Clocks = Clocks {
+    sys_clk: Hertz::hz(HSI_FREQ),
+    ahb_clk: Hertz::hz(HSI_FREQ),
+    apb_clk: Hertz::hz(HSI_FREQ),
+    hse_clk: None,
+    pll_clk: None,
+    usb_clk: None,
+};
+
+static CLOCKS: Mutex<Cell<Option<Clocks>>> = Mutex::new(Cell::new(None));
 
 /// Initialize the clock system
 pub fn init(config: Config) -> Clocks {
     let ckcu = unsafe { &*Ckcu::ptr() };
 
     // Configure system clock based on config
-    let sys_freq = config.sys_clk.unwrap_or(Hertz::mhz(8)); // Default HSI freq
+    let sys_freq = config.sys_clk.unwrap_or(Hertz::hz(HSI_FREQ));
 
-    let clocks = if config.use_hse && config.hse_freq.is_some() {
-        configure_hse_clock(ckcu, config.hse_freq.unwrap(), sys_freq)
-    } else {
-        configure_hsi_clock(ckcu, sys_freq)
+    let hse_clk = config.hse_freq.filter(|_| config.use_hse);
+    let (sys_clk, pll_clk) = match hse_clk {
+        Some(hse_freq) => configure_hse_clock(ckcu, hse_freq, sys_freq),
+        None => configure_hsi_clock(ckcu, sys_freq),
+    };
+
+    // USB clock is derived from the PLL output
+    ckcu.gcfgr().modify(|_, w| unsafe { w.usbpre().bits(config.usb_prescaler.bits()) });
+    let usb_clk = pll_clk.map(|pll| Hertz::hz(pll.to_hz() / config.usb_prescaler.divisor()));
+
+    let ahb_clk = configure_bus_clocks(ckcu, sys_clk, config.ahb_clk);
+    debug_assert!(config.apb_clk.is_none_or(|apb| apb.to_hz() == ahb_clk.to_hz()));
+
+    configure_clock_monitor(ckcu, config.clock_monitor && hse_clk.is_some());
+
+    let clocks = Clocks {
+        sys_clk,
+        ahb_clk,
+        apb_clk: ahb_clk,
+        hse_clk,
+        pll_clk,
+        usb_clk,
     };
 
     // Store clocks globally for later access
-    unsafe {
-        CLOCKS = Some(clocks);
-    }
+    critical_section::with(|cs| CLOCKS.borrow(cs).set(Some(clocks)));
+    debug!("clocks: sys {} Hz, ahb {} Hz", sys_clk.to_hz(), ahb_clk.to_hz());
 
     // Enable GPIO clocks by default
     enable_gpio_clocks(ckcu);
@@ -85,18 +206,324 @@ pub fn init(config: Config) -> Clocks {
 
 /// Get the current clock configuration
 pub fn get_clocks() -> Clocks {
-    unsafe { CLOCKS.unwrap_or_else(|| {
-        // Return default HSI clocks if not initialized
-        Clocks {
-            sys_clk: Hertz::mhz(8),
-            ahb_clk: Hertz::mhz(8),
-            apb_clk: Hertz::mhz(8),
-            hse_clk: None,
+    // Return default HSI clocks if not initialized
+    critical_section::with(|cs| CLOCKS.borrow(cs).get()).unwrap_or(RESET_CLOCKS)
+}
+
+/// Oscillator and clock monitor state
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ClockStatus {
+    pub hsi_ready: bool,
+    pub hse_ready: bool,
+    pub pll_ready: bool,
+    /// The clock monitor saw HSE stop and switched the system clock to HSI
+    pub clock_failure: bool,
+}
+
+/// Read oscillator readiness and the clock monitor failure flag
+pub fn status() -> ClockStatus {
+    let ckcu = unsafe { &*Ckcu::ptr() };
+    let gcsr = ckcu.gcsr().read();
+
+    ClockStatus {
+        hsi_ready: gcsr.hsirdy().bit_is_set(),
+        hse_ready: gcsr.hserdy().bit_is_set(),
+        pll_ready: gcsr.pllrdy().bit_is_set(),
+        clock_failure: ckcu.gcir().read().bits() & GCIR_CKSF != 0,
+    }
+}
+
+/// Clear the clock monitor failure flag
+///
+/// After a failure the system runs from HSI; call `init` again to return to HSE.
+pub fn clear_clock_failure() {
+    let ckcu = unsafe { &*Ckcu::ptr() };
+    ckcu.gcir().modify(|r, w| unsafe { w.bits((r.bits() & GCIR_CKSIE) | GCIR_CKSF) });
+}
+
+/// Causes of the last reset
+///
+/// The flags accumulate until cleared, so after [`reset_reason`] without
+/// clearing, a later reset reports both its own cause and the earlier ones.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ResetReason(u8);
+
+impl ResetReason {
+    /// Power-on, or VDD dropping below the brown-out/LVD reset level
+    pub const POWER_ON: ResetReason = ResetReason(1 << 0);
+    /// nRST pin pulled low
+    pub const EXTERNAL: ResetReason = ResetReason(1 << 1);
+    /// Watchdog timeout
+    pub const WATCHDOG: ResetReason = ResetReason(1 << 2);
+    /// `SYSRESETREQ`, e.g. `cortex_m::peripheral::SCB::sys_reset`
+    pub const SOFTWARE: ResetReason = ResetReason(1 << 3);
+
+    /// Whether every cause in `other` is in `self`
+    pub const fn contains(self, other: ResetReason) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// No flag set, e.g. after they were cleared and no reset followed
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Raw bitmask, bit 0 = power-on ... bit 3 = software
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+}
+
+impl core::ops::BitOr for ResetReason {
+    type Output = ResetReason;
+
+    fn bitor(self, rhs: ResetReason) -> ResetReason {
+        ResetReason(self.0 | rhs.0)
+    }
+}
+
+/// Read what caused the last reset, clearing the flags if `clear` is set
+///
+/// Clear them once logged so the next boot reports only its own cause.
+pub fn reset_reason(clear: bool) -> ResetReason {
+    let grsr = Mmio.read(RSTCU_GRSR);
+    if clear {
+        Mmio.write(RSTCU_GRSR, grsr & GRSR_ALL);
+    }
+
+    let mut reason = ResetReason(0);
+    for (flag, cause) in [
+        (GRSR_PORSTF, ResetReason::POWER_ON),
+        (GRSR_EXTRSTF, ResetReason::EXTERNAL),
+        (GRSR_WDTRSTF, ResetReason::WATCHDOG),
+        (GRSR_SYSRSTF, ResetReason::SOFTWARE),
+    ] {
+        if grsr & flag != 0 {
+            reason = reason | cause;
         }
-    })}
+    }
+    reason
+}
+
+/// Reference the HSI is trimmed against
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TrimReference {
+    /// 32.768 kHz LSE crystal, started if it is not running
+    Lse,
+    /// 1 kHz USB start-of-frame packets; only while the host is sending them
+    UsbSof,
 }
 
-fn configure_hsi_clock(ckcu: &crate::pac::ckcu::RegisterBlock, target_freq: Hertz) -> Clocks {
+/// Why [`calibrate_hsi`] failed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TrimError {
+    /// The LSE crystal did not start
+    NoLse,
+    /// The trim did not settle, e.g. no SOFs arrived
+    Timeout,
+}
+
+impl core::fmt::Display for TrimError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            TrimError::NoLse => "LSE did not start",
+            TrimError::Timeout => "HSI trim did not settle",
+        })
+    }
+}
+
+impl core::error::Error for TrimError {}
+
+/// Current HSI fine trim
+pub fn hsi_trim() -> u8 {
+    ((Mmio.read(CKCU_HSICR) & HSICR_HSIFT_MASK) >> HSICR_HSIFT_SHIFT) as u8
+}
+
+/// Apply a fixed HSI fine trim, e.g. one saved from [`calibrate_hsi`]
+///
+/// Stops the auto-trimming controller.
+pub fn set_hsi_trim(trim: u8) {
+    Mmio.modify(CKCU_HSICR, |v| {
+        let v = v & !(HSICR_ATCEN | HSICR_HSIFT_MASK);
+        v | HSICR_TRIMEN | (trim as u32) << HSICR_HSIFT_SHIFT
+    });
+}
+
+/// Keep the HSI trimmed against `reference` in hardware
+///
+/// The auto-trimming controller keeps adjusting as the die temperature
+/// changes, so UART baud rates and crystal-less USB stay within tolerance.
+/// For [`TrimReference::Lse`] the LSE must already run, see [`calibrate_hsi`].
+pub fn enable_hsi_auto_trim(reference: TrimReference) {
+    Mmio.modify(CKCU_HSICR, |v| {
+        let v = match reference {
+            TrimReference::Lse => v & !HSICR_REFCLKSEL_USB,
+            TrimReference::UsbSof => v | HSICR_REFCLKSEL_USB,
+        };
+        v | HSICR_TRIMEN | HSICR_ATCEN
+    });
+}
+
+/// Stop the auto-trimming controller, keeping the trim it reached
+pub fn disable_hsi_auto_trim() {
+    Mmio.modify(CKCU_HSICR, |v| v & !HSICR_ATCEN);
+}
+
+/// Trim the HSI against `reference` and return the trim it settled on
+///
+/// Busy-waits up to `timeout_ms` (plus the LSE start-up) for the trim to
+/// stop changing. The auto-trimming controller is left running; call
+/// [`disable_hsi_auto_trim`] to freeze the result, and store it for
+/// [`set_hsi_trim`] on boots where the reference is not available.
+pub fn calibrate_hsi(reference: TrimReference, timeout_ms: u32) -> Result<u8, TrimError> {
+    // 32 kHz crystals take up to a couple of seconds to start
+    if reference == TrimReference::Lse && !start_lse(2000) {
+        return Err(TrimError::NoLse);
+    }
+    enable_hsi_auto_trim(reference);
+
+    let mut trim = hsi_trim();
+    let mut stable_ms = 0;
+    for _ in 0..timeout_ms {
+        crate::cortex_delay::delay_ms(1);
+        let now = hsi_trim();
+        stable_ms = if now == trim { stable_ms + 1 } else { 0 };
+        trim = now;
+        if stable_ms >= TRIM_SETTLED_MS {
+            debug!("rcc: HSI trim settled at {}", trim);
+            return Ok(trim);
+        }
+    }
+    warn!("rcc: HSI trim did not settle, at {}", trim);
+    Err(TrimError::Timeout)
+}
+
+/// Start the 32.768 kHz LSE crystal; `false` if it is not ready within `timeout_ms`
+pub(crate) fn start_lse(timeout_ms: u32) -> bool {
+    if crate::lvd::enable_backup_domain().is_err() {
+        return false;
+    }
+    Mmio.modify(RTC_CR, |v| v | RTCCR_LSEEN);
+
+    let ckcu = unsafe { &*Ckcu::ptr() };
+    let mut waited_ms = 0;
+    while ckcu.gcsr().read().bits() & GCSR_LSERDY == 0 {
+        if waited_ms >= timeout_ms {
+            Mmio.modify(RTC_CR, |v| v & !RTCCR_LSEEN);
+            return false;
+        }
+        crate::cortex_delay::delay_ms(1);
+        waited_ms += 1;
+    }
+    true
+}
+
+/// Clock tree saved by [`enter_hsi_only`]
+#[cfg(feature = "usb")]
+pub(crate) struct SavedClocks {
+    clocks: Clocks,
+    gccr: u32,
+    hse: bool,
+    pll: bool,
+    ahbpre: u8,
+}
+
+/// Poll `ready` for up to [`OSC_READY_TIMEOUT_US`]
+#[cfg(feature = "usb")]
+fn wait_ready(ready: impl Fn() -> bool) -> bool {
+    for _ in 0..OSC_READY_TIMEOUT_US {
+        if ready() {
+            return true;
+        }
+        crate::cortex_delay::delay_us(1);
+    }
+    ready()
+}
+
+/// Run the core straight from the HSI with the PLL and HSE stopped
+///
+/// Used while the USB bus is suspended. The time driver is moved to the new
+/// APB clock so `embassy-time` keeps its rate. `None` if the HSI does not
+/// start, in which case the clocks are left as they were.
+#[cfg(feature = "usb")]
+pub(crate) fn enter_hsi_only() -> Option<SavedClocks> {
+    let ckcu = unsafe { &*Ckcu::ptr() };
+    let gccr = ckcu.gccr().read();
+    let saved = SavedClocks {
+        clocks: get_clocks(),
+        gccr: gccr.bits(),
+        hse: gccr.hseen().bit_is_set(),
+        pll: gccr.pllen().bit_is_set(),
+        ahbpre: ckcu.ahbcfgr().read().ahbpre().bits(),
+    };
+
+    // HSI first, then stop what it replaced; the clock monitor would see HSE stop
+    ckcu.gccr().modify(|_, w| w.hsien().set_bit());
+    if !wait_ready(|| ckcu.gcsr().read().hsirdy().bit_is_set()) {
+        warn!("rcc: HSI not ready, staying on the current clocks");
+        return None;
+    }
+    ckcu.gccr().modify(|_, w| w.sw().variant(0));
+    ckcu.ahbcfgr().modify(|_, w| unsafe { w.ahbpre().bits(0) });
+    ckcu.gccr().modify(|r, w| unsafe { w.bits(r.bits() & !GCCR_CKMEN) });
+    ckcu.gccr().modify(|_, w| w.pllen().clear_bit().hseen().clear_bit());
+
+    let hsi = Hertz::hz(HSI_FREQ);
+    let clocks = Clocks {
+        sys_clk: hsi,
+        ahb_clk: hsi,
+        apb_clk: hsi,
+        hse_clk: None,
+        pll_clk: None,
+        usb_clk: None,
+    };
+    critical_section::with(|cs| CLOCKS.borrow(cs).set(Some(clocks)));
+    #[cfg(feature = "time-driver")]
+    crate::time_driver::set_timer_clock(HSI_FREQ);
+
+    Some(saved)
+}
+
+/// Undo [`enter_hsi_only`], waiting for the oscillators and PLL to restart
+///
+/// `false` if the HSE or PLL does not come back; the core then stays on the
+/// HSI with both stopped and [`get_clocks`] reports the HSI-only tree.
+#[cfg(feature = "usb")]
+pub(crate) fn restore_clocks(saved: SavedClocks) -> bool {
+    let ckcu = unsafe { &*Ckcu::ptr() };
+
+    // Restart the oscillators and PLL while still on HSI, then switch back
+    if saved.hse {
+        ckcu.gccr().modify(|_, w| w.hseen().set_bit());
+        if !wait_ready(|| ckcu.gcsr().read().hserdy().bit_is_set()) {
+            ckcu.gccr().modify(|_, w| w.hseen().clear_bit());
+            warn!("rcc: HSE did not restart, staying on HSI");
+            return false;
+        }
+    }
+    if saved.pll {
+        ckcu.gccr().modify(|_, w| w.pllen().set_bit());
+        if !wait_ready(|| ckcu.gcsr().read().pllrdy().bit_is_set()) {
+            ckcu.gccr().modify(|_, w| w.pllen().clear_bit().hseen().clear_bit());
+            warn!("rcc: PLL did not lock, staying on HSI");
+            return false;
+        }
+    }
+    ckcu.ahbcfgr().modify(|_, w| unsafe { w.ahbpre().bits(saved.ahbpre) });
+    // Clock source and clock monitor as they were
+    ckcu.gccr().write(|w| unsafe { w.bits(saved.gccr) });
+
+    critical_section::with(|cs| CLOCKS.borrow(cs).set(Some(saved.clocks)));
+    #[cfg(feature = "time-driver")]
+    crate::time_driver::set_timer_clock(saved.clocks.apb_clk().to_hz());
+    true
+}
+
+/// Returns the system clock and the PLL output, if the PLL is used
+fn configure_hsi_clock(ckcu: &crate::pac::ckcu::RegisterBlock, target_freq: Hertz) -> (Hertz, Option<Hertz>) {
     // Enable HSI (High Speed Internal oscillator) first
     ckcu.gccr().modify(|_, w| w.hsien().set_bit());
 
@@ -104,19 +531,17 @@ fn configure_hsi_clock(ckcu: &crate::pac::ckcu::RegisterBlock, target_freq: Hert
     while !ckcu.gcsr().read().hsirdy().bit_is_set() {}
 
     // Configure PLL if target frequency is higher than HSI
-    let sys_clk = if target_freq.to_hz() > 8_000_000 {
-        configure_pll_from_hsi(ckcu, target_freq)
+    if target_freq.to_hz() > HSI_FREQ {
+        configure_pll(ckcu, pll::Source::Hsi, HSI_FREQ, target_freq)
     } else {
         // Use HSI directly - SW field: 0=HSI, 1=HSE, 2=PLL
         ckcu.gccr().modify(|_, w| w.sw().variant(0));
-        Hertz::mhz(8) // HSI frequency
-    };
-
-    // Configure AHB and APB prescalers
-    configure_bus_clocks(ckcu, sys_clk)
+        (Hertz::hz(HSI_FREQ), None)
+    }
 }
 
-fn configure_hse_clock(ckcu: &crate::pac::ckcu::RegisterBlock, hse_freq: Hertz, target_freq: Hertz) -> Clocks {
+/// Returns the system clock and the PLL output, if the PLL is used
+fn configure_hse_clock(ckcu: &crate::pac::ckcu::RegisterBlock, hse_freq: Hertz, target_freq: Hertz) -> (Hertz, Option<Hertz>) {
     // Enable HSE (High Speed External oscillator)
     ckcu.gccr().modify(|_, w| w.hseen().set_bit());
 
@@ -124,32 +549,34 @@ fn configure_hse_clock(ckcu: &crate::pac::ckcu::RegisterBlock, hse_freq: Hertz,
     while !ckcu.gcsr().read().hserdy().bit_is_set() {}
 
     // Configure PLL from HSE if needed
-    let sys_clk = if target_freq.to_hz() > hse_freq.to_hz() {
-        configure_pll_from_hse(ckcu, hse_freq, target_freq)
+    if target_freq.to_hz() > hse_freq.to_hz() {
+        configure_pll(ckcu, pll::Source::Hse, hse_freq.to_hz(), target_freq)
     } else {
         // Use HSE directly
         ckcu.gccr().modify(|_, w| w.sw().variant(1));
-        hse_freq
-    };
-
-    configure_bus_clocks(ckcu, sys_clk)
+        (hse_freq, None)
+    }
 }
 
-fn configure_pll_from_hsi(ckcu: &crate::pac::ckcu::RegisterBlock, target_freq: Hertz) -> Hertz {
-    // HSI = 8MHz as input to PLL
-    let hsi_freq = 8_000_000u32;
-    let target = target_freq.to_hz();
-
-    // HT32F523xx PLL formula: PLL_Output = Input_Freq * ((PFBD + 2) / (2^POTD))
-    // PFBD: 4-bit feedback divider (0-15, representing 2-17 multiplier)
-    // POTD: 2-bit output divider (0-3, representing 2^0 to 2^3 = 1,2,4,8 divider)
-
-    let (pfbd, potd) = calculate_pll_params_ht32(hsi_freq, target);
+/// Returns the system clock and the PLL output, if a valid PLL setting exists
+fn configure_pll(
+    ckcu: &crate::pac::ckcu::RegisterBlock,
+    source: pll::Source,
+    input_hz: u32,
+    target_freq: Hertz,
+) -> (Hertz, Option<Hertz>) {
+    let Some(params) = pll::calculate(input_hz, target_freq.to_hz(), pll::SYS_CLK_MAX) else {
+        // No valid PLL setting; run straight from the oscillator instead
+        ckcu.gccr().modify(|_, w| w.sw().variant(source.sw()));
+        return (Hertz::hz(input_hz), None);
+    };
 
-    // Configure PLL
+    // Select the PLL source and program the dividers while the PLL is off
+    ckcu.gccr().modify(|_, w| w.pllen().clear_bit());
+    ckcu.gcfgr().modify(|_, w| w.pllsrc().bit(matches!(source, pll::Source::Hsi)));
     ckcu.pllcfgr().modify(|_, w| unsafe {
-        w.pfbd().bits(pfbd)    // Feedback divider (4 bits)
-         .potd().bits(potd)    // Output divider (2 bits)
+        w.pfbd().bits(params.pfbd())    // Feedback divider (4 bits)
+         .potd().bits(params.potd())    // Output divider (2 bits)
     });
 
     // Enable PLL
@@ -161,103 +588,187 @@ fn configure_pll_from_hsi(ckcu: &crate::pac::ckcu::RegisterBlock, target_freq: H
     // Switch to PLL as system clock
     ckcu.gccr().modify(|_, w| w.sw().variant(2));
 
-    // Calculate actual frequency: Input * ((PFBD + 2) / (2^POTD))
-    let actual_freq = hsi_freq * (pfbd as u32 + 2) / (1u32 << potd as u32);
-    Hertz::hz(actual_freq)
+    let pll_clk = Hertz::hz(params.output(input_hz));
+    (pll_clk, Some(pll_clk))
 }
 
-fn configure_pll_from_hse(ckcu: &crate::pac::ckcu::RegisterBlock, hse_freq: Hertz, target_freq: Hertz) -> Hertz {
-    // Similar to HSI but using HSE as input
-    let hse_hz = hse_freq.to_hz();
-    let target = target_freq.to_hz();
-
-    let (pfbd, potd) = calculate_pll_params_ht32(hse_hz, target);
+/// PLL parameter math
+///
+/// `f_PLL = f_in * NF2 / NO2` with NF2 = 1..=16 (PFBD, 16 encoded as 0) and
+/// NO2 = 1, 2, 4 or 8 (POTD = log2 NO2). Limits are from the HT32F52342/52352
+/// datasheet PLL characteristics. Everything here is `const` and register-free;
+/// the table checks at the bottom run at compile time.
+pub mod pll {
+    /// Lowest PLL input frequency
+    pub const IN_MIN: u32 = 4_000_000;
+    /// Highest PLL input frequency
+    pub const IN_MAX: u32 = 16_000_000;
+    /// Lowest VCO frequency (`f_in * NF2`)
+    pub const VCO_MIN: u32 = 48_000_000;
+    /// Highest VCO frequency (`f_in * NF2`)
+    pub const VCO_MAX: u32 = 96_000_000;
+    /// Highest system clock of the HT32F52342/52352
+    pub const SYS_CLK_MAX: u32 = 48_000_000;
+
+    /// PLL reference clock
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum Source {
+        Hsi,
+        Hse,
+    }
 
-    // Configure PLL with HSE as source
-    ckcu.pllcfgr().modify(|_, w| unsafe {
-        w.pfbd().bits(pfbd)    // Feedback divider (4 bits)
-         .potd().bits(potd)    // Output divider (2 bits)
-    });
+    impl Source {
+        /// GCCR.SW value selecting this oscillator directly
+        pub(crate) const fn sw(self) -> u8 {
+            match self {
+                Source::Hsi => 0,
+                Source::Hse => 1,
+            }
+        }
+    }
 
-    // Enable PLL
-    ckcu.gccr().modify(|_, w| w.pllen().set_bit());
+    /// PLL divider setting
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub struct Params {
+        /// Feedback multiplier, 1..=16
+        pub nf2: u8,
+        /// log2 of the output divider, 0..=3
+        pub no2_log2: u8,
+    }
 
-    // Wait for PLL to be ready
-    while !ckcu.gcsr().read().pllrdy().bit_is_set() {}
+    impl Params {
+        /// PLLCFGR.PFBD field value
+        pub const fn pfbd(&self) -> u8 {
+            self.nf2 & 0x0F
+        }
 
-    // Switch to PLL as system clock
-    ckcu.gccr().modify(|_, w| w.sw().variant(2));
+        /// PLLCFGR.POTD field value
+        pub const fn potd(&self) -> u8 {
+            self.no2_log2
+        }
 
-    // Calculate actual frequency: Input * ((PFBD + 2) / (2^POTD))
-    let actual_freq = hse_hz * (pfbd as u32 + 2) / (1u32 << potd as u32);
-    Hertz::hz(actual_freq)
-}
+        /// VCO frequency for `input` Hz
+        pub const fn vco(&self, input: u32) -> u32 {
+            input * self.nf2 as u32
+        }
 
-fn calculate_pll_params_ht32(input_freq: u32, target_freq: u32) -> (u8, u8) {
-    // HT32F523xx PLL calculation: Output = Input * ((PFBD + 2) / (2^POTD))
-    // PFBD: 0-15 (representing multiplier 2-17)
-    // POTD: 0-3 (representing divider 1,2,4,8)
+        /// PLL output frequency for `input` Hz
+        pub const fn output(&self, input: u32) -> u32 {
+            self.vco(input) >> self.no2_log2
+        }
 
-    let mut best_error = u32::MAX;
-    let mut best_pfbd = 6; // Default: 8MHz * ((6+2)/1) = 64MHz, but limited by max freq
-    let mut best_potd = 1; // Default: divide by 2 -> 32MHz
+        /// Whether this setting respects the VCO and output limits for `input` Hz
+        pub const fn is_valid(&self, input: u32, max_output: u32) -> bool {
+            let vco = input as u64 * self.nf2 as u64;
+            self.nf2 >= 1
+                && self.nf2 <= 16
+                && self.no2_log2 <= 3
+                && vco >= VCO_MIN as u64
+                && vco <= VCO_MAX as u64
+                && (vco >> self.no2_log2) <= max_output as u64
+        }
+    }
 
-    // Try all combinations within reasonable bounds
-    for potd in 0..=3u8 {
-        let divisor = 1u32 << potd;
-        for pfbd in 0..=15u8 {
-            let multiplier = pfbd as u32 + 2;
-            let output_freq = input_freq * multiplier / divisor;
+    /// Closest valid setting to `target` Hz for `input` Hz, not above `max_output`
+    ///
+    /// Ties go to the lower VCO frequency. Returns `None` if `input` is outside
+    /// the PLL input range or no setting satisfies the limits.
+    pub const fn calculate(input: u32, target: u32, max_output: u32) -> Option<Params> {
+        if input < IN_MIN || input > IN_MAX {
+            return None;
+        }
 
-            // Ensure we don't exceed maximum system clock (usually 60MHz for HT32F523xx)
-            if output_freq > 60_000_000 {
-                continue;
+        let mut best: Option<Params> = None;
+        let mut best_error = u32::MAX;
+
+        let mut nf2 = 1u8;
+        while nf2 <= 16 {
+            let mut no2_log2 = 0u8;
+            while no2_log2 <= 3 {
+                let params = Params { nf2, no2_log2 };
+                if params.is_valid(input, max_output) {
+                    let output = params.output(input);
+                    let error = output.abs_diff(target);
+                    if error < best_error {
+                        best_error = error;
+                        best = Some(params);
+                    }
+                }
+                no2_log2 += 1;
             }
+            nf2 += 1;
+        }
 
-            // Ensure VCO frequency is within bounds (typically 120-200MHz before final division)
-            let vco_freq = input_freq * multiplier;
-            if vco_freq < 120_000_000 || vco_freq > 200_000_000 {
-                continue;
-            }
+        best
+    }
 
-            let error = if output_freq > target_freq {
-                output_freq - target_freq
-            } else {
-                target_freq - output_freq
-            };
+    // Known-good settings
+    const _: () = {
+        // 8 MHz HSI or HSE to 48 MHz: NF2 = 6, NO2 = 1
+        match calculate(8_000_000, 48_000_000, SYS_CLK_MAX) {
+            Some(p) => assert!(p.nf2 == 6 && p.no2_log2 == 0),
+            None => panic!("8 MHz -> 48 MHz must be achievable"),
+        }
+        // 12 MHz HSE to 48 MHz: NF2 = 4, NO2 = 1
+        match calculate(12_000_000, 48_000_000, SYS_CLK_MAX) {
+            Some(p) => assert!(p.nf2 == 4 && p.no2_log2 == 0),
+            None => panic!("12 MHz -> 48 MHz must be achievable"),
+        }
+        // 16 MHz HSE to 48 MHz: NF2 = 3, NO2 = 1
+        match calculate(16_000_000, 48_000_000, SYS_CLK_MAX) {
+            Some(p) => assert!(p.nf2 == 3 && p.no2_log2 == 0),
+            None => panic!("16 MHz -> 48 MHz must be achievable"),
+        }
+        // Out-of-range inputs are rejected
+        assert!(calculate(IN_MIN - 1, 48_000_000, SYS_CLK_MAX).is_none());
+        assert!(calculate(IN_MAX + 1, 48_000_000, SYS_CLK_MAX).is_none());
+    };
 
-            if error < best_error {
-                best_error = error;
-                best_pfbd = pfbd;
-                best_potd = potd;
+    // Exhaustive: for every whole-MHz input, every achievable output is hit exactly
+    const _: () = {
+        let mut input = IN_MIN;
+        while input <= IN_MAX {
+            let mut nf2 = 1u8;
+            while nf2 <= 16 {
+                let mut no2_log2 = 0u8;
+                while no2_log2 <= 3 {
+                    let params = Params { nf2, no2_log2 };
+                    if params.is_valid(input, SYS_CLK_MAX) {
+                        let target = params.output(input);
+                        match calculate(input, target, SYS_CLK_MAX) {
+                            Some(p) => assert!(p.output(input) == target),
+                            None => panic!("achievable output not found"),
+                        }
+                    }
+                    no2_log2 += 1;
+                }
+                nf2 += 1;
             }
 
-            // Exact match found
-            if error == 0 {
-                break;
-            }
+            input += 1_000_000;
         }
-    }
-
-    (best_pfbd, best_potd)
+    };
 }
 
-fn configure_bus_clocks(_ckcu: &crate::pac::ckcu::RegisterBlock, sys_clk: Hertz) -> Clocks {
-    // For HT32, AHB and APB are typically the same as system clock
-    // This can be modified based on specific requirements
-
-    // Configure AHB prescaler (if needed)
-    // ckcu.ahbcfgr.modify(|_, w| w.ahbpre().div1());
+/// Program the AHB prescaler and return the resulting AHB clock
+fn configure_bus_clocks(ckcu: &crate::pac::ckcu::RegisterBlock, sys_clk: Hertz, ahb_clk: Option<Hertz>) -> Hertz {
+    // AHBPRE: HCLK = SYSCLK / 2^n, n = 0..=5; pick the fastest clock not above the request
+    let target = ahb_clk.map_or(sys_clk.to_hz(), |f| f.to_hz().max(1));
+    let mut shift = 0;
+    while shift < 5 && (sys_clk.to_hz() >> shift) > target {
+        shift += 1;
+    }
+    ckcu.ahbcfgr().modify(|_, w| unsafe { w.ahbpre().bits(shift as u8) });
 
-    // Configure APB prescaler (if needed)
-    // ckcu.apbcfgr.modify(|_, w| w.apbpre().div1());
+    Hertz::hz(sys_clk.to_hz() >> shift)
+}
 
-    Clocks {
-        sys_clk,
-        ahb_clk: sys_clk, // Same as system clock
-        apb_clk: sys_clk, // Same as system clock
-        hse_clk: None,    // TODO: Track HSE frequency if used
-    }
+fn configure_clock_monitor(ckcu: &crate::pac::ckcu::RegisterBlock, enable: bool) {
+    // Clear any stale failure before (re)arming the monitor
+    ckcu.gcir().write(|w| unsafe { w.bits(GCIR_CKSF) });
+    ckcu.gccr().modify(|r, w| unsafe {
+        w.bits(if enable { r.bits() | GCCR_CKMEN } else { r.bits() & !GCCR_CKMEN })
+    });
 }
 
 fn enable_gpio_clocks(ckcu: &crate::pac::ckcu::RegisterBlock) {
@@ -300,6 +811,7 @@ impl Rcc {
             Peripheral::TIM0 => ckcu.apbccr1().modify(|_, w| w.gptm0en().set_bit()),
             Peripheral::TIM1 => ckcu.apbccr1().modify(|_, w| w.gptm1en().set_bit()),
             Peripheral::USB => ckcu.ahbccr().modify(|_, w| w.usben().set_bit()),
+            Peripheral::PDMA => ckcu.ahbccr().modify(|_, w| w.pdmaen().set_bit()),
         }
     }
 
@@ -318,6 +830,7 @@ impl Rcc {
             Peripheral::TIM0 => ckcu.apbccr1().modify(|_, w| w.gptm0en().clear_bit()),
             Peripheral::TIM1 => ckcu.apbccr1().modify(|_, w| w.gptm1en().clear_bit()),
             Peripheral::USB => ckcu.ahbccr().modify(|_, w| w.usben().clear_bit()),
+            Peripheral::PDMA => ckcu.ahbccr().modify(|_, w| w.pdmaen().clear_bit()),
         }
     }
 
@@ -340,6 +853,7 @@ pub enum Peripheral {
     TIM0,
     TIM1,
     USB,
+    PDMA,
 }
 
 /// Extension trait for RCC
@@ -0,0 +1,86 @@
+//! Raw register access
+//!
+//! Register blocks the PAC does not model usefully (EP_SRAM, the PDMA channel
+//! array) are accessed by address through [`RegisterAccess`], as are the
+//! registers of driver logic tested on the host, such as the extended GPTM
+//! count of the time driver. On target this is [`Mmio`]; the
+//! `mock-registers` feature adds [`MockRegisters`], an in-memory register
+//! file for exercising that logic on the host.
+
+/// 32-bit register reads and writes by absolute address
+pub trait RegisterAccess {
+    /// Read the register at `addr`
+    fn read(&self, addr: usize) -> u32;
+
+    /// Write `value` to the register at `addr`
+    fn write(&self, addr: usize, value: u32);
+
+    /// Read-modify-write the register at `addr`
+    fn modify(&self, addr: usize, f: impl FnOnce(u32) -> u32) {
+        let value = self.read(addr);
+        self.write(addr, f(value));
+    }
+}
+
+/// Volatile memory-mapped I/O
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Mmio;
+
+impl RegisterAccess for Mmio {
+    #[inline(always)]
+    fn read(&self, addr: usize) -> u32 {
+        unsafe { core::ptr::read_volatile(addr as *const u32) }
+    }
+
+    #[inline(always)]
+    fn write(&self, addr: usize, value: u32) {
+        unsafe { core::ptr::write_volatile(addr as *mut u32, value) }
+    }
+}
+
+/// In-memory register file covering `N` words starting at `base`
+///
+/// Accesses outside the window or not word aligned panic, which is what a
+/// host test wants to hear about.
+#[cfg(feature = "mock-registers")]
+pub struct MockRegisters<const N: usize> {
+    base: usize,
+    words: core::cell::RefCell<[u32; N]>,
+}
+
+#[cfg(feature = "mock-registers")]
+impl<const N: usize> MockRegisters<N> {
+    /// Create a zeroed register file at `base`
+    pub const fn new(base: usize) -> Self {
+        Self {
+            base,
+            words: core::cell::RefCell::new([0; N]),
+        }
+    }
+
+    fn index(&self, addr: usize) -> usize {
+        assert!(addr % 4 == 0, "unaligned register access at {:#x}", addr);
+        let index = addr.checked_sub(self.base).map(|offset| offset / 4);
+        match index {
+            Some(index) if index < N => index,
+            _ => panic!("register access at {:#x} outside mock window", addr),
+        }
+    }
+
+    /// Current contents, for assertions
+    pub fn snapshot(&self) -> [u32; N] {
+        *self.words.borrow()
+    }
+}
+
+#[cfg(feature = "mock-registers")]
+impl<const N: usize> RegisterAccess for MockRegisters<N> {
+    fn read(&self, addr: usize) -> u32 {
+        self.words.borrow()[self.index(addr)]
+    }
+
+    fn write(&self, addr: usize, value: u32) {
+        let index = self.index(addr);
+        self.words.borrow_mut()[index] = value;
+    }
+}
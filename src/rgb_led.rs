@@ -0,0 +1,261 @@
+//! RGB LEDs on three PWM channels
+//!
+//! [`RgbLed`] takes colours as [`Rgb`] or [`Hsv`], passes each channel
+//! through a gamma curve so that equal steps look equal, and sets the duty
+//! cycles. With the `time` feature it also runs fades, breathing and hue
+//! cycles from `embassy-time`:
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::rgb_led::{Hsv, Rgb, RgbLed, Wiring};
+//!
+//! let mut led = RgbLed::new(pwm, Channel::Ch0, Channel::Ch1, Channel::Ch2, Wiring::CommonCathode);
+//! led.set(Rgb::new(255, 80, 0));
+//! led.fade_to(Hsv::new(170, 255, 128), Duration::from_millis(500)).await;
+//! ```
+//!
+//! Colour values are perceptual: 128 looks about half as bright as 255,
+//! which takes a duty cycle near 18 %. The curve is `x^2.5`, kept as a
+//! 16-bit [`GAMMA`] table so the dim end still has distinct steps; run the
+//! timer with a period of at least a few thousand counts to keep them. Hue
+//! runs over the full `u8` range, 0 and 255 being red, as in QMK.
+
+#[cfg(feature = "time")]
+use embassy_time::{Duration, Ticker};
+
+use crate::timer::{self, Channel, Pwm};
+
+/// Largest value in [`GAMMA`]
+pub const DUTY_MAX: u16 = u16::MAX;
+
+/// Colour update interval of the animations, in ms
+#[cfg(feature = "time")]
+pub const UPDATE_MS: u64 = 10;
+
+/// `(i / 255)^2.5` scaled to [`DUTY_MAX`]
+pub static GAMMA: [u16; 256] = gamma_table();
+
+const fn gamma_table() -> [u16; 256] {
+    // x^2.5 = x^2 * sqrt(x), with the square root in 16.16 fixed point
+    let full = 255 * 255 * (255u64 << 32).isqrt();
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let x = i as u64;
+        table[i] = (x * x * (x << 32).isqrt() * DUTY_MAX as u64 / full) as u16;
+        i += 1;
+    }
+    table
+}
+
+const _: () = {
+    let table = gamma_table();
+    assert!(table[0] == 0 && table[255] == DUTY_MAX);
+};
+
+/// A colour as red, green and blue levels
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const BLACK: Rgb = Rgb::new(0, 0, 0);
+    pub const WHITE: Rgb = Rgb::new(255, 255, 255);
+    pub const RED: Rgb = Rgb::new(255, 0, 0);
+    pub const GREEN: Rgb = Rgb::new(0, 255, 0);
+    pub const BLUE: Rgb = Rgb::new(0, 0, 255);
+    pub const YELLOW: Rgb = Rgb::new(255, 255, 0);
+    pub const CYAN: Rgb = Rgb::new(0, 255, 255);
+    pub const MAGENTA: Rgb = Rgb::new(255, 0, 255);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    /// Every channel times `level / 255`
+    pub const fn scale(self, level: u8) -> Self {
+        Self::new(scale(self.r, level), scale(self.g, level), scale(self.b, level))
+    }
+
+    /// The colour `t / 255` of the way from `self` to `other`
+    pub const fn lerp(self, other: Rgb, t: u8) -> Self {
+        Self::new(lerp(self.r, other.r, t), lerp(self.g, other.g, t), lerp(self.b, other.b, t))
+    }
+}
+
+const fn scale(c: u8, level: u8) -> u8 {
+    (c as u16 * level as u16 / 255) as u8
+}
+
+const fn lerp(a: u8, b: u8, t: u8) -> u8 {
+    (a as i32 + (b as i32 - a as i32) * t as i32 / 255) as u8
+}
+
+/// A colour as hue, saturation and value, each over the full `u8` range
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Hsv {
+    /// 0 red, 85 green, 170 blue
+    pub h: u8,
+    pub s: u8,
+    pub v: u8,
+}
+
+impl Hsv {
+    pub const fn new(h: u8, s: u8, v: u8) -> Self {
+        Self { h, s, v }
+    }
+
+    /// Six-sector integer conversion
+    pub const fn to_rgb(self) -> Rgb {
+        let v = self.v as u16;
+        if self.s == 0 {
+            return Rgb::new(self.v, self.v, self.v);
+        }
+        // Each sector spans 256 / 6 hue steps; `rem` is the position in it, scaled to 0..=255
+        let h = self.h as u16 * 6;
+        let sector = h >> 8;
+        let rem = h & 0xFF;
+        let s = self.s as u16;
+        let p = (v * (255 - s) / 255) as u8;
+        let q = (v * (255 - s * rem / 255) / 255) as u8;
+        let t = (v * (255 - s * (255 - rem) / 255) / 255) as u8;
+        let v = self.v;
+        match sector {
+            0 => Rgb::new(v, t, p),
+            1 => Rgb::new(q, v, p),
+            2 => Rgb::new(p, v, t),
+            3 => Rgb::new(p, q, v),
+            4 => Rgb::new(t, p, v),
+            _ => Rgb::new(v, p, q),
+        }
+    }
+}
+
+impl From<Hsv> for Rgb {
+    fn from(hsv: Hsv) -> Self {
+        hsv.to_rgb()
+    }
+}
+
+/// How the LED is connected to the pins
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Wiring {
+    /// Anodes to the pins or to high-side drivers: a high pin lights the LED
+    CommonCathode,
+    /// Cathodes to the pins, anode to the supply: a low pin lights the LED
+    CommonAnode,
+}
+
+/// One RGB LED on three channels of a PWM timer
+pub struct RgbLed<'d, T: timer::Instance> {
+    pwm: Pwm<'d, T>,
+    channels: [Channel; 3],
+    wiring: Wiring,
+    color: Rgb,
+    brightness: u8,
+}
+
+impl<'d, T: timer::Instance> RgbLed<'d, T> {
+    /// Take over three channels of `pwm` and turn the LED off
+    ///
+    /// `pwm` must already run at its final frequency, well above 100 Hz.
+    pub fn new(mut pwm: Pwm<'d, T>, red: Channel, green: Channel, blue: Channel, wiring: Wiring) -> Self {
+        let channels = [red, green, blue];
+        for channel in channels {
+            pwm.enable_channel(channel);
+        }
+        let mut led = Self {
+            pwm,
+            channels,
+            wiring,
+            color: Rgb::BLACK,
+            brightness: 255,
+        };
+        led.update();
+        led
+    }
+
+    /// Show `color`
+    pub fn set(&mut self, color: impl Into<Rgb>) {
+        self.color = color.into();
+        self.update();
+    }
+
+    /// The colour last set, before brightness scaling
+    pub fn color(&self) -> Rgb {
+        self.color
+    }
+
+    /// Scale every colour, 255 for full; a user setting
+    pub fn set_brightness(&mut self, brightness: u8) {
+        self.brightness = brightness;
+        self.update();
+    }
+
+    /// Turn the LED off
+    pub fn off(&mut self) {
+        self.set(Rgb::BLACK);
+    }
+
+    /// Release the PWM, with the LED off
+    pub fn into_pwm(mut self) -> Pwm<'d, T> {
+        self.off();
+        self.pwm
+    }
+
+    fn update(&mut self) {
+        let color = self.color.scale(self.brightness);
+        for (channel, level) in self.channels.into_iter().zip([color.r, color.g, color.b]) {
+            let duty = match self.wiring {
+                Wiring::CommonCathode => GAMMA[level as usize],
+                Wiring::CommonAnode => DUTY_MAX - GAMMA[level as usize],
+            };
+            self.pwm.set_duty_cycle(channel, duty, DUTY_MAX);
+        }
+    }
+}
+
+#[cfg(feature = "time")]
+impl<T: timer::Instance> RgbLed<'_, T> {
+    /// Move from the current colour to `target` over `duration`
+    ///
+    /// Cancelling leaves the LED at the colour reached so far.
+    pub async fn fade_to(&mut self, target: impl Into<Rgb>, duration: Duration) {
+        let target = target.into();
+        let from = self.color;
+        let updates = duration.as_millis().div_ceil(UPDATE_MS).max(1);
+        let mut ticker = Ticker::every(Duration::from_millis(UPDATE_MS));
+        for i in 1..=updates {
+            ticker.next().await;
+            self.set(from.lerp(target, (i * 255 / updates) as u8));
+        }
+    }
+
+    /// Pulse `color` up from off and back down once every `period`, forever
+    pub async fn breathe(&mut self, color: impl Into<Rgb>, period: Duration) -> ! {
+        let color = color.into();
+        let half = period / 2;
+        self.set(Rgb::BLACK);
+        loop {
+            self.fade_to(color, half).await;
+            self.fade_to(Rgb::BLACK, half).await;
+        }
+    }
+
+    /// Run through every hue at `saturation` and `value` once every `period`, forever
+    pub async fn rainbow(&mut self, saturation: u8, value: u8, period: Duration) -> ! {
+        let updates = period.as_millis().div_ceil(UPDATE_MS).max(1);
+        let mut ticker = Ticker::every(Duration::from_millis(UPDATE_MS));
+        loop {
+            for i in 0..updates {
+                self.set(Hsv::new((i * 256 / updates) as u8, saturation, value));
+                ticker.next().await;
+            }
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! Panic-time safe state registry
+//!
+//! Drivers and applications register actions that put outputs into a safe
+//! state (heater pin low, PWM off, USB disconnected). A panic or fault handler
+//! calls [`enter`] before halting so power electronics are never left driven.
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::{safe_state, timer};
+//!
+//! safe_state::register(safe_state::Action::PinLow { port: 'B', pin: 3 }).unwrap();
+//! safe_state::register(safe_state::pwm_off::<timer::Timer1>()).unwrap();
+//!
+//! #[panic_handler]
+//! fn panic(_info: &core::panic::PanicInfo) -> ! {
+//!     safe_state::enter();
+//!     loop {
+//!         cortex_m::asm::wfi();
+//!     }
+//! }
+//! ```
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use critical_section::Mutex;
+
+use crate::gpio::AnyPin;
+use crate::timer;
+
+/// Maximum number of registered actions
+pub const CAPACITY: usize = 8;
+
+/// Something to do on the way to the safe state
+#[derive(Debug, Copy, Clone)]
+pub enum Action {
+    /// Drive a GPIO output low
+    PinLow { port: char, pin: u8 },
+    /// Drive a GPIO output high
+    PinHigh { port: char, pin: u8 },
+    /// Remove the D+ pull-up so the host sees a disconnect
+    #[cfg(feature = "usb")]
+    UsbDisconnect,
+    /// Call a function; it must not panic or block
+    Custom(fn()),
+}
+
+impl Action {
+    /// Drive `pin` low
+    pub fn pin_low(pin: &AnyPin) -> Self {
+        Action::PinLow { port: pin.port(), pin: pin.pin() }
+    }
+
+    /// Drive `pin` high
+    pub fn pin_high(pin: &AnyPin) -> Self {
+        Action::PinHigh { port: pin.port(), pin: pin.pin() }
+    }
+
+    fn run(self) {
+        use embedded_hal::digital::OutputPin;
+
+        match self {
+            Action::PinLow { port, pin } => {
+                let _ = AnyPin::new(port, pin).set_low();
+            }
+            Action::PinHigh { port, pin } => {
+                let _ = AnyPin::new(port, pin).set_high();
+            }
+            #[cfg(feature = "usb")]
+            Action::UsbDisconnect => crate::usb::emergency_disconnect(),
+            Action::Custom(f) => f(),
+        }
+    }
+}
+
+/// Disable all PWM channel outputs of timer `T` and stop its counter
+pub fn pwm_off<T: timer::Instance>() -> Action {
+    fn stop<T: timer::Instance>() {
+        let regs = T::regs();
+        regs.gptm_chctr().write(|w| unsafe { w.bits(0) });
+        regs.gptm_ctr().modify(|_, w| w.tme().clear_bit());
+    }
+    Action::Custom(stop::<T>)
+}
+
+/// The registry already holds `CAPACITY` actions
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RegistryFull;
+
+impl core::fmt::Display for RegistryFull {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("safe state registry full")
+    }
+}
+
+impl core::error::Error for RegistryFull {}
+
+static ACTIONS: Mutex<RefCell<[Option<Action>; CAPACITY]>> = Mutex::new(RefCell::new([None; CAPACITY]));
+static ENTERED: AtomicBool = AtomicBool::new(false);
+
+/// Register an action to run from [`enter`]
+///
+/// Actions run in registration order.
+pub fn register(action: Action) -> Result<(), RegistryFull> {
+    critical_section::with(|cs| {
+        let mut actions = ACTIONS.borrow_ref_mut(cs);
+        let slot = actions.iter_mut().find(|slot| slot.is_none()).ok_or(RegistryFull)?;
+        *slot = Some(action);
+        Ok(())
+    })
+}
+
+/// Remove every registered action
+pub fn clear() {
+    critical_section::with(|cs| {
+        *ACTIONS.borrow_ref_mut(cs) = [None; CAPACITY];
+    });
+}
+
+/// Disable interrupts and run all registered actions
+///
+/// Only the first call does anything, so it is safe to call from both the
+/// panic handler and a fault handler. Interrupts stay disabled afterwards.
+pub fn enter() {
+    cortex_m::interrupt::disable();
+
+    if ENTERED.load(Ordering::Relaxed) {
+        return;
+    }
+    ENTERED.store(true, Ordering::Relaxed);
+
+    // Interrupts are off, so this is the only context; a panic inside
+    // `register` may still hold the borrow, in which case nothing can be run
+    let actions = critical_section::with(|cs| ACTIONS.borrow(cs).try_borrow().map(|a| *a));
+    if let Ok(actions) = actions {
+        for action in actions.iter().flatten() {
+            action.run();
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Oscilloscope service over USB CDC-ACM
+//!
+//! [`Scope`] streams samples to a host over a CDC-ACM port: an
+//! [`AdcStream`](crate::adc::AdcStream) channel for analog traces. It
+//! doubles as a soak test of sustained bulk IN throughput, since at 40 kHz
+//! the device sends 80 kB/s without pause.
+//!
+//! # Protocol
+//!
+//! The host sends 8-byte commands, all integers little-endian:
+//!
+//! ```text
+//! 'S', source, channel, 0, rate: u32    start (source 0 = ADC)
+//! 'X', 0, 0, 0, 0, 0, 0, 0              stop
+//! ```
+//!
+//! While running, the device answers with one frame per block:
+//!
+//! ```text
+//! magic: u16 = 0x5C0E, sequence: u16, count: u16, flags: u16, samples: [u16; count]
+//! ```
+//!
+//! `sequence` counts blocks, so a gap means the host missed frames;
+//! `flags` bit 0 marks the first block after the device itself dropped
+//! samples ([`Error::Overrun`](crate::adc::Error::Overrun)). `examples/usb-scope/plot.py`
+//! is a matching host plotter.
+
+use embassy_futures::select::{select, Either};
+use embassy_usb::class::cdc_acm::{Receiver, Sender};
+use embassy_usb::driver::EndpointError;
+
+use crate::adc::{self, Adc, AdcStream};
+use crate::time::Hertz;
+use crate::timer;
+use crate::usb::Driver;
+
+/// Frame header magic
+pub const FRAME_MAGIC: u16 = 0x5C0E;
+/// Frame flag: samples were dropped before this block
+pub const FLAG_OVERRUN: u16 = 1 << 0;
+/// Highest accepted sample rate
+pub const MAX_RATE_HZ: u32 = 100_000;
+
+const COMMAND_START: u8 = b'S';
+const COMMAND_STOP: u8 = b'X';
+const SOURCE_ADC: u8 = 0;
+const HEADER_SIZE: usize = 8;
+
+/// Throughput counters
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ScopeStats {
+    /// Frames sent
+    pub frames: u32,
+    /// Payload bytes sent, headers included
+    pub bytes: u32,
+    /// Blocks lost to overruns on the device
+    pub overruns: u32,
+}
+
+/// Capture request parsed from a start command
+#[derive(Copy, Clone)]
+struct Start {
+    channel: u8,
+    rate: u32,
+}
+
+/// CDC-ACM scope service, sending `N` samples per frame
+pub struct Scope<'d, const N: usize> {
+    sender: Sender<'d, Driver<'d>>,
+    receiver: Receiver<'d, Driver<'d>>,
+    stats: ScopeStats,
+}
+
+impl<'d, const N: usize> Scope<'d, N> {
+    /// Serve on a CDC-ACM class with 64-byte packets
+    pub fn new(sender: Sender<'d, Driver<'d>>, receiver: Receiver<'d, Driver<'d>>) -> Self {
+        Self {
+            sender,
+            receiver,
+            stats: ScopeStats::default(),
+        }
+    }
+
+    /// Counters so far
+    pub fn stats(&self) -> ScopeStats {
+        self.stats
+    }
+
+    /// Serve commands forever, sampling with `adc` paced by `timer`
+    ///
+    /// `buf` is the ping-pong buffer of the ADC stream.
+    pub async fn run<T: timer::Instance>(&mut self, adc: &mut Adc, timer: &mut T, buf: &mut [[u16; N]; 2]) -> ! {
+        loop {
+            self.receiver.wait_connection().await;
+            info!("scope: host connected");
+
+            let mut start = None;
+            loop {
+                let request = match start.take() {
+                    Some(request) => request,
+                    None => match self.wait_start().await {
+                        Some(request) => request,
+                        None => break,
+                    },
+                };
+                match self.stream(AdcStream::start(adc, timer, request.channel, Hertz(request.rate), buf)).await {
+                    Ok(next) => start = next,
+                    Err(EndpointError::Disabled) => break,
+                    Err(EndpointError::BufferOverflow) => {}
+                }
+            }
+
+            info!("scope: host disconnected, {} frames sent", self.stats.frames);
+        }
+    }
+
+    /// Wait for a valid start command; `None` once the host disconnects
+    async fn wait_start(&mut self) -> Option<Start> {
+        let mut command = [0u8; 64];
+        loop {
+            match self.receiver.read_packet(&mut command).await {
+                Ok(n) => {
+                    if let Some(start) = parse_start(&command[..n]) {
+                        return Some(start);
+                    }
+                }
+                Err(EndpointError::BufferOverflow) => {}
+                Err(EndpointError::Disabled) => return None,
+            }
+        }
+    }
+
+    /// Send frames until a stop command (`Ok(None)`), a new start (`Ok(Some)`) or a disconnect
+    async fn stream<T: timer::Instance>(&mut self, mut stream: AdcStream<'_, T, N>) -> Result<Option<Start>, EndpointError> {
+        debug!("scope: streaming {} samples per frame", N);
+        let mut sequence = 0u16;
+        let mut flags = 0;
+        let mut command = [0u8; 64];
+
+        loop {
+            let block = match select(stream.next_block(), self.receiver.read_packet(&mut command)).await {
+                Either::First(Ok(block)) => block,
+                Either::First(Err(adc::Error::Overrun)) => {
+                    self.stats.overruns += 1;
+                    flags |= FLAG_OVERRUN;
+                    continue;
+                }
+                Either::First(Err(adc::Error::Transfer)) => {
+                    warn!("scope: DMA error, stopping");
+                    return Ok(None);
+                }
+                Either::Second(Ok(n)) => match command[..n].first() {
+                    Some(&COMMAND_STOP) => return Ok(None),
+                    _ => match parse_start(&command[..n]) {
+                        Some(start) => return Ok(Some(start)),
+                        None => continue,
+                    },
+                },
+                Either::Second(Err(e)) => return Err(e),
+            };
+
+            let mut header = [0u8; HEADER_SIZE];
+            header[0..2].copy_from_slice(&FRAME_MAGIC.to_le_bytes());
+            header[2..4].copy_from_slice(&sequence.to_le_bytes());
+            header[4..6].copy_from_slice(&(N as u16).to_le_bytes());
+            header[6..8].copy_from_slice(&flags.to_le_bytes());
+            send_frame(&mut self.sender, &header, block).await?;
+
+            self.stats.frames += 1;
+            self.stats.bytes = self.stats.bytes.wrapping_add((HEADER_SIZE + 2 * N) as u32);
+            sequence = sequence.wrapping_add(1);
+            flags = 0;
+        }
+    }
+}
+
+fn parse_start(command: &[u8]) -> Option<Start> {
+    match *command {
+        [COMMAND_START, SOURCE_ADC, channel, _, r0, r1, r2, r3] => {
+            let rate = u32::from_le_bytes([r0, r1, r2, r3]);
+            if rate == 0 || rate > MAX_RATE_HZ {
+                warn!("scope: rate {} Hz out of range", rate);
+                return None;
+            }
+            Some(Start { channel, rate })
+        }
+        _ => None,
+    }
+}
+
+/// Send header and samples packed into full-size packets
+async fn send_frame(sender: &mut Sender<'_, Driver<'_>>, header: &[u8], samples: &[u16]) -> Result<(), EndpointError> {
+    let max = sender.max_packet_size() as usize;
+    let mut packet = [0u8; 64];
+    let mut len = 0;
+
+    let bytes = header.iter().copied().chain(samples.iter().flat_map(|s| s.to_le_bytes()));
+    for byte in bytes {
+        packet[len] = byte;
+        len += 1;
+        if len == max.min(packet.len()) {
+            sender.write_packet(&packet[..len]).await?;
+            len = 0;
+        }
+    }
+    if len > 0 {
+        sender.write_packet(&packet[..len]).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,294 @@
+//! Boot-time hardware self-test
+//!
+//! [`run`] checks the parts of a board that production QA cares about and
+//! returns the [`Failures`] as a bitmask, for the application to report over
+//! whatever channel it has (LED blink code, USB, UART):
+//!
+//! - RAM: a march test over a scratch buffer the caller provides; the stack
+//!   and statics in use cannot be tested in place.
+//! - Flash: CRC-32 of the application image against the expected value,
+//!   typically appended by the release build.
+//! - Clock: the system clock measured against a 32.768 kHz LSE crystal, if
+//!   one is fitted; a missing crystal counts as skipped, not failed.
+//! - USB SRAM: a pattern test of EP_SRAM (`usb` feature), which must run
+//!   before the USB driver is created.
+//!
+//! The fastest reliable SPI clock needs a MOSI to MISO jumper and so is not
+//! part of [`run`]; see [`Spi::verify_max_frequency`](crate::spi::Spi::verify_max_frequency).
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::selftest::{self, ImageCheck};
+//!
+//! let mut scratch = [0u32; 256];
+//! let failures = selftest::run(&mut p.flash, &mut p.crc, selftest::Config {
+//!     ram: Some(&mut scratch),
+//!     image: Some(ImageCheck { len: IMAGE_LEN, crc: IMAGE_CRC }),
+//!     ..Default::default()
+//! });
+//! if !failures.is_empty() {
+//!     report(failures.bits());
+//! }
+//! ```
+//!
+//! The RTC and SysTick are borrowed for the clock check and left stopped and
+//! restored respectively.
+
+use embedded_storage::nor_flash::ReadNorFlash;
+
+use crate::crc::Crc;
+use crate::flash::Flash;
+use crate::regs::{Mmio, RegisterAccess};
+
+const RTC_BASE: usize = 0x4006_A000;
+const RTC_CNT: usize = 0x000;
+const RTC_CR: usize = 0x008;
+
+// RTCCR bits
+const RTCCR_RTCEN: u32 = 1 << 0;
+const RTCCR_RTCSRC_LSE: u32 = 1 << 1;
+const RTCCR_RPRE_MASK: u32 = 0xF << 8;
+
+const SYST_CSR: usize = 0xE000_E010;
+const SYST_RVR: usize = 0xE000_E014;
+const SYST_CVR: usize = 0xE000_E018;
+const SYST_CSR_ENABLE_CORE: u32 = 0b101;
+const SYST_MAX: u32 = 0x00FF_FFFF;
+
+/// LSE frequency
+const LSE_HZ: u32 = 32_768;
+/// LSE ticks per clock measurement, 1/32 s
+const MEASURE_TICKS: u32 = 1024;
+
+/// Set of failed checks
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Failures(u8);
+
+impl Failures {
+    /// Nothing failed
+    pub const NONE: Failures = Failures(0);
+    /// RAM pattern mismatch
+    pub const RAM: Failures = Failures(1 << 0);
+    /// Image CRC mismatch
+    pub const FLASH: Failures = Failures(1 << 1);
+    /// System clock outside [`Config::clock_tolerance_ppm`] of the LSE
+    pub const CLOCK: Failures = Failures(1 << 2);
+    /// EP_SRAM pattern mismatch
+    pub const USB_SRAM: Failures = Failures(1 << 3);
+
+    /// Whether every check passed
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether every failure in `other` is in `self`
+    pub const fn contains(self, other: Failures) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Raw bitmask, bit 0 = RAM ... bit 3 = USB SRAM
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+}
+
+impl core::ops::BitOr for Failures {
+    type Output = Failures;
+
+    fn bitor(self, rhs: Failures) -> Failures {
+        Failures(self.0 | rhs.0)
+    }
+}
+
+impl core::ops::BitOrAssign for Failures {
+    fn bitor_assign(&mut self, rhs: Failures) {
+        self.0 |= rhs.0;
+    }
+}
+
+/// Expected CRC-32 of the image at the start of flash
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ImageCheck {
+    /// Image length in bytes, from flash address 0
+    pub len: u32,
+    /// CRC-32 (IEEE) of those bytes
+    pub crc: u32,
+}
+
+/// What to test
+pub struct Config<'a> {
+    /// Scratch RAM for the march test; `None` skips it
+    pub ram: Option<&'a mut [u32]>,
+    /// Image to checksum; `None` skips it
+    pub image: Option<ImageCheck>,
+    /// Measure the system clock against the LSE if it starts
+    pub clock: bool,
+    /// Allowed system clock error
+    pub clock_tolerance_ppm: u32,
+    /// How long to wait for the LSE crystal to start, in ms
+    pub lse_timeout_ms: u32,
+    /// Test EP_SRAM; only before the USB driver exists
+    #[cfg(feature = "usb")]
+    pub usb_sram: bool,
+}
+
+impl Default for Config<'_> {
+    fn default() -> Self {
+        Self {
+            ram: None,
+            image: None,
+            clock: true,
+            // HSI is trimmed to 2 %, an HSE crystal is far better
+            clock_tolerance_ppm: 20_000,
+            // 32 kHz crystals take up to a couple of seconds to start
+            lse_timeout_ms: 2000,
+            #[cfg(feature = "usb")]
+            usb_sram: true,
+        }
+    }
+}
+
+/// Run the configured checks
+pub fn run(flash: &mut Flash, crc: &mut Crc, config: Config<'_>) -> Failures {
+    let mut failures = Failures::NONE;
+
+    if config.ram.is_some_and(|ram| !march(ram)) {
+        warn!("selftest: RAM pattern mismatch");
+        failures |= Failures::RAM;
+    }
+
+    if let Some(image) = config.image {
+        let crc = image_crc(flash, crc, image.len);
+        if crc != Some(image.crc) {
+            warn!("selftest: image CRC {:#x}, expected {:#x}", crc.unwrap_or(0), image.crc);
+            failures |= Failures::FLASH;
+        }
+    }
+
+    if config.clock {
+        match measure_clock_ppm(config.lse_timeout_ms) {
+            Some(ppm) if ppm.unsigned_abs() > config.clock_tolerance_ppm => {
+                warn!("selftest: system clock off by {} ppm", ppm);
+                failures |= Failures::CLOCK;
+            }
+            Some(ppm) => debug!("selftest: system clock within {} ppm", ppm),
+            None => debug!("selftest: no LSE, clock check skipped"),
+        }
+    }
+
+    #[cfg(feature = "usb")]
+    if config.usb_sram && !crate::usb::sram_selftest() {
+        warn!("selftest: EP_SRAM pattern mismatch");
+        failures |= Failures::USB_SRAM;
+    }
+
+    info!("selftest: failures {:#x}", failures.0);
+    failures
+}
+
+/// March C- over `ram`; leaves it zeroed
+fn march(ram: &mut [u32]) -> bool {
+    let cells = ram.as_mut_ptr();
+    let len = ram.len();
+    let read = |i: usize| unsafe { core::ptr::read_volatile(cells.add(i)) };
+    let write = |i: usize, v: u32| unsafe { core::ptr::write_volatile(cells.add(i), v) };
+
+    for i in 0..len {
+        write(i, 0);
+    }
+    // Up: r0 w1, up: r1 w0, down: r0 w1, down: r1 w0, then r0
+    for i in 0..len {
+        if read(i) != 0 {
+            return false;
+        }
+        write(i, !0);
+    }
+    for i in 0..len {
+        if read(i) != !0 {
+            return false;
+        }
+        write(i, 0);
+    }
+    for i in (0..len).rev() {
+        if read(i) != 0 {
+            return false;
+        }
+        write(i, !0);
+    }
+    for i in (0..len).rev() {
+        if read(i) != !0 {
+            return false;
+        }
+        write(i, 0);
+    }
+    (0..len).all(|i| read(i) == 0)
+}
+
+/// CRC-32 of the first `len` bytes of flash; `None` if `len` is out of range
+fn image_crc(flash: &mut Flash, crc: &mut Crc, len: u32) -> Option<u32> {
+    let mut chunk = [0u8; 64];
+    crc.reset();
+    let mut offset = 0;
+    while offset < len {
+        let n = (len - offset).min(chunk.len() as u32) as usize;
+        flash.read(offset, &mut chunk[..n]).ok()?;
+        crc.feed(&chunk[..n]);
+        offset += n as u32;
+    }
+    Some(crc.finish())
+}
+
+/// System clock error against the LSE in ppm; `None` if the LSE does not start
+///
+/// Counts core cycles with SysTick over [`MEASURE_TICKS`] LSE periods.
+pub fn measure_clock_ppm(lse_timeout_ms: u32) -> Option<i32> {
+    let sys_hz = crate::rcc::get_clocks().sys_clk().to_hz();
+    if !crate::rcc::start_lse(lse_timeout_ms) {
+        return None;
+    }
+
+    // RTC counting raw LSE periods
+    Mmio.modify(RTC_BASE + RTC_CR, |v| (v & !RTCCR_RPRE_MASK) | RTCCR_RTCSRC_LSE | RTCCR_RTCEN);
+
+    let saved_csr = Mmio.read(SYST_CSR);
+    let saved_rvr = Mmio.read(SYST_RVR);
+    Mmio.write(SYST_RVR, SYST_MAX);
+    Mmio.write(SYST_CVR, 0);
+    Mmio.write(SYST_CSR, SYST_CSR_ENABLE_CORE);
+
+    let cycles = critical_section::with(|_| {
+        // Start on a tick edge so the window is whole periods
+        let first = wait_rtc_change(Mmio.read(RTC_BASE + RTC_CNT));
+        let start = Mmio.read(SYST_CVR);
+        let mut elapsed = 0u32;
+        let mut last = start;
+        let mut count = first;
+        while count.wrapping_sub(first) < MEASURE_TICKS {
+            count = wait_rtc_change(count);
+            // SysTick counts down and wraps at 24 bits
+            let now = Mmio.read(SYST_CVR);
+            elapsed += last.wrapping_sub(now) & SYST_MAX;
+            last = now;
+        }
+        elapsed
+    });
+
+    Mmio.write(SYST_CSR, saved_csr & !SYST_CSR_ENABLE_CORE);
+    Mmio.write(SYST_RVR, saved_rvr);
+    Mmio.write(SYST_CVR, 0);
+    Mmio.write(SYST_CSR, saved_csr);
+    Mmio.modify(RTC_BASE + RTC_CR, |v| v & !RTCCR_RTCEN);
+
+    let expected = sys_hz as u64 * MEASURE_TICKS as u64 / LSE_HZ as u64;
+    Some(((cycles as i64 - expected as i64) * 1_000_000 / expected as i64) as i32)
+}
+
+/// Busy-wait until the RTC counter moves on from `count`
+fn wait_rtc_change(count: u32) -> u32 {
+    loop {
+        let now = Mmio.read(RTC_BASE + RTC_CNT);
+        if now != count {
+            return now;
+        }
+    }
+}
@@ -0,0 +1,401 @@
+//! Bit-banged I2C master
+//!
+//! [`SoftI2c`] drives two GPIOs as open-drain SCL/SDA and paces every edge
+//! from a free-running BFTM, so the timing holds regardless of code size or
+//! optimisation level. It implements the same `embedded-hal` and
+//! `embedded-hal-async` `I2c` traits as a hardware driver, so sensor code can
+//! be written against it now and swapped over later, or run against both for
+//! A/B comparison.
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::soft_i2c::{Config, SoftI2c};
+//!
+//! let mut i2c = SoftI2c::new(p.bftm0, p.gpiob.pb0().degrade(), p.gpiob.pb1().degrade(), Config::fast());
+//! let mut id = [0u8; 1];
+//! i2c.write_read(0x76, &[0xD0], &mut id).await?;
+//! ```
+//!
+//! - Standard (100 kHz) and fast (400 kHz) mode low/high times and the
+//!   start, stop and bus-free times are met for any requested frequency.
+//! - Devices may stretch the clock for up to [`Config::stretch_timeout_us`].
+//! - Losing SDA to another master is reported as arbitration loss.
+//! - 7-bit addresses only.
+//!
+//! The pins use their internal pull-ups, which are only strong enough for
+//! short, slow buses; fit external pull-ups (4.7 kΩ at 100 kHz, 2.2 kΩ at
+//! 400 kHz) for anything else.
+//!
+//! Transfers busy-wait. The async implementation yields to other tasks after
+//! every byte, while SCL is held low, but a byte itself (about 25 µs at
+//! 400 kHz) runs with the CPU spinning.
+
+use embedded_hal::i2c::{ErrorKind, NoAcknowledgeSource, Operation, SevenBitAddress};
+
+use crate::gpio::AnyPin;
+use crate::i2c::Mode;
+use crate::peripheral::{Peri, Peripheral, PeripheralType};
+use crate::time::Hertz;
+use crate::timer::BftmInstance;
+
+// BFTMCR bits
+const CR_CEN: u32 = 1 << 2;
+
+/// Bus clocks a stuck device may need to finish the byte it is sending
+const RECOVERY_CLOCKS: u32 = 9;
+
+/// I2C error
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// No device acknowledged the address
+    AddressNack,
+    /// The device did not acknowledge a written byte
+    DataNack,
+    /// SDA was low while this master released it
+    ArbitrationLost,
+    /// SCL was held low longer than the stretch timeout
+    Timeout,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Error::AddressNack => "address not acknowledged",
+            Error::DataNack => "data not acknowledged",
+            Error::ArbitrationLost => "arbitration lost",
+            Error::Timeout => "clock stretch timeout",
+        })
+    }
+}
+
+impl core::error::Error for Error {}
+
+impl embedded_hal::i2c::Error for Error {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Error::AddressNack => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address),
+            Error::DataNack => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data),
+            Error::ArbitrationLost => ErrorKind::ArbitrationLoss,
+            Error::Timeout => ErrorKind::Bus,
+        }
+    }
+}
+
+/// Bus configuration
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// SCL frequency, at most 400 kHz
+    pub frequency: Hertz,
+    /// Longest a device may hold SCL low, in µs
+    pub stretch_timeout_us: u32,
+}
+
+impl Config {
+    /// Fast mode, 400 kHz
+    pub const fn fast() -> Self {
+        Self {
+            frequency: Hertz::khz(400),
+            stretch_timeout_us: 25_000,
+        }
+    }
+}
+
+impl Default for Config {
+    /// Standard mode, 100 kHz; the SMBus timeout of 25 ms for stretching
+    fn default() -> Self {
+        Self {
+            frequency: Hertz::khz(100),
+            stretch_timeout_us: 25_000,
+        }
+    }
+}
+
+/// Edge timing in BFTM ticks
+#[derive(Copy, Clone)]
+struct Timing {
+    /// SCL low; also the bus-free time between stop and start
+    low: u32,
+    /// SCL high; also the start/stop setup and hold times
+    high: u32,
+    stretch_timeout: u32,
+}
+
+impl Timing {
+    fn new(config: &Config, tick_hz: u32) -> Self {
+        let hz = config.frequency.to_hz().clamp(1, 400_000);
+        // Minimum tLOW/tHIGH, which also cover tBUF/tSU;STA
+        let mode = Mode::for_frequency(hz).unwrap_or(Mode::Fast);
+        let (min_low, min_high) = (mode.min_low_ns(), mode.min_high_ns());
+        let ticks = |ns: u32| (ns as u64 * tick_hz as u64).div_ceil(1_000_000_000) as u32;
+
+        let period = tick_hz.div_ceil(hz);
+        let low = (period / 2).max(ticks(min_low));
+        let high = period.saturating_sub(low).max(ticks(min_high));
+        Self {
+            low,
+            high,
+            stretch_timeout: (config.stretch_timeout_us as u64 * tick_hz as u64 / 1_000_000) as u32,
+        }
+    }
+}
+
+/// Bit-banged I2C master on two GPIOs, paced by a BFTM
+pub struct SoftI2c<'d, T: BftmInstance + PeripheralType> {
+    _bftm: Peri<'d, T>,
+    scl: AnyPin,
+    sda: AnyPin,
+    timing: Timing,
+    /// BFTM count at the last edge, which the next one is timed from
+    edge: u32,
+}
+
+impl<'d, T: BftmInstance + PeripheralType> SoftI2c<'d, T> {
+    /// Take over `bftm` as the time base and release both lines
+    pub fn new(bftm: impl Peripheral<P = T> + 'd, mut scl: AnyPin, mut sda: AnyPin, config: Config) -> Self {
+        scl.set_as_open_drain();
+        sda.set_as_open_drain();
+
+        T::enable_clock();
+        let regs = T::regs();
+        regs.bftm_cr().write(|w| unsafe { w.bits(0) });
+        regs.bftm_cmpr().write(|w| unsafe { w.bits(u32::MAX) });
+        regs.bftm_cntr().write(|w| unsafe { w.bits(0) });
+        regs.bftm_sr().write(|w| unsafe { w.bits(0) });
+        regs.bftm_cr().write(|w| unsafe { w.bits(CR_CEN) });
+
+        let tick_hz = crate::rcc::get_clocks().apb_clk().to_hz();
+        let mut i2c = Self {
+            _bftm: bftm.into_ref(),
+            scl,
+            sda,
+            timing: Timing::new(&config, tick_hz),
+            edge: 0,
+        };
+        i2c.edge = i2c.now();
+        i2c.recover();
+        i2c
+    }
+
+    /// Change the bus frequency or stretch timeout
+    pub fn set_config(&mut self, config: &Config) {
+        let tick_hz = crate::rcc::get_clocks().apb_clk().to_hz();
+        self.timing = Timing::new(config, tick_hz);
+    }
+
+    /// Free a bus left with SDA held low by a device interrupted mid-byte
+    ///
+    /// Clocks SCL until the device lets go of SDA, then sends a stop.
+    pub fn recover(&mut self) {
+        let mut clocks = 0;
+        while !self.sda.level() && clocks < RECOVERY_CLOCKS {
+            self.scl.pull_low();
+            self.wait(self.timing.low);
+            // A device still stretching after the timeout is beyond help here
+            let _ = self.scl_high();
+            self.wait(self.timing.high);
+            clocks += 1;
+        }
+        if clocks > 0 {
+            warn!("soft_i2c: bus recovery took {} clocks", clocks);
+            self.scl.pull_low();
+            self.sda.pull_low();
+            self.wait(self.timing.low);
+            let _ = self.stop();
+        }
+    }
+
+    fn now(&self) -> u32 {
+        T::regs().bftm_cntr().read().bits()
+    }
+
+    /// Wait until `ticks` after the previous edge, which becomes this one
+    fn wait(&mut self, ticks: u32) {
+        while self.now().wrapping_sub(self.edge) < ticks {}
+        self.edge = self.edge.wrapping_add(ticks);
+        // Fell behind, e.g. an interrupt ran: time the next edge from now
+        if self.now().wrapping_sub(self.edge) > ticks {
+            self.edge = self.now();
+        }
+    }
+
+    /// Release SCL and wait for it to rise, honouring clock stretching
+    fn scl_high(&mut self) -> Result<(), Error> {
+        self.scl.release();
+        if self.scl.level() {
+            return Ok(());
+        }
+        let start = self.now();
+        while !self.scl.level() {
+            if self.now().wrapping_sub(start) > self.timing.stretch_timeout {
+                warn!("soft_i2c: SCL held low");
+                return Err(Error::Timeout);
+            }
+        }
+        // Stretched: the high time counts from the rising edge, not the release
+        self.edge = self.now();
+        Ok(())
+    }
+
+    /// Start, or repeated start with SCL low on entry
+    fn start(&mut self) -> Result<(), Error> {
+        self.sda.release();
+        self.wait(self.timing.low);
+        self.scl_high()?;
+        self.wait(self.timing.high);
+        if !self.sda.level() {
+            return Err(Error::ArbitrationLost);
+        }
+        self.sda.pull_low();
+        self.wait(self.timing.high);
+        self.scl.pull_low();
+        Ok(())
+    }
+
+    /// Stop with SCL low on entry; leaves the bus free
+    fn stop(&mut self) -> Result<(), Error> {
+        self.sda.pull_low();
+        self.wait(self.timing.low);
+        self.scl_high()?;
+        self.wait(self.timing.high);
+        self.sda.release();
+        // Bus-free time before the next start
+        self.wait(self.timing.low);
+        Ok(())
+    }
+
+    /// Clock one bit out with SCL low on entry and exit; returns the line level
+    fn bit(&mut self, high: bool) -> Result<bool, Error> {
+        if high {
+            self.sda.release();
+        } else {
+            self.sda.pull_low();
+        }
+        self.wait(self.timing.low);
+        self.scl_high()?;
+        self.wait(self.timing.high);
+        let level = self.sda.level();
+        self.scl.pull_low();
+        Ok(level)
+    }
+
+    /// Send a byte; returns whether it was acknowledged
+    fn write_byte(&mut self, byte: u8) -> Result<bool, Error> {
+        for i in (0..8).rev() {
+            let high = byte & (1 << i) != 0;
+            if self.bit(high)? != high {
+                self.sda.release();
+                return Err(Error::ArbitrationLost);
+            }
+        }
+        let nack = self.bit(true)?;
+        Ok(!nack)
+    }
+
+    /// Receive a byte and acknowledge it if `ack`
+    fn read_byte(&mut self, ack: bool) -> Result<u8, Error> {
+        let mut byte = 0;
+        for _ in 0..8 {
+            byte = (byte << 1) | self.bit(true)? as u8;
+        }
+        self.bit(!ack)?;
+        Ok(byte)
+    }
+
+    /// Run `operations`; with `cooperative`, yield after every byte
+    async fn run(&mut self, address: u8, operations: &mut [Operation<'_>], cooperative: bool) -> Result<(), Error> {
+        let result = self.run_inner(address, operations, cooperative).await;
+        match result {
+            // Another master owns the bus; leave the lines alone
+            Err(Error::ArbitrationLost) => self.sda.release(),
+            // A timeout leaves SCL wherever the device holds it
+            Err(Error::Timeout) => {
+                self.sda.release();
+                self.scl.release();
+            }
+            _ => self.stop()?,
+        }
+        result
+    }
+
+    async fn run_inner(&mut self, address: u8, operations: &mut [Operation<'_>], cooperative: bool) -> Result<(), Error> {
+        // SCL rests high between transactions; bring it low as `start` expects
+        self.edge = self.now();
+        self.start_from_idle()?;
+
+        let mut previous_read = None;
+        for i in 0..operations.len() {
+            // A read that runs into a repeated start or stop ends on a NACK
+            let next_is_read = matches!(operations.get(i + 1), Some(Operation::Read(_)));
+            let operation = &mut operations[i];
+            let read = matches!(operation, Operation::Read(_));
+            if previous_read != Some(read) {
+                if previous_read.is_some() {
+                    self.start()?;
+                }
+                if !self.write_byte((address << 1) | read as u8)? {
+                    return Err(Error::AddressNack);
+                }
+                previous_read = Some(read);
+            }
+            match operation {
+                Operation::Write(bytes) => {
+                    for &byte in bytes.iter() {
+                        if !self.write_byte(byte)? {
+                            return Err(Error::DataNack);
+                        }
+                        if cooperative {
+                            embassy_futures::yield_now().await;
+                        }
+                    }
+                }
+                Operation::Read(buffer) => {
+                    let len = buffer.len();
+                    for (j, byte) in buffer.iter_mut().enumerate() {
+                        let last = j + 1 == len && !next_is_read;
+                        *byte = self.read_byte(!last)?;
+                        if cooperative {
+                            embassy_futures::yield_now().await;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Start condition from an idle bus
+    fn start_from_idle(&mut self) -> Result<(), Error> {
+        if !self.scl.level() || !self.sda.level() {
+            return Err(Error::ArbitrationLost);
+        }
+        self.sda.pull_low();
+        self.wait(self.timing.high);
+        self.scl.pull_low();
+        Ok(())
+    }
+}
+
+impl<T: BftmInstance + PeripheralType> Drop for SoftI2c<'_, T> {
+    fn drop(&mut self) {
+        T::regs().bftm_cr().write(|w| unsafe { w.bits(0) });
+        self.scl.release();
+        self.sda.release();
+    }
+}
+
+impl<T: BftmInstance + PeripheralType> embedded_hal::i2c::ErrorType for SoftI2c<'_, T> {
+    type Error = Error;
+}
+
+impl<T: BftmInstance + PeripheralType> embedded_hal::i2c::I2c<SevenBitAddress> for SoftI2c<'_, T> {
+    fn transaction(&mut self, address: u8, operations: &mut [Operation<'_>]) -> Result<(), Error> {
+        // Nothing awaits without `cooperative`, so this completes on the first poll
+        embassy_futures::block_on(self.run(address, operations, false))
+    }
+}
+
+impl<T: BftmInstance + PeripheralType> embedded_hal_async::i2c::I2c<SevenBitAddress> for SoftI2c<'_, T> {
+    async fn transaction(&mut self, address: u8, operations: &mut [Operation<'_>]) -> Result<(), Error> {
+        self.run(address, operations, true).await
+    }
+}
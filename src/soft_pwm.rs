@@ -0,0 +1,257 @@
+//! Software PWM on plain GPIOs
+//!
+//! When every GPTM channel is taken (typically by the backlight), status LEDs
+//! can still be dimmed by [`SoftPwm`]: BFTM0 interrupts at each duty
+//! threshold and the handler drives the affected pins through the port
+//! set/reset registers. Each period costs at most `N + 1` interrupts rather
+//! than one per duty step. The handler is registered in the BFTM0 slot of
+//! [`vectors`](crate::vectors) while a `SoftPwm` exists, so it needs the
+//! `rt` feature and no `#[interrupt] fn BFTM0` in the application.
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::soft_pwm::SoftPwm;
+//! use embassy_ht32f523xx::time::Hertz;
+//!
+//! let leds = [p.gpioc.pc14().degrade(), p.gpioc.pc15().degrade()];
+//! let mut leds = SoftPwm::new(p.bftm0, leds, Hertz::khz(1));
+//! leds.set_duty(0, 32);
+//! leds.set_duty(1, 255);
+//! ```
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+
+use crate::gpio::{self, AnyPin, Level};
+use crate::time::Hertz;
+use crate::timer::{Bftm0, BftmInstance};
+use crate::vectors;
+
+/// Maximum number of pins driven by one [`SoftPwm`]
+pub const MAX_CHANNELS: usize = 8;
+
+/// Number of duty steps per period
+const STEPS: u32 = 256;
+
+/// Number of GPIO ports (A..=D)
+const PORTS: usize = 4;
+
+// BFTMCR bits
+const CR_MIEN: u32 = 1 << 0;
+const CR_CEN: u32 = 1 << 2;
+
+/// Per-port pin masks
+type PortMasks = [u16; PORTS];
+
+fn port_index(port: char) -> usize {
+    (port as u8 - b'A') as usize
+}
+
+/// One period worth of pin edges
+///
+/// `steps[0]` starts at the period boundary, where every pin in `set` is
+/// driven high and every pin in `reset` low. Each following step starts by
+/// clearing its `clear` pins.
+#[derive(Copy, Clone)]
+struct Schedule {
+    set: PortMasks,
+    reset: PortMasks,
+    /// (duty threshold, pins to clear when it is reached)
+    steps: [(u8, PortMasks); MAX_CHANNELS],
+    len: usize,
+}
+
+impl Schedule {
+    fn build(pins: &[(char, u8)], duty: &[u8]) -> Self {
+        let mut schedule = Schedule {
+            set: [0; PORTS],
+            reset: [0; PORTS],
+            steps: [(0, [0; PORTS]); MAX_CHANNELS],
+            len: 0,
+        };
+
+        for (&(port, pin), &duty) in pins.iter().zip(duty) {
+            let port = port_index(port);
+            let mask = 1 << pin;
+            match duty {
+                0 => schedule.reset[port] |= mask,
+                255 => schedule.set[port] |= mask,
+                duty => {
+                    schedule.set[port] |= mask;
+                    schedule.insert(duty, port, mask);
+                }
+            }
+        }
+
+        schedule
+    }
+
+    /// Add a falling edge at `duty`, keeping steps sorted and distinct
+    fn insert(&mut self, duty: u8, port: usize, mask: u16) {
+        let at = self.steps[..self.len].iter().position(|&(d, _)| d >= duty).unwrap_or(self.len);
+        if at < self.len && self.steps[at].0 == duty {
+            self.steps[at].1[port] |= mask;
+            return;
+        }
+        self.steps.copy_within(at..self.len, at + 1);
+        let mut clear = [0; PORTS];
+        clear[port] = mask;
+        self.steps[at] = (duty, clear);
+        self.len += 1;
+    }
+
+    /// Duty threshold at which step `index` ends
+    fn end_of(&self, index: usize) -> u32 {
+        if index < self.len {
+            self.steps[index].0 as u32
+        } else {
+            STEPS
+        }
+    }
+}
+
+struct State {
+    active: Schedule,
+    /// Picked up at the next period boundary so a change never truncates a pulse
+    pending: Option<Schedule>,
+    /// Index of the next edge in `active`; `len` means the period end
+    step: usize,
+    /// BFTM ticks per duty step
+    ticks_per_step: u32,
+}
+
+static STATE: Mutex<RefCell<Option<State>>> = Mutex::new(RefCell::new(None));
+
+fn apply(set: &PortMasks, reset: &PortMasks) {
+    for (index, (&set, &reset)) in set.iter().zip(reset).enumerate() {
+        if set | reset != 0 {
+            gpio::write_port((b'A' + index as u8) as char, set, reset);
+        }
+    }
+}
+
+/// Program the compare value for the interval `from..to` duty steps
+fn arm(state: &State, from: u32, to: u32) {
+    let ticks = (to - from) * state.ticks_per_step;
+    Bftm0::regs().bftm_cmpr().write(|w| unsafe { w.bits(ticks - 1) });
+}
+
+/// BFTM0 interrupt handler body
+pub(crate) fn on_interrupt() {
+    let bftm = Bftm0::regs();
+    // MIF is cleared by writing 0
+    bftm.bftm_sr().write(|w| unsafe { w.bits(0) });
+
+    critical_section::with(|cs| {
+        let mut state = STATE.borrow_ref_mut(cs);
+        let Some(state) = state.as_mut() else {
+            return;
+        };
+
+        let from = if state.step < state.active.len {
+            // Falling edge for every pin whose duty ends here
+            let (duty, clear) = state.active.steps[state.step];
+            apply(&[0; PORTS], &clear);
+            state.step += 1;
+            duty as u32
+        } else {
+            // Period boundary
+            if let Some(pending) = state.pending.take() {
+                state.active = pending;
+            }
+            apply(&state.active.set, &state.active.reset);
+            state.step = 0;
+            0
+        };
+
+        let to = state.active.end_of(state.step);
+        arm(state, from, to);
+    });
+}
+
+/// Software PWM on up to [`MAX_CHANNELS`] GPIOs with 8-bit duty
+///
+/// Duty 0 holds a pin low and 255 holds it high, so those settings cost no
+/// interrupts. Other values produce `duty / 256` high time.
+pub struct SoftPwm<const N: usize> {
+    pins: [(char, u8); N],
+    duty: [u8; N],
+}
+
+impl<const N: usize> SoftPwm<N> {
+    /// Start driving `pins` at `frequency`, all at duty 0
+    ///
+    /// The PWM frequency is limited by interrupt load; around 1 kHz suits
+    /// LEDs. Panics if more than [`MAX_CHANNELS`] pins are given.
+    pub fn new(_bftm: Bftm0, pins: [AnyPin; N], frequency: Hertz) -> Self {
+        assert!(N <= MAX_CHANNELS, "SoftPwm supports at most {} pins", MAX_CHANNELS);
+
+        let pins = pins.map(|mut pin| {
+            pin.set_as_output(Level::Low);
+            (pin.port(), pin.pin())
+        });
+        let duty = [0; N];
+
+        let pclk = crate::rcc::get_clocks().apb_clk().to_hz();
+        let ticks_per_step = (pclk / (frequency.to_hz() * STEPS)).max(1);
+
+        Bftm0::enable_clock();
+        let bftm = Bftm0::regs();
+        bftm.bftm_cr().write(|w| unsafe { w.bits(0) });
+        bftm.bftm_cntr().write(|w| unsafe { w.bits(0) });
+        bftm.bftm_sr().write(|w| unsafe { w.bits(0) });
+
+        critical_section::with(|cs| {
+            let active = Schedule::build(&pins, &duty);
+            let state = State {
+                // The first match is treated as a period boundary
+                step: active.len,
+                active,
+                pending: None,
+                ticks_per_step,
+            };
+            arm(&state, 0, 1);
+            *STATE.borrow_ref_mut(cs) = Some(state);
+        });
+
+        bftm.bftm_cr().write(|w| unsafe { w.bits(CR_MIEN | CR_CEN) });
+        vectors::set_handler::<vectors::Bftm0>(Some(on_interrupt));
+
+        Self { pins, duty }
+    }
+
+    /// Number of channels
+    pub const fn channels(&self) -> usize {
+        N
+    }
+
+    /// Set the duty of `channel`; takes effect at the next period
+    pub fn set_duty(&mut self, channel: usize, duty: u8) {
+        self.duty[channel] = duty;
+        let schedule = Schedule::build(&self.pins, &self.duty);
+        critical_section::with(|cs| {
+            if let Some(state) = STATE.borrow_ref_mut(cs).as_mut() {
+                state.pending = Some(schedule);
+            }
+        });
+    }
+
+    /// Current duty of `channel`
+    pub fn duty(&self, channel: usize) -> u8 {
+        self.duty[channel]
+    }
+}
+
+impl<const N: usize> Drop for SoftPwm<N> {
+    fn drop(&mut self) {
+        vectors::set_handler::<vectors::Bftm0>(None);
+        Bftm0::regs().bftm_cr().write(|w| unsafe { w.bits(0) });
+        critical_section::with(|cs| STATE.borrow_ref_mut(cs).take());
+
+        let mut reset = [0; PORTS];
+        for &(port, pin) in &self.pins {
+            reset[port_index(port)] |= 1 << pin;
+        }
+        apply(&[0; PORTS], &reset);
+    }
+}
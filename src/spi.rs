@@ -0,0 +1,907 @@
+//! SPI (Serial Peripheral Interface) master driver
+//!
+//! Supports SPI0 and SPI1 in master mode with 8-bit frames. [`Spi`] is the
+//! bus and implements `embedded_hal_async::spi::SpiBus`; chip select belongs
+//! to the devices on it.
+//!
+//! To share one bus between several chips, put it in a mutex and give each
+//! chip a device, which locks the bus, applies its own [`DeviceConfig`] and
+//! frames every transaction with its chip select:
+//!
+//! ```rust,ignore
+//! use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+//! use embassy_sync::mutex::Mutex;
+//! use embassy_ht32f523xx::spi::{DeviceConfig, SelSpiDevice, Spi, SpiDevice};
+//!
+//! let bus: Mutex<NoopRawMutex, _> = Mutex::new(Spi::new(p.spi0, sck, mosi, miso, Default::default()));
+//! // Any GPIO as chip select
+//! let mut flash = SpiDevice::new(&bus, cs, DeviceConfig::default());
+//! // The instance's own SEL pin (AF5), driven by the SPI block
+//! let mut adc = SelSpiDevice::new(&bus, sel, DeviceConfig { cs_setup_ns: 500, ..Default::default() });
+//! ```
+//!
+//! Both implement `embedded_hal_async::spi::SpiDevice`, so drivers written
+//! against the trait work with either.
+//!
+//! How fast SCK can go depends on the board as much as the chip. With MOSI
+//! jumpered to MISO, [`Spi::verify_max_frequency`] steps the prescaler up
+//! from the configured rate and reads back a test pattern at each step. The
+//! fastest rate that passes is kept for [`Spi::max_verified_hz`], and
+//! [`Spi::set_config`] warns about faster settings from then on:
+//!
+//! ```rust,ignore
+//! let mut spi = Spi::new(p.spi0, sck, mosi, miso, Default::default());
+//! if let Some(max) = spi.verify_max_frequency().await {
+//!     info!("SPI0 verified up to {} Hz", max.to_hz());
+//! }
+//! ```
+
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::waitqueue::AtomicWaker;
+use embedded_hal::digital::OutputPin;
+use embedded_hal_async::spi::Operation;
+
+use crate::drop::DropGuard;
+use crate::gpio::{mode, Pin};
+use crate::pac::{Spi0 as Spi0Pac, Spi1 as Spi1Pac};
+use crate::peripheral::{Peri, Peripheral, PeripheralType};
+use crate::time::Hertz;
+
+// SPICR0 bits
+const CR0_SPIEN: u32 = 1 << 0;
+const CR0_TXDMAE: u32 = 1 << 1;
+const CR0_RXDMAE: u32 = 1 << 2;
+const CR0_SELOEN: u32 = 1 << 3;
+const CR0_SSELC: u32 = 1 << 4;
+
+// SPICR1 bits
+const CR1_DFL_8BIT: u32 = 8;
+const CR1_FORMAT_SHIFT: u32 = 8;
+const CR1_SELAP: u32 = 1 << 11;
+const CR1_MODE_MASTER: u32 = 1 << 14;
+
+/// Bytes read back per prescaler step by [`Spi::verify_max_frequency`]
+const VERIFY_LEN: usize = 64;
+/// Times the pattern is sent per prescaler step
+const VERIFY_ROUNDS: u8 = 4;
+
+// SPISR bits
+const SR_TXBE: u32 = 1 << 0;
+const SR_RXBNE: u32 = 1 << 2;
+const SR_RO: u32 = 1 << 4;
+const SR_MF: u32 = 1 << 5;
+const SR_BUSY: u32 = 1 << 8;
+
+/// SPI error
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// RX buffer overrun
+    Overrun,
+    /// Mode fault (SEL driven low by another master)
+    ModeFault,
+    /// A `*_timeout` operation did not finish in time
+    Timeout,
+    /// The PDMA channel reported a bus error
+    Dma,
+}
+
+impl From<crate::dma::Error> for Error {
+    fn from(_: crate::dma::Error) -> Self {
+        Error::Dma
+    }
+}
+
+impl embedded_hal::spi::Error for Error {
+    fn kind(&self) -> embedded_hal::spi::ErrorKind {
+        match self {
+            Error::Overrun => embedded_hal::spi::ErrorKind::Overrun,
+            Error::ModeFault => embedded_hal::spi::ErrorKind::ModeFault,
+            Error::Timeout | Error::Dma => embedded_hal::spi::ErrorKind::Other,
+        }
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Error::Overrun => "RX overrun",
+            Error::ModeFault => "mode fault",
+            Error::Timeout => "timed out",
+            Error::Dma => "DMA transfer error",
+        })
+    }
+}
+
+impl core::error::Error for Error {}
+
+/// SPI mode (clock polarity and phase)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Mode {
+    /// CPOL = 0, CPHA = 0
+    Mode0,
+    /// CPOL = 0, CPHA = 1
+    Mode1,
+    /// CPOL = 1, CPHA = 0
+    Mode2,
+    /// CPOL = 1, CPHA = 1
+    Mode3,
+}
+
+impl Mode {
+    /// SPICR1 FORMAT field value
+    fn format(self) -> u32 {
+        match self {
+            Mode::Mode0 => 0b001,
+            Mode::Mode1 => 0b010,
+            Mode::Mode2 => 0b101,
+            Mode::Mode3 => 0b110,
+        }
+    }
+}
+
+/// SPI configuration
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// SCK frequency; the closest frequency not above this is used
+    pub frequency: Hertz,
+    /// Clock polarity and phase
+    pub mode: Mode,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            frequency: Hertz::mhz(1),
+            mode: Mode::Mode0,
+        }
+    }
+}
+
+/// SPI SCK pin trait
+pub trait SckPin<T> {}
+
+/// SPI MOSI pin trait
+pub trait MosiPin<T> {}
+
+/// SPI MISO pin trait
+pub trait MisoPin<T> {}
+
+/// SPI SEL (hardware chip select) pin trait
+pub trait SelPin<T> {}
+
+// SPI signals are on AF5; the caller picks a pin that is routed to the instance
+impl<T: Instance, const PORT: char, const PIN: u8> SckPin<T> for Pin<PORT, PIN, mode::AF5> {}
+impl<T: Instance, const PORT: char, const PIN: u8> MosiPin<T> for Pin<PORT, PIN, mode::AF5> {}
+impl<T: Instance, const PORT: char, const PIN: u8> MisoPin<T> for Pin<PORT, PIN, mode::AF5> {}
+impl<T: Instance, const PORT: char, const PIN: u8> SelPin<T> for Pin<PORT, PIN, mode::AF5> {}
+
+/// SPI instance trait
+pub trait Instance: PeripheralType {
+    /// Get the SPI register block
+    fn regs() -> &'static crate::pac::spi0::RegisterBlock;
+
+    /// Get the waker
+    fn waker() -> &'static AtomicWaker;
+
+    /// Fastest SCK in Hz that passed [`Spi::verify_max_frequency`], 0 if not run
+    fn verified_hz() -> &'static AtomicU32;
+
+    /// Enable SPI clock
+    fn enable_clock();
+
+    /// PDMA channel wired to the RX request
+    const DMA_RX: usize;
+
+    /// PDMA channel wired to the TX request
+    const DMA_TX: usize;
+
+    /// Name the PDMA channels are claimed under
+    const NAME: &'static str;
+}
+
+/// SPI0 instance
+pub struct Spi0 {
+    _private: (),
+}
+
+impl Spi0 {
+    pub(crate) fn new() -> Self {
+        Self { _private: () }
+    }
+}
+
+impl_peripheral!(Spi0, Spi1);
+
+impl Instance for Spi0 {
+    fn regs() -> &'static crate::pac::spi0::RegisterBlock {
+        unsafe { &*Spi0Pac::ptr() }
+    }
+
+    fn waker() -> &'static AtomicWaker {
+        static WAKER: AtomicWaker = AtomicWaker::new();
+        &WAKER
+    }
+
+    fn verified_hz() -> &'static AtomicU32 {
+        static HZ: AtomicU32 = AtomicU32::new(0);
+        &HZ
+    }
+
+    fn enable_clock() {
+        let ckcu = unsafe { &*crate::pac::Ckcu::ptr() };
+        ckcu.apbccr0().modify(|_, w| w.spi0en().set_bit());
+    }
+
+    const DMA_RX: usize = 0;
+    const DMA_TX: usize = 1;
+    const NAME: &'static str = "SPI0";
+}
+
+/// SPI1 instance
+pub struct Spi1 {
+    _private: (),
+}
+
+impl Spi1 {
+    pub(crate) fn new() -> Self {
+        Self { _private: () }
+    }
+}
+
+impl Instance for Spi1 {
+    fn regs() -> &'static crate::pac::spi0::RegisterBlock {
+        unsafe { &*Spi1Pac::ptr() }
+    }
+
+    fn waker() -> &'static AtomicWaker {
+        static WAKER: AtomicWaker = AtomicWaker::new();
+        &WAKER
+    }
+
+    fn verified_hz() -> &'static AtomicU32 {
+        static HZ: AtomicU32 = AtomicU32::new(0);
+        &HZ
+    }
+
+    fn enable_clock() {
+        let ckcu = unsafe { &*crate::pac::Ckcu::ptr() };
+        ckcu.apbccr0().modify(|_, w| w.spi1en().set_bit());
+    }
+
+    const DMA_RX: usize = 2;
+    const DMA_TX: usize = 3;
+    const NAME: &'static str = "SPI1";
+}
+
+/// SPI master driver
+pub struct Spi<'d, T: Instance> {
+    _spi: Peri<'d, T>,
+    /// Holds the PDMA channels, see [`with_dma`](Self::with_dma)
+    dma: bool,
+}
+
+impl<'d, T: Instance> Spi<'d, T> {
+    /// Create a new SPI master instance
+    ///
+    /// The SPI and pins may be borrowed, see [`peripheral`](crate::peripheral).
+    pub fn new<SCK: SckPin<T>, MOSI: MosiPin<T>, MISO: MisoPin<T>>(
+        spi: impl Peripheral<P = T> + 'd,
+        _sck: impl Peripheral<P = SCK> + 'd,
+        _mosi: impl Peripheral<P = MOSI> + 'd,
+        _miso: impl Peripheral<P = MISO> + 'd,
+        config: Config,
+    ) -> Self {
+        // Enable clock
+        T::enable_clock();
+
+        let regs = T::regs();
+
+        // Disable SPI while configuring
+        regs.spi_spicr0().write(|w| unsafe { w.bits(0) });
+
+        let mut this = Self { _spi: spi.into_ref(), dma: false };
+        this.set_config(&config);
+
+        // Enable SPI
+        regs.spi_spicr0().write(|w| unsafe { w.bits(CR0_SPIEN) });
+
+        this
+    }
+
+    /// Claim the instance's RX/TX PDMA channels for [`read_dma`](Self::read_dma)
+    /// and [`write_dma`](Self::write_dma) until the driver is dropped
+    ///
+    /// # Panics
+    ///
+    /// If another driver holds either channel, e.g. an ADC stream on SPI0 RX.
+    pub fn with_dma(mut self) -> Self {
+        crate::dma::init();
+        crate::dma::claim(T::DMA_RX, T::NAME);
+        crate::dma::claim(T::DMA_TX, T::NAME);
+        self.dma = true;
+        self
+    }
+
+    /// Change frequency and mode; call between transfers
+    pub fn set_config(&mut self, config: &Config) {
+        let regs = T::regs();
+
+        // SCK = PCLK / (2 * (CP + 1))
+        let pclk = crate::rcc::get_clocks().apb_clk().to_hz();
+        let div = pclk.div_ceil(2 * config.frequency.to_hz()).max(1);
+        regs.spi_spicpr().write(|w| unsafe { w.bits((div - 1).min(0xFFFF)) });
+
+        let verified = T::verified_hz().load(Ordering::Relaxed);
+        if verified != 0 && pclk / (2 * div) > verified {
+            warn!("spi: {} Hz is above the verified {} Hz", pclk / (2 * div), verified);
+        }
+
+        // Master, MSB first, 8-bit frames; keep the SEL polarity
+        regs.spi_spicr1().modify(|r, w| unsafe {
+            w.bits((r.bits() & CR1_SELAP) | CR1_MODE_MASTER | (config.mode.format() << CR1_FORMAT_SHIFT) | CR1_DFL_8BIT)
+        });
+    }
+
+    /// Wait until the last frame has been shifted out
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        core::future::poll_fn(|cx| {
+            if Self::is_idle() {
+                core::task::Poll::Ready(())
+            } else {
+                cx.waker().wake_by_ref();
+                core::task::Poll::Pending
+            }
+        })
+        .await;
+        Self::check_errors()
+    }
+
+    fn check_errors() -> Result<(), Error> {
+        let sr = T::regs().spi_spisr().read().bits();
+        if sr & SR_RO != 0 {
+            // Reading SPISR then SPIDR clears the overrun flag
+            let _ = T::regs().spi_spidr().read().bits();
+            return Err(Error::Overrun);
+        }
+        if sr & SR_MF != 0 {
+            return Err(Error::ModeFault);
+        }
+        Ok(())
+    }
+
+    /// Start sending a byte if the TX buffer is empty
+    fn try_write(byte: u8) -> nb::Result<(), Error> {
+        Self::check_errors()?;
+        if T::regs().spi_spisr().read().bits() & SR_TXBE != 0 {
+            T::regs().spi_spidr().write(|w| unsafe { w.bits(byte as u32) });
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    /// Take a received byte if there is one
+    fn try_read() -> nb::Result<u8, Error> {
+        Self::check_errors()?;
+        if T::regs().spi_spisr().read().bits() & SR_RXBNE != 0 {
+            Ok(T::regs().spi_spidr().read().bits() as u8)
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    /// Exchange one byte asynchronously
+    ///
+    /// If dropped after the byte went out, the reply is discarded so the next
+    /// exchange does not read it.
+    async fn transfer_byte(&mut self, byte: u8) -> Result<u8, Error> {
+        let waker = T::waker();
+
+        core::future::poll_fn(|cx| {
+            waker.register(cx.waker());
+
+            match Self::try_write(byte) {
+                Ok(()) => core::task::Poll::Ready(Ok(())),
+                Err(nb::Error::WouldBlock) => {
+                    // A byte takes a few cycles at typical SCK rates; poll again rather than wait for an IRQ
+                    cx.waker().wake_by_ref();
+                    core::task::Poll::Pending
+                }
+                Err(nb::Error::Other(e)) => core::task::Poll::Ready(Err(e)),
+            }
+        }).await?;
+
+        let guard = DropGuard::new(Self::discard_rx);
+        let result = core::future::poll_fn(|cx| {
+            waker.register(cx.waker());
+
+            match Self::try_read() {
+                Ok(byte) => core::task::Poll::Ready(Ok(byte)),
+                Err(nb::Error::WouldBlock) => {
+                    cx.waker().wake_by_ref();
+                    core::task::Poll::Pending
+                }
+                Err(nb::Error::Other(e)) => core::task::Poll::Ready(Err(e)),
+            }
+        }).await;
+        guard.defuse();
+        result
+    }
+
+    /// Send `write` while receiving into `read`; the shorter buffer is padded with 0x00 / ignored
+    pub async fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Error> {
+        for i in 0..read.len().max(write.len()) {
+            let byte = self.transfer_byte(write.get(i).copied().unwrap_or(0)).await?;
+            if let Some(slot) = read.get_mut(i) {
+                *slot = byte;
+            }
+        }
+        Ok(())
+    }
+
+    /// Send and receive in place
+    pub async fn transfer_in_place(&mut self, data: &mut [u8]) -> Result<(), Error> {
+        for byte in data.iter_mut() {
+            *byte = self.transfer_byte(*byte).await?;
+        }
+        Ok(())
+    }
+
+    /// Send a buffer, discarding received data
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        for &byte in data {
+            self.transfer_byte(byte).await?;
+        }
+        Ok(())
+    }
+
+    /// Receive into a buffer, sending 0x00
+    pub async fn read(&mut self, data: &mut [u8]) -> Result<(), Error> {
+        for byte in data.iter_mut() {
+            *byte = self.transfer_byte(0).await?;
+        }
+        Ok(())
+    }
+
+    /// Receive into a buffer with PDMA, sending 0x00
+    ///
+    /// Runs at full SCK rate instead of one poll per byte, for bulk reads such
+    /// as SPI flash data. Without [`with_dma`](Self::with_dma) this is
+    /// [`read`](Self::read).
+    pub async fn read_dma(&mut self, data: &mut [u8]) -> Result<(), Error> {
+        static FILL: u8 = 0;
+        if !self.dma {
+            return self.read(data).await;
+        }
+        self.exchange_dma(data.as_mut_ptr(), true, &FILL, false, data.len()).await
+    }
+
+    /// Send a buffer with PDMA, discarding received data
+    ///
+    /// The counterpart of [`read_dma`](Self::read_dma) for frame buffers and
+    /// other bulk writes, falling back to [`write`](Self::write) the same way.
+    /// Returns once the last byte has been shifted out.
+    pub async fn write_dma(&mut self, data: &[u8]) -> Result<(), Error> {
+        static SINK: AtomicU8 = AtomicU8::new(0);
+        if !self.dma {
+            return self.write(data).await;
+        }
+        self.exchange_dma(SINK.as_ptr(), false, data.as_ptr(), true, data.len()).await
+    }
+
+    /// Move `len` bytes each way with PDMA, in chunks of at most 0xFFFF
+    ///
+    /// A side that is not incremented stays on its one byte. Completion is
+    /// taken from the RX channel, which finishes after the last byte is
+    /// shifted in.
+    async fn exchange_dma(
+        &mut self,
+        rx_buf: *mut u8,
+        rx_inc: bool,
+        tx_buf: *const u8,
+        tx_inc: bool,
+        len: usize,
+    ) -> Result<(), Error> {
+        let regs = T::regs();
+        let dr = regs.spi_spidr().as_ptr() as *mut u8;
+        let mut rx = crate::dma::Channel::new(T::DMA_RX);
+        let mut tx = crate::dma::Channel::new(T::DMA_TX);
+
+        Self::discard_rx();
+
+        let guard = DropGuard::new(|| {
+            crate::dma::Channel::new(T::DMA_TX).stop();
+            crate::dma::Channel::new(T::DMA_RX).stop();
+            T::regs().spi_spicr0().modify(|r, w| unsafe { w.bits(r.bits() & !(CR0_RXDMAE | CR0_TXDMAE)) });
+            Self::discard_rx();
+        });
+
+        let mut result = Ok(());
+        let mut offset = 0;
+        while offset < len {
+            let count = (len - offset).min(0xFFFF);
+            let rx_ptr = if rx_inc { rx_buf.wrapping_add(offset) } else { rx_buf };
+            let tx_ptr = if tx_inc { tx_buf.wrapping_add(offset) } else { tx_buf };
+            rx.start_paced(dr, rx_ptr, count, crate::dma::Width::Byte, false, rx_inc);
+            tx.start_paced(tx_ptr, dr, count, crate::dma::Width::Byte, tx_inc, false);
+            regs.spi_spicr0().modify(|r, w| unsafe { w.bits(r.bits() | CR0_RXDMAE | CR0_TXDMAE) });
+
+            result = core::future::poll_fn(|cx| match rx.poll() {
+                Some(done) => core::task::Poll::Ready(done),
+                None => {
+                    // No PDMA interrupt is wired up; poll like the byte path does
+                    cx.waker().wake_by_ref();
+                    core::task::Poll::Pending
+                }
+            })
+            .await
+            .map_err(Error::from)
+            .and_then(|()| Self::check_errors());
+
+            regs.spi_spicr0().modify(|r, w| unsafe { w.bits(r.bits() & !(CR0_RXDMAE | CR0_TXDMAE)) });
+            rx.stop();
+            tx.stop();
+            if result.is_err() {
+                break;
+            }
+            offset += count;
+        }
+
+        guard.defuse();
+        result
+    }
+
+    /// Drop a byte left in the RX buffer by an abandoned exchange
+    fn discard_rx() {
+        while !Self::is_idle() {}
+        while T::regs().spi_spisr().read().bits() & SR_RXBNE != 0 {
+            let _ = T::regs().spi_spidr().read().bits();
+        }
+    }
+
+    fn is_idle() -> bool {
+        T::regs().spi_spisr().read().bits() & SR_BUSY == 0
+    }
+
+    /// Run `op` with a deadline; a cancelled exchange cleans up after itself
+    #[cfg(feature = "time")]
+    async fn with_timeout<R>(
+        timeout: embassy_time::Duration,
+        op: impl core::future::Future<Output = Result<R, Error>>,
+    ) -> Result<R, Error> {
+        embassy_time::with_timeout(timeout, op).await.unwrap_or(Err(Error::Timeout))
+    }
+
+    /// [`transfer`](Self::transfer) with a timeout
+    #[cfg(feature = "time")]
+    pub async fn transfer_timeout(
+        &mut self,
+        read: &mut [u8],
+        write: &[u8],
+        timeout: embassy_time::Duration,
+    ) -> Result<(), Error> {
+        Self::with_timeout(timeout, self.transfer(read, write)).await
+    }
+
+    /// [`write`](Self::write) with a timeout
+    #[cfg(feature = "time")]
+    pub async fn write_timeout(&mut self, data: &[u8], timeout: embassy_time::Duration) -> Result<(), Error> {
+        Self::with_timeout(timeout, self.write(data)).await
+    }
+
+    /// [`read`](Self::read) with a timeout
+    #[cfg(feature = "time")]
+    pub async fn read_timeout(&mut self, data: &mut [u8], timeout: embassy_time::Duration) -> Result<(), Error> {
+        Self::with_timeout(timeout, self.read(data)).await
+    }
+
+    /// Exchange one byte, busy-waiting
+    #[cfg(feature = "blocking")]
+    fn blocking_transfer_byte(&mut self, byte: u8) -> Result<u8, Error> {
+        nb::block!(Self::try_write(byte))?;
+        nb::block!(Self::try_read())
+    }
+
+    /// Send `write` while receiving into `read`, busy-waiting
+    #[cfg(feature = "blocking")]
+    pub fn blocking_transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Error> {
+        for i in 0..read.len().max(write.len()) {
+            let byte = self.blocking_transfer_byte(write.get(i).copied().unwrap_or(0))?;
+            if let Some(slot) = read.get_mut(i) {
+                *slot = byte;
+            }
+        }
+        Ok(())
+    }
+
+    /// Send and receive in place, busy-waiting
+    #[cfg(feature = "blocking")]
+    pub fn blocking_transfer_in_place(&mut self, data: &mut [u8]) -> Result<(), Error> {
+        for byte in data.iter_mut() {
+            *byte = self.blocking_transfer_byte(*byte)?;
+        }
+        Ok(())
+    }
+
+    /// Send a buffer, busy-waiting
+    #[cfg(feature = "blocking")]
+    pub fn blocking_write(&mut self, data: &[u8]) -> Result<(), Error> {
+        for &byte in data {
+            self.blocking_transfer_byte(byte)?;
+        }
+        Ok(())
+    }
+
+    /// Find the fastest SCK at which a MOSI to MISO loopback reads back correctly
+    ///
+    /// Needs MOSI jumpered to MISO and no device selected on the bus. Starts
+    /// at the configured rate and lowers the prescaler value, halving it down
+    /// to 8 and then one step at a time, sending a test pattern four times at
+    /// each setting. Stops at the first setting that fails. Returns the last rate that passed, which is
+    /// also kept for [`max_verified_hz`](Self::max_verified_hz), or `None` if
+    /// the configured rate already fails. The configured rate is restored
+    /// afterwards.
+    pub async fn verify_max_frequency(&mut self) -> Option<Hertz> {
+        let regs = T::regs();
+        let pclk = crate::rcc::get_clocks().apb_clk().to_hz();
+        let configured = regs.spi_spicpr().read().bits() & 0xFFFF;
+
+        let mut verified = None;
+        let mut cp = configured;
+        loop {
+            regs.spi_spicpr().write(|w| unsafe { w.bits(cp) });
+            let hz = pclk / (2 * (cp + 1));
+            if !self.loopback_passes().await {
+                debug!("spi: loopback fails at {} Hz", hz);
+                break;
+            }
+            verified = Some(Hertz::hz(hz));
+            if cp == 0 {
+                break;
+            }
+            cp = if cp > 8 { cp / 2 } else { cp - 1 };
+        }
+
+        regs.spi_spicpr().write(|w| unsafe { w.bits(configured) });
+        T::verified_hz().store(verified.map_or(0, Hertz::to_hz), Ordering::Relaxed);
+        match verified {
+            Some(hz) => info!("spi: loopback verified up to {} Hz", hz.to_hz()),
+            None => warn!("spi: loopback fails at the configured rate, check the MOSI-MISO jumper"),
+        }
+        verified
+    }
+
+    /// Send the test pattern and compare what comes back
+    async fn loopback_passes(&mut self) -> bool {
+        for round in 0..VERIFY_ROUNDS {
+            let mut data = [0u8; VERIFY_LEN];
+            for (i, byte) in data.iter_mut().enumerate() {
+                // Alternating bits for the fastest edges, then a counter for the rest
+                let pattern = match i % 4 {
+                    0 => 0x55,
+                    1 => 0xAA,
+                    2 => i as u8,
+                    _ => !(i as u8),
+                };
+                *byte = pattern ^ round;
+            }
+            let sent = data;
+            if self.transfer_in_place(&mut data).await.is_err() || data != sent {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Fastest SCK in Hz that passed [`verify_max_frequency`](Self::verify_max_frequency)
+    ///
+    /// `None` until the check has run and passed at least once on this instance.
+    pub fn max_verified_hz(&self) -> Option<u32> {
+        match T::verified_hz().load(Ordering::Relaxed) {
+            0 => None,
+            hz => Some(hz),
+        }
+    }
+
+    /// Check whether a frame is still being shifted out
+    pub fn is_busy(&self) -> bool {
+        !Self::is_idle()
+    }
+}
+
+impl<T: Instance> Drop for Spi<'_, T> {
+    fn drop(&mut self) {
+        if self.dma {
+            crate::dma::release(T::DMA_RX, T::NAME);
+            crate::dma::release(T::DMA_TX, T::NAME);
+        }
+    }
+}
+
+impl<T: Instance> embedded_hal::spi::ErrorType for Spi<'_, T> {
+    type Error = Error;
+}
+
+impl<T: Instance> embedded_hal_async::spi::SpiBus for Spi<'_, T> {
+    async fn read(&mut self, words: &mut [u8]) -> Result<(), Error> {
+        Spi::read(self, words).await
+    }
+
+    async fn write(&mut self, words: &[u8]) -> Result<(), Error> {
+        Spi::write(self, words).await
+    }
+
+    async fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Error> {
+        Spi::transfer(self, read, write).await
+    }
+
+    async fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Error> {
+        Spi::transfer_in_place(self, words).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        Spi::flush(self).await
+    }
+}
+
+/// Per-device settings on a shared bus
+#[derive(Debug, Clone)]
+pub struct DeviceConfig {
+    /// Bus frequency and mode, applied at the start of every transaction
+    pub bus: Config,
+    /// Delay from asserting CS to the first SCK edge, in ns
+    pub cs_setup_ns: u32,
+    /// Delay from the last SCK edge to releasing CS, in ns
+    pub cs_hold_ns: u32,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self {
+            bus: Config::default(),
+            cs_setup_ns: 0,
+            cs_hold_ns: 0,
+        }
+    }
+}
+
+/// Busy-wait at least `ns` nanoseconds; CS timings are far below a scheduler tick
+fn delay_ns(ns: u32) {
+    if ns == 0 {
+        return;
+    }
+    let sys_mhz = crate::rcc::get_clocks().sys_clk().to_hz() / 1_000_000;
+    cortex_m::asm::delay(ns.saturating_mul(sys_mhz).div_ceil(1000).max(1));
+}
+
+/// Run `operations` on a locked bus; CS is already asserted
+async fn run_operations<T: Instance>(bus: &mut Spi<'_, T>, operations: &mut [Operation<'_, u8>]) -> Result<(), Error> {
+    for op in operations {
+        match op {
+            Operation::Read(buf) => bus.read(buf).await?,
+            Operation::Write(buf) => bus.write(buf).await?,
+            Operation::Transfer(read, write) => bus.transfer(read, write).await?,
+            Operation::TransferInPlace(buf) => bus.transfer_in_place(buf).await?,
+            Operation::DelayNs(ns) => {
+                bus.flush().await?;
+                delay_ns(*ns);
+            }
+        }
+    }
+    bus.flush().await
+}
+
+/// Device on a shared bus with a GPIO chip select
+///
+/// CS is active low and driven by any `OutputPin`; initialise the pin high.
+pub struct SpiDevice<'a, 'd, M: RawMutex, T: Instance, CS> {
+    bus: &'a Mutex<M, Spi<'d, T>>,
+    cs: CS,
+    config: DeviceConfig,
+}
+
+impl<'a, 'd, M: RawMutex, T: Instance, CS: OutputPin> SpiDevice<'a, 'd, M, T, CS> {
+    /// Attach a device selected by `cs`
+    pub fn new(bus: &'a Mutex<M, Spi<'d, T>>, cs: CS, config: DeviceConfig) -> Self {
+        Self { bus, cs, config }
+    }
+
+    /// Detach the device and return its chip select
+    pub fn release(self) -> CS {
+        self.cs
+    }
+}
+
+impl<M: RawMutex, T: Instance, CS> embedded_hal::spi::ErrorType for SpiDevice<'_, '_, M, T, CS> {
+    type Error = Error;
+}
+
+impl<M: RawMutex, T: Instance, CS: OutputPin> embedded_hal_async::spi::SpiDevice for SpiDevice<'_, '_, M, T, CS> {
+    async fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Error> {
+        let mut bus = self.bus.lock().await;
+        bus.set_config(&self.config.bus);
+
+        // GPIO writes cannot fail on this chip
+        let _ = self.cs.set_low();
+        // Release CS even if the transaction is dropped half way
+        let cs = &mut self.cs;
+        let guard = DropGuard::new(|| {
+            let _ = cs.set_high();
+        });
+        delay_ns(self.config.cs_setup_ns);
+
+        let result = run_operations(&mut bus, operations).await;
+
+        delay_ns(self.config.cs_hold_ns);
+        drop(guard);
+        result
+    }
+}
+
+/// Device on a shared bus selected by the instance's SEL pin
+///
+/// The SPI block drives SEL itself, so only one such device can exist per
+/// instance. SEL is held asserted across the whole transaction rather than
+/// toggled per byte.
+pub struct SelSpiDevice<'a, 'd, M: RawMutex, T: Instance> {
+    bus: &'a Mutex<M, Spi<'d, T>>,
+    config: DeviceConfig,
+}
+
+impl<'a, 'd, M: RawMutex, T: Instance> SelSpiDevice<'a, 'd, M, T> {
+    /// Attach a device on the SEL pin (AF5), active low
+    pub fn new<SEL: SelPin<T>>(
+        bus: &'a Mutex<M, Spi<'d, T>>,
+        _sel: impl Peripheral<P = SEL> + 'a,
+        config: DeviceConfig,
+    ) -> Self {
+        // SEL is driven from SSELC, which starts out inactive
+        critical_section::with(|_| {
+            let regs = T::regs();
+            regs.spi_spicr1().modify(|r, w| unsafe { w.bits(r.bits() & !CR1_SELAP) });
+            regs.spi_spicr0().modify(|r, w| unsafe { w.bits((r.bits() & !CR0_SSELC) | CR0_SELOEN) });
+        });
+        Self { bus, config }
+    }
+
+    fn set_selected(selected: bool) {
+        critical_section::with(|_| {
+            T::regs().spi_spicr0().modify(|r, w| unsafe {
+                w.bits(if selected { r.bits() | CR0_SSELC } else { r.bits() & !CR0_SSELC })
+            });
+        });
+    }
+}
+
+impl<M: RawMutex, T: Instance> Drop for SelSpiDevice<'_, '_, M, T> {
+    fn drop(&mut self) {
+        critical_section::with(|_| {
+            T::regs().spi_spicr0().modify(|r, w| unsafe { w.bits(r.bits() & !(CR0_SSELC | CR0_SELOEN)) });
+        });
+    }
+}
+
+impl<M: RawMutex, T: Instance> embedded_hal::spi::ErrorType for SelSpiDevice<'_, '_, M, T> {
+    type Error = Error;
+}
+
+impl<M: RawMutex, T: Instance> embedded_hal_async::spi::SpiDevice for SelSpiDevice<'_, '_, M, T> {
+    async fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Error> {
+        let mut bus = self.bus.lock().await;
+        bus.set_config(&self.config.bus);
+
+        Self::set_selected(true);
+        let guard = DropGuard::new(|| Self::set_selected(false));
+        delay_ns(self.config.cs_setup_ns);
+
+        let result = run_operations(&mut bus, operations).await;
+
+        delay_ns(self.config.cs_hold_ns);
+        drop(guard);
+        result
+    }
+}
@@ -0,0 +1,365 @@
+//! External SPI NOR flash (W25Qxx and compatibles)
+//!
+//! [`SpiFlash`] implements the async `embedded-storage` NOR flash traits over
+//! [`Spi`], for assets that do not fit in the on-chip flash (fonts, sounds,
+//! logs). The part is identified with a JEDEC ID read; erase is in 4 KiB
+//! sectors (64 KiB blocks where the range allows), programming in 256-byte
+//! pages, and bulk reads use `FAST_READ` with PDMA if the [`Spi`] was built
+//! [`with_dma`](Spi::with_dma).
+//!
+//! Small reads are served from a tiny direct-mapped cache of
+//! [`CACHE_LINES`] x [`LINE_SIZE`] bytes, which helps glyph and table lookups
+//! that touch the same few lines repeatedly. Erase and program invalidate the
+//! lines they overlap.
+//!
+//! Only 24-bit addressing is used, so parts above 16 MiB are limited to their
+//! first 16 MiB.
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::spiflash::SpiFlash;
+//! use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
+//!
+//! let cs = p.gpiob.pb2().into_push_pull_output(Level::High, Speed::High);
+//! let mut flash = SpiFlash::new(spi, cs).await?;
+//! flash.erase(0, 4096).await?;
+//! flash.write(0, b"hello").await?;
+//! ```
+
+use embedded_hal::digital::OutputPin;
+use embedded_storage::nor_flash::{ErrorType, NorFlashError, NorFlashErrorKind};
+use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
+
+use crate::spi::{self, Instance, Spi};
+
+/// Bytes per cache line
+pub const LINE_SIZE: usize = 64;
+
+/// Number of cache lines
+pub const CACHE_LINES: usize = 4;
+
+const SECTOR_SIZE: u32 = 4096;
+const BLOCK_SIZE: u32 = 65536;
+const PAGE_SIZE: u32 = 256;
+
+/// Largest size reachable with 3 address bytes
+const MAX_CAPACITY: u32 = 1 << 24;
+
+// Commands
+const CMD_WRITE_ENABLE: u8 = 0x06;
+const CMD_READ_STATUS1: u8 = 0x05;
+const CMD_PAGE_PROGRAM: u8 = 0x02;
+const CMD_SECTOR_ERASE: u8 = 0x20;
+const CMD_BLOCK_ERASE: u8 = 0xD8;
+const CMD_FAST_READ: u8 = 0x0B;
+const CMD_JEDEC_ID: u8 = 0x9F;
+const CMD_RELEASE_POWER_DOWN: u8 = 0xAB;
+
+// Status register 1 bits
+const SR1_BUSY: u8 = 1 << 0;
+
+/// SPI flash error
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The SPI transfer failed
+    Spi(spi::Error),
+    /// Driving the chip-select pin failed
+    ChipSelect,
+    /// The JEDEC ID does not describe a supported part
+    UnknownDevice(JedecId),
+    /// The range runs past the end of the device
+    OutOfBounds,
+    /// The erase range is not sector aligned
+    NotAligned,
+}
+
+impl From<spi::Error> for Error {
+    fn from(e: spi::Error) -> Self {
+        Error::Spi(e)
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Spi(e) => write!(f, "SPI error: {}", e),
+            Error::ChipSelect => f.write_str("chip select failed"),
+            Error::UnknownDevice(id) => write!(
+                f,
+                "unknown device {:02x} {:02x} {:02x}",
+                id.manufacturer, id.memory_type, id.capacity
+            ),
+            Error::OutOfBounds => f.write_str("address out of range"),
+            Error::NotAligned => f.write_str("erase range not sector aligned"),
+        }
+    }
+}
+
+impl core::error::Error for Error {}
+
+impl NorFlashError for Error {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Error::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            Error::NotAligned => NorFlashErrorKind::NotAligned,
+            _ => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+/// JEDEC manufacturer and device ID
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct JedecId {
+    /// Manufacturer ID, 0xEF for Winbond
+    pub manufacturer: u8,
+    /// Memory type
+    pub memory_type: u8,
+    /// Capacity as a power of two in bytes
+    pub capacity: u8,
+}
+
+#[derive(Copy, Clone)]
+struct Line {
+    /// Line-aligned flash address, `None` when empty
+    tag: Option<u32>,
+    data: [u8; LINE_SIZE],
+}
+
+/// SPI NOR flash on a dedicated SPI bus
+pub struct SpiFlash<'d, T: Instance, CS: OutputPin> {
+    spi: Spi<'d, T>,
+    cs: CS,
+    id: JedecId,
+    capacity: u32,
+    cache: [Line; CACHE_LINES],
+}
+
+impl<'d, T: Instance, CS: OutputPin> SpiFlash<'d, T, CS> {
+    /// Wake the part and identify it
+    ///
+    /// `cs` should already be driven high.
+    pub async fn new(spi: Spi<'d, T>, cs: CS) -> Result<Self, Error> {
+        let mut flash = Self {
+            spi,
+            cs,
+            id: JedecId {
+                manufacturer: 0,
+                memory_type: 0,
+                capacity: 0,
+            },
+            capacity: 0,
+            cache: [Line {
+                tag: None,
+                data: [0; LINE_SIZE],
+            }; CACHE_LINES],
+        };
+
+        // A part left in deep power-down ignores everything else
+        flash.command(&[CMD_RELEASE_POWER_DOWN]).await?;
+
+        let mut id = [0u8; 3];
+        flash.command_read(&[CMD_JEDEC_ID], &mut id).await?;
+        flash.id = JedecId {
+            manufacturer: id[0],
+            memory_type: id[1],
+            capacity: id[2],
+        };
+
+        // 0x00 / 0xFF mean nothing answered; below 64 KiB is not a NOR flash
+        if !(0x10..=0x20).contains(&flash.id.capacity) || flash.id.manufacturer == 0xFF {
+            return Err(Error::UnknownDevice(flash.id));
+        }
+        flash.capacity = (1u32 << flash.id.capacity.min(24)).min(MAX_CAPACITY);
+
+        debug!("spiflash: {:02x} {:02x} {:02x}, {} bytes", id[0], id[1], id[2], flash.capacity);
+        Ok(flash)
+    }
+
+    /// The ID read at probe time
+    pub fn jedec_id(&self) -> JedecId {
+        self.id
+    }
+
+    /// Release the SPI bus and chip-select pin
+    pub fn release(self) -> (Spi<'d, T>, CS) {
+        (self.spi, self.cs)
+    }
+
+    /// Drop all cached lines, e.g. after another master wrote the part
+    pub fn invalidate_cache(&mut self) {
+        for line in &mut self.cache {
+            line.tag = None;
+        }
+    }
+
+    fn select(&mut self) -> Result<(), Error> {
+        self.cs.set_low().map_err(|_| Error::ChipSelect)
+    }
+
+    fn deselect(&mut self) -> Result<(), Error> {
+        self.cs.set_high().map_err(|_| Error::ChipSelect)
+    }
+
+    /// Run one command with chip select held across `header` and `data`
+    async fn transaction(&mut self, header: &[u8], data: Data<'_>) -> Result<(), Error> {
+        self.select()?;
+        let result = async {
+            self.spi.write(header).await?;
+            match data {
+                Data::None => Ok(()),
+                Data::Write(bytes) => self.spi.write(bytes).await,
+                Data::Read(buf) if buf.len() > LINE_SIZE => self.spi.read_dma(buf).await,
+                Data::Read(buf) => self.spi.read(buf).await,
+            }
+        }
+        .await;
+        self.deselect()?;
+        result.map_err(Error::from)
+    }
+
+    async fn command(&mut self, cmd: &[u8]) -> Result<(), Error> {
+        self.transaction(cmd, Data::None).await
+    }
+
+    async fn command_read(&mut self, cmd: &[u8], buf: &mut [u8]) -> Result<(), Error> {
+        self.transaction(cmd, Data::Read(buf)).await
+    }
+
+    async fn wait_idle(&mut self) -> Result<(), Error> {
+        loop {
+            let mut sr = [0u8];
+            self.command_read(&[CMD_READ_STATUS1], &mut sr).await?;
+            if sr[0] & SR1_BUSY == 0 {
+                return Ok(());
+            }
+            // Sector erase takes tens of milliseconds; let other tasks run
+            crate::wdt::pet_if_scoped();
+            embassy_futures::yield_now().await;
+        }
+    }
+
+    /// Write-enable, send `cmd` with a 24-bit address and optional data, then wait for completion
+    async fn program_op(&mut self, cmd: u8, address: u32, data: &[u8]) -> Result<(), Error> {
+        self.command(&[CMD_WRITE_ENABLE]).await?;
+        let [_, a2, a1, a0] = address.to_be_bytes();
+        self.transaction(&[cmd, a2, a1, a0], Data::Write(data)).await?;
+        self.wait_idle().await
+    }
+
+    async fn read_raw(&mut self, address: u32, buf: &mut [u8]) -> Result<(), Error> {
+        let [_, a2, a1, a0] = address.to_be_bytes();
+        // FAST_READ takes one dummy byte after the address
+        self.transaction(&[CMD_FAST_READ, a2, a1, a0, 0], Data::Read(buf)).await
+    }
+
+    fn invalidate_range(&mut self, from: u32, to: u32) {
+        for line in &mut self.cache {
+            if let Some(tag) = line.tag {
+                if tag < to && tag + LINE_SIZE as u32 > from {
+                    line.tag = None;
+                }
+            }
+        }
+    }
+
+    fn check_range(&self, offset: u32, len: usize) -> Result<(), Error> {
+        match offset.checked_add(len as u32) {
+            Some(end) if end <= self.capacity => Ok(()),
+            _ => Err(Error::OutOfBounds),
+        }
+    }
+}
+
+enum Data<'a> {
+    None,
+    Write(&'a [u8]),
+    Read(&'a mut [u8]),
+}
+
+impl<T: Instance, CS: OutputPin> ErrorType for SpiFlash<'_, T, CS> {
+    type Error = Error;
+}
+
+impl<T: Instance, CS: OutputPin> ReadNorFlash for SpiFlash<'_, T, CS> {
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.check_range(offset, bytes.len())?;
+
+        // Large reads go straight to the part and leave the cache alone
+        if bytes.len() > LINE_SIZE {
+            return self.read_raw(offset, bytes).await;
+        }
+
+        let mut address = offset;
+        let mut done = 0;
+        while done < bytes.len() {
+            let base = address & !(LINE_SIZE as u32 - 1);
+            let slot = (base as usize / LINE_SIZE) % CACHE_LINES;
+
+            if self.cache[slot].tag != Some(base) {
+                let len = LINE_SIZE.min((self.capacity - base) as usize);
+                let mut data = [0u8; LINE_SIZE];
+                self.read_raw(base, &mut data[..len]).await?;
+                self.cache[slot] = Line { tag: Some(base), data };
+            }
+
+            let start = (address - base) as usize;
+            let n = (LINE_SIZE - start).min(bytes.len() - done);
+            bytes[done..done + n].copy_from_slice(&self.cache[slot].data[start..start + n]);
+            done += n;
+            address += n as u32;
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity as usize
+    }
+}
+
+impl<T: Instance, CS: OutputPin> NorFlash for SpiFlash<'_, T, CS> {
+    const WRITE_SIZE: usize = 1;
+    const ERASE_SIZE: usize = SECTOR_SIZE as usize;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        if from > to || to > self.capacity {
+            return Err(Error::OutOfBounds);
+        }
+        if from % SECTOR_SIZE != 0 || to % SECTOR_SIZE != 0 {
+            return Err(Error::NotAligned);
+        }
+
+        self.invalidate_range(from, to);
+
+        let mut address = from;
+        while address < to {
+            if address % BLOCK_SIZE == 0 && to - address >= BLOCK_SIZE {
+                self.program_op(CMD_BLOCK_ERASE, address, &[]).await?;
+                address += BLOCK_SIZE;
+            } else {
+                self.program_op(CMD_SECTOR_ERASE, address, &[]).await?;
+                address += SECTOR_SIZE;
+            }
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.check_range(offset, bytes.len())?;
+        self.invalidate_range(offset, offset + bytes.len() as u32);
+
+        // A page program wraps within its page, so split at page boundaries
+        let mut address = offset;
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let n = ((PAGE_SIZE - address % PAGE_SIZE) as usize).min(rest.len());
+            let (chunk, tail) = rest.split_at(n);
+            self.program_op(CMD_PAGE_PROGRAM, address, chunk).await?;
+            address += n as u32;
+            rest = tail;
+        }
+        Ok(())
+    }
+}
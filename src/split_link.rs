@@ -0,0 +1,350 @@
+//! Split-keyboard link over one UART wire
+//!
+//! Both halves share a single half-duplex line: TX as open-drain with a
+//! pull-up, tied to RX. Every byte a side sends is also received by itself;
+//! [`SplitLink`] reads that echo back and reports a mismatch as
+//! [`Error::Collision`]. With separate TX/RX wires set [`Config::echo`] to
+//! `false`.
+//!
+//! # Roles
+//!
+//! [`SplitLink::negotiate`] exchanges `HELLO` frames until both halves agree
+//! which one is the [`Role::Master`]: the half that has USB wins, and
+//! [`Config::id`] breaks the tie. Afterwards the master drives every
+//! exchange, so the halves never talk at the same time: it sends its events
+//! (possibly none) and the slave answers with its own.
+//!
+//! # Frames
+//!
+//! `0xA5, kind, len, payload[len], crc8` where the CRC-8 (polynomial 0x07)
+//! covers `kind`, `len` and the payload. A key event is two bytes:
+//! `row | pressed << 7`, `col`.
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::split_link::{Config, KeyEvent, SplitLink};
+//!
+//! let mut link = SplitLink::new(uart, Config { id: 1, ..Default::default() });
+//! let role = link.negotiate(usb_powered).await;
+//!
+//! let mut incoming = [KeyEvent::default(); 8];
+//! loop {
+//!     let outgoing = scan_matrix();
+//!     let n = link.exchange(&outgoing, &mut incoming).await?;
+//!     // ...
+//! }
+//! ```
+
+use embassy_time::{Duration, Timer};
+
+use crate::uart::{self, Instance, Uart};
+
+/// Start of every frame
+const SYNC: u8 = 0xA5;
+
+const KIND_HELLO: u8 = 0x01;
+const KIND_HELLO_REPLY: u8 = 0x02;
+const KIND_EVENTS: u8 = 0x03;
+
+/// Most events carried by one frame
+pub const MAX_EVENTS: usize = 8;
+
+const MAX_PAYLOAD: usize = MAX_EVENTS * 2;
+
+/// Link error
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The UART reported an error
+    Uart(uart::Error),
+    /// The peer did not answer in time
+    Timeout,
+    /// A frame failed its CRC check
+    Crc,
+    /// The echo of a sent byte differed; both sides were driving the line
+    Collision,
+    /// A well-formed frame that is not valid here
+    Protocol,
+    /// [`SplitLink::exchange`] was called before a role was set
+    NoRole,
+}
+
+impl From<uart::Error> for Error {
+    fn from(e: uart::Error) -> Self {
+        Error::Uart(e)
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Uart(e) => write!(f, "UART error: {}", e),
+            Error::Timeout => f.write_str("peer did not answer"),
+            Error::Crc => f.write_str("frame CRC mismatch"),
+            Error::Collision => f.write_str("collision on the link"),
+            Error::Protocol => f.write_str("unexpected frame"),
+            Error::NoRole => f.write_str("role not negotiated"),
+        }
+    }
+}
+
+impl core::error::Error for Error {}
+
+/// Which half drives the link
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Role {
+    /// Starts every exchange; normally the half connected to the host
+    Master,
+    /// Answers the master
+    Slave,
+}
+
+/// Matrix key state change
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeyEvent {
+    /// Matrix row, 0..=127
+    pub row: u8,
+    /// Matrix column
+    pub col: u8,
+    /// `true` on press, `false` on release
+    pub pressed: bool,
+}
+
+/// Link configuration
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Distinct per half; breaks the tie when both or neither have USB
+    pub id: u32,
+    /// TX and RX share one wire, so sent bytes come back
+    pub echo: bool,
+    /// How long the master waits for the slave's answer
+    pub response_timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            echo: true,
+            response_timeout: Duration::from_millis(5),
+        }
+    }
+}
+
+struct Frame {
+    kind: u8,
+    len: usize,
+    payload: [u8; MAX_PAYLOAD],
+}
+
+/// One end of the split link
+pub struct SplitLink<'d, T: Instance> {
+    uart: Uart<'d, T>,
+    config: Config,
+    role: Option<Role>,
+    has_usb: bool,
+}
+
+impl<'d, T: Instance> SplitLink<'d, T> {
+    /// Wrap a configured UART; call [`negotiate`](Self::negotiate) or [`set_role`](Self::set_role) next
+    pub fn new(uart: Uart<'d, T>, config: Config) -> Self {
+        Self {
+            uart,
+            config,
+            role: None,
+            has_usb: false,
+        }
+    }
+
+    /// Role in use, once known
+    pub fn role(&self) -> Option<Role> {
+        self.role
+    }
+
+    /// Skip negotiation, e.g. when the halves are fixed by hardware
+    pub fn set_role(&mut self, role: Role) {
+        self.role = Some(role);
+    }
+
+    /// Release the UART
+    pub fn release(self) -> Uart<'d, T> {
+        self.uart
+    }
+
+    /// Agree on roles with the other half, retrying until it answers
+    ///
+    /// `has_usb` should be whether this half is connected to a host.
+    pub async fn negotiate(&mut self, has_usb: bool) -> Role {
+        self.has_usb = has_usb;
+        let hello = self.hello();
+
+        let mut attempt = 0u32;
+        loop {
+            if self.send_frame(KIND_HELLO, &hello).await.is_ok() {
+                // Jitter the listen window so two halves that booted together drift apart
+                let window = 10 + (self.config.id.wrapping_mul(7).wrapping_add(attempt.wrapping_mul(13))) % 20;
+                let received = embassy_time::with_timeout(Duration::from_millis(window as u64), self.receive_frame()).await;
+
+                if let Ok(Ok(frame)) = received {
+                    if (frame.kind == KIND_HELLO || frame.kind == KIND_HELLO_REPLY) && frame.len == hello.len() {
+                        if frame.kind == KIND_HELLO {
+                            let _ = self.send_frame(KIND_HELLO_REPLY, &hello).await;
+                        }
+
+                        let peer_usb = frame.payload[0] != 0;
+                        let peer_id = u32::from_le_bytes([frame.payload[1], frame.payload[2], frame.payload[3], frame.payload[4]]);
+                        let role = if (has_usb, self.config.id) > (peer_usb, peer_id) {
+                            Role::Master
+                        } else {
+                            Role::Slave
+                        };
+                        if peer_id == self.config.id {
+                            warn!("split_link: both halves use id {}", peer_id);
+                        }
+
+                        debug!("split_link: negotiated {}", role);
+                        self.role = Some(role);
+                        return role;
+                    }
+                }
+            }
+
+            attempt = attempt.wrapping_add(1);
+            Timer::after_millis(1).await;
+        }
+    }
+
+    /// Trade key events with the other half
+    ///
+    /// The master sends `outgoing` and waits up to
+    /// [`Config::response_timeout`] for the slave's events; the slave waits
+    /// for the master and answers with `outgoing`. At most [`MAX_EVENTS`]
+    /// are sent. Returns how many events were written to `incoming`.
+    pub async fn exchange(&mut self, outgoing: &[KeyEvent], incoming: &mut [KeyEvent]) -> Result<usize, Error> {
+        let mut payload = [0u8; MAX_PAYLOAD];
+        let count = outgoing.len().min(MAX_EVENTS);
+        for (event, bytes) in outgoing[..count].iter().zip(payload.chunks_exact_mut(2)) {
+            bytes[0] = (event.row & 0x7F) | ((event.pressed as u8) << 7);
+            bytes[1] = event.col;
+        }
+        let payload = &payload[..count * 2];
+
+        let frame = match self.role.ok_or(Error::NoRole)? {
+            Role::Master => {
+                self.send_frame(KIND_EVENTS, payload).await?;
+                embassy_time::with_timeout(self.config.response_timeout, self.receive_frame())
+                    .await
+                    .map_err(|_| Error::Timeout)??
+            }
+            Role::Slave => loop {
+                let frame = self.receive_frame().await?;
+                match frame.kind {
+                    KIND_EVENTS => {
+                        self.send_frame(KIND_EVENTS, payload).await?;
+                        break frame;
+                    }
+                    // The master missed our reply and is still negotiating
+                    KIND_HELLO => {
+                        let hello = self.hello();
+                        self.send_frame(KIND_HELLO_REPLY, &hello).await?;
+                    }
+                    _ => return Err(Error::Protocol),
+                }
+            },
+        };
+
+        if frame.kind != KIND_EVENTS || frame.len % 2 != 0 {
+            return Err(Error::Protocol);
+        }
+
+        let mut n = 0;
+        for (bytes, slot) in frame.payload[..frame.len].chunks_exact(2).zip(incoming.iter_mut()) {
+            *slot = KeyEvent {
+                row: bytes[0] & 0x7F,
+                col: bytes[1],
+                pressed: bytes[0] & 0x80 != 0,
+            };
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    fn hello(&self) -> [u8; 5] {
+        let mut hello = [0u8; 5];
+        hello[0] = self.has_usb as u8;
+        hello[1..5].copy_from_slice(&self.config.id.to_le_bytes());
+        hello
+    }
+
+    async fn send_frame(&mut self, kind: u8, payload: &[u8]) -> Result<(), Error> {
+        let mut frame = [0u8; MAX_PAYLOAD + 4];
+        frame[0] = SYNC;
+        frame[1] = kind;
+        frame[2] = payload.len() as u8;
+        frame[3..3 + payload.len()].copy_from_slice(payload);
+        frame[3 + payload.len()] = crc8(&frame[1..3 + payload.len()]);
+        let frame = &frame[..4 + payload.len()];
+
+        self.uart.write(frame).await?;
+
+        if self.config.echo {
+            for &sent in frame {
+                let mut echo = [0u8];
+                self.uart.read(&mut echo).await?;
+                if echo[0] != sent {
+                    return Err(Error::Collision);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn receive_frame(&mut self) -> Result<Frame, Error> {
+        let mut byte = [0u8];
+        loop {
+            self.uart.read(&mut byte).await?;
+            if byte[0] != SYNC {
+                continue;
+            }
+
+            let mut header = [0u8; 2];
+            self.uart.read(&mut header).await?;
+            let len = header[1] as usize;
+            if len > MAX_PAYLOAD {
+                // Not a header after all; hunt for the next sync byte
+                continue;
+            }
+
+            let mut frame = Frame {
+                kind: header[0],
+                len,
+                payload: [0; MAX_PAYLOAD],
+            };
+            self.uart.read(&mut frame.payload[..len]).await?;
+            self.uart.read(&mut byte).await?;
+
+            let mut crc = crc8(&header);
+            crc = crc8_update(crc, &frame.payload[..len]);
+            if crc != byte[0] {
+                return Err(Error::Crc);
+            }
+            return Ok(frame);
+        }
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    crc8_update(0, bytes)
+}
+
+/// CRC-8, polynomial 0x07, bitwise
+fn crc8_update(mut crc: u8, bytes: &[u8]) -> u8 {
+    for &byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+    }
+    crc
+}
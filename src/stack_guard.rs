@@ -0,0 +1,122 @@
+//! Stack high watermark and overflow canary
+//!
+//! Nothing on the M0+ stops the stack from growing down into `.bss`: with no
+//! MPU it silently overwrites statics, and on the 8 KB part a few large
+//! futures or buffers on the stack are enough to get there. With the
+//! `stack-guard` feature, `init()` paints the free RAM between the end of
+//! the statics and the stack pointer with a known word, so that
+//!
+//! - [`stack_high_watermark`] finds the deepest the stack has been, by
+//!   scanning for the first word that is no longer paint;
+//! - [`check`] looks at two canary words: [`WARN_MARGIN`] bytes above the
+//!   bottom of the stack, where it logs a warning once, and the bottom word
+//!   itself, where it panics, as statics may already be overwritten.
+//!
+//! With `time-driver`, `check` runs from the GPTM0 interrupt, about every
+//! 33 ms. Without it, call [`check`] from a periodic task or interrupt. A
+//! function that skips over the canary words without writing them, e.g.
+//! with a large uninitialised local array, can still get past.
+//!
+//! ```rust,ignore
+//! loop {
+//!     Timer::after_secs(10).await;
+//!     stack_guard::report();
+//! }
+//! ```
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Fill pattern for unused stack
+const PAINT: u32 = 0xDEAD_C0DE;
+/// Stack left unpainted below the stack pointer at painting time, for the painting code
+const PAINT_MARGIN: usize = 64;
+
+/// Distance above the bottom of the stack at which [`check`] warns
+pub const WARN_MARGIN: usize = 256;
+
+static PAINTED: AtomicBool = AtomicBool::new(false);
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+unsafe extern "C" {
+    // From cortex-m-rt's link.x: end of the statics, and the initial stack pointer
+    static mut __sheap: u32;
+    static _stack_start: u32;
+}
+
+/// Lowest address the stack may grow down to
+fn bottom() -> *mut u32 {
+    &raw mut __sheap
+}
+
+/// Initial stack pointer, the top of the stack
+fn top() -> usize {
+    &raw const _stack_start as usize
+}
+
+/// Stack space between the statics and the top of RAM, in bytes
+pub fn stack_size() -> usize {
+    top() - bottom() as usize
+}
+
+/// Paint the free stack below the current stack pointer
+///
+/// Called by `init()`; interrupts are off while painting, as their frames
+/// would land in the region being painted.
+pub(crate) fn init() {
+    critical_section::with(|_| {
+        let sp = cortex_m::register::msp::read() as usize;
+        let end = (sp - PAINT_MARGIN) & !3;
+        let mut word = bottom();
+        while (word as usize) < end {
+            unsafe {
+                word.write_volatile(PAINT);
+                word = word.add(1);
+            }
+        }
+    });
+    PAINTED.store(true, Ordering::Relaxed);
+    debug!("stack_guard: {} bytes of stack above {:#x}", stack_size(), bottom() as usize);
+}
+
+/// Most stack used since `init()`, in bytes
+///
+/// Zero if the stack was never painted. Scans up from the bottom of the
+/// stack, so it takes longer the less of the stack has been used.
+pub fn stack_high_watermark() -> usize {
+    if !PAINTED.load(Ordering::Relaxed) {
+        return 0;
+    }
+    let mut word = bottom();
+    while (word as usize) < top() && unsafe { word.read_volatile() } == PAINT {
+        word = unsafe { word.add(1) };
+    }
+    top() - word as usize
+}
+
+/// Warn once if the stack came within [`WARN_MARGIN`] of the statics, and
+/// panic if it reached them
+pub fn check() {
+    if !PAINTED.load(Ordering::Relaxed) {
+        return;
+    }
+    let bottom = bottom();
+    if unsafe { bottom.read_volatile() } != PAINT {
+        panic!("stack overflow into statics at {:#x}", bottom as usize);
+    }
+    if !WARNED.load(Ordering::Relaxed) && unsafe { bottom.add(WARN_MARGIN / 4).read_volatile() } != PAINT {
+        WARNED.store(true, Ordering::Relaxed);
+        warn!(
+            "stack_guard: stack within {} bytes of the statics ({} of {} bytes used)",
+            WARN_MARGIN,
+            stack_high_watermark(),
+            stack_size()
+        );
+    }
+}
+
+/// Log the stack watermark
+pub fn report() {
+    let size = stack_size();
+    let used = stack_high_watermark();
+    info!("stack: {} of {} bytes used at most, {} free", used, size, size - used);
+}
@@ -0,0 +1,282 @@
+//! Step/direction stepper motor driver with trapezoidal ramps
+//!
+//! For A4988, DRV8825, TMC2208 and similar drivers. A GPTM update interrupt
+//! ends every step interval: the handler pulses the STEP pin and loads the
+//! next interval, so pulse timing does not depend on the executor. Intervals
+//! follow D. Austin's recurrence ("Generate stepper-motor speed profiles in
+//! real time", 2005), one division per step, accelerating to the maximum
+//! speed, cruising, and decelerating to stop exactly on the target:
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::stepper::{Stepper, StepperConfig};
+//!
+//! let step = p.gpioa.pa4().degrade();
+//! let dir = p.gpioa.pa5().degrade();
+//! let config = StepperConfig { max_speed: 4_000, acceleration: 8_000, ..Default::default() };
+//! let mut slider = Stepper::new(p.timer1, step, dir, config);
+//! slider.move_to(32_000).await;
+//! slider.move_to(0).await;
+//! ```
+//!
+//! Intervals are counted in µs, so speeds run from about 16 to 50 000 steps
+//! per second; above roughly 20 000 the interrupt load starts to show. Only
+//! one `Stepper` can run at a time. Cancelling a move stops the pulses
+//! at once, without a ramp; [`Stepper::position`] stays correct.
+
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::task::Poll;
+
+use critical_section::Mutex;
+
+use crate::gpio::{self, AnyPin, Level};
+use crate::peripheral::{Peri, Peripheral};
+use crate::timer::{self, Instance};
+
+/// Timer tick rate: step intervals are in µs
+const TICK_HZ: u32 = 1_000_000;
+/// Longest interval the 16-bit counter holds, in ticks
+const MAX_INTERVAL: u32 = u16::MAX as u32;
+/// Fractional bits of the interval during ramps
+const FRAC: u32 = 8;
+
+/// Motion limits and pin options
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StepperConfig {
+    /// Cruise speed in steps per second
+    pub max_speed: u32,
+    /// Acceleration and deceleration in steps per second²
+    pub acceleration: u32,
+    /// STEP high time in µs; the handler holds the pin for at least this long
+    pub pulse_us: u32,
+    /// Drive DIR low instead of high for positive moves
+    pub invert_direction: bool,
+}
+
+impl Default for StepperConfig {
+    fn default() -> Self {
+        Self {
+            max_speed: 1_000,
+            acceleration: 2_000,
+            pulse_us: 2,
+            invert_direction: false,
+        }
+    }
+}
+
+/// Ramp state of a move in progress
+struct Motion {
+    /// Steps left, including the one the running interval ends with
+    remaining: u32,
+    /// Steps taken to accelerate so far; decelerating takes as many
+    ramp_steps: u32,
+    /// Current interval in ticks << FRAC
+    interval: u32,
+    /// Cruise interval in ticks << FRAC
+    min_interval: u32,
+    /// +1 or -1
+    direction: i32,
+}
+
+struct State {
+    position: i32,
+    motion: Option<Motion>,
+    /// STEP pin, as port and pin number for the handler
+    step: (char, u8),
+    pulse_cycles: u32,
+}
+
+static STATE: Mutex<RefCell<Option<State>>> = Mutex::new(RefCell::new(None));
+
+impl Motion {
+    /// Interval after the step just taken, or `None` once the move is done
+    fn advance(&mut self) -> Option<u32> {
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            return None;
+        }
+
+        if self.remaining <= self.ramp_steps {
+            // Mirror of the ramp up, ending at the first interval
+            self.interval += 2 * self.interval / (4 * self.remaining - 1);
+        } else if self.interval > self.min_interval {
+            self.ramp_steps += 1;
+            self.interval -= 2 * self.interval / (4 * self.ramp_steps + 1);
+            self.interval = self.interval.max(self.min_interval);
+        }
+        Some(self.interval >> FRAC)
+    }
+}
+
+/// Update interrupt: one step per timer period
+fn on_interrupt<T: Instance>() {
+    let regs = T::regs();
+    // INTSR flags are cleared by writing 0
+    regs.gptm_intsr().write(|w| unsafe { w.bits(!timer::INT_UEV) });
+
+    critical_section::with(|cs| {
+        let mut state = STATE.borrow_ref_mut(cs);
+        let Some(state) = state.as_mut() else { return };
+        let Some(motion) = state.motion.as_mut() else { return };
+
+        let (port, pin) = state.step;
+        gpio::write_port(port, 1 << pin, 0);
+        state.position += motion.direction;
+
+        match motion.advance() {
+            Some(ticks) => regs.gptm_crr().write(|w| unsafe { w.bits(ticks.clamp(2, MAX_INTERVAL) - 1) }),
+            None => {
+                regs.gptm_ctr().modify(|_, w| w.tme().clear_bit());
+                state.motion = None;
+                T::waker().wake();
+            }
+        }
+
+        cortex_m::asm::delay(state.pulse_cycles);
+        gpio::write_port(port, 0, 1 << pin);
+    });
+}
+
+/// Stepper motor on a STEP/DIR driver, timed by a GPTM
+pub struct Stepper<'d, T: Instance> {
+    _timer: Peri<'d, T>,
+    dir: AnyPin,
+    config: StepperConfig,
+}
+
+impl<'d, T: Instance> Stepper<'d, T> {
+    /// Drive `step` and `dir` as outputs, at position 0
+    ///
+    /// The timer's interrupt handler must be installed (`rt` feature); GPTM0
+    /// is only available without `time-driver`.
+    pub fn new(
+        timer: impl Peripheral<P = T> + 'd,
+        mut step: AnyPin,
+        mut dir: AnyPin,
+        config: StepperConfig,
+    ) -> Self {
+        step.set_as_output(Level::Low);
+        dir.set_as_output(Level::Low);
+
+        T::enable_clock();
+        let mut peri = timer.into_ref();
+        let mut timer = timer::Timer::new(peri.reborrow());
+        let pclk = crate::rcc::get_clocks().apb_clk().to_hz();
+        timer.set_prescaler((pclk / TICK_HZ - 1) as u16);
+
+        critical_section::with(|cs| {
+            STATE.borrow_ref_mut(cs).replace(State {
+                position: 0,
+                motion: None,
+                step: (step.port(), step.pin()),
+                pulse_cycles: config.pulse_us * crate::cortex_delay::cycles_per_us(),
+            });
+        });
+        timer::set_handler::<T>(Some(on_interrupt::<T>));
+
+        Self { _timer: peri, dir, config }
+    }
+
+    /// Change speed and acceleration for the next move
+    pub fn set_config(&mut self, config: StepperConfig) {
+        critical_section::with(|cs| {
+            if let Some(state) = STATE.borrow_ref_mut(cs).as_mut() {
+                state.pulse_cycles = config.pulse_us * crate::cortex_delay::cycles_per_us();
+            }
+        });
+        self.config = config;
+    }
+
+    /// Current position in steps
+    pub fn position(&self) -> i32 {
+        critical_section::with(|cs| STATE.borrow_ref(cs).as_ref().map_or(0, |s| s.position))
+    }
+
+    /// Redefine the current position, e.g. after homing; not while moving
+    pub fn set_position(&mut self, position: i32) {
+        critical_section::with(|cs| {
+            if let Some(state) = STATE.borrow_ref_mut(cs).as_mut() {
+                state.position = position;
+            }
+        });
+    }
+
+    /// Move to the absolute `target` and wait until the last step
+    pub async fn move_to(&mut self, target: i32) {
+        let steps = target.wrapping_sub(self.position());
+        self.move_by(steps).await
+    }
+
+    /// Move `steps` from the current position and wait until the last step
+    pub async fn move_by(&mut self, steps: i32) {
+        if steps == 0 {
+            return;
+        }
+        let forward = steps > 0;
+        let level = if forward != self.config.invert_direction { Level::High } else { Level::Low };
+        self.dir.set_as_output(level);
+
+        // First interval 0.676 * sqrt(2 / a), which corrects the recurrence's error on step one
+        let acceleration = self.config.acceleration.max(1) as u64;
+        let first = (2 * (TICK_HZ as u64).pow(2) / acceleration).isqrt() * 676 / 1000;
+        let first = (first as u32).clamp(2, MAX_INTERVAL);
+        let cruise = (TICK_HZ / self.config.max_speed.max(1)).clamp(2, MAX_INTERVAL);
+        let motion = Motion {
+            remaining: steps.unsigned_abs(),
+            ramp_steps: 0,
+            interval: first.max(cruise) << FRAC,
+            min_interval: cruise << FRAC,
+            direction: if forward { 1 } else { -1 },
+        };
+        trace!("stepper: {} steps, first interval {} us, cruise {} us", steps, first, cruise);
+
+        let regs = T::regs();
+        critical_section::with(|cs| {
+            if let Some(state) = STATE.borrow_ref_mut(cs).as_mut() {
+                state.motion = Some(motion);
+            }
+            regs.gptm_crr().write(|w| unsafe { w.bits(first.max(cruise) - 1) });
+            regs.gptm_cntr().reset();
+            regs.gptm_intsr().write(|w| unsafe { w.bits(0) });
+            regs.gptm_dictr().write(|w| unsafe { w.bits(timer::INT_UEV) });
+            regs.gptm_ctr().modify(|_, w| w.tme().set_bit());
+        });
+
+        // Halt on cancellation; the position counts the steps already taken
+        let guard = crate::drop::DropGuard::new(Self::halt);
+
+        poll_fn(|cx| {
+            T::waker().register(cx.waker());
+            if self.is_moving() { Poll::Pending } else { Poll::Ready(()) }
+        })
+        .await;
+
+        guard.defuse();
+    }
+
+    /// Whether a move is in progress
+    pub fn is_moving(&self) -> bool {
+        critical_section::with(|cs| STATE.borrow_ref(cs).as_ref().is_some_and(|s| s.motion.is_some()))
+    }
+
+    /// Stop the timer and drop the move in progress
+    fn halt() {
+        let regs = T::regs();
+        regs.gptm_ctr().modify(|_, w| w.tme().clear_bit());
+        regs.gptm_dictr().write(|w| unsafe { w.bits(0) });
+        critical_section::with(|cs| {
+            if let Some(state) = STATE.borrow_ref_mut(cs).as_mut() {
+                state.motion = None;
+            }
+        });
+    }
+}
+
+impl<T: Instance> Drop for Stepper<'_, T> {
+    fn drop(&mut self) {
+        Self::halt();
+        timer::set_handler::<T>(None);
+        critical_section::with(|cs| STATE.borrow_ref_mut(cs).take());
+    }
+}
@@ -0,0 +1,275 @@
+//! Wear-levelled storage region at the end of on-chip flash
+//!
+//! [`Storage`] reserves the last `PAGES` flash pages and exposes `PAGES - 1`
+//! logical pages through the async `embedded-storage` NOR flash traits, which
+//! is what RMK's storage expects. The remaining physical page is a blank
+//! spare.
+//!
+//! Every physical page starts with a header naming the logical page it holds
+//! and a sequence number. Erasing a logical page does not erase it in place:
+//! the spare gets a header for that logical page with the next sequence
+//! number, and only then is the old page erased, becoming the new spare. A
+//! logical erase therefore costs one physical erase, as it would in place,
+//! but the spare moves around the region, so a logical page that is erased
+//! far more often than the others (RMK's active page) spreads its wear over
+//! all `PAGES` pages. A power loss at any point leaves the old or the new
+//! copy in charge; the newer header wins on the next mount and the other
+//! page is erased. Erasing pages that are already blank is free, as are
+//! writes.
+//!
+//! The header takes [`HEADER_SIZE`] bytes of each page, so a logical page
+//! (the `ERASE_SIZE` seen through the traits) is that much smaller than a
+//! physical one.
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::storage::Storage;
+//!
+//! // 4 pages = 4 KiB reserved, 3 x 1008 bytes usable
+//! let storage: Storage<4> = Storage::new(p.flash).await?;
+//! // hand `storage` to RMK in place of the raw flash
+//! ```
+//!
+//! The linker script must keep the application out of the reserved pages.
+
+use embedded_storage::nor_flash::{ErrorType, NorFlash as _, ReadNorFlash as _};
+use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
+
+use crate::flash::{Flash, FlashError};
+
+/// Flash page size, the FMC's erase unit
+const PAGE_SIZE: u32 = Flash::ERASE_SIZE as u32;
+/// Bytes at the start of each page taken by its header
+pub const HEADER_SIZE: u32 = 16;
+/// Marks a complete header; written last so a torn header is invalid
+const HEADER_MAGIC: u32 = 0x5354_4F52;
+/// Owner of a page that holds no logical page
+const SPARE: u16 = u16::MAX;
+
+/// Wear-levelled storage over the last `PAGES` pages of flash
+pub struct Storage<const PAGES: usize> {
+    flash: Flash,
+    /// Start of the first page
+    base: u32,
+    /// Logical page held by each physical page, [`SPARE`] for none
+    owner: [u16; PAGES],
+    /// Highest sequence number in use
+    sequence: u32,
+}
+
+impl<const PAGES: usize> Storage<PAGES> {
+    /// Logical page size
+    const LOGICAL_SIZE: u32 = PAGE_SIZE - HEADER_SIZE;
+    const LOGICAL_PAGES: u16 = PAGES as u16 - 1;
+    const DATA_SIZE: u32 = Self::LOGICAL_PAGES as u32 * Self::LOGICAL_SIZE;
+    const CHECK_PAGES: () = assert!(PAGES >= 2 && PAGES < SPARE as usize, "need a spare and at least one data page");
+
+    /// Mount the region, recovering from an interrupted page move
+    ///
+    /// On blank (or foreign) flash every page that is not blank is erased and
+    /// every logical page starts out empty.
+    pub async fn new(flash: Flash) -> Result<Self, FlashError> {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::CHECK_PAGES;
+
+        let base = flash.capacity() as u32 - PAGES as u32 * PAGE_SIZE;
+        let mut this = Self {
+            flash,
+            base,
+            owner: [SPARE; PAGES],
+            sequence: 0,
+        };
+
+        // Newest header for each logical page wins
+        let headers: [Option<(u16, u32)>; PAGES] = core::array::from_fn(|page| this.read_header(page));
+        for (page, header) in headers.iter().enumerate() {
+            let Some((logical, sequence)) = *header else {
+                continue;
+            };
+            if let Some(other) = this.page_of(logical) {
+                if headers[other].is_some_and(|(_, newer)| newer > sequence) {
+                    continue;
+                }
+                this.owner[other] = SPARE;
+            }
+            this.owner[page] = logical;
+            this.sequence = this.sequence.max(sequence);
+        }
+        if this.sequence == 0 {
+            info!("storage: no valid pages, formatting");
+        }
+
+        // Stale copies and torn headers leave pages that are neither held nor blank
+        for page in 0..PAGES {
+            if this.owner[page] == SPARE && !this.is_blank(this.page_base(page), this.page_base(page) + PAGE_SIZE) {
+                debug!("storage: erasing page {}", page);
+                this.erase_page(page).await?;
+            }
+        }
+
+        // Give logical pages without a copy a blank page each; one spare is always left
+        for logical in 0..Self::LOGICAL_PAGES {
+            if this.page_of(logical).is_none() {
+                let page = this.spare();
+                this.assign(page, logical).await?;
+            }
+        }
+
+        debug!("storage: {} pages, sequence {}", PAGES, this.sequence);
+        Ok(this)
+    }
+
+    /// Page moves since the region was formatted, plus the logical page count
+    pub fn sequence(&self) -> u32 {
+        self.sequence
+    }
+
+    /// Release the flash controller
+    pub fn free(self) -> Flash {
+        self.flash
+    }
+
+    fn page_base(&self, page: usize) -> u32 {
+        self.base + page as u32 * PAGE_SIZE
+    }
+
+    /// Physical page holding `logical`
+    fn page_of(&self, logical: u16) -> Option<usize> {
+        self.owner.iter().position(|&owner| owner == logical)
+    }
+
+    /// The blank page not holding any logical page
+    fn spare(&self) -> usize {
+        // Held pages are distinct logical pages, so at least one is spare
+        self.page_of(SPARE).unwrap_or(0)
+    }
+
+    /// Physical address of logical `offset`, and the bytes left in its logical page
+    fn data_address(&self, offset: u32) -> (u32, u32) {
+        let logical = (offset / Self::LOGICAL_SIZE) as u16;
+        let within = offset % Self::LOGICAL_SIZE;
+        // Every logical page has a copy once mounted
+        let page = self.page_of(logical).unwrap_or(0);
+        (self.page_base(page) + HEADER_SIZE + within, Self::LOGICAL_SIZE - within)
+    }
+
+    fn read_word(&mut self, address: u32) -> u32 {
+        let mut word = [0u8; 4];
+        // In range by construction
+        let _ = self.flash.read(address, &mut word);
+        u32::from_le_bytes(word)
+    }
+
+    fn read_header(&mut self, page: usize) -> Option<(u16, u32)> {
+        let address = self.page_base(page);
+        let sequence = self.read_word(address);
+        let logical = self.read_word(address + 4);
+        let check = self.read_word(address + 8);
+        let magic = self.read_word(address + 12);
+        let valid = magic == HEADER_MAGIC
+            && check == !(sequence ^ logical)
+            && sequence != 0
+            && sequence != u32::MAX
+            && logical < Self::LOGICAL_PAGES as u32;
+        valid.then_some((logical as u16, sequence))
+    }
+
+    /// Hand the blank `page` to `logical` under the next sequence number
+    async fn assign(&mut self, page: usize, logical: u16) -> Result<(), FlashError> {
+        let sequence = self.sequence + 1;
+        let mut header = [0u8; HEADER_SIZE as usize];
+        header[0..4].copy_from_slice(&sequence.to_le_bytes());
+        header[4..8].copy_from_slice(&(logical as u32).to_le_bytes());
+        header[8..12].copy_from_slice(&(!(sequence ^ logical as u32)).to_le_bytes());
+        header[12..16].copy_from_slice(&HEADER_MAGIC.to_le_bytes());
+        self.flash.write_async(self.page_base(page), &header).await?;
+        self.owner[page] = logical;
+        self.sequence = sequence;
+        Ok(())
+    }
+
+    async fn erase_page(&mut self, page: usize) -> Result<(), FlashError> {
+        let base = self.page_base(page);
+        self.flash.erase_async(base, base + PAGE_SIZE).await
+    }
+
+    fn is_blank(&mut self, from: u32, to: u32) -> bool {
+        (from..to).step_by(4).all(|address| self.read_word(address) == u32::MAX)
+    }
+
+    fn check_range(&self, offset: u32, len: usize) -> Result<(), FlashError> {
+        match offset.checked_add(len as u32) {
+            Some(end) if end <= Self::DATA_SIZE => Ok(()),
+            _ => Err(FlashError::AddressOutOfRange),
+        }
+    }
+
+    /// Move `logical` to the spare page, leaving it blank
+    async fn relocate(&mut self, logical: u16) -> Result<(), FlashError> {
+        let old = self.page_of(logical).unwrap_or(0);
+        let new = self.spare();
+        debug!("storage: logical page {} moves from page {} to {}", logical, old, new);
+
+        // The new copy takes over once its header is complete
+        self.assign(new, logical).await?;
+        self.owner[old] = SPARE;
+        self.erase_page(old).await
+    }
+}
+
+impl<const PAGES: usize> ErrorType for Storage<PAGES> {
+    type Error = FlashError;
+}
+
+impl<const PAGES: usize> ReadNorFlash for Storage<PAGES> {
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, mut offset: u32, mut bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.check_range(offset, bytes.len())?;
+        while !bytes.is_empty() {
+            let (address, left) = self.data_address(offset);
+            let (now, rest) = bytes.split_at_mut(bytes.len().min(left as usize));
+            self.flash.read(address, now)?;
+            offset += now.len() as u32;
+            bytes = rest;
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        Self::DATA_SIZE as usize
+    }
+}
+
+impl<const PAGES: usize> NorFlash for Storage<PAGES> {
+    const WRITE_SIZE: usize = Flash::WRITE_SIZE;
+    const ERASE_SIZE: usize = Self::LOGICAL_SIZE as usize;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        if from > to || to > Self::DATA_SIZE {
+            return Err(FlashError::AddressOutOfRange);
+        }
+        if from % Self::LOGICAL_SIZE != 0 || to % Self::LOGICAL_SIZE != 0 {
+            return Err(FlashError::UnalignedAddress);
+        }
+
+        for offset in (from..to).step_by(Self::LOGICAL_SIZE as usize) {
+            let (start, _) = self.data_address(offset);
+            if !self.is_blank(start, start + Self::LOGICAL_SIZE) {
+                self.relocate((offset / Self::LOGICAL_SIZE) as u16).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, mut offset: u32, mut bytes: &[u8]) -> Result<(), Self::Error> {
+        self.check_range(offset, bytes.len())?;
+        while !bytes.is_empty() {
+            let (address, left) = self.data_address(offset);
+            let (now, rest) = bytes.split_at(bytes.len().min(left as usize));
+            self.flash.write_async(address, now).await?;
+            offset += now.len() as u32;
+            bytes = rest;
+        }
+        Ok(())
+    }
+}
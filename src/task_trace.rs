@@ -0,0 +1,260 @@
+//! Task and interrupt trace over RTT
+//!
+//! With the `task-trace` feature the executor's trace hooks and the HAL's
+//! interrupt handlers write a compact record for every task poll, wake-up,
+//! idle period and interrupt entry and exit to a second RTT up channel,
+//! `trace`, next to the `defmt` one of [`log_sink`](crate::log_sink). Saved
+//! to a file, `cargo xtask trace` turns it into per-task and per-interrupt
+//! CPU time and a timeline for Perfetto, which shows where USB, the matrix
+//! scan and the timers hold each other up on the single core:
+//!
+//! ```text
+//! cargo xtask trace trace.bin --elf target/thumbv6m-none-eabi/release/app --json trace.json
+//! ```
+//!
+//! Any RTT client that can save an up channel works, e.g. OpenOCD's
+//! `rtt server start 9091 1` and `nc localhost 9091 > trace.bin`. Handlers
+//! the application defines itself show up once they hold an [`Isr`]:
+//!
+//! ```rust,ignore
+//! #[interrupt]
+//! fn SCTM0() {
+//!     let _isr = task_trace::Isr::enter();
+//!     // ...
+//! }
+//! ```
+//!
+//! A record is a kind byte, the `embassy-time` ticks since the previous
+//! record and an argument, as LEB128 varints; most take 3 or 4 bytes. The
+//! stream opens with `"HTTR"`, the format version and the tick rate. When
+//! the channel is full, records are dropped whole and counted, and the next
+//! one that fits is preceded by a `Dropped` record. Writing a record takes a
+//! few microseconds with interrupts masked, so expect the trace to stretch
+//! short handlers somewhat.
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use critical_section::Mutex;
+
+/// Size of the RTT `trace` channel buffer
+pub const BUFFER_SIZE: usize = 1024;
+
+/// Start of the stream
+const MAGIC: &[u8; 4] = b"HTTR";
+/// Format version, bumped when the host decoder has to change
+const VERSION: u8 = 1;
+/// Task ids are sent relative to the start of SRAM, which saves a byte or two
+const RAM_START: u32 = 0x2000_0000;
+/// Largest record: header, `Dropped` record and the record itself
+const MAX_RECORD: usize = 32;
+
+/// Record kinds; must match `xtask/src/trace.rs`
+#[derive(Copy, Clone)]
+#[repr(u8)]
+enum Kind {
+    /// Task spawned; argument task id
+    TaskNew = 1,
+    /// Task woken and queued; argument task id
+    TaskReady = 2,
+    /// Poll started; argument task id
+    TaskExecBegin = 3,
+    /// Poll finished; argument task id
+    TaskExecEnd = 4,
+    /// Task finished; argument task id
+    TaskEnd = 5,
+    /// Executor found its run queue empty and went to sleep
+    Idle = 6,
+    /// Executor woke up and started polling
+    PollStart = 7,
+    /// Handler entered; argument exception number (IRQ + 16)
+    IsrEnter = 8,
+    /// Handler returned; argument exception number
+    IsrExit = 9,
+    /// Records lost before this one; argument their number
+    Dropped = 10,
+}
+
+struct State {
+    /// Tick of the last record written
+    last: u64,
+    /// Records lost since the last one written
+    dropped: u32,
+    /// Whether the header has been written
+    started: bool,
+}
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
+    last: 0,
+    dropped: 0,
+    started: false,
+}));
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Records lost because the RTT channel was full, since reset
+pub fn dropped() -> u32 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// A record under construction
+struct Record {
+    buf: [u8; MAX_RECORD],
+    len: usize,
+}
+
+impl Record {
+    fn byte(&mut self, byte: u8) {
+        self.buf[self.len] = byte;
+        self.len += 1;
+    }
+
+    fn varint(&mut self, mut value: u32) {
+        while value >= 0x80 {
+            self.byte(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.byte(value as u8);
+    }
+}
+
+fn record(kind: Kind, arg: Option<u32>) {
+    critical_section::with(|cs| {
+        let mut state = STATE.borrow_ref_mut(cs);
+        // Read the time inside the critical section so records stay in order
+        let at = embassy_time::Instant::now().as_ticks();
+        let delta = at.saturating_sub(state.last).min(u32::MAX as u64) as u32;
+
+        let mut record = Record { buf: [0; MAX_RECORD], len: 0 };
+        if !state.started {
+            record.buf[..MAGIC.len()].copy_from_slice(MAGIC);
+            record.len = MAGIC.len();
+            record.byte(VERSION);
+            record.varint(embassy_time::TICK_HZ as u32);
+        }
+        if state.dropped != 0 {
+            record.byte(Kind::Dropped as u8);
+            record.varint(delta);
+            record.varint(state.dropped);
+        }
+        record.byte(kind as u8);
+        record.varint(if state.dropped != 0 { 0 } else { delta });
+        if let Some(arg) = arg {
+            record.varint(arg);
+        }
+
+        if crate::log_sink::trace_write(&record.buf[..record.len]) {
+            state.last = at;
+            state.dropped = 0;
+            state.started = true;
+        } else {
+            state.dropped += 1;
+            DROPPED.store(DROPPED.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+        }
+    });
+}
+
+fn task_record(kind: Kind, task_id: u32) {
+    record(kind, Some(task_id.wrapping_sub(RAM_START)));
+}
+
+/// Exception number of the running handler, from ICSR.VECTACTIVE
+fn active_exception() -> u32 {
+    unsafe { (*cortex_m::peripheral::SCB::PTR).icsr.read() & 0x1FF }
+}
+
+/// Marks an interrupt handler in the trace from [`Isr::enter`] until it is dropped
+///
+/// Hold it for the whole handler, as the HAL's own handlers do.
+pub struct Isr {
+    exception: u32,
+}
+
+impl Isr {
+    /// Record entry into the running handler
+    pub fn enter() -> Self {
+        let exception = active_exception();
+        record(Kind::IsrEnter, Some(exception));
+        Self { exception }
+    }
+}
+
+impl Drop for Isr {
+    fn drop(&mut self) {
+        record(Kind::IsrExit, Some(self.exception));
+    }
+}
+
+// Executor events, called from the trace hooks; `executor_metrics` owns the
+// hooks when it is enabled and forwards to these
+
+pub(crate) fn task_new(task_id: u32) {
+    task_record(Kind::TaskNew, task_id);
+}
+
+pub(crate) fn task_end(task_id: u32) {
+    task_record(Kind::TaskEnd, task_id);
+}
+
+pub(crate) fn task_ready(task_id: u32) {
+    task_record(Kind::TaskReady, task_id);
+}
+
+pub(crate) fn task_exec_begin(task_id: u32) {
+    task_record(Kind::TaskExecBegin, task_id);
+}
+
+pub(crate) fn task_exec_end(task_id: u32) {
+    task_record(Kind::TaskExecEnd, task_id);
+}
+
+pub(crate) fn poll_start() {
+    record(Kind::PollStart, None);
+}
+
+pub(crate) fn executor_idle() {
+    record(Kind::Idle, None);
+}
+
+// Hooks called by embassy-executor's `trace` feature
+
+#[cfg(not(feature = "executor-metrics"))]
+#[unsafe(no_mangle)]
+fn _embassy_trace_task_new(_executor_id: u32, task_id: u32) {
+    task_new(task_id);
+}
+
+#[cfg(not(feature = "executor-metrics"))]
+#[unsafe(no_mangle)]
+fn _embassy_trace_task_end(_executor_id: u32, task_id: u32) {
+    task_end(task_id);
+}
+
+#[cfg(not(feature = "executor-metrics"))]
+#[unsafe(no_mangle)]
+fn _embassy_trace_task_ready_begin(_executor_id: u32, task_id: u32) {
+    task_ready(task_id);
+}
+
+#[cfg(not(feature = "executor-metrics"))]
+#[unsafe(no_mangle)]
+fn _embassy_trace_task_exec_begin(_executor_id: u32, task_id: u32) {
+    task_exec_begin(task_id);
+}
+
+#[cfg(not(feature = "executor-metrics"))]
+#[unsafe(no_mangle)]
+fn _embassy_trace_task_exec_end(_executor_id: u32, task_id: u32) {
+    task_exec_end(task_id);
+}
+
+#[cfg(not(feature = "executor-metrics"))]
+#[unsafe(no_mangle)]
+fn _embassy_trace_poll_start(_executor_id: u32) {
+    poll_start();
+}
+
+#[cfg(not(feature = "executor-metrics"))]
+#[unsafe(no_mangle)]
+fn _embassy_trace_executor_idle(_executor_id: u32) {
+    executor_idle();
+}
@@ -0,0 +1,161 @@
+//! Fixed-rate ticks from a GPTM update interrupt
+//!
+//! [`ticker_at_rate`] runs a GPTM as a free-running period counter and wakes
+//! the task on every update event, so the tick phase comes from the timer
+//! hardware instead of the `embassy-time` queue: a busy queue or a slow loop
+//! iteration delays when the task runs, never when the next tick falls.
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::ticker::ticker_at_rate;
+//!
+//! let mut ticker = ticker_at_rate(p.timer1, Hertz::khz(1));
+//! loop {
+//!     let elapsed = ticker.next().await; // 1 unless this loop overran a tick
+//!     matrix.scan();
+//!     controller.step(elapsed as f32 * 0.001);
+//! }
+//! ```
+//!
+//! Rates that do not divide the timer clock evenly alternate between two
+//! period lengths, one counter tick apart, so the average rate is exact and the
+//! phase never drifts by more than half a counter tick.
+
+use core::future::poll_fn;
+use core::sync::atomic::Ordering;
+use core::task::Poll;
+
+use crate::peripheral::Peripheral;
+use crate::time::Hertz;
+use crate::timer::{self, Instance, Timer};
+
+// CTR counter-reload buffer enable: CRR writes take effect at the next update
+const CTR_CRBE: u32 = 1 << 1;
+// EVGR software update event, loads the prescaler
+const EVGR_UEVG: u32 = 1 << 8;
+
+/// Periodic wakeups locked to a GPTM's update events
+pub struct Ticker<'d, T: Instance> {
+    timer: Timer<'d, T>,
+    /// Update events consumed by [`next`](Self::next)
+    seen: u32,
+    /// Whole counter ticks per period
+    period: u32,
+    /// Fractional counter ticks per period, in 1/`den`
+    rem: u64,
+    den: u64,
+    /// Accumulated phase lead in 1/`den` counter ticks; positive means early
+    lead: i64,
+    /// Whether the period now running is one tick long
+    active_long: bool,
+    /// Whether the period after it is, as written to the buffered CRR
+    pending_long: bool,
+}
+
+/// Tick `rate` times per second from `timer`'s update interrupt
+///
+/// The timer's interrupt handler must be installed (`rt` feature); GPTM0 is
+/// only available without `time-driver`.
+///
+/// # Panics
+///
+/// If `rate` is zero or above half the APB clock.
+pub fn ticker_at_rate<'d, T: Instance>(timer: impl Peripheral<P = T> + 'd, rate: Hertz) -> Ticker<'d, T> {
+    let apb = crate::rcc::get_clocks().apb_clk().to_hz() as u64;
+    let hz = rate.to_hz() as u64;
+    assert!(hz > 0 && apb / hz >= 2, "ticker rate out of range");
+
+    // Smallest prescaler that keeps the period within the 16-bit counter
+    let prescaler = (apb / hz / u16::MAX as u64).min(u16::MAX as u64);
+    let den = (prescaler + 1) * hz;
+    let period = (apb / den) as u32;
+
+    T::enable_clock();
+    let mut timer = Timer::new(timer);
+    let regs = T::regs();
+    timer.set_prescaler(prescaler as u16);
+    timer.set_period((period - 1) as u16);
+    regs.gptm_ctr().modify(|r, w| unsafe { w.bits(r.bits() | CTR_CRBE) });
+
+    critical_section::with(|_| {
+        timer.reset_counter();
+        // Load the prescaler now rather than at the first overflow
+        regs.gptm_evgr().write(|w| unsafe { w.bits(EVGR_UEVG) });
+        T::overflows().store(0, Ordering::Relaxed);
+        regs.gptm_intsr().write(|w| unsafe { w.bits(0) });
+    });
+    regs.gptm_dictr().modify(|r, w| unsafe { w.bits(r.bits() | timer::INT_UEV) });
+    timer.start();
+
+    debug!("ticker: {} Hz, prescaler {}, period {}", hz, prescaler, period);
+    Ticker {
+        timer,
+        seen: 0,
+        period,
+        rem: apb % den,
+        den,
+        lead: 0,
+        active_long: false,
+        pending_long: false,
+    }
+}
+
+impl<'d, T: Instance> Ticker<'d, T> {
+    /// Wait for the next tick; returns the ticks since the previous call
+    ///
+    /// More than 1 means the caller missed ticks. The phase is kept either
+    /// way: the next tick still falls on the original grid.
+    pub async fn next(&mut self) -> u32 {
+        poll_fn(|cx| {
+            T::waker().register(cx.waker());
+
+            let now = T::overflows().load(Ordering::Acquire);
+            let elapsed = now.wrapping_sub(self.seen);
+            if elapsed == 0 {
+                return Poll::Pending;
+            }
+            self.seen = now;
+            self.compensate(elapsed);
+            Poll::Ready(elapsed)
+        })
+        .await
+    }
+
+    /// Forget missed ticks, so the next [`next`](Self::next) waits for a fresh one
+    pub fn skip_missed(&mut self) {
+        let now = T::overflows().load(Ordering::Acquire);
+        if now != self.seen {
+            self.compensate(now.wrapping_sub(self.seen));
+            self.seen = now;
+        }
+    }
+
+    /// Stop ticking and release the timer
+    pub fn stop(mut self) -> Timer<'d, T> {
+        let regs = T::regs();
+        self.timer.stop();
+        regs.gptm_dictr().modify(|r, w| unsafe { w.bits(r.bits() & !timer::INT_UEV) });
+        regs.gptm_ctr().modify(|r, w| unsafe { w.bits(r.bits() & !CTR_CRBE) });
+        self.timer
+    }
+
+    /// Account for `elapsed` finished periods and pick the length of the one after the current
+    fn compensate(&mut self, elapsed: u32) {
+        if self.rem == 0 {
+            return;
+        }
+
+        // The first finished period ran with the old length, the rest and the
+        // current one with what was pending
+        self.lead += self.error(self.active_long) + (elapsed as i64 - 1) * self.error(self.pending_long);
+        self.active_long = self.pending_long;
+
+        let projected = self.lead + self.error(self.active_long);
+        self.pending_long = projected + self.rem as i64 > self.den as i64 / 2;
+        self.timer.set_period((self.period - 1 + self.pending_long as u32) as u16);
+    }
+
+    /// Phase lead one period adds, in 1/`den` counter ticks
+    fn error(&self, long: bool) -> i64 {
+        self.rem as i64 - if long { self.den as i64 } else { 0 }
+    }
+}
@@ -1,39 +1,192 @@
 //! Embassy-time driver implementation for HT32F523x2
 //!
 //! This module provides a complete embassy-time driver using GPTM0.
+//!
+//! GPTM0 counts at 1 MHz and its 16-bit count is extended to 64 bits from the
+//! overflow and half-period (CH3 compare) interrupts. Timers wait in an
+//! `embassy-time-queue-utils` queue, and the CH0 compare is set for the
+//! earliest once it is less than half a period away. The 32-bit BFTMs would
+//! need fewer interrupts, but they have no prescaler, so their rate would
+//! follow the APB clock through USB suspend, and `SoftPwm`/`Ps2Device` use them.
+//!
+//! ## RTC discipline
+//! GPTM0 counts the HSI-derived APB clock, which wanders by a percent or
+//! more over temperature. With the `time-rtc-discipline` feature,
+//! [`run_rtc_discipline`] compares it against the RTC counting the 32.768 kHz
+//! LSE crystal and `now()` is scaled by the measured rate, with any phase
+//! error steered out over the next window. `embassy-time` then keeps crystal
+//! accuracy (tens of ppm) over long sleeps and timeouts, and stays monotonic
+//! across corrections. The RTC is left running with prescaler 1; do not stop
+//! it, e.g. with [`selftest::measure_clock_ppm`](crate::selftest::measure_clock_ppm).
 
+use core::cell::{Cell, RefCell};
 use core::task::Waker;
+use critical_section::{CriticalSection, Mutex};
 use embassy_time_driver::Driver;
+use embassy_time_queue_utils::Queue;
+
+use crate::regs::{Mmio, RegisterAccess};
+use crate::timer::{COUNTER_BITS, ExtendedCounter, INT_CH0CC, INT_CH3CC, INT_UEV};
 
 /// Time driver for HT32F523x2 using GPTM0
 pub struct TimeDriver;
 
 const FREQUENCY: u64 = 1_000_000; // 1 MHz
 
+/// EVGR software update event: reloads the prescaler and clears the counter
+const EVGR_UEVG: u32 = 1 << 8;
+
+const COUNTER_MASK: u32 = (1 << COUNTER_BITS) - 1;
+/// CH3 compare value, so the count is extended twice per counter period
+const HALF_PERIOD: u32 = 1 << (COUNTER_BITS - 1);
+
+/// GPTM0 count extended to 64 bits
+static COUNTER: Mutex<ExtendedCounter> = Mutex::new(ExtendedCounter::new());
+/// Timers waiting to be woken
+static QUEUE: Mutex<RefCell<Queue>> = Mutex::new(RefCell::new(Queue::new()));
+/// Next wake-up time, `u64::MAX` for none
+static ALARM: Mutex<Cell<u64>> = Mutex::new(Cell::new(u64::MAX));
+
+fn gptm0() -> &'static crate::pac::gptm0::RegisterBlock {
+    unsafe { &*crate::pac::Gptm0::ptr() }
+}
+
+/// Address of the GPTM0 counter register
+fn cntr() -> usize {
+    gptm0().gptm_cntr().as_ptr() as usize
+}
+
+/// GPTM0 count extended to 64 bits, before any RTC discipline
+fn raw_now(cs: CriticalSection) -> u64 {
+    // The overflow and half-period interrupts read it often enough to catch every wrap
+    COUNTER.borrow(cs).read(&Mmio, cntr())
+}
+
+/// Time as `embassy-time` sees it
+fn now(cs: CriticalSection) -> u64 {
+    discipline::scale(cs, raw_now(cs))
+}
+
 embassy_time_driver::time_driver_impl!(static DRIVER: TimeDriver = TimeDriver);
 
 impl Driver for TimeDriver {
     fn now(&self) -> u64 {
-        let timer = unsafe { &*crate::pac::Gptm0::ptr() };
+        critical_section::with(now)
+    }
+
+    fn schedule_wake(&self, at: u64, waker: &Waker) {
+        critical_section::with(|cs| {
+            if QUEUE.borrow_ref_mut(cs).schedule_wake(at, waker) {
+                wake_expired(cs);
+            }
+        });
+    }
+}
 
-        // Read the current counter value
-        let counter = timer.gptm_cntr().read().bits() as u64;
+/// Wake the timers that are due and set the alarm for the next one
+fn wake_expired(cs: CriticalSection) {
+    let mut queue = QUEUE.borrow_ref_mut(cs);
+    loop {
+        ALARM.borrow(cs).set(queue.next_expiration(now(cs)));
+        if arm(cs) {
+            break;
+        }
+    }
+}
 
-        // For simplicity, we'll just use the counter directly
-        // In a full implementation, we'd handle overflow and maintain a 64-bit tick count
-        counter
+/// Point the CH0 compare at [`ALARM`] once it is less than half a counter
+/// period away; `false` if it is already due
+///
+/// Until then the overflow and half-period interrupts call this again.
+fn arm(cs: CriticalSection) -> bool {
+    let timer = gptm0();
+    timer.gptm_dictr().modify(|r, w| unsafe { w.bits(r.bits() & !INT_CH0CC) });
+
+    let at = ALARM.borrow(cs).get();
+    if at == u64::MAX {
+        return true;
+    }
+    let counter = Mmio.read(cntr());
+    let now = now(cs);
+    if at <= now {
+        return false;
     }
+    let ticks = discipline::raw_ticks(cs, (at - now).min(FREQUENCY));
+    if ticks >= HALF_PERIOD as u64 {
+        return true;
+    }
+
+    let ticks = ticks as u32;
+    timer.gptm_ch0ccr().write(|w| unsafe { w.bits(counter.wrapping_add(ticks) & COUNTER_MASK) });
+    // INTSR flags are cleared by writing 0
+    timer.gptm_intsr().write(|w| unsafe { w.bits(!INT_CH0CC) });
+    timer.gptm_dictr().modify(|r, w| unsafe { w.bits(r.bits() | INT_CH0CC) });
 
-    fn schedule_wake(&self, _at: u64, _waker: &Waker) {
-        // For now, we don't implement scheduled wakes
-        // This would require configuring the timer compare register and enabling interrupts
-        // to wake the system at a specific time
+    // The counter may have gone past the compare value while it was written
+    let elapsed = Mmio.read(cntr()).wrapping_sub(counter) & COUNTER_MASK;
+    if elapsed >= ticks {
+        timer.gptm_dictr().modify(|r, w| unsafe { w.bits(r.bits() & !INT_CH0CC) });
+        return false;
     }
+    true
+}
+
+/// GPTM0 interrupt handler body
+///
+/// Defined as the `GPTM0` handler with `rt`. Without it, call this from the
+/// application's `GPTM0` handler, or timers never fire.
+pub fn on_interrupt() {
+    #[cfg(feature = "task-trace")]
+    let _isr = crate::task_trace::Isr::enter();
+    // Runs twice per counter period, a regular place to look
+    #[cfg(feature = "stack-guard")]
+    crate::stack_guard::check();
+
+    let timer = gptm0();
+    critical_section::with(|cs| {
+        let flags = timer.gptm_intsr().read().bits() & timer.gptm_dictr().read().bits();
+        timer.gptm_intsr().write(|w| unsafe { w.bits(!flags) });
+        if flags & (INT_UEV | INT_CH3CC) != 0 {
+            raw_now(cs);
+        }
+        if !arm(cs) {
+            wake_expired(cs);
+        }
+    });
+}
+
+#[cfg(feature = "rt")]
+use crate::pac::interrupt;
+
+#[cfg(feature = "rt")]
+#[interrupt]
+fn GPTM0() {
+    on_interrupt();
+}
+
+/// Keep the 1 MHz tick after the APB clock changed to `apb_hz`
+///
+/// The prescaler is preloaded, so an update event loads it at once. That also
+/// resets the counter, so the time so far is folded into the extended count
+/// first and `now()` carries on from it.
+#[cfg(feature = "usb")]
+pub(crate) fn set_timer_clock(apb_hz: u32) {
+    let timer = gptm0();
+    critical_section::with(|cs| {
+        let now = raw_now(cs);
+        timer.gptm_pscr().write(|w| unsafe { w.bits(apb_hz / FREQUENCY as u32 - 1) });
+        timer.gptm_evgr().write(|w| unsafe { w.bits(EVGR_UEVG) });
+        COUNTER.borrow(cs).restart(now);
+        // The compare value was relative to the old count
+        if !arm(cs) {
+            wake_expired(cs);
+        }
+    });
 }
 
 /// Initialize the time driver using GPTM0
 pub fn init() {
-    let timer = unsafe { &*crate::pac::Gptm0::ptr() };
+    let timer = gptm0();
 
     // Enable timer clock
     let ckcu = unsafe { &*crate::pac::Ckcu::ptr() };
@@ -49,12 +202,172 @@ pub fn init() {
     // Configure timer for basic operation
     timer.gptm_ctr().modify(|_, w| w.tme().clear_bit()); // Disable timer first
     timer.gptm_pscr().write(|w| unsafe { w.bits(prescaler) }); // Set prescaler
-    timer.gptm_crr().write(|w| unsafe { w.bits(0xFFFFFFFF) }); // Set to maximum period
+    timer.gptm_crr().write(|w| unsafe { w.bits((1 << COUNTER_BITS) - 1) }); // Full counter period
     timer.gptm_cntr().write(|w| unsafe { w.bits(0) }); // Reset counter
+    timer.gptm_evgr().write(|w| unsafe { w.bits(EVGR_UEVG) }); // Load the prescaler now
 
     // Configure for up-counting mode
     timer.gptm_mdcfr().modify(|_, w| w.tse().bit(true)); // Up counting
 
+    // Overflow and half-period interrupts extend the count; CH0 is the alarm
+    timer.gptm_ch3ccr().write(|w| unsafe { w.bits(HALF_PERIOD) });
+    timer.gptm_intsr().write(|w| unsafe { w.bits(0) });
+    timer.gptm_dictr().write(|w| unsafe { w.bits(INT_UEV | INT_CH3CC) });
+
     // Start timer
     timer.gptm_ctr().modify(|_, w| w.tme().set_bit());
-}
\ No newline at end of file
+}
+
+#[cfg(feature = "time-rtc-discipline")]
+pub use discipline::{rtc_correction_ppm, run_rtc_discipline};
+
+#[cfg(feature = "time-rtc-discipline")]
+mod discipline {
+    use core::cell::Cell;
+
+    use critical_section::{CriticalSection, Mutex};
+
+    use crate::regs::{Mmio, RegisterAccess};
+
+    const RTC_CNT: usize = 0x4006_A000;
+    const RTC_CR: usize = 0x4006_A008;
+    // RTCCR bits
+    const RTCCR_RTCEN: u32 = 1 << 0;
+    const RTCCR_RTCSRC_LSE: u32 = 1 << 1;
+    const RTCCR_RPRE_MASK: u32 = 0xF << 8;
+
+    const LSE_HZ: u64 = 32_768;
+    /// Seconds between corrections; the ±1 RTC tick read jitter is 4 ppm of this
+    const WINDOW_S: u64 = 16;
+    /// Largest correction applied, well past the HSI's tolerance
+    const MAX_CORRECTION_PPM: i64 = 50_000;
+    /// Raw ticks after which `scale` moves its anchor, to keep the product in range
+    const REANCHOR_TICKS: u64 = 1 << 30;
+
+    /// Rate correction in parts per 2^32
+    const ONE: i64 = 1 << 32;
+
+    /// Maps raw driver ticks to disciplined ticks, linear from an anchor
+    #[derive(Copy, Clone)]
+    struct Scale {
+        raw: u64,
+        now: u64,
+        correction: i64,
+    }
+
+    impl Scale {
+        fn apply(&self, raw: u64) -> u64 {
+            let elapsed = raw.wrapping_sub(self.raw);
+            let adjust = (elapsed as i64 * self.correction) >> 32;
+            self.now.wrapping_add(elapsed).wrapping_add(adjust as u64)
+        }
+    }
+
+    static SCALE: Mutex<Cell<Scale>> = Mutex::new(Cell::new(Scale { raw: 0, now: 0, correction: 0 }));
+
+    /// Disciplined time for the raw tick count `raw`
+    pub(super) fn scale(cs: CriticalSection, raw: u64) -> u64 {
+        let cell = SCALE.borrow(cs);
+        let mut scale = cell.get();
+        let now = scale.apply(raw);
+        if raw.wrapping_sub(scale.raw) > REANCHOR_TICKS {
+            scale.raw = raw;
+            scale.now = now;
+            cell.set(scale);
+        }
+        now
+    }
+
+    /// Raw ticks for `ticks` (at most a second) of disciplined time at the current rate
+    pub(super) fn raw_ticks(cs: CriticalSection, ticks: u64) -> u64 {
+        let correction = SCALE.borrow(cs).get().correction;
+        (ticks as i64 * ONE / (ONE + correction)) as u64
+    }
+
+    /// Current correction of the timer's rate in ppm; positive if the HSI runs slow
+    pub fn rtc_correction_ppm() -> i32 {
+        let correction = critical_section::with(|cs| SCALE.borrow(cs).get().correction);
+        (correction * 1_000_000 / ONE) as i32
+    }
+
+    /// Discipline `embassy-time` against the LSE-driven RTC, see the module docs
+    ///
+    /// Run it in its own task. Starts the LSE (waiting up to 2 s) and parks
+    /// for good if it does not come up, leaving the time base undisciplined.
+    pub async fn run_rtc_discipline() -> ! {
+        use embassy_time::{Duration, Timer};
+
+        if !crate::rcc::start_lse(2000) {
+            warn!("time: LSE did not start, time stays on the HSI");
+            core::future::pending::<()>().await;
+        }
+        Mmio.modify(RTC_CR, |v| (v & !RTCCR_RPRE_MASK) | RTCCR_RTCSRC_LSE | RTCCR_RTCEN);
+
+        // Disciplined time and RTC count at the start; phase is judged against both
+        let (epoch, mut last_raw, mut last_rtc) = critical_section::with(|cs| {
+            let raw = super::raw_now(cs);
+            (scale(cs, raw), raw, Mmio.read(RTC_CNT))
+        });
+        let mut rtc_ticks = 0u64;
+        let mut rate: Option<i64> = None;
+
+        loop {
+            Timer::after(Duration::from_secs(WINDOW_S)).await;
+
+            critical_section::with(|cs| {
+                let rtc = Mmio.read(RTC_CNT);
+                let raw = super::raw_now(cs);
+                let raw_elapsed = raw - last_raw;
+                let rtc_elapsed = rtc.wrapping_sub(last_rtc) as u64;
+                last_raw = raw;
+                last_rtc = rtc;
+                rtc_ticks += rtc_elapsed;
+                if raw_elapsed == 0 {
+                    return;
+                }
+
+                // Rate of the raw ticks against the crystal over this window, smoothed
+                let true_elapsed = rtc_elapsed * super::FREQUENCY / LSE_HZ;
+                let measured = (true_elapsed as i64 - raw_elapsed as i64) * ONE / raw_elapsed as i64;
+                let smoothed = match rate {
+                    Some(rate) => rate + (measured - rate) / 4,
+                    None => measured,
+                };
+                rate = Some(smoothed);
+
+                // Phase: how far disciplined time is ahead of the crystal, removed over the next window
+                let cell = SCALE.borrow(cs);
+                let now = cell.get().apply(raw);
+                let crystal = epoch + rtc_ticks * super::FREQUENCY / LSE_HZ;
+                let second = super::FREQUENCY as i64;
+                let ahead = (now as i64 - crystal as i64).clamp(-second, second);
+                let steer = ahead * ONE / (WINDOW_S * super::FREQUENCY) as i64;
+
+                // Re-anchor at this instant so time continues from where it is
+                let limit = MAX_CORRECTION_PPM * ONE / 1_000_000;
+                cell.set(Scale {
+                    raw,
+                    now,
+                    correction: (smoothed - steer).clamp(-limit, limit),
+                });
+            });
+            trace!("time: rate correction {} ppm", rtc_correction_ppm());
+        }
+    }
+}
+
+#[cfg(not(feature = "time-rtc-discipline"))]
+mod discipline {
+    use critical_section::CriticalSection;
+
+    /// Without the RTC, time is the raw count
+    #[inline(always)]
+    pub(super) fn scale(_cs: CriticalSection, raw: u64) -> u64 {
+        raw
+    }
+
+    #[inline(always)]
+    pub(super) fn raw_ticks(_cs: CriticalSection, ticks: u64) -> u64 {
+        ticks
+    }
+}
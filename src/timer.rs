@@ -1,18 +1,41 @@
 //! Timer driver for HT32 GPTM (General Purpose Timer Module)
+//!
+//! Besides PCLK, a GPTM counter can be clocked from its ETR pin or from
+//! channel input edges ([`ClockSource`]), and a trigger input can restart,
+//! gate or start it ([`SlaveMode`]). BFTMs have no external inputs.
 
 use crate::pac::Gptm1;
 
+#[cfg(feature = "time")]
 use embassy_time::Duration;
 use embassy_sync::waitqueue::AtomicWaker;
-use core::marker::PhantomData;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use critical_section::Mutex;
+
+use crate::peripheral::{Peri, Peripheral, PeripheralType};
+use crate::regs::RegisterAccess;
+
+/// Interrupt hook a driver installs to take over a timer's interrupt
+pub type Handler = Mutex<Cell<Option<fn()>>>;
 
 /// Timer instance trait
-pub trait Instance {
+pub trait Instance: PeripheralType {
     /// Get the timer register block
     fn regs() -> &'static crate::pac::gptm0::RegisterBlock;
 
     /// Get the timer interrupt waker
     fn waker() -> &'static AtomicWaker;
+
+    /// Counter overflows seen by the interrupt handler
+    fn overflows() -> &'static AtomicU32;
+
+    /// Driver hook run instead of the default interrupt handling
+    fn handler() -> &'static Handler;
+
+    /// Enable timer clock
+    fn enable_clock();
 }
 
 /// Timer 0
@@ -26,6 +49,8 @@ impl Timer0 {
     }
 }
 
+impl_peripheral!(Timer0, Timer1, Bftm0, Bftm1);
+
 impl Instance for Timer0 {
     fn regs() -> &'static crate::pac::gptm0::RegisterBlock {
         unsafe { &*crate::pac::Gptm0::ptr() }
@@ -35,6 +60,21 @@ impl Instance for Timer0 {
         static WAKER: AtomicWaker = AtomicWaker::new();
         &WAKER
     }
+
+    fn overflows() -> &'static AtomicU32 {
+        static OVERFLOWS: AtomicU32 = AtomicU32::new(0);
+        &OVERFLOWS
+    }
+
+    fn handler() -> &'static Handler {
+        static HANDLER: Handler = Mutex::new(Cell::new(None));
+        &HANDLER
+    }
+
+    fn enable_clock() {
+        let ckcu = unsafe { &*crate::pac::Ckcu::ptr() };
+        ckcu.apbccr1().modify(|_, w| w.gptm0en().set_bit());
+    }
 }
 
 /// Timer 1
@@ -57,19 +97,103 @@ impl Instance for Timer1 {
         static WAKER: AtomicWaker = AtomicWaker::new();
         &WAKER
     }
+
+    fn overflows() -> &'static AtomicU32 {
+        static OVERFLOWS: AtomicU32 = AtomicU32::new(0);
+        &OVERFLOWS
+    }
+
+    fn handler() -> &'static Handler {
+        static HANDLER: Handler = Mutex::new(Cell::new(None));
+        &HANDLER
+    }
+
+    fn enable_clock() {
+        let ckcu = unsafe { &*crate::pac::Ckcu::ptr() };
+        ckcu.apbccr1().modify(|_, w| w.gptm1en().set_bit());
+    }
+}
+
+/// Basic function timer (BFTM) instance trait
+///
+/// BFTMs are 32-bit up-counters clocked from PCLK with a single compare
+/// register; the counter restarts from 0 on a compare match.
+pub trait BftmInstance {
+    /// Get the BFTM register block
+    fn regs() -> &'static crate::pac::bftm0::RegisterBlock;
+
+    /// Enable BFTM clock
+    fn enable_clock();
+
+    /// NVIC interrupt line
+    fn interrupt() -> crate::pac::Interrupt;
+}
+
+/// Basic function timer 0
+pub struct Bftm0 {
+    _private: (),
+}
+
+impl Bftm0 {
+    pub(crate) fn new() -> Self {
+        Self { _private: () }
+    }
+}
+
+impl BftmInstance for Bftm0 {
+    fn regs() -> &'static crate::pac::bftm0::RegisterBlock {
+        unsafe { &*crate::pac::Bftm0::ptr() }
+    }
+
+    fn enable_clock() {
+        let ckcu = unsafe { &*crate::pac::Ckcu::ptr() };
+        ckcu.apbccr1().modify(|_, w| w.bftm0en().set_bit());
+    }
+
+    fn interrupt() -> crate::pac::Interrupt {
+        crate::pac::Interrupt::BFTM0
+    }
+}
+
+/// Basic function timer 1
+pub struct Bftm1 {
+    _private: (),
+}
+
+impl Bftm1 {
+    pub(crate) fn new() -> Self {
+        Self { _private: () }
+    }
+}
+
+impl BftmInstance for Bftm1 {
+    fn regs() -> &'static crate::pac::bftm0::RegisterBlock {
+        unsafe { &*crate::pac::Bftm1::ptr() }
+    }
+
+    fn enable_clock() {
+        let ckcu = unsafe { &*crate::pac::Ckcu::ptr() };
+        ckcu.apbccr1().modify(|_, w| w.bftm1en().set_bit());
+    }
+
+    fn interrupt() -> crate::pac::Interrupt {
+        crate::pac::Interrupt::BFTM1
+    }
 }
 
 // Note: HT32F523x2 only has GPTM0 and GPTM1 available
 // Additional timer instances would be added here for other HT32 variants
 
 /// Generic timer driver
-pub struct Timer<T: Instance> {
-    _instance: PhantomData<T>,
+pub struct Timer<'d, T: Instance> {
+    _timer: Peri<'d, T>,
 }
 
-impl<T: Instance> Timer<T> {
+impl<'d, T: Instance> Timer<'d, T> {
     /// Create a new timer instance
-    pub fn new() -> Self {
+    ///
+    /// The timer may be borrowed, see [`peripheral`](crate::peripheral).
+    pub fn new(timer: impl Peripheral<P = T> + 'd) -> Self {
         // Initialize the timer hardware
         let regs = T::regs();
 
@@ -77,19 +201,20 @@ impl<T: Instance> Timer<T> {
         regs.gptm_ctr().modify(|_, w| w.tme().clear_bit()); // Disable timer
         regs.gptm_mdcfr().modify(|_, w| w.tse().bit(true)); // Up counting mode
 
-        Self {
-            _instance: PhantomData,
-        }
+        Self { _timer: timer.into_ref() }
     }
 
     /// Start a one-shot timer for the given duration
+    #[cfg(feature = "time")]
     pub async fn sleep(&mut self, duration: Duration) {
-        let _regs = T::regs();
-        let _waker = T::waker();
+        self.sleep_micros(duration.as_micros()).await;
+    }
 
+    /// Start a one-shot timer for the given number of microseconds
+    pub async fn sleep_micros(&mut self, micros: u64) {
         // Calculate timer parameters based on system clock
         let clock_freq = crate::rcc::get_clocks().apb_clk().to_hz();
-        let ticks = (duration.as_micros() as u64 * clock_freq as u64) / 1_000_000;
+        let ticks = (micros * clock_freq as u64) / 1_000_000;
 
         if ticks > u32::MAX as u64 {
             // Duration too long, split into multiple waits
@@ -152,8 +277,365 @@ impl<T: Instance> Timer<T> {
     }
 }
 
-// Interrupt handlers would go here
-// These need to be implemented for each timer instance
+// MDCFR slave mode select
+const MDCFR_SMSEL_SHIFT: u32 = 8;
+const MDCFR_SMSEL_MASK: u32 = 0b111 << MDCFR_SMSEL_SHIFT;
+const SMSEL_RESTART: u32 = 0b100;
+const SMSEL_PAUSE: u32 = 0b101;
+const SMSEL_TRIGGER: u32 = 0b110;
+const SMSEL_STIED: u32 = 0b111;
+
+// TRCFR trigger select and external clock mode enable
+const TRCFR_TRSEL_MASK: u32 = 0xF;
+const TRCFR_ECME: u32 = 1 << 24;
+
+// ETCR external trigger filter, prescaler and polarity
+const ETCR_ETF_MASK: u32 = 0xF;
+const ETCR_ETIPSC_SHIFT: u32 = 12;
+const ETCR_ETIPOL: u32 = 1 << 16;
+
+// CHxICFR: capture source = direct TIx input, digital filter in [3:0]
+pub(crate) const ICFR_CCS_DIRECT: u32 = 0b01 << 16;
+const ICFR_TIF_MASK: u32 = 0xF;
+
+/// GPTM ETR / channel input pin (AF4)
+pub trait TimerInputPin<T> {}
+
+impl<T: Instance, const PORT: char, const PIN: u8> TimerInputPin<T> for crate::gpio::Pin<PORT, PIN, crate::gpio::mode::AF4> {}
+
+/// GPTM channel output pin (AF4)
+pub trait TimerOutputPin<T> {}
+
+impl<T: Instance, const PORT: char, const PIN: u8> TimerOutputPin<T> for crate::gpio::Pin<PORT, PIN, crate::gpio::mode::AF4> {}
+
+/// Edge or level polarity of a timer input
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Polarity {
+    /// Rising edge / active high
+    Rising,
+    /// Falling edge / active low
+    Falling,
+}
+
+/// ETR input prescaler
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EtrPrescaler {
+    Div1,
+    Div2,
+    Div4,
+    Div8,
+}
+
+/// External trigger (ETR) input configuration
+#[derive(Debug, Copy, Clone)]
+pub struct EtrConfig {
+    pub polarity: Polarity,
+    /// Divide the ETR signal before it reaches the counter; keep the result
+    /// below a quarter of PCLK
+    pub prescaler: EtrPrescaler,
+    /// Digital filter setting (0 = off, 1..=15 = longer filters)
+    pub filter: u8,
+}
+
+impl Default for EtrConfig {
+    fn default() -> Self {
+        Self {
+            polarity: Polarity::Rising,
+            prescaler: EtrPrescaler::Div1,
+            filter: 0,
+        }
+    }
+}
+
+/// Trigger input for the slave modes
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TriggerInput {
+    /// Filtered channel 0 input
+    Ch0,
+    /// Filtered channel 1 input
+    Ch1,
+    /// Both edges of the channel 0 input
+    Ch0BothEdges,
+    /// Filtered ETR input
+    Etr,
+}
+
+impl TriggerInput {
+    /// TRCFR TRSEL value
+    fn trsel(self) -> u32 {
+        match self {
+            TriggerInput::Ch0 => 0b0001,
+            TriggerInput::Ch1 => 0b0010,
+            TriggerInput::Etr => 0b0011,
+            TriggerInput::Ch0BothEdges => 0b1000,
+        }
+    }
+}
+
+/// Counter clock
+#[derive(Debug, Copy, Clone)]
+pub enum ClockSource {
+    /// PCLK through the prescaler (reset default)
+    Internal,
+    /// Count edges on the ETR pin (external clock mode 2)
+    ///
+    /// Leaves the slave mode free, so the count can still be gated.
+    Etr(EtrConfig),
+    /// Count rising edges of a trigger input (external clock mode 1)
+    ///
+    /// Uses the slave mode controller; it cannot be combined with [`SlaveMode`].
+    Trigger(TriggerInput),
+}
+
+/// How the trigger input controls the counter
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SlaveMode {
+    /// Trigger input ignored
+    Disabled,
+    /// Reset the counter on each trigger edge
+    Restart,
+    /// Count only while the trigger input is high (gated counting)
+    Gated,
+    /// Start counting on the first trigger edge
+    Triggered,
+}
+
+/// External clock and trigger control
+impl<'d, T: Instance> Timer<'d, T> {
+    fn modify_mdcfr_smsel(smsel: u32) {
+        T::regs().gptm_mdcfr().modify(|r, w| unsafe {
+            w.bits((r.bits() & !MDCFR_SMSEL_MASK) | (smsel << MDCFR_SMSEL_SHIFT))
+        });
+    }
+
+    fn select_trigger(input: TriggerInput) {
+        let regs = T::regs();
+        regs.gptm_trcfr().modify(|r, w| unsafe {
+            w.bits((r.bits() & !TRCFR_TRSEL_MASK) | input.trsel())
+        });
+
+        // Channel triggers are taken from the capture input stage
+        match input {
+            TriggerInput::Ch0 | TriggerInput::Ch0BothEdges => {
+                regs.gptm_ch0icfr().modify(|r, w| unsafe { w.bits(r.bits() | ICFR_CCS_DIRECT) })
+            }
+            TriggerInput::Ch1 => {
+                regs.gptm_ch1icfr().modify(|r, w| unsafe { w.bits(r.bits() | ICFR_CCS_DIRECT) })
+            }
+            TriggerInput::Etr => {}
+        }
+    }
+
+    /// Select what clocks the counter
+    pub fn set_clock_source(&mut self, source: ClockSource) {
+        let regs = T::regs();
+
+        match source {
+            ClockSource::Internal => {
+                regs.gptm_trcfr().modify(|r, w| unsafe { w.bits(r.bits() & !TRCFR_ECME) });
+                if regs.gptm_mdcfr().read().bits() & MDCFR_SMSEL_MASK == SMSEL_STIED << MDCFR_SMSEL_SHIFT {
+                    Self::modify_mdcfr_smsel(0);
+                }
+            }
+            ClockSource::Etr(config) => {
+                let psc = match config.prescaler {
+                    EtrPrescaler::Div1 => 0,
+                    EtrPrescaler::Div2 => 1,
+                    EtrPrescaler::Div4 => 2,
+                    EtrPrescaler::Div8 => 3,
+                };
+                let pol = if config.polarity == Polarity::Falling { ETCR_ETIPOL } else { 0 };
+                regs.gptm_etcr().write(|w| unsafe {
+                    w.bits((config.filter as u32 & ETCR_ETF_MASK) | (psc << ETCR_ETIPSC_SHIFT) | pol)
+                });
+                regs.gptm_trcfr().modify(|r, w| unsafe { w.bits(r.bits() | TRCFR_ECME) });
+            }
+            ClockSource::Trigger(input) => {
+                regs.gptm_trcfr().modify(|r, w| unsafe { w.bits(r.bits() & !TRCFR_ECME) });
+                Self::select_trigger(input);
+                Self::modify_mdcfr_smsel(SMSEL_STIED);
+            }
+        }
+    }
+
+    /// Let `trigger` restart, gate or start the counter
+    pub fn set_slave_mode(&mut self, mode: SlaveMode, trigger: TriggerInput) {
+        let smsel = match mode {
+            SlaveMode::Disabled => 0,
+            SlaveMode::Restart => SMSEL_RESTART,
+            SlaveMode::Gated => SMSEL_PAUSE,
+            SlaveMode::Triggered => SMSEL_TRIGGER,
+        };
+        if mode != SlaveMode::Disabled {
+            Self::select_trigger(trigger);
+        }
+        Self::modify_mdcfr_smsel(smsel);
+    }
+
+    /// Set the digital filter (0..=15) and polarity of a channel input used as a trigger
+    pub fn set_input_filter(&mut self, channel: Channel, filter: u8, polarity: Polarity) {
+        let regs = T::regs();
+        let filter = filter as u32 & ICFR_TIF_MASK;
+        let set_filter = |r: u32| (r & !ICFR_TIF_MASK) | filter;
+        match channel {
+            Channel::Ch0 => regs.gptm_ch0icfr().modify(|r, w| unsafe { w.bits(set_filter(r.bits())) }),
+            Channel::Ch1 => regs.gptm_ch1icfr().modify(|r, w| unsafe { w.bits(set_filter(r.bits())) }),
+            Channel::Ch2 => regs.gptm_ch2icfr().modify(|r, w| unsafe { w.bits(set_filter(r.bits())) }),
+            Channel::Ch3 => regs.gptm_ch3icfr().modify(|r, w| unsafe { w.bits(set_filter(r.bits())) }),
+        }
+
+        // CHPOLR: CHxP at bit 2 * x
+        let bit = 1 << (2 * channel as u32);
+        regs.gptm_chpolr().modify(|r, w| unsafe {
+            let bits = r.bits() & !bit;
+            w.bits(if polarity == Polarity::Falling { bits | bit } else { bits })
+        });
+    }
+
+    /// Claim the ETR or channel input pin; configure it with `into_alternate_function::<4>()`
+    pub fn with_input_pin<P: TimerInputPin<T>>(self, _pin: impl Peripheral<P = P> + 'd) -> Self {
+        self
+    }
+
+    /// Set the counter reload value (counts run 0..=`period`)
+    pub fn set_period(&mut self, period: u16) {
+        T::regs().gptm_crr().write(|w| unsafe { w.bits(period as u32) });
+    }
+
+    /// Clear the counter
+    pub fn reset_counter(&mut self) {
+        T::regs().gptm_cntr().write(|w| unsafe { w.bits(0) });
+    }
+
+    /// Enable counting; with [`SlaveMode::Triggered`] the count starts at the trigger
+    pub fn start(&mut self) {
+        T::regs().gptm_ctr().modify(|_, w| w.tme().set_bit());
+    }
+
+    /// Stop counting
+    pub fn stop(&mut self) {
+        T::regs().gptm_ctr().modify(|_, w| w.tme().clear_bit());
+    }
+}
+
+// DICTR / INTSR bits
+pub(crate) const INT_CH0CC: u32 = 1 << 0;
+pub(crate) const INT_CH3CC: u32 = 1 << 3;
+const INT_CC_ALL: u32 = 0xF;
+pub(crate) const INT_UEV: u32 = 1 << 8;
+
+/// GPTM interrupt handler body
+///
+/// Counts update events (counter overflows) and wakes the instance waker.
+/// Compare interrupts are masked again and their flags left set for the
+/// waiting future to see and clear. A hook installed with [`set_handler`]
+/// replaces all of this.
+pub(crate) fn on_interrupt<T: Instance>() {
+    if let Some(handler) = critical_section::with(|cs| T::handler().borrow(cs).get()) {
+        handler();
+        return;
+    }
+
+    let regs = T::regs();
+    let dictr = regs.gptm_dictr().read().bits();
+    let flags = regs.gptm_intsr().read().bits() & dictr;
+
+    if flags & INT_UEV != 0 {
+        // INTSR flags are cleared by writing 0
+        regs.gptm_intsr().write(|w| unsafe { w.bits(!INT_UEV) });
+        // Only this handler writes the count outside a critical section
+        let overflows = T::overflows();
+        overflows.store(overflows.load(Ordering::Relaxed).wrapping_add(1), Ordering::Release);
+    }
+    if flags & INT_CC_ALL != 0 {
+        regs.gptm_dictr().write(|w| unsafe { w.bits(dictr & !(flags & INT_CC_ALL)) });
+    }
+
+    T::waker().wake();
+}
+
+/// Route the timer's interrupt to `handler`, or back to the default with `None`
+pub(crate) fn set_handler<T: Instance>(handler: Option<fn()>) {
+    critical_section::with(|cs| T::handler().borrow(cs).set(handler));
+}
+
+/// Width of the GPTM counter
+pub const COUNTER_BITS: u32 = 16;
+
+/// Extend a reading of a free-running `bits`-wide counter to 64 bits
+///
+/// `last` is the previous extended value. The result is correct as long as
+/// readings are taken at least once per counter period.
+pub fn extend_counter(last: u64, counter: u32, bits: u32) -> u64 {
+    let mask = (1u64 << bits) - 1;
+    let now = (last & !mask) | (counter as u64 & mask);
+    if now < last { now + (1u64 << bits) } else { now }
+}
+
+/// A free-running GPTM count extended to 64 bits
+///
+/// The counter register is read through [`RegisterAccess`], so the host
+/// tests drive it from [`MockRegisters`](crate::regs). Reads must come less
+/// than one counter period apart. [`restart`](Self::restart) carries the
+/// count across a reset of the counter, e.g. to load a new prescaler.
+pub struct ExtendedCounter {
+    /// Count at the last restart
+    base: Cell<u64>,
+    /// Counter ticks since the last restart, extended
+    ticks: Cell<u64>,
+}
+
+impl ExtendedCounter {
+    /// Start at 0 with the counter at 0
+    pub const fn new() -> Self {
+        Self {
+            base: Cell::new(0),
+            ticks: Cell::new(0),
+        }
+    }
+
+    /// Read the counter register at `cntr` and return the extended count
+    pub fn read(&self, regs: &impl RegisterAccess, cntr: usize) -> u64 {
+        let ticks = extend_counter(self.ticks.get(), regs.read(cntr), COUNTER_BITS);
+        self.ticks.set(ticks);
+        self.base.get() + ticks
+    }
+
+    /// Continue from `now` after the counter was reset to 0
+    ///
+    /// `now` is a [`read`](Self::read) taken just before the reset; ticks
+    /// between the two are lost.
+    pub fn restart(&self, now: u64) {
+        self.base.set(now);
+        self.ticks.set(0);
+    }
+}
+
+impl Default for ExtendedCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "rt")]
+use crate::pac::interrupt;
+
+// GPTM0 belongs to the time driver when it is enabled
+#[cfg(all(feature = "rt", not(feature = "time-driver")))]
+#[interrupt]
+fn GPTM0() {
+    #[cfg(feature = "task-trace")]
+    let _isr = crate::task_trace::Isr::enter();
+    on_interrupt::<Timer0>();
+}
+
+#[cfg(feature = "rt")]
+#[interrupt]
+fn GPTM1() {
+    #[cfg(feature = "task-trace")]
+    let _isr = crate::task_trace::Isr::enter();
+    on_interrupt::<Timer1>();
+}
 
 /// Initialize embassy-time using a hardware timer
 pub fn init_embassy_time() {
@@ -162,6 +644,7 @@ pub fn init_embassy_time() {
 }
 
 /// PWM channel configuration
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Channel {
     Ch0,
     Ch1,
@@ -170,21 +653,21 @@ pub enum Channel {
 }
 
 /// PWM driver
-pub struct Pwm<T: Instance> {
-    _instance: PhantomData<T>,
+pub struct Pwm<'d, T: Instance> {
+    _timer: Peri<'d, T>,
 }
 
-impl<T: Instance> Pwm<T> {
+impl<'d, T: Instance> Pwm<'d, T> {
     /// Create a new PWM instance
-    pub fn new() -> Self {
+    ///
+    /// The timer may be borrowed, see [`peripheral`](crate::peripheral).
+    pub fn new(timer: impl Peripheral<P = T> + 'd) -> Self {
         let regs = T::regs();
 
         // Configure timer for PWM mode
         regs.gptm_mdcfr().modify(|_, w| w.tse().bit(true)); // Up counting
 
-        Self {
-            _instance: PhantomData,
-        }
+        Self { _timer: timer.into_ref() }
     }
 
     /// Set PWM duty cycle for a channel
@@ -1,16 +1,22 @@
 //! UART (Universal Asynchronous Receiver/Transmitter) driver
+//!
+//! The USART only inverts its lines in IrDA mode. Idle-low links such as
+//! SBUS need an external inverter, e.g. a transistor or a 74LVC1G04, between
+//! the pin and the bus.
 
-use core::marker::PhantomData;
 use embassy_sync::waitqueue::AtomicWaker;
 use embedded_hal_nb::serial::{ErrorKind};
 use embedded_hal_nb::serial::{ErrorType, Read, Write};
 use nb;
 
+use crate::gpio::{mode, Pin};
 use crate::pac::{Usart0 as Usart0Pac, Usart1 as Usart1Pac};
+use crate::peripheral::{Peri, Peripheral, PeripheralType};
 use crate::time::Hertz;
 
 /// UART error
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Error {
     /// Framing error
     Framing,
@@ -22,6 +28,8 @@ pub enum Error {
     Parity,
     /// Buffer full
     BufferFull,
+    /// A `*_timeout` operation did not finish in time
+    Timeout,
 }
 
 impl embedded_hal_nb::serial::Error for Error {
@@ -32,16 +40,54 @@ impl embedded_hal_nb::serial::Error for Error {
             Error::Overrun => ErrorKind::Overrun,
             Error::Parity => ErrorKind::Parity,
             Error::BufferFull => ErrorKind::Other,
+            Error::Timeout => ErrorKind::Other,
         }
     }
 }
 
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Error::Framing => "framing error",
+            Error::Noise => "noise error",
+            Error::Overrun => "RX overrun",
+            Error::Parity => "parity error",
+            Error::BufferFull => "buffer full",
+            Error::Timeout => "timed out",
+        })
+    }
+}
+
+impl core::error::Error for Error {}
+
+/// A [`Config`] the USART cannot do
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConfigError {
+    /// Word lengths below 7 bits; the USART only has 7, 8 and 9
+    DataBits,
+}
+
+impl core::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            ConfigError::DataBits => "USART only supports 7, 8 or 9 data bits",
+        })
+    }
+}
+
+impl core::error::Error for ConfigError {}
+
 /// UART TX pin trait
 pub trait UartTx<T> {}
 
 /// UART RX pin trait
 pub trait UartRx<T> {}
 
+// USART pins are on AF6
+impl<T: Instance, const PORT: char, const PIN: u8> UartTx<T> for Pin<PORT, PIN, mode::AF6> {}
+impl<T: Instance, const PORT: char, const PIN: u8> UartRx<T> for Pin<PORT, PIN, mode::AF6> {}
+
 /// UART configuration
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -69,10 +115,81 @@ impl Default for Config {
     }
 }
 
+impl Config {
+    /// Check the frame format against what the USART can do
+    ///
+    /// Any parity and stop bit setting works with 7, 8 and 9 data bits; the
+    /// parity bit comes on top of the data bits.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if matches!(self.data_bits, DataBits::Five | DataBits::Six) {
+            return Err(ConfigError::DataBits);
+        }
+        Ok(())
+    }
+}
+
+/// Smallest USRDLR divisor the USART accepts
+const BRD_MIN: u32 = 16;
+/// Largest USRDLR divisor
+const BRD_MAX: u32 = 0xFFFF;
+
+/// Baud rate generator setting and the rate it actually produces
+///
+/// The USART clock is PCLK and `baud = PCLK / BRD`. USRDLR only holds an
+/// integer divisor, so the divisor is rounded to the nearest value; at
+/// 48 MHz this gives 923077 baud (+0.16 %) for 921600.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BaudRate {
+    /// Requested rate in baud
+    pub requested: u32,
+    /// Achieved rate in baud
+    pub actual: u32,
+    /// USRDLR value
+    pub divisor: u16,
+}
+
+impl BaudRate {
+    /// Best divisor for `baud` with the USART clocked at `pclk` Hz
+    ///
+    /// Rates outside what the divisor can reach are clamped to the nearest
+    /// reachable rate; check `error_ppm` to catch that.
+    pub const fn calculate(pclk: u32, baud: u32) -> Self {
+        let baud = if baud == 0 { 1 } else { baud };
+        let mut divisor = (pclk + baud / 2) / baud;
+        if divisor < BRD_MIN {
+            divisor = BRD_MIN;
+        } else if divisor > BRD_MAX {
+            divisor = BRD_MAX;
+        }
+
+        Self {
+            requested: baud,
+            actual: pclk / divisor,
+            divisor: divisor as u16,
+        }
+    }
+
+    /// Deviation of the achieved rate from the requested one in parts per million
+    pub const fn error_ppm(&self) -> i32 {
+        let diff = self.actual as i64 - self.requested as i64;
+        (diff * 1_000_000 / self.requested as i64) as i32
+    }
+
+    /// Whether the achieved rate is within `tolerance_ppm` of the requested one
+    pub const fn within(&self, tolerance_ppm: u32) -> bool {
+        self.error_ppm().unsigned_abs() <= tolerance_ppm
+    }
+}
+
 /// Data bits
+///
+/// With `Nine`, use [`Uart::write_word`] and [`Uart::read_word`]; the byte
+/// methods drop the ninth bit.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum DataBits {
+    /// Not supported by the USART, see [`ConfigError::DataBits`]
     Five,
+    /// Not supported by the USART, see [`ConfigError::DataBits`]
     Six,
     Seven,
     Eight,
@@ -92,10 +209,14 @@ pub enum Parity {
     None,
     Even,
     Odd,
+    /// Parity bit always 1
+    Mark,
+    /// Parity bit always 0
+    Space,
 }
 
 /// UART instance trait
-pub trait Instance {
+pub trait Instance: PeripheralType {
     /// Get the UART register block
     fn regs() -> &'static crate::pac::usart0::RegisterBlock;
 
@@ -120,6 +241,8 @@ impl Usart0 {
     }
 }
 
+impl_peripheral!(Usart0, Usart1);
+
 impl Instance for Usart0 {
     fn regs() -> &'static crate::pac::usart0::RegisterBlock {
         unsafe { &*Usart0Pac::ptr() }
@@ -174,18 +297,38 @@ impl Instance for Usart1 {
 }
 
 /// UART driver
-pub struct Uart<T: Instance> {
-    _instance: PhantomData<T>,
+pub struct Uart<'d, T: Instance> {
+    _uart: Peri<'d, T>,
+    baud: BaudRate,
 }
 
-impl<T: Instance> Uart<T> {
+impl<'d, T: Instance> Uart<'d, T> {
     /// Create a new UART instance
-    pub fn new(
-        _uart: T,
-        _tx_pin: impl UartTx<T>,
-        _rx_pin: impl UartRx<T>,
+    ///
+    /// Panics if the USART cannot do `config`, see [`Uart::try_new`].
+    pub fn new<TX: UartTx<T>, RX: UartRx<T>>(
+        uart: impl Peripheral<P = T> + 'd,
+        tx_pin: impl Peripheral<P = TX> + 'd,
+        rx_pin: impl Peripheral<P = RX> + 'd,
         config: Config,
     ) -> Self {
+        match Self::try_new(uart, tx_pin, rx_pin, config) {
+            Ok(uart) => uart,
+            Err(e) => panic!("UART: {}", e),
+        }
+    }
+
+    /// Create a new UART instance, checking `config` with [`Config::validate`] first
+    ///
+    /// The USART and pins may be borrowed, see [`peripheral`](crate::peripheral).
+    pub fn try_new<TX: UartTx<T>, RX: UartRx<T>>(
+        uart: impl Peripheral<P = T> + 'd,
+        _tx_pin: impl Peripheral<P = TX> + 'd,
+        _rx_pin: impl Peripheral<P = RX> + 'd,
+        config: Config,
+    ) -> Result<Self, ConfigError> {
+        config.validate()?;
+
         // Enable clock
         T::enable_clock();
 
@@ -197,21 +340,16 @@ impl<T: Instance> Uart<T> {
              .urrxen().clear_bit()
         });
 
-        // Configure baud rate
-        let clock_freq = crate::rcc::get_clocks().apb_clk().to_hz();
-        let baudrate = config.baudrate.to_hz();
-        let brr = clock_freq / baudrate;
-        regs.usart_usrdlr().write(|w| unsafe { w.bits(brr) });
+        // Configure baud rate from the real PCLK
+        let baud = Self::apply_baudrate(config.baudrate);
 
         // Configure data format in control register
         regs.usart_usrcr().modify(|_, w| {
-            // Data bits
+            // Data bits; 0b11 is reserved, and validate() has ruled out 5 and 6
             let wls = match config.data_bits {
-                DataBits::Five => 0b00,
-                DataBits::Six => 0b01,
-                DataBits::Seven => 0b10,
-                DataBits::Eight => 0b11,
-                DataBits::Nine => 0b11, // Use 8 bits + parity for 9-bit mode
+                DataBits::Seven | DataBits::Five | DataBits::Six => 0b00,
+                DataBits::Eight => 0b01,
+                DataBits::Nine => 0b10,
             };
 
             // Stop bits
@@ -220,11 +358,13 @@ impl<T: Instance> Uart<T> {
                 StopBits::Two => true,
             };
 
-            // Parity
-            let (pbe, epe) = match config.parity {
-                Parity::None => (false, false),
-                Parity::Even => (true, true),
-                Parity::Odd => (true, false),
+            // Parity; stick parity sends EPE inverted as a fixed bit
+            let (pbe, epe, spe) = match config.parity {
+                Parity::None => (false, false, false),
+                Parity::Even => (true, true, false),
+                Parity::Odd => (true, false, false),
+                Parity::Mark => (true, false, true),
+                Parity::Space => (true, true, true),
             };
 
             unsafe {
@@ -232,6 +372,7 @@ impl<T: Instance> Uart<T> {
                  .nsb().bit(nsb)
                  .pbe().bit(pbe)
                  .epe().bit(epe)
+                 .spe().bit(spe)
             }
         });
 
@@ -254,17 +395,61 @@ impl<T: Instance> Uart<T> {
              .urrxen().set_bit()     // RX enable
         });
 
-        Self {
-            _instance: PhantomData,
+        Ok(Self {
+            _uart: uart.into_ref(),
+            baud,
+        })
+    }
+
+    fn apply_baudrate(baudrate: Hertz) -> BaudRate {
+        let pclk = crate::rcc::get_clocks().apb_clk().to_hz();
+        let baud = BaudRate::calculate(pclk, baudrate.to_hz());
+        T::regs().usart_usrdlr().write(|w| unsafe { w.bits(baud.divisor as u32) });
+        baud
+    }
+
+    /// Baud rate setting in use, including the achieved rate and its error
+    pub fn baud_rate(&self) -> BaudRate {
+        self.baud
+    }
+
+    /// Change the baud rate and return the achieved setting
+    ///
+    /// Waits for the transmitter to drain first so a frame in flight is not corrupted.
+    pub fn set_baudrate(&mut self, baudrate: Hertz) -> BaudRate {
+        while T::regs().usart_usrsifr().read().txc().bit_is_clear() {}
+        self.baud = Self::apply_baudrate(baudrate);
+        self.baud
+    }
+
+    /// Hold TX low (send a break) until called again with `false`
+    ///
+    /// Wait for [`wait_tx_complete`](Self::wait_tx_complete) first, or the
+    /// frame still in the shift register is cut short.
+    pub fn set_break(&mut self, on: bool) {
+        T::regs().usart_usrcr().modify(|_, w| w.bcb().bit(on));
+    }
+
+    /// Wait until the last frame has left the shift register, not just the TX FIFO
+    pub async fn wait_tx_complete(&mut self) {
+        // TXC has no interrupt enabled; it is at most one frame away once TXDE is set
+        self.flush().await.ok();
+        while T::regs().usart_usrsifr().read().txc().bit_is_clear() {
+            embassy_futures::yield_now().await;
         }
     }
 
     /// Write a single byte (blocking)
     pub fn write_byte(&mut self, byte: u8) -> nb::Result<(), Error> {
+        self.write_word(byte as u16)
+    }
+
+    /// Write a single word of up to 9 bits, for [`DataBits::Nine`]
+    pub fn write_word(&mut self, word: u16) -> nb::Result<(), Error> {
         let regs = T::regs();
 
         if regs.usart_usrsifr().read().txde().bit_is_set() {
-            regs.usart_usrdr().write(|w| unsafe { w.bits(byte as u32) });
+            regs.usart_usrdr().write(|w| unsafe { w.bits((word & 0x1FF) as u32) });
             Ok(())
         } else {
             Err(nb::Error::WouldBlock)
@@ -273,6 +458,11 @@ impl<T: Instance> Uart<T> {
 
     /// Read a single byte (blocking)
     pub fn read_byte(&mut self) -> nb::Result<u8, Error> {
+        self.read_word().map(|word| word as u8)
+    }
+
+    /// Read a single word of up to 9 bits, for [`DataBits::Nine`]
+    pub fn read_word(&mut self) -> nb::Result<u16, Error> {
         let regs = T::regs();
         let lsr = regs.usart_usrsifr().read();
 
@@ -288,13 +478,40 @@ impl<T: Instance> Uart<T> {
         }
 
         if lsr.rxdr().bit_is_set() {
-            Ok(regs.usart_usrdr().read().bits() as u8)
+            Ok((regs.usart_usrdr().read().bits() & 0x1FF) as u16)
         } else {
             Err(nb::Error::WouldBlock)
         }
     }
 
+    /// Write a buffer, busy-waiting on the TX buffer
+    #[cfg(feature = "blocking")]
+    pub fn blocking_write(&mut self, buffer: &[u8]) -> Result<(), Error> {
+        for &byte in buffer {
+            nb::block!(self.write_byte(byte))?;
+        }
+        Ok(())
+    }
+
+    /// Fill a buffer, busy-waiting on the RX buffer
+    #[cfg(feature = "blocking")]
+    pub fn blocking_read(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        for slot in buffer.iter_mut() {
+            *slot = nb::block!(self.read_byte())?;
+        }
+        Ok(())
+    }
+
+    /// Busy-wait until the TX buffer is empty
+    #[cfg(feature = "blocking")]
+    pub fn blocking_flush(&mut self) -> Result<(), Error> {
+        nb::block!(Write::flush(self))
+    }
+
     /// Write a buffer asynchronously
+    ///
+    /// Cancel-safe: dropping the future only stops feeding the transmitter;
+    /// bytes already in it are still sent.
     pub async fn write(&mut self, buffer: &[u8]) -> Result<(), Error> {
         for &byte in buffer {
             self.write_byte_async(byte).await?;
@@ -303,6 +520,8 @@ impl<T: Instance> Uart<T> {
     }
 
     /// Read into a buffer asynchronously
+    ///
+    /// Cancel-safe: bytes not yet taken stay in the RX FIFO for the next read.
     pub async fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
         let mut count = 0;
         for slot in buffer.iter_mut() {
@@ -317,6 +536,59 @@ impl<T: Instance> Uart<T> {
         Ok(count)
     }
 
+    /// Write a buffer, giving up with [`Error::Timeout`] after `timeout`
+    ///
+    /// Bytes already handed to the transmitter are still sent.
+    #[cfg(feature = "time")]
+    pub async fn write_timeout(&mut self, buffer: &[u8], timeout: embassy_time::Duration) -> Result<(), Error> {
+        let deadline = embassy_time::Instant::now() + timeout;
+        for &byte in buffer {
+            embassy_time::with_deadline(deadline, self.write_byte_async(byte))
+                .await
+                .map_err(|_| Error::Timeout)??;
+        }
+        Ok(())
+    }
+
+    /// Fill a buffer, giving up with [`Error::Timeout`] after `timeout`
+    ///
+    /// Bytes received before the timeout are left at the start of `buffer`.
+    #[cfg(feature = "time")]
+    pub async fn read_timeout(&mut self, buffer: &mut [u8], timeout: embassy_time::Duration) -> Result<usize, Error> {
+        let deadline = embassy_time::Instant::now() + timeout;
+        for slot in buffer.iter_mut() {
+            *slot = embassy_time::with_deadline(deadline, self.read_byte_async())
+                .await
+                .map_err(|_| Error::Timeout)??;
+        }
+        Ok(buffer.len())
+    }
+
+    /// Receive one frame that ends when the line has been idle for `idle`
+    ///
+    /// Waits as long as it takes for the first byte, then collects bytes
+    /// until none arrives for `idle` (3.5 characters for Modbus RTU). A frame
+    /// longer than `buffer` is read to its end and dropped with
+    /// [`Error::BufferFull`], so the next call starts on a frame boundary.
+    /// Panics if `buffer` is empty.
+    #[cfg(feature = "time")]
+    pub async fn read_until_idle(&mut self, buffer: &mut [u8], idle: embassy_time::Duration) -> Result<usize, Error> {
+        buffer[0] = self.read_byte_async().await?;
+        let mut count = 1;
+        while let Ok(byte) = embassy_time::with_timeout(idle, self.read_byte_async()).await {
+            let byte = byte?;
+            if count == buffer.len() {
+                while let Ok(byte) = embassy_time::with_timeout(idle, self.read_byte_async()).await {
+                    byte?;
+                }
+                return Err(Error::BufferFull);
+            }
+            buffer[count] = byte;
+            count += 1;
+        }
+        Ok(count)
+    }
+
     async fn write_byte_async(&mut self, byte: u8) -> Result<(), Error> {
         let waker = T::tx_waker();
 
@@ -363,11 +635,11 @@ impl<T: Instance> Uart<T> {
 }
 
 // Implement embedded-hal traits
-impl<T: Instance> ErrorType for Uart<T> {
+impl<T: Instance> ErrorType for Uart<'_, T> {
     type Error = Error;
 }
 
-impl<T: Instance> Write<u8> for Uart<T> {
+impl<T: Instance> Write<u8> for Uart<'_, T> {
     fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
         self.write_byte(word)
     }
@@ -382,7 +654,7 @@ impl<T: Instance> Write<u8> for Uart<T> {
     }
 }
 
-impl<T: Instance> Read<u8> for Uart<T> {
+impl<T: Instance> Read<u8> for Uart<'_, T> {
     fn read(&mut self) -> nb::Result<u8, Self::Error> {
         self.read_byte()
     }
@@ -9,25 +9,235 @@
 //! - 4 double-buffered endpoints for bulk, interrupt and isochronous transfer
 //! - 1,024 bytes EP_SRAM for endpoint data buffers
 //! - Total: 8 endpoints (1 control + 7 configurable)
-
+//!
+//! ## Control transfers
+//! EP0 SETUP, DATA and STATUS stages are driven from the USB interrupt, so
+//! class-specific requests (e.g. CDC-ACM `SET_LINE_CODING` and
+//! `SET_CONTROL_LINE_STATE`) reach the embassy-usb class handlers. The `rt`
+//! feature must be enabled so the HAL can install the `USB` interrupt handler.
+//!
+//! ## EP_SRAM copies
+//! Packets are copied a word at a time. With `Config::dma` set, word-aligned
+//! copies of 16 bytes or more go through a reserved PDMA channel instead.
+//!
+//! ## Isochronous endpoints
+//! Isochronous endpoints get two EP_SRAM buffers and run double buffered:
+//! the hardware receives or sends through one while `read` or `write` works
+//! on the other, so a task that comes back a little late does not lose the
+//! frame's packet.
+//!
+//! ## Endpoint budget
+//! [`assert_endpoint_budget`] checks at compile time that a set of classes
+//! fits the 7 configurable endpoints and the EP_SRAM left after EP0.
+//!
+//! ## Power source
+//! [`power_source`] reports how much current the port may supply, so firmware
+//! can scale LED brightness or charging current. The USB block has no BC1.2
+//! data-line contact detection or D+/D- line state, so a dedicated charging
+//! port (D+ shorted to D-) is recognised by VBUS being present without a bus
+//! reset, see [`detect_power_source`].
+//!
+//! ## Board power
+//! Boards with a USB power switch, an external charge pump, a switchable
+//! D+ pull-up or a level shifter on the data lines implement [`UsbSupply`]
+//! and pass it in [`Config::supply`]; the driver calls it when the bus is
+//! enabled and disabled and when it needs the VBUS level.
+//!
+//! ## Detach and attach
+//! [`detach`] takes the device off the bus at run time, e.g. before jumping
+//! to a bootloader, entering deep sleep or on a power-fail warning; it
+//! disconnects the D+ pull-up and holds it off for [`DETACH_MS`] so the hub
+//! registers the disconnect. [`attach`] reconnects, and the host resets and
+//! enumerates the device again.
+//!
+//! ## Frame clock
+//! [`sof_ticker`] yields once per USB start-of-frame, every 1 ms ±500 ppm of
+//! the host's clock, for pacing reports or recovering an audio clock
+//! without `embassy-time`. The SOF interrupt is only enabled while a ticker
+//! exists.
+//!
+//! ## Crystal-less operation
+//! USB full speed needs the 48 MHz clock within ±2500 ppm, which the HSI only
+//! meets near room temperature. With the `usb-crystalless` feature,
+//! [`run_hsi_trim`] measures the HSI against the host's SOF packets and
+//! nudges the HSI trim to hold it there; run it in its own task on boards
+//! without an HSE crystal.
+
+use core::future::poll_fn;
 use core::marker::PhantomData;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::cell::{Cell, RefCell};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::task::Poll;
 
-use embassy_sync::waitqueue::AtomicWaker;
+use critical_section::Mutex;
+use embassy_sync::waitqueue::{AtomicWaker, MultiWakerRegistration};
 use embassy_usb_driver::{
     Direction, EndpointAddress, EndpointAllocError, EndpointError, EndpointInfo, EndpointType,
     Event, Unsupported,
 };
 
+use crate::dma;
+use crate::drop::DropGuard;
+use crate::gpio::AnyPin;
 use crate::pac;
+use crate::peripheral::Peripheral;
+use crate::regs::Mmio;
+
+#[doc(hidden)]
+pub mod sram;
+
+use sram::{EP0_BUF_OFFSET, EP0_SETUP_OFFSET, EP_BUF_START, EP_SRAM_BASE, EP_SRAM_SIZE, MAX_PACKET_SIZE};
 
 // HT32F52352 USB Controller Hardware Specifications
 const MAX_EP_COUNT: usize = 8;          // 1 control EP + 7 configurable EPs
-const MAX_PACKET_SIZE: usize = 64;      // Full-speed USB max packet size
-const EP_SRAM_SIZE: usize = 1024;       // Total endpoint buffer memory
 const SINGLE_BUFFERED_EPS: usize = 3;   // Single-buffered endpoints (bulk/interrupt)
 const DOUBLE_BUFFERED_EPS: usize = 4;   // Double-buffered endpoints (bulk/interrupt/iso)
 
+// USBIER / USBISR bits
+const INT_UGIE: u32 = 1 << 0;
+const INT_SOF: u32 = 1 << 1;
+const INT_URST: u32 = 1 << 2;
+const INT_RSM: u32 = 1 << 3;
+const INT_SUSP: u32 = 1 << 4;
+const INT_EP0: u32 = 1 << 8;
+
+// USBEPnIER / USBEPnISR bits
+const EP_INT_ODRX: u32 = 1 << 1;  // OUT data received
+const EP_INT_IDTX: u32 = 1 << 4;  // IN data transmitted
+const EP_INT_SDRX: u32 = 1 << 9;  // SETUP data received
+
+// USBEPnCSR bits (write 1 to toggle); EP1-EP7 use the TX bits for either direction
+const EP_CSR_DTGTX: u32 = 1 << 0;
+const EP_CSR_NAKTX: u32 = 1 << 1;
+const EP_CSR_STLTX: u32 = 1 << 2;
+const EP_CSR_UDBTG: u32 = 1 << 3; // EP4-EP7 double-buffered: buffer the CPU owns
+const EP_CSR_NAKRX: u32 = 1 << 4; // EP0 only
+const EP_CSR_STLRX: u32 = 1 << 5; // EP0 only
+
+// USBEPnCFGR / USBEPnTCR double-buffer fields, EP4-EP7 only
+const EP_CFGR_SDBS: u32 = 1 << 23; // second buffer at EPBUFA + EPLEN
+const EP_TCR_TCNT1_SHIFT: u32 = 16; // byte count of the second buffer
+const EP_TCR_TCNT_MASK: u32 = 0x1FF;
+
+// Per-endpoint register access, dispatched by endpoint index like the GPIO port macros
+macro_rules! ep_reg {
+    ($ep:expr, csr, |$r:ident| $body:expr) => {
+        ep_reg!(@dispatch $ep, $r, $body; ep0csr, ep1csr, ep2csr, ep3csr, ep4csr, ep5csr, ep6csr, ep7csr)
+    };
+    ($ep:expr, ier, |$r:ident| $body:expr) => {
+        ep_reg!(@dispatch $ep, $r, $body; ep0ier, ep1ier, ep2ier, ep3ier, ep4ier, ep5ier, ep6ier, ep7ier)
+    };
+    ($ep:expr, isr, |$r:ident| $body:expr) => {
+        ep_reg!(@dispatch $ep, $r, $body; ep0isr, ep1isr, ep2isr, ep3isr, ep4isr, ep5isr, ep6isr, ep7isr)
+    };
+    ($ep:expr, tcr, |$r:ident| $body:expr) => {
+        ep_reg!(@dispatch $ep, $r, $body; ep0tcr, ep1tcr, ep2tcr, ep3tcr, ep4tcr, ep5tcr, ep6tcr, ep7tcr)
+    };
+    ($ep:expr, cfgr, |$r:ident| $body:expr) => {
+        ep_reg!(@dispatch $ep, $r, $body; ep0cfgr, ep1cfgr, ep2cfgr, ep3cfgr, ep4cfgr, ep5cfgr, ep6cfgr, ep7cfgr)
+    };
+    (@dispatch $ep:expr, $r:ident, $body:expr;
+     $e0:ident, $e1:ident, $e2:ident, $e3:ident, $e4:ident, $e5:ident, $e6:ident, $e7:ident) => {{
+        let usb = unsafe { &*pac::Usb::ptr() };
+        match $ep {
+            0 => { let $r = usb.$e0(); $body }
+            1 => { let $r = usb.$e1(); $body }
+            2 => { let $r = usb.$e2(); $body }
+            3 => { let $r = usb.$e3(); $body }
+            4 => { let $r = usb.$e4(); $body }
+            5 => { let $r = usb.$e5(); $body }
+            6 => { let $r = usb.$e6(); $body }
+            7 => { let $r = usb.$e7(); $body }
+            _ => panic!("Invalid USB endpoint"),
+        }
+    }};
+}
+
+// State shared with the USB interrupt handler
+const NEW_AW: AtomicWaker = AtomicWaker::new();
+const NEW_FLAG: AtomicBool = AtomicBool::new(false);
+static BUS_WAKER: AtomicWaker = AtomicWaker::new();
+static EP_IN_WAKERS: [AtomicWaker; MAX_EP_COUNT] = [NEW_AW; MAX_EP_COUNT];
+static EP_OUT_WAKERS: [AtomicWaker; MAX_EP_COUNT] = [NEW_AW; MAX_EP_COUNT];
+static IRQ_RESET: AtomicBool = AtomicBool::new(false);
+static IRQ_SUSPEND: AtomicBool = AtomicBool::new(false);
+static IRQ_RESUME: AtomicBool = AtomicBool::new(false);
+static EP0_SETUP: AtomicBool = AtomicBool::new(false);
+static EP_IN_DONE: [AtomicBool; MAX_EP_COUNT] = [NEW_FLAG; MAX_EP_COUNT];
+static EP_OUT_READY: [AtomicBool; MAX_EP_COUNT] = [NEW_FLAG; MAX_EP_COUNT];
+static DEVICE_CONFIGURED: AtomicBool = AtomicBool::new(false);
+/// Endpoints enabled by the current configuration and alternate settings
+static EP_IN_ENABLED: [AtomicBool; MAX_EP_COUNT] = [NEW_FLAG; MAX_EP_COUNT];
+static EP_OUT_ENABLED: [AtomicBool; MAX_EP_COUNT] = [NEW_FLAG; MAX_EP_COUNT];
+/// Bus is suspended (no SOF for 3 ms); cleared by resume or reset
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+/// Woken whenever `SUSPENDED` changes, for `power::run_usb_suspend`
+pub(crate) static SUSPEND_WAKER: AtomicWaker = AtomicWaker::new();
+/// VBUS sense pin (port, pin), if configured
+static VBUS_PIN: Mutex<Cell<Option<(char, u8)>>> = Mutex::new(Cell::new(None));
+/// Board power control from `Config::supply`
+static SUPPLY: Mutex<RefCell<Option<&'static mut dyn UsbSupply>>> = Mutex::new(RefCell::new(None));
+/// Suspend duration treated as a detach without VBUS sensing, 0 = never
+static DETACH_TIMEOUT_MS: AtomicU32 = AtomicU32::new(0);
+/// A bus reset was seen since VBUS appeared, i.e. a host is driving the data lines
+static RESET_SEEN: AtomicBool = AtomicBool::new(false);
+/// bMaxPower granted by the host once configured, in mA
+static MAX_POWER_MA: AtomicU32 = AtomicU32::new(100);
+/// SET_ADDRESS value waiting for its status stage, or [`NO_ADDRESS`]
+static PENDING_ADDRESS: AtomicU32 = AtomicU32::new(NO_ADDRESS);
+const NO_ADDRESS: u32 = u32::MAX;
+/// Start-of-frame interrupts seen while the SOF interrupt was enabled
+static SOF_COUNT: AtomicU32 = AtomicU32::new(0);
+/// Live [`SofTicker`]s; the SOF interrupt is enabled while non-zero
+static SOF_TICKERS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+static SOF_WAKERS: Mutex<RefCell<MultiWakerRegistration<SOF_WAKER_SLOTS>>> =
+    Mutex::new(RefCell::new(MultiWakerRegistration::new()));
+/// Tasks that can wait on SOF tickers at the same time
+const SOF_WAKER_SLOTS: usize = 4;
+/// Set once PDMA has been verified to reach EP_SRAM
+static SRAM_DMA: AtomicBool = AtomicBool::new(false);
+
+/// PDMA channel reserved for EP_SRAM copies when `Config::dma` is set
+const SRAM_DMA_CHANNEL: usize = dma::CHANNEL_COUNT - 1;
+/// Shorter copies are cheaper to do with the CPU than to set up a transfer
+const SRAM_DMA_MIN_WORDS: usize = 4;
+
+/// USB driver error
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The USB clock is not 48 MHz; check `rcc::Config::usb_prescaler`
+    UsbClock,
+    /// Packet larger than the endpoint's max packet size
+    BufferOverflow,
+    /// Endpoint is disabled (device not configured or bus reset)
+    Disabled,
+    /// A `*_timeout` operation did not finish in time
+    Timeout,
+}
+
+impl From<EndpointError> for Error {
+    fn from(error: EndpointError) -> Self {
+        match error {
+            EndpointError::BufferOverflow => Error::BufferOverflow,
+            EndpointError::Disabled => Error::Disabled,
+        }
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Error::UsbClock => "USB clock is not 48 MHz",
+            Error::BufferOverflow => "packet exceeds max packet size",
+            Error::Disabled => "endpoint disabled",
+            Error::Timeout => "timed out",
+        })
+    }
+}
+
+impl core::error::Error for Error {}
+
 /// USB peripheral handle
 pub struct Usb {
     _private: (),
@@ -35,71 +245,303 @@ pub struct Usb {
 
 impl Usb {
     pub(crate) fn new() -> Self {
-        // Enable USB clock
-        // let ckcu = unsafe { &*crate::pac::Ckcu::ptr() };
-        // ckcu.apbccr1().modify(|_, w| w.usben().set_bit());
-        // TODO: Implement proper USB clock enabling based on HT32F523xx PAC
-
         Self { _private: () }
     }
 }
 
+impl_peripheral!(Usb);
+
+/// Endpoint allocation record
+#[derive(Copy, Clone)]
+struct EndpointData {
+    ep_type: EndpointType,
+    dir: Direction,
+    buf_addr: u16,
+    max_packet_size: u16,
+    /// Two buffers at `buf_addr`, one for the CPU while the hardware uses the other
+    double_buffered: bool,
+}
+
 /// USB driver implementation
 pub struct Driver<'d> {
     phantom: PhantomData<&'d ()>,
-    alloc_in: AtomicBool,
-    alloc_out: AtomicBool,
+    endpoints: [Option<EndpointData>; MAX_EP_COUNT],
+    sram: sram::Allocator,
+    config: Config,
 }
 
 impl<'d> Driver<'d> {
     /// Create a new USB driver instance
-    pub fn new(_usb: Usb, config: Config) -> Self {
+    ///
+    /// Panics if the USB clock is not 48 MHz, see [`Driver::try_new`].
+    pub fn new(usb: impl Peripheral<P = Usb> + 'd, config: Config) -> Self {
+        match Self::try_new(usb, config) {
+            Ok(driver) => driver,
+            Err(e) => panic!("USB driver: {}", e),
+        }
+    }
+
+    /// Create a new USB driver instance, checking the clock setup first
+    ///
+    /// The USB peripheral may be borrowed, see [`peripheral`](crate::peripheral).
+    pub fn try_new(_usb: impl Peripheral<P = Usb> + 'd, mut config: Config) -> Result<Self, Error> {
+        if !crate::rcc::get_clocks().usb_clk().is_some_and(|f| f.to_hz() == 48_000_000) {
+            return Err(Error::UsbClock);
+        }
+
         let usb = unsafe { &*pac::Usb::ptr() };
 
+        let vbus_pin = config.vbus_pin.take().map(|pin| (pin.port(), pin.pin()));
+        let supply = config.supply.take();
+        critical_section::with(|cs| {
+            VBUS_PIN.borrow(cs).set(vbus_pin);
+            *SUPPLY.borrow_ref_mut(cs) = supply;
+        });
+        DETACH_TIMEOUT_MS.store(config.detach_timeout_ms, Ordering::Relaxed);
+        MAX_POWER_MA.store(config.max_power_ma as u32, Ordering::Relaxed);
+        RESET_SEEN.store(false, Ordering::Relaxed);
+
         // Initialize USB hardware
-        initialize_usb_hardware(usb, config);
+        initialize_usb_hardware(usb, &config);
 
-        Self {
+        let sram_dma = config.dma && sram_dma_probe();
+        if config.dma && !sram_dma {
+            warn!("usb: PDMA cannot reach EP_SRAM, using CPU copies");
+        }
+        SRAM_DMA.store(sram_dma, Ordering::Relaxed);
+
+        Ok(Self {
             phantom: PhantomData,
-            alloc_in: AtomicBool::new(false),
-            alloc_out: AtomicBool::new(false),
+            endpoints: [None; MAX_EP_COUNT],
+            sram: sram::Allocator::new(),
+            config,
+        })
+    }
+
+    /// Take the device off the bus, see [`detach`]
+    ///
+    /// Once the driver is handed to `embassy_usb::Builder`, call [`detach`]
+    /// and [`attach`] directly.
+    pub fn detach(&mut self) {
+        detach();
+    }
+
+    /// Reconnect after [`Driver::detach`], see [`attach`]
+    pub fn attach(&mut self) {
+        attach();
+    }
+
+    fn alloc_endpoint(
+        &mut self,
+        ep_type: EndpointType,
+        ep_addr: Option<EndpointAddress>,
+        dir: Direction,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<EndpointInfo, EndpointAllocError> {
+        if !valid_packet_size(ep_type, max_packet_size) {
+            warn!("usb: unsupported max packet size {} for endpoint type {}", max_packet_size, ep_type as u8);
+            return Err(EndpointAllocError);
         }
+
+        // Isochronous transfers are only supported by the double-buffered EP4-EP7
+        let candidates = match ep_type {
+            EndpointType::Isochronous => 1 + SINGLE_BUFFERED_EPS..MAX_EP_COUNT,
+            _ => 1..MAX_EP_COUNT,
+        };
+
+        let index = match ep_addr {
+            Some(addr) => {
+                let index = addr.index();
+                if !candidates.contains(&index) || self.endpoints[index].is_some() {
+                    return Err(EndpointAllocError);
+                }
+                index
+            }
+            None => candidates
+                .clone()
+                .find(|&i| self.endpoints[i].is_none())
+                .ok_or(EndpointAllocError)?,
+        };
+
+        // Isochronous packets arrive every frame whether or not the last one was
+        // handled, so those endpoints get a second buffer to ping-pong with
+        let double_buffered = matches!(ep_type, EndpointType::Isochronous);
+        let len = if double_buffered { 2 * ep_buf_len(max_packet_size) } else { max_packet_size };
+        let buf_addr = self.sram.alloc(len).ok_or(EndpointAllocError)?;
+
+        self.endpoints[index] = Some(EndpointData {
+            ep_type,
+            dir,
+            buf_addr,
+            max_packet_size,
+            double_buffered,
+        });
+
+        Ok(EndpointInfo {
+            addr: EndpointAddress::from_parts(index, dir),
+            ep_type,
+            max_packet_size,
+            interval_ms,
+        })
     }
 }
 
-/// USB bus implementation for HT32F52352 USB controller
-/// Hardware: 1 control EP + 7 configurable EPs, 1024-byte EP_SRAM
-pub struct Bus<'d> {
-    phantom: PhantomData<&'d ()>,
-    // Arrays for all 8 endpoints (EP0 + 7 configurable)
-    ep_types: [Option<EndpointType>; MAX_EP_COUNT], // 8 endpoints total
-    ep_in_wakers: [AtomicWaker; MAX_EP_COUNT], // IN endpoint wakers
-    ep_out_wakers: [AtomicWaker; MAX_EP_COUNT], // OUT endpoint wakers
-    bus_waker: AtomicWaker,
+/// Whether the hardware can serve `ep_type` with packets of `max_packet_size`
+///
+/// The buffer is sized, EPLEN programmed and transfers length-checked with
+/// exactly this size. Bulk packets must be 8, 16, 32 or 64 bytes as in the
+/// USB spec, interrupt and isochronous ones anything up to the 64-byte
+/// EPLEN limit.
+const fn valid_packet_size(ep_type: EndpointType, max_packet_size: u16) -> bool {
+    match ep_type {
+        EndpointType::Bulk | EndpointType::Control => matches!(max_packet_size, 8 | 16 | 32 | 64),
+        EndpointType::Interrupt | EndpointType::Isochronous => max_packet_size as usize <= MAX_PACKET_SIZE,
+    }
+}
+
+/// EP_SRAM bytes left for the configurable endpoints
+pub const EP_SRAM_AVAILABLE: usize = EP_SRAM_SIZE - EP_BUF_START as usize;
+
+/// Endpoints a USB class allocates, for [`assert_endpoint_budget`]
+///
+/// Every endpoint occupies a hardware endpoint of its own (IN and OUT do
+/// not share a number) and a word-aligned EP_SRAM buffer of its max packet
+/// size, which may not exceed 64 bytes. Isochronous endpoints are double
+/// buffered and take two.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EndpointBudget {
+    /// Endpoints of any type
+    pub endpoints: usize,
+    /// How many of them are isochronous (EP4-EP7 only)
+    pub isochronous: usize,
+    /// EP_SRAM bytes, after rounding each buffer up to a word
+    pub sram_bytes: usize,
 }
 
-impl<'d> Bus<'d> {
-    fn new() -> Self {
-        const NEW_AW: AtomicWaker = AtomicWaker::new();
+impl EndpointBudget {
+    /// Nothing allocated
+    pub const NONE: Self = Self { endpoints: 0, isochronous: 0, sram_bytes: 0 };
+
+    const fn buffer(max_packet_size: u16) -> usize {
+        assert!(max_packet_size as usize <= MAX_PACKET_SIZE, "USB endpoints take at most 64-byte packets");
+        (max_packet_size as usize + 3) & !3
+    }
+
+    /// One bulk or interrupt endpoint
+    pub const fn endpoint(max_packet_size: u16) -> Self {
+        Self { endpoints: 1, isochronous: 0, sram_bytes: Self::buffer(max_packet_size) }
+    }
+
+    /// One isochronous endpoint, with both of its buffers
+    pub const fn isochronous(max_packet_size: u16) -> Self {
+        Self { endpoints: 1, isochronous: 1, sram_bytes: 2 * Self::buffer(max_packet_size) }
+    }
+
+    /// HID: interrupt IN, plus interrupt OUT if the class has output reports
+    pub const fn hid(max_packet_size_in: u16, max_packet_size_out: Option<u16>) -> Self {
+        let budget = Self::endpoint(max_packet_size_in);
+        match max_packet_size_out {
+            Some(out) => budget.and(Self::endpoint(out)),
+            None => budget,
+        }
+    }
+
+    /// CDC-ACM: 8-byte notification IN plus bulk IN and OUT
+    pub const fn cdc_acm(max_packet_size: u16) -> Self {
+        Self::endpoint(8).and(Self::endpoint(max_packet_size)).and(Self::endpoint(max_packet_size))
+    }
+
+    /// USB MIDI: bulk IN and OUT
+    pub const fn midi(max_packet_size: u16) -> Self {
+        Self::endpoint(max_packet_size).and(Self::endpoint(max_packet_size))
+    }
+
+    /// UAC1 speaker: isochronous OUT stream plus the 3-byte isochronous feedback IN
+    pub const fn uac1_speaker(max_packet_size: u16) -> Self {
+        Self::isochronous(max_packet_size).and(Self::isochronous(3))
+    }
+
+    /// Both budgets together
+    pub const fn and(self, other: Self) -> Self {
         Self {
-            phantom: PhantomData,
-            ep_types: [None; MAX_EP_COUNT], // 8 endpoints
-            ep_in_wakers: [NEW_AW; MAX_EP_COUNT], // Full waker arrays
-            ep_out_wakers: [NEW_AW; MAX_EP_COUNT], // Full waker arrays
-            bus_waker: AtomicWaker::new(),
+            endpoints: self.endpoints + other.endpoints,
+            isochronous: self.isochronous + other.isochronous,
+            sram_bytes: self.sram_bytes + other.sram_bytes,
         }
     }
 }
 
+/// Fail the build if `classes` do not fit the USB block together
+///
+/// Call it in a `const` item so a class set that would make
+/// `Builder::build` fail with an allocation error at runtime stops the
+/// build instead:
+///
+/// ```rust,ignore
+/// use embassy_ht32f523xx::usb::{assert_endpoint_budget, EndpointBudget};
+///
+/// const _: () = assert_endpoint_budget(&[
+///     EndpointBudget::hid(8, None),   // keyboard
+///     EndpointBudget::cdc_acm(64),    // console
+///     EndpointBudget::midi(64),
+/// ]);
+/// ```
+pub const fn assert_endpoint_budget(classes: &[EndpointBudget]) {
+    let mut total = EndpointBudget::NONE;
+    let mut i = 0;
+    while i < classes.len() {
+        total = total.and(classes[i]);
+        i += 1;
+    }
+
+    assert!(
+        total.endpoints <= MAX_EP_COUNT - 1,
+        "USB class set needs more than the 7 endpoints besides EP0"
+    );
+    assert!(
+        total.isochronous <= DOUBLE_BUFFERED_EPS,
+        "USB class set needs more than the 4 isochronous-capable endpoints (EP4-EP7)"
+    );
+    assert!(
+        total.sram_bytes <= EP_SRAM_AVAILABLE,
+        "USB class set needs more EP_SRAM than the 888 bytes left after EP0"
+    );
+}
+
+// The budget mirrors the allocator and the message its numbers
+const _: () = {
+    assert!(EP_SRAM_AVAILABLE == 888);
+    assert!(MAX_EP_COUNT - 1 == SINGLE_BUFFERED_EPS + DOUBLE_BUFFERED_EPS);
+    assert!(EndpointBudget::endpoint(62).sram_bytes == 64);
+    assert!(EndpointBudget::endpoint(8).sram_bytes == 8);
+    assert!(EndpointBudget::isochronous(3).sram_bytes == 8);
+    assert!(EndpointBudget::uac1_speaker(64).sram_bytes == 136);
+    assert_endpoint_budget(&[EndpointBudget::hid(8, None), EndpointBudget::cdc_acm(64), EndpointBudget::midi(64)]);
+};
+
+/// USB bus implementation for HT32F52352 USB controller
+/// Hardware: 1 control EP + 7 configurable EPs, 1024-byte EP_SRAM
+pub struct Bus<'d> {
+    phantom: PhantomData<&'d ()>,
+    endpoints: [Option<EndpointData>; MAX_EP_COUNT],
+    control_max_packet_size: u16,
+    vbus_detection: bool,
+    power_reported: bool,
+}
+
 /// USB control pipe implementation
 pub struct ControlPipe<'d> {
     _phantom: PhantomData<&'d ()>,
+    max_packet_size: u16,
 }
 
 /// USB endpoint implementation
 pub struct Endpoint<'d, D> {
     _phantom: PhantomData<&'d ()>,
     info: EndpointInfo,
+    buf_addr: u16,
+    double_buffered: bool,
     _direction: PhantomData<D>,
 }
 
@@ -120,24 +562,14 @@ impl<'d> embassy_usb_driver::Driver<'d> for Driver<'d> {
         max_packet_size: u16,
         interval: u8,
     ) -> Result<Self::EndpointIn, EndpointAllocError> {
-        if self.alloc_in.load(Ordering::Relaxed) {
-            return Err(EndpointAllocError);
-        }
-        self.alloc_in.store(true, Ordering::Relaxed);
-
-        let addr = ep_addr.unwrap_or(EndpointAddress::from_parts(1, Direction::In));
-
-        // Configure hardware endpoint
-        configure_endpoint_hardware(addr, ep_type, max_packet_size);
+        let info = self.alloc_endpoint(ep_type, ep_addr, Direction::In, max_packet_size, interval)?;
+        let ep = self.endpoints[info.addr.index()].unwrap();
 
         Ok(Endpoint {
             _phantom: PhantomData,
-            info: EndpointInfo {
-                addr,
-                ep_type,
-                max_packet_size: max_packet_size.min(MAX_PACKET_SIZE as u16),
-                interval_ms: interval,
-            },
+            info,
+            buf_addr: ep.buf_addr,
+            double_buffered: ep.double_buffered,
             _direction: PhantomData,
         })
     }
@@ -149,32 +581,36 @@ impl<'d> embassy_usb_driver::Driver<'d> for Driver<'d> {
         max_packet_size: u16,
         interval: u8,
     ) -> Result<Self::EndpointOut, EndpointAllocError> {
-        if self.alloc_out.load(Ordering::Relaxed) {
-            return Err(EndpointAllocError);
-        }
-        self.alloc_out.store(true, Ordering::Relaxed);
-
-        let addr = ep_addr.unwrap_or(EndpointAddress::from_parts(1, Direction::Out));
-
-        // Configure hardware endpoint
-        configure_endpoint_hardware(addr, ep_type, max_packet_size);
+        let info = self.alloc_endpoint(ep_type, ep_addr, Direction::Out, max_packet_size, interval)?;
+        let ep = self.endpoints[info.addr.index()].unwrap();
 
         Ok(Endpoint {
             _phantom: PhantomData,
-            info: EndpointInfo {
-                addr,
-                ep_type,
-                max_packet_size: max_packet_size.min(MAX_PACKET_SIZE as u16),
-                interval_ms: interval,
-            },
+            info,
+            buf_addr: ep.buf_addr,
+            double_buffered: ep.double_buffered,
             _direction: PhantomData,
         })
     }
 
     fn start(self, control_max_packet_size: u16) -> (Self::Bus, Self::ControlPipe) {
-        let bus = Bus::new();
+        let control_max_packet_size = if valid_packet_size(EndpointType::Control, control_max_packet_size) {
+            control_max_packet_size
+        } else {
+            warn!("usb: unsupported EP0 max packet size {}, using 64", control_max_packet_size);
+            MAX_PACKET_SIZE as u16
+        };
+
+        let bus = Bus {
+            phantom: PhantomData,
+            endpoints: self.endpoints,
+            control_max_packet_size,
+            vbus_detection: self.config.vbus_detection,
+            power_reported: false,
+        };
         let control_pipe = ControlPipe {
             _phantom: PhantomData,
+            max_packet_size: control_max_packet_size,
         };
 
         // Configure EP0 for control transfers
@@ -191,8 +627,7 @@ impl<'d> embassy_usb_driver::Endpoint for Endpoint<'d, In> {
     }
 
     async fn wait_enabled(&mut self) {
-        // Wait for endpoint to be enabled by host
-        // This is a simplified implementation
+        wait_endpoint_enabled(self.info.addr).await
     }
 }
 
@@ -202,70 +637,267 @@ impl<'d> embassy_usb_driver::Endpoint for Endpoint<'d, Out> {
     }
 
     async fn wait_enabled(&mut self) {
-        // Wait for endpoint to be enabled by host
+        wait_endpoint_enabled(self.info.addr).await
     }
 }
 
 impl<'d> embassy_usb_driver::EndpointOut for Endpoint<'d, Out> {
     async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
-        if buf.len() > MAX_PACKET_SIZE {
-            return Err(EndpointError::BufferOverflow);
-        }
-
         // Read from USB hardware
-        read_endpoint_data(self.info.addr, buf).await
+        read_endpoint_data(self.info.addr, self.buf_addr, self.info.max_packet_size, self.double_buffered, buf).await
     }
 }
 
 impl<'d> embassy_usb_driver::EndpointIn for Endpoint<'d, In> {
     async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
-        if buf.len() > MAX_PACKET_SIZE {
+        if buf.len() > self.info.max_packet_size as usize {
             return Err(EndpointError::BufferOverflow);
         }
 
         // Write to USB hardware
-        write_endpoint_data(self.info.addr, buf).await
+        write_endpoint_data(self.info.addr, self.buf_addr, self.info.max_packet_size, self.double_buffered, buf).await
+    }
+}
+
+impl<'d> ControlPipe<'d> {
+    fn in_buf(&self) -> u16 {
+        EP0_BUF_OFFSET
+    }
+
+    fn out_buf(&self) -> u16 {
+        EP0_BUF_OFFSET + self.max_packet_size
+    }
+
+    /// Status stage of a control write or no-data request: zero-length IN
+    ///
+    /// OUT is NAKed first so extra data from the host is refused rather
+    /// than taken as a status packet.
+    async fn send_zlp(&mut self) {
+        ep_set_csr(0, EP_CSR_NAKRX, EP_CSR_NAKRX);
+
+        EP_IN_DONE[0].store(false, Ordering::Relaxed);
+        ep_reg!(0, tcr, |r| r.write(|w| unsafe { w.bits(0) }));
+        ep_set_csr(0, EP_CSR_NAKTX, 0);
+
+        let guard = in_cancel_guard(0);
+        wait_in_done(0).await;
+        guard.defuse();
+    }
+
+    /// Status stage of a control read: wait for the host's zero-length OUT
+    ///
+    /// A host may also skip ahead with a new SETUP, which ends the wait and
+    /// is left for [`setup`](embassy_usb_driver::ControlPipe::setup).
+    async fn wait_status_out(&mut self) {
+        ep_set_csr(0, EP_CSR_NAKRX, 0);
+        let guard = DropGuard::new(|| ep_set_csr(0, EP_CSR_NAKRX, EP_CSR_NAKRX));
+
+        poll_fn(|cx| {
+            EP_OUT_WAKERS[0].register(cx.waker());
+
+            if EP_OUT_READY[0].load(Ordering::Acquire) {
+                EP_OUT_READY[0].store(false, Ordering::Relaxed);
+                #[cfg(hot_path_log)]
+                {
+                    let len = (ep_reg!(0, tcr, |r| r.read().bits()) >> 16) & 0x7F;
+                    if len != 0 {
+                        hot_debug!("usb: {} byte status OUT on EP0", len);
+                    }
+                }
+                Poll::Ready(())
+            } else if EP0_SETUP.load(Ordering::Acquire) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+
+        // NAK OUT again until the next data stage opens it
+        drop(guard);
     }
 }
 
 impl<'d> embassy_usb_driver::ControlPipe for ControlPipe<'d> {
     fn max_packet_size(&self) -> usize {
-        64
+        self.max_packet_size as usize
     }
 
     async fn setup(&mut self) -> [u8; 8] {
-        // Read setup packet from hardware
-        read_setup_packet().await
+        poll_fn(|cx| {
+            EP_OUT_WAKERS[0].register(cx.waker());
+
+            if EP0_SETUP.load(Ordering::Acquire) {
+                EP0_SETUP.store(false, Ordering::Relaxed);
+
+                let mut packet = [0u8; 8];
+                sram_read(EP0_SETUP_OFFSET, &mut packet);
+
+                // A new SETUP aborts whatever was left of the previous transfer,
+                // including a SET_ADDRESS whose status stage never completed
+                PENDING_ADDRESS.store(NO_ADDRESS, Ordering::Relaxed);
+                EP_OUT_READY[0].store(false, Ordering::Relaxed);
+                EP_IN_DONE[0].store(false, Ordering::Relaxed);
+                ep_set_csr(0, EP_CSR_STLTX | EP_CSR_STLRX, 0);
+
+                Poll::Ready(packet)
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
     }
 
     async fn data_out(&mut self, buf: &mut [u8], _first: bool, _last: bool) -> Result<usize, EndpointError> {
-        // Read control data from hardware
-        Ok(buf.len().min(64))
+        // Allow the host to send the next DATA OUT packet; NAK again if the
+        // transfer is abandoned (e.g. a new SETUP arrived)
+        ep_set_csr(0, EP_CSR_NAKRX, 0);
+        let guard = DropGuard::new(|| ep_set_csr(0, EP_CSR_NAKRX, EP_CSR_NAKRX));
+
+        poll_fn(|cx| {
+            EP_OUT_WAKERS[0].register(cx.waker());
+
+            if EP_OUT_READY[0].load(Ordering::Acquire) {
+                EP_OUT_READY[0].store(false, Ordering::Relaxed);
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+        guard.defuse();
+
+        // RXCNT lives in bits [22:16] of EP0TCR
+        let len = ((ep_reg!(0, tcr, |r| r.read().bits()) >> 16) & 0x7F) as usize;
+        if len > buf.len() {
+            return Err(EndpointError::BufferOverflow);
+        }
+
+        sram_read(self.out_buf(), &mut buf[..len]);
+        Ok(len)
     }
 
-    async fn data_in(&mut self, _data: &[u8], _first: bool, _last: bool) -> Result<(), EndpointError> {
-        // Write control data to hardware
+    async fn data_in(&mut self, data: &[u8], _first: bool, last: bool) -> Result<(), EndpointError> {
+        if data.len() > self.max_packet_size as usize {
+            return Err(EndpointError::BufferOverflow);
+        }
+
+        EP_IN_DONE[0].store(false, Ordering::Relaxed);
+        sram_write(self.in_buf(), data);
+        ep_reg!(0, tcr, |r| r.write(|w| unsafe { w.bits(data.len() as u32) }));
+        ep_set_csr(0, EP_CSR_NAKTX, 0);
+
+        let guard = in_cancel_guard(0);
+        wait_in_done(0).await;
+        guard.defuse();
+
+        if last {
+            self.wait_status_out().await;
+        }
+
         Ok(())
     }
 
     async fn accept(&mut self) {
-        // Send ACK to host
+        self.send_zlp().await;
     }
 
     async fn reject(&mut self) {
         // Send STALL to host
+        ep_set_csr(0, EP_CSR_STLTX | EP_CSR_STLRX, EP_CSR_STLTX | EP_CSR_STLRX);
     }
 
     async fn accept_set_address(&mut self, addr: u8) {
-        // Set device address
-        set_device_address(addr);
+        // The status stage still goes to the old address; the interrupt
+        // commits the new one once the host has taken the ZLP
+        PENDING_ADDRESS.store(addr as u32, Ordering::Release);
+        self.send_zlp().await;
     }
 }
 
 impl<'d> embassy_usb_driver::Bus for Bus<'d> {
     async fn poll(&mut self) -> Event {
-        // Poll USB hardware for events
-        poll_usb_events().await
+        let powered = !self.vbus_detection || vbus_present();
+        if powered != self.power_reported {
+            self.power_reported = powered;
+            if !powered {
+                RESET_SEEN.store(false, Ordering::Relaxed);
+                reset_device_state();
+            }
+            return if powered { Event::PowerDetected } else { Event::PowerRemoved };
+        }
+
+        let mut bus_event = poll_fn(|cx| {
+            BUS_WAKER.register(cx.waker());
+
+            if IRQ_RESET.load(Ordering::Acquire) {
+                IRQ_RESET.store(false, Ordering::Relaxed);
+
+                PENDING_ADDRESS.store(NO_ADDRESS, Ordering::Relaxed);
+                set_device_address(0);
+                configure_control_endpoint(self.control_max_packet_size);
+                for (index, ep) in self.endpoints.iter().enumerate() {
+                    if let Some(ep) = ep {
+                        configure_endpoint_hardware(index, ep);
+                    }
+                }
+
+                return Poll::Ready(Event::Reset);
+            }
+
+            if IRQ_RESUME.load(Ordering::Acquire) {
+                IRQ_RESUME.store(false, Ordering::Relaxed);
+                return Poll::Ready(Event::Resume);
+            }
+
+            if IRQ_SUSPEND.load(Ordering::Acquire) {
+                IRQ_SUSPEND.store(false, Ordering::Relaxed);
+                return Poll::Ready(Event::Suspend);
+            }
+
+            Poll::Pending
+        });
+
+        // Without VBUS sensing a long enough suspend stands in for the detach
+        #[cfg(feature = "time")]
+        if vbus_level().is_none() && SUSPENDED.load(Ordering::Acquire) {
+            let timeout = DETACH_TIMEOUT_MS.load(Ordering::Relaxed);
+            if timeout != 0 {
+                let detached = embassy_time::Timer::after_millis(timeout as u64);
+                if let embassy_futures::select::Either::First(event) =
+                    embassy_futures::select::select(&mut bus_event, detached).await
+                {
+                    return event;
+                }
+                debug!("usb: suspended for {} ms, treating as detach", timeout);
+                reset_device_state();
+            }
+        }
+
+        // Without interrupts on the VBUS pin, watch its level alongside the bus events
+        #[cfg(feature = "time")]
+        if self.vbus_detection && vbus_level().is_some() {
+            let expected = self.power_reported;
+            let vbus_changed = async {
+                while vbus_present() == expected {
+                    embassy_time::Timer::after_millis(VBUS_POLL_MS as u64).await;
+                }
+            };
+
+            return match embassy_futures::select::select(bus_event, vbus_changed).await {
+                embassy_futures::select::Either::First(event) => event,
+                embassy_futures::select::Either::Second(()) => {
+                    self.power_reported = !expected;
+                    if expected {
+                        RESET_SEEN.store(false, Ordering::Relaxed);
+                        reset_device_state();
+                    }
+                    if expected { Event::PowerRemoved } else { Event::PowerDetected }
+                }
+            };
+        }
+
+        bus_event.await
     }
 
     fn endpoint_set_stalled(&mut self, ep_addr: EndpointAddress, stalled: bool) {
@@ -294,16 +926,40 @@ impl<'d> embassy_usb_driver::Bus for Bus<'d> {
     }
 
     async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
-        Err(Unsupported)
+        // Resume signalling needs the 48 MHz clock back
+        crate::power::leave_usb_suspend();
+        let usb = unsafe { &*pac::Usb::ptr() };
+        usb.csr().modify(|_, w| w.genrsm().set_bit());
+        Ok(())
     }
 }
 
 /// USB configuration
 pub struct Config {
-    /// Enable VBUS detection
+    /// Report power events from `vbus_pin` instead of assuming the bus is powered
     pub vbus_detection: bool,
     /// Enable VBUS detect interrupt
     pub enable_vbus_detect: bool,
+    /// GPIO input that is high while VBUS is present (through a divider)
+    pub vbus_pin: Option<AnyPin>,
+    /// Board USB power control; `None` uses the on-chip pull-up and `vbus_pin`
+    pub supply: Option<&'static mut dyn UsbSupply>,
+    /// Without VBUS sensing, treat a suspend longer than this many ms as a detach (0 = never)
+    ///
+    /// A host that suspends the bus while sleeping looks the same as a pulled cable.
+    /// Once the timeout passes the endpoints are disabled until the host
+    /// resets and enumerates the device again.
+    pub detach_timeout_ms: u32,
+    /// Use PDMA to copy packets to and from EP_SRAM
+    ///
+    /// The driver checks at start-up that PDMA can reach EP_SRAM and silently
+    /// falls back to CPU word copies if it cannot.
+    pub dma: bool,
+    /// Current requested in the configuration descriptor, in mA
+    ///
+    /// Must match `embassy_usb::Config::max_power`; reported by
+    /// [`power_source`] once the host has configured the device.
+    pub max_power_ma: u16,
 }
 
 impl Default for Config {
@@ -311,15 +967,21 @@ impl Default for Config {
         Self {
             vbus_detection: false,
             enable_vbus_detect: false,
+            vbus_pin: None,
+            supply: None,
+            detach_timeout_ms: 0,
+            dma: false,
+            max_power_ma: 100,
         }
     }
 }
 
 // Hardware-specific implementation functions
-fn initialize_usb_hardware(usb: &crate::pac::usb::RegisterBlock, _config: Config) {
-    // Initialize USB hardware registers
-    // This is a simplified implementation - actual HT32 USB initialization
-    // would involve proper register configuration based on the datasheet
+fn initialize_usb_hardware(usb: &crate::pac::usb::RegisterBlock, _config: &Config) {
+    // USB needs its AHB clock; the 48 MHz USB clock comes from the PLL through
+    // the prescaler set by `rcc::Config::usb_prescaler`
+    let ckcu = unsafe { &*pac::Ckcu::ptr() };
+    ckcu.ahbccr().modify(|_, w| w.usben().set_bit());
 
     // Reset USB
     usb.csr().modify(|_, w| w.fres().set_bit());
@@ -327,139 +989,376 @@ fn initialize_usb_hardware(usb: &crate::pac::usb::RegisterBlock, _config: Config
 
     // Enable USB
     usb.csr().modify(|_, w| w.pdwn().clear_bit());
+
+    // Start from a clean interrupt state
+    usb.isr().write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+    usb.ier().write(|w| unsafe { w.bits(0) });
 }
 
-fn configure_endpoint_hardware(addr: EndpointAddress, _ep_type: EndpointType, max_packet_size: u16) {
-    let usb = unsafe { &*pac::Usb::ptr() };
-    let ep_num = addr.index();
+/// EPnCFGR buffer fields `(EPBUFA, EPLEN)` for a buffer at EP_SRAM offset `addr` of `len_bytes`
+///
+/// Both fields count bytes, but the controller only handles whole words:
+/// the address must be word aligned and the length is rounded up to a
+/// multiple of four. Every EPnCFGR buffer write goes through here.
+const fn ep_buf_fields(addr: u16, len_bytes: u16) -> (u16, u8) {
+    assert!(addr % 4 == 0, "EP_SRAM buffers must be word aligned");
+    let len = ep_buf_len(len_bytes);
+    assert!(len as usize <= MAX_PACKET_SIZE, "EPLEN is at most 64 bytes");
+    assert!(addr as usize + len as usize <= EP_SRAM_SIZE, "buffer ends past EP_SRAM");
+    (addr, len as u8)
+}
 
-    // Calculate buffer address in 1024-byte EP_SRAM
-    // EP0 (control): First 64 bytes, then distribute remaining space among configurable EPs
-    let buffer_addr = if ep_num == 0 {
-        0 // EP0 control endpoint starts at beginning
-    } else {
-        // Configurable endpoints: distribute remaining 960 bytes (1024 - 64)
-        64 + ((ep_num - 1) * (960 / 7)) // Approximately equal distribution
+/// EPLEN for a `len_bytes` buffer: the EP_SRAM it takes, and the offset of the second buffer
+const fn ep_buf_len(len_bytes: u16) -> u16 {
+    (len_bytes + 3) & !3
+}
+
+// Unit conversions the EPnCFGR writes rely on
+const _: () = {
+    // Lengths are bytes, not words
+    assert!(ep_buf_fields(EP0_BUF_OFFSET, 64).1 == 64);
+    assert!(ep_buf_fields(EP_BUF_START, 8).1 == 8);
+    // Odd lengths round up to whole words
+    assert!(ep_buf_fields(EP_BUF_START, 1).1 == 4);
+    assert!(ep_buf_fields(EP_BUF_START, 62).1 == 64);
+    // Addresses pass through in bytes
+    assert!(ep_buf_fields(0x3C0, 64).0 == 0x3C0);
+};
+
+/// Point endpoint `ep` at its EP_SRAM buffer, see [`ep_buf_fields`]
+fn set_ep_buf(ep: usize, addr: u16, len_bytes: u16) {
+    let (epbufa, eplen) = ep_buf_fields(addr, len_bytes);
+    ep_reg!(ep, cfgr, |r| r.modify(|_, w| unsafe { w.epbufa().bits(epbufa).eplen().bits(eplen) }));
+}
+
+fn configure_endpoint_hardware(index: usize, ep: &EndpointData) {
+    // Endpoint is configured but left disabled until the host selects a configuration
+    ep_reg!(index, cfgr, |r| r.write(|w| unsafe {
+        w.epadr().bits(index as u8)
+         .epdir().bit(matches!(ep.dir, Direction::In))
+         .eptype().bit(matches!(ep.ep_type, EndpointType::Isochronous))
+         .epen().clear_bit()
+    }));
+    set_ep_buf(index, ep.buf_addr, ep.max_packet_size);
+    if ep.double_buffered {
+        // Not in the EP1-EP3 register layout, so set by hand
+        ep_reg!(index, cfgr, |r| r.modify(|r, w| unsafe { w.bits(r.bits() | EP_CFGR_SDBS) }));
+    }
+
+    let int = match ep.dir {
+        Direction::In => EP_INT_IDTX,
+        Direction::Out => EP_INT_ODRX,
     };
+    ep_reg!(index, ier, |r| r.write(|w| unsafe { w.bits(int) }));
+}
 
-    // Configure endpoint based on endpoint number and type
-    // Hardware supports: EP1-3 (single-buffered), EP4-7 (double-buffered)
-    match ep_num {
-        0 => {
-            usb.ep0cfgr().modify(|_, w| unsafe {
-                w.epbufa().bits(buffer_addr as u16)
-                 .eplen().bits(max_packet_size.min(64) as u8)
-                 .epadr().bits(0) // EP0 address is always 0
-                 .epen().set_bit()
-            });
-        }
-        1 => {
-            usb.ep1cfgr().modify(|_, w| unsafe {
-                w.epbufa().bits(buffer_addr as u16)
-                 .eplen().bits(max_packet_size.min(64) as u8)
-                 .epadr().bits(ep_num as u8)
-                 .epen().set_bit()
-            });
-        }
-        2 => {
-            usb.ep2cfgr().modify(|_, w| unsafe {
-                w.epbufa().bits(buffer_addr as u16)
-                 .eplen().bits(max_packet_size.min(64) as u8)
-                 .epadr().bits(ep_num as u8)
-                 .epen().set_bit()
-            });
-        }
-        3 => {
-            usb.ep3cfgr().modify(|_, w| unsafe {
-                w.epbufa().bits(buffer_addr as u16)
-                 .eplen().bits(max_packet_size.min(64) as u8)
-                 .epadr().bits(ep_num as u8)
-                 .epen().set_bit()
-            });
-        }
-        _ => {
-            // Additional endpoints can be configured here if needed
-        }
-    }
-}
-
-fn configure_control_endpoint(_max_packet_size: u16) {
+fn configure_control_endpoint(max_packet_size: u16) {
     // Configure EP0 for control transfers
-    let _usb = unsafe { &*pac::Usb::ptr() };
+    let usb = unsafe { &*pac::Usb::ptr() };
+
+    usb.ep0cfgr().write(|w| unsafe {
+        w.epadr().bits(0) // EP0 address is always 0
+         .epen().set_bit()
+    });
+    set_ep_buf(0, EP0_BUF_OFFSET, max_packet_size);
+    usb.ep0ier().write(|w| unsafe { w.bits(EP_INT_SDRX | EP_INT_ODRX | EP_INT_IDTX) });
+    usb.ep0isr().write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+
+    EP0_SETUP.store(false, Ordering::Relaxed);
+    EP_OUT_READY[0].store(false, Ordering::Relaxed);
+    EP_IN_DONE[0].store(false, Ordering::Relaxed);
 }
 
-async fn read_endpoint_data(addr: EndpointAddress, buf: &mut [u8]) -> Result<usize, EndpointError> {
-    let usb = unsafe { &*pac::Usb::ptr() };
-    let ep_num = addr.index();
+/// Update the toggle-on-write bits in `mask` of an endpoint CSR so they read back as `value`
+fn ep_set_csr(ep: usize, mask: u32, value: u32) {
+    let current = ep_reg!(ep, csr, |r| r.read().bits());
+    let toggle = (current ^ value) & mask;
+    if toggle != 0 {
+        ep_reg!(ep, csr, |r| r.write(|w| unsafe { w.bits(toggle) }));
+    }
+}
 
-    // Wait for data to be available (simplified - in real implementation would use interrupts)
-    let bytes_available = match ep_num {
-        0 => {
-            // Read from EP0 buffer
-            let cfg = usb.ep0cfgr().read();
-            cfg.eplen().bits() as usize
-        }
-        1 => {
-            let cfg = usb.ep1cfgr().read();
-            cfg.eplen().bits() as usize
-        }
-        2 => {
-            let cfg = usb.ep2cfgr().read();
-            cfg.eplen().bits() as usize
+/// Read one word of EP_SRAM; `offset` must be word aligned
+#[inline(always)]
+fn sram_read_word(offset: usize) -> u32 {
+    sram::read_word(&Mmio, offset)
+}
+
+/// Write one word of EP_SRAM; `offset` must be word aligned
+#[inline(always)]
+fn sram_write_word(offset: usize, word: u32) {
+    sram::write_word(&Mmio, offset, word)
+}
+
+/// Copy `words` words between word-aligned addresses with PDMA
+///
+/// Returns `false` if the copy was not done, in which case the caller copies with the CPU.
+fn sram_dma_copy(src: *const u8, dst: *mut u8, words: usize) -> bool {
+    if words < SRAM_DMA_MIN_WORDS || !SRAM_DMA.load(Ordering::Relaxed) {
+        return false;
+    }
+    dma::Channel::new(SRAM_DMA_CHANNEL)
+        .blocking_copy(src, dst, words, dma::Width::Word)
+        .is_ok()
+}
+
+/// Check that PDMA can write and read back EP_SRAM
+///
+/// Uses the last EP_SRAM words, which are not handed out before the bus is started.
+/// The channel stays claimed for the driver if it can, and is released if not.
+fn sram_dma_probe() -> bool {
+    dma::init();
+    dma::claim(SRAM_DMA_CHANNEL, "USB");
+
+    let pattern = [0xA5C3_0F96u32, 0x1234_5678, 0xDEAD_BEEF, 0x0F1E_2D3C];
+    let mut readback = [0u32; 4];
+    let offset = EP_SRAM_SIZE - core::mem::size_of_val(&pattern);
+    let sram = (EP_SRAM_BASE + offset) as *mut u8;
+
+    let mut ch = dma::Channel::new(SRAM_DMA_CHANNEL);
+    let ok = ch.blocking_copy(pattern.as_ptr() as *const u8, sram, pattern.len(), dma::Width::Word).is_ok()
+        && ch.blocking_copy(sram, readback.as_mut_ptr() as *mut u8, pattern.len(), dma::Width::Word).is_ok()
+        && (0..pattern.len()).all(|i| sram_read_word(offset + i * 4) == pattern[i])
+        && readback == pattern;
+
+    for i in 0..pattern.len() {
+        sram_write_word(offset + i * 4, 0);
+    }
+    if !ok {
+        dma::release(SRAM_DMA_CHANNEL, "USB");
+    }
+    ok
+}
+
+/// Pattern test of all of EP_SRAM for [`selftest`](crate::selftest)
+///
+/// Overwrites every endpoint buffer, so it must run before the driver is created.
+pub(crate) fn sram_selftest() -> bool {
+    let ckcu = unsafe { &*pac::Ckcu::ptr() };
+    ckcu.ahbccr().modify(|_, w| w.usben().set_bit());
+
+    let words = EP_SRAM_SIZE / 4;
+    let mut ok = true;
+    for pattern in [0x5555_5555u32, 0xAAAA_AAAA] {
+        for i in 0..words {
+            sram_write_word(i * 4, pattern);
         }
-        _ => 0,
-    };
+        ok &= (0..words).all(|i| sram_read_word(i * 4) == pattern);
+    }
+    // Address in every word catches aliased address lines
+    for i in 0..words {
+        sram_write_word(i * 4, i as u32 * 0x0101_0101);
+    }
+    ok &= (0..words).all(|i| sram_read_word(i * 4) == i as u32 * 0x0101_0101);
 
-    let _bytes_to_read = bytes_available.min(buf.len());
+    for i in 0..words {
+        sram_write_word(i * 4, 0);
+    }
+    ok
+}
 
-    // In real hardware implementation, would copy from USB SRAM to buffer
-    // For now, return 0 bytes as placeholder
-    Ok(0)
+/// Copy bytes out of EP_SRAM, which only supports 32-bit accesses
+fn sram_read(offset: u16, buf: &mut [u8]) {
+    sram::read(&Mmio, offset, buf, sram_dma_copy)
 }
 
-async fn write_endpoint_data(addr: EndpointAddress, buf: &[u8]) -> Result<(), EndpointError> {
-    let usb = unsafe { &*pac::Usb::ptr() };
-    let ep_num = addr.index();
+/// Copy bytes into EP_SRAM; `offset` must be word aligned
+fn sram_write(offset: u16, data: &[u8]) {
+    sram::write(&Mmio, offset, data, sram_dma_copy)
+}
 
-    if buf.len() > MAX_PACKET_SIZE {
-        return Err(EndpointError::BufferOverflow);
+/// Enabled flag and waker of an endpoint
+fn endpoint_state(addr: EndpointAddress) -> (&'static AtomicBool, &'static AtomicWaker) {
+    let index = addr.index();
+    match addr.direction() {
+        Direction::In => (&EP_IN_ENABLED[index], &EP_IN_WAKERS[index]),
+        Direction::Out => (&EP_OUT_ENABLED[index], &EP_OUT_WAKERS[index]),
     }
+}
+
+fn endpoint_enabled(addr: EndpointAddress) -> bool {
+    endpoint_state(addr).0.load(Ordering::Acquire)
+}
 
-    // In real implementation, would:
-    // 1. Copy data to USB SRAM buffer
-    // 2. Set endpoint length
-    // 3. Trigger transmission
-    // 4. Wait for completion interrupt
+/// Wait until the host enables `addr`, by SET_CONFIGURATION or SET_INTERFACE
+async fn wait_endpoint_enabled(addr: EndpointAddress) {
+    let (enabled, waker) = endpoint_state(addr);
+    poll_fn(|cx| {
+        waker.register(cx.waker());
 
-    // For now, update the endpoint length to indicate data is ready
-    match ep_num {
-        0 => {
-            usb.ep0cfgr().modify(|_, w| unsafe {
-                w.eplen().bits(buf.len() as u8)
-            });
+        if enabled.load(Ordering::Acquire) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
         }
-        1 => {
-            usb.ep1cfgr().modify(|_, w| unsafe {
-                w.eplen().bits(buf.len() as u8)
-            });
+    })
+    .await
+}
+
+async fn wait_in_done(index: usize) {
+    poll_fn(|cx| {
+        EP_IN_WAKERS[index].register(cx.waker());
+
+        if EP_IN_DONE[index].load(Ordering::Acquire) {
+            EP_IN_DONE[index].store(false, Ordering::Relaxed);
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+/// The buffer the CPU owns on endpoint `index`: its EP_SRAM offset and EPnTCR byte count shift
+///
+/// Single-buffered endpoints only have the one. On a double-buffered
+/// endpoint the hardware moves data through the other buffer meanwhile,
+/// and [`flip_user_buffer`] swaps them over.
+fn user_buffer(index: usize, buf_addr: u16, max_packet_size: u16, double_buffered: bool) -> (u16, u32) {
+    if double_buffered && ep_reg!(index, csr, |r| r.read().bits()) & EP_CSR_UDBTG != 0 {
+        (buf_addr + ep_buf_len(max_packet_size), EP_TCR_TCNT1_SHIFT)
+    } else {
+        (buf_addr, 0)
+    }
+}
+
+/// Hand the CPU's buffer to the hardware and take the other one
+fn flip_user_buffer(index: usize) {
+    ep_reg!(index, csr, |r| r.write(|w| unsafe { w.bits(EP_CSR_UDBTG) }));
+}
+
+async fn read_endpoint_data(
+    addr: EndpointAddress,
+    buf_addr: u16,
+    max_packet_size: u16,
+    double_buffered: bool,
+    buf: &mut [u8],
+) -> Result<usize, EndpointError> {
+    let index = addr.index();
+
+    poll_fn(|cx| {
+        EP_OUT_WAKERS[index].register(cx.waker());
+
+        if !endpoint_enabled(addr) {
+            return Poll::Ready(Err(EndpointError::Disabled));
         }
-        2 => {
-            usb.ep2cfgr().modify(|_, w| unsafe {
-                w.eplen().bits(buf.len() as u8)
-            });
+
+        if EP_OUT_READY[index].load(Ordering::Acquire) {
+            EP_OUT_READY[index].store(false, Ordering::Relaxed);
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
         }
-        _ => return Err(EndpointError::BufferOverflow), // Use available error variant
+    })
+    .await?;
+
+    let (data_addr, tcnt_shift) = user_buffer(index, buf_addr, max_packet_size, double_buffered);
+    let len = ((ep_reg!(index, tcr, |r| r.read().bits()) >> tcnt_shift) & EP_TCR_TCNT_MASK) as usize;
+    let len = len.min(max_packet_size as usize);
+
+    let result = if len > buf.len() {
+        Err(EndpointError::BufferOverflow)
+    } else {
+        sram_read(data_addr, &mut buf[..len]);
+        Ok(len)
+    };
+
+    // Hand the buffer back to the hardware for the next packet
+    if double_buffered {
+        flip_user_buffer(index);
     }
+    ep_set_csr(index, EP_CSR_NAKTX, 0);
+
+    result
+}
 
-    // Wait for transmission complete (simplified)
-    crate::interrupt::get_waker(crate::pac::Interrupt::USB).wait().await;
+async fn write_endpoint_data(
+    addr: EndpointAddress,
+    buf_addr: u16,
+    max_packet_size: u16,
+    double_buffered: bool,
+    buf: &[u8],
+) -> Result<(), EndpointError> {
+    let index = addr.index();
+
+    if !endpoint_enabled(addr) {
+        return Err(EndpointError::Disabled);
+    }
+
+    EP_IN_DONE[index].store(false, Ordering::Relaxed);
+    let (data_addr, tcnt_shift) = user_buffer(index, buf_addr, max_packet_size, double_buffered);
+    sram_write(data_addr, buf);
+    if double_buffered {
+        // Leave the count of the buffer the hardware is sending from alone
+        ep_reg!(index, tcr, |r| r.modify(|r, w| unsafe {
+            w.bits((r.bits() & !(EP_TCR_TCNT_MASK << tcnt_shift)) | ((buf.len() as u32) << tcnt_shift))
+        }));
+        flip_user_buffer(index);
+    } else {
+        ep_reg!(index, tcr, |r| r.write(|w| unsafe { w.bits(buf.len() as u32) }));
+    }
+    ep_set_csr(index, EP_CSR_NAKTX, 0);
+
+    // Wait for transmission complete; disabling the endpoint withdraws the packet
+    let guard = in_cancel_guard(index);
+    poll_fn(|cx| {
+        EP_IN_WAKERS[index].register(cx.waker());
+
+        if EP_IN_DONE[index].load(Ordering::Acquire) {
+            EP_IN_DONE[index].store(false, Ordering::Relaxed);
+            Poll::Ready(Ok(()))
+        } else if !endpoint_enabled(addr) {
+            Poll::Ready(Err(EndpointError::Disabled))
+        } else {
+            Poll::Pending
+        }
+    })
+    .await?;
+    guard.defuse();
 
     Ok(())
 }
 
-async fn read_setup_packet() -> [u8; 8] {
-    // Read setup packet from control endpoint
-    [0; 8]
+/// Withdraw an armed IN packet if the write is dropped before the host takes it
+///
+/// Without this a cancelled write would still be sent later, ahead of
+/// whatever the next write puts in the buffer.
+fn in_cancel_guard(index: usize) -> DropGuard<impl FnOnce()> {
+    DropGuard::new(move || {
+        ep_set_csr(index, EP_CSR_NAKTX, EP_CSR_NAKTX);
+        EP_IN_DONE[index].store(false, Ordering::Relaxed);
+    })
+}
+
+#[cfg(feature = "time")]
+impl<'d> Endpoint<'d, Out> {
+    /// Read a packet, giving up with [`Error::Timeout`] after `timeout`
+    ///
+    /// The endpoint stays armed, so a packet arriving later is kept for the next read.
+    pub async fn read_timeout(&mut self, buf: &mut [u8], timeout: embassy_time::Duration) -> Result<usize, Error> {
+        use embassy_usb_driver::EndpointOut;
+
+        match embassy_time::with_timeout(timeout, self.read(buf)).await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(Error::Timeout),
+        }
+    }
+}
+
+#[cfg(feature = "time")]
+impl<'d> Endpoint<'d, In> {
+    /// Write a packet, giving up with [`Error::Timeout`] after `timeout`
+    ///
+    /// If the host has not collected the packet by then it is withdrawn (the
+    /// endpoint NAKs again), so it is not delivered late.
+    pub async fn write_timeout(&mut self, buf: &[u8], timeout: embassy_time::Duration) -> Result<(), Error> {
+        use embassy_usb_driver::EndpointIn;
+
+        match embassy_time::with_timeout(timeout, self.write(buf)).await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(Error::Timeout),
+        }
+    }
 }
 
 fn set_device_address(addr: u8) {
@@ -468,35 +1367,608 @@ fn set_device_address(addr: u8) {
     usb.devar().modify(|_, w| unsafe { w.deva().bits(addr) });
 }
 
-async fn poll_usb_events() -> Event {
-    // Poll for USB events (reset, suspend, resume, etc.)
-    Event::PowerDetected
+/// SET_FEATURE / CLEAR_FEATURE(ENDPOINT_HALT)
+///
+/// Clearing a halt always restarts the data toggle at DATA0, even on an
+/// endpoint that was not halted (USB 2.0 9.4.5). A write waiting on a
+/// halted IN endpoint keeps its packet armed and completes once the host
+/// reads it after the clear; an OUT endpoint is armed again unless an
+/// unread packet still sits in its buffer.
+fn set_endpoint_stall(addr: EndpointAddress, stalled: bool) {
+    let index = addr.index();
+    if index == 0 {
+        // EP0 stalls clear themselves on the next SETUP
+        let mask = EP_CSR_STLTX | EP_CSR_STLRX;
+        ep_set_csr(0, mask, if stalled { mask } else { 0 });
+        return;
+    }
+
+    if stalled {
+        ep_set_csr(index, EP_CSR_STLTX, EP_CSR_STLTX);
+        return;
+    }
+
+    ep_set_csr(index, EP_CSR_STLTX | EP_CSR_DTGTX, 0);
+    if addr.direction() == Direction::Out && !EP_OUT_READY[index].load(Ordering::Acquire) {
+        ep_set_csr(index, EP_CSR_NAKTX, 0);
+    }
+
+    EP_IN_WAKERS[index].wake();
+    EP_OUT_WAKERS[index].wake();
 }
 
-fn set_endpoint_stall(_addr: EndpointAddress, _stalled: bool) {
-    // Set/clear endpoint stall condition
+fn get_endpoint_stall(addr: EndpointAddress) -> bool {
+    let index = addr.index();
+    let csr = ep_reg!(index, csr, |r| r.read().bits());
+
+    if index == 0 {
+        match addr.direction() {
+            Direction::In => csr & EP_CSR_STLTX != 0,
+            Direction::Out => csr & EP_CSR_STLRX != 0,
+        }
+    } else {
+        csr & EP_CSR_STLTX != 0
+    }
 }
 
-fn get_endpoint_stall(_addr: EndpointAddress) -> bool {
-    // Check if endpoint is stalled
-    false
+fn set_endpoint_enabled(addr: EndpointAddress, enabled: bool) {
+    let index = addr.index();
+    if index == 0 {
+        return;
+    }
+
+    let usb = unsafe { &*pac::Usb::ptr() };
+
+    ep_reg!(index, cfgr, |r| r.modify(|_, w| w.epen().bit(enabled)));
+    usb.ier().modify(|r, w| unsafe {
+        let bit = INT_EP0 << index;
+        w.bits(if enabled { r.bits() | bit } else { r.bits() & !bit })
+    });
+
+    if enabled {
+        // Restart from DATA0 (the first buffer when double-buffered); OUT
+        // endpoints are armed to receive straight away
+        let toggles = if index > SINGLE_BUFFERED_EPS { EP_CSR_DTGTX | EP_CSR_UDBTG } else { EP_CSR_DTGTX };
+        ep_set_csr(index, toggles, 0);
+        match addr.direction() {
+            Direction::Out => ep_set_csr(index, EP_CSR_NAKTX, 0),
+            Direction::In => ep_set_csr(index, EP_CSR_NAKTX, EP_CSR_NAKTX),
+        }
+        DEVICE_CONFIGURED.store(true, Ordering::Release);
+    }
+
+    // Only the endpoint that changed; a pending transfer on it ends with `Disabled`
+    let (flag, waker) = endpoint_state(addr);
+    flag.store(enabled, Ordering::Release);
+    waker.wake();
 }
 
-fn set_endpoint_enabled(_addr: EndpointAddress, _enabled: bool) {
-    // Enable/disable endpoint
+/// Forget the configuration and any pending transfer state, after a bus reset or detach
+///
+/// Every endpoint reads as disabled until the host enumerates again, and
+/// tasks waiting on one are woken to see `Disabled` instead of moving data
+/// for a host that is no longer there.
+fn reset_device_state() {
+    DEVICE_CONFIGURED.store(false, Ordering::Release);
+    PENDING_ADDRESS.store(NO_ADDRESS, Ordering::Relaxed);
+    EP0_SETUP.store(false, Ordering::Relaxed);
+    for index in 0..MAX_EP_COUNT {
+        EP_IN_ENABLED[index].store(false, Ordering::Release);
+        EP_OUT_ENABLED[index].store(false, Ordering::Release);
+        EP_IN_DONE[index].store(false, Ordering::Relaxed);
+        EP_OUT_READY[index].store(false, Ordering::Relaxed);
+        EP_IN_WAKERS[index].wake();
+        EP_OUT_WAKERS[index].wake();
+    }
 }
 
 fn enable_usb_device() {
     // Enable USB device functionality
     let usb = unsafe { &*pac::Usb::ptr() };
-    usb.csr().modify(|_, w| w.genrsm().set_bit());
+
+    usb.ier().write(|w| unsafe { w.bits(INT_UGIE | INT_URST | INT_RSM | INT_SUSP | INT_EP0 | sof_interrupt()) });
+
+    // Power the board side, then connect the DP pull-up so the host sees the device
+    with_supply(|supply| {
+        supply.set_power(true);
+        supply.set_pull_up(true);
+    });
 }
 
 fn disable_usb_device() {
     // Disable USB device functionality
     let usb = unsafe { &*pac::Usb::ptr() };
-    usb.csr().modify(|_, w| w.genrsm().clear_bit());
+
+    with_supply(|supply| {
+        supply.set_pull_up(false);
+        supply.set_power(false);
+    });
+    usb.ier().write(|w| unsafe { w.bits(0) });
+    reset_device_state();
 }
 
-/// USB endpoint memory buffer - matches HT32F52352 hardware: 1024-byte EP_SRAM
-static mut EP_MEMORY: [u8; EP_SRAM_SIZE] = [0; EP_SRAM_SIZE];
\ No newline at end of file
+/// Connect or disconnect the on-chip DP pull-up
+fn set_internal_pull_up(connected: bool) {
+    let usb = unsafe { &*pac::Usb::ptr() };
+    usb.csr().modify(|_, w| w.dppuen().bit(connected));
+}
+
+/// Board-specific USB power and connection control
+///
+/// Every method has a default for a board wired straight to the connector:
+/// no power switch, the on-chip D+ pull-up and VBUS from `Config::vbus_pin`.
+/// Override the ones the board does differently:
+///
+/// ```rust,ignore
+/// struct Supply {
+///     /// Enable of the USB load switch feeding the level shifter
+///     switch: AnyPin,
+///     /// VBUS through a divider
+///     vbus: AnyPin,
+/// }
+///
+/// impl usb::UsbSupply for Supply {
+///     fn set_power(&mut self, on: bool) {
+///         if on { self.switch.set_high() } else { self.switch.set_low() }.ok();
+///     }
+///
+///     fn vbus_present(&mut self) -> Option<bool> {
+///         self.vbus.is_high().ok()
+///     }
+/// }
+///
+/// static SUPPLY: StaticCell<Supply> = StaticCell::new();
+/// let config = usb::Config {
+///     supply: Some(SUPPLY.init(Supply { switch, vbus })),
+///     ..Default::default()
+/// };
+/// ```
+///
+/// The methods run in a critical section, so they must be quick and must
+/// not call back into the USB driver.
+pub trait UsbSupply: Send {
+    /// Switch the board's USB power path (load switch, charge pump, level
+    /// shifter) on before the device connects, and off after it disconnects
+    fn set_power(&mut self, on: bool) {
+        let _ = on;
+    }
+
+    /// Connect or disconnect the D+ pull-up that announces the device
+    ///
+    /// The default drives the on-chip pull-up; boards with an external,
+    /// switched pull-up drive that instead and leave the on-chip one off.
+    fn set_pull_up(&mut self, connected: bool) {
+        set_internal_pull_up(connected);
+    }
+
+    /// VBUS level, or `None` if this board cannot sense it here
+    ///
+    /// With `None` the driver falls back to `Config::vbus_pin`, then to
+    /// assuming VBUS is present.
+    fn vbus_present(&mut self) -> Option<bool> {
+        None
+    }
+}
+
+/// Drop off the bus from a fault path
+///
+/// Unlike the normal disable this does not wait for a supply that is in use
+/// at the time of the fault; the on-chip pull-up goes off regardless.
+pub(crate) fn emergency_disconnect() {
+    set_internal_pull_up(false);
+    critical_section::with(|cs| {
+        if let Ok(mut supply) = SUPPLY.borrow(cs).try_borrow_mut() {
+            if let Some(supply) = supply.as_deref_mut() {
+                supply.set_pull_up(false);
+                supply.set_power(false);
+            }
+        }
+    });
+}
+
+/// Time [`detach`] keeps the D+ pull-up off, in ms
+///
+/// Hosts see a disconnect after 2.5 µs of SE0 (TDDIS), but some hubs only
+/// sample the port every few ms; 10 ms is enough for all of them.
+pub const DETACH_MS: u32 = 10;
+
+/// Take the device off the bus and wait [`DETACH_MS`]
+///
+/// The bus interrupts are masked while detached, as the SE0 the host's
+/// pull-downs hold would otherwise read as an endless bus reset, and every
+/// endpoint reports [`EndpointError::Disabled`]. Busy-waits, so it can run
+/// from a fault or power-fail path without the executor; the board supply
+/// stays on.
+pub fn detach() {
+    let usb = unsafe { &*pac::Usb::ptr() };
+    usb.ier().write(|w| unsafe { w.bits(0) });
+    with_supply(|supply| supply.set_pull_up(false));
+    reset_device_state();
+    BUS_WAKER.wake();
+    crate::cortex_delay::delay_ms(DETACH_MS);
+    debug!("usb: detached");
+}
+
+/// Reconnect after [`detach`]; the host resets and enumerates the device within about 100 ms
+pub fn attach() {
+    let usb = unsafe { &*pac::Usb::ptr() };
+    usb.isr().write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+    enable_usb_device();
+    debug!("usb: attached");
+}
+
+/// Supply used without `Config::supply`: all defaults
+struct OnChipSupply;
+
+impl UsbSupply for OnChipSupply {}
+
+/// Run `f` with the configured supply, or the on-chip defaults
+fn with_supply<R>(f: impl FnOnce(&mut dyn UsbSupply) -> R) -> R {
+    critical_section::with(|cs| match SUPPLY.borrow_ref_mut(cs).as_deref_mut() {
+        Some(supply) => f(supply),
+        None => f(&mut OnChipSupply),
+    })
+}
+
+/// VBUS sense pin from `Config`, as port and pin number
+fn vbus_pin() -> Option<(char, u8)> {
+    critical_section::with(|cs| VBUS_PIN.borrow(cs).get())
+}
+
+/// VBUS level from the board supply or the sense pin, `None` if neither can tell
+fn vbus_level() -> Option<bool> {
+    use embedded_hal::digital::InputPin;
+
+    with_supply(|supply| supply.vbus_present())
+        .or_else(|| vbus_pin().map(|(port, pin)| AnyPin::new(port, pin).is_high().unwrap_or(true)))
+}
+
+/// VBUS level; assumed present when it cannot be sensed
+fn vbus_present() -> bool {
+    vbus_level().unwrap_or(true)
+}
+
+/// Interval for polling the VBUS pin and the suspend state
+#[cfg(feature = "time")]
+const VBUS_POLL_MS: u32 = 10;
+
+/// Wait until the USB cable is unplugged
+///
+/// With VBUS sensing (`Config::vbus_pin` or a [`UsbSupply`] that reports it)
+/// this waits for VBUS to drop. Otherwise, if
+/// `Config::detach_timeout_ms` is set, a suspend that lasts longer than the
+/// timeout without resume or reset counts as a detach. With neither it never
+/// returns. Usable while `UsbDevice::run()` owns the bus.
+#[cfg(feature = "time")]
+pub async fn wait_for_vbus_removed() {
+    let mut suspended_ms = 0u32;
+
+    loop {
+        if let Some(present) = vbus_level() {
+            if !present {
+                return;
+            }
+        } else {
+            let timeout = DETACH_TIMEOUT_MS.load(Ordering::Relaxed);
+            if SUSPENDED.load(Ordering::Acquire) {
+                suspended_ms = suspended_ms.saturating_add(VBUS_POLL_MS);
+                if timeout != 0 && suspended_ms >= timeout {
+                    return;
+                }
+            } else {
+                suspended_ms = 0;
+            }
+        }
+
+        embassy_time::Timer::after_millis(VBUS_POLL_MS as u64).await;
+    }
+}
+
+/// Whether the host has suspended the bus
+pub(crate) fn is_suspended() -> bool {
+    SUSPENDED.load(Ordering::Acquire)
+}
+
+/// Put the transceiver in its suspend low-power mode, or take it out
+pub(crate) fn set_low_power(enable: bool) {
+    let usb = unsafe { &*pac::Usb::ptr() };
+    usb.csr().modify(|_, w| w.lpmode().bit(enable));
+}
+
+/// Where the USB port's power comes from
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PowerSource {
+    /// VBUS is absent (only detectable with `Config::vbus_pin` or a [`UsbSupply`])
+    Detached,
+    /// VBUS is present but no host has reset the bus yet
+    Unknown,
+    /// Standard downstream port, not yet configured
+    Unconfigured,
+    /// Standard downstream port, configured with the device's bMaxPower
+    Configured { max_power_ma: u16 },
+    /// Standard downstream port that suspended the bus
+    Suspended,
+    /// Dedicated charging port: VBUS without any host activity
+    Charger,
+}
+
+impl PowerSource {
+    /// Current the device may draw from VBUS, in mA
+    ///
+    /// `Unknown` is treated as a single unit load until detection finishes;
+    /// `Charger` assumes the BC1.2 DCP minimum of 1.5 A.
+    pub fn current_limit_ma(&self) -> u16 {
+        match self {
+            PowerSource::Detached => 0,
+            PowerSource::Unknown | PowerSource::Unconfigured => 100,
+            PowerSource::Configured { max_power_ma } => *max_power_ma,
+            // 2.5 mA suspend budget
+            PowerSource::Suspended => 2,
+            PowerSource::Charger => 1500,
+        }
+    }
+}
+
+/// Current power source as far as the driver can tell
+///
+/// Never returns [`PowerSource::Charger`]; that needs the timeout in
+/// [`detect_power_source`].
+pub fn power_source() -> PowerSource {
+    if !vbus_present() {
+        PowerSource::Detached
+    } else if SUSPENDED.load(Ordering::Acquire) {
+        PowerSource::Suspended
+    } else if DEVICE_CONFIGURED.load(Ordering::Acquire) {
+        PowerSource::Configured { max_power_ma: MAX_POWER_MA.load(Ordering::Relaxed) as u16 }
+    } else if RESET_SEEN.load(Ordering::Acquire) {
+        PowerSource::Unconfigured
+    } else {
+        PowerSource::Unknown
+    }
+}
+
+/// Classify the port, treating "no bus reset within `timeout_ms`" as a charger
+///
+/// Hosts reset the bus within about 100 ms of seeing the D+ pull-up, while a
+/// DCP shorts D+ to D- and never does; 500 ms is a safe timeout. Call it after
+/// `UsbDevice::run()` has enabled the device. A host that never enumerates
+/// (e.g. a powered hub without an upstream link) also reads as a charger.
+#[cfg(feature = "time")]
+pub async fn detect_power_source(timeout_ms: u32) -> PowerSource {
+    let mut waited_ms = 0;
+    loop {
+        match power_source() {
+            PowerSource::Unknown if waited_ms >= timeout_ms => return PowerSource::Charger,
+            PowerSource::Unknown => {}
+            source => return source,
+        }
+        embassy_time::Timer::after_millis(VBUS_POLL_MS as u64).await;
+        waited_ms += VBUS_POLL_MS;
+    }
+}
+
+/// SOF interrupt enable bit for the current number of tickers
+fn sof_interrupt() -> u32 {
+    if critical_section::with(|cs| SOF_TICKERS.borrow(cs).get()) > 0 { INT_SOF } else { 0 }
+}
+
+/// Frame number of the last SOF (11 bits)
+pub fn frame_number() -> u16 {
+    let usb = unsafe { &*pac::Usb::ptr() };
+    // FCR: FRNUM[10:0]
+    (usb.fcr().read().bits() & 0x7FF) as u16
+}
+
+/// Ticker locked to the host's 1 ms USB frames, see [`sof_ticker`]
+pub struct SofTicker {
+    seen: u32,
+}
+
+/// Start a ticker that fires on every USB start-of-frame
+///
+/// No frames arrive while the device is detached or the bus suspended, so
+/// combine with a timeout where that matters. Up to four tasks can wait on
+/// tickers at once.
+pub fn sof_ticker() -> SofTicker {
+    critical_section::with(|cs| {
+        let tickers = SOF_TICKERS.borrow(cs);
+        tickers.set(tickers.get() + 1);
+        if tickers.get() == 1 {
+            let usb = unsafe { &*pac::Usb::ptr() };
+            usb.isr().write(|w| unsafe { w.bits(INT_SOF) });
+            // Only add SOF to an enabled device; enable_usb_device picks it up otherwise
+            if usb.ier().read().bits() & INT_UGIE != 0 {
+                usb.ier().modify(|r, w| unsafe { w.bits(r.bits() | INT_SOF) });
+            }
+        }
+    });
+    SofTicker {
+        seen: SOF_COUNT.load(Ordering::Acquire),
+    }
+}
+
+impl SofTicker {
+    /// Wait for the next frame and return its frame number
+    ///
+    /// Like `embassy_time::Ticker`, frames missed while the caller was busy
+    /// are caught up one call at a time without waiting.
+    pub async fn next(&mut self) -> u16 {
+        poll_fn(|cx| {
+            if SOF_COUNT.load(Ordering::Acquire) != self.seen {
+                self.seen = self.seen.wrapping_add(1);
+                return Poll::Ready(frame_number());
+            }
+            critical_section::with(|cs| SOF_WAKERS.borrow_ref_mut(cs).register(cx.waker()));
+            if SOF_COUNT.load(Ordering::Acquire) != self.seen {
+                self.seen = self.seen.wrapping_add(1);
+                Poll::Ready(frame_number())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Skip frames that have already passed
+    pub fn reset(&mut self) {
+        self.seen = SOF_COUNT.load(Ordering::Acquire);
+    }
+}
+
+impl Drop for SofTicker {
+    fn drop(&mut self) {
+        critical_section::with(|cs| {
+            let tickers = SOF_TICKERS.borrow(cs);
+            tickers.set(tickers.get() - 1);
+            if tickers.get() == 0 {
+                let usb = unsafe { &*pac::Usb::ptr() };
+                usb.ier().modify(|r, w| unsafe { w.bits(r.bits() & !INT_SOF) });
+            }
+        });
+    }
+}
+
+/// SOF frames per HSI measurement
+#[cfg(feature = "usb-crystalless")]
+const TRIM_WINDOW_FRAMES: u32 = 256;
+/// HSI error left alone, well inside the USB tolerance of ±2500 ppm
+#[cfg(feature = "usb-crystalless")]
+const TRIM_DEADBAND_PPM: i32 = 500;
+
+/// Last HSI error measured by [`run_hsi_trim`]
+#[cfg(feature = "usb-crystalless")]
+static HSI_ERROR_PPM: core::sync::atomic::AtomicI32 = core::sync::atomic::AtomicI32::new(0);
+
+/// Keep the HSI, and with it the USB clock, locked to the host's frame clock
+///
+/// Every 256 SOFs the elapsed `embassy-time` ticks, which
+/// run from the HSI, are compared to the nominal 1 ms per frame and the HSI
+/// fine trim is stepped by one towards zero error. Windows broken by a
+/// suspend, reset or missed frames are discarded. Takes over the trim from
+/// [`rcc::enable_hsi_auto_trim`](crate::rcc::enable_hsi_auto_trim).
+#[cfg(feature = "usb-crystalless")]
+pub async fn run_hsi_trim() -> ! {
+    use embassy_time::{Duration, Instant, with_timeout};
+
+    let mut ticker = sof_ticker();
+    loop {
+        // Start on a fresh frame so the window is whole frames
+        ticker.reset();
+        let start_frame = ticker.next().await;
+        let start = Instant::now();
+
+        let mut frames = 0;
+        let mut frame = start_frame;
+        while frames < TRIM_WINDOW_FRAMES {
+            // More than 3 ms without a frame means suspend or detach
+            match with_timeout(Duration::from_millis(3), ticker.next()).await {
+                Ok(next) => {
+                    frames += (next.wrapping_sub(frame) & 0x7FF) as u32;
+                    frame = next;
+                }
+                Err(_) => break,
+            }
+        }
+        let elapsed = start.elapsed();
+        if frames != TRIM_WINDOW_FRAMES {
+            continue;
+        }
+
+        // Timer ticks run from the HSI: more ticks than nominal means a fast HSI
+        let expected = Duration::from_millis(TRIM_WINDOW_FRAMES as u64).as_ticks() as i64;
+        let error_ppm = ((elapsed.as_ticks() as i64 - expected) * 1_000_000 / expected) as i32;
+        HSI_ERROR_PPM.store(error_ppm, Ordering::Relaxed);
+
+        if error_ppm.abs() > TRIM_DEADBAND_PPM {
+            let trim = crate::rcc::hsi_trim();
+            let trim = if error_ppm > 0 { trim.saturating_sub(1) } else { trim.saturating_add(1) };
+            debug!("usb: HSI off by {} ppm, trim {}", error_ppm, trim);
+            crate::rcc::set_hsi_trim(trim);
+        }
+    }
+}
+
+/// HSI error against the host's frame clock from the last [`run_hsi_trim`] window, in ppm
+#[cfg(feature = "usb-crystalless")]
+pub fn hsi_error_ppm() -> i32 {
+    HSI_ERROR_PPM.load(Ordering::Relaxed)
+}
+
+/// USB interrupt handler body
+///
+/// Records bus and endpoint events for the async driver and wakes the waiting tasks.
+pub(crate) fn on_interrupt() {
+    let usb = unsafe { &*pac::Usb::ptr() };
+    let isr = usb.isr().read().bits() & usb.ier().read().bits();
+
+    if isr & INT_URST != 0 {
+        hot_trace!("usb: bus reset");
+        RESET_SEEN.store(true, Ordering::Release);
+        SUSPENDED.store(false, Ordering::Release);
+        reset_device_state();
+        IRQ_RESET.store(true, Ordering::Release);
+        BUS_WAKER.wake();
+        SUSPEND_WAKER.wake();
+    }
+    if isr & INT_SUSP != 0 {
+        SUSPENDED.store(true, Ordering::Release);
+        IRQ_SUSPEND.store(true, Ordering::Release);
+        BUS_WAKER.wake();
+        SUSPEND_WAKER.wake();
+    }
+    if isr & INT_RSM != 0 {
+        SUSPENDED.store(false, Ordering::Release);
+        IRQ_RESUME.store(true, Ordering::Release);
+        BUS_WAKER.wake();
+        SUSPEND_WAKER.wake();
+    }
+
+    if isr & INT_SOF != 0 {
+        SOF_COUNT.store(SOF_COUNT.load(Ordering::Relaxed).wrapping_add(1), Ordering::Release);
+        critical_section::with(|cs| SOF_WAKERS.borrow_ref_mut(cs).wake());
+    }
+
+    for index in 0..MAX_EP_COUNT {
+        if isr & (INT_EP0 << index) == 0 {
+            continue;
+        }
+
+        let ep_isr = ep_reg!(index, isr, |r| r.read().bits());
+        ep_reg!(index, isr, |r| r.write(|w| unsafe { w.bits(ep_isr) }));
+
+        if ep_isr & EP_INT_SDRX != 0 {
+            EP0_SETUP.store(true, Ordering::Release);
+            EP_OUT_WAKERS[0].wake();
+        }
+        if ep_isr & EP_INT_ODRX != 0 {
+            EP_OUT_READY[index].store(true, Ordering::Release);
+            EP_OUT_WAKERS[index].wake();
+        }
+        if ep_isr & EP_INT_IDTX != 0 {
+            if index == 0 {
+                let addr = PENDING_ADDRESS.load(Ordering::Acquire);
+                if addr != NO_ADDRESS {
+                    PENDING_ADDRESS.store(NO_ADDRESS, Ordering::Relaxed);
+                    set_device_address(addr as u8);
+                }
+            }
+            EP_IN_DONE[index].store(true, Ordering::Release);
+            EP_IN_WAKERS[index].wake();
+        }
+    }
+
+    // Acknowledge everything that was handled above; SOF only counts while enabled
+    usb.isr().write(|w| unsafe { w.bits(isr) });
+}
+
+#[cfg(feature = "rt")]
+use crate::pac::interrupt;
+
+#[cfg(feature = "rt")]
+#[interrupt]
+fn USB() {
+    #[cfg(feature = "task-trace")]
+    let _isr = crate::task_trace::Isr::enter();
+    on_interrupt();
+}
@@ -0,0 +1,165 @@
+//! EP_SRAM layout, endpoint buffer allocation and byte access
+//!
+//! EP_SRAM only takes 32-bit accesses, so every packet copy is split into an
+//! unaligned head, whole words and a tail. None of this touches the USB
+//! registers directly: word accesses go through [`RegisterAccess`] and bulk
+//! word copies through a caller-supplied function, so the host tests in
+//! `tests/host` run the same code against [`MockRegisters`](crate::regs).
+//!
+//! Not part of the API; the driver is the only user on target.
+
+use crate::regs::RegisterAccess;
+
+/// EP_SRAM base address (32-bit access only)
+pub const EP_SRAM_BASE: usize = 0x400A_A000;
+/// Total endpoint buffer memory
+pub const EP_SRAM_SIZE: usize = 1024;
+/// Full-speed USB max packet size
+pub const MAX_PACKET_SIZE: usize = 64;
+/// The first 8 bytes of EP_SRAM hold the last received SETUP packet
+pub const EP0_SETUP_OFFSET: u16 = 0;
+/// EP0 IN buffer follows the SETUP area, EP0 OUT buffer follows the IN buffer
+pub const EP0_BUF_OFFSET: u16 = 8;
+/// First EP_SRAM byte available to the configurable endpoints
+pub const EP_BUF_START: u16 = EP0_BUF_OFFSET + 2 * MAX_PACKET_SIZE as u16;
+
+/// Bump allocator for endpoint buffers in EP_SRAM
+#[derive(Debug, Copy, Clone)]
+pub struct Allocator {
+    next: u16,
+}
+
+impl Allocator {
+    /// Start after the SETUP area and the EP0 buffers
+    pub const fn new() -> Self {
+        Self { next: EP_BUF_START }
+    }
+
+    /// Reserve a word-aligned buffer of at least `len` bytes, returning its EP_SRAM offset
+    pub fn alloc(&mut self, len: u16) -> Option<u16> {
+        let len = (len as usize + 3) & !3;
+        if self.next as usize + len > EP_SRAM_SIZE {
+            return None;
+        }
+        let offset = self.next;
+        self.next += len as u16;
+        Some(offset)
+    }
+
+    /// Bytes not handed out yet
+    pub const fn remaining(&self) -> usize {
+        EP_SRAM_SIZE - self.next as usize
+    }
+}
+
+impl Default for Allocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Unpack the bytes of an EP_SRAM word, lowest address first
+#[inline(always)]
+pub const fn word_to_bytes(word: u32) -> [u8; 4] {
+    word.to_le_bytes()
+}
+
+/// Pack up to 4 bytes into an EP_SRAM word, lowest address first; missing bytes are zero
+#[inline(always)]
+pub fn bytes_to_word(bytes: &[u8]) -> u32 {
+    let mut word = [0u8; 4];
+    word[..bytes.len()].copy_from_slice(bytes);
+    u32::from_le_bytes(word)
+}
+
+/// Read one word of EP_SRAM; `offset` must be word aligned
+#[inline(always)]
+pub fn read_word(regs: &impl RegisterAccess, offset: usize) -> u32 {
+    regs.read(EP_SRAM_BASE + offset)
+}
+
+/// Write one word of EP_SRAM; `offset` must be word aligned
+#[inline(always)]
+pub fn write_word(regs: &impl RegisterAccess, offset: usize, word: u32) {
+    regs.write(EP_SRAM_BASE + offset, word)
+}
+
+/// Copy bytes out of EP_SRAM
+///
+/// `bulk(src, dst, words)` may copy the word-aligned middle part in one go,
+/// e.g. with PDMA; it returns `false` to have it copied word by word instead.
+pub fn read(
+    regs: &impl RegisterAccess,
+    offset: u16,
+    buf: &mut [u8],
+    bulk: impl FnOnce(*const u8, *mut u8, usize) -> bool,
+) {
+    let mut addr = offset as usize;
+
+    // Unaligned head: pick the wanted bytes out of the first word
+    let skip = addr & 3;
+    let head_len = if skip != 0 { buf.len().min(4 - skip) } else { 0 };
+    let (head, buf) = buf.split_at_mut(head_len);
+    if !head.is_empty() {
+        let bytes = word_to_bytes(read_word(regs, addr & !3));
+        head.copy_from_slice(&bytes[skip..skip + head_len]);
+        addr += head_len;
+    }
+
+    let words = buf.len() / 4;
+    if buf.as_ptr() as usize & 3 == 0 && bulk((EP_SRAM_BASE + addr) as *const u8, buf.as_mut_ptr(), words) {
+        // Copied by `bulk`
+    } else if buf.as_ptr() as usize & 3 == 0 {
+        // Word-aligned destination: one SRAM access and one store per 4 bytes
+        let dst = buf.as_mut_ptr() as *mut u32;
+        for i in 0..words {
+            let word = read_word(regs, addr + i * 4);
+            unsafe { dst.add(i).write(u32::from_le(word)) };
+        }
+    } else {
+        for (i, chunk) in buf[..words * 4].chunks_exact_mut(4).enumerate() {
+            chunk.copy_from_slice(&word_to_bytes(read_word(regs, addr + i * 4)));
+        }
+    }
+
+    // Tail: less than a word left
+    let tail = &mut buf[words * 4..];
+    if !tail.is_empty() {
+        let bytes = word_to_bytes(read_word(regs, addr + words * 4));
+        let n = tail.len();
+        tail.copy_from_slice(&bytes[..n]);
+    }
+}
+
+/// Copy bytes into EP_SRAM; `offset` must be word aligned
+///
+/// `bulk` is as for [`read`]. The bytes after a partial last word are zeroed.
+pub fn write(
+    regs: &impl RegisterAccess,
+    offset: u16,
+    data: &[u8],
+    bulk: impl FnOnce(*const u8, *mut u8, usize) -> bool,
+) {
+    let addr = offset as usize;
+    let words = data.len() / 4;
+
+    if data.as_ptr() as usize & 3 == 0 && bulk(data.as_ptr(), (EP_SRAM_BASE + addr) as *mut u8, words) {
+        // Copied by `bulk`
+    } else if data.as_ptr() as usize & 3 == 0 {
+        // Word-aligned source: one load and one SRAM access per 4 bytes
+        let src = data.as_ptr() as *const u32;
+        for i in 0..words {
+            let word = unsafe { src.add(i).read() };
+            write_word(regs, addr + i * 4, u32::to_le(word));
+        }
+    } else {
+        for (i, chunk) in data[..words * 4].chunks_exact(4).enumerate() {
+            write_word(regs, addr + i * 4, bytes_to_word(chunk));
+        }
+    }
+
+    let tail = &data[words * 4..];
+    if !tail.is_empty() {
+        write_word(regs, addr + words * 4, bytes_to_word(tail));
+    }
+}
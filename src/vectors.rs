@@ -0,0 +1,270 @@
+//! Interrupt vector audit and run-time handler slots
+//!
+//! Every HT32F523xx interrupt is declared here as a type implementing
+//! [`Vector`], with its IRQ number and whether the HAL defines its handler
+//! in this build. Vectors the HAL leaves alone end up in the HAL's
+//! `DefaultHandler` (`rt` feature), which calls the handler registered for
+//! them with [`set_handler`]:
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::vectors::{self, Spi1};
+//!
+//! fn on_spi1() {
+//!     // ...
+//! }
+//!
+//! vectors::set_handler::<Spi1>(Some(on_spi1));
+//! ```
+//!
+//! Registering a vector the HAL handles itself fails to compile. An
+//! interrupt that fires with no handler is logged with its name and the PC
+//! it arrived at, kept for [`last_unhandled`], and masked in the NVIC so it
+//! cannot fire again. cortex-m-rt's own default spins in place, which without
+//! a debugger looks like a hung chip. A `#[interrupt]` function in the
+//! application still takes precedence over both.
+
+use core::cell::Cell;
+
+use cortex_m::interrupt::InterruptNumber;
+use cortex_m::peripheral::NVIC;
+use critical_section::Mutex;
+
+use crate::pac::Interrupt;
+
+/// Number of interrupt lines on the Cortex-M0+
+pub const IRQ_COUNT: usize = 32;
+
+/// Exception number of IRQ 0
+const IRQ_BASE: u16 = 16;
+
+/// An HT32F523xx interrupt
+pub trait Vector {
+    /// IRQ number, the position in the vector table after the 16 system exceptions
+    const IRQ: u16;
+    /// Name in the reference manual
+    const NAME: &'static str;
+    /// Whether the HAL defines the handler in this build, so it cannot be registered
+    const HAL_HANDLER: bool;
+}
+
+/// An IRQ number for the NVIC functions
+#[derive(Copy, Clone)]
+struct Irqn(u16);
+
+unsafe impl InterruptNumber for Irqn {
+    fn number(self) -> u16 {
+        self.0
+    }
+}
+
+macro_rules! vectors {
+    ($($(#[$doc:meta])* $ty:ident = $irq:expr, $name:literal, $hal:expr;)*) => {
+        $(
+            $(#[$doc])*
+            pub struct $ty;
+
+            impl Vector for $ty {
+                const IRQ: u16 = $irq;
+                const NAME: &'static str = $name;
+                const HAL_HANDLER: bool = $hal;
+            }
+        )*
+
+        /// Name of every IRQ by number, empty for reserved lines
+        const NAMES: [&str; IRQ_COUNT] = {
+            let mut names = [""; IRQ_COUNT];
+            $(
+                assert!(($irq as usize) < IRQ_COUNT && names[$irq as usize].is_empty(), "IRQ declared twice");
+                names[$irq as usize] = $name;
+            )*
+            names
+        };
+    };
+}
+
+// Lines the HAL has a driver for take their number from the PAC; the rest
+// follow the reference manual's vector table, and the check in `NAMES`
+// catches any that collide
+vectors! {
+    /// Low voltage and brown-out detector
+    LvdBod = Interrupt::LVD_BOD as u16, "LVD_BOD", cfg!(feature = "rt");
+    /// Real-time clock
+    Rtc = 1, "RTC", false;
+    /// Flash memory controller
+    Fmc = 2, "FMC", false;
+    /// Event wake-up
+    Evwup = 3, "EVWUP", false;
+    /// External interrupt lines 0 and 1
+    Exti0_1 = Interrupt::EXTI0_1 as u16, "EXTI0_1", cfg!(feature = "rt");
+    /// External interrupt lines 2 and 3
+    Exti2_3 = Interrupt::EXTI2_3 as u16, "EXTI2_3", cfg!(feature = "rt");
+    /// External interrupt lines 4 to 15
+    Exti4_15 = Interrupt::EXTI4_15 as u16, "EXTI4_15", cfg!(feature = "rt");
+    /// Comparators
+    Cmp = 7, "CMP", false;
+    /// ADC
+    Adc = 8, "ADC", false;
+    /// Motor control timer 0
+    Mctm0 = 10, "MCTM0", false;
+    /// General-purpose timer 1
+    Gptm1 = Interrupt::GPTM1 as u16, "GPTM1", cfg!(feature = "rt");
+    /// General-purpose timer 0; the time driver's when it owns it
+    Gptm0 = Interrupt::GPTM0 as u16, "GPTM0", cfg!(feature = "rt");
+    /// Single-channel timer 0
+    Sctm0 = 14, "SCTM0", false;
+    /// Single-channel timer 1
+    Sctm1 = 15, "SCTM1", false;
+    /// Basic function timer 0
+    Bftm0 = Interrupt::BFTM0 as u16, "BFTM0", false;
+    /// Basic function timer 1
+    Bftm1 = Interrupt::BFTM1 as u16, "BFTM1", false;
+    /// I2C 0
+    I2c0 = 19, "I2C0", false;
+    /// I2C 1
+    I2c1 = 20, "I2C1", false;
+    /// SPI 0
+    Spi0 = 21, "SPI0", false;
+    /// SPI 1
+    Spi1 = 22, "SPI1", false;
+    /// USART 0
+    Usart0 = Interrupt::USART0 as u16, "USART0", false;
+    /// USART 1
+    Usart1 = Interrupt::USART1 as u16, "USART1", false;
+    /// UART 0
+    Uart0 = 25, "UART0", false;
+    /// UART 1
+    Uart1 = 26, "UART1", false;
+    /// Smart card interface
+    Sci = 27, "SCI", false;
+    /// I2S
+    I2s = 28, "I2S", false;
+    /// USB device
+    Usb = Interrupt::USB as u16, "USB", cfg!(all(feature = "rt", feature = "usb"));
+    /// PDMA channels 0 and 1
+    PdmaCh0_1 = Interrupt::PDMA_CH0_1 as u16, "PDMA_CH0_1", cfg!(feature = "rt");
+    /// PDMA channels 2 to 5
+    PdmaCh2_5 = Interrupt::PDMA_CH2_5 as u16, "PDMA_CH2_5", cfg!(feature = "rt");
+}
+
+/// Name of exception number `exception`: a system exception below 16, IRQ + 16 above
+pub fn name(exception: u16) -> &'static str {
+    let irq = exception.wrapping_sub(IRQ_BASE) as usize;
+    match exception {
+        2 => "NMI",
+        3 => "HardFault",
+        11 => "SVCall",
+        14 => "PendSV",
+        15 => "SysTick",
+        _ if exception >= IRQ_BASE && irq < IRQ_COUNT && !NAMES[irq].is_empty() => NAMES[irq],
+        _ => "reserved",
+    }
+}
+
+static HANDLERS: [Mutex<Cell<Option<fn()>>>; IRQ_COUNT] = [const { Mutex::new(Cell::new(None)) }; IRQ_COUNT];
+
+/// Route `V` to `handler` and unmask it in the NVIC; `None` masks it again
+///
+/// The handler runs in interrupt context and must clear the peripheral's
+/// flag, as with a `#[interrupt]` function.
+pub fn set_handler<V: Vector>(handler: Option<fn()>) {
+    const { assert!(!V::HAL_HANDLER, "the HAL defines this interrupt's handler") };
+    critical_section::with(|cs| HANDLERS[V::IRQ as usize].borrow(cs).set(handler));
+    match handler {
+        Some(_) => unsafe { NVIC::unmask(Irqn(V::IRQ)) },
+        None => NVIC::mask(Irqn(V::IRQ)),
+    }
+}
+
+/// An exception that reached `DefaultHandler` with nothing to handle it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Unhandled {
+    /// Exception number, IRQ + 16 for interrupts; see [`name`]
+    pub exception: u16,
+    /// Address of the instruction it arrived at
+    pub pc: u32,
+}
+
+static LAST_UNHANDLED: Mutex<Cell<Option<Unhandled>>> = Mutex::new(Cell::new(None));
+static UNHANDLED_COUNT: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// The latest unhandled exception since reset
+pub fn last_unhandled() -> Option<Unhandled> {
+    critical_section::with(|cs| LAST_UNHANDLED.borrow(cs).get())
+}
+
+/// Unhandled exceptions since reset
+pub fn unhandled_count() -> u32 {
+    critical_section::with(|cs| UNHANDLED_COUNT.borrow(cs).get())
+}
+
+/// Define the exception handler symbol `$name` as a trampoline that calls
+/// `extern "C" fn $handler(frame: &cortex_m_rt::ExceptionFrame)` with the
+/// frame stacked on entry
+///
+/// EXC_RETURN bit 2 says whether the frame went to the process or the main
+/// stack. The handler returns straight from the exception, as LR still holds
+/// EXC_RETURN.
+#[cfg(feature = "rt")]
+macro_rules! exception_trampoline {
+    ($name:literal, $handler:path) => {
+        core::arch::global_asm!(
+            concat!(".section .text.", $name, ", \"ax\""),
+            concat!(".global ", $name),
+            concat!(".type ", $name, ", %function"),
+            ".thumb_func",
+            concat!($name, ":"),
+            "    mov r0, lr",
+            "    movs r1, #4",
+            "    tst r0, r1",
+            "    bne 1f",
+            "    mrs r0, MSP",
+            "    b 2f",
+            "1:",
+            "    mrs r0, PSP",
+            "2:",
+            "    ldr r1, ={handler}",
+            "    bx r1",
+            "    .ltorg",
+            concat!(".size ", $name, ", . - ", $name),
+            handler = sym $handler,
+        );
+    };
+}
+#[cfg(feature = "rt")]
+pub(crate) use exception_trampoline;
+
+#[cfg(feature = "rt")]
+exception_trampoline!("DefaultHandler", default_handler);
+
+#[cfg(feature = "rt")]
+extern "C" fn default_handler(frame: &cortex_m_rt::ExceptionFrame) {
+    // ICSR.VECTACTIVE
+    let exception = unsafe { (*cortex_m::peripheral::SCB::PTR).icsr.read() & 0x1FF } as u16;
+
+    if let Some(irq) = exception.checked_sub(IRQ_BASE).filter(|&irq| (irq as usize) < IRQ_COUNT) {
+        if let Some(handler) = critical_section::with(|cs| HANDLERS[irq as usize].borrow(cs).get()) {
+            #[cfg(feature = "task-trace")]
+            let _isr = crate::task_trace::Isr::enter();
+            handler();
+            return;
+        }
+        NVIC::mask(Irqn(irq));
+    } else if exception == 15 {
+        // A SysTick interrupt would come back every period; stop it
+        unsafe { (*cortex_m::peripheral::SYST::PTR).csr.modify(|csr| csr & !(1 << 1)) };
+    }
+
+    let unhandled = Unhandled { exception, pc: frame.pc() };
+    critical_section::with(|cs| {
+        LAST_UNHANDLED.borrow(cs).set(Some(unhandled));
+        let count = UNHANDLED_COUNT.borrow(cs);
+        count.set(count.get().wrapping_add(1));
+    });
+    error!(
+        "unhandled {} (exception {}) at PC {:#010x}, now masked",
+        name(exception),
+        exception,
+        unhandled.pc
+    );
+}
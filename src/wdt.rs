@@ -0,0 +1,138 @@
+//! Independent watchdog timer (WDT)
+//!
+//! The WDT counts down from the LSI (~32 kHz) and resets the chip unless it
+//! is reloaded in time. Once started it cannot be stopped.
+//!
+//! # Long operations
+//!
+//! Flash erase, SPI flash erase and similar waits can outlast a tight
+//! timeout. Wrap them in [`scoped_pet`]: while the guard lives, the HAL's
+//! long-running loops reload the watchdog themselves. Outside a guard they
+//! never do, so a task stuck in one still trips the watchdog.
+//!
+//! ```rust,ignore
+//! use embassy_ht32f523xx::wdt;
+//!
+//! p.wdt.start(500);
+//! loop {
+//!     wdt::pet();
+//!     if settings_changed {
+//!         let _pet = wdt::scoped_pet();
+//!         flash.erase_async(SETTINGS, SETTINGS + 4096).await?;
+//!         flash.write_async(SETTINGS, &bytes).await?;
+//!     }
+//!     Timer::after_millis(100).await;
+//! }
+//! ```
+//!
+//! The PAC has no WDT view, so registers are accessed by address.
+
+use core::cell::Cell;
+
+use critical_section::Mutex;
+
+use crate::regs::{Mmio, RegisterAccess};
+
+const WDT_BASE: usize = 0x4006_8000;
+const WDT_CR: usize = 0x000;
+const WDT_MR0: usize = 0x004;
+const WDT_MR1: usize = 0x008;
+const WDT_PR: usize = 0x010;
+
+// WDTCR: reload with the key in the upper half-word
+const CR_RELOAD: u32 = 0x5FA0 << 16 | 1;
+// WDTMR0
+const MR0_WDTRSTEN: u32 = 1 << 13;
+const MR0_WDTEN: u32 = 1 << 16;
+const MR0_WDTV_MAX: u32 = 0xFFF;
+// WDTMR1: delta (reload window) in [11:0], prescaler exponent in [14:12]
+const MR1_WDTD_MAX: u32 = 0xFFF;
+const MR1_WDTPS_SHIFT: u32 = 12;
+// WDTPR: write to unlock WDTMR0/1
+const PR_UNLOCK: u32 = 0x35CA;
+const PR_LOCK: u32 = 0;
+
+/// Nominal LSI frequency; the real one varies by about ±10 % over temperature
+const LSI_HZ: u32 = 32_000;
+
+/// Nesting depth of [`scoped_pet`] guards
+static SCOPED: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Watchdog timer
+pub struct Watchdog {
+    _private: (),
+}
+
+impl Watchdog {
+    pub(crate) fn new() -> Self {
+        Self { _private: () }
+    }
+
+    /// Start with a timeout of about `timeout_ms` (up to ~32 s); cannot be undone
+    pub fn start(&mut self, timeout_ms: u32) {
+        let ckcu = unsafe { &*crate::pac::Ckcu::ptr() };
+        ckcu.apbccr1().modify(|_, w| w.wdtren().set_bit());
+
+        // Smallest prescaler that fits the 12-bit counter
+        let ticks = (timeout_ms as u64 * LSI_HZ as u64 / 1000).max(1);
+        let mut prescaler = 0;
+        while (ticks >> prescaler) > MR0_WDTV_MAX as u64 + 1 && prescaler < 7 {
+            prescaler += 1;
+        }
+        let value = ((ticks >> prescaler) as u32).clamp(1, MR0_WDTV_MAX + 1) - 1;
+
+        Mmio.write(WDT_BASE + WDT_PR, PR_UNLOCK);
+        // No window: reloads are accepted at any count
+        Mmio.write(WDT_BASE + WDT_MR1, MR1_WDTD_MAX | prescaler << MR1_WDTPS_SHIFT);
+        Mmio.write(WDT_BASE + WDT_MR0, value | MR0_WDTRSTEN | MR0_WDTEN);
+        Mmio.write(WDT_BASE + WDT_PR, PR_LOCK);
+        pet();
+
+        debug!("wdt: started, ~{} ms", timeout_ms);
+    }
+
+    /// Reload the counter, see [`pet`]
+    pub fn pet(&mut self) {
+        pet();
+    }
+}
+
+/// Reload the watchdog counter
+///
+/// Harmless while the watchdog is stopped, so libraries may call it freely.
+pub fn pet() {
+    Mmio.write(WDT_BASE + WDT_CR, CR_RELOAD);
+}
+
+/// Let the HAL's long operations reload the watchdog until the guard is dropped
+pub fn scoped_pet() -> ScopedPet {
+    critical_section::with(|cs| {
+        let depth = SCOPED.borrow(cs);
+        depth.set(depth.get() + 1);
+    });
+    pet();
+    ScopedPet { _private: () }
+}
+
+/// Guard returned by [`scoped_pet`]
+#[must_use = "long operations only pet the watchdog while the guard is alive"]
+pub struct ScopedPet {
+    _private: (),
+}
+
+impl Drop for ScopedPet {
+    fn drop(&mut self) {
+        critical_section::with(|cs| {
+            let depth = SCOPED.borrow(cs);
+            depth.set(depth.get() - 1);
+        });
+        pet();
+    }
+}
+
+/// Called from long-running HAL loops; reloads only inside a [`scoped_pet`]
+pub(crate) fn pet_if_scoped() {
+    if critical_section::with(|cs| SCOPED.borrow(cs).get()) > 0 {
+        pet();
+    }
+}
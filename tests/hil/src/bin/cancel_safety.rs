@@ -0,0 +1,62 @@
+//! Dropping an in-flight SPI transfer leaves the driver in sync
+//!
+//! A long transfer is cancelled by a short timer through `select`, then a
+//! short exchange must read back exactly what it sent.
+//!
+//! Wiring: connect PB4 (SPI0 MOSI) to PB5 (SPI0 MISO).
+
+#![no_std]
+#![no_main]
+
+use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
+use embassy_ht32f523xx::spi::{self, Spi};
+use embassy_ht32f523xx::time::Hertz;
+use embassy_time::{Duration, Timer};
+use hil_tests::{check, finish, TestResult};
+use {defmt_rtt as _, panic_probe as _};
+
+const NAME: &str = "CANCEL_SAFETY";
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let mut p = embassy_ht32f523xx::init(embassy_ht32f523xx::Config::default());
+
+    let config = spi::Config {
+        // Slow enough that 64 bytes take several milliseconds
+        frequency: Hertz::khz(100),
+        ..Default::default()
+    };
+    let mut spi = Spi::new(
+        p.spi0,
+        p.gpiob.pb3().into_alternate_function::<5>(),
+        p.gpiob.pb4().into_alternate_function::<5>(),
+        p.gpiob.pb5().into_alternate_function::<5>(),
+        config,
+    );
+
+    let result: TestResult = async {
+        let tx = [0xFFu8; 64];
+        let mut rx = [0u8; 64];
+
+        for round in 0..8u64 {
+            // Cancel at a different point of the transfer each round
+            let cancelled = select(spi.transfer(&mut rx, &tx), Timer::after_micros(300 + round * 70)).await;
+            check(matches!(cancelled, Either::Second(())), "transfer finished before the timer")?;
+
+            let mut probe = [0xA5, 0x5A, 0x3C];
+            spi.transfer_in_place(&mut probe).await.map_err(|_| "transfer after cancel failed")?;
+            check(probe == [0xA5, 0x5A, 0x3C], "stale byte read after a cancelled transfer")?;
+        }
+
+        let timed_out = spi.write_timeout(&tx, Duration::from_micros(500)).await;
+        check(timed_out == Err(spi::Error::Timeout), "write_timeout did not time out")?;
+
+        let mut probe = [0x81, 0x42];
+        spi.transfer_in_place(&mut probe).await.map_err(|_| "transfer after timeout failed")?;
+        check(probe == [0x81, 0x42], "stale byte read after a timeout")
+    }
+    .await;
+
+    finish(NAME, result);
+}
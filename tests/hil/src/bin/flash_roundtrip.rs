@@ -0,0 +1,42 @@
+//! Erase, program and read back the last flash page
+
+#![no_std]
+#![no_main]
+
+use embassy_executor::Spawner;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use embassy_ht32f523xx::flash::Flash;
+use hil_tests::{check, finish, TestResult};
+use {defmt_rtt as _, panic_probe as _};
+
+const NAME: &str = "FLASH_ROUNDTRIP";
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_ht32f523xx::init(embassy_ht32f523xx::Config::default());
+    let mut flash = p.flash;
+
+    let page = (flash.capacity() - Flash::ERASE_SIZE) as u32;
+    let end = page + Flash::ERASE_SIZE as u32;
+
+    let result: TestResult = async {
+        let mut pattern = [0u8; 64];
+        for (i, byte) in pattern.iter_mut().enumerate() {
+            *byte = (i as u8).wrapping_mul(37) ^ 0x5A;
+        }
+        let mut readback = [0u8; 64];
+
+        flash.erase_async(page, end).await.map_err(|_| "erase failed")?;
+        flash.read(page, &mut readback).map_err(|_| "read failed")?;
+        check(readback.iter().all(|&b| b == 0xFF), "page not blank after erase")?;
+
+        flash.write_async(page, &pattern).await.map_err(|_| "program failed")?;
+        flash.read(page, &mut readback).map_err(|_| "read failed")?;
+        check(readback == pattern, "read back differs from programmed data")?;
+
+        flash.erase_async(page, end).await.map_err(|_| "final erase failed")
+    }
+    .await;
+
+    finish(NAME, result);
+}
@@ -0,0 +1,46 @@
+//! GPIO output to input loopback and edge wake-up
+//!
+//! Wiring: connect PA0 to PA1 with a jumper wire.
+
+#![no_std]
+#![no_main]
+
+use embassy_executor::Spawner;
+use embassy_ht32f523xx::gpio::{Level, Pull, Speed};
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_hal_async::digital::Wait;
+use hil_tests::{check, finish, TestResult};
+use {defmt_rtt as _, panic_probe as _};
+
+const NAME: &str = "GPIO_LOOPBACK";
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let mut p = embassy_ht32f523xx::init(embassy_ht32f523xx::Config::default());
+
+    let mut out = p.gpioa.pa0().into_push_pull_output(Level::Low, Speed::High);
+    let mut input = p.gpioa.pa1().into_input_with_pull(Pull::Down).degrade();
+
+    let result: TestResult = async {
+        for level in [true, false, true, false] {
+            if level { out.set_high().unwrap() } else { out.set_low().unwrap() }
+            Timer::after_micros(10).await;
+            check(input.is_high().unwrap() == level, "PA1 does not follow PA0, check the jumper")?;
+        }
+
+        let edge = async {
+            Timer::after_millis(1).await;
+            out.set_high().unwrap();
+        };
+        let (woken, ()) = embassy_futures::join::join(
+            with_timeout(Duration::from_millis(100), input.wait_for_rising_edge()),
+            edge,
+        )
+        .await;
+        check(matches!(woken, Ok(Ok(()))), "no wake-up on rising edge")
+    }
+    .await;
+
+    finish(NAME, result);
+}
@@ -0,0 +1,44 @@
+//! Fastest SPI clock that survives a loopback on this board
+//!
+//! Sweeps SPI0 from 1 MHz up with `Spi::verify_max_frequency` and reports
+//! the fastest rate that reads back correctly, then checks that a transfer
+//! at that rate still works with the configuration set normally.
+//!
+//! Wiring: connect PB4 (SPI0 MOSI) to PB5 (SPI0 MISO).
+
+#![no_std]
+#![no_main]
+
+use embassy_executor::Spawner;
+use embassy_ht32f523xx::spi::{self, Spi};
+use hil_tests::{check, finish, TestResult};
+use {defmt_rtt as _, panic_probe as _};
+
+const NAME: &str = "SPI_MAX_CLOCK";
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let mut p = embassy_ht32f523xx::init(embassy_ht32f523xx::Config::default());
+
+    let mut spi = Spi::new(
+        p.spi0,
+        p.gpiob.pb3().into_alternate_function::<5>(),
+        p.gpiob.pb4().into_alternate_function::<5>(),
+        p.gpiob.pb5().into_alternate_function::<5>(),
+        spi::Config::default(),
+    );
+
+    let result: TestResult = async {
+        let max = spi.verify_max_frequency().await.ok_or("loopback fails at 1 MHz, check the jumper")?;
+        check(spi.max_verified_hz() == Some(max.to_hz()), "max_verified_hz does not match the sweep")?;
+        defmt::info!("SPI0 verified up to {=u32} Hz", max.to_hz());
+
+        spi.set_config(&spi::Config { frequency: max, ..Default::default() });
+        let mut probe = [0xA5, 0x5A, 0x00, 0xFF];
+        spi.transfer_in_place(&mut probe).await.map_err(|_| "transfer at the verified rate failed")?;
+        check(probe == [0xA5, 0x5A, 0x00, 0xFF], "wrong data at the verified rate")
+    }
+    .await;
+
+    finish(NAME, result);
+}
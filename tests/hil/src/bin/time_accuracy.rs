@@ -0,0 +1,53 @@
+//! embassy-time against an independent cycle count
+//!
+//! BFTM0 counts PCLK cycles while the time driver sleeps; the two must agree
+//! within 1 %.
+
+#![no_std]
+#![no_main]
+
+use embassy_executor::Spawner;
+use embassy_ht32f523xx::pac;
+use embassy_time::Timer;
+use hil_tests::{check, finish, TestResult};
+use {defmt_rtt as _, panic_probe as _};
+
+const NAME: &str = "TIME_ACCURACY";
+
+/// BFTMCR counter enable bit
+const BFTM_CR_CEN: u32 = 1 << 2;
+
+const SLEEP_MS: u32 = 100;
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let _p = embassy_ht32f523xx::init(embassy_ht32f523xx::Config::default());
+
+    let ckcu = unsafe { &*pac::Ckcu::ptr() };
+    ckcu.apbccr1().modify(|_, w| w.bftm0en().set_bit());
+    let bftm = unsafe { &*pac::Bftm0::ptr() };
+    bftm.bftm_cmpr().write(|w| unsafe { w.bits(u32::MAX) });
+    bftm.bftm_cntr().write(|w| unsafe { w.bits(0) });
+    bftm.bftm_cr().write(|w| unsafe { w.bits(BFTM_CR_CEN) });
+
+    let cycles_per_ms = embassy_ht32f523xx::rcc::get_clocks().apb_clk().to_hz() / 1_000;
+
+    let result: TestResult = async {
+        for _ in 0..5 {
+            // Align to a tick so the measured sleep is not shortened by a partial one
+            Timer::after_ticks(1).await;
+
+            let start = bftm.bftm_cntr().read().bits();
+            Timer::after_millis(SLEEP_MS as u64).await;
+            let elapsed = bftm.bftm_cntr().read().bits().wrapping_sub(start);
+
+            let expected = SLEEP_MS * cycles_per_ms;
+            defmt::info!("slept {} cycles, expected {}", elapsed, expected);
+            check(elapsed.abs_diff(expected) <= expected / 100, "sleep off by more than 1 %")?;
+        }
+        Ok(())
+    }
+    .await;
+
+    finish(NAME, result);
+}
@@ -0,0 +1,83 @@
+//! USB enumeration and CDC-ACM serial loopback
+//!
+//! Needs the host side of `cargo xtask hil --usb`: the runner waits for
+//! `c0de:cafe` to enumerate, opens the serial port and checks that the bytes
+//! it sends are echoed back.
+
+#![no_std]
+#![no_main]
+
+use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_ht32f523xx::usb::{Config as UsbConfig, Driver};
+use embassy_time::{with_timeout, Duration, Timer};
+use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
+use embassy_usb::Builder;
+use hil_tests::{finish, ready, TestResult};
+use static_cell::StaticCell;
+use {defmt_rtt as _, panic_probe as _};
+
+const NAME: &str = "USB_CDC_LOOPBACK";
+
+/// Bytes the host sends; must match the runner
+const LOOPBACK_LEN: usize = 256;
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_ht32f523xx::init(embassy_ht32f523xx::Config::default());
+
+    let driver = Driver::new(p.usb, UsbConfig::default());
+
+    let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("HT32 HIL");
+    config.serial_number = Some("hil-tests");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+
+    static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+    static STATE: StaticCell<State> = StaticCell::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        CONFIG_DESCRIPTOR.init([0; 256]),
+        BOS_DESCRIPTOR.init([0; 256]),
+        &mut [],
+        CONTROL_BUF.init([0; 64]),
+    );
+    let mut class = CdcAcmClass::new(&mut builder, STATE.init(State::new()), 64);
+    let mut usb = builder.build();
+
+    let test = async {
+        ready(NAME);
+
+        let result: TestResult = async {
+            with_timeout(Duration::from_secs(20), class.wait_connection())
+                .await
+                .map_err(|_| "host never opened the port")?;
+
+            let mut echoed = 0;
+            let mut buf = [0u8; 64];
+            while echoed < LOOPBACK_LEN {
+                let n = with_timeout(Duration::from_secs(5), class.read_packet(&mut buf))
+                    .await
+                    .map_err(|_| "timed out waiting for host data")?
+                    .map_err(|_| "read failed")?;
+                class.write_packet(&buf[..n]).await.map_err(|_| "echo failed")?;
+                echoed += n;
+            }
+
+            // Let the host collect the last packet before the core halts
+            Timer::after_millis(200).await;
+            Ok(())
+        }
+        .await;
+
+        finish(NAME, result)
+    };
+
+    join(usb.run(), test).await;
+}
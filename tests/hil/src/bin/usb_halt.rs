@@ -0,0 +1,122 @@
+//! USB endpoint halt and clear-halt, driven by the Linux `usbtest` driver
+//!
+//! The device is a bulk source/sink like the kernel's gadget zero: bulk IN
+//! sends zero-filled packets for as long as the host reads, bulk OUT takes
+//! whatever it is sent. The host side of `cargo xtask hil --usb` binds
+//! `usbtest` to `c0de:cafe`, runs `testusb` test 9 (chapter 9 requests) and
+//! test 13 (set/clear halt on each bulk endpoint, then I/O through it), and
+//! finally de-authorizes the device. The firmware passes once it is
+//! deconfigured after data has moved both ways, i.e. the endpoints came back
+//! from every halt.
+
+#![no_std]
+#![no_main]
+
+use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_futures::select::{select, Either};
+use embassy_ht32f523xx::embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_ht32f523xx::embassy_sync::signal::Signal;
+use embassy_ht32f523xx::usb::{Config as UsbConfig, Driver};
+use embassy_time::{with_timeout, Duration, Timer};
+use embassy_usb::driver::{Endpoint, EndpointIn, EndpointOut};
+use embassy_usb::{Builder, Handler};
+use hil_tests::{check, finish, ready, TestResult};
+use static_cell::StaticCell;
+use {defmt_rtt as _, panic_probe as _};
+
+const NAME: &str = "USB_HALT";
+
+/// Set on SET_CONFIGURATION(0) after having been configured
+static DECONFIGURED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+struct ConfigWatch {
+    configured: bool,
+}
+
+impl Handler for ConfigWatch {
+    fn configured(&mut self, configured: bool) {
+        if core::mem::replace(&mut self.configured, configured) && !configured {
+            DECONFIGURED.signal(());
+        }
+    }
+}
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_ht32f523xx::init(embassy_ht32f523xx::Config::default());
+
+    let driver = Driver::new(p.usb, UsbConfig::default());
+
+    let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("HT32 HIL source/sink");
+    config.serial_number = Some("hil-tests");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+
+    static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+    static WATCH: StaticCell<ConfigWatch> = StaticCell::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        CONFIG_DESCRIPTOR.init([0; 256]),
+        BOS_DESCRIPTOR.init([0; 256]),
+        &mut [],
+        CONTROL_BUF.init([0; 64]),
+    );
+    builder.handler(WATCH.init(ConfigWatch { configured: false }));
+
+    // Vendor interface with one bulk endpoint each way, as usbtest's autoconf expects
+    let mut function = builder.function(0xFF, 0, 0);
+    let mut interface = function.interface();
+    let mut alt = interface.alt_setting(0xFF, 0, 0, None);
+    let mut ep_in = alt.endpoint_bulk_in(None, 64);
+    let mut ep_out = alt.endpoint_bulk_out(None, 64);
+    drop(function);
+    let mut usb = builder.build();
+
+    let test = async {
+        ready(NAME);
+
+        let mut sent = 0usize;
+        let mut received = 0usize;
+
+        let source = async {
+            let packet = [0u8; 64];
+            loop {
+                ep_in.wait_enabled().await;
+                while ep_in.write(&packet).await.is_ok() {
+                    sent += packet.len();
+                }
+            }
+        };
+        let sink = async {
+            let mut buf = [0u8; 64];
+            loop {
+                ep_out.wait_enabled().await;
+                while let Ok(n) = ep_out.read(&mut buf).await {
+                    received += n;
+                }
+            }
+        };
+
+        let done = with_timeout(Duration::from_secs(60), DECONFIGURED.wait());
+        let result: TestResult = match select(done, join(source, sink)).await {
+            Either::First(Ok(())) => Ok(()),
+            Either::First(Err(_)) => Err("host never finished the usbtest run"),
+            Either::Second(_) => unreachable!(),
+        };
+        let result = result
+            .and_then(|()| check(sent > 0, "no IN data after clear-halt"))
+            .and_then(|()| check(received > 0, "no OUT data after clear-halt"));
+
+        Timer::after_millis(100).await;
+        finish(NAME, result)
+    };
+
+    join(usb.run(), test).await;
+}
@@ -0,0 +1,120 @@
+//! HID interrupt IN at a 1 ms polling interval
+//!
+//! The device is a `hid::MOUSE_DESCRIPTOR` mouse with `poll_ms: 1`. The host
+//! side of `cargo xtask hil --usb` opens its hidraw node, which makes the
+//! kernel poll the endpoint, and reads reports for a while. The firmware
+//! times a window of completed writes: at one report per frame it must take
+//! close to 1 ms each, neither completing early (reports dropped by the
+//! driver) nor late (frames missed). The reports jitter X by one count either
+//! way so the host pointer stays put.
+
+#![no_std]
+#![no_main]
+
+use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_ht32f523xx::hid::{MouseReport, MOUSE_DESCRIPTOR, MOUSE_REPORT_SIZE};
+use embassy_ht32f523xx::usb::{Config as UsbConfig, Driver};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embassy_usb::class::hid::{Config as HidConfig, HidWriter, State};
+use embassy_usb::Builder;
+use hil_tests::{check, finish, ready, TestResult};
+use static_cell::StaticCell;
+use {defmt_rtt as _, panic_probe as _};
+
+const NAME: &str = "USB_HID_POLL";
+
+/// Reports written before timing starts, while the host settles
+const WARMUP: u32 = 50;
+/// Reports in the timed window
+const WINDOW: u32 = 500;
+/// Accepted window length: one report per 1 ms frame, 10 % slack for missed frames
+const WINDOW_MIN_MS: u64 = WINDOW as u64 * 95 / 100;
+const WINDOW_MAX_MS: u64 = WINDOW as u64 * 110 / 100;
+/// After the window, stop once the host has not polled for this long
+const IDLE_MS: u64 = 200;
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_ht32f523xx::init(embassy_ht32f523xx::Config::default());
+
+    let driver = Driver::new(p.usb, UsbConfig::default());
+
+    let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("HT32 HIL mouse");
+    config.serial_number = Some("hil-tests");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+
+    static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+    static STATE: StaticCell<State> = StaticCell::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        CONFIG_DESCRIPTOR.init([0; 256]),
+        BOS_DESCRIPTOR.init([0; 256]),
+        &mut [],
+        CONTROL_BUF.init([0; 64]),
+    );
+    let mut mouse = HidWriter::<_, MOUSE_REPORT_SIZE>::new(
+        &mut builder,
+        STATE.init(State::new()),
+        HidConfig {
+            report_descriptor: MOUSE_DESCRIPTOR,
+            request_handler: None,
+            poll_ms: 1,
+            max_packet_size: 8,
+        },
+    );
+    let mut usb = builder.build();
+
+    let test = async {
+        ready(NAME);
+
+        let result: TestResult = async {
+            let mut report = MouseReport { x: 1, ..MouseReport::default() };
+            let mut send = async || {
+                report.x = -report.x;
+                mouse.write(&report.to_bytes()).await.map_err(|_| "report write failed")
+            };
+
+            with_timeout(Duration::from_secs(20), mouse.ready())
+                .await
+                .map_err(|_| "host never configured the device")?;
+            with_timeout(Duration::from_secs(20), send())
+                .await
+                .map_err(|_| "host never polled the endpoint")??;
+            for _ in 1..WARMUP {
+                send().await?;
+            }
+
+            let start = Instant::now();
+            for _ in 0..WINDOW {
+                send().await?;
+            }
+            let elapsed = start.elapsed().as_millis();
+            defmt::info!("{} reports in {} ms", WINDOW, elapsed);
+
+            // Keep the host's reads going until it closes the device
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while Instant::now() < deadline {
+                if with_timeout(Duration::from_millis(IDLE_MS), send()).await.is_err() {
+                    break;
+                }
+            }
+
+            check(elapsed >= WINDOW_MIN_MS, "reports completed faster than the host polls")?;
+            check(elapsed <= WINDOW_MAX_MS, "reports missed polling frames")
+        }
+        .await;
+
+        Timer::after_millis(100).await;
+        finish(NAME, result)
+    };
+
+    join(usb.run(), test).await;
+}
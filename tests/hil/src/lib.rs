@@ -0,0 +1,38 @@
+//! Shared helpers for the hardware-in-the-loop tests
+//!
+//! Each binary in `src/bin` is one test. It reports its result with a single
+//! defmt marker line that `cargo xtask hil` looks for:
+//!
+//! - `TEST_<NAME>_READY`: firmware side is up, host-side checks may start
+//! - `TEST_<NAME>_OK`: the test passed
+//! - `TEST_<NAME>_FAILED: <reason>`: the test failed
+//!
+//! `<NAME>` is the binary name in upper case. After reporting, the core halts
+//! on a breakpoint so `probe-rs run` exits.
+
+#![no_std]
+
+/// Test outcome; the error is a short reason for the log
+pub type TestResult = Result<(), &'static str>;
+
+/// Fail with `reason` unless `condition` holds
+pub fn check(condition: bool, reason: &'static str) -> TestResult {
+    if condition { Ok(()) } else { Err(reason) }
+}
+
+/// Tell the runner that host-side checks can start
+pub fn ready(name: &str) {
+    defmt::info!("TEST_{=str}_READY", name);
+}
+
+/// Report the result and halt
+pub fn finish(name: &str, result: TestResult) -> ! {
+    match result {
+        Ok(()) => defmt::info!("TEST_{=str}_OK", name),
+        Err(reason) => defmt::error!("TEST_{=str}_FAILED: {=str}", name, reason),
+    }
+
+    loop {
+        cortex_m::asm::bkpt();
+    }
+}
@@ -0,0 +1,179 @@
+//! On-target unit tests
+//!
+//! Register math and helpers that the compile-time checks cannot cover,
+//! because they only mean something against the real peripherals. Run with
+//! `cargo test -p hil-tests --test unit`; probe-rs flashes the binary and
+//! defmt-test reports each test. No wiring is needed.
+//!
+//! The GPIO tests drive PA0, PA1, PB0 and PC1 as outputs, so nothing may be
+//! connected to them. The USB tests use EP_SRAM before the driver exists.
+
+#![no_std]
+#![no_main]
+
+use {defmt_rtt as _, panic_probe as _};
+
+#[defmt_test::tests]
+mod tests {
+    use defmt::{assert, assert_eq};
+    use embassy_ht32f523xx::gpio::{self, AnyPin, Level, PinGroup, Pull};
+    use embassy_ht32f523xx::rcc::{self, pll};
+    use embassy_ht32f523xx::regs::Mmio;
+    use embassy_ht32f523xx::usb::sram;
+    use embassy_ht32f523xx::{pac, Peripherals};
+
+    /// HSI frequency, the PLL input with the default clock config
+    const HSI_HZ: u32 = 8_000_000;
+    /// EP_SRAM scratch area, past the control endpoint buffers
+    const SCRATCH: u16 = 0x200;
+
+    /// Byte buffer on a word boundary, to pick aligned or unaligned slices from
+    #[repr(align(4))]
+    struct Aligned([u8; 20]);
+
+    #[init]
+    fn init() -> Peripherals {
+        let p = embassy_ht32f523xx::init(embassy_ht32f523xx::Config::default());
+        let ckcu = unsafe { &*pac::Ckcu::ptr() };
+        ckcu.ahbccr().modify(|_, w| w.usben().set_bit());
+        p
+    }
+
+    /// Copy every word with the CPU, as the driver does without `Config::dma`
+    fn no_dma(_: *const u8, _: *mut u8, _: usize) -> bool {
+        false
+    }
+
+    fn gpioa() -> &'static pac::gpioa::RegisterBlock {
+        unsafe { &*pac::Gpioa::ptr() }
+    }
+
+    #[test]
+    fn output_sets_direction_and_level() {
+        let mut pin = AnyPin::new('A', 0);
+        pin.set_as_output(Level::High);
+        assert_eq!(gpioa().dircr().read().bits() & 1, 1);
+        assert_eq!(gpioa().doutr().read().bits() & 1, 1);
+
+        pin.set_as_input(Pull::Up);
+        assert_eq!(gpioa().dircr().read().bits() & 1, 0);
+        assert_eq!(gpioa().pur().read().bits() & 1, 1);
+        pin.set_as_input(Pull::None);
+    }
+
+    #[test]
+    fn pin_group_writes_each_port() {
+        let gpiob = unsafe { &*pac::Gpiob::ptr() };
+        let gpioc = unsafe { &*pac::Gpioc::ptr() };
+        let mut group = PinGroup::new([
+            AnyPin::new('A', 0),
+            AnyPin::new('B', 0),
+            AnyPin::new('A', 1),
+            AnyPin::new('C', 1),
+        ]);
+        group.set_as_outputs(Level::Low);
+
+        group.write(0b0101);
+        assert_eq!(gpioa().doutr().read().bits() & 0b11, 0b11);
+        assert_eq!(gpiob.doutr().read().bits() & 0b1, 0);
+        assert_eq!(gpioc.doutr().read().bits() & 0b10, 0);
+
+        group.write(0b1010);
+        assert_eq!(gpioa().doutr().read().bits() & 0b11, 0);
+        assert_eq!(gpiob.doutr().read().bits() & 0b1, 0b1);
+        assert_eq!(gpioc.doutr().read().bits() & 0b10, 0b10);
+
+        group.set_low(3);
+        assert_eq!(gpioc.doutr().read().bits() & 0b10, 0);
+        group.set_as_inputs(Pull::None);
+    }
+
+    #[test]
+    fn alternate_function_fields(p: &mut Peripherals) {
+        let afio = unsafe { &*pac::Afio::ptr() };
+
+        // PA9 parked (analog, AF2), then PA10 switched to AF6 in the same register
+        gpio::park_unused(&[AnyPin::new('A', 9)]);
+        let _pa10 = p.gpioa.pa10().into_alternate_function::<6>();
+        let cfghr = afio.gpacfghr().read().bits();
+        assert_eq!((cfghr >> 4) & 0xF, 2);
+        assert_eq!((cfghr >> 8) & 0xF, 6);
+        assert_eq!(gpioa().iner().read().bits() & (1 << 9), 0);
+        assert_eq!(gpioa().dircr().read().bits() & (1 << 9), 0);
+    }
+
+    #[test]
+    fn sram_byte_order() {
+        sram::write(&Mmio, SCRATCH, &[1, 2, 3, 4, 5, 6, 7, 8], no_dma);
+        assert_eq!(sram::read_word(&Mmio, SCRATCH as usize), 0x0403_0201);
+        assert_eq!(sram::read_word(&Mmio, SCRATCH as usize + 4), 0x0807_0605);
+    }
+
+    #[test]
+    fn sram_unaligned_reads() {
+        let pattern: [u8; 32] = core::array::from_fn(|i| i as u8 ^ 0xA5);
+        sram::write(&Mmio, SCRATCH, &pattern, no_dma);
+
+        // Every start offset and length, into aligned and unaligned buffers
+        let mut storage = Aligned([0; 20]);
+        for skip in 0..4 {
+            for len in 0..=13 {
+                for shift in 0..2 {
+                    let buf = &mut storage.0[shift..shift + len];
+                    sram::read(&Mmio, SCRATCH + skip as u16, buf, no_dma);
+                    assert_eq!(&*buf, &pattern[skip..skip + len]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sram_writes_from_unaligned_source() {
+        let storage = Aligned(core::array::from_fn(|i| (i as u8).wrapping_mul(29)));
+        let data = &storage.0[1..17];
+        sram::write(&Mmio, SCRATCH, data, no_dma);
+        let mut readback = [0u8; 16];
+        sram::read(&Mmio, SCRATCH, &mut readback, no_dma);
+        assert_eq!(&readback, data);
+    }
+
+    #[test]
+    fn sram_tail_write_zero_fills() {
+        sram::write_word(&Mmio, SCRATCH as usize + 4, 0xFFFF_FFFF);
+        sram::write(&Mmio, SCRATCH, &[0xAA; 5], no_dma);
+        assert_eq!(sram::read_word(&Mmio, SCRATCH as usize), 0xAAAA_AAAA);
+        assert_eq!(sram::read_word(&Mmio, SCRATCH as usize + 4), 0x0000_00AA);
+    }
+
+    #[test]
+    fn pll_matches_running_clock() {
+        let ckcu = unsafe { &*pac::Ckcu::ptr() };
+        let params = pll::calculate(HSI_HZ, 48_000_000, pll::SYS_CLK_MAX).unwrap();
+
+        let pllcfgr = ckcu.pllcfgr().read();
+        assert_eq!(pllcfgr.pfbd().bits(), params.pfbd());
+        assert_eq!(pllcfgr.potd().bits(), params.potd());
+        assert!(ckcu.gcsr().read().pllrdy().bit_is_set());
+        assert_eq!(ckcu.gccr().read().sw().bits(), 2);
+        let clocks = rcc::get_clocks();
+        assert_eq!(clocks.sys_clk().to_hz(), 48_000_000);
+        assert_eq!(clocks.pll_clk().map(|f| f.to_hz()), Some(params.output(HSI_HZ)));
+    }
+
+    #[test]
+    fn pll_runtime_matches_const() {
+        // The const checks in `rcc::pll` run in the compiler; this runs the same
+        // code as compiled for the M0+
+        for mhz in 4..=16 {
+            let input = core::hint::black_box(mhz * 1_000_000);
+            for target in [24_000_000, 32_000_000, 36_000_000, 48_000_000] {
+                if let Some(params) = pll::calculate(input, core::hint::black_box(target), pll::SYS_CLK_MAX) {
+                    assert!(params.is_valid(input, pll::SYS_CLK_MAX));
+                    assert!(params.output(input) <= pll::SYS_CLK_MAX);
+                }
+            }
+        }
+        let too_fast = core::hint::black_box(pll::IN_MAX + 1);
+        assert!(pll::calculate(too_fast, 48_000_000, pll::SYS_CLK_MAX).is_none());
+    }
+}
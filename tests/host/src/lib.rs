@@ -0,0 +1,4 @@
+//! Host-side tests of the HAL's register-free logic
+//!
+//! The tests live in `tests/`; they run the driver code against
+//! `regs::MockRegisters` instead of the chip. Run them with `cargo test-host`.
@@ -0,0 +1,92 @@
+//! 64-bit extension of the 16-bit GPTM count
+//!
+//! The time driver builds `embassy-time`'s 64-bit clock out of a counter
+//! that wraps every 65.5 ms. A lost or doubled wrap shifts every deadline
+//! after it, so these tests walk the extension across many wraps, at the
+//! edges of the period and across a counter restart.
+
+use embassy_ht32f523xx::regs::{MockRegisters, RegisterAccess};
+use embassy_ht32f523xx::timer::{COUNTER_BITS, ExtendedCounter, extend_counter};
+
+/// Counter register of the mock timer; any word address will do
+const CNTR: usize = 0x4006_E080;
+
+const PERIOD: u64 = 1 << COUNTER_BITS;
+
+#[test]
+fn extend_without_wrap() {
+    assert_eq!(extend_counter(0, 0, COUNTER_BITS), 0);
+    assert_eq!(extend_counter(0, 1234, COUNTER_BITS), 1234);
+    assert_eq!(extend_counter(1234, 1234, COUNTER_BITS), 1234);
+    assert_eq!(extend_counter(3 * PERIOD + 10, 0xFFFF, COUNTER_BITS), 3 * PERIOD + 0xFFFF);
+}
+
+#[test]
+fn extend_across_wrap() {
+    assert_eq!(extend_counter(0xFFFF, 0, COUNTER_BITS), PERIOD);
+    assert_eq!(extend_counter(0xFFF0, 0x0010, COUNTER_BITS), PERIOD + 0x10);
+    assert_eq!(extend_counter(5 * PERIOD + 0x8000, 0x7FFF, COUNTER_BITS), 6 * PERIOD + 0x7FFF);
+    // The longest gap that still extends correctly: one tick short of a period
+    assert_eq!(extend_counter(0x1000, 0x0FFF, COUNTER_BITS), PERIOD + 0x0FFF);
+}
+
+#[test]
+fn extend_ignores_bits_above_the_counter() {
+    assert_eq!(extend_counter(0, 0xABCD_1234, COUNTER_BITS), 0x1234);
+    assert_eq!(extend_counter(0, 0x1_0005, 16), 5);
+    assert_eq!(extend_counter(0xFF, 0x0F, 8), 0x10F);
+}
+
+#[test]
+fn extend_at_the_top_of_the_range() {
+    let last = u64::MAX - PERIOD - 0xFF;
+    assert_eq!(extend_counter(last, 0xFFFF, COUNTER_BITS), (last & !(PERIOD - 1)) + 0xFFFF);
+}
+
+#[test]
+fn every_step_size_over_many_wraps() {
+    // Reads at any spacing below one period track a reference count exactly
+    for step in [1, 7, 255, 0x7FFF, 0x8000, 0xC001, 0xFFFF] {
+        let mut expected = 0u64;
+        let mut last = 0u64;
+        for _ in 0..1000 {
+            expected += step;
+            last = extend_counter(last, expected as u32, COUNTER_BITS);
+            assert_eq!(last, expected, "step {step}");
+        }
+    }
+}
+
+#[test]
+fn counter_reads_the_register() {
+    let regs = MockRegisters::<1>::new(CNTR);
+    let counter = ExtendedCounter::new();
+
+    let mut expected = 0u64;
+    for step in [100u64, 0xFF00, 0x200, 0x8000, 0x8000, 0xFFFF, 1] {
+        expected += step;
+        regs.write(CNTR, (expected % PERIOD) as u32);
+        assert_eq!(counter.read(&regs, CNTR), expected);
+    }
+}
+
+#[test]
+fn restart_continues_the_count() {
+    let regs = MockRegisters::<1>::new(CNTR);
+    let counter = ExtendedCounter::new();
+
+    regs.write(CNTR, 0xFFF0);
+    counter.read(&regs, CNTR);
+    regs.write(CNTR, 0x0040);
+    let before = counter.read(&regs, CNTR);
+    assert_eq!(before, PERIOD + 0x40);
+
+    // Counter reset, e.g. by an update event loading a new prescaler
+    regs.write(CNTR, 0);
+    counter.restart(before);
+    assert_eq!(counter.read(&regs, CNTR), before);
+    regs.write(CNTR, 0x0010);
+    assert_eq!(counter.read(&regs, CNTR), before + 0x10);
+    regs.write(CNTR, 0x0005);
+    assert_eq!(counter.read(&regs, CNTR), before + PERIOD + 0x05);
+}
@@ -0,0 +1,90 @@
+//! EP_SRAM buffer allocation
+//!
+//! Overlapping endpoint buffers do not fail loudly on the chip: the device
+//! enumerates and then corrupts one endpoint's packets with another's. These
+//! tests check the allocator against every allocation order over a spread of
+//! packet sizes.
+
+use embassy_ht32f523xx::usb::sram::{Allocator, EP_BUF_START, EP_SRAM_SIZE, MAX_PACKET_SIZE};
+
+/// Configurable endpoints, EP1 to EP7
+const ENDPOINTS: usize = 7;
+
+fn round_up(len: u16) -> usize {
+    (len as usize).div_ceil(4) * 4
+}
+
+/// Allocate `sizes` in order, checking each buffer against the layout and the others
+///
+/// Returns how many allocations succeeded.
+fn check_sequence(sizes: &[u16]) -> usize {
+    let mut alloc = Allocator::new();
+    let mut buffers: Vec<(usize, usize)> = Vec::new();
+
+    for &len in sizes {
+        let rounded = round_up(len);
+        let Some(offset) = alloc.alloc(len) else {
+            assert!(
+                alloc.remaining() < rounded,
+                "{sizes:?}: {len} bytes refused with {} left",
+                alloc.remaining()
+            );
+            continue;
+        };
+
+        let start = offset as usize;
+        assert_eq!(start % 4, 0, "{sizes:?}: buffer at {start:#x} not word aligned");
+        assert!(start >= EP_BUF_START as usize, "{sizes:?}: buffer at {start:#x} overlaps EP0");
+        assert!(start + rounded <= EP_SRAM_SIZE, "{sizes:?}: buffer at {start:#x} ends past EP_SRAM");
+        for &(other, other_len) in &buffers {
+            assert!(
+                start + rounded <= other || other + other_len <= start,
+                "{sizes:?}: buffers at {start:#x} and {other:#x} overlap"
+            );
+        }
+        buffers.push((start, rounded));
+    }
+
+    let used: usize = buffers.iter().map(|&(_, len)| len).sum();
+    assert_eq!(alloc.remaining(), EP_SRAM_SIZE - EP_BUF_START as usize - used, "{sizes:?}");
+    buffers.len()
+}
+
+#[test]
+fn every_packet_size_alone() {
+    for len in 0..=MAX_PACKET_SIZE as u16 {
+        assert_eq!(check_sequence(&[len]), 1);
+    }
+}
+
+#[test]
+fn every_order_of_mixed_sizes() {
+    // Each endpoint takes one of these sizes: 6^7 orders, including repeats
+    const SIZES: [u16; 6] = [1, 8, 17, 32, 63, 64];
+
+    let mut sequence = [0u16; ENDPOINTS];
+    for mut n in 0..SIZES.len().pow(ENDPOINTS as u32) {
+        for slot in &mut sequence {
+            *slot = SIZES[n % SIZES.len()];
+            n /= SIZES.len();
+        }
+        check_sequence(&sequence);
+    }
+}
+
+#[test]
+fn every_pair_of_packet_sizes() {
+    for a in 0..=MAX_PACKET_SIZE as u16 {
+        for b in 0..=MAX_PACKET_SIZE as u16 {
+            check_sequence(&[a, b, a, b, a, b, a]);
+        }
+    }
+}
+
+#[test]
+fn exhaustion_refuses_without_overlap() {
+    // 888 bytes after EP0: thirteen 64-byte buffers, then only smaller ones fit
+    let mut sizes = vec![64; 14];
+    sizes.extend([56, 4]);
+    assert_eq!(check_sequence(&sizes), 14);
+}
@@ -0,0 +1,100 @@
+//! PLL divider search
+//!
+//! `rcc::pll::calculate` picks the feedback and output dividers for every
+//! clock configuration. Its compile-time checks cover whole-MHz inputs; these
+//! tests compare it against a brute-force search over a finer grid, including
+//! targets that cannot be hit exactly and the edges of the input range.
+
+use embassy_ht32f523xx::rcc::pll::{self, IN_MAX, IN_MIN, Params, SYS_CLK_MAX, VCO_MAX, VCO_MIN};
+
+/// Every valid setting for `input`, by brute force
+fn valid(input: u32, max_output: u32) -> Vec<Params> {
+    let mut all = Vec::new();
+    for nf2 in 1..=16 {
+        for no2_log2 in 0..=3 {
+            let params = Params { nf2, no2_log2 };
+            let vco = input as u64 * nf2 as u64;
+            let ok = (VCO_MIN as u64..=VCO_MAX as u64).contains(&vco) && (vco >> no2_log2) <= max_output as u64;
+            assert_eq!(params.is_valid(input, max_output), ok, "{input} Hz, {params:?}");
+            if ok {
+                all.push(params);
+            }
+        }
+    }
+    all
+}
+
+fn check(input: u32, target: u32, max_output: u32) {
+    let found = pll::calculate(input, target, max_output);
+    let candidates = valid(input, max_output);
+    let Some(best) = candidates.iter().map(|p| p.output(input).abs_diff(target)).min() else {
+        assert_eq!(found, None, "{input} Hz -> {target} Hz");
+        return;
+    };
+
+    let params = found.unwrap_or_else(|| panic!("{input} Hz -> {target} Hz: no setting found"));
+    assert!(params.is_valid(input, max_output), "{input} Hz -> {target} Hz: {params:?}");
+    assert_eq!(params.output(input).abs_diff(target), best, "{input} Hz -> {target} Hz: {params:?}");
+    // Ties go to the lower VCO frequency
+    let lowest_vco = candidates
+        .iter()
+        .filter(|p| p.output(input).abs_diff(target) == best)
+        .map(|p| p.vco(input))
+        .min();
+    assert_eq!(Some(params.vco(input)), lowest_vco, "{input} Hz -> {target} Hz: {params:?}");
+}
+
+#[test]
+fn known_settings() {
+    for (input, nf2) in [(8_000_000, 6), (12_000_000, 4), (16_000_000, 3)] {
+        let params = pll::calculate(input, 48_000_000, SYS_CLK_MAX).unwrap();
+        assert_eq!(params, Params { nf2, no2_log2: 0 });
+        assert_eq!(params.output(input), 48_000_000);
+    }
+}
+
+#[test]
+fn register_fields() {
+    let params = Params { nf2: 16, no2_log2: 3 };
+    assert_eq!(params.pfbd(), 0, "NF2 = 16 is encoded as 0");
+    assert_eq!(params.potd(), 3);
+    assert_eq!(Params { nf2: 6, no2_log2: 1 }.pfbd(), 6);
+}
+
+#[test]
+fn inputs_outside_the_range() {
+    for input in [0, 1_000_000, IN_MIN - 1, IN_MAX + 1, 25_000_000] {
+        assert_eq!(pll::calculate(input, 48_000_000, SYS_CLK_MAX), None, "{input} Hz");
+    }
+}
+
+#[test]
+fn closest_output_for_every_input_and_target() {
+    // 250 kHz input steps, 1 MHz target steps up to past the limit
+    for input in (IN_MIN..=IN_MAX).step_by(250_000) {
+        for target in (1_000_000..=60_000_000).step_by(1_000_000) {
+            check(input, target, SYS_CLK_MAX);
+        }
+    }
+}
+
+#[test]
+fn odd_inputs_and_targets() {
+    for input in [IN_MIN, 4_194_304, 7_372_800, 11_059_200, 14_745_600, IN_MAX] {
+        for target in [0, 1, 12_345_678, 24_000_000, 36_864_000, 47_999_999, 48_000_000, u32::MAX] {
+            check(input, target, SYS_CLK_MAX);
+        }
+    }
+}
+
+#[test]
+fn lower_output_limit() {
+    for input in (IN_MIN..=IN_MAX).step_by(1_000_000) {
+        for max_output in [6_000_000, 12_000_000, 24_000_000, 40_000_000] {
+            check(input, 48_000_000, max_output);
+            if let Some(params) = pll::calculate(input, 48_000_000, max_output) {
+                assert!(params.output(input) <= max_output);
+            }
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! EP_SRAM byte access
+//!
+//! EP_SRAM only takes word accesses, so packet copies are split into an
+//! unaligned head, whole words and a tail. These tests check the byte order
+//! of that split against every offset, length and buffer alignment, using an
+//! in-memory EP_SRAM.
+
+use std::cell::Cell;
+
+use embassy_ht32f523xx::regs::MockRegisters;
+use embassy_ht32f523xx::usb::sram::{self, EP_BUF_START, EP_SRAM_BASE, EP_SRAM_SIZE, MAX_PACKET_SIZE};
+
+type Sram = MockRegisters<{ EP_SRAM_SIZE / 4 }>;
+
+/// Byte buffer on a word boundary, to pick aligned or unaligned slices from
+#[repr(align(4))]
+struct Aligned([u8; MAX_PACKET_SIZE + 8]);
+
+fn no_dma(_: *const u8, _: *mut u8, _: usize) -> bool {
+    false
+}
+
+fn round_up(len: u16) -> usize {
+    (len as usize).div_ceil(4) * 4
+}
+
+#[test]
+fn word_byte_order() {
+    assert_eq!(sram::word_to_bytes(0x0403_0201), [1, 2, 3, 4]);
+    assert_eq!(sram::bytes_to_word(&[1, 2, 3, 4]), 0x0403_0201);
+    assert_eq!(sram::bytes_to_word(&[1, 2]), 0x0000_0201);
+    assert_eq!(sram::bytes_to_word(&[]), 0);
+}
+
+#[test]
+fn read_every_offset_length_and_alignment() {
+    let regs = Sram::new(EP_SRAM_BASE);
+    let pattern: Vec<u8> = (0..EP_SRAM_SIZE).map(|i| (i as u8).wrapping_mul(31) ^ 0x5A).collect();
+    for (i, chunk) in pattern.chunks_exact(4).enumerate() {
+        sram::write_word(&regs, i * 4, sram::bytes_to_word(chunk));
+    }
+
+    let mut storage = Aligned([0; MAX_PACKET_SIZE + 8]);
+    for offset in EP_BUF_START as usize..EP_BUF_START as usize + 4 {
+        for len in 0..=MAX_PACKET_SIZE {
+            for shift in 0..4 {
+                let buf = &mut storage.0[shift..shift + len];
+                sram::read(&regs, offset as u16, buf, no_dma);
+                let expected = &pattern[offset..offset + len];
+                assert_eq!(buf, expected, "offset {offset:#x}, len {len}, shift {shift}");
+            }
+        }
+    }
+}
+
+#[test]
+fn write_every_length_and_alignment() {
+    let regs = Sram::new(EP_SRAM_BASE);
+    let mut storage = Aligned([0; MAX_PACKET_SIZE + 8]);
+    for (i, byte) in storage.0.iter_mut().enumerate() {
+        *byte = (i as u8).wrapping_mul(29) | 1;
+    }
+
+    for len in 0..=MAX_PACKET_SIZE {
+        for shift in 0..4 {
+            // Poison the area, including the word after the packet
+            for i in 0..MAX_PACKET_SIZE / 4 + 1 {
+                sram::write_word(&regs, EP_BUF_START as usize + i * 4, 0xFFFF_FFFF);
+            }
+
+            let data = &storage.0[shift..shift + len];
+            sram::write(&regs, EP_BUF_START, data, no_dma);
+
+            let mut readback = vec![0u8; round_up(len as u16)];
+            sram::read(&regs, EP_BUF_START, &mut readback, no_dma);
+            assert_eq!(&readback[..len], data, "len {len}, shift {shift}");
+            assert!(readback[len..].iter().all(|&b| b == 0), "len {len}: partial word not zero filled");
+            let after = sram::read_word(&regs, EP_BUF_START as usize + round_up(len as u16));
+            assert_eq!(after, 0xFFFF_FFFF, "len {len}, shift {shift}: wrote past the packet");
+        }
+    }
+}
+
+#[test]
+fn bulk_copy_gets_only_the_aligned_middle() {
+    let regs = Sram::new(EP_SRAM_BASE);
+    let mut storage = Aligned([0; MAX_PACKET_SIZE + 8]);
+    let calls = Cell::new(None);
+    let record = |src: *const u8, dst: *mut u8, words: usize| {
+        calls.set(Some((src as usize, dst as usize, words)));
+        true
+    };
+
+    // Aligned on both sides: the whole packet is one bulk copy, no word reads
+    let buf = &mut storage.0[..20];
+    let dst = buf.as_ptr() as usize;
+    sram::read(&regs, EP_BUF_START, buf, record);
+    assert_eq!(calls.take(), Some((EP_SRAM_BASE + EP_BUF_START as usize, dst, 5)));
+
+    // Offset by one: a 3-byte head, then the rest lands unaligned in `buf`
+    sram::read(&regs, EP_BUF_START + 1, &mut storage.0[..20], record);
+    assert_eq!(calls.take(), None);
+
+    // Unaligned source: copied by the CPU
+    sram::write(&regs, EP_BUF_START, &storage.0[1..21], record);
+    assert_eq!(calls.take(), None);
+
+    // Aligned source with a tail: bulk for the words, the tail by the CPU
+    storage.0[16..20].copy_from_slice(&[9, 8, 7, 6]);
+    sram::write(&regs, EP_BUF_START, &storage.0[..19], record);
+    assert_eq!(calls.take(), Some((storage.0.as_ptr() as usize, EP_SRAM_BASE + EP_BUF_START as usize, 4)));
+    assert_eq!(sram::read_word(&regs, EP_BUF_START as usize + 16), 0x0007_0809);
+}
@@ -0,0 +1,522 @@
+//! Development tasks for embassy-ht32f523xx
+//!
+//! `cargo xtask hil` builds the firmware in `tests/hil`, flashes each test
+//! with `probe-rs run`, watches the defmt output for the `TEST_<NAME>_OK` /
+//! `TEST_<NAME>_FAILED` markers, runs host-side USB checks where a test asks
+//! for them, and prints a summary. The exit status is non-zero if any test
+//! did not pass.
+//!
+//! `cargo xtask stamp <elf>` fills in the firmware's `fw_info` block (git
+//! hash, build time, image length, CRC) after a build.
+//!
+//! `cargo xtask trace <capture>` summarizes a `task-trace` capture per task
+//! and interrupt and can export the timeline for Perfetto.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitCode, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod stamp;
+mod trace;
+
+const USAGE: &str = "\
+Usage: cargo xtask hil [OPTIONS] [TEST...]
+
+Flash and run the hardware-in-the-loop tests in tests/hil.
+
+Options:
+    --chip <CHIP>       Target chip passed to probe-rs [default: HT32F52352]
+    --probe <SELECTOR>  probe-rs probe selector (VID:PID[:SERIAL])
+    --usb               Run tests that need host-side USB checks
+    --port <PATH>       Serial port of the CDC-ACM test device [default: first ttyACM/usbmodem]
+    --timeout <SECS>    Per-test timeout [default: 30]
+    --verbose           Echo the probe-rs output
+    --list              List the tests and exit
+";
+
+/// USB VID:PID used by the USB tests
+const TEST_VID_PID: &str = "c0de:cafe";
+/// Bytes sent through the CDC-ACM loopback; must match the firmware
+const LOOPBACK_LEN: usize = 256;
+/// Mouse reports the HID polling check reads; more than the firmware's warm-up and timed window
+const HID_POLL_REPORTS: usize = 1000;
+/// Size of one mouse report; must match `hid::MOUSE_REPORT_SIZE`
+const HID_REPORT_LEN: usize = 7;
+/// Firmware target directory, following `.cargo/config.toml`
+const TARGET: &str = "thumbv6m-none-eabi";
+
+/// Checks run on the host once the firmware reports `READY`
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum HostCheck {
+    None,
+    /// Device enumerates and echoes data on its CDC-ACM port
+    UsbSerial,
+    /// Linux `usbtest` chapter 9 and halt tests pass against the source/sink device
+    UsbTest,
+    /// Reports arrive on the device's Linux hidraw node at the 1 ms polling rate
+    HidPoll,
+}
+
+struct TestSpec {
+    /// Binary name in tests/hil/src/bin
+    name: &'static str,
+    host: HostCheck,
+    /// Wiring or setup the test needs
+    setup: &'static str,
+}
+
+const TESTS: &[TestSpec] = &[
+    TestSpec { name: "gpio_loopback", host: HostCheck::None, setup: "jumper PA0 to PA1" },
+    TestSpec { name: "time_accuracy", host: HostCheck::None, setup: "" },
+    TestSpec { name: "flash_roundtrip", host: HostCheck::None, setup: "erases the last flash page" },
+    TestSpec { name: "cancel_safety", host: HostCheck::None, setup: "jumper PB4 to PB5 (SPI0 MOSI-MISO)" },
+    TestSpec { name: "spi_max_clock", host: HostCheck::None, setup: "jumper PB4 to PB5 (SPI0 MOSI-MISO)" },
+    TestSpec { name: "usb_cdc_loopback", host: HostCheck::UsbSerial, setup: "USB cable to this host, --usb" },
+    TestSpec {
+        name: "usb_halt",
+        host: HostCheck::UsbTest,
+        setup: "USB cable to a Linux host with usbtest and testusb, run as root, --usb",
+    },
+    TestSpec {
+        name: "usb_hid_poll",
+        host: HostCheck::HidPoll,
+        setup: "USB cable to a Linux host, hidraw readable (root or udev rule), --usb",
+    },
+];
+
+/// `testusb` cases run by the halt test: 9 = chapter 9 requests, 13 = set/clear halt
+const USBTEST_CASES: &[u32] = &[9, 13];
+/// Gadget zero's VID:PID, whose usbtest settings (autoconf bulk source/sink) the test device borrows
+const USBTEST_REFERENCE: &str = "0525 a4a0";
+
+struct Options {
+    chip: String,
+    probe: Option<String>,
+    usb: bool,
+    port: Option<PathBuf>,
+    timeout: Duration,
+    verbose: bool,
+    filter: Vec<String>,
+}
+
+enum Outcome {
+    Passed,
+    Failed(String),
+    Skipped(&'static str),
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("hil") => match parse_options(&args[1..]) {
+            Ok(Some(options)) => hil(&options),
+            Ok(None) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("error: {e}\n\n{USAGE}");
+                ExitCode::FAILURE
+            }
+        },
+        Some("stamp") => match stamp::run(&args[1..]) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("error: {e}\n\n{}", stamp::USAGE);
+                ExitCode::FAILURE
+            }
+        },
+        Some("trace") => match trace::run(&args[1..]) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("error: {e}\n\n{}", trace::USAGE);
+                ExitCode::FAILURE
+            }
+        },
+        _ => {
+            eprintln!("{USAGE}\n{}\n{}", stamp::USAGE, trace::USAGE);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Parse `hil` options; `Ok(None)` means the request was handled (e.g. `--list`)
+fn parse_options(args: &[String]) -> Result<Option<Options>, String> {
+    let mut options = Options {
+        chip: "HT32F52352".into(),
+        probe: None,
+        usb: false,
+        port: None,
+        timeout: Duration::from_secs(30),
+        verbose: false,
+        filter: Vec::new(),
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().cloned().ok_or(format!("{name} needs a value"));
+        match arg.as_str() {
+            "--chip" => options.chip = value("--chip")?,
+            "--probe" => options.probe = Some(value("--probe")?),
+            "--usb" => options.usb = true,
+            "--port" => options.port = Some(value("--port")?.into()),
+            "--timeout" => {
+                let secs = value("--timeout")?;
+                let secs = secs.parse().map_err(|_| format!("invalid timeout `{secs}`"))?;
+                options.timeout = Duration::from_secs(secs);
+            }
+            "--verbose" => options.verbose = true,
+            "--list" => {
+                for test in TESTS {
+                    println!("{:<20} {}", test.name, test.setup);
+                }
+                return Ok(None);
+            }
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(None);
+            }
+            name if !name.starts_with('-') => {
+                if !TESTS.iter().any(|t| t.name == name) {
+                    return Err(format!("unknown test `{name}` (see --list)"));
+                }
+                options.filter.push(name.into());
+            }
+            other => return Err(format!("unknown option `{other}`")),
+        }
+    }
+
+    Ok(Some(options))
+}
+
+fn workspace_root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap().to_path_buf()
+}
+
+fn hil(options: &Options) -> ExitCode {
+    let root = workspace_root();
+
+    println!("Building tests/hil ...");
+    let status = Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".into()))
+        .current_dir(&root)
+        .args(["build", "--release", "-p", "hil-tests", "--bins"])
+        .status();
+    if !matches!(status, Ok(s) if s.success()) {
+        eprintln!("error: building the HIL firmware failed");
+        return ExitCode::FAILURE;
+    }
+
+    let selected = TESTS
+        .iter()
+        .filter(|t| options.filter.is_empty() || options.filter.iter().any(|f| f == t.name));
+
+    let mut results = Vec::new();
+    for test in selected {
+        let start = Instant::now();
+        let outcome = if test.host != HostCheck::None && !options.usb {
+            Outcome::Skipped("needs --usb")
+        } else {
+            println!("\n=== {} ===", test.name);
+            let elf = root.join("target").join(TARGET).join("release").join(test.name);
+            run_test(options, test, &elf)
+        };
+        results.push((test.name, outcome, start.elapsed()));
+    }
+
+    print_summary(&results)
+}
+
+/// Output from the probe-rs child, line by line
+enum Message {
+    Line(String),
+    Exited,
+    Host(Result<(), String>),
+}
+
+fn run_test(options: &Options, test: &TestSpec, elf: &Path) -> Outcome {
+    let marker = test.name.to_uppercase();
+    let ready = format!("TEST_{marker}_READY");
+    let ok = format!("TEST_{marker}_OK");
+    let failed = format!("TEST_{marker}_FAILED");
+
+    let mut command = Command::new("probe-rs");
+    command.args(["run", "--chip", &options.chip]);
+    if let Some(probe) = &options.probe {
+        command.args(["--probe", probe]);
+    }
+    command.arg(elf).stdout(Stdio::piped()).stderr(Stdio::piped());
+
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => return Outcome::Failed(format!("cannot start probe-rs: {e}")),
+    };
+
+    let (tx, rx) = mpsc::channel();
+    forward_lines(child.stdout.take().unwrap(), tx.clone());
+    forward_lines(child.stderr.take().unwrap(), tx.clone());
+
+    let deadline = Instant::now() + options.timeout;
+    let mut streams_open = 2;
+    let mut firmware: Option<Result<(), String>> = None;
+    let mut host: Option<Result<(), String>> = (test.host == HostCheck::None).then_some(Ok(()));
+
+    let outcome = loop {
+        if let (Some(firmware), Some(host)) = (&firmware, &host) {
+            break match (firmware, host) {
+                (Ok(()), Ok(())) => Outcome::Passed,
+                (Err(e), _) => Outcome::Failed(e.clone()),
+                (_, Err(e)) => Outcome::Failed(format!("host check: {e}")),
+            };
+        }
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(remaining) {
+            Ok(Message::Line(line)) => {
+                if options.verbose {
+                    println!("  {line}");
+                }
+                if line.contains(&ok) {
+                    firmware = Some(Ok(()));
+                } else if let Some(at) = line.find(&failed) {
+                    let reason = line[at + failed.len()..].trim_start_matches(':').trim();
+                    firmware = Some(Err(reason.to_string()));
+                } else if line.contains(&ready) && host.is_none() {
+                    spawn_host_check(options, test.host, tx.clone());
+                }
+            }
+            Ok(Message::Host(result)) => host = Some(result),
+            Ok(Message::Exited) => {
+                streams_open -= 1;
+                if streams_open == 0 && firmware.is_none() {
+                    break Outcome::Failed("probe-rs exited without a result marker".into());
+                }
+            }
+            Err(_) => {
+                break Outcome::Failed(format!("timed out after {}s", options.timeout.as_secs()));
+            }
+        }
+    };
+
+    let _ = child.kill();
+    let _ = child.wait();
+    outcome
+}
+
+fn forward_lines(stream: impl Read + Send + 'static, tx: mpsc::Sender<Message>) {
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines().map_while(Result::ok) {
+            if tx.send(Message::Line(line)).is_err() {
+                return;
+            }
+        }
+        let _ = tx.send(Message::Exited);
+    });
+}
+
+fn spawn_host_check(options: &Options, check: HostCheck, tx: mpsc::Sender<Message>) {
+    let port = options.port.clone();
+    thread::spawn(move || {
+        let result = match check {
+            HostCheck::None => Ok(()),
+            HostCheck::UsbSerial => usb_serial_check(port),
+            HostCheck::UsbTest => usbtest_check(),
+            HostCheck::HidPoll => hid_poll_check(),
+        };
+        let _ = tx.send(Message::Host(result));
+    });
+}
+
+/// Wait for the test device to enumerate, then check the CDC-ACM loopback
+fn usb_serial_check(port: Option<PathBuf>) -> Result<(), String> {
+    wait_for_device(Duration::from_secs(10))?;
+
+    let port = match port {
+        Some(port) => port,
+        None => find_serial_port().ok_or("no ttyACM/usbmodem serial port found, pass --port")?,
+    };
+    println!("  host: loopback on {}", port.display());
+
+    // Raw mode; reads return after 1 s without data instead of blocking forever
+    let flag = if cfg!(target_os = "macos") { "-f" } else { "-F" };
+    let status = Command::new("stty")
+        .arg(flag)
+        .arg(&port)
+        .args(["raw", "-echo", "min", "0", "time", "10"])
+        .status()
+        .map_err(|e| format!("stty: {e}"))?;
+    if !status.success() {
+        return Err(format!("stty failed on {}", port.display()));
+    }
+
+    let mut serial = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&port)
+        .map_err(|e| format!("open {}: {e}", port.display()))?;
+
+    let pattern: Vec<u8> = (0..LOOPBACK_LEN).map(|i| (i * 7 + 3) as u8).collect();
+    serial.write_all(&pattern).map_err(|e| format!("write: {e}"))?;
+
+    let mut echoed = vec![0; LOOPBACK_LEN];
+    let mut received = 0;
+    while received < LOOPBACK_LEN {
+        match serial.read(&mut echoed[received..]) {
+            Ok(0) => return Err(format!("echo stopped after {received} of {LOOPBACK_LEN} bytes")),
+            Ok(n) => received += n,
+            Err(e) => return Err(format!("read: {e}")),
+        }
+    }
+
+    if echoed == pattern {
+        Ok(())
+    } else {
+        Err("echoed data differs from what was sent".into())
+    }
+}
+
+/// Run the kernel's usbtest halt checks against the test device, then de-authorize it
+///
+/// De-authorizing deconfigures the device, which is the firmware's cue to
+/// report its own result.
+fn usbtest_check() -> Result<(), String> {
+    wait_for_device(Duration::from_secs(10))?;
+
+    let _ = Command::new("modprobe").arg("usbtest").status();
+    let (vid, pid) = TEST_VID_PID.split_once(':').unwrap();
+    // Fails with EEXIST once the ID is known, which is fine
+    let _ = std::fs::write(
+        "/sys/bus/usb/drivers/usbtest/new_id",
+        format!("{vid} {pid} 0 {USBTEST_REFERENCE}"),
+    );
+    thread::sleep(Duration::from_millis(500));
+
+    let device = find_sysfs_device(vid, pid).ok_or("test device not found in /sys/bus/usb/devices")?;
+    let result = USBTEST_CASES.iter().try_for_each(|case| {
+        println!("  host: testusb test {case}");
+        let output = Command::new("testusb")
+            .args(["-a", "-t", &case.to_string(), "-c", "10"])
+            .output()
+            .map_err(|e| format!("testusb: {e}"))?;
+        let text = String::from_utf8_lossy(&output.stdout);
+        // Failures print "test N --> <errno> (<reason>)"
+        if !output.status.success() || text.contains("-->") {
+            return Err(format!("testusb test {case} failed: {}", text.trim()));
+        }
+        Ok(())
+    });
+
+    std::fs::write(device.join("authorized"), "0").map_err(|e| format!("de-authorize device: {e}"))?;
+    result
+}
+
+/// Read mouse reports from the test device's hidraw node, which keeps the kernel polling it
+///
+/// The firmware times the reports; this only checks that they arrive whole
+/// and at about the polling rate. Closing the node stops the polling, which
+/// tells the firmware to report.
+fn hid_poll_check() -> Result<(), String> {
+    wait_for_device(Duration::from_secs(10))?;
+    thread::sleep(Duration::from_millis(500));
+
+    let node = find_hidraw().ok_or("no hidraw node for the test device")?;
+    println!("  host: reading {}", node.display());
+    let mut hidraw = std::fs::File::open(&node).map_err(|e| format!("open {}: {e}", node.display()))?;
+
+    let start = Instant::now();
+    let mut report = [0u8; 64];
+    for i in 0..HID_POLL_REPORTS {
+        let n = hidraw.read(&mut report).map_err(|e| format!("read: {e}"))?;
+        if n != HID_REPORT_LEN {
+            return Err(format!("report {i} is {n} bytes, expected {HID_REPORT_LEN}"));
+        }
+    }
+    let elapsed = start.elapsed();
+    println!("  host: {HID_POLL_REPORTS} reports in {} ms", elapsed.as_millis());
+
+    // Generous: the first reads may come from reports queued before the open
+    if elapsed > Duration::from_millis(HID_POLL_REPORTS as u64 * 3 / 2) {
+        return Err(format!("{HID_POLL_REPORTS} reports took {} ms", elapsed.as_millis()));
+    }
+    Ok(())
+}
+
+/// `/dev/hidrawN` of the test device
+fn find_hidraw() -> Option<PathBuf> {
+    let (vid, pid) = TEST_VID_PID.split_once(':').unwrap();
+    let id = format!("HID_ID=0003:0000{}:0000{}", vid.to_uppercase(), pid.to_uppercase());
+    std::fs::read_dir("/sys/class/hidraw")
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .find(|entry| {
+            std::fs::read_to_string(entry.path().join("device/uevent")).is_ok_and(|uevent| uevent.contains(&id))
+        })
+        .map(|entry| Path::new("/dev").join(entry.file_name()))
+}
+
+/// sysfs directory of the USB device with `vid`:`pid`
+fn find_sysfs_device(vid: &str, pid: &str) -> Option<PathBuf> {
+    let read = |path: PathBuf| std::fs::read_to_string(path).map(|s| s.trim().to_lowercase()).ok();
+    std::fs::read_dir("/sys/bus/usb/devices")
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|path| {
+            read(path.join("idVendor")).as_deref() == Some(vid) && read(path.join("idProduct")).as_deref() == Some(pid)
+        })
+}
+
+/// Poll `cyme` (or `lsusb`) until the test VID:PID shows up
+fn wait_for_device(timeout: Duration) -> Result<(), String> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let listing = Command::new("cyme")
+            .arg("--lsusb")
+            .output()
+            .or_else(|_| Command::new("lsusb").output())
+            .map_err(|_| "neither cyme nor lsusb is installed")?;
+
+        if String::from_utf8_lossy(&listing.stdout).to_lowercase().contains(TEST_VID_PID) {
+            println!("  host: {TEST_VID_PID} enumerated");
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(format!("{TEST_VID_PID} did not enumerate within {}s", timeout.as_secs()));
+        }
+        thread::sleep(Duration::from_millis(250));
+    }
+}
+
+fn find_serial_port() -> Option<PathBuf> {
+    let mut ports: Vec<PathBuf> = std::fs::read_dir("/dev")
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            name.starts_with("ttyACM") || name.starts_with("cu.usbmodem")
+        })
+        .collect();
+    ports.sort();
+    ports.into_iter().next()
+}
+
+fn print_summary(results: &[(&str, Outcome, Duration)]) -> ExitCode {
+    println!("\n{:<20} {:<8} {:>7}  details", "test", "result", "time");
+    let mut failures = 0;
+    for (name, outcome, elapsed) in results {
+        let (result, details) = match outcome {
+            Outcome::Passed => ("PASS", String::new()),
+            Outcome::Failed(reason) => {
+                failures += 1;
+                ("FAIL", reason.clone())
+            }
+            Outcome::Skipped(reason) => ("SKIP", reason.to_string()),
+        };
+        println!("{:<20} {:<8} {:>6.1}s  {}", name, result, elapsed.as_secs_f32(), details);
+    }
+
+    let passed = results.iter().filter(|(_, o, _)| matches!(o, Outcome::Passed)).count();
+    println!("\n{passed} passed, {failures} failed, {} skipped", results.len() - passed - failures);
+
+    if failures == 0 { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+}
@@ -0,0 +1,260 @@
+//! `cargo xtask stamp`: fill in the firmware's `fw_info` block
+//!
+//! Finds the `_FW_INFO` symbol placed by `firmware_info!`, writes the git
+//! hash, build time, image length and CRC-32 into it, and patches the ELF in
+//! place. The CRC covers the flash image from address 0 to its end with the
+//! `crc` field skipped and gaps between segments read as erased (0xFF),
+//! matching `FirmwareInfo::verify` on the device.
+
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const USAGE: &str = "\
+Usage: cargo xtask stamp [OPTIONS] <ELF>
+
+Write the git hash, build time, image length and CRC into the firmware's
+fw_info block. Run after every build, before flashing.
+
+Options:
+    --git-hash <HASH>   Hash to record [default: `git rev-parse --short=12 HEAD`]
+    --build-time <SECS> Unix time to record [default: $SOURCE_DATE_EPOCH or now]
+";
+
+/// `"HTFW"`, must match `fw_info::MAGIC`
+const MAGIC: &[u8; 4] = b"HTFW";
+/// Symbol defined by `firmware_info!`
+const SYMBOL: &str = "_FW_INFO";
+/// Flash ends where SRAM starts
+const FLASH_END: u32 = 0x2000_0000;
+
+// Field offsets in `FirmwareInfo`
+const GIT_HASH: usize = 20;
+const GIT_HASH_LEN: usize = 16;
+const BUILD_TIME: usize = 36;
+const IMAGE_LEN: usize = 40;
+const CRC: usize = 44;
+const INFO_SIZE: usize = 48;
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+
+pub fn run(args: &[String]) -> Result<(), String> {
+    let mut elf = None;
+    let mut git_hash = None;
+    let mut build_time = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().cloned().ok_or(format!("{name} needs a value"));
+        match arg.as_str() {
+            "--git-hash" => git_hash = Some(value("--git-hash")?),
+            "--build-time" => {
+                let secs = value("--build-time")?;
+                build_time = Some(secs.parse().map_err(|_| format!("invalid build time `{secs}`"))?);
+            }
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(());
+            }
+            path if !path.starts_with('-') => elf = Some(path.to_string()),
+            other => return Err(format!("unknown option `{other}`")),
+        }
+    }
+    let elf = elf.ok_or("no ELF file given")?;
+    let git_hash = match git_hash {
+        Some(hash) => hash,
+        None => current_git_hash()?,
+    };
+    let build_time = match build_time {
+        Some(secs) => secs,
+        None => default_build_time(),
+    };
+
+    stamp(Path::new(&elf), &git_hash, build_time)
+}
+
+fn stamp(path: &Path, git_hash: &str, build_time: u32) -> Result<(), String> {
+    let mut file = std::fs::read(path).map_err(|e| format!("read {}: {e}", path.display()))?;
+    let elf = Elf::parse(&file)?;
+
+    let address = elf.symbol(&file, SYMBOL)?.ok_or(format!(
+        "no `{SYMBOL}` symbol in {}; does the firmware invoke `firmware_info!()`?",
+        path.display()
+    ))?;
+    let info_offset = elf
+        .file_offset(address, INFO_SIZE as u32)
+        .ok_or(format!("`{SYMBOL}` at {address:#x} is not in a flash segment"))?;
+    if &file[info_offset..info_offset + 4] != MAGIC {
+        return Err(format!("`{SYMBOL}` at {address:#x} does not start with the fw_info magic"));
+    }
+
+    // Fill in everything but the CRC, which covers the rest
+    let mut hash = [0u8; GIT_HASH_LEN];
+    let len = git_hash.len().min(GIT_HASH_LEN);
+    hash[..len].copy_from_slice(&git_hash.as_bytes()[..len]);
+    let image_len = elf.flash_end();
+    let info = &mut file[info_offset..info_offset + INFO_SIZE];
+    info[GIT_HASH..GIT_HASH + GIT_HASH_LEN].copy_from_slice(&hash);
+    info[BUILD_TIME..BUILD_TIME + 4].copy_from_slice(&build_time.to_le_bytes());
+    info[IMAGE_LEN..IMAGE_LEN + 4].copy_from_slice(&image_len.to_le_bytes());
+
+    let image = elf.flash_image(&file);
+    let crc_at = address as usize + CRC;
+    let crc = crc32(&[&image[..crc_at], &image[crc_at + 4..]]);
+    file[info_offset + CRC..info_offset + CRC + 4].copy_from_slice(&crc.to_le_bytes());
+
+    std::fs::write(path, &file).map_err(|e| format!("write {}: {e}", path.display()))?;
+    println!(
+        "{}: {SYMBOL} at {address:#x}, git {}, built {build_time}, {image_len} bytes, crc {crc:#010x}",
+        path.display(),
+        &git_hash[..len]
+    );
+    Ok(())
+}
+
+fn current_git_hash() -> Result<String, String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .map_err(|e| format!("git: {e}"))?;
+    if !output.status.success() {
+        return Err("git rev-parse failed, pass --git-hash".into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// `SOURCE_DATE_EPOCH` for reproducible builds, else the current time
+fn default_build_time() -> u32 {
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as u32))
+}
+
+/// CRC-32 (IEEE) over `parts` in order, as the CRC peripheral computes it
+fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in parts.iter().flat_map(|part| part.iter()) {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// A loadable segment that ends up in flash
+struct Segment {
+    /// Load (flash) address
+    address: u32,
+    offset: usize,
+    size: usize,
+}
+
+/// A named symbol table entry
+pub(crate) struct Symbol {
+    pub name: String,
+    pub value: u32,
+    pub size: u32,
+    /// `STT_*` type
+    pub kind: u8,
+}
+
+/// The parts of a 32-bit little-endian ELF file the stamp and the trace decoder need
+pub(crate) struct Elf {
+    segments: Vec<Segment>,
+    /// (offset, entry count) of the symbol table and offset of its string table
+    symtab: Option<(usize, usize, usize)>,
+}
+
+impl Elf {
+    pub(crate) fn parse(file: &[u8]) -> Result<Self, String> {
+        if file.len() < 52 || &file[..4] != b"\x7fELF" || file[4] != 1 || file[5] != 1 {
+            return Err("not a 32-bit little-endian ELF file".into());
+        }
+        let u16_at = |at: usize| u16::from_le_bytes([file[at], file[at + 1]]) as usize;
+        let u32_at = |at: usize| u32::from_le_bytes(file[at..at + 4].try_into().unwrap());
+
+        let (phoff, phentsize, phnum) = (u32_at(0x1C) as usize, u16_at(0x2A), u16_at(0x2C));
+        let segments = (0..phnum)
+            .map(|i| phoff + i * phentsize)
+            .filter(|&ph| u32_at(ph) == PT_LOAD && u32_at(ph + 16) != 0 && u32_at(ph + 12) < FLASH_END)
+            .map(|ph| Segment {
+                address: u32_at(ph + 12),
+                offset: u32_at(ph + 4) as usize,
+                size: u32_at(ph + 16) as usize,
+            })
+            .collect();
+
+        let (shoff, shentsize, shnum) = (u32_at(0x20) as usize, u16_at(0x2E), u16_at(0x30));
+        let symtab = (0..shnum)
+            .map(|i| shoff + i * shentsize)
+            .find(|&sh| u32_at(sh + 4) == SHT_SYMTAB)
+            .map(|sh| {
+                let strtab = shoff + u32_at(sh + 24) as usize * shentsize;
+                (u32_at(sh + 16) as usize, u32_at(sh + 20) as usize / 16, u32_at(strtab + 16) as usize)
+            });
+
+        Ok(Self { segments, symtab })
+    }
+
+    /// Value of the symbol `name`
+    fn symbol(&self, file: &[u8], name: &str) -> Result<Option<u32>, String> {
+        let (offset, count, strtab) = self.symtab.ok_or("ELF has no symbol table (stripped?)")?;
+        let u32_at = |at: usize| u32::from_le_bytes(file[at..at + 4].try_into().unwrap());
+        Ok((0..count).map(|i| offset + i * 16).find_map(|sym| {
+            let start = strtab + u32_at(sym) as usize;
+            let end = start + file[start..].iter().position(|&b| b == 0)?;
+            (&file[start..end] == name.as_bytes()).then(|| u32_at(sym + 4))
+        }))
+    }
+
+    /// Every named symbol
+    pub(crate) fn symbols(&self, file: &[u8]) -> Result<Vec<Symbol>, String> {
+        let (offset, count, strtab) = self.symtab.ok_or("ELF has no symbol table (stripped?)")?;
+        let u32_at = |at: usize| u32::from_le_bytes(file[at..at + 4].try_into().unwrap());
+        Ok((0..count)
+            .map(|i| offset + i * 16)
+            .filter_map(|sym| {
+                let start = strtab + u32_at(sym) as usize;
+                let end = start + file[start..].iter().position(|&b| b == 0)?;
+                (end > start).then(|| Symbol {
+                    name: String::from_utf8_lossy(&file[start..end]).into_owned(),
+                    value: u32_at(sym + 4),
+                    size: u32_at(sym + 8),
+                    kind: file[sym + 12] & 0xF,
+                })
+            })
+            .collect())
+    }
+
+    /// Word loaded at `address` in flash
+    pub(crate) fn read_u32(&self, file: &[u8], address: u32) -> Option<u32> {
+        let at = self.file_offset(address, 4)?;
+        Some(u32::from_le_bytes(file[at..at + 4].try_into().unwrap()))
+    }
+
+    /// File offset of `size` bytes loaded at `address`
+    fn file_offset(&self, address: u32, size: u32) -> Option<usize> {
+        self.segments
+            .iter()
+            .find(|s| address >= s.address && address + size <= s.address + s.size as u32)
+            .map(|s| s.offset + (address - s.address) as usize)
+    }
+
+    /// End of the flash image
+    fn flash_end(&self) -> u32 {
+        self.segments.iter().map(|s| s.address + s.size as u32).max().unwrap_or(0)
+    }
+
+    /// Flash contents from address 0, gaps erased
+    fn flash_image(&self, file: &[u8]) -> Vec<u8> {
+        let mut image = vec![0xFF; self.flash_end() as usize];
+        for s in &self.segments {
+            let at = s.address as usize;
+            image[at..at + s.size].copy_from_slice(&file[s.offset..s.offset + s.size]);
+        }
+        image
+    }
+}
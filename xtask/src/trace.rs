@@ -0,0 +1,466 @@
+//! `cargo xtask trace`: decode a `task_trace` capture
+//!
+//! Reads the records the firmware writes to its RTT `trace` channel (the
+//! format is described in `src/task_trace.rs`), prints CPU time, counts and
+//! worst cases for every task and interrupt, and optionally writes the
+//! timeline as Chrome trace events for Perfetto or `chrome://tracing`. Given
+//! the firmware's ELF, tasks are named after their `POOL` statics and
+//! interrupts after the handlers in the vector table.
+//!
+//! CPU time of a task or handler excludes the interrupts nested in it, so
+//! the "preempted" column is what interrupts took out of a task's polls, and
+//! "max wait" is the longest a woken task sat in the run queue.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::stamp::Elf;
+
+pub const USAGE: &str = "\
+Usage: cargo xtask trace [OPTIONS] <TRACE>
+
+Summarize a capture of the RTT `trace` channel from a `task-trace` build.
+
+Options:
+    --elf <ELF>         Firmware the capture came from, to name tasks and interrupts
+    --json <PATH>       Write the timeline as Chrome trace events (open in ui.perfetto.dev)
+";
+
+/// Start of the stream; must match `task_trace::MAGIC`
+const MAGIC: &[u8; 4] = b"HTTR";
+const VERSION: u8 = 1;
+/// Task ids are sent relative to this
+const RAM_START: u32 = 0x2000_0000;
+/// Exception number of IRQ 0
+const IRQ_BASE: u32 = 16;
+/// Vector table symbol defined by cortex-m-rt
+const INTERRUPTS: &str = "__INTERRUPTS";
+
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+// Record kinds; must match `task_trace::Kind`
+const TASK_NEW: u8 = 1;
+const TASK_READY: u8 = 2;
+const TASK_EXEC_BEGIN: u8 = 3;
+const TASK_EXEC_END: u8 = 4;
+const TASK_END: u8 = 5;
+const IDLE: u8 = 6;
+const POLL_START: u8 = 7;
+const ISR_ENTER: u8 = 8;
+const ISR_EXIT: u8 = 9;
+const DROPPED: u8 = 10;
+
+/// Cortex-M0+ system exceptions by number
+const EXCEPTIONS: [&str; 16] = [
+    "Thread", "Reset", "NMI", "HardFault", "", "", "", "", "", "", "", "SVCall", "", "", "PendSV", "SysTick",
+];
+
+pub fn run(args: &[String]) -> Result<(), String> {
+    let mut trace = None;
+    let mut elf = None;
+    let mut json = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().cloned().ok_or(format!("{name} needs a value"));
+        match arg.as_str() {
+            "--elf" => elf = Some(value("--elf")?),
+            "--json" => json = Some(value("--json")?),
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(());
+            }
+            path if !path.starts_with('-') => trace = Some(path.to_string()),
+            other => return Err(format!("unknown option `{other}`")),
+        }
+    }
+    let trace = trace.ok_or("no trace file given")?;
+
+    let data = std::fs::read(&trace).map_err(|e| format!("read {trace}: {e}"))?;
+    let names = match elf {
+        Some(path) => Names::from_elf(Path::new(&path))?,
+        None => Names::default(),
+    };
+
+    let mut reader = Reader { data: &data, pos: 0, at: 0 };
+    let tick_hz = reader.header()?;
+    let mut timeline = Timeline::new(tick_hz, json.is_some());
+    while let Some(event) = reader.next()? {
+        timeline.apply(&names, event);
+    }
+
+    timeline.report(&names);
+    if let Some(path) = json {
+        std::fs::write(&path, timeline.json()).map_err(|e| format!("write {path}: {e}"))?;
+        println!("\ntimeline written to {path}");
+    }
+    Ok(())
+}
+
+/// One decoded record
+struct Event {
+    kind: u8,
+    /// Ticks since the start of the stream
+    at: u64,
+    arg: u32,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// Time of the last record
+    at: u64,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Option<u8> {
+        let byte = *self.data.get(self.pos)?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    fn varint(&mut self) -> Option<u32> {
+        let mut value = 0u32;
+        for shift in (0..35).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7F) as u32) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    /// Check the magic and version, and return the tick rate
+    fn header(&mut self) -> Result<u64, String> {
+        if !self.data.starts_with(MAGIC) {
+            return Err("not a task-trace capture (no `HTTR` header); was it saved from reset?".into());
+        }
+        self.pos = MAGIC.len();
+        match self.byte() {
+            Some(VERSION) => {}
+            Some(v) => return Err(format!("trace format version {v}, this decoder reads {VERSION}")),
+            None => return Err("capture ends in the header".into()),
+        }
+        let tick_hz = self.varint().ok_or("capture ends in the header")?;
+        Ok(tick_hz as u64)
+    }
+
+    /// Next record, or `None` at the end; a record cut off by the end of the capture is ignored
+    fn next(&mut self) -> Result<Option<Event>, String> {
+        let start = self.pos;
+        let Some(kind) = self.byte() else { return Ok(None) };
+        let Some(delta) = self.varint() else { return Ok(None) };
+        let arg = match kind {
+            TASK_NEW..=TASK_END | ISR_ENTER | ISR_EXIT | DROPPED => match self.varint() {
+                Some(arg) => arg,
+                None => return Ok(None),
+            },
+            IDLE | POLL_START => 0,
+            _ => return Err(format!("unknown record kind {kind} at offset {start}")),
+        };
+        self.at += delta as u64;
+        Ok(Some(Event { kind, at: self.at, arg }))
+    }
+}
+
+/// What the CPU is running
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Context {
+    /// A task poll; task storage address
+    Task(u32),
+    /// An exception or interrupt handler; exception number
+    Handler(u32),
+    /// The executor asleep waiting for an interrupt
+    Idle,
+}
+
+#[derive(Default)]
+struct Stats {
+    count: u64,
+    /// Ticks spent in this context itself, without nested handlers
+    busy: u64,
+    /// Longest single run, nested handlers included
+    max: u64,
+    /// Ticks of handlers nested in this context
+    preempted: u64,
+    /// Longest wake-up to poll wait, for tasks
+    max_wait: u64,
+}
+
+/// An open context on the (single) CPU
+struct Frame {
+    context: Context,
+    start: u64,
+    /// Ticks of the contexts nested in it so far
+    nested: u64,
+}
+
+struct Timeline {
+    tick_hz: u64,
+    stack: Vec<Frame>,
+    /// Wake-up time of every queued task
+    ready: BTreeMap<u32, u64>,
+    stats: BTreeMap<Context, Stats>,
+    end: u64,
+    dropped: u64,
+    /// Chrome trace events, when asked for
+    events: Option<Vec<String>>,
+}
+
+impl Timeline {
+    fn new(tick_hz: u64, json: bool) -> Self {
+        Self {
+            tick_hz,
+            stack: Vec::new(),
+            ready: BTreeMap::new(),
+            stats: BTreeMap::new(),
+            end: 0,
+            dropped: 0,
+            events: json.then(Vec::new),
+        }
+    }
+
+    fn micros(&self, ticks: u64) -> f64 {
+        ticks as f64 * 1e6 / self.tick_hz as f64
+    }
+
+    fn event(&mut self, names: &Names, phase: char, context: Context, at: u64) {
+        let ts = self.micros(at);
+        if let Some(events) = &mut self.events {
+            let name = escape(&names.context(context));
+            events.push(format!(r#"{{"name":"{name}","ph":"{phase}","ts":{ts:.3},"pid":1,"tid":1}}"#));
+        }
+    }
+
+    fn instant(&mut self, name: &str, at: u64) {
+        let ts = self.micros(at);
+        if let Some(events) = &mut self.events {
+            let name = escape(name);
+            events.push(format!(r#"{{"name":"{name}","ph":"i","s":"t","ts":{ts:.3},"pid":1,"tid":1}}"#));
+        }
+    }
+
+    fn apply(&mut self, names: &Names, event: Event) {
+        self.end = event.at;
+        let task = event.arg.wrapping_add(RAM_START);
+        match event.kind {
+            TASK_NEW => {
+                self.stats.entry(Context::Task(task)).or_default();
+            }
+            TASK_READY => {
+                self.ready.entry(task).or_insert(event.at);
+                self.instant(&format!("wake {}", names.task(task)), event.at);
+            }
+            TASK_EXEC_BEGIN => {
+                if let Some(ready) = self.ready.remove(&task) {
+                    let stats = self.stats.entry(Context::Task(task)).or_default();
+                    stats.max_wait = stats.max_wait.max(event.at - ready);
+                }
+                self.open(names, Context::Task(task), event.at);
+            }
+            TASK_EXEC_END => self.close(names, Context::Task(task), event.at),
+            TASK_END => {
+                self.ready.remove(&task);
+            }
+            IDLE => self.open(names, Context::Idle, event.at),
+            POLL_START => self.close(names, Context::Idle, event.at),
+            ISR_ENTER => self.open(names, Context::Handler(event.arg), event.at),
+            ISR_EXIT => self.close(names, Context::Handler(event.arg), event.at),
+            DROPPED => {
+                // Whatever was open may have ended in the gap; drop it unaccounted
+                while let Some(frame) = self.stack.pop() {
+                    self.event(names, 'E', frame.context, event.at);
+                }
+                self.ready.clear();
+                self.dropped += event.arg as u64;
+                self.instant(&format!("{} records dropped", event.arg), event.at);
+            }
+            _ => unreachable!("rejected by the reader"),
+        }
+    }
+
+    fn open(&mut self, names: &Names, context: Context, at: u64) {
+        self.event(names, 'B', context, at);
+        self.stack.push(Frame { context, start: at, nested: 0 });
+    }
+
+    /// Close `context` and anything left open inside it; ignored if it was
+    /// never opened, e.g. at the start of the capture
+    fn close(&mut self, names: &Names, context: Context, at: u64) {
+        if !self.stack.iter().any(|f| f.context == context) {
+            return;
+        }
+        while let Some(frame) = self.stack.pop() {
+            self.event(names, 'E', frame.context, at);
+            let duration = at - frame.start;
+            let stats = self.stats.entry(frame.context).or_default();
+            stats.count += 1;
+            stats.busy += duration - frame.nested.min(duration);
+            stats.max = stats.max.max(duration);
+            stats.preempted += frame.nested;
+            if let Some(parent) = self.stack.last_mut() {
+                parent.nested += duration;
+            }
+            if frame.context == context {
+                return;
+            }
+        }
+    }
+
+    fn report(&self, names: &Names) {
+        let total = self.end.max(1);
+        println!(
+            "{:.3} s traced at {} Hz, {} records dropped",
+            self.micros(self.end) / 1e6,
+            self.tick_hz,
+            self.dropped
+        );
+
+        let mut rows: Vec<_> = self.stats.iter().collect();
+        rows.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.busy));
+        println!(
+            "\n{:<40} {:>8} {:>7} {:>10} {:>10} {:>12} {:>12}",
+            "context", "count", "cpu %", "busy ms", "max us", "max wait us", "preempted ms"
+        );
+        let mut accounted = 0;
+        for (&context, stats) in rows {
+            accounted += stats.busy;
+            let wait = match context {
+                Context::Task(_) => format!("{:.1}", self.micros(stats.max_wait)),
+                _ => String::new(),
+            };
+            println!(
+                "{:<40} {:>8} {:>7.2} {:>10.3} {:>10.1} {:>12} {:>12.3}",
+                names.context(context),
+                stats.count,
+                stats.busy as f64 * 100.0 / total as f64,
+                self.micros(stats.busy) / 1e3,
+                self.micros(stats.max),
+                wait,
+                self.micros(stats.preempted) / 1e3
+            );
+        }
+        let other = total.saturating_sub(accounted);
+        println!(
+            "{:<40} {:>8} {:>7.2} {:>10.3}",
+            "(executor, untraced code, gaps)",
+            "",
+            other as f64 * 100.0 / total as f64,
+            self.micros(other) / 1e3
+        );
+    }
+
+    fn json(&self) -> String {
+        let events = self.events.as_deref().unwrap_or_default();
+        let mut out = String::from("{\"traceEvents\":[\n");
+        out += r#"{"name":"thread_name","ph":"M","pid":1,"tid":1,"args":{"name":"cpu"}}"#;
+        for event in events {
+            out += ",\n";
+            out += event;
+        }
+        out += "\n]}\n";
+        out
+    }
+}
+
+fn escape(name: &str) -> String {
+    name.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Names for task ids and exception numbers, from the firmware's symbols
+#[derive(Default)]
+struct Names {
+    /// (start, end, name) of every data symbol
+    objects: Vec<(u32, u32, String)>,
+    /// Handler name by exception number
+    handlers: BTreeMap<u32, String>,
+}
+
+impl Names {
+    fn from_elf(path: &Path) -> Result<Self, String> {
+        let file = std::fs::read(path).map_err(|e| format!("read {}: {e}", path.display()))?;
+        let elf = Elf::parse(&file)?;
+        let symbols = elf.symbols(&file)?;
+
+        let objects = symbols
+            .iter()
+            .filter(|s| s.kind == STT_OBJECT && s.size > 0)
+            .map(|s| (s.value, s.value + s.size, demangle(&s.name)))
+            .collect();
+
+        // Thumb function symbols and vector entries have bit 0 set
+        let functions: BTreeMap<u32, &str> = symbols
+            .iter()
+            .filter(|s| s.kind == STT_FUNC)
+            .map(|s| (s.value & !1, s.name.as_str()))
+            .collect();
+        let mut handlers = BTreeMap::new();
+        if let Some(table) = symbols.iter().find(|s| s.name == INTERRUPTS) {
+            for irq in 0..table.size / 4 {
+                let Some(vector) = elf.read_u32(&file, table.value + irq * 4) else { continue };
+                match functions.get(&(vector & !1)) {
+                    Some(&"DefaultHandler") | None => {}
+                    Some(name) => {
+                        let name = demangle(name);
+                        let name = name.rsplit("::").next().unwrap_or(&name).to_string();
+                        handlers.insert(IRQ_BASE + irq, name);
+                    }
+                }
+            }
+        }
+
+        Ok(Self { objects, handlers })
+    }
+
+    fn context(&self, context: Context) -> String {
+        match context {
+            Context::Task(task) => self.task(task),
+            Context::Handler(exception) => self.handler(exception),
+            Context::Idle => "idle".into(),
+        }
+    }
+
+    /// The `POOL` static holding the task, without the `::POOL`
+    fn task(&self, task: u32) -> String {
+        match self.objects.iter().find(|(start, end, _)| (*start..*end).contains(&task)) {
+            Some((_, _, name)) => format!("task {}", name.trim_end_matches("::POOL")),
+            None => format!("task {task:#010x}"),
+        }
+    }
+
+    fn handler(&self, exception: u32) -> String {
+        match self.handlers.get(&exception) {
+            Some(name) => format!("irq {name}"),
+            None if exception < IRQ_BASE => format!("exception {}", EXCEPTIONS[exception as usize]),
+            None => format!("irq {}", exception - IRQ_BASE),
+        }
+    }
+}
+
+/// `a::b::c` from a legacy-mangled Rust symbol, without the hash; other names as they are
+fn demangle(name: &str) -> String {
+    let Some(mut rest) = name.strip_prefix("_ZN") else { return name.into() };
+    let mut parts = Vec::new();
+    while let Some(digits) = rest.find(|c: char| !c.is_ascii_digit()).filter(|&n| n > 0) {
+        let Ok(len) = rest[..digits].parse::<usize>() else { break };
+        let Some(part) = rest.get(digits..digits + len) else { break };
+        parts.push(part);
+        rest = &rest[digits + len..];
+    }
+    if parts.last().is_some_and(|p| p.len() == 17 && p.starts_with('h')) {
+        parts.pop();
+    }
+    if parts.is_empty() {
+        return name.into();
+    }
+    parts
+        .join("::")
+        .replace("$LT$", "<")
+        .replace("$GT$", ">")
+        .replace("$u7b$", "{")
+        .replace("$u7d$", "}")
+        .replace("$u20$", " ")
+        .replace("..", "::")
+}